@@ -3,30 +3,706 @@
 // SPDX-License-Identifier: MPL-2.0
 
 //! The Clipper CLI.
+use anon_packets::AnonymizeOptions;
 use clap::Parser;
-use libclipper::{devtools::do_devtools_server_inner, Error};
+use libclipper::{
+    adb::AdbCapture,
+    devtools::{do_devtools_server_inner, do_devtools_stream_inner},
+    k8s::K8sCapture,
+    key_store::KeyFile,
+    merge,
+    remote::RemoteCapture,
+    schedule::{self, CaptureSchedule, DailyWindow},
+    slice, Error,
+};
 use tracing::metadata::LevelFilter;
 
 use std::{
     fmt::Debug,
-    path::PathBuf,
+    io,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 
+#[cfg(feature = "stage-timing")]
+use net_decode::stage_timing::{Stage, StageTimes, Timed};
 use net_decode::{
-    chomp::{self},
-    key_db::KeyDB,
+    body_policy::{BodyPolicies, BodyPolicyRule},
+    capture_filter::CaptureFilter,
+    capture_index::{CaptureIndex, IndexedCapture},
+    capture_merge::MergeInput,
+    capture_slice::{self, CaptureSlice, SliceBound},
+    checkpoint::ReplayGate,
+    checksum::ChecksumMode,
+    chomp::FrameChomper,
+    key_db::{parse_psk_file, ExternalPsk, KeyDB, RsaKey},
     listener::DebugListener,
+    parallel::{ParallelChomper, DEFAULT_QUEUE_FRAMES},
+    pipeline::Pipeline,
+    plugin::PluginRegistry,
+    tcp_reassemble, udp_flow,
+    wasm_plugin::WasmPlugin,
+    DecodeOptions,
 };
 use tracing_subscriber::prelude::*;
 
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_SCTP: u8 = 132;
+
+/// Ways to get keys into a live capture, in addition to the injected
+/// library.
+#[derive(clap::Args, Debug)]
+struct KeySourceArgs {
+    /// Key log file to set as SSLKEYLOGFILE for the program and read keys
+    /// from as it is written.
+    #[clap(long)]
+    keylog_file: Option<PathBuf>,
+
+    /// Accept key log lines on a socket: `tcp:ADDR:PORT` or `unix:PATH`.
+    #[clap(long)]
+    keylog_listen: Option<String>,
+
+    /// Read keys out of every process running this Go program, which
+    /// can't be injected into, with eBPF. May be repeated. Needs root and
+    /// clipper built with the `ebpf` feature.
+    #[clap(long = "go-binary")]
+    go_binaries: Vec<PathBuf>,
+
+    /// Load clipper's Java agent into any JVM the program starts, to get
+    /// the keys of Java's own TLS. Needs `make java-agent`.
+    #[clap(long)]
+    java_agent: bool,
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    all(windows, feature = "npcap")
+))]
+impl KeySourceArgs {
+    fn into_key_sources(self) -> Result<libclipper::capture::KeySources, Error> {
+        Ok(libclipper::capture::KeySources {
+            key_log_file: self.keylog_file,
+            listen: self.keylog_listen.map(|a| a.parse()).transpose()?,
+            go_binaries: self.go_binaries,
+            java_agent: self.java_agent,
+        })
+    }
+}
+
+/// Options shared by everything that runs a live capture.
+#[derive(clap::Args, Debug)]
+struct CaptureArgs {
+    #[clap(flatten)]
+    keys: KeySourceArgs,
+
+    /// Stop the capture, and the program, after this long, e.g. `1h 30m`.
+    #[clap(long, value_parser = schedule::parse_duration)]
+    duration: Option<Duration>,
+
+    /// Stop the capture, and the program, at this time (UTC): either a time
+    /// of day like `17:00` or a date like `2023-08-01 17:00:00`.
+    #[clap(long, value_parser = schedule::parse_until)]
+    until: Option<SystemTime>,
+
+    /// Only record during this daily window (UTC), e.g. `09:00-10:00`. May
+    /// be repeated. Each window is written to its own file, named after the
+    /// time it started.
+    #[clap(long = "window")]
+    windows: Vec<DailyWindow>,
+
+    /// Listen here for `clipper resume`, to hand the capture over to
+    /// another clipper process without restarting the program.
+    #[clap(long)]
+    handoff_socket: Option<PathBuf>,
+
+    /// Start with the keys saved in this file by `--save-keys`, or in a
+    /// key log file. Encrypted files need the passphrase in
+    /// CLIPPER_KEY_PASSPHRASE.
+    #[clap(long)]
+    load_keys: Option<PathBuf>,
+
+    /// Save every key seen to this file when the capture finishes. This is
+    /// a key log file unless `--encrypt-keys` is given.
+    #[clap(long)]
+    save_keys: Option<PathBuf>,
+
+    /// Encrypt the `--save-keys` file with the passphrase in
+    /// CLIPPER_KEY_PASSPHRASE.
+    #[clap(long, requires = "save_keys")]
+    encrypt_keys: bool,
+
+    /// PEM private key of a server, to decrypt TLS 1.2 connections to it
+    /// that use RSA key exchange. May be repeated.
+    #[clap(long = "rsa-key")]
+    rsa_keys: Vec<PathBuf>,
+
+    /// File of external pre-shared keys for TLS-PSK connections, one
+    /// `IDENTITY=HEXKEY` per line.
+    #[clap(long)]
+    psk_file: Option<PathBuf>,
+
+    /// Show which process opened each connection, found out with eBPF.
+    /// Needs root and clipper built with the `ebpf` feature. Only outgoing
+    /// TCP connections are attributed.
+    #[clap(long)]
+    attribute_processes: bool,
+
+    /// Only capture packets matching this tcpdump-style filter, e.g.
+    /// `tcp port 443 and not host 10.0.0.1`.
+    #[clap(long)]
+    filter: Option<CaptureFilter>,
+
+    /// Only keep this many bytes of each packet, e.g. 256 for little more
+    /// than the headers. Bodies cut short are marked as truncated.
+    #[clap(long = "snaplen", value_parser = clap::value_parser!(u32).range(64..))]
+    snap_len: Option<u32>,
+
+    /// Start with the capture paused, throwing packets away until resumed.
+    /// Send clipper SIGUSR1 to pause and SIGUSR2 to resume, or call
+    /// `Clipper.pause` and `Clipper.resume` from devtools.
+    #[clap(long)]
+    paused: bool,
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    all(windows, feature = "npcap")
+))]
+impl CaptureArgs {
+    fn into_options(self) -> Result<libclipper::capture::CaptureOptions, Error> {
+        Ok(libclipper::capture::CaptureOptions {
+            key_sources: self.keys.into_key_sources()?,
+            handoff_socket: self.handoff_socket,
+            schedule: CaptureSchedule::new(self.duration, self.until, self.windows),
+            load_keys: self.load_keys.map(KeyFile::from_env),
+            save_keys: self
+                .save_keys
+                .map(|path| save_key_file(path, self.encrypt_keys))
+                .transpose()?,
+            rsa_keys: read_rsa_keys(&self.rsa_keys)?,
+            psks: read_psk_file(self.psk_file)?,
+            attribute_processes: self.attribute_processes,
+            filter: self.filter,
+            snap_len: self.snap_len,
+            start_paused: self.paused,
+        })
+    }
+}
+
+/// Recording a devtools capture to a file as it is served.
+#[derive(clap::Args, Debug)]
+struct RecordArgs {
+    /// Also write everything captured to this pcapng as it is served, with
+    /// the TLS keys in it, to look at again later.
+    #[clap(long)]
+    record: Option<PathBuf>,
+
+    /// Start a new `--record` file this often, e.g. `1h`, each named after
+    /// the time it started.
+    #[clap(long, requires = "record", value_parser = schedule::parse_duration)]
+    rotate_every: Option<Duration>,
+
+    /// Do not write the TLS keys into the `--record` file.
+    #[clap(long, requires = "record")]
+    record_without_keys: bool,
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    all(windows, feature = "npcap")
+))]
+impl RecordArgs {
+    fn into_recording(self) -> Option<libclipper::capture::Recording> {
+        Some(libclipper::capture::Recording {
+            file: self.record?,
+            embed_keys: !self.record_without_keys,
+            rotate_every: self.rotate_every,
+        })
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    all(windows, feature = "npcap")
+))]
+fn save_key_file(path: PathBuf, encrypt: bool) -> Result<KeyFile, Error> {
+    use libclipper::key_store::PASSPHRASE_ENV_VAR;
+    let passphrase =
+        if encrypt {
+            Some(std::env::var(PASSPHRASE_ENV_VAR).map_err(|_| {
+                format!("--encrypt-keys needs a passphrase in {PASSPHRASE_ENV_VAR}")
+            })?)
+        } else {
+            None
+        };
+    Ok(KeyFile { path, passphrase })
+}
+
+fn read_rsa_keys(paths: &[PathBuf]) -> Result<Vec<RsaKey>, Error> {
+    paths
+        .iter()
+        .map(|path| -> Result<RsaKey, Error> {
+            let pem = std::fs::read_to_string(path)?;
+            RsaKey::from_pem(&pem).map_err(|e| format!("{}: {e}", path.display()).into())
+        })
+        .collect()
+}
+
+fn read_psk_file(path: Option<PathBuf>) -> Result<Vec<ExternalPsk>, Error> {
+    let Some(path) = path else {
+        return Ok(Vec::new());
+    };
+    let contents = std::fs::read_to_string(&path)?;
+    parse_psk_file(&contents).map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+/// Keys for decoding a pcapng file, on top of any embedded in it.
+#[derive(clap::Args, Debug)]
+struct KeyFileArgs {
+    /// Also use the keys in this file, saved by `--save-keys` or a key
+    /// log file.
+    #[clap(long)]
+    load_keys: Option<PathBuf>,
+
+    /// PEM private key of a server, to decrypt TLS 1.2 connections to it
+    /// that use RSA key exchange. May be repeated.
+    #[clap(long = "rsa-key")]
+    rsa_keys: Vec<PathBuf>,
+
+    /// File of external pre-shared keys for TLS-PSK connections, one
+    /// `IDENTITY=HEXKEY` per line.
+    #[clap(long)]
+    psk_file: Option<PathBuf>,
+
+    /// WireGuard key log, in the format of Wireshark's
+    /// `extract-handshakes.sh`, to decrypt WireGuard tunnels with.
+    #[clap(long)]
+    wireguard_keys: Option<PathBuf>,
+}
+
+impl KeyFileArgs {
+    fn into_key_db(self) -> Result<KeyDB, Error> {
+        let mut key_db = match self.load_keys {
+            Some(path) => KeyFile::from_env(path).load()?,
+            None => KeyDB::default(),
+        };
+        for key in read_rsa_keys(&self.rsa_keys)? {
+            key_db.add_rsa_key(key);
+        }
+        for psk in read_psk_file(self.psk_file)? {
+            key_db.add_psk(psk);
+        }
+        if let Some(path) = self.wireguard_keys {
+            key_db.load_wireguard_key_log(&std::fs::read(path)?);
+        }
+        Ok(key_db)
+    }
+}
+
+/// Decoding only part of a capture file.
+#[derive(clap::Args, Debug)]
+struct SliceArgs {
+    /// Start at this packet, counting from 1, time since the first packet,
+    /// e.g. `+90s`, or time, e.g. `2023-06-15T21:45:51Z`. Connections
+    /// already open are picked up from the packets before it.
+    #[clap(long, value_parser = slice::parse_slice_bound)]
+    from: Option<SliceBound>,
+
+    /// Stop after this packet or time, given like `--from`.
+    #[clap(long, value_parser = slice::parse_slice_bound)]
+    to: Option<SliceBound>,
+}
+
+impl SliceArgs {
+    fn into_slice(self) -> CaptureSlice {
+        CaptureSlice {
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+/// Reading a busy interface through a ring shared with the kernel.
+#[derive(clap::Args, Debug)]
+struct RingArgs {
+    /// Read packets through a memory-mapped TPACKET_V3 ring, a block at a
+    /// time, which keeps up with far higher packet rates. Linux only.
+    #[clap(long)]
+    ring: bool,
+
+    /// Size of each ring block, in KiB. Must be a power of two multiple of
+    /// the page size.
+    #[clap(long, default_value_t = 1024)]
+    ring_block_kib: usize,
+
+    /// Number of ring blocks.
+    #[clap(long, default_value_t = 64)]
+    ring_blocks: usize,
+
+    /// Longest the kernel holds on to a block that is not full yet, in
+    /// milliseconds.
+    #[clap(long, default_value_t = 50)]
+    ring_block_timeout_ms: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl RingArgs {
+    fn into_options(self) -> Option<libclipper::capture::RingOptions> {
+        self.ring.then_some(libclipper::capture::RingOptions {
+            block_size: self.ring_block_kib * 1024,
+            block_count: self.ring_blocks,
+            block_timeout_ms: self.ring_block_timeout_ms,
+        })
+    }
+}
+
+/// Reading a very busy interface through `AF_XDP` sockets.
+#[derive(clap::Args, Debug)]
+struct XdpArgs {
+    /// Capture through AF_XDP sockets, for packet rates even `--ring` drops
+    /// packets at. This takes the packets from the machine, so only use it
+    /// on an interface nothing else needs, like a mirror port. Only
+    /// received packets are seen. Needs root and Linux 5.9. Linux only.
+    #[clap(long, conflicts_with = "ring")]
+    xdp: bool,
+
+    /// Have the network card write packets straight into clipper's memory,
+    /// rather than the kernel copying them there. Needs driver support.
+    #[clap(long, requires = "xdp")]
+    xdp_zero_copy: bool,
+
+    /// Frames of packet memory for each receive queue of the interface.
+    /// Must be a power of two.
+    #[clap(long, default_value_t = 4096)]
+    xdp_frames: u32,
+
+    /// Size of each frame, 2048 or 4096 bytes. Bigger packets are dropped.
+    #[clap(long, default_value_t = 4096)]
+    xdp_frame_size: u32,
+
+    /// NUMA node to put the packet memory on, rather than the network
+    /// card's.
+    #[clap(long, requires = "xdp")]
+    xdp_numa_node: Option<u32>,
+}
+
+#[cfg(target_os = "linux")]
+impl XdpArgs {
+    fn into_options(self) -> Option<libclipper::capture::XdpOptions> {
+        self.xdp.then_some(libclipper::capture::XdpOptions {
+            mode: if self.xdp_zero_copy {
+                libclipper::capture::XdpMode::ZeroCopy
+            } else {
+                libclipper::capture::XdpMode::Copy
+            },
+            frame_count: self.xdp_frames,
+            frame_size: self.xdp_frame_size,
+            numa_node: self.xdp_numa_node,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn interface_options(
+    interfaces: Vec<String>,
+    promiscuous: bool,
+    ring: RingArgs,
+    xdp: XdpArgs,
+    netns: Option<String>,
+) -> Result<Vec<libclipper::capture::InterfaceOptions>, Error> {
+    let ring = ring.into_options();
+    let xdp = xdp.into_options();
+    let netns = netns.map(|ns| libclipper::capture::netns_path(&ns));
+    Ok(interfaces
+        .into_iter()
+        .map(|interface| libclipper::capture::InterfaceOptions {
+            interface,
+            promiscuous,
+            ring,
+            xdp,
+            netns: netns.clone(),
+        })
+        .collect())
+}
+
+#[cfg(any(target_os = "macos", all(windows, feature = "npcap")))]
+fn interface_options(
+    interfaces: Vec<String>,
+    promiscuous: bool,
+    ring: RingArgs,
+    xdp: XdpArgs,
+    netns: Option<String>,
+) -> Result<Vec<libclipper::capture::InterfaceOptions>, Error> {
+    if ring.ring {
+        return Err("--ring is only supported on Linux".into());
+    }
+    if xdp.xdp {
+        return Err("--xdp is only supported on Linux".into());
+    }
+    if netns.is_some() {
+        return Err("--netns is only supported on Linux".into());
+    }
+    Ok(interfaces
+        .into_iter()
+        .map(|interface| libclipper::capture::InterfaceOptions {
+            interface,
+            promiscuous,
+        })
+        .collect())
+}
+
+#[derive(clap::Args, Debug)]
+struct BodyPolicyArgs {
+    /// Whether to keep HTTP bodies for hosts matching a pattern, as
+    /// `PATTERN=full` or `PATTERN=headers`, e.g. `*.google.com=headers`.
+    /// May be repeated; the first matching rule wins and other hosts get
+    /// full bodies.
+    #[clap(long = "body-policy")]
+    rules: Vec<BodyPolicyRule>,
+}
+
+impl BodyPolicyArgs {
+    fn into_policies(self) -> BodyPolicies {
+        BodyPolicies::new(self.rules)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct DecodeArgs {
+    /// How much TLS data to keep per connection while waiting for its keys,
+    /// in MiB. Connections going over this are not decrypted.
+    #[clap(long, default_value_t = 16)]
+    key_wait_buffer_mib: usize,
+
+    /// Forget the TLS keys of closed connections, keeping only the last
+    /// this many, to bound memory in long captures. Forgotten keys are not
+    /// written by `--save-keys`.
+    #[clap(long)]
+    keep_closed_keys: Option<usize>,
+
+    /// Stop following TCP connections that have been quiet for this many
+    /// seconds.
+    #[clap(long, default_value_t = tcp_reassemble::DEFAULT_IDLE_TIMEOUT / NANOS_PER_SEC)]
+    tcp_idle_timeout_secs: u64,
+
+    /// Follow at most this many TCP connections at once, forgetting the one
+    /// quiet the longest to make room for a new one.
+    #[clap(long, default_value_t = tcp_reassemble::DEFAULT_MAX_FLOWS)]
+    max_tcp_flows: usize,
+
+    /// Consider UDP flows over after this many seconds without datagrams.
+    #[clap(long, default_value_t = udp_flow::DEFAULT_IDLE_TIMEOUT / NANOS_PER_SEC)]
+    udp_idle_timeout_secs: u64,
+
+    /// Check IP, TCP and UDP checksums: `off`, `flag` to report bad ones or
+    /// `drop` to also ignore those packets. Leave off when capturing on a
+    /// machine whose network card computes checksums, since its own packets
+    /// are captured without them.
+    #[clap(long, default_value = "off")]
+    checksums: ChecksumMode,
+
+    /// Decode only one in this many connections, for captures too busy to
+    /// keep up with. The same connections are picked on every run.
+    #[clap(long, default_value_t = 1)]
+    sample_flows: u32,
+
+    /// Keep only the first this many bytes of each HTTP body.
+    #[clap(long)]
+    max_body_bytes: Option<usize>,
+
+    /// Keep response bodies bigger than this many MiB in temporary files
+    /// rather than in memory.
+    #[clap(long, default_value_t = net_decode::DEFAULT_SPILL_BODIES_OVER / 1024 / 1024)]
+    spill_bodies_over_mib: usize,
+
+    /// Keep finished response bodies in this directory, named by their
+    /// SHA-256, along with an index.jsonl of the requests they answered.
+    /// Without it they go in a temporary directory deleted on exit.
+    #[clap(long)]
+    body_store: Option<PathBuf>,
+}
+
+impl DecodeArgs {
+    fn into_options(self) -> DecodeOptions {
+        DecodeOptions {
+            max_queued_tls_bytes: self.key_wait_buffer_mib * 1024 * 1024,
+            closed_key_retention: self.keep_closed_keys,
+            tcp_idle_timeout: self.tcp_idle_timeout_secs.saturating_mul(NANOS_PER_SEC),
+            max_tcp_flows: self.max_tcp_flows,
+            udp_idle_timeout: self.udp_idle_timeout_secs.saturating_mul(NANOS_PER_SEC),
+            checksums: self.checksums,
+            sample_flows: self.sample_flows,
+            max_body_bytes: self.max_body_bytes,
+            spill_bodies_over: self.spill_bodies_over_mib * 1024 * 1024,
+            body_store: self.body_store,
+        }
+    }
+}
+
 #[derive(clap::Parser, Debug)]
 enum Command {
     /// Debug: run a pcap through the clipper network stack
-    DumpPcap { file: PathBuf },
+    DumpPcap {
+        file: PathBuf,
+        /// WebAssembly decoder plugin to try on flows that aren't HTTP or
+        /// TLS, as a `.wasm` or `.wat` file. May be repeated.
+        #[clap(long = "plugin")]
+        plugins: Vec<PathBuf>,
+        /// Threads to decode on. Flows are spread across them, and the
+        /// output is put back in order.
+        #[clap(long, default_value_t = 1)]
+        workers: usize,
+        /// Only decode packets matching this tcpdump-style filter.
+        #[clap(long)]
+        filter: Option<CaptureFilter>,
+        #[clap(flatten)]
+        slice: SliceArgs,
+        /// Only decode this flow, numbered as `index --flows` lists them,
+        /// reading just its packets with the index of the file.
+        #[clap(long, conflicts_with_all = ["filter", "from", "to"])]
+        flow: Option<u32>,
+    },
     /// Starts a devtools server on a pcapng file.
-    DevtoolsServer { file: PathBuf },
-    /// Anonymizes the addresses in a pcapng file.
+    DevtoolsServer {
+        /// Capture file to serve. If it is `-` for stdin, or a named pipe,
+        /// it is decoded as it is written, e.g. by `tcpdump -w -`.
+        file: PathBuf,
+
+        /// Another capture file to merge with this one by time, e.g. one
+        /// taken at the other end of the connections. `FILE@OFFSET` adds
+        /// OFFSET to its timestamps, e.g. `server.pcapng@-250ms`. May be
+        /// repeated.
+        #[clap(long = "merge", value_parser = merge::parse_merge_input)]
+        merge: Vec<MergeInput>,
+
+        /// Only decode packets matching this tcpdump-style filter.
+        #[clap(long)]
+        filter: Option<CaptureFilter>,
+
+        #[clap(flatten)]
+        slice: SliceArgs,
+
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Indexes a capture file, so that `--from`, `--to` and `--flow` seek to
+    /// the packets they need rather than reading everything before them.
+    /// Captures over a GiB are indexed the first time they are sliced
+    /// anyway.
+    Index {
+        file: PathBuf,
+        /// List the flows in the file.
+        #[clap(long)]
+        flows: bool,
+    },
+    /// Merges capture files into one pcapng in order of time, e.g. captures
+    /// taken at both ends of a connection.
+    Merge {
+        /// Files to merge. `FILE@OFFSET` adds OFFSET to the timestamps in
+        /// FILE, to make up for its clock being off, e.g.
+        /// `server.pcapng@-250ms`.
+        #[clap(required = true, value_parser = merge::parse_merge_input)]
+        files: Vec<MergeInput>,
+        /// File to write to
+        #[clap(short = 'o', long)]
+        output_file: PathBuf,
+    },
+    /// Writes the server certificate chains in a pcapng file out as PEM
+    /// files, one per host.
+    ExportCerts {
+        file: PathBuf,
+        /// Directory to write `<host>.pem` files to
+        #[clap(short = 'o', long)]
+        output_dir: PathBuf,
+    },
+    /// Writes the decrypted TLS connections in a pcapng file out as a pcapng
+    /// of plain TCP, for tools that cannot decrypt TLS themselves.
+    ExportPlaintext {
+        file: PathBuf,
+        /// File to write to
+        #[clap(short = 'o', long)]
+        output_file: PathBuf,
+        #[clap(flatten)]
+        slice: SliceArgs,
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+    },
+    /// Copies a capture file to a pcapng with comments on the packets about
+    /// what clipper made of them, such as where each HTTP request starts and
+    /// which packets were a TCP handshake, for Wireshark to show.
+    Annotate {
+        file: PathBuf,
+        /// File to write to
+        #[clap(short = 'o', long)]
+        output_file: PathBuf,
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+        #[clap(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Writes a time-bucketed per-endpoint latency matrix for a pcapng file,
+    /// for heat maps. JSON if the output ends in `.json`, otherwise CSV.
+    LatencyHeatmap {
+        file: PathBuf,
+        /// File to write to
+        #[clap(short = 'o', long)]
+        output_file: PathBuf,
+        /// Width of each time bucket, in seconds
+        #[clap(long, default_value_t = 60)]
+        bucket_secs: u64,
+    },
+    /// Reports how much of a pcapng file clipper could decode, by flows and
+    /// bytes.
+    Coverage {
+        file: PathBuf,
+        /// Print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+    },
+    /// Lists the TLS connections in a pcapng file that could not be
+    /// decrypted, with why.
+    MissingKeys {
+        file: PathBuf,
+        /// Print JSON instead of one line per connection
+        #[clap(long)]
+        json: bool,
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+    },
+    /// Copies a pcapng file, embedding TLS keys from key log files in it.
+    EmbedKeys {
+        /// File to read from
+        #[clap(short = 'i', long)]
+        input_file: PathBuf,
+        /// File to write to
+        #[clap(short = 'o', long)]
+        output_file: PathBuf,
+        /// Key log file to take keys from. May be repeated.
+        #[clap(long = "keylog-file", required = true)]
+        keylog_files: Vec<PathBuf>,
+        /// Embed every key, not just the ones for connections in the
+        /// capture.
+        #[clap(long)]
+        all_keys: bool,
+    },
+    /// Anonymizes the addresses in a pcapng or pcap file, for sharing it.
+    /// IP addresses on the same network are kept on the same network.
     Anonymize {
         /// File to read from
         #[clap(short = 'i', long)]
@@ -34,6 +710,18 @@ enum Command {
         /// File to write to
         #[clap(short = 'o', long)]
         output_file: PathBuf,
+        /// Cut packets off after their TCP or UDP headers, and leave out
+        /// embedded TLS keys.
+        #[clap(long)]
+        strip_payloads: bool,
+        /// Replace each IP address with a random one in the same scope,
+        /// rather than keeping which addresses share a network.
+        #[clap(long)]
+        random_addresses: bool,
+        /// Files anonymized with the same seed have their addresses mapped
+        /// the same way.
+        #[clap(long, default_value_t = anon_packets::DEFAULT_SEED)]
+        seed: u64,
     },
     /// Invokes a program with capture. Does not require root on Linux.
     Capture {
@@ -41,36 +729,505 @@ enum Command {
         #[clap(short = 'o', long)]
         output_file: PathBuf,
 
+        #[clap(flatten)]
+        capture: CaptureArgs,
+
+        /// Do not write the TLS keys into the capture file. Without a key
+        /// log file, this means the capture cannot be decrypted later.
+        #[clap(long)]
+        no_embed_keys: bool,
+
+        /// Decode the traffic as it is captured, and comment on the packets
+        /// in the file where each request and response starts.
+        #[clap(long)]
+        annotate: bool,
+
         /// Arguments for the program to invoke.
         #[clap(num_args = 0..)]
         args: Vec<String>,
     },
     /// Serves a devtools server while capturing packets
     CaptureDevtools {
+        #[clap(flatten)]
+        capture: CaptureArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+
+        #[clap(flatten)]
+        record: RecordArgs,
+
         /// Arguments for the program to invoke.
         #[clap(num_args = 0..)]
         args: Vec<String>,
     },
+    /// Runs a program in a network namespace of its own with its TLS keys
+    /// logged, and serves a devtools server on everything it sends and
+    /// receives, carrying on after it exits until Ctrl-C. E.g.
+    /// `clipper run -- curl https://example.com`.
+    Run {
+        #[clap(flatten)]
+        capture: CaptureArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+
+        #[clap(flatten)]
+        record: RecordArgs,
+
+        /// The program to run and its arguments.
+        #[clap(required = true, num_args = 1..)]
+        args: Vec<String>,
+    },
+    /// Captures everything on one or more network interfaces, without
+    /// starting a program. Needs root or CAP_NET_RAW, on macOS access to
+    /// /dev/bpf*, and on Windows Npcap and clipper built with the `npcap`
+    /// feature. Keys come from `--keylog-file`, `--keylog-listen` or
+    /// `--load-keys`.
+    CaptureInterface {
+        /// Interface to capture on, e.g. `eth0`, or on Windows its device
+        /// name, GUID or adapter description. May be repeated, to capture on
+        /// several at once, merged in order of time.
+        #[clap(short = 'i', long = "interface", required = true)]
+        interfaces: Vec<String>,
+
+        /// Also capture packets addressed to other machines, e.g. on a
+        /// mirror port.
+        #[clap(long)]
+        promiscuous: bool,
+
+        #[clap(flatten)]
+        ring: RingArgs,
+
+        #[clap(flatten)]
+        xdp: XdpArgs,
+
+        /// Network namespace the interfaces are in, e.g. a container's: a
+        /// process ID to use that process's, a name from `ip netns`, or a
+        /// path such as `/proc/PID/ns/net`. Needs root. Linux only.
+        #[clap(long)]
+        netns: Option<String>,
+
+        /// File to write a pcapng to. Without this, serves a devtools
+        /// server instead. The file gets the packets as they are, so this
+        /// can't be given with the body policy, decoding or recording
+        /// options.
+        #[clap(
+            short = 'o',
+            long,
+            conflicts_with_all = ["BodyPolicyArgs", "DecodeArgs", "RecordArgs"]
+        )]
+        output_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        capture: CaptureArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+
+        #[clap(flatten)]
+        record: RecordArgs,
+
+        /// Do not write the TLS keys into the capture file.
+        #[clap(long, requires = "output_file")]
+        no_embed_keys: bool,
+
+        /// Decode the traffic as it is captured, and comment on the packets
+        /// in the file where each request and response starts.
+        #[clap(long, requires = "output_file")]
+        annotate: bool,
+    },
+    /// Captures on another machine by running tcpdump there over SSH, and
+    /// serves a devtools server on what it sees as it arrives. Needs
+    /// tcpdump on that machine, and root there or `--sudo`, and keys from
+    /// `--load-keys`.
+    RemoteCapture {
+        /// Machine to capture on, as given to ssh, e.g. `user@server`.
+        host: String,
+
+        /// Interface to capture on there, e.g. `eth0` or `any`. tcpdump
+        /// picks one if not given.
+        #[clap(short = 'i', long)]
+        interface: Option<String>,
+
+        /// Run tcpdump with sudo, which must not ask for a password.
+        #[clap(long)]
+        sudo: bool,
+
+        /// Extra argument for ssh, e.g. `--ssh-arg=-p2222`. May be repeated.
+        #[clap(long = "ssh-arg", allow_hyphen_values = true)]
+        ssh_args: Vec<String>,
+
+        /// Only capture packets matching this tcpdump-style filter. It is
+        /// applied on the remote machine.
+        #[clap(long)]
+        filter: Option<CaptureFilter>,
+
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Captures on an Android device or emulator by running tcpdump there
+    /// over adb, and serves a devtools server on what it sees as it arrives.
+    /// Needs tcpdump on the device, and root there or `--su`.
+    AdbCapture {
+        /// Serial of the device, as `adb devices` lists them. Not needed if
+        /// only one is connected.
+        #[clap(short = 's', long)]
+        serial: Option<String>,
+
+        /// Interface to capture on there, e.g. `wlan0` or `any`. tcpdump
+        /// picks one if not given.
+        #[clap(short = 'i', long)]
+        interface: Option<String>,
+
+        /// Run tcpdump with `su -c`, as on phones rooted with Magisk.
+        #[clap(long)]
+        su: bool,
+
+        /// tcpdump on the device, e.g.
+        /// `/data/data/com.termux/files/usr/bin/tcpdump`.
+        #[clap(long, default_value = "tcpdump")]
+        tcpdump: String,
+
+        /// Package of a debuggable app to set SSLKEYLOGFILE for, through
+        /// its `wrap.` property, and take keys from while it runs. Only
+        /// apps with a TLS library that writes key logs write one. The app
+        /// is stopped, to be started again under the new property.
+        #[clap(long)]
+        app: Option<String>,
+
+        /// Only capture packets matching this tcpdump-style filter. It is
+        /// applied on the device.
+        #[clap(long)]
+        filter: Option<CaptureFilter>,
+
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Captures in a Kubernetes pod by adding an ephemeral debug container
+    /// to it that runs tcpdump, and serves a devtools server on what it sees
+    /// as it arrives. The container stays in the pod, stopped, once done.
+    K8s {
+        /// Pod to capture in.
+        pod: String,
+
+        /// Namespace of the pod.
+        #[clap(short = 'n', long)]
+        namespace: Option<String>,
+
+        /// kubeconfig context to use.
+        #[clap(long)]
+        context: Option<String>,
+
+        /// Container of the pod whose processes the debug container should
+        /// see.
+        #[clap(long)]
+        target: Option<String>,
+
+        /// Image of the debug container, which needs tcpdump and a shell.
+        #[clap(long, default_value = libclipper::k8s::DEFAULT_IMAGE)]
+        image: String,
+
+        /// Interface to capture on in the pod. tcpdump picks one if not
+        /// given.
+        #[clap(short = 'i', long)]
+        interface: Option<String>,
+
+        /// Only capture packets matching this tcpdump-style filter. It is
+        /// applied in the pod.
+        #[clap(long)]
+        filter: Option<CaptureFilter>,
+
+        #[clap(flatten)]
+        keys: KeyFileArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Takes over a running capture from another clipper started with
+    /// `--handoff-socket`. Connections already open are not decoded.
+    ///
+    /// Key sources and the schedule are not handed over, so pass them again
+    /// to keep using them.
+    Resume {
+        /// Handoff socket of the clipper to take over from.
+        #[clap(long)]
+        from: PathBuf,
+
+        /// File to write a pcapng to. Without this, serves a devtools
+        /// server instead. The file gets the packets as they are, so this
+        /// can't be given with the body policy, decoding or recording
+        /// options.
+        #[clap(
+            short = 'o',
+            long,
+            conflicts_with_all = ["BodyPolicyArgs", "DecodeArgs", "RecordArgs"]
+        )]
+        output_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        capture: CaptureArgs,
+
+        #[clap(flatten)]
+        bodies: BodyPolicyArgs,
+
+        #[clap(flatten)]
+        decode: DecodeArgs,
+
+        #[clap(flatten)]
+        record: RecordArgs,
+
+        /// Do not write the TLS keys into the capture file.
+        #[clap(long, requires = "output_file")]
+        no_embed_keys: bool,
+
+        /// Decode the traffic as it is captured, and comment on the packets
+        /// in the file where each request and response starts.
+        #[clap(long, requires = "output_file")]
+        annotate: bool,
+    },
 }
 
-fn do_dump_pcap(file: PathBuf) -> Result<(), Error> {
+fn dump_pipeline(plugins: &[PathBuf]) -> Result<Pipeline, Error> {
     let key_db = Arc::new(RwLock::new(KeyDB::default()));
-    let mut chomper = net_decode::chomper(DebugListener {}, key_db.clone());
+    let mut pipeline = Pipeline::new().tls(key_db).http();
+    if !plugins.is_empty() {
+        let mut registry = PluginRegistry::new(DebugListener {});
+        for path in plugins {
+            registry.register(Box::new(WasmPlugin::load_file(path)?))?;
+        }
+        pipeline = pipeline.plugins(registry);
+    }
+    Ok(pipeline)
+}
 
-    chomp::dump_pcap_file(file, &mut chomper)?;
+fn do_dump_pcap(
+    file: PathBuf,
+    filter: Option<CaptureFilter>,
+    slice: CaptureSlice,
+    flow: Option<u32>,
+    plugins: Vec<PathBuf>,
+    workers: usize,
+) -> Result<(), Error> {
+    if workers > 1 && slice.from.is_some() {
+        // The workers decode behind the reader, so they can't be told when
+        // the warm-up before the start is over.
+        return Err("--from can't be used with --workers".into());
+    }
+    let first = dump_pipeline(&plugins)?;
+    let gate = ReplayGate::default();
+    let dump = |chomper: &mut dyn FrameChomper| -> Result<(), Error> {
+        match flow {
+            Some(flow) => IndexedCapture::open(&file)?.dump_flow(flow, chomper),
+            None => capture_slice::dump_pcap_file(file, slice, filter, gate.clone(), chomper),
+        }
+    };
+    #[cfg(feature = "stage-timing")]
+    let times = StageTimes::default();
+
+    if workers > 1 {
+        // Each worker has its own keys and plugin instances.
+        let mut pipelines = vec![first];
+        for _ in 1..workers {
+            pipelines.push(dump_pipeline(&plugins)?);
+        }
+        #[cfg(feature = "stage-timing")]
+        let pipelines = pipelines
+            .into_iter()
+            .map(|p| p.stage_timing(times.clone()))
+            .collect();
+        let mut chomper =
+            ParallelChomper::from_pipelines(pipelines, DEFAULT_QUEUE_FRAMES, DebugListener {});
+        dump(&mut chomper)?;
+        chomper.finish()?;
+    } else {
+        #[cfg(feature = "stage-timing")]
+        let first = first.stage_timing(times.clone());
+        let mut chomper = first.replay_gate(gate.clone()).build(DebugListener {});
+        #[cfg(feature = "stage-timing")]
+        let mut chomper = Timed::new(times.clone(), Stage::Frames, chomper);
+        dump(&mut chomper)?;
+    }
+    #[cfg(feature = "stage-timing")]
+    eprint!("{}", times.report());
+    Ok(())
+}
+
+fn do_index(file: PathBuf, list_flows: bool) -> Result<(), Error> {
+    let index = CaptureIndex::open(&file)?;
+    if list_flows {
+        let mut requests = vec![0; index.flows.len()];
+        for request in &index.requests {
+            requests[request.flow as usize] += 1;
+        }
+        for (id, flow) in index.flows.iter().enumerate() {
+            let proto = match flow.proto {
+                IPPROTO_TCP => "tcp".to_string(),
+                IPPROTO_UDP => "udp".to_string(),
+                IPPROTO_SCTP => "sctp".to_string(),
+                other => other.to_string(),
+            };
+            println!(
+                "{id}\t{proto}\t{:?}\tpackets {}-{}\t{} packets\t{} bytes\t{} requests",
+                flow.target,
+                flow.first_packet,
+                flow.last_packet,
+                flow.packets,
+                flow.bytes,
+                requests[id],
+            );
+        }
+    }
+    tracing::info!(
+        "{} packets in {} flows, indexed in {:?}",
+        index.packets.len(),
+        index.flows.len(),
+        net_decode::capture_index::index_path(&file)
+    );
     Ok(())
 }
 
-fn do_devtools_server(file: PathBuf) -> Result<(), Error> {
+fn do_devtools_server(
+    file: PathBuf,
+    merge: Vec<MergeInput>,
+    filter: Option<CaptureFilter>,
+    slice: CaptureSlice,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?;
 
-    rt.block_on(do_devtools_server_inner(file))
+    match open_stream(&file)? {
+        Some(_) if !merge.is_empty() => {
+            Err("--merge needs capture files, not stdin or a pipe".into())
+        }
+        Some(_) if !slice.is_whole() => {
+            Err("--from and --to need capture files, not stdin or a pipe".into())
+        }
+        Some(stream) => rt.block_on(do_devtools_stream_inner(
+            stream,
+            filter,
+            None,
+            body_policies,
+            decode_options,
+            key_db,
+        )),
+        None => rt.block_on(do_devtools_server_inner(
+            file,
+            merge,
+            filter,
+            slice,
+            body_policies,
+            decode_options,
+            key_db,
+        )),
+    }
+}
+
+/// Opens `file` if it is stdin (`-`) or a named pipe, which have to be
+/// decoded as they are written rather than read to the end first.
+fn open_stream(file: &Path) -> Result<Option<Box<dyn io::Read + Send>>, Error> {
+    if file.as_os_str() == "-" {
+        return Ok(Some(Box::new(io::stdin())));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if std::fs::metadata(file)?.file_type().is_fifo() {
+            let f = std::fs::File::open(file)?;
+            return Ok(Some(Box::new(io::BufReader::new(f))));
+        }
+    }
+    Ok(None)
 }
 
-fn do_anonymize(input_file: PathBuf, output_file: PathBuf) -> Result<(), Error> {
-    use std::{fs, io};
+fn do_remote_capture(
+    remote: RemoteCapture,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    rt.block_on(libclipper::remote::do_remote_capture_to_devtools(
+        remote,
+        body_policies,
+        decode_options,
+        key_db,
+    ))
+}
+
+fn do_adb_capture(
+    adb: AdbCapture,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    rt.block_on(libclipper::adb::do_adb_capture_to_devtools(
+        adb,
+        body_policies,
+        decode_options,
+        key_db,
+    ))
+}
+
+fn do_k8s_capture(
+    k8s: K8sCapture,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    rt.block_on(libclipper::k8s::do_k8s_capture_to_devtools(
+        k8s,
+        body_policies,
+        decode_options,
+        key_db,
+    ))
+}
+
+fn do_anonymize(
+    input_file: PathBuf,
+    output_file: PathBuf,
+    options: &AnonymizeOptions,
+) -> Result<(), Error> {
+    use std::fs;
     let mut reader = io::BufReader::new(fs::OpenOptions::new().read(true).open(input_file)?);
     let mut writer = io::BufWriter::new(
         fs::OpenOptions::new()
@@ -80,7 +1237,7 @@ fn do_anonymize(input_file: PathBuf, output_file: PathBuf) -> Result<(), Error>
             .open(output_file)?,
     );
 
-    anon_packets::process_pcap(&mut reader, &mut writer)
+    anon_packets::process_pcap_with_options(&mut reader, &mut writer, options)
 }
 
 #[cfg(target_os = "linux")]
@@ -105,30 +1262,294 @@ fn main() -> Result<(), Error> {
     let args = Command::parse();
 
     match args {
-        Command::DumpPcap { file } => do_dump_pcap(file)?,
-        Command::DevtoolsServer { file } => do_devtools_server(file)?,
+        Command::DumpPcap {
+            file,
+            plugins,
+            workers,
+            filter,
+            slice,
+            flow,
+        } => do_dump_pcap(file, filter, slice.into_slice(), flow, plugins, workers)?,
+        Command::DevtoolsServer {
+            file,
+            merge,
+            filter,
+            slice,
+            keys,
+            bodies,
+            decode,
+        } => do_devtools_server(
+            file,
+            merge,
+            filter,
+            slice.into_slice(),
+            bodies.into_policies(),
+            decode.into_options(),
+            keys.into_key_db()?,
+        )?,
+        Command::Index { file, flows } => do_index(file, flows)?,
+        Command::Merge { files, output_file } => merge::do_merge(files, output_file)?,
+        Command::ExportCerts { file, output_dir } => {
+            libclipper::cert_export::do_export_certs(file, output_dir)?
+        }
+        Command::ExportPlaintext {
+            file,
+            output_file,
+            slice,
+            keys,
+        } => libclipper::plaintext_export::do_export_plaintext(
+            file,
+            slice.into_slice(),
+            output_file,
+            keys.into_key_db()?,
+        )?,
+        Command::Annotate {
+            file,
+            output_file,
+            keys,
+            decode,
+        } => libclipper::annotate::do_annotate_file(
+            file,
+            output_file,
+            keys.into_key_db()?,
+            decode.into_options(),
+        )?,
+        Command::LatencyHeatmap {
+            file,
+            output_file,
+            bucket_secs,
+        } => libclipper::latency_export::do_export_latency(file, output_file, bucket_secs)?,
+        Command::Coverage { file, json, keys } => {
+            libclipper::coverage::do_coverage_report(file, keys.into_key_db()?, json)?
+        }
+        Command::MissingKeys { file, json, keys } => {
+            libclipper::missing_keys::do_missing_keys_report(file, keys.into_key_db()?, json)?
+        }
+        Command::EmbedKeys {
+            input_file,
+            output_file,
+            keylog_files,
+            all_keys,
+        } => libclipper::key_embed::do_embed_keys(input_file, keylog_files, output_file, all_keys)?,
         Command::Anonymize {
             input_file,
             output_file,
-        } => do_anonymize(input_file, output_file)?,
-        #[cfg(not(target_os = "linux"))]
+            strip_payloads,
+            random_addresses,
+            seed,
+        } => do_anonymize(
+            input_file,
+            output_file,
+            &AnonymizeOptions {
+                prefix_preserving: !random_addresses,
+                strip_payloads,
+                seed,
+            },
+        )?,
+        Command::RemoteCapture {
+            host,
+            interface,
+            sudo,
+            ssh_args,
+            filter,
+            keys,
+            bodies,
+            decode,
+        } => do_remote_capture(
+            RemoteCapture {
+                host,
+                interface,
+                filter,
+                sudo,
+                ssh_args,
+            },
+            bodies.into_policies(),
+            decode.into_options(),
+            keys.into_key_db()?,
+        )?,
+        Command::AdbCapture {
+            serial,
+            interface,
+            su,
+            tcpdump,
+            app,
+            filter,
+            keys,
+            bodies,
+            decode,
+        } => do_adb_capture(
+            AdbCapture {
+                serial,
+                interface,
+                filter,
+                su,
+                tcpdump,
+                app,
+            },
+            bodies.into_policies(),
+            decode.into_options(),
+            keys.into_key_db()?,
+        )?,
+        Command::K8s {
+            pod,
+            namespace,
+            context,
+            target,
+            image,
+            interface,
+            filter,
+            keys,
+            bodies,
+            decode,
+        } => do_k8s_capture(
+            K8sCapture {
+                pod,
+                namespace,
+                context,
+                target,
+                image,
+                interface,
+                filter,
+            },
+            bodies.into_policies(),
+            decode.into_options(),
+            keys.into_key_db()?,
+        )?,
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "macos",
+            all(windows, feature = "npcap")
+        )))]
+        Command::Capture { .. }
+        | Command::CaptureDevtools { .. }
+        | Command::Run { .. }
+        | Command::CaptureInterface { .. }
+        | Command::Resume { .. } => {
+            eprintln!("Capture is currently only supported on Linux, macOS, and Windows with clipper built with the `npcap` feature. See https://github.com/lf-/clipper/issues/10 for details");
+        }
+        #[cfg(any(target_os = "macos", all(windows, feature = "npcap")))]
+        Command::Capture { .. }
+        | Command::CaptureDevtools { .. }
+        | Command::Run { .. }
+        | Command::Resume { .. } => {
+            eprintln!("Capturing a program is currently only supported on Linux; on macOS and Windows, use capture-interface");
+        }
+        #[cfg(target_os = "linux")]
         Command::Capture {
-            args: _,
-            output_file: _,
+            args,
+            output_file,
+            capture,
+            no_embed_keys,
+            annotate,
+        } => libclipper::capture::do_capture_to_pcap(
+            output_file,
+            !no_embed_keys,
+            annotate,
+            capture.into_options()?,
+            fixup_args(args),
+        )?,
+        #[cfg(target_os = "linux")]
+        Command::CaptureDevtools {
+            args,
+            capture,
+            bodies,
+            decode,
+            record,
+        } => libclipper::capture::do_capture_to_devtools(
+            bodies.into_policies(),
+            decode.into_options(),
+            record.into_recording(),
+            capture.into_options()?,
+            fixup_args(args),
+        )?,
+        #[cfg(target_os = "linux")]
+        Command::Run {
+            args,
+            capture,
+            bodies,
+            decode,
+            record,
+        } => libclipper::capture::do_run(
+            bodies.into_policies(),
+            decode.into_options(),
+            record.into_recording(),
+            capture.into_options()?,
+            args,
+        )?,
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "macos",
+            all(windows, feature = "npcap")
+        ))]
+        Command::CaptureInterface {
+            interfaces,
+            promiscuous,
+            ring,
+            xdp,
+            netns,
+            output_file,
+            capture,
+            bodies,
+            decode,
+            record,
+            no_embed_keys,
+            annotate,
         } => {
-            eprintln!("Capture is currently only supported on Linux. See https://github.com/lf-/clipper/issues/10 for details");
+            let interfaces = interface_options(interfaces, promiscuous, ring, xdp, netns)?;
+            match output_file {
+                Some(output_file) => libclipper::capture::do_capture_interface_to_pcap(
+                    interfaces,
+                    output_file,
+                    !no_embed_keys,
+                    annotate,
+                    capture.into_options()?,
+                )?,
+                None => libclipper::capture::do_capture_interface_to_devtools(
+                    interfaces,
+                    bodies.into_policies(),
+                    decode.into_options(),
+                    record.into_recording(),
+                    capture.into_options()?,
+                )?,
+            }
         }
         #[cfg(target_os = "linux")]
-        Command::Capture { args, output_file } => {
-            libclipper::capture::do_capture_to_pcap(output_file, fixup_args(args))?
-        }
-        #[cfg(not(target_os = "linux"))]
-        Command::CaptureDevtools { args: _ } => {
-            eprintln!("Capture is currently only supported on Linux. See https://github.com/lf-/clipper/issues/10 for details");
+        Command::Resume {
+            from,
+            output_file: Some(output_file),
+            capture,
+            bodies: _,
+            decode: _,
+            record: _,
+            no_embed_keys,
+            annotate,
+        } => {
+            libclipper::capture::do_resume_to_pcap(
+                from,
+                output_file,
+                !no_embed_keys,
+                annotate,
+                capture.into_options()?,
+            )?;
         }
         #[cfg(target_os = "linux")]
-        Command::CaptureDevtools { args } => {
-            libclipper::capture::do_capture_to_devtools(fixup_args(args))?
+        Command::Resume {
+            from,
+            output_file: None,
+            capture,
+            bodies,
+            decode,
+            record,
+            no_embed_keys: _,
+            annotate: _,
+        } => {
+            libclipper::capture::do_resume_to_devtools(
+                from,
+                bodies.into_policies(),
+                decode.into_options(),
+                record.into_recording(),
+                capture.into_options()?,
+            )?;
         }
     }
     Ok(())
@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Hooks to pull keys out of BoringSSL.
+//!
+//! BoringSSL has the same keylog callback as OpenSSL, but it is hardly ever
+//! a library of its own: Chrome and its derivatives, Envoy and gRPC's C core
+//! all link it in statically, into the executable or into a library of
+//! their own such as `libgrpc.so`. So it is looked for by its symbols in
+//! every module, and each copy that is found gets its `SSL_new` hooked to
+//! set the callback, like OpenSSL's.
+//!
+//! Modules are looked through again whenever a library is loaded, since
+//! the copy is often in a plugin or a language binding that is opened
+//! late.
+//!
+//! That needs the symbols to be there, which they are not in stripped
+//! release builds such as Chrome's. Chrome writes `SSLKEYLOGFILE` by itself
+//! though.
+
+use std::{marker::FnPtr, ptr, sync::OnceLock};
+
+use frida_gum::{Module, NativePointer};
+use libc::c_void;
+
+use super::{
+    openssl::{keylog_callback, SSL_CTX_keylog_cb_func, SSL, SSL_CTX},
+    to_libname, ApplicabilityContext, HookApplicability, HookService, Hooks,
+};
+
+/// Only in BoringSSL, to tell it apart from OpenSSL.
+const MARKER_SYM: &str = "SSL_CTX_set_custom_verify";
+
+type SSL_new_func = unsafe extern "C" fn(SSL_CTX) -> SSL;
+type SSL_CTX_set_keylog_callback_func = unsafe extern "C" fn(SSL_CTX, SSL_CTX_keylog_cb_func);
+
+/// One copy of BoringSSL that has been hooked.
+struct Instance {
+    module: String,
+    SSL_new: SSL_new_func,
+    SSL_CTX_set_keylog_callback: SSL_CTX_set_keylog_callback_func,
+}
+
+/// Most copies of BoringSSL that are hooked in one process. Each needs its
+/// own `SSL_new_wrap` to know which it is.
+const MAX_INSTANCES: usize = 4;
+
+static INSTANCES: [OnceLock<Instance>; MAX_INSTANCES] = [
+    OnceLock::new(),
+    OnceLock::new(),
+    OnceLock::new(),
+    OnceLock::new(),
+];
+
+unsafe extern "C" fn SSL_new_wrap<const N: usize>(ctx: SSL_CTX) -> SSL {
+    // The instance is set before the hook goes live, so this should not
+    // happen; but there is no original to call without it, and panicking
+    // would take the whole process down.
+    let Some(instance) = INSTANCES[N].get() else {
+        tracing::error!("BoringSSL instance {N} missing");
+        return SSL(ptr::null_mut());
+    };
+    (instance.SSL_CTX_set_keylog_callback)(ctx, keylog_callback);
+    (instance.SSL_new)(ctx)
+}
+
+static WRAPPERS: [SSL_new_func; MAX_INSTANCES] = [
+    SSL_new_wrap::<0>,
+    SSL_new_wrap::<1>,
+    SSL_new_wrap::<2>,
+    SSL_new_wrap::<3>,
+];
+
+/// Finds `name` in `module`, whether it is exported or not.
+fn find_function(module: &str, name: &str) -> Option<NativePointer> {
+    Module::find_export_by_name(Some(module), name)
+        .or_else(|| Module::find_symbol_by_name(module, name))
+}
+
+/// Modules that have a copy of BoringSSL in them. A `libssl` is left to
+/// the OpenSSL hooks, which work just as well on a BoringSSL one.
+fn modules_with_boringssl<'a>(
+    context: &ApplicabilityContext<'a>,
+) -> impl Iterator<Item = &'a str> + 'a {
+    let modules = context.modules;
+    modules
+        .iter()
+        .map(|m| m.name.as_str())
+        .filter(|name| to_libname(name) != Some("ssl"))
+        .filter(|name| find_function(name, MARKER_SYM).is_some())
+}
+
+struct BoringSSLPresent;
+
+impl HookApplicability for BoringSSLPresent {
+    fn is_applicable(&self, context: ApplicabilityContext<'_>) -> bool {
+        modules_with_boringssl(&context).next().is_some()
+    }
+}
+
+pub struct BoringSSLHooks {}
+
+impl Hooks for BoringSSLHooks {
+    fn applicability(&self) -> &'static dyn HookApplicability {
+        &BoringSSLPresent
+    }
+
+    fn name(&self) -> &'static str {
+        "boringssl"
+    }
+
+    fn rerun_on_load(&self) -> bool {
+        true
+    }
+
+    unsafe fn apply(&self, hook_service: &mut HookService, context: ApplicabilityContext<'_>) {
+        let mut slots = INSTANCES
+            .iter()
+            .zip(WRAPPERS)
+            .filter(|(i, _)| i.get().is_none());
+        for module in modules_with_boringssl(&context) {
+            let hooked = INSTANCES
+                .iter()
+                .filter_map(OnceLock::get)
+                .any(|i| i.module == module);
+            if hooked {
+                continue;
+            }
+            let functions = find_function(module, "SSL_new")
+                .zip(find_function(module, "SSL_CTX_set_keylog_callback"));
+            let Some((ssl_new, set_keylog_callback)) = functions else {
+                tracing::debug!("BoringSSL in {module} is missing SSL_new or the keylog callback");
+                continue;
+            };
+            let Some((instance, wrapper)) = slots.next() else {
+                tracing::warn!(
+                    "too many copies of BoringSSL to hook, skipping the one in {module}"
+                );
+                continue;
+            };
+
+            // Another thread may call SSL_new as soon as the hook is live,
+            // so it only goes live once the instance is set.
+            hook_service.begin_transaction();
+            let orig = hook_service.raw_hook(ssl_new, NativePointer(wrapper.addr() as *mut c_void));
+            if let Ok(orig) = &orig {
+                let _ = instance.set(Instance {
+                    module: module.to_string(),
+                    SSL_new: super::transmute_same_size(*orig),
+                    SSL_CTX_set_keylog_callback: super::transmute_same_size(set_keylog_callback),
+                });
+            }
+            hook_service.end_transaction();
+            match orig {
+                Ok(_) => tracing::debug!("hooked BoringSSL in {module}"),
+                Err(e) => tracing::warn!("could not hook BoringSSL in {module}: {e}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Hooks to pull keys out of GnuTLS.
+//!
+//! GnuTLS has had a keylog callback per session since 3.6.13, which is set
+//! on each new session. It gets the label and secret but not the client
+//! random, which is asked of the session.
+//!
+//! `gnutls_init` gives every session GnuTLS's own keylog function, which
+//! writes to `SSLKEYLOGFILE` if that is set. Ours replaces it, so ours calls
+//! it in turn, and the file keeps getting written for anyone relying on it.
+//! It is the same function for every session, so it is only looked up once.
+
+use std::{
+    ffi::{c_char, c_int, c_uint, CStr},
+    sync::OnceLock,
+};
+
+use crate::log_target::LOG_TARGET;
+
+use super::{ApplicabilityContext, HookApplicability, HookService, Hooks, LibItem};
+
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+struct gnutls_session_t(*mut ());
+
+#[repr(C)]
+struct gnutls_datum_t {
+    data: *const u8,
+    size: c_uint,
+}
+
+impl gnutls_datum_t {
+    unsafe fn as_slice(&self) -> &[u8] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(self.data, self.size as usize)
+        }
+    }
+}
+
+type gnutls_keylog_func =
+    unsafe extern "C" fn(gnutls_session_t, *const c_char, *const gnutls_datum_t) -> c_int;
+
+// FIXME: same soname problem as in openssl.rs
+
+static gnutls_init: LibItem<unsafe extern "C" fn(*mut gnutls_session_t, c_uint) -> c_int> =
+    LibItem::new("libgnutls.so.30", "gnutls_init");
+static gnutls_session_set_keylog_function: LibItem<
+    unsafe extern "C" fn(gnutls_session_t, gnutls_keylog_func),
+> = LibItem::new("libgnutls.so.30", "gnutls_session_set_keylog_function");
+static gnutls_session_get_keylog_function: LibItem<
+    unsafe extern "C" fn(gnutls_session_t) -> Option<gnutls_keylog_func>,
+> = LibItem::new("libgnutls.so.30", "gnutls_session_get_keylog_function");
+static gnutls_session_get_random: LibItem<
+    unsafe extern "C" fn(gnutls_session_t, *mut gnutls_datum_t, *mut gnutls_datum_t),
+> = LibItem::new("libgnutls.so.30", "gnutls_session_get_random");
+
+/// The keylog function GnuTLS set up the sessions with, if any.
+static PREVIOUS_KEYLOG_FUNCTION: OnceLock<Option<gnutls_keylog_func>> = OnceLock::new();
+
+unsafe extern "C" fn keylog_function(
+    session: gnutls_session_t,
+    label: *const c_char,
+    secret: *const gnutls_datum_t,
+) -> c_int {
+    let ret = match PREVIOUS_KEYLOG_FUNCTION.get() {
+        Some(Some(previous)) => previous(session, label, secret),
+        _ => 0,
+    };
+    let Ok(label) = CStr::from_ptr(label).to_str() else {
+        return ret;
+    };
+
+    let mut client_random = gnutls_datum_t {
+        data: std::ptr::null(),
+        size: 0,
+    };
+    let mut server_random = gnutls_datum_t {
+        data: std::ptr::null(),
+        size: 0,
+    };
+    gnutls_session_get_random(session, &mut client_random, &mut server_random);
+
+    LOG_TARGET
+        .get()
+        .unwrap()
+        .log(label, client_random.as_slice(), (*secret).as_slice());
+    ret
+}
+
+unsafe extern "C" fn gnutls_init_wrap(session: *mut gnutls_session_t, flags: c_uint) -> c_int {
+    let ret = gnutls_init(session, flags);
+    if ret == 0 {
+        PREVIOUS_KEYLOG_FUNCTION.get_or_init(|| gnutls_session_get_keylog_function(*session));
+        gnutls_session_set_keylog_function(*session, keylog_function);
+    }
+    ret
+}
+
+pub struct GnuTLSHooks {}
+
+impl Hooks for GnuTLSHooks {
+    fn applicability(&self) -> &'static dyn HookApplicability {
+        &super::applicability::LibName("gnutls")
+    }
+
+    fn name(&self) -> &'static str {
+        "gnutls"
+    }
+
+    unsafe fn apply(&self, hook_service: &mut HookService, _context: ApplicabilityContext<'_>) {
+        let Ok(()) = hook_service.find_export(&gnutls_session_set_keylog_function) else {
+            tracing::warn!("GnuTLS is older than 3.6.13, which added the keylog function");
+            return;
+        };
+        if let Err(e) = hook_service.find_export(&gnutls_session_get_random) {
+            tracing::error!(
+                "could not find gnutls_session_get_random in GnuTLS, not hooking it: {e}"
+            );
+            return;
+        }
+        let Ok(()) = hook_service.find_export(&gnutls_session_get_keylog_function) else {
+            tracing::warn!("GnuTLS has no gnutls_session_get_keylog_function, not hooking it");
+            return;
+        };
+        if let Err(e) = hook_service.hook_export(&gnutls_init, gnutls_init_wrap as _) {
+            tracing::error!("could not hook gnutls_init in GnuTLS: {e}");
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Hooks to pull keys out of LibreSSL.
+//!
+//! LibreSSL is the libssl on the BSDs and gets bundled by some tools
+//! elsewhere. It has `SSL_CTX_set_keylog_callback` for compatibility, but
+//! never calls the callback, so the OpenSSL approach does not work. Instead,
+//! the handshake functions are hooked, and once a handshake is done the
+//! master key is read off the session and logged as `CLIENT_RANDOM`.
+//!
+//! That only covers TLS 1.2 and older: LibreSSL has no public way to get at
+//! the TLS 1.3 traffic secrets.
+
+use std::{ffi::c_int, marker::FnPtr, sync::OnceLock};
+
+use frida_gum::{Module, NativePointer};
+use libc::{c_void, size_t};
+
+use crate::log_target::LOG_TARGET;
+
+use super::{
+    openssl::SSL, to_libname, ApplicabilityContext, HookApplicability, HookService, Hooks,
+};
+
+/// Only in LibreSSL, to tell its libssl apart from OpenSSL's.
+const MARKER_SYM: &str = "SSL_CTX_use_certificate_chain_mem";
+
+const TLS1_3_VERSION: c_int = 0x0304;
+const SSL3_RANDOM_SIZE: usize = 32;
+const SSL_MAX_MASTER_KEY_LENGTH: usize = 48;
+
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+struct SSL_SESSION(*mut ());
+
+type handshake_func = unsafe extern "C" fn(SSL) -> c_int;
+
+/// Functions that are called but not hooked.
+struct Functions {
+    SSL_version: unsafe extern "C" fn(SSL) -> c_int,
+    SSL_get_client_random: unsafe extern "C" fn(SSL, *mut u8, size_t) -> size_t,
+    SSL_get_session: unsafe extern "C" fn(SSL) -> SSL_SESSION,
+    SSL_SESSION_get_master_key: unsafe extern "C" fn(SSL_SESSION, *mut u8, size_t) -> size_t,
+}
+
+static FUNCTIONS: OnceLock<Functions> = OnceLock::new();
+
+static SSL_connect: OnceLock<handshake_func> = OnceLock::new();
+static SSL_accept: OnceLock<handshake_func> = OnceLock::new();
+static SSL_do_handshake: OnceLock<handshake_func> = OnceLock::new();
+
+/// Is the library `module` LibreSSL?
+pub(super) fn is_libressl(module: &str) -> bool {
+    Module::find_export_by_name(Some(module), MARKER_SYM).is_some()
+}
+
+/// The LibreSSL libssl that is loaded, if there is one.
+fn libressl_module<'a>(context: &ApplicabilityContext<'a>) -> Option<&'a str> {
+    context
+        .modules
+        .iter()
+        .map(|m| m.name.as_str())
+        .find(|name| to_libname(name) == Some("ssl") && is_libressl(name))
+}
+
+/// Logs the master key of `ssl`, which has just finished its handshake.
+unsafe fn log_master_key(ssl: SSL) {
+    let Some(f) = FUNCTIONS.get() else {
+        return;
+    };
+    if (f.SSL_version)(ssl) >= TLS1_3_VERSION {
+        tracing::debug!("LibreSSL TLS 1.3 connection, can't get its secrets");
+        return;
+    }
+
+    let session = (f.SSL_get_session)(ssl);
+    if session.0.is_null() {
+        return;
+    }
+
+    let mut client_random = [0u8; SSL3_RANDOM_SIZE];
+    let mut master_key = [0u8; SSL_MAX_MASTER_KEY_LENGTH];
+    let random_len =
+        (f.SSL_get_client_random)(ssl, client_random.as_mut_ptr(), client_random.len());
+    let key_len =
+        (f.SSL_SESSION_get_master_key)(session, master_key.as_mut_ptr(), master_key.len());
+    if random_len == 0 || key_len == 0 {
+        return;
+    }
+
+    LOG_TARGET.get().unwrap().log(
+        "CLIENT_RANDOM",
+        &client_random[..random_len],
+        &master_key[..key_len],
+    );
+}
+
+/// Calls the original handshake function, logging the key if the handshake
+/// finished. `SSL_do_handshake` and friends return 1 only then.
+unsafe fn handshake(orig: &OnceLock<handshake_func>, ssl: SSL) -> c_int {
+    let ret = (orig.get().expect("LibreSSL handshake function missing"))(ssl);
+    if ret == 1 {
+        log_master_key(ssl);
+    }
+    ret
+}
+
+unsafe extern "C" fn SSL_connect_wrap(ssl: SSL) -> c_int {
+    handshake(&SSL_connect, ssl)
+}
+
+unsafe extern "C" fn SSL_accept_wrap(ssl: SSL) -> c_int {
+    handshake(&SSL_accept, ssl)
+}
+
+unsafe extern "C" fn SSL_do_handshake_wrap(ssl: SSL) -> c_int {
+    handshake(&SSL_do_handshake, ssl)
+}
+
+struct LibreSSLPresent;
+
+impl HookApplicability for LibreSSLPresent {
+    fn is_applicable(&self, context: ApplicabilityContext<'_>) -> bool {
+        libressl_module(&context).is_some()
+    }
+}
+
+pub struct LibreSSLHooks {}
+
+impl Hooks for LibreSSLHooks {
+    fn applicability(&self) -> &'static dyn HookApplicability {
+        &LibreSSLPresent
+    }
+
+    fn name(&self) -> &'static str {
+        "libressl"
+    }
+
+    unsafe fn apply(&self, hook_service: &mut HookService, context: ApplicabilityContext<'_>) {
+        let Some(module) = libressl_module(&context) else {
+            tracing::error!("LibreSSL went away before it could be hooked");
+            return;
+        };
+        let find = |name| {
+            let found = Module::find_export_by_name(Some(module), name);
+            if found.is_none() {
+                tracing::error!("LibreSSL in {module} is missing {name}, not hooking it");
+            }
+            found
+        };
+
+        let Some(functions) = (|| {
+            Some(Functions {
+                SSL_version: super::transmute_same_size(find("SSL_version")?),
+                SSL_get_client_random: super::transmute_same_size(find("SSL_get_client_random")?),
+                SSL_get_session: super::transmute_same_size(find("SSL_get_session")?),
+                SSL_SESSION_get_master_key: super::transmute_same_size(find(
+                    "SSL_SESSION_get_master_key",
+                )?),
+            })
+        })() else {
+            return;
+        };
+        let hooks: [(&str, &OnceLock<handshake_func>, handshake_func); 3] = [
+            ("SSL_connect", &SSL_connect, SSL_connect_wrap),
+            ("SSL_accept", &SSL_accept, SSL_accept_wrap),
+            ("SSL_do_handshake", &SSL_do_handshake, SSL_do_handshake_wrap),
+        ];
+        let Some(targets) = hooks
+            .iter()
+            .map(|&(name, _, _)| find(name))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return;
+        };
+        let _ = FUNCTIONS.set(functions);
+
+        // A handshake may start on another thread as soon as a hook is live,
+        // so the hooks only go live once the originals are set.
+        hook_service.begin_transaction();
+        for ((name, orig, wrapper), target) in hooks.into_iter().zip(targets) {
+            match hook_service.raw_hook(target, NativePointer(wrapper.addr() as *mut c_void)) {
+                Ok(hooked) => {
+                    let _ = orig.set(super::transmute_same_size(hooked));
+                }
+                Err(e) => tracing::error!("could not hook {name} in LibreSSL in {module}: {e}"),
+            }
+        }
+        hook_service.end_transaction();
+        tracing::debug!("hooked LibreSSL in {module}");
+    }
+}
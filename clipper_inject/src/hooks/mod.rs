@@ -11,7 +11,11 @@
 //! the interesting function in question is using dynamic binding, which
 //! cannot be assumed.
 
+mod boringssl;
 mod dlopen;
+mod gnutls;
+mod libressl;
+mod nss;
 mod openssl;
 mod rustls;
 
@@ -152,6 +156,17 @@ impl<'a> HookService<'a> {
             .replace(fun, redirect_to, NativePointer(ptr::null_mut()))?)
     }
 
+    /// Holds off making the hooks that follow live until
+    /// [`HookService::end_transaction`], so that what they need can be set
+    /// up first.
+    pub unsafe fn begin_transaction(&mut self) {
+        self.interceptor.begin_transaction();
+    }
+
+    pub unsafe fn end_transaction(&mut self) {
+        self.interceptor.end_transaction();
+    }
+
     pub unsafe fn init_hooks(&mut self) {
         // FIXME: list of disabled hooks
 
@@ -170,7 +185,7 @@ impl<'a> HookService<'a> {
 
         for &hook in HOOKS {
             let name = hook.name();
-            if self.applied.contains(name) {
+            if self.applied.contains(name) && !hook.rerun_on_load() {
                 tracing::debug!("already applied: {}", name);
                 continue;
             }
@@ -264,6 +279,13 @@ pub trait Hooks: Send + Sync {
     /// Name used for disabling this particular hook
     fn name(&self) -> &'static str;
 
+    /// Whether to apply the hook again whenever a library is loaded, even
+    /// once it has been applied, for hooks that look through every module
+    /// rather than for one library.
+    fn rerun_on_load(&self) -> bool {
+        false
+    }
+
     /// Applies the hook
     unsafe fn apply(&self, hook_service: &mut HookService, context: ApplicabilityContext<'_>);
 }
@@ -280,5 +302,9 @@ fn to_libname(name: &str) -> Option<&str> {
 static HOOKS: &[&dyn Hooks] = &[
     &dlopen::DlopenHook,
     &openssl::OpenSSLHooks {},
+    &boringssl::BoringSSLHooks {},
+    &libressl::LibreSSLHooks {},
+    &gnutls::GnuTLSHooks {},
+    &nss::NSSHooks {},
     &rustls::RustlsHooks {},
 ];
@@ -0,0 +1,92 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Hooks to pull keys out of NSS, as used by Firefox and Thunderbird.
+//!
+//! NSS has no keylog callback; the only thing it offers that has both the
+//! client random and the secrets is its own writing of `SSLKEYLOGFILE`. So
+//! when it asks NSPR for that variable, it gets the path of a pipe that is
+//! read on a thread of our own and sent on to the log target like any other
+//! key, whether or not the variable was set.
+//!
+//! This works as long as NSS was built with keylog support, which Firefox's
+//! and most distributions' builds are.
+
+use std::{
+    ffi::{c_char, CStr, CString},
+    fs::File,
+    io::{BufRead, BufReader},
+    os::fd::FromRawFd,
+    sync::OnceLock,
+};
+
+use libc::c_int;
+
+use crate::log_target::log_keylog_line;
+
+use super::{ApplicabilityContext, HookApplicability, HookService, Hooks, LibItem};
+
+// NSPR is in libnspr4 on distributions but is folded into libnss3 by
+// Firefox, so this is found wherever it is.
+static PR_GetEnvSecure: LibItem<unsafe extern "C" fn(*const c_char) -> *mut c_char> =
+    LibItem::new_no_module("PR_GetEnvSecure");
+
+/// The path NSS gets for `SSLKEYLOGFILE`, or None if the pipe could not be
+/// made.
+static KEYLOG_PIPE: OnceLock<Option<CString>> = OnceLock::new();
+
+fn make_keylog_pipe() -> Option<CString> {
+    let mut fds: [c_int; 2] = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        tracing::warn!(
+            "could not make a pipe for NSS keys: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    let [read_fd, write_fd] = fds;
+
+    // SAFETY: the pipe was just made and nothing else has its read end.
+    let reader = BufReader::new(unsafe { File::from_raw_fd(read_fd) });
+    std::thread::Builder::new()
+        .name("clipper-nss".to_string())
+        .spawn(move || {
+            for line in reader.lines().map_while(Result::ok) {
+                log_keylog_line(&line);
+            }
+        })
+        .ok()?;
+
+    // The write end is kept open for as long as the process lives, for NSS
+    // to open it by this path whenever it likes.
+    CString::new(format!("/proc/self/fd/{write_fd}")).ok()
+}
+
+unsafe extern "C" fn PR_GetEnvSecure_wrap(var: *const c_char) -> *mut c_char {
+    if CStr::from_ptr(var).to_bytes() == b"SSLKEYLOGFILE" {
+        if let Some(path) = KEYLOG_PIPE.get_or_init(make_keylog_pipe) {
+            // NSS only reads it.
+            return path.as_ptr() as *mut c_char;
+        }
+    }
+    PR_GetEnvSecure(var)
+}
+
+pub struct NSSHooks {}
+
+impl Hooks for NSSHooks {
+    fn applicability(&self) -> &'static dyn HookApplicability {
+        &super::applicability::LibName("ssl3")
+    }
+
+    fn name(&self) -> &'static str {
+        "nss"
+    }
+
+    unsafe fn apply(&self, hook_service: &mut HookService, _context: ApplicabilityContext<'_>) {
+        hook_service
+            .hook_export(&PR_GetEnvSecure, PR_GetEnvSecure_wrap as _)
+            .unwrap();
+    }
+}
@@ -6,19 +6,22 @@
 
 use std::ffi::{c_char, CStr};
 
-use crate::log_target::LOG_TARGET;
+use crate::log_target::log_keylog_line;
 
-use super::{ApplicabilityContext, HookApplicability, HookService, Hooks, LibItem};
+use super::{
+    libressl::is_libressl, to_libname, ApplicabilityContext, HookApplicability, HookService, Hooks,
+    LibItem,
+};
 
 #[repr(transparent)]
 #[derive(Clone, Copy)]
-struct SSL(*mut ());
+pub(super) struct SSL(pub(super) *mut ());
 
 #[repr(transparent)]
 #[derive(Clone, Copy)]
-struct SSL_CTX(*mut ());
+pub(super) struct SSL_CTX(*mut ());
 
-type SSL_CTX_keylog_cb_func = unsafe extern "C" fn(SSL, *const c_char);
+pub(super) type SSL_CTX_keylog_cb_func = unsafe extern "C" fn(SSL, *const c_char);
 
 // FIXME: we need to be able to disregard sonames for this purpose, which
 // involves not inputting the libssl.so.3 name here. this needs to be passed in
@@ -29,21 +32,12 @@ static SSL_new: LibItem<unsafe extern "C" fn(SSL_CTX) -> SSL> =
 static SSL_CTX_set_keylog_callback: LibItem<unsafe extern "C" fn(SSL_CTX, SSL_CTX_keylog_cb_func)> =
     LibItem::new("libssl.so.3", "SSL_CTX_set_keylog_callback");
 
-unsafe extern "C" fn keylog_callback(_ssl: SSL, s: *const c_char) {
+/// Also used for libraries with the same keylog callback, like BoringSSL.
+pub(super) unsafe extern "C" fn keylog_callback(_ssl: SSL, s: *const c_char) {
     let s = unsafe { CStr::from_ptr(s) };
-    let _ = (move || {
-        let s = s.to_str().ok()?;
-        let mut s = s.split(' ');
-        let label = s.next()?;
-        let client_random = hex::decode(s.next()?).ok()?;
-        let secret = hex::decode(s.next()?).ok()?;
-
-        LOG_TARGET
-            .get()
-            .unwrap()
-            .log(label, &client_random, &secret);
-        Some(())
-    })();
+    if let Ok(s) = s.to_str() {
+        log_keylog_line(s);
+    }
 }
 
 unsafe extern "C" fn SSL_new_wrap(ctx: SSL_CTX) -> SSL {
@@ -51,11 +45,24 @@ unsafe extern "C" fn SSL_new_wrap(ctx: SSL_CTX) -> SSL {
     SSL_new(ctx)
 }
 
+/// A libssl that is not LibreSSL's, which has the keylog callback but never
+/// calls it.
+struct OpenSSLPresent;
+
+impl HookApplicability for OpenSSLPresent {
+    fn is_applicable(&self, context: ApplicabilityContext<'_>) -> bool {
+        context
+            .modules
+            .iter()
+            .any(|m| to_libname(&m.name) == Some("ssl") && !is_libressl(&m.name))
+    }
+}
+
 pub struct OpenSSLHooks {}
 
 impl Hooks for OpenSSLHooks {
     fn applicability(&self) -> &'static dyn HookApplicability {
-        &super::applicability::LibName("ssl")
+        &OpenSSLPresent
     }
 
     fn name(&self) -> &'static str {
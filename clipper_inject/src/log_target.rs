@@ -18,6 +18,23 @@ pub trait LogTarget: Send + Sync {
     fn log(&self, label: &str, client_random: &[u8], secret: &[u8]);
 }
 
+/// Logs a line in the SSLKEYLOGFILE format, ignoring it if it is not one,
+/// like a comment.
+pub fn log_keylog_line(line: &str) {
+    let _ = (move || {
+        let mut s = line.split(' ');
+        let label = s.next()?;
+        let client_random = hex::decode(s.next()?).ok()?;
+        let secret = hex::decode(s.next()?).ok()?;
+
+        LOG_TARGET
+            .get()
+            .unwrap()
+            .log(label, &client_random, &secret);
+        Some(())
+    })();
+}
+
 pub struct LogTargetStream<W: std::io::Write>(Mutex<W>);
 
 impl<W: std::io::Write> LogTargetStream<W> {
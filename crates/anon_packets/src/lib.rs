@@ -13,7 +13,7 @@ use std::{
 };
 
 use cidr::{Ipv4Cidr, Ipv6Cidr};
-use pcap_parser::{traits::PcapReaderIterator, ToVec};
+use pcap_parser::{traits::PcapReaderIterator, PcapBlockOwned, PcapError, ToVec};
 use pnet_base::MacAddr;
 use pnet_packet::{
     ethernet::{EtherType, EtherTypes},
@@ -290,15 +290,114 @@ impl Mapping<IpAddr> for IPScopeRemap {
     }
 }
 
+/// An IP address is mapped so that two addresses sharing their first n bits
+/// are mapped to two addresses sharing their first n bits, as in Crypto-PAn,
+/// so that which hosts are on the same network is kept. Each bit is flipped
+/// or not by a function of the bits before it and the seed, so the same seed
+/// maps an address the same way in every file.
+///
+/// Addresses in the private, example, link-local and multicast ranges keep
+/// the range's prefix, so they stay in the same scope. Loopback and
+/// broadcast addresses are kept as they are.
+struct IPPrefixRemap {
+    key: [u8; 15],
+}
+
+impl IPPrefixRemap {
+    fn new(seed: u64) -> Self {
+        let mut key = [0u8; 15];
+        ChaChaRng::seed_from_u64(seed).fill(&mut key);
+        Self { key }
+    }
+
+    /// Whether to flip the bit after the first `len` bits of `prefix`,
+    /// which are at the top of it.
+    fn flip(&self, prefix: u128, len: u32, v4: bool) -> bool {
+        let mut seed = [0u8; 32];
+        seed[..16].copy_from_slice(&prefix.to_be_bytes());
+        seed[16] = len as u8 | if v4 { 0x80 } else { 0 };
+        seed[17..].copy_from_slice(&self.key);
+        ChaChaRng::from_seed(seed).gen()
+    }
+
+    /// Remaps the bits of an address of `bits` bits at the top of `addr`,
+    /// other than the first `keep`.
+    fn remap_bits(&self, addr: u128, bits: u32, keep: u32, v4: bool) -> u128 {
+        let mut out = addr;
+        for i in keep..bits {
+            let prefix = addr & !(u128::MAX >> i);
+            if self.flip(prefix, i, v4) {
+                out ^= 1 << (127 - i);
+            }
+        }
+        out
+    }
+
+    /// How many leading bits of `addr` to keep so it stays in the same
+    /// scope, or None to keep all of them.
+    fn kept_bits_v4(addr: Ipv4Addr) -> Option<u8> {
+        let in_range = |ranges: &[Ipv4Cidr]| {
+            ranges
+                .iter()
+                .find(|r| r.contains(&addr))
+                .map(|r| r.network_length())
+        };
+        match IPScopeRemap::scope_for_v4(addr) {
+            IPScope::Public => Some(0),
+            IPScope::Example => in_range(EXAMPLE_RANGES_V4),
+            IPScope::Private => in_range(PRIVATE_RANGES_V4),
+            IPScope::LinkLocal => Some(LINK_LOCAL_RANGE_V4.network_length()),
+            IPScope::Multicast => Some(MULTICAST_RANGE_V4.network_length()),
+            IPScope::Broadcast | IPScope::Loopback => None,
+        }
+    }
+
+    fn kept_bits_v6(addr: Ipv6Addr) -> Option<u8> {
+        match IPScopeRemap::scope_for_v6(addr) {
+            IPScope::Public => Some(0),
+            IPScope::Example => Some(EXAMPLE_RANGE_V6.network_length()),
+            IPScope::Private => Some(PRIVATE_RANGE_V6.network_length()),
+            IPScope::LinkLocal => Some(LINK_LOCAL_RANGE_V6.network_length()),
+            IPScope::Multicast => Some(MULTICAST_RANGE_V6.network_length()),
+            IPScope::Broadcast | IPScope::Loopback => None,
+        }
+    }
+}
+
+impl Mapping<IpAddr> for IPPrefixRemap {
+    fn remap(&mut self, input: IpAddr) -> Option<IpAddr> {
+        match input {
+            IpAddr::V4(addr) => {
+                let Some(keep) = Self::kept_bits_v4(addr) else {
+                    return Some(input);
+                };
+                let bits = (u32::from(addr) as u128) << 96;
+                let new = self.remap_bits(bits, 32, keep as u32, true);
+                Some(IpAddr::V4(Ipv4Addr::from((new >> 96) as u32)))
+            }
+            IpAddr::V6(addr) => {
+                let Some(keep) = Self::kept_bits_v6(addr) else {
+                    return Some(input);
+                };
+                let new = self.remap_bits(u128::from(addr), 128, keep as u32, false);
+                Some(IpAddr::V6(Ipv6Addr::from(new)))
+            }
+        }
+    }
+}
+
+/// A MAC address is mapped to a random locally administered one, picked by
+/// the address and the seed, so the same seed maps an address the same way
+/// in every file.
 struct MacRandomRemap {
-    rng: ChaChaRng,
+    key: [u8; 26],
 }
 
 impl MacRandomRemap {
     fn new(seed: u64) -> Self {
-        Self {
-            rng: ChaChaRng::seed_from_u64(seed),
-        }
+        let mut key = [0u8; 26];
+        ChaChaRng::seed_from_u64(seed).fill(&mut key);
+        Self { key }
     }
 }
 
@@ -311,15 +410,19 @@ impl Mapping<MacAddr> for MacRandomRemap {
         const LOCALLY_ADMINISTERED: u8 = 1 << 1;
         const GROUP_ADDR: u8 = 1 << 0;
 
-        let oct1 =
-            (self.rng.gen::<u8>() & !GROUP_ADDR) | LOCALLY_ADMINISTERED | (input.0 & GROUP_ADDR);
+        let mut seed = [0u8; 32];
+        seed[..6].copy_from_slice(&[input.0, input.1, input.2, input.3, input.4, input.5]);
+        seed[6..].copy_from_slice(&self.key);
+        let mut rng = ChaChaRng::from_seed(seed);
+
+        let oct1 = (rng.gen::<u8>() & !GROUP_ADDR) | LOCALLY_ADMINISTERED | (input.0 & GROUP_ADDR);
         Some(MacAddr(
             oct1,
-            self.rng.gen(),
-            self.rng.gen(),
-            self.rng.gen(),
-            self.rng.gen(),
-            self.rng.gen(),
+            rng.gen(),
+            rng.gen(),
+            rng.gen(),
+            rng.gen(),
+            rng.gen(),
         ))
     }
 }
@@ -352,10 +455,42 @@ enum DropReason {
 
 const BANNED_PORTS: &'static [u16] = &[53];
 
+/// Seed used by [`AnonymizeOptions::default`].
+pub const DEFAULT_SEED: u64 = 1337;
+
+/// How to anonymize a capture.
+#[derive(Clone, Debug)]
+pub struct AnonymizeOptions {
+    /// Map IP addresses so that addresses on the same network stay on the
+    /// same network. Otherwise each is replaced with a random address in
+    /// the same scope, in the order they are seen.
+    pub prefix_preserving: bool,
+    /// Cut packets off after their TCP or UDP header, leaving out what was
+    /// sent. The original lengths are kept. Decryption secrets are left out
+    /// too, having nothing left to decrypt.
+    pub strip_payloads: bool,
+    /// Addresses are mapped the same way in every file anonymized with the
+    /// same seed, so that captures from one network can be compared. IP
+    /// addresses without `prefix_preserving` are only mapped the same way
+    /// if they are seen in the same order.
+    pub seed: u64,
+}
+
+impl Default for AnonymizeOptions {
+    fn default() -> Self {
+        AnonymizeOptions {
+            prefix_preserving: true,
+            strip_payloads: false,
+            seed: DEFAULT_SEED,
+        }
+    }
+}
+
 /// Deterministic (on the same input file) anonymizer for pcapng files.
 struct Anonymizer {
     ip_remap: Mapper<IpAddr>,
     mac_remap: Mapper<MacAddr>,
+    strip_payloads: bool,
 }
 
 impl Anonymizer {
@@ -387,7 +522,10 @@ impl Anonymizer {
             .ok_or_else(|| DropReason::FailedToRemapMac(addr))
     }
 
-    fn anonymize_l4_udp(&mut self, data: &mut [u8]) -> Result<(), DropReason> {
+    // The anonymize_* functions return the length of the headers they looked
+    // at, including those of the layers below, for cutting payloads off.
+
+    fn anonymize_l4_udp(&mut self, data: &mut [u8]) -> Result<usize, DropReason> {
         let udp =
             pnet_packet::udp::MutableUdpPacket::new(data).ok_or(DropReason::UdpParseFailed)?;
 
@@ -395,11 +533,12 @@ impl Anonymizer {
         {
             Err(DropReason::BannedProtocol)
         } else {
-            Ok(())
+            Ok(pnet_packet::udp::MutableUdpPacket::minimum_packet_size())
         }
     }
 
-    fn anonymize_l4_tcp(&mut self, data: &mut [u8]) -> Result<(), DropReason> {
+    fn anonymize_l4_tcp(&mut self, data: &mut [u8]) -> Result<usize, DropReason> {
+        let len = data.len();
         let tcp =
             pnet_packet::tcp::MutableTcpPacket::new(data).ok_or(DropReason::TcpParseFailed)?;
 
@@ -407,7 +546,7 @@ impl Anonymizer {
         {
             Err(DropReason::BannedProtocol)
         } else {
-            Ok(())
+            Ok((tcp.get_data_offset() as usize * 4).min(len))
         }
     }
 
@@ -415,7 +554,7 @@ impl Anonymizer {
         &mut self,
         proto: pnet_packet::ip::IpNextHeaderProtocol,
         data: &mut [u8],
-    ) -> Result<(), DropReason> {
+    ) -> Result<usize, DropReason> {
         // More specifically, drop protocols I don't want to implement yet.
         match proto {
             pnet_packet::ip::IpNextHeaderProtocols::Udp => self.anonymize_l4_udp(data),
@@ -429,7 +568,7 @@ impl Anonymizer {
         }
     }
 
-    fn anonymize_l3_ipv4(&mut self, data: &mut [u8]) -> Result<(), DropReason> {
+    fn anonymize_l3_ipv4(&mut self, data: &mut [u8]) -> Result<usize, DropReason> {
         let mut packet =
             pnet_packet::ipv4::MutableIpv4Packet::new(data).ok_or(DropReason::IpParseFailed)?;
 
@@ -442,10 +581,12 @@ impl Anonymizer {
         let checksum = pnet_packet::ipv4::checksum(&packet.to_immutable());
         packet.set_checksum(checksum);
 
-        self.anonymize_l4(packet.get_next_level_protocol(), packet.payload_mut())
+        let header_len = packet.get_header_length() as usize * 4;
+        let proto = packet.get_next_level_protocol();
+        Ok(header_len + self.anonymize_l4(proto, packet.payload_mut())?)
     }
 
-    fn anonymize_l3_ipv6(&mut self, data: &mut [u8]) -> Result<(), DropReason> {
+    fn anonymize_l3_ipv6(&mut self, data: &mut [u8]) -> Result<usize, DropReason> {
         let mut packet =
             pnet_packet::ipv6::MutableIpv6Packet::new(data).ok_or(DropReason::IpParseFailed)?;
 
@@ -457,10 +598,13 @@ impl Anonymizer {
 
         tracing::debug!(next_header = ?packet.get_next_header(), "v6");
 
-        self.anonymize_l4(packet.get_next_header(), packet.payload_mut())
+        let proto = packet.get_next_header();
+        Ok(pnet_packet::ipv6::MutableIpv6Packet::minimum_packet_size()
+            + self.anonymize_l4(proto, packet.payload_mut())?)
     }
 
-    fn anonymize_l3_arp(&mut self, data: &mut [u8]) -> Result<(), DropReason> {
+    fn anonymize_l3_arp(&mut self, data: &mut [u8]) -> Result<usize, DropReason> {
+        let len = data.len();
         let mut packet =
             pnet_packet::arp::MutableArpPacket::new(data).ok_or(DropReason::ArpParseFailed)?;
 
@@ -476,17 +620,18 @@ impl Anonymizer {
         let target_proto = packet.get_target_proto_addr();
         packet.set_target_proto_addr(self.remap_v4(target_proto)?);
 
-        Ok(())
+        // Nothing in it but headers
+        Ok(len)
     }
 
     /// Assumes that packet is ethernet (not necessarily always true; we would
     /// have to track interfaces to verify this)
-    fn anonymize_l2_ethernet(&mut self, packet: &mut [u8]) -> Result<(), DropReason> {
+    fn anonymize_l2_ethernet(&mut self, packet: &mut [u8]) -> Result<usize, DropReason> {
         let mut p = pnet_packet::ethernet::MutableEthernetPacket::new(packet)
             .ok_or(DropReason::EthernetParseFailed)?;
         tracing::debug!(ethertype = ?p.get_ethertype(), src = ?p.get_source(), dest = ?p.get_destination(), "ethernet");
 
-        match p.get_ethertype() {
+        let header_len = match p.get_ethertype() {
             EtherTypes::Ipv4 => self.anonymize_l3_ipv4(p.payload_mut()),
             EtherTypes::Ipv6 => self.anonymize_l3_ipv6(p.payload_mut()),
             EtherTypes::Arp => self.anonymize_l3_arp(p.payload_mut()),
@@ -506,24 +651,36 @@ impl Anonymizer {
         );
 
         // p.set_source();
-        Ok(())
+        Ok(pnet_packet::ethernet::MutableEthernetPacket::minimum_packet_size() + header_len)
+    }
+
+    /// Anonymized copy of an Ethernet frame, or None if it should be
+    /// dropped.
+    fn anonymize_frame(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut new_data = data.to_vec();
+        match self.anonymize_l2_ethernet(&mut new_data) {
+            Ok(header_len) => {
+                if self.strip_payloads {
+                    new_data.truncate(header_len);
+                }
+                Some(new_data)
+            }
+            Err(e) => {
+                tracing::warn!("Dropped packet: {e}");
+                None
+            }
+        }
     }
 
     fn anonymize<'a>(&mut self, block: pcap_parser::Block<'a>) -> Option<Vec<u8>> {
         match block {
             pcap_parser::Block::EnhancedPacket(mut epb) => {
-                let mut new_data = epb.data.to_vec();
-                match self.anonymize_l2_ethernet(&mut new_data) {
-                    Ok(()) => {
-                        epb.data = &new_data;
-                        epb.to_vec().ok()
-                    }
-                    Err(e) => {
-                        tracing::warn!("Dropped packet: {e}");
-                        None
-                    }
-                }
+                let new_data = self.anonymize_frame(epb.data)?;
+                epb.caplen = epb.caplen.min(new_data.len() as u32);
+                epb.data = &new_data;
+                epb.to_vec().ok()
             }
+            pcap_parser::Block::DecryptionSecrets(_) if self.strip_payloads => None,
             pcap_parser::Block::SimplePacket(_s) => {
                 tracing::warn!("Discarded simple packet block");
                 None
@@ -537,24 +694,65 @@ impl Anonymizer {
     }
 }
 
-pub fn process_pcap(reader: impl io::Read, mut writer: impl io::Write) -> Result<(), Error> {
-    let mut reader = pcap_parser::PcapNGReader::new(1_000_000, reader)?;
+/// Anonymizes a pcapng or classic pcap file, writing the same format back
+/// out. Timestamps are copied as they are, so nanosecond pcap files stay
+/// nanosecond pcap files.
+pub fn process_pcap(reader: impl io::Read, writer: impl io::Write) -> Result<(), Error> {
+    process_pcap_with_options(reader, writer, &AnonymizeOptions::default())
+}
+
+/// [`process_pcap`], anonymizing as `options` says.
+pub fn process_pcap_with_options(
+    reader: impl io::Read,
+    mut writer: impl io::Write,
+    options: &AnonymizeOptions,
+) -> Result<(), Error> {
+    let mut reader = pcap_parser::create_reader(1_000_000, reader)?;
+    let ip_remap: Box<dyn Mapping<IpAddr>> = if options.prefix_preserving {
+        Box::new(IPPrefixRemap::new(options.seed))
+    } else {
+        Box::new(IPScopeRemap::new(options.seed))
+    };
     let mut anonymizer = Anonymizer {
-        ip_remap: Mapper::new(Box::new(IPScopeRemap::new(1337))),
-        mac_remap: Mapper::new(Box::new(MacRandomRemap::new(1337))),
+        ip_remap: Mapper::new(ip_remap),
+        mac_remap: Mapper::new(Box::new(MacRandomRemap::new(options.seed))),
+        strip_payloads: options.strip_payloads,
     };
 
     let mut frame_num = 0u64;
 
-    while let Ok((size, block)) = reader.next() {
+    loop {
+        let (size, block) = match reader.next() {
+            Ok(next) => next,
+            Err(PcapError::Eof) => break,
+            Err(PcapError::Incomplete) => {
+                if reader.reader_exhausted() {
+                    tracing::warn!("input is cut off part way through a block");
+                    break;
+                }
+                reader
+                    .refill()
+                    .map_err(|e| format!("error reading pcap: {e:?}"))?;
+                continue;
+            }
+            Err(e) => return Err(format!("bad pcap file: {e:?}").into()),
+        };
         let _scope = tracing::debug_span!("packet", ?frame_num).entered();
         match block {
-            pcap_parser::PcapBlockOwned::Legacy(_) => unreachable!(),
-            pcap_parser::PcapBlockOwned::LegacyHeader(_) => unreachable!(),
-            pcap_parser::PcapBlockOwned::NG(b) => {
+            PcapBlockOwned::LegacyHeader(header) => {
+                writer.write_all(&header.to_vec_raw().unwrap())?;
+            }
+            PcapBlockOwned::Legacy(mut packet) => {
+                if let Some(new_data) = anonymizer.anonymize_frame(packet.data) {
+                    packet.caplen = packet.caplen.min(new_data.len() as u32);
+                    packet.data = &new_data;
+                    writer.write_all(&packet.to_vec_raw().unwrap())?;
+                }
+            }
+            PcapBlockOwned::NG(b) => {
                 let b = anonymizer.anonymize(b);
                 if let Some(b) = b {
-                    writer.write(&b)?;
+                    writer.write_all(&b)?;
                 }
             }
         }
@@ -591,6 +789,96 @@ mod test {
         assert_eq!(ipv6addr_from_u128(test), Ipv6Addr::from(test));
     }
 
+    #[test]
+    fn test_nanosecond_pcap() {
+        let mut file = 0xa1b23c4du32.to_le_bytes().to_vec();
+        file.extend(2u16.to_le_bytes());
+        file.extend(4u16.to_le_bytes());
+        file.extend([0; 8]);
+        file.extend(65535u32.to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+
+        let mut frame = vec![2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2, 0x08, 0x00];
+        // IPv4, UDP from 10.0.0.1 to 10.0.0.2
+        frame.extend([0x45, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0]);
+        frame.extend([10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend([0x9c, 0x40, 0x1f, 0x90, 0, 8, 0, 0]);
+        file.extend(1_700_000_000u32.to_le_bytes());
+        file.extend(123_456_789u32.to_le_bytes());
+        file.extend((frame.len() as u32).to_le_bytes());
+        file.extend((frame.len() as u32).to_le_bytes());
+        file.extend(&frame);
+
+        let mut out = Vec::new();
+        process_pcap(&file[..], &mut out).unwrap();
+
+        // Same header, and the same timestamp and lengths on the packet
+        assert_eq!(out.len(), file.len());
+        assert_eq!(out[..40], file[..40]);
+        assert_ne!(out[40..], file[40..]);
+    }
+
+    #[test]
+    fn test_prefix_preserving() {
+        fn remap_v4(remap: &mut IPPrefixRemap, addr: [u8; 4]) -> u32 {
+            match remap.remap(IpAddr::from(addr)).unwrap() {
+                IpAddr::V4(v) => u32::from(v),
+                IpAddr::V6(_) => unreachable!(),
+            }
+        }
+        let mut remap = IPPrefixRemap::new(DEFAULT_SEED);
+        let a = remap_v4(&mut remap, [10, 1, 2, 3]);
+        let b = remap_v4(&mut remap, [10, 1, 2, 4]);
+        let c = remap_v4(&mut remap, [10, 1, 9, 9]);
+
+        // The same bits in common, and still private
+        assert_eq!((a ^ b).leading_zeros(), 29);
+        assert_eq!((a ^ c).leading_zeros(), 20);
+        assert!(PRIVATE_RANGES_V4[2].contains(&Ipv4Addr::from(a)));
+        assert_ne!(a, u32::from(Ipv4Addr::new(10, 1, 2, 3)));
+
+        // And the same in another file
+        let mut other = IPPrefixRemap::new(DEFAULT_SEED);
+        assert_eq!(remap_v4(&mut other, [10, 1, 9, 9]), c);
+    }
+
+    #[test]
+    fn test_strip_payloads() {
+        let mut file = 0xa1b2c3d4u32.to_le_bytes().to_vec();
+        file.extend(2u16.to_le_bytes());
+        file.extend(4u16.to_le_bytes());
+        file.extend([0; 8]);
+        file.extend(65535u32.to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+
+        let mut frame = vec![2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2, 0x08, 0x00];
+        // IPv4, TCP from 10.0.0.1 to 10.0.0.2, with 5 bytes of data
+        frame.extend([0x45, 0, 0, 45, 0, 0, 0, 0, 64, 6, 0, 0]);
+        frame.extend([10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend([0x9c, 0x40, 0x00, 0x50, 0, 0, 0, 1, 0, 0, 0, 0]);
+        frame.extend([0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        frame.extend(b"hello");
+        file.extend(1_700_000_000u32.to_le_bytes());
+        file.extend(0u32.to_le_bytes());
+        file.extend((frame.len() as u32).to_le_bytes());
+        file.extend((frame.len() as u32).to_le_bytes());
+        file.extend(&frame);
+
+        let mut out = Vec::new();
+        let options = AnonymizeOptions {
+            strip_payloads: true,
+            ..Default::default()
+        };
+        process_pcap_with_options(&file[..], &mut out, &options).unwrap();
+
+        let headers_len = 14 + 20 + 20;
+        assert_eq!(out.len(), file.len() - 5);
+        // Captured length cut down, original length kept
+        assert_eq!(out[32..36], (headers_len as u32).to_le_bytes());
+        assert_eq!(out[36..40], (frame.len() as u32).to_le_bytes());
+        assert!(!out.windows(5).any(|w| w == b"hello"));
+    }
+
     #[test]
     fn test_v6_loopback() {
         let mut remap = IPScopeRemap::new(1337);
@@ -23,6 +23,7 @@ pub use chromiumoxide_cdp as cdp;
 pub use chromiumoxide_types as cdp_types;
 
 pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -0,0 +1,286 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture on an Android device or emulator, by running tcpdump on it over
+//! adb and decoding the pcap it writes to its stdout as it arrives.
+//!
+//! tcpdump has to be on the device and run as root. Emulator images and
+//! `adb root` on other userdebug builds give that, and phones rooted with
+//! Magisk can run it with `su`. Termux's tcpdump works too, given its path.
+//!
+//! Android's TLS stack doesn't write key logs, but apps with a TLS library
+//! of their own may write one to `SSLKEYLOGFILE`. For a debuggable app,
+//! that can be set with its `wrap.<package>` property, which the app is
+//! started under; the key log is then followed with `run-as`, from the
+//! app's own data directory, where it is allowed to write.
+
+use std::{
+    io::{self, Read},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+};
+
+use net_decode::{
+    body_policy::BodyPolicies,
+    capture_filter::CaptureFilter,
+    key_db::{KeyDB, KeyLogReader},
+    DecodeOptions,
+};
+
+use crate::{
+    devtools::{do_devtools_stream_inner, LiveKeys},
+    remote::shell_quote,
+    Error,
+};
+
+/// Name of the key log in the app's data directory.
+const KEY_LOG_NAME: &str = "clipper-sslkeys.log";
+
+/// Which device to capture on and how.
+#[derive(Clone, Debug)]
+pub struct AdbCapture {
+    /// Serial of the device, as given to `adb -s`. adb picks the only one
+    /// connected if this is `None`.
+    pub serial: Option<String>,
+    /// Interface to capture on. tcpdump picks one if this is `None`.
+    pub interface: Option<String>,
+    /// Applied by tcpdump on the device.
+    pub filter: Option<CaptureFilter>,
+    /// Run tcpdump with `su -c`.
+    pub su: bool,
+    /// tcpdump on the device, e.g. Termux's.
+    pub tcpdump: String,
+    /// Package of a debuggable app to set `SSLKEYLOGFILE` for and follow
+    /// the key log of.
+    pub app: Option<String>,
+}
+
+impl Default for AdbCapture {
+    fn default() -> Self {
+        AdbCapture {
+            serial: None,
+            interface: None,
+            filter: None,
+            su: false,
+            tcpdump: "tcpdump".to_string(),
+            app: None,
+        }
+    }
+}
+
+impl AdbCapture {
+    fn adb(&self) -> Command {
+        let mut command = Command::new("adb");
+        if let Some(serial) = &self.serial {
+            command.arg("-s").arg(serial);
+        }
+        command
+    }
+
+    /// Runs `shell_command` on the device, failing if it does.
+    fn shell(&self, shell_command: &str) -> Result<(), Error> {
+        tracing::debug!("running on the device: {shell_command}");
+        let status = self
+            .adb()
+            .arg("shell")
+            .arg(shell_command)
+            .stdin(Stdio::null())
+            .status()
+            .map_err(|e| format!("could not run adb: {e}"))?;
+        if !status.success() {
+            return Err(format!("`{shell_command}` failed on the device: adb {status}").into());
+        }
+        Ok(())
+    }
+
+    /// Starts `shell_command` on the device with its stdout piped back
+    /// untouched, which `adb shell` would turn CRLF.
+    fn spawn_exec_out(&self, shell_command: &str) -> Result<Child, Error> {
+        tracing::debug!("running on the device: {shell_command}");
+        self.adb()
+            .arg("exec-out")
+            .arg(shell_command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run adb: {e}").into())
+    }
+
+    /// Command line for tcpdump on the device.
+    fn tcpdump_command(&self) -> String {
+        // -U: write each packet as it is captured, rather than when a
+        // buffer fills up.
+        let mut command = format!("{} -U -w -", shell_quote(&self.tcpdump));
+        if let Some(interface) = &self.interface {
+            command += " -i ";
+            command += &shell_quote(interface);
+        }
+        let mut filters = Vec::new();
+        // Over wireless debugging, adb's own packets would be sent back over
+        // adb and captured again.
+        if let Some((_, port)) = self.serial.as_ref().and_then(|s| s.rsplit_once(':')) {
+            if port.parse::<u16>().is_ok() {
+                filters.push(format!("not tcp port {port}"));
+            }
+        }
+        if let Some(filter) = &self.filter {
+            filters.push(format!("({filter})"));
+        }
+        if !filters.is_empty() {
+            command += " ";
+            command += &shell_quote(&filters.join(" and "));
+        }
+
+        if self.su {
+            format!("exec su -c {}", shell_quote(&command))
+        } else {
+            format!("exec {command}")
+        }
+    }
+
+    /// Starts tcpdump on the device, with the capture on its stdout.
+    /// Whatever adb and tcpdump print otherwise goes to our stderr.
+    pub fn spawn(&self) -> Result<Child, Error> {
+        self.spawn_exec_out(&self.tcpdump_command())
+    }
+
+    /// Sets `SSLKEYLOGFILE` for `app` and stops it, so that it writes a key
+    /// log from when it is next started.
+    fn push_key_log(&self, app: &str) -> Result<(), Error> {
+        let key_log = format!("/data/data/{app}/{KEY_LOG_NAME}");
+        self.shell(&format!(
+            "setprop {} {}",
+            shell_quote(&format!("wrap.{app}")),
+            shell_quote(&format!("SSLKEYLOGFILE={key_log}")),
+        ))?;
+        self.shell(&format!("am force-stop {}", shell_quote(app)))?;
+        tracing::info!("Start {app} again to have it log its TLS keys");
+        Ok(())
+    }
+
+    /// Puts the `wrap.` property of `app` back as it was.
+    fn clear_key_log(&self, app: &str) {
+        let cleared = self.shell(&format!(
+            "setprop {} ''",
+            shell_quote(&format!("wrap.{app}"))
+        ));
+        if let Err(e) = cleared {
+            tracing::warn!("could not unset SSLKEYLOGFILE for {app}: {e}");
+        }
+    }
+
+    /// Follows the key log of `app`, sending the keys in it to the returned
+    /// receiver as they are written.
+    fn follow_key_log(&self, app: &str) -> Result<(Child, LiveKeys), Error> {
+        // tail -F waits for the app to create it.
+        let mut child = self.spawn_exec_out(&format!(
+            "exec run-as {} tail -n +1 -F {KEY_LOG_NAME}",
+            shell_quote(app)
+        ))?;
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = KeyLogReader::default();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = match stdout.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        tracing::error!("Error reading key log from the device: {e}");
+                        return;
+                    }
+                };
+                let mut gone = false;
+                reader.feed(&buf[..n], &mut |cr, ty, secret| {
+                    tracing::trace!("key from the device: {cr:?} {ty:?}");
+                    gone |= send.send((cr, ty, secret)).is_err();
+                });
+                if gone {
+                    // Nobody is listening any more
+                    return;
+                }
+            }
+        });
+        Ok((child, recv))
+    }
+}
+
+fn stop(mut child: Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+async fn capture_to_devtools(
+    adb: &AdbCapture,
+    live_keys: Option<LiveKeys>,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let mut child = adb.spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    tracing::info!(
+        "Capturing on {} over adb",
+        adb.serial.as_deref().unwrap_or("the device")
+    );
+
+    let result = do_devtools_stream_inner(
+        io::BufReader::new(stdout),
+        None,
+        live_keys,
+        body_policies,
+        decode_options,
+        key_db,
+    )
+    .await;
+
+    // If adb or tcpdump failed, that says more than what came of its empty
+    // output.
+    if result.is_err() {
+        if let Ok(Some(status)) = child.try_wait() {
+            if !status.success() {
+                return Err(format!("capture over adb failed: adb {status}").into());
+            }
+        }
+    }
+    // tcpdump exits once it can't write to the stream any more.
+    stop(child);
+    result
+}
+
+/// Serves a devtools server on what tcpdump captures on an Android device,
+/// as it is captured, with the keys of `adb.app` if it writes them.
+pub async fn do_adb_capture_to_devtools(
+    adb: AdbCapture,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let (key_log, live_keys) = match &adb.app {
+        Some(app) => {
+            adb.push_key_log(app)?;
+            match adb.follow_key_log(app) {
+                Ok((child, keys)) => (Some(child), Some(keys)),
+                Err(e) => {
+                    adb.clear_key_log(app);
+                    return Err(e);
+                }
+            }
+        }
+        None => (None, None),
+    };
+
+    let result = capture_to_devtools(&adb, live_keys, body_policies, decode_options, key_db).await;
+
+    if let Some(child) = key_log {
+        stop(child);
+    }
+    if let Some(app) = &adb.app {
+        adb.clear_key_log(app);
+    }
+    result
+}
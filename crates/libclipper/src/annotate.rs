@@ -0,0 +1,463 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Comments on captured packets about what clipper made of them, so that
+//! someone opening the capture in Wireshark can find, say, where request 42
+//! starts.
+//!
+//! The decoders process a packet entirely within the call that is given it,
+//! so whatever they report during that call is about that packet (or about
+//! a message that it finished). Decoders and listeners can also comment on
+//! a range of packets ending with that one, with a
+//! [`PacketComment`](net_decode::packet_comment::side_data::PacketComment).
+//! Those are written on both ends of the range when a file is annotated,
+//! but only on the last one in a live capture, whose earlier packets have
+//! been written already.
+//!
+//! The server names that clients ask for in their TLS ClientHellos are also
+//! collected, to be written as name resolution blocks so that Wireshark
+//! shows the servers by name.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use net_decode::{
+    capture_file::{read_capture_file, Record},
+    chomp::{EthernetChomper, FrameChomper},
+    dispatch::ListenerDispatcher,
+    http::HTTPStreamEvent,
+    key_db::{ClientRandom, KeyDB, Secret, SecretType},
+    link::{Linktype, PacketDirection},
+    listener::{Nanos, TimingInfo},
+    DecodeOptions,
+};
+use wire_blahaj::pcap_writer::{CaptureMetadata, InterfaceInfo, PacketOptions, PcapWriter};
+
+use crate::{
+    events::{ClipperEvent, EventListener, EventSink, TlsEvent},
+    Error,
+};
+
+/// A comment on the packet being decoded, or on a range of packets ending
+/// with it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Annotation {
+    pub text: String,
+    /// When the first packet of the range was received, if it is one.
+    pub since: Option<Nanos>,
+}
+
+/// Turns events into comments, for whichever packet is being decoded.
+struct CommentSink {
+    comments: Arc<Mutex<Vec<Annotation>>>,
+    /// Servers and the names clients asked them for.
+    names: Arc<Mutex<Vec<(IpAddr, String)>>>,
+}
+
+impl EventSink for CommentSink {
+    fn on_event(&mut self, event: ClipperEvent) {
+        let text = match event {
+            ClipperEvent::Http {
+                event: HTTPStreamEvent::NewRequest(id, parts),
+                ..
+            } => format!("request {id} starts here: {} {}", parts.method, parts.uri),
+            ClipperEvent::Http {
+                event: HTTPStreamEvent::NewResponse(id, parts),
+                ..
+            } => format!("response to request {id} starts here: {}", parts.status),
+            ClipperEvent::Finding(finding) => finding.message,
+            ClipperEvent::Comment(comment) => {
+                self.comments.lock().unwrap().push(Annotation {
+                    text: comment.text,
+                    since: comment.since,
+                });
+                return;
+            }
+            ClipperEvent::Tls(TlsEvent::ClientHello(hello)) => {
+                if let Some(name) = hello.server_name {
+                    let server = hello.target.server_addr().ip();
+                    self.names.lock().unwrap().push((server, name));
+                }
+                return;
+            }
+            _ => return,
+        };
+        self.comments
+            .lock()
+            .unwrap()
+            .push(Annotation { text, since: None });
+    }
+}
+
+/// Decodes packets as they are captured, to comment on them.
+pub struct Annotator {
+    chomper: EthernetChomper<ListenerDispatcher>,
+    comments: Arc<Mutex<Vec<Annotation>>>,
+    names: Arc<Mutex<Vec<(IpAddr, String)>>>,
+    /// Names given out by [`Annotator::take_names`] already.
+    named: BTreeSet<(IpAddr, String)>,
+}
+
+impl Annotator {
+    pub fn new(key_db: Arc<RwLock<KeyDB>>, decode_options: DecodeOptions) -> Self {
+        let comments: Arc<Mutex<Vec<Annotation>>> = Default::default();
+        let names: Arc<Mutex<Vec<(IpAddr, String)>>> = Default::default();
+        let chomper = net_decode::chomper_with_options(
+            EventListener::new(CommentSink {
+                comments: comments.clone(),
+                names: names.clone(),
+            }),
+            key_db,
+            decode_options,
+        );
+        Self {
+            chomper,
+            comments,
+            names,
+            named: BTreeSet::new(),
+        }
+    }
+
+    /// Decodes a packet, returning the comments on it and on ranges ending
+    /// with it.
+    pub fn annotate(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<Vec<Annotation>, Error> {
+        self.chomper.chomp(timing, link_type, packet)?;
+        Ok(std::mem::take(&mut *self.comments.lock().unwrap()))
+    }
+
+    /// Host names learned since the last call, from the server names that
+    /// clients asked for. Each is only given once.
+    pub fn take_names(&mut self) -> Vec<(IpAddr, String)> {
+        let names = std::mem::take(&mut *self.names.lock().unwrap());
+        names
+            .into_iter()
+            .filter(|name| self.named.insert(name.clone()))
+            .collect()
+    }
+
+    pub fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.chomper.on_key(client_random, secret_type, secret);
+    }
+
+    /// Takes the TLS key log of a decryption secrets block.
+    pub fn on_keys(&mut self, dsb: &[u8]) {
+        self.chomper.on_keys(dsb);
+    }
+
+    /// Takes a WireGuard key log.
+    pub fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.chomper.on_wireguard_keys(key_log);
+    }
+}
+
+/// Where a range starting at `since` starts, in the packets received at
+/// `times` so far: the earliest of those at the end that were received no
+/// earlier than it.
+fn range_start(times: &[Nanos], since: Nanos) -> usize {
+    let mut first = times.len() - 1;
+    while first > 0 && times[first - 1] >= since {
+        first -= 1;
+    }
+    first
+}
+
+/// Decodes `file`, returning the comments to write on each of its packets,
+/// by where they are in it, and the host names of addresses in it, both
+/// those it had already and those learned from decoding it.
+fn collect_comments(
+    file: &Path,
+    key_db: KeyDB,
+    decode_options: DecodeOptions,
+) -> Result<(BTreeMap<usize, Vec<String>>, Vec<(IpAddr, String)>), Error> {
+    let mut annotator = Annotator::new(Arc::new(RwLock::new(key_db)), decode_options);
+    let mut times = Vec::new();
+    let mut comments: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let info = read_capture_file(file, &mut |_, record| {
+        match record {
+            Record::Frame(frame) => {
+                let number = times.len();
+                times.push(frame.timing.received_on_wire);
+                for annotation in annotator.annotate(frame.timing, frame.link_type, frame.data)? {
+                    let first = annotation.since.map_or(number, |t| range_start(&times, t));
+                    if first == number {
+                        comments.entry(number).or_default().push(annotation.text);
+                        continue;
+                    }
+                    // Numbered from 1, as Wireshark does.
+                    let text = format!(
+                        "{} (packets {} to {})",
+                        annotation.text,
+                        first + 1,
+                        number + 1
+                    );
+                    comments.entry(first).or_default().push(text.clone());
+                    comments.entry(number).or_default().push(text);
+                }
+            }
+            Record::TlsKeys(keys) => annotator.on_keys(keys),
+            Record::WireguardKeys(keys) => annotator.on_wireguard_keys(keys),
+        }
+        Ok(())
+    })?;
+
+    let mut names: Vec<(IpAddr, String)> = info
+        .names
+        .into_iter()
+        .flat_map(|(addr, names)| names.into_iter().map(move |name| (addr, name)))
+        .collect();
+    for name in annotator.take_names() {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    Ok((comments, names))
+}
+
+/// Copies the capture `file` to `output_file` as pcapng, with comments on
+/// its packets about what clipper made of them, for Wireshark to show.
+/// `key_db` has any keys not embedded in the file, which are used but not
+/// written out.
+pub fn do_annotate_file(
+    file: PathBuf,
+    output_file: PathBuf,
+    key_db: KeyDB,
+    decode_options: DecodeOptions,
+) -> Result<(), Error> {
+    // Comments on ranges are made after their first packet has gone by, so
+    // the file is read once to decode it and again to copy it.
+    let (mut comments, names) = collect_comments(&file, key_db, decode_options)?;
+
+    let mut out = io::BufWriter::new(fs::File::create(&output_file)?);
+    let mut pcap = PcapWriter::new(
+        crate::APP_IDENTIFICATION,
+        &CaptureMetadata {
+            comments: vec![format!("annotated from {}", file.display())],
+            ..Default::default()
+        },
+        &mut out,
+    )?;
+    if !names.is_empty() {
+        pcap.on_names(&mut out, &names)?;
+    }
+    // Interfaces described to `pcap` so far.
+    let mut described = 0;
+    let mut number = 0;
+    let mut warned_wireguard = false;
+    read_capture_file(&file, &mut |info, record| {
+        match record {
+            Record::Frame(frame) => {
+                for (index, interface) in info.interfaces.iter().enumerate().skip(described) {
+                    pcap.set_interface_info(
+                        index as u32,
+                        InterfaceInfo {
+                            name: interface.name.clone(),
+                            comment: Some(interface.comments.join("; ")),
+                            link_type: Some(interface.link_type),
+                            snap_len: interface.snap_len,
+                        },
+                    );
+                }
+                described = info.interfaces.len();
+
+                let mut packet_comments: Vec<String> =
+                    frame.comments.iter().map(|&c| c.to_owned()).collect();
+                packet_comments.extend(comments.remove(&number).unwrap_or_default());
+                number += 1;
+                pcap.on_packet_with_options(
+                    &mut out,
+                    frame.timing.received_on_wire,
+                    frame.interface as u32,
+                    frame.data,
+                    frame.original_len as usize,
+                    PacketOptions {
+                        comments: &packet_comments,
+                        flags: frame.timing.direction.map(PacketDirection::epb_flags),
+                    },
+                )?;
+            }
+            Record::TlsKeys(keys) => pcap.on_dsb(&mut out, keys)?,
+            Record::WireguardKeys(_) => {
+                if !warned_wireguard {
+                    tracing::warn!("WireGuard keys are not carried over into annotated files");
+                    warned_wireguard = true;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    out.flush()?;
+
+    tracing::info!("annotated {number} packets into {output_file:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use net_decode::capture_file::{read_capture, CaptureFormat};
+
+    use super::*;
+
+    #[test]
+    fn test_range_start() {
+        let times = [10, 20, 20, 30];
+        assert_eq!(range_start(&times, 20), 1);
+        assert_eq!(range_start(&times, 5), 0);
+        assert_eq!(range_start(&times, 40), 3);
+    }
+
+    #[test]
+    fn test_pcap_writer_round_trip() {
+        let mut file = Vec::new();
+        let mut pcap = PcapWriter::new(
+            "clipper test",
+            &CaptureMetadata {
+                hardware: Some("x86_64".to_owned()),
+                os: Some("Linux 6.4.8".to_owned()),
+                comments: vec!["started at noon".to_owned()],
+            },
+            &mut file,
+        )
+        .unwrap();
+        pcap.set_interface_info(
+            7,
+            InterfaceInfo {
+                name: Some("eth0".to_owned()),
+                comment: Some("the only one".to_owned()),
+                link_type: None,
+                snap_len: Some(128),
+            },
+        );
+        let server: IpAddr = "10.0.0.2".parse().unwrap();
+        pcap.on_names(&mut file, &[(server, "server.test".to_owned())])
+            .unwrap();
+        let comments = vec!["request 0 starts here".to_owned(), "and more".to_owned()];
+        pcap.on_packet_with_options(
+            &mut file,
+            1_700_000_000_123_456_789,
+            7,
+            &[1, 2, 3, 4, 5],
+            1500,
+            PacketOptions {
+                comments: &comments,
+                flags: Some(PacketDirection::Outgoing.epb_flags()),
+            },
+        )
+        .unwrap();
+
+        let mut frames = Vec::new();
+        let info = read_capture(&file[..], &mut |_, record| {
+            if let Record::Frame(frame) = record {
+                frames.push((
+                    frame.interface,
+                    frame.timing.received_on_wire,
+                    frame.timing.direction,
+                    frame.data.to_vec(),
+                    frame.original_len,
+                    frame
+                        .comments
+                        .iter()
+                        .map(|c| c.to_string())
+                        .collect::<Vec<_>>(),
+                ));
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(info.format, Some(CaptureFormat::PcapNg));
+        assert_eq!(info.hardware.as_deref(), Some("x86_64"));
+        assert_eq!(info.os.as_deref(), Some("Linux 6.4.8"));
+        assert_eq!(info.comments, ["started at noon"]);
+        let [interface] = &info.interfaces[..] else {
+            panic!("expected one interface: {:?}", info.interfaces);
+        };
+        assert_eq!(interface.name.as_deref(), Some("eth0"));
+        assert_eq!(interface.comments, ["the only one"]);
+        assert_eq!(interface.link_type, Linktype::ETHERNET);
+        assert_eq!(interface.snap_len, Some(128));
+        assert_eq!(info.names[&server], ["server.test"]);
+        assert_eq!(
+            frames,
+            vec![(
+                0,
+                1_700_000_000_123_456_789,
+                Some(PacketDirection::Outgoing),
+                vec![1, 2, 3, 4, 5],
+                1500,
+                comments,
+            )]
+        );
+    }
+
+    #[test]
+    fn test_annotate_file() {
+        let file =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../net_decode/corpus/nya-dsb.pcapng");
+        let dir = tempfile::tempdir().unwrap();
+        let output_file = dir.path().join("annotated.pcapng");
+        do_annotate_file(
+            file.clone(),
+            output_file.clone(),
+            KeyDB::default(),
+            DecodeOptions::default(),
+        )
+        .unwrap();
+
+        let mut original = 0;
+        read_capture_file(&file, &mut |_, record| {
+            if let Record::Frame(_) = record {
+                original += 1;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let mut packets = 0;
+        let mut comments = Vec::new();
+        let info = read_capture_file(&output_file, &mut |_, record| {
+            if let Record::Frame(frame) = record {
+                packets += 1;
+                comments.extend(frame.comments.iter().map(|c| c.to_string()));
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(packets, original);
+        assert!(
+            info.comments
+                .iter()
+                .any(|c| c.starts_with("annotated from ")),
+            "{:?}",
+            info.comments
+        );
+        assert!(
+            comments
+                .iter()
+                .any(|c| c.starts_with("request ") && c.contains(" starts here: GET ")),
+            "{comments:?}"
+        );
+        assert!(
+            comments
+                .iter()
+                .any(|c| c.starts_with("response to request ")),
+            "{comments:?}"
+        );
+        assert!(
+            info.names.values().flatten().any(|name| name == "jade.fyi"),
+            "{:?}",
+            info.names
+        );
+    }
+}
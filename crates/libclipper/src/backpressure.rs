@@ -0,0 +1,303 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Slowing the capture down to the speed of whoever is consuming its output,
+//! rather than buffering without end or dropping things.
+//!
+//! Each item produced is numbered, and each consumer says how far it has
+//! got. Once the slowest consumer is [`Backpressure::new`]'s `limit` items
+//! behind, [`Backpressure::room`] waits. A live capture then stops reading
+//! packets, leaving them to the kernel's buffer, which drops them and counts
+//! the drops if it too fills up.
+//!
+//! A consumer that takes nothing at all for the stall timeout while it is
+//! holding things up is given up on: it is no longer waited for, and misses
+//! whatever it is too far behind to get.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct State {
+    produced: u64,
+    /// How many items each consumer has taken, by consumer.
+    consumed: BTreeMap<u64, u64>,
+    next_consumer: u64,
+}
+
+/// See [`Backpressure::with_stall_timeout`].
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Inner {
+    limit: u64,
+    stall_timeout: Duration,
+    state: Mutex<State>,
+    progress: Notify,
+}
+
+#[derive(Clone)]
+pub struct Backpressure {
+    inner: Arc<Inner>,
+}
+
+impl Backpressure {
+    pub fn new(limit: usize) -> Self {
+        Backpressure {
+            inner: Arc::new(Inner {
+                limit: limit as u64,
+                stall_timeout: DEFAULT_STALL_TIMEOUT,
+                state: Default::default(),
+                progress: Notify::new(),
+            }),
+        }
+    }
+
+    /// Gives up on consumers that take nothing for `stall_timeout` while
+    /// the producer waits for them. Must be called before there are any
+    /// consumers or clones.
+    pub fn with_stall_timeout(mut self, stall_timeout: Duration) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("stall timeout set on a shared Backpressure")
+            .stall_timeout = stall_timeout;
+        self
+    }
+
+    /// Counts an item as produced, returning its number.
+    pub fn produced(&self) -> u64 {
+        let mut state = self.inner.state.lock().unwrap();
+        state.produced += 1;
+        state.produced
+    }
+
+    /// Registers a consumer, which has taken nothing produced so far.
+    pub fn consumer(&self) -> Consumer {
+        let mut state = self.inner.state.lock().unwrap();
+        let id = state.next_consumer;
+        state.next_consumer += 1;
+        let produced = state.produced;
+        state.consumed.insert(id, produced);
+        Consumer {
+            id,
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// How far behind the slowest consumer is.
+    pub fn lag(&self) -> u64 {
+        let state = self.inner.state.lock().unwrap();
+        let slowest = state.consumed.values().min().copied();
+        slowest.map_or(0, |c| state.produced.saturating_sub(c))
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.lag() < self.inner.limit
+    }
+
+    /// Waits until the slowest consumer is less than the limit behind, or
+    /// has been given up on. Returns straight away if there are no
+    /// consumers.
+    pub async fn room(&self) {
+        loop {
+            let progress = self.inner.progress.notified();
+            if self.has_room() {
+                return;
+            }
+            // Every consumer that took something would have woken us, so the
+            // ones still at the limit have been stuck for the whole wait.
+            if tokio::time::timeout(self.inner.stall_timeout, progress)
+                .await
+                .is_err()
+            {
+                self.give_up_on_stalled();
+            }
+        }
+    }
+
+    fn give_up_on_stalled(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        let produced = state.produced;
+        let limit = self.inner.limit;
+        state.consumed.retain(|id, taken| {
+            let stalled = produced.saturating_sub(*taken) >= limit;
+            if stalled {
+                tracing::warn!(
+                    consumer = id,
+                    "consumer took nothing for {:?}, no longer waiting for it",
+                    self.inner.stall_timeout
+                );
+            }
+            !stalled
+        });
+    }
+}
+
+/// One consumer of a [`Backpressure`]. Stops counting when dropped.
+pub struct Consumer {
+    id: u64,
+    inner: Arc<Inner>,
+}
+
+impl Consumer {
+    /// Whether the producer stopped waiting for this consumer because it
+    /// stalled. It is not waited for again.
+    pub fn given_up(&self) -> bool {
+        !self
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .consumed
+            .contains_key(&self.id)
+    }
+
+    /// Says that item `n`, and everything before it, has been taken.
+    pub fn consumed(&self, n: u64) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(c) = state.consumed.get_mut(&self.id) {
+            *c = (*c).max(n);
+        }
+        drop(state);
+        self.inner.progress.notify_waiters();
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().consumed.remove(&self.id);
+        self.inner.progress.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Fails the test rather than hanging it if `room` never returns.
+    async fn room(pressure: &Backpressure) {
+        tokio::time::timeout(Duration::from_secs(5), pressure.room())
+            .await
+            .expect("no room");
+    }
+
+    #[test]
+    fn test_no_consumers() {
+        let pressure = Backpressure::new(2);
+        for _ in 0..10 {
+            pressure.produced();
+        }
+        assert_eq!(pressure.lag(), 0);
+        assert!(pressure.has_room());
+    }
+
+    #[test]
+    fn test_slowest_consumer() {
+        let pressure = Backpressure::new(2);
+        let fast = pressure.consumer();
+        let slow = pressure.consumer();
+        pressure.produced();
+        let n = pressure.produced();
+        assert!(!pressure.has_room());
+
+        fast.consumed(n);
+        assert_eq!(pressure.lag(), 2);
+        assert!(!pressure.has_room());
+        slow.consumed(n - 1);
+        assert_eq!(pressure.lag(), 1);
+        assert!(pressure.has_room());
+
+        // Only what is produced after a consumer starts counts against it.
+        let late = pressure.consumer();
+        slow.consumed(n);
+        assert_eq!(pressure.lag(), 0);
+        drop(late);
+    }
+
+    #[test]
+    fn test_dropped_consumer() {
+        let pressure = Backpressure::new(1);
+        let consumer = pressure.consumer();
+        pressure.produced();
+        assert!(!pressure.has_room());
+        drop(consumer);
+        assert!(pressure.has_room());
+    }
+
+    /// A sink that takes a while over each item never falls more than the
+    /// limit behind a producer that waits for room.
+    #[tokio::test]
+    async fn test_slow_sink() {
+        const LIMIT: usize = 3;
+        let pressure = Backpressure::new(LIMIT);
+        let (send, mut recv) = tokio::sync::mpsc::unbounded_channel();
+        let consumer = pressure.consumer();
+        let sink = tokio::spawn(async move {
+            let mut taken = Vec::new();
+            while let Some(n) = recv.recv().await {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                taken.push(n);
+                consumer.consumed(n);
+            }
+            taken
+        });
+
+        let mut most_behind = 0;
+        for _ in 0..20 {
+            room(&pressure).await;
+            let n = pressure.produced();
+            most_behind = most_behind.max(pressure.lag());
+            send.send(n).unwrap();
+        }
+        drop(send);
+
+        assert!(most_behind <= LIMIT as u64, "{most_behind} behind");
+        assert_eq!(sink.await.unwrap(), (1..=20).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_sink_given_up() {
+        let pressure = Backpressure::new(2).with_stall_timeout(Duration::from_millis(50));
+        let stalled = pressure.consumer();
+        let working = pressure.consumer();
+        pressure.produced();
+        let n = pressure.produced();
+        working.consumed(n);
+        assert!(!pressure.has_room());
+
+        let start = Instant::now();
+        room(&pressure).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(stalled.given_up());
+        assert!(!working.given_up());
+
+        // The one still working is still waited for.
+        pressure.produced();
+        pressure.produced();
+        assert!(!pressure.has_room());
+        // Taking things late does not bring it back.
+        stalled.consumed(n);
+        assert!(stalled.given_up());
+    }
+
+    #[tokio::test]
+    async fn test_slow_sink_not_given_up() {
+        let pressure = Backpressure::new(1).with_stall_timeout(Duration::from_millis(200));
+        let consumer = pressure.consumer();
+        let n = pressure.produced();
+        let sink = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            consumer.consumed(n);
+            consumer
+        });
+        room(&pressure).await;
+        let consumer = sink.await.unwrap();
+        assert!(!consumer.given_up());
+    }
+}
@@ -0,0 +1,478 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Keeping response bodies around for `Network.getResponseBody` and
+//! exporting.
+//!
+//! Finished bodies go in a [`ContentStore`] on disk, named by their hash, so
+//! that the same body seen many times (retries, polling, cached assets) is
+//! only stored once. Bodies still arriving are held in memory while they are
+//! small and in a temporary file in the store once they are not, so that a
+//! big download costs disk rather than RAM.
+//!
+//! A store kept around with [`ContentStore::open`] also gets an
+//! `index.jsonl` of [`BodyRecord`]s, saying which request each body
+//! answered, since the hashes alone mean nothing once clipper exits.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use net_decode::{http::RequestId, listener::Nanos};
+use ring::digest;
+use tempfile::{NamedTempFile, TempDir};
+
+/// SHA-256 of a body.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BodyHash(pub [u8; 32]);
+
+impl fmt::Display for BodyHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BodyHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BodyHash({self})")
+    }
+}
+
+/// Name of the [`BodyRecord`] log in a store's directory.
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// A finished response whose body is in a [`ContentStore`].
+#[derive(Clone, Debug)]
+pub struct BodyRecord {
+    /// When the response finished.
+    pub time: Nanos,
+    /// Only unique within one run of clipper.
+    pub request: RequestId,
+    pub method: String,
+    pub uri: String,
+    pub hash: BodyHash,
+    pub len: u64,
+}
+
+impl BodyRecord {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "time": self.time,
+            "request": self.request,
+            "method": self.method,
+            "uri": self.uri,
+            "hash": self.hash.to_string(),
+            "len": self.len,
+        })
+    }
+}
+
+/// Directory of bodies named by their [`BodyHash`], as
+/// `ab/abcdef...` like git does, to keep directories small.
+pub struct ContentStore {
+    dir: PathBuf,
+    /// Where [`BodyRecord`]s go, if the store outlives clipper.
+    index: Option<File>,
+    /// Deleted along with the store, if it is temporary.
+    _temp_dir: Option<TempDir>,
+}
+
+impl ContentStore {
+    /// A store in `dir`, which may already have bodies in it from before.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(INDEX_FILE))?;
+        Ok(ContentStore {
+            dir,
+            index: Some(index),
+            _temp_dir: None,
+        })
+    }
+
+    /// A store that is deleted when dropped.
+    pub fn temporary() -> io::Result<Self> {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("clipper-bodies")
+            .tempdir()?;
+        Ok(ContentStore {
+            dir: temp_dir.path().to_owned(),
+            index: None,
+            _temp_dir: Some(temp_dir),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, hash: BodyHash) -> PathBuf {
+        let hex = hash.to_string();
+        self.dir.join(&hex[..2]).join(hex)
+    }
+
+    pub fn contains(&self, hash: BodyHash) -> bool {
+        self.path(hash).exists()
+    }
+
+    pub fn open_body(&self, hash: BodyHash) -> io::Result<File> {
+        File::open(self.path(hash))
+    }
+
+    /// Notes down which request a body answered. Does nothing for a
+    /// temporary store.
+    pub fn record(&mut self, record: &BodyRecord) -> io::Result<()> {
+        let Some(index) = &mut self.index else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(&record.to_json())?;
+        line.push(b'\n');
+        index.write_all(&line)
+    }
+
+    /// Somewhere to write a body that will be [`ContentStore::insert`]ed,
+    /// on the same filesystem so that it can be moved into place.
+    fn new_file(&self) -> io::Result<NamedTempFile> {
+        NamedTempFile::new_in(&self.dir)
+    }
+
+    /// Moves a finished body into place, unless the store already has it.
+    fn insert(&self, file: NamedTempFile, hash: BodyHash) -> io::Result<()> {
+        let path = self.path(hash);
+        if path.exists() {
+            tracing::trace!(%hash, "already have body");
+            return Ok(());
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+enum InFlight {
+    Memory(Vec<u8>),
+    File(NamedTempFile),
+}
+
+enum Stored {
+    InFlight {
+        data: InFlight,
+        len: u64,
+        // Boxed, being several times the size of `Finished`.
+        hasher: Box<digest::Context>,
+    },
+    Finished {
+        hash: BodyHash,
+        len: u64,
+    },
+}
+
+pub struct BodyStore {
+    bodies: BTreeMap<RequestId, Stored>,
+    content: ContentStore,
+    spill_over: usize,
+}
+
+impl BodyStore {
+    /// Bodies going over `spill_over` bytes are moved to disk before they
+    /// finish.
+    pub fn new(content: ContentStore, spill_over: usize) -> Self {
+        BodyStore {
+            bodies: BTreeMap::new(),
+            content,
+            spill_over,
+        }
+    }
+
+    pub fn content(&self) -> &ContentStore {
+        &self.content
+    }
+
+    pub fn content_mut(&mut self) -> &mut ContentStore {
+        &mut self.content
+    }
+
+    /// Adds the next part of a body.
+    pub fn append(&mut self, id: RequestId, chunk: &[u8]) -> io::Result<()> {
+        let stored = self.bodies.entry(id).or_insert_with(|| Stored::InFlight {
+            data: InFlight::Memory(Vec::new()),
+            len: 0,
+            hasher: Box::new(digest::Context::new(&digest::SHA256)),
+        });
+        let Stored::InFlight { data, len, hasher } = stored else {
+            tracing::warn!(id, "more body after it finished");
+            return Ok(());
+        };
+        hasher.update(chunk);
+        *len += chunk.len() as u64;
+        match data {
+            InFlight::Memory(buf) if buf.len() + chunk.len() > self.spill_over => {
+                tracing::debug!(
+                    id,
+                    "moving a body of over {} bytes to disk",
+                    self.spill_over
+                );
+                let mut file = self.content.new_file()?;
+                file.write_all(buf)?;
+                file.write_all(chunk)?;
+                *data = InFlight::File(file);
+            }
+            InFlight::Memory(buf) => buf.extend_from_slice(chunk),
+            InFlight::File(file) => file.write_all(chunk)?,
+        }
+        Ok(())
+    }
+
+    /// Puts a body that has all arrived into the [`ContentStore`].
+    pub fn finish(&mut self, id: RequestId) -> io::Result<Option<BodyHash>> {
+        if !matches!(self.bodies.get(&id), Some(Stored::InFlight { .. })) {
+            return Ok(self.hash(id));
+        }
+        let Some(Stored::InFlight { data, len, hasher }) = self.bodies.remove(&id) else {
+            unreachable!();
+        };
+        let hash = BodyHash(hasher.finish().as_ref().try_into().unwrap());
+        let file = match data {
+            InFlight::Memory(_) if self.content.contains(hash) => None,
+            InFlight::Memory(buf) => {
+                let mut file = self.content.new_file()?;
+                file.write_all(&buf)?;
+                Some(file)
+            }
+            InFlight::File(file) => Some(file),
+        };
+        if let Some(file) = file {
+            self.content.insert(file, hash)?;
+        }
+        self.bodies.insert(id, Stored::Finished { hash, len });
+        Ok(Some(hash))
+    }
+
+    /// Forgets a body, for instance after failing to store it. The
+    /// [`ContentStore`] keeps it if it was finished, since other requests
+    /// may share it.
+    pub fn remove(&mut self, id: RequestId) {
+        self.bodies.remove(&id);
+    }
+
+    /// The hash of a finished body.
+    pub fn hash(&self, id: RequestId) -> Option<BodyHash> {
+        match self.bodies.get(&id)? {
+            Stored::Finished { hash, .. } => Some(*hash),
+            Stored::InFlight { .. } => None,
+        }
+    }
+
+    /// How much of the body there is so far.
+    pub fn len(&self, id: RequestId) -> Option<u64> {
+        self.bodies.get(&id).map(|stored| match stored {
+            Stored::InFlight { len, .. } | Stored::Finished { len, .. } => *len,
+        })
+    }
+
+    /// Reads the body as it is so far. Bodies on disk are read as the
+    /// reader is read from, so this is cheap to call with a lock held.
+    pub fn reader(&self, id: RequestId) -> Option<io::Result<Box<dyn Read + Send>>> {
+        let file = match self.bodies.get(&id)? {
+            Stored::InFlight {
+                data: InFlight::Memory(buf),
+                ..
+            } => return Some(Ok(Box::new(io::Cursor::new(buf.clone())))),
+            // Opened again so that it has its own position, and limited to
+            // what was written by now.
+            Stored::InFlight {
+                data: InFlight::File(file),
+                len,
+                ..
+            } => File::open(file.path()).map(|f| f.take(*len)),
+            Stored::Finished { hash, len } => self.content.open_body(*hash).map(|f| f.take(*len)),
+        };
+        Some(file.map(|f| Box::new(f) as Box<dyn Read + Send>))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(store: &BodyStore, id: RequestId) -> Vec<u8> {
+        let mut out = Vec::new();
+        store
+            .reader(id)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    /// Every body file in the store.
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut out = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                out.extend(files(&path));
+            } else if !path.ends_with(INDEX_FILE) {
+                out.push(path);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_small_body_stays_in_memory() {
+        let mut store = BodyStore::new(ContentStore::temporary().unwrap(), 16);
+        store.append(1, b"hello ").unwrap();
+        store.append(1, b"world").unwrap();
+        assert_eq!(store.len(1), Some(11));
+        assert_eq!(read_all(&store, 1), b"hello world");
+        assert!(files(store.content().dir()).is_empty());
+    }
+
+    #[test]
+    fn test_spill() {
+        let mut store = BodyStore::new(ContentStore::temporary().unwrap(), 4);
+        store.append(1, b"abc").unwrap();
+        store.append(1, b"defg").unwrap();
+        assert_eq!(files(store.content().dir()).len(), 1);
+
+        // A reader only sees what was there when it was made.
+        let mut early = store.reader(1).unwrap().unwrap();
+        store.append(1, b"hij").unwrap();
+        let mut out = Vec::new();
+        early.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abcdefg");
+        assert_eq!(read_all(&store, 1), b"abcdefghij");
+        assert_eq!(store.len(1), Some(10));
+    }
+
+    #[test]
+    fn test_same_body_once() {
+        let mut store = BodyStore::new(ContentStore::temporary().unwrap(), 4);
+        // One in memory until it finishes and one spilled on the way.
+        store.append(1, b"same body").unwrap();
+        store.append(2, b"same").unwrap();
+        store.append(2, b" body").unwrap();
+        let first = store.finish(1).unwrap().unwrap();
+        let second = store.finish(2).unwrap().unwrap();
+        assert_eq!(first, second);
+
+        let files = files(store.content().dir());
+        assert_eq!(files, vec![store.content().path(first)]);
+        assert_eq!(read_all(&store, 1), b"same body");
+        assert_eq!(read_all(&store, 2), b"same body");
+
+        // Forgetting one request keeps the body for the other.
+        store.remove(1);
+        assert!(store.reader(1).is_none());
+        assert_eq!(read_all(&store, 2), b"same body");
+    }
+
+    #[test]
+    fn test_finish_twice() {
+        let mut store = BodyStore::new(ContentStore::temporary().unwrap(), 1024);
+        assert_eq!(store.finish(1).unwrap(), None);
+        store.append(1, b"body").unwrap();
+        let hash = store.finish(1).unwrap();
+        assert!(hash.is_some());
+        assert_eq!(store.finish(1).unwrap(), hash);
+        // Ignored, rather than changing a body that is already stored.
+        store.append(1, b"more").unwrap();
+        assert_eq!(read_all(&store, 1), b"body");
+    }
+
+    fn record(request: RequestId, hash: BodyHash) -> BodyRecord {
+        BodyRecord {
+            time: 1_000_000_000,
+            request,
+            method: "GET".to_string(),
+            uri: "/a".to_string(),
+            hash,
+            len: 9,
+        }
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = {
+            let mut store = BodyStore::new(ContentStore::open(dir.path()).unwrap(), 1024);
+            store.append(1, b"same body").unwrap();
+            let hash = store.finish(1).unwrap().unwrap();
+            store.content_mut().record(&record(1, hash)).unwrap();
+            hash
+        };
+        assert_eq!(
+            hash.to_string(),
+            "8f6372a8b1509601faa57ff3a292cfcccb95aa2325c18b8e50b0c035ea1648fe"
+        );
+
+        let mut store = BodyStore::new(ContentStore::open(dir.path()).unwrap(), 1024);
+        assert!(store.content().contains(hash));
+        let mut body = String::new();
+        store
+            .content()
+            .open_body(hash)
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "same body");
+
+        // Already there, so not written again.
+        store.append(7, b"same body").unwrap();
+        assert_eq!(store.finish(7).unwrap(), Some(hash));
+        assert_eq!(files(dir.path()).len(), 1);
+
+        store.content_mut().record(&record(7, hash)).unwrap();
+        let index = fs::read_to_string(dir.path().join(INDEX_FILE)).unwrap();
+        let records: Vec<serde_json::Value> = index
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["request"], 1);
+        assert_eq!(records[1]["request"], 7);
+        for r in &records {
+            assert_eq!(r["hash"], hash.to_string());
+            assert_eq!(r["uri"], "/a");
+            assert_eq!(r["len"], 9);
+        }
+    }
+
+    #[test]
+    fn test_open_body() {
+        let store = ContentStore::temporary().unwrap();
+        let missing = BodyHash([0xab; 32]);
+        assert!(!store.contains(missing));
+        assert_eq!(
+            store.open_body(missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            store.path(missing),
+            store.dir().join("ab").join("ab".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_temporary_has_no_index() {
+        let mut store = ContentStore::temporary().unwrap();
+        let dir = store.dir().to_owned();
+        store.record(&record(1, BodyHash([0; 32]))).unwrap();
+        assert!(!dir.join(INDEX_FILE).exists());
+        drop(store);
+        assert!(!dir.exists());
+    }
+}
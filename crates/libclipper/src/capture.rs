@@ -2,56 +2,125 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-//! Implementation of (unprivileged) capture on Linux.
-//!
-//! FIXME: how do we do other-OS or privileged capture?
+//! Implementation of live capture. On Linux, that is unprivileged capture
+//! of a program we start, or capture of a whole interface for those allowed
+//! to. On macOS, only whole interfaces can be captured, through a BPF
+//! device, and on Windows likewise, through Npcap.
 
+#[cfg(unix)]
 use clipper_protocol::proto::embedding::{
     clipper_embedding_server::{ClipperEmbedding, ClipperEmbeddingServer},
     new_keys_req::Keys,
     NewKeysReq, NewKeysResp, TlsKeys,
 };
-use futures::{Future, StreamExt};
+use futures::{Future, Stream, StreamExt};
 use net_decode::{
-    chomp::{EthernetChomper, FrameChomper},
+    body_policy::BodyPolicies,
+    capture_filter::{BpfProgram, CaptureFilter},
+    checkpoint::{CheckpointingChomper, ReplayGate},
+    chomp::FrameChomper,
     dispatch::ListenerDispatcher,
-    key_db::{ClientRandom, KeyDB, Secret, SecretType},
-    listener::TimingInfo,
+    key_db::{ClientRandom, ExternalPsk, KeyDB, RsaKey, Secret, SecretType},
+    link::{Linktype, PacketDirection},
+    listener::{CaptureInterface, TimingInfo},
+    live::LiveConfig,
+    DecodeOptions,
 };
+#[cfg(target_os = "linux")]
+use nix::libc::sock_filter as FilterInsn;
 use tokio::{
     fs::OpenOptions as TokioOpenOptions,
-    io::{unix::AsyncFd, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+#[cfg(unix)]
+use tokio::{
+    io::unix::AsyncFd,
+    signal::unix::{signal, Signal, SignalKind},
 };
 use tokio_util::sync::CancellationToken;
+#[cfg(unix)]
 use tonic::Response;
+#[cfg(target_os = "macos")]
+pub use wire_blahaj::bpf_device::InterfaceOptions;
+#[cfg(target_os = "macos")]
+use wire_blahaj::bpf_device::{
+    attach_filter, kernel_stats, open_interface, BpfCapture, BpfInsn as FilterInsn,
+};
+#[cfg(feature = "ebpf")]
+use wire_blahaj::flow_owner::FlowOwnerTracer;
+#[cfg(feature = "ebpf")]
+use wire_blahaj::go_tls_keys::GoKeyTracer;
+#[cfg(windows)]
+pub use wire_blahaj::npcap::InterfaceOptions;
+#[cfg(windows)]
+use wire_blahaj::npcap::{
+    attach_filter, open_interface, BpfInsn as FilterInsn, NpcapCapture, NpcapHandle, NpcapStats,
+};
+#[cfg(target_os = "linux")]
+use wire_blahaj::{
+    af_packet::{attach_filter, kernel_stats, open_interface},
+    probe::{probe_interface, InterfaceCapabilities},
+    ring::RingCapture,
+    unprivileged::{run_in_ns, AfterParentGo, LaunchHooks, UnprivilegedCapture, DEV_NAME},
+    xdp::{open_xdp, XdpCapture, XdpSocket, XdpStats},
+};
+#[cfg(target_os = "linux")]
+pub use wire_blahaj::{
+    af_packet::{netns_path, InterfaceOptions},
+    ring::RingOptions,
+    xdp::{XdpMode, XdpOptions},
+};
 use wire_blahaj::{
-    pcap_writer::{AsyncWriteHack, PcapWriter},
-    unprivileged::{run_in_ns, CapturedPacketMeta, LaunchHooks},
+    merge::MergeByTime,
+    pcap_writer::{AsyncWriteHack, CaptureMetadata, InterfaceInfo, PacketOptions, PcapWriter},
+    CapturedPacketMeta, KernelStats,
 };
 
+#[cfg(target_os = "linux")]
+use std::fs::read_link;
+#[cfg(unix)]
+use std::os::{
+    fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    unix::net::{UnixListener, UnixStream},
+};
 use std::{
-    fs::read_link,
-    future,
-    os::{
-        fd::{FromRawFd, OwnedFd, RawFd},
-        unix::net::UnixListener,
-    },
+    collections::BTreeMap,
+    future, io,
     path::{Path, PathBuf},
     pin::Pin,
     sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
+#[cfg(target_os = "linux")]
+use crate::handoff::receive_handoff;
 use crate::{
+    annotate::Annotator,
+    backpressure::Backpressure,
     devtools::{
-        make_devtools_listener, run_devtools_server, DevtoolsListener, DEVTOOLS_PORT_RANGE,
+        devtools_chomper, make_devtools_listener, run_devtools_server, DevtoolsListener,
+        DEVTOOLS_PORT_RANGE,
     },
+    key_store::KeyFile,
+    keylog_listen::{listen_key_log, KeyLogAddr},
+    keylog_tail::{tail_key_log, KeySender},
+    pause::CapturePause,
+    process_owner::{ConnectionOwner, ProcessOwners},
+    schedule::CaptureSchedule,
     Error,
 };
+#[cfg(unix)]
+use crate::{
+    handoff::{send_handoff, HandoffFds},
+    keylog_listen::remove_stale_socket,
+};
 
+#[cfg(unix)]
 struct EmbeddingServer {
     send: tokio::sync::mpsc::Sender<(ClientRandom, SecretType, Secret)>,
 }
 
+#[cfg(unix)]
 #[tonic::async_trait]
 impl ClipperEmbedding for EmbeddingServer {
     async fn new_keys(
@@ -99,6 +168,25 @@ pub trait CaptureTarget {
 
     async fn shutdown(self, key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error>;
 
+    /// Called before any packets with what we could find out about the
+    /// interface being captured on.
+    #[cfg(target_os = "linux")]
+    fn on_interface_probed(&mut self, _caps: &InterfaceCapabilities) {}
+
+    /// Called before any packets if only the first `snap_len` bytes of each
+    /// are kept.
+    fn on_snap_len(&mut self, _snap_len: u32) {}
+
+    /// Called when a scheduled capture window closes. Packets after this
+    /// belong to a new session.
+    async fn end_session(&mut self, _key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when a process opens a connection, if the capture is
+    /// attributing connections to processes.
+    fn on_connection_owner(&mut self, _owner: ConnectionOwner) {}
+
     /// Called after the key has been added to the key db already.
     async fn on_key(
         &mut self,
@@ -107,17 +195,81 @@ pub trait CaptureTarget {
         _secret_type: SecretType,
         _secret: Secret,
     ) -> Result<(), Error>;
+
+    /// The state of the connections being decoded, to be given to
+    /// [`CaptureTarget::restore`] by whoever takes the capture over.
+    fn checkpoint(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    /// Held back while whatever the target sends things on to is behind,
+    /// during which no packets are read. Targets that write files, such as
+    /// [`CaptureToPcap`], need none: each [`CaptureTarget::on_packet`] is
+    /// waited for, so a slow disk holds reading back by itself.
+    fn backpressure(&self) -> Option<Backpressure> {
+        None
+    }
+
+    /// Pauses the capture, for targets that let their users do that.
+    fn pause_control(&self) -> Option<CapturePause> {
+        None
+    }
+
+    /// Called before any packets if the capture was handed over with a
+    /// checkpoint.
+    fn restore(&mut self, _key_db: Arc<RwLock<KeyDB>>, _checkpoint: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
-pub struct CaptureToPcap {
+/// What to record about the capture in the section header of its files.
+fn capture_metadata() -> CaptureMetadata {
+    #[cfg(unix)]
+    let (hardware, os) = {
+        let uname = nix::sys::utsname::uname().ok();
+        (
+            uname
+                .as_ref()
+                .map(|u| u.machine().to_string_lossy().into_owned()),
+            uname.as_ref().map(|u| {
+                format!(
+                    "{} {}",
+                    u.sysname().to_string_lossy(),
+                    u.release().to_string_lossy()
+                )
+            }),
+        )
+    };
+    #[cfg(windows)]
+    let (hardware, os) = (
+        Some(std::env::consts::ARCH.to_owned()),
+        Some("Windows".to_owned()),
+    );
+    CaptureMetadata {
+        hardware,
+        os,
+        comments: vec![format!(
+            "capture started at {}",
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        )],
+    }
+}
+
+/// One output file's worth of capture.
+struct PcapSession {
     file: tokio::fs::File,
     packets_writer: tokio::io::BufWriter<tokio::fs::File>,
     writer: AsyncWriteHack,
     pcap_writer: PcapWriter,
+    started: Instant,
 }
 
-impl CaptureToPcap {
-    pub async fn new(output_file: &Path) -> Result<Self, Error> {
+impl PcapSession {
+    async fn new(
+        output_file: &Path,
+        if_info: &[(u32, InterfaceInfo)],
+        snap_len: Option<u32>,
+    ) -> Result<Self, Error> {
         let mut file = TokioOpenOptions::new()
             .write(true)
             .truncate(true)
@@ -133,93 +285,368 @@ impl CaptureToPcap {
         let packets_writer = tokio::io::BufWriter::new(packets_file);
 
         let mut writer = AsyncWriteHack::default();
-        let pcap_writer = PcapWriter::new(crate::APP_IDENTIFICATION, &mut writer)?;
+        let mut pcap_writer =
+            PcapWriter::new(crate::APP_IDENTIFICATION, &capture_metadata(), &mut writer)?;
         writer.flush_downstream(&mut file).await?;
+        for (if_index, info) in if_info {
+            pcap_writer.set_interface_info(*if_index, info.clone());
+        }
+        if let Some(snap_len) = snap_len {
+            pcap_writer.set_snap_len(snap_len);
+        }
 
         Ok(Self {
             file,
             pcap_writer,
             writer,
             packets_writer,
+            started: Instant::now(),
         })
     }
+
+    async fn finish(mut self, key_db: &RwLock<KeyDB>, embed_keys: bool) -> Result<(), Error> {
+        self.packets_writer.flush().await?;
+
+        if embed_keys {
+            let key_log = key_db.read().unwrap().to_key_log();
+            self.pcap_writer.on_dsb(&mut self.writer, &key_log)?;
+            self.writer.flush_downstream(&mut self.file).await?;
+        } else {
+            tracing::info!("not embedding keys in the capture, as requested");
+        }
+
+        let mut packets_file = self.packets_writer.into_inner();
+
+        packets_file.seek(std::io::SeekFrom::Start(0)).await?;
+        tokio::io::copy(&mut packets_file, &mut self.file).await?;
+        Ok(())
+    }
+}
+
+/// Names of the interfaces being captured on, to say where each packet was
+/// seen.
+#[derive(Default)]
+struct InterfaceNames(BTreeMap<u32, Arc<str>>);
+
+impl InterfaceNames {
+    #[cfg(target_os = "linux")]
+    fn on_interface_probed(&mut self, caps: &InterfaceCapabilities) {
+        if let Some(if_index) = caps.if_index {
+            self.0.insert(if_index, caps.name.as_str().into());
+        }
+    }
+
+    /// Where the packet of `meta` was seen and which way it was going, for
+    /// decoding it.
+    fn timing(&self, meta: &CapturedPacketMeta) -> TimingInfo {
+        let index = meta.if_index as u32;
+        TimingInfo {
+            received_on_wire: meta.time,
+            interface: Some(CaptureInterface {
+                index,
+                name: self.0.get(&index).cloned(),
+            }),
+            direction: meta
+                .packet_type
+                .and_then(|t| PacketDirection::from_sll(t as u16)),
+            ..Default::default()
+        }
+    }
+}
+
+pub struct CaptureToPcap {
+    output_file: PathBuf,
+    /// Whether to write the keys into the file at the end.
+    embed_keys: bool,
+    /// Whether each session goes into its own file, named after the time it
+    /// started.
+    rotate: bool,
+    /// Start a new file once the current one has been written to for this
+    /// long.
+    rotate_every: Option<Duration>,
+    /// Whether to decode the packets as they are written and comment on
+    /// them, e.g. where each request starts.
+    annotate: bool,
+    annotator: Option<Annotator>,
+    if_info: Vec<(u32, InterfaceInfo)>,
+    snap_len: Option<u32>,
+    if_names: InterfaceNames,
+    session: Option<PcapSession>,
+}
+
+impl CaptureToPcap {
+    pub async fn new(
+        output_file: &Path,
+        embed_keys: bool,
+        rotate: bool,
+        annotate: bool,
+    ) -> Result<Self, Error> {
+        let mut this = Self {
+            output_file: output_file.to_owned(),
+            embed_keys,
+            rotate,
+            rotate_every: None,
+            annotate,
+            annotator: None,
+            if_info: Vec::new(),
+            snap_len: None,
+            if_names: InterfaceNames::default(),
+            session: None,
+        };
+        // Rotated files are only created once there is something to put in
+        // them.
+        if !rotate {
+            this.session = Some(PcapSession::new(output_file, &[], None).await?);
+        }
+        Ok(this)
+    }
+
+    /// `capture.pcapng` becomes `capture-2023-08-01T09-00-00Z.pcapng`.
+    fn rotated_file_name(&self) -> PathBuf {
+        let time = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(':', "-");
+        let stem = self
+            .output_file
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.output_file.extension() {
+            Some(ext) => format!("{stem}-{time}.{}", ext.to_string_lossy()),
+            None => format!("{stem}-{time}"),
+        };
+        self.output_file.with_file_name(name)
+    }
+
+    async fn session(&mut self) -> Result<&mut PcapSession, Error> {
+        if self.session.is_none() {
+            let path = self.rotated_file_name();
+            tracing::info!("Starting capture file {}", path.display());
+            self.session = Some(PcapSession::new(&path, &self.if_info, self.snap_len).await?);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    /// Finishes the current file if it is due to be rotated.
+    async fn maybe_rotate(&mut self, key_db: &RwLock<KeyDB>) -> Result<(), Error> {
+        let Some(every) = self.rotate_every else {
+            return Ok(());
+        };
+        if self
+            .session
+            .as_ref()
+            .is_some_and(|s| s.started.elapsed() >= every)
+        {
+            let session = self.session.take().unwrap();
+            session.finish(key_db, self.embed_keys).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A pcapng to record a capture to while also serving a devtools server on
+/// it, to look through again later.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    pub file: PathBuf,
+    /// Whether to write the keys into the file, so that it can be decrypted
+    /// on its own.
+    pub embed_keys: bool,
+    /// Start a new file this often, named after the time it started, like
+    /// for scheduled windows.
+    pub rotate_every: Option<Duration>,
+}
+
+/// Opens the file of `recording`, if there is one. With
+/// `rotate_on_windows`, each scheduled window is written to its own file.
+async fn start_recording(
+    recording: Option<Recording>,
+    rotate_on_windows: bool,
+) -> Result<Option<CaptureToPcap>, Error> {
+    let Some(recording) = recording else {
+        return Ok(None);
+    };
+    let rotate = rotate_on_windows || recording.rotate_every.is_some();
+    let mut pcap = CaptureToPcap::new(&recording.file, recording.embed_keys, rotate, false).await?;
+    pcap.rotate_every = recording.rotate_every;
+    tracing::info!("Recording the capture to {}", recording.file.display());
+    Ok(Some(pcap))
 }
 
 #[async_trait::async_trait]
 impl CaptureTarget for CaptureToPcap {
     async fn on_packet(
         &mut self,
-        _key_db: Arc<RwLock<KeyDB>>,
+        key_db: Arc<RwLock<KeyDB>>,
         meta: CapturedPacketMeta,
         packet: Vec<u8>,
     ) -> Result<(), Error> {
-        self.pcap_writer.on_packet(
-            &mut self.writer,
-            wire_blahaj::ts_to_nanos(meta.time),
+        self.maybe_rotate(&key_db).await?;
+        let timing = self.if_names.timing(&meta);
+        let flags = timing.direction.map(PacketDirection::epb_flags);
+        let (comments, names): (Vec<String>, _) = if self.annotate {
+            let annotator = self
+                .annotator
+                .get_or_insert_with(|| Annotator::new(key_db, DecodeOptions::default()));
+            // The earlier packets of a range are written already, so it
+            // can only be commented on at its end.
+            let annotations = annotator.annotate(timing, Linktype::ETHERNET, &packet)?;
+            (
+                annotations.into_iter().map(|a| a.text).collect(),
+                annotator.take_names(),
+            )
+        } else {
+            Default::default()
+        };
+
+        let session = self.session().await?;
+        if !names.is_empty() {
+            session.pcap_writer.on_names(&mut session.writer, &names)?;
+        }
+        session.pcap_writer.on_packet_with_options(
+            &mut session.writer,
+            meta.time,
             meta.if_index as u32,
             &packet,
+            meta.len,
+            PacketOptions {
+                comments: &comments,
+                flags,
+            },
         )?;
-        self.writer
-            .flush_downstream(&mut self.packets_writer)
+        session
+            .writer
+            .flush_downstream(&mut session.packets_writer)
             .await?;
 
         tracing::trace!("pakit {} {}", meta.time, hexdump::HexDumper::new(&packet));
         Ok(())
     }
 
-    async fn shutdown(mut self, key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error> {
-        self.packets_writer.flush().await?;
+    #[cfg(target_os = "linux")]
+    fn on_interface_probed(&mut self, caps: &InterfaceCapabilities) {
+        self.if_names.on_interface_probed(caps);
+        if let Some(if_index) = caps.if_index {
+            let info = InterfaceInfo {
+                name: Some(caps.name.clone()),
+                comment: Some(caps.to_string()),
+                ..Default::default()
+            };
+            if let Some(session) = &mut self.session {
+                session
+                    .pcap_writer
+                    .set_interface_info(if_index, info.clone());
+            }
+            self.if_info.push((if_index, info));
+        }
+    }
 
-        self.pcap_writer
-            .on_dsb(&mut self.writer, &key_db.read().unwrap().to_key_log())?;
-        self.writer.flush_downstream(&mut self.file).await?;
+    fn on_snap_len(&mut self, snap_len: u32) {
+        if let Some(session) = &mut self.session {
+            session.pcap_writer.set_snap_len(snap_len);
+        }
+        self.snap_len = Some(snap_len);
+    }
 
-        let mut packets_file = self.packets_writer.into_inner();
+    async fn end_session(&mut self, key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error> {
+        if !self.rotate {
+            return Ok(());
+        }
+        if let Some(session) = self.session.take() {
+            session.finish(&key_db, self.embed_keys).await?;
+        }
+        Ok(())
+    }
 
-        packets_file.seek(std::io::SeekFrom::Start(0)).await?;
-        tokio::io::copy(&mut packets_file, &mut self.file).await?;
+    async fn shutdown(mut self, key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error> {
+        if let Some(session) = self.session.take() {
+            session.finish(&key_db, self.embed_keys).await?;
+        }
         Ok(())
     }
 
     async fn on_key(
         &mut self,
         _key_db: Arc<RwLock<KeyDB>>,
-        _client_random: ClientRandom,
-        _secret_type: SecretType,
-        _secret: Secret,
+        client_random: ClientRandom,
+        secret_type: SecretType,
+        secret: Secret,
     ) -> Result<(), Error> {
+        if let Some(annotator) = &mut self.annotator {
+            annotator.on_key(client_random, secret_type, secret);
+        }
         Ok(())
     }
 }
 
 pub struct CaptureToDevtools {
     devtools_listener: Option<DevtoolsListener>,
-    chomper: Option<EthernetChomper<ListenerDispatcher>>,
+    body_policies: BodyPolicies,
+    live: LiveConfig,
+    owners: ProcessOwners,
+    decode_options: DecodeOptions,
+    chomper: Option<CheckpointingChomper<ListenerDispatcher>>,
+    if_names: InterfaceNames,
+    pressure: Backpressure,
+    pause: CapturePause,
     join: tokio::task::JoinHandle<Result<(), Error>>,
+    /// Stops the server, if it carries on after the capture finishes.
+    serve_after: Option<CancellationToken>,
+    /// Where everything served is also written to.
+    recording: Option<CaptureToPcap>,
 }
 
 impl CaptureToDevtools {
-    async fn new(terminate: CancellationToken) -> Self {
-        let (devtools_listener, bits) = make_devtools_listener();
+    /// With `serve_after`, the server carries on after the capture
+    /// finishes, until Ctrl-C. With a `recording`, the packets and keys are
+    /// written to it too.
+    async fn new(
+        terminate: CancellationToken,
+        body_policies: BodyPolicies,
+        decode_options: DecodeOptions,
+        serve_after: bool,
+        recording: Option<CaptureToPcap>,
+    ) -> Result<Self, Error> {
+        let (devtools_listener, bits) = make_devtools_listener(&decode_options)?;
+        let live = bits.live.clone();
+        let owners = bits.owners.clone();
+        let pressure = bits.backpressure();
+        let pause = bits.pause.clone();
 
-        let join =
-            tokio::spawn(
-                async move { run_devtools_server(bits, terminate, DEVTOOLS_PORT_RANGE).await },
-            );
+        let serve_after = serve_after.then(CancellationToken::new);
+        let server_cancel = serve_after.clone().unwrap_or(terminate);
+        let join = tokio::spawn(async move {
+            run_devtools_server(bits, server_cancel, DEVTOOLS_PORT_RANGE).await
+        });
 
-        Self {
+        Ok(Self {
             join,
+            serve_after,
+            recording,
             chomper: None,
+            if_names: InterfaceNames::default(),
+            pressure,
+            pause,
             devtools_listener: Some(devtools_listener),
-        }
+            body_policies,
+            live,
+            owners,
+            decode_options,
+        })
     }
 
     fn init(&mut self, key_db: Arc<RwLock<KeyDB>>) {
         if self.chomper.is_none() {
-            self.chomper = Some(net_decode::chomper(
+            let gate = ReplayGate::default();
+            let chomper = devtools_chomper(
                 self.devtools_listener.take().unwrap(),
+                std::mem::take(&mut self.body_policies),
+                self.live.clone(),
+                gate.clone(),
+                self.decode_options.clone(),
                 key_db,
-            ));
+            );
+            self.chomper = Some(CheckpointingChomper::new(chomper, gate));
         }
     }
 }
@@ -232,17 +659,52 @@ impl CaptureTarget for CaptureToDevtools {
         meta: CapturedPacketMeta,
         packet: Vec<u8>,
     ) -> Result<(), Error> {
-        self.init(key_db);
+        self.init(key_db.clone());
         self.chomper.as_mut().unwrap().chomp(
-            TimingInfo {
-                received_on_wire: wire_blahaj::ts_to_nanos(meta.time),
-                other_times: Default::default(),
-            },
+            self.if_names.timing(&meta),
+            Linktype::ETHERNET,
             &packet,
-        )
+        )?;
+        if let Some(recording) = &mut self.recording {
+            recording.on_packet(key_db, meta, packet).await?;
+        }
+        Ok(())
     }
 
-    async fn shutdown(mut self, _key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    fn on_interface_probed(&mut self, caps: &InterfaceCapabilities) {
+        self.if_names.on_interface_probed(caps);
+        if let Some(recording) = &mut self.recording {
+            recording.on_interface_probed(caps);
+        }
+    }
+
+    fn on_snap_len(&mut self, snap_len: u32) {
+        if let Some(recording) = &mut self.recording {
+            recording.on_snap_len(snap_len);
+        }
+    }
+
+    async fn end_session(&mut self, key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error> {
+        if let Some(recording) = &mut self.recording {
+            recording.end_session(key_db).await?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(mut self, key_db: Arc<RwLock<KeyDB>>) -> Result<(), Error> {
+        // Finished first, so that it is all there while the server carries
+        // on.
+        if let Some(recording) = self.recording.take() {
+            recording.shutdown(key_db).await?;
+        }
+        if let Some(cancel) = &self.serve_after {
+            tracing::info!("Capture finished, still serving what was seen until Ctrl-C");
+            tokio::select! {
+                r = &mut self.join => return r?,
+                _ = tokio::signal::ctrl_c() => cancel.cancel(),
+            }
+        }
         self.join.await??;
         Ok(())
     }
@@ -261,85 +723,1000 @@ impl CaptureTarget for CaptureToDevtools {
             .on_key(client_random, secret_type, secret);
         Ok(())
     }
+
+    fn backpressure(&self) -> Option<Backpressure> {
+        Some(self.pressure.clone())
+    }
+
+    fn pause_control(&self) -> Option<CapturePause> {
+        Some(self.pause.clone())
+    }
+
+    fn on_connection_owner(&mut self, owner: ConnectionOwner) {
+        self.owners.insert(owner);
+    }
+
+    fn checkpoint(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(chomper) = &mut self.chomper else {
+            return Ok(None);
+        };
+        let mut out = Vec::new();
+        chomper.checkpoint(&mut out)?;
+        Ok(Some(out))
+    }
+
+    fn restore(&mut self, key_db: Arc<RwLock<KeyDB>>, checkpoint: &[u8]) -> Result<(), Error> {
+        self.init(key_db);
+        self.chomper.as_mut().unwrap().restore(checkpoint)
+    }
+}
+
+/// Where to get keys from, besides the library injected into the program.
+#[derive(Clone, Debug, Default)]
+pub struct KeySources {
+    /// SSLKEYLOGFILE to set for the child and follow for keys.
+    pub key_log_file: Option<PathBuf>,
+    /// Socket to accept key log lines on.
+    pub listen: Option<KeyLogAddr>,
+    /// Go programs to read keys out of with eBPF, since injecting into them
+    /// does nothing.
+    pub go_binaries: Vec<PathBuf>,
+    /// Load the Java agent into JVMs the program starts, to get the keys of
+    /// Java's own TLS.
+    pub java_agent: bool,
+}
+
+/// Written by the Java agent, in the capture's temporary directory.
+const JAVA_KEY_LOG_NAME: &str = "java-keys.log";
+
+impl KeySources {
+    fn spawn(self, temp_dir: &Path, send: &KeySender, terminate: &CancellationToken) {
+        if let Some(path) = self.key_log_file {
+            let (send, terminate) = (send.clone(), terminate.clone());
+            tokio::spawn(async move {
+                if let Err(e) = tail_key_log(path, send, terminate).await {
+                    tracing::error!("Error reading key log: {e}");
+                }
+            });
+        }
+        if let Some(addr) = self.listen {
+            let (send, terminate) = (send.clone(), terminate.clone());
+            tokio::spawn(async move {
+                if let Err(e) = listen_key_log(addr.clone(), send, terminate).await {
+                    tracing::error!("Error listening for keys on {addr}: {e}");
+                }
+            });
+        }
+        if self.java_agent {
+            let path = temp_dir.join(JAVA_KEY_LOG_NAME);
+            let (send, terminate) = (send.clone(), terminate.clone());
+            tokio::spawn(async move {
+                if let Err(e) = tail_key_log(path, send, terminate).await {
+                    tracing::error!("Error reading keys from the Java agent: {e}");
+                }
+            });
+        }
+        for binary in self.go_binaries {
+            if let Err(e) = start_go_key_tracer(&binary, send.clone(), terminate.clone()) {
+                tracing::error!("Error reading keys from {}: {e}", binary.display());
+            }
+        }
+    }
+}
+
+/// Everything about how to run a capture that does not depend on where it
+/// is going.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureOptions {
+    pub key_sources: KeySources,
+    /// Socket to accept a replacement clipper on.
+    pub handoff_socket: Option<PathBuf>,
+    pub schedule: CaptureSchedule,
+    /// Keys to start with, e.g. from an earlier capture.
+    pub load_keys: Option<KeyFile>,
+    /// Where to save all the keys when the capture finishes.
+    pub save_keys: Option<KeyFile>,
+    /// Server private keys, for TLS 1.2 connections using static RSA key
+    /// exchange.
+    pub rsa_keys: Vec<RsaKey>,
+    /// External pre-shared keys, for TLS-PSK connections.
+    pub psks: Vec<ExternalPsk>,
+    /// Find out which process opened each connection, with eBPF.
+    pub attribute_processes: bool,
+    /// Only capture packets this matches. Attached to the capture socket,
+    /// so the rest are dropped by the kernel.
+    pub filter: Option<CaptureFilter>,
+    /// Only keep this many bytes of each packet, e.g. enough for the
+    /// headers. How long the packets were is still recorded, and decoding
+    /// marks what was cut off as missing.
+    pub snap_len: Option<u32>,
+    /// Start with the capture paused, see [`crate::pause`].
+    pub start_paused: bool,
+}
+
+impl CaptureOptions {
+    fn fixup_paths(mut self) -> Result<Self, Error> {
+        // The child runs in another mount namespace, but with the same view
+        // of the filesystem, so this only needs to be absolute.
+        self.key_sources.key_log_file = match self.key_sources.key_log_file {
+            Some(p) if p.is_relative() => Some(std::env::current_dir()?.join(p)),
+            p => p,
+        };
+        Ok(self)
+    }
+
+    fn initial_keys(&self) -> Result<KeyDB, Error> {
+        let mut key_db = match &self.load_keys {
+            Some(f) => f.load()?,
+            None => KeyDB::default(),
+        };
+        self.add_configured_keys(&mut key_db);
+        Ok(key_db)
+    }
+
+    /// Adds the keys that are configured rather than logged, which are not
+    /// saved with the rest.
+    fn add_configured_keys(&self, key_db: &mut KeyDB) {
+        for key in &self.rsa_keys {
+            key_db.add_rsa_key(key.clone());
+        }
+        for psk in &self.psks {
+            key_db.add_psk(psk.clone());
+        }
+    }
+}
+
+/// How a capture ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureEnd {
+    /// The program exited, we were interrupted or the schedule ran out.
+    Finished,
+    /// Another clipper took over the capture, along with the connections
+    /// still open. The program is left running.
+    HandedOff,
+}
+
+/// A packet socket to capture from, or on Windows, an Npcap capture.
+struct CaptureSocket {
+    #[cfg(unix)]
+    fd: RawFd,
+    /// Taken once packets start being read from it.
+    #[cfg(windows)]
+    handle: Option<NpcapHandle>,
+    #[cfg(windows)]
+    stats: NpcapStats,
+    /// Interface the socket is on.
+    interface: String,
+    /// Read the socket through a ring rather than with `recvmsg`.
+    #[cfg(target_os = "linux")]
+    ring: Option<RingOptions>,
+    /// Read the interface through `AF_XDP` sockets instead, in which case
+    /// `fd` is only for asking about the interface.
+    #[cfg(target_os = "linux")]
+    xdp: Option<XdpSocket>,
+}
+
+impl CaptureSocket {
+    fn is_xdp(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.xdp.is_some();
+        #[cfg(not(target_os = "linux"))]
+        return false;
+    }
+
+    fn stats_source(&self) -> StatsSource {
+        #[cfg(unix)]
+        return self.fd;
+        #[cfg(windows)]
+        return self.stats.clone();
+    }
+}
+
+/// What a capture filter is attached to.
+#[cfg(unix)]
+type CaptureHandle = RawFd;
+#[cfg(windows)]
+type CaptureHandle = NpcapHandle;
+
+/// What the kernel's counts of a capture are read from. Npcap's are read by
+/// the thread reading its packets, which leaves them to be picked up.
+#[cfg(unix)]
+type StatsSource = RawFd;
+#[cfg(windows)]
+type StatsSource = NpcapStats;
+
+/// How often to ask the kernel whether it has had to drop packets.
+const DROP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The kernel's counts for all the sockets of a capture, added up.
+struct KernelCounters {
+    /// Each socket, with what the kernel has counted on it so far.
+    sockets: Vec<(StatsSource, KernelStats)>,
+    /// Interfaces captured through `AF_XDP`, which are counted differently.
+    #[cfg(target_os = "linux")]
+    xdp: Vec<XdpStats>,
+    total: KernelStats,
+}
+
+impl KernelCounters {
+    fn new(sockets: &[CaptureSocket]) -> Self {
+        KernelCounters {
+            sockets: sockets
+                .iter()
+                .filter(|s| !s.is_xdp())
+                .map(|s| (s.stats_source(), KernelStats::default()))
+                .collect(),
+            #[cfg(target_os = "linux")]
+            xdp: sockets
+                .iter()
+                .filter_map(|s| s.xdp.as_ref().map(|x| x.stats()))
+                .collect(),
+            total: KernelStats::default(),
+        }
+    }
+
+    /// Adds in what the kernel has counted since last time, and returns it.
+    fn update(&mut self) -> KernelStats {
+        let mut new = KernelStats::default();
+        for (source, seen) in &mut self.sockets {
+            #[cfg(unix)]
+            let stats = kernel_stats(*source);
+            #[cfg(windows)]
+            let stats = Ok::<_, Error>(source.get());
+            match stats {
+                // Reading the counts resets them.
+                #[cfg(target_os = "linux")]
+                Ok(stats) => {
+                    new += stats;
+                    *seen += stats;
+                }
+                // BPF devices and Npcap keep running totals.
+                #[cfg(any(target_os = "macos", windows))]
+                Ok(stats) => {
+                    new += stats - *seen;
+                    *seen = stats;
+                }
+                Err(e) => tracing::debug!("could not get packet counts from the kernel: {e}"),
+            }
+        }
+        #[cfg(target_os = "linux")]
+        for xdp in &mut self.xdp {
+            match xdp.take() {
+                Ok(stats) => new += stats,
+                Err(e) => tracing::debug!("could not get packet counts from the kernel: {e}"),
+            }
+        }
+        self.total += new;
+        new
+    }
+}
+
+/// Everything a capture runs off of, whether it was started by us or handed
+/// over from another clipper.
+struct CaptureContext {
+    /// Socket for the injected library to send keys to. Only there if we
+    /// are capturing a program.
+    #[cfg(unix)]
+    listener: Option<UnixListener>,
+    /// One per interface, merged in order of time. A capture of a program
+    /// has only the one.
+    sockets: Vec<CaptureSocket>,
+    /// The program being captured, if any.
+    #[cfg(unix)]
+    child_pidfd: Option<RawFd>,
+    temp_dir: PathBuf,
+    key_db: KeyDB,
+    /// Checkpoint from the previous clipper, to restore before any packets.
+    flows: Option<Vec<u8>>,
+    options: CaptureOptions,
+}
+
+/// Compiles `filter` and attaches it to the capture socket, which always
+/// gets Ethernet frames. The filter can also cut packets short at
+/// `snap_len`.
+fn attach_capture_filter(
+    capture: &CaptureHandle,
+    filter: Option<&CaptureFilter>,
+    snap_len: Option<u32>,
+) -> Result<(), Error> {
+    let program = match (filter, snap_len) {
+        (Some(filter), None) => filter.compile(Linktype::ETHERNET)?,
+        (Some(filter), Some(snap_len)) => {
+            filter.compile(Linktype::ETHERNET)?.with_snap_len(snap_len)
+        }
+        (None, Some(snap_len)) => BpfProgram::snap_len_only(snap_len),
+        (None, None) => return Ok(()),
+    };
+    let program: Vec<_> = program
+        .instructions()
+        .iter()
+        .map(|insn| FilterInsn {
+            code: insn.code,
+            jt: insn.jt,
+            jf: insn.jf,
+            k: insn.k,
+        })
+        .collect();
+    attach_filter(capture, &program)?;
+    if let Some(filter) = filter {
+        tracing::debug!("capturing only {filter}");
+    }
+    Ok(())
+}
+
+/// Asks the program to exit, for when the schedule says we are done.
+#[cfg(target_os = "linux")]
+fn terminate_child(child_pidfd: RawFd) {
+    // FIXME: nix does not have pidfd_send_signal yet.
+    let ret = unsafe {
+        nix::libc::syscall(
+            nix::libc::SYS_pidfd_send_signal,
+            child_pidfd,
+            nix::libc::SIGTERM,
+            std::ptr::null::<()>(),
+            0,
+        )
+    };
+    if ret != 0 {
+        tracing::warn!(
+            "could not stop the program: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// There is no program to stop on macOS, where only interfaces are captured.
+#[cfg(target_os = "macos")]
+fn terminate_child(_child_pidfd: RawFd) {}
+
+fn sleep_until(t: SystemTime) -> tokio::time::Sleep {
+    let after = t.duration_since(SystemTime::now()).unwrap_or_default();
+    tokio::time::sleep_until(tokio::time::Instant::now() + after)
+}
+
+/// Accepts the clipper a capture of a program is handed over to.
+#[cfg(unix)]
+struct HandoffListener {
+    listener: tokio::net::UnixListener,
+    fds: HandoffFds,
+}
+
+#[cfg(unix)]
+impl HandoffListener {
+    /// Only captures of a program can be handed over, which is what `fds`
+    /// are there for.
+    async fn bind(path: &Path, fds: Option<HandoffFds>) -> Result<Self, Error> {
+        // Refused up front too, see do_capture_interface.
+        let fds = fds.ok_or("only captures of a program can be handed over")?;
+        remove_stale_socket(path)?;
+        let listener = tokio::net::UnixListener::bind(path)?;
+        tracing::info!(
+            "Accepting a replacement clipper on {}; run `clipper resume --from {}` to hand over",
+            path.display(),
+            path.display()
+        );
+        Ok(HandoffListener { listener, fds })
+    }
+
+    async fn accept(&self) -> io::Result<PendingHandoff> {
+        let conn = self.listener.accept().await?.0.into_std()?;
+        conn.set_nonblocking(false)?;
+        Ok(PendingHandoff {
+            conn,
+            fds: self.fds,
+        })
+    }
+}
+
+/// A clipper that has asked to take over the capture.
+#[cfg(unix)]
+struct PendingHandoff {
+    conn: UnixStream,
+    fds: HandoffFds,
+}
+
+#[cfg(unix)]
+impl PendingHandoff {
+    fn send(self, temp_dir: &Path, key_db: &KeyDB, flows: Option<&[u8]>) -> Result<(), Error> {
+        send_handoff(&self.conn, self.fds, temp_dir, key_db, flows)
+    }
+}
+
+/// Handing over sends file descriptors over a unix socket, which Windows
+/// does not have, so there is never anything to accept.
+#[cfg(windows)]
+enum HandoffListener {}
+
+#[cfg(windows)]
+impl HandoffListener {
+    async fn accept(&self) -> io::Result<PendingHandoff> {
+        match *self {}
+    }
+}
+
+#[cfg(windows)]
+enum PendingHandoff {}
+
+#[cfg(windows)]
+impl PendingHandoff {
+    fn send(self, _temp_dir: &Path, _key_db: &KeyDB, _flows: Option<&[u8]>) -> Result<(), Error> {
+        match self {}
+    }
+}
+
+async fn accept_handoff(listener: Option<&HandoffListener>) -> io::Result<PendingHandoff> {
+    match listener {
+        Some(l) => l.accept().await,
+        None => future::pending().await,
+    }
+}
+
+/// Pauses and resumes the capture on SIGUSR1 and SIGUSR2. Windows has no
+/// such signals, so there it is only done from devtools.
+struct PauseSignals {
+    #[cfg(unix)]
+    pause: Signal,
+    #[cfg(unix)]
+    resume: Signal,
+}
+
+impl PauseSignals {
+    fn new() -> io::Result<Self> {
+        Ok(PauseSignals {
+            #[cfg(unix)]
+            pause: signal(SignalKind::user_defined1())?,
+            #[cfg(unix)]
+            resume: signal(SignalKind::user_defined2())?,
+        })
+    }
+
+    /// Waits for the next signal: `true` to pause, `false` to resume.
+    async fn recv(&mut self) -> Option<bool> {
+        #[cfg(unix)]
+        return tokio::select! {
+            Some(()) = self.pause.recv() => Some(true),
+            Some(()) = self.resume.recv() => Some(false),
+            else => None,
+        };
+        #[cfg(windows)]
+        return future::pending().await;
+    }
+}
+
+/// Waits for the program being captured to exit.
+struct ChildExit {
+    #[cfg(unix)]
+    pidfd: Option<AsyncFd<OwnedFd>>,
+}
+
+impl ChildExit {
+    fn new(ctx: &CaptureContext) -> io::Result<Self> {
+        #[cfg(windows)]
+        let _ = ctx;
+        Ok(ChildExit {
+            #[cfg(unix)]
+            pidfd: ctx
+                .child_pidfd
+                .map(|fd| AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fd) }))
+                .transpose()?,
+        })
+    }
+
+    /// Never finishes if there is no program, as when capturing interfaces.
+    async fn wait(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(fd) = &self.pidfd {
+            return fd.readable().await.map(|_| ());
+        }
+        future::pending().await
+    }
+}
+
+type CaptureStream = Box<dyn Stream<Item = io::Result<(Vec<u8>, CapturedPacketMeta)>> + Unpin>;
+
+/// Sets up a capture socket and starts reading packets from it.
+#[cfg(target_os = "linux")]
+fn open_capture_stream(
+    socket: &mut CaptureSocket,
+    options: &CaptureOptions,
+    target: &mut impl CaptureTarget,
+) -> Result<CaptureStream, Error> {
+    if let Some(xdp) = socket.xdp.take() {
+        let mut caps = probe_interface(socket.fd, &socket.interface);
+        // That was of the socket asked with, and packets queue in the rings
+        // anyway.
+        caps.rcvbuf = None;
+        return open_xdp_stream(xdp, caps, options, target);
+    }
+
+    // Packets cut short by the kernel are only said to be by a ring:
+    // `recvmsg` would give how long they were after being cut.
+    let kernel_snap_len = socket.ring.and(options.snap_len);
+    attach_capture_filter(&socket.fd, options.filter.as_ref(), kernel_snap_len)?;
+    let caps = probe_interface(socket.fd, &socket.interface);
+    tracing::debug!("interface capabilities: {caps}");
+    for advice in caps.guidance() {
+        tracing::warn!("{advice}");
+    }
+    target.on_interface_probed(&caps);
+
+    Ok(match socket.ring {
+        Some(ring) => Box::new(unsafe { RingCapture::new(socket.fd, ring)? }),
+        None => {
+            let mut cap = unsafe { UnprivilegedCapture::new(socket.fd)? };
+            if let Some(snap_len) = options.snap_len {
+                cap = cap.with_snap_len(snap_len as usize);
+            }
+            Box::new(cap)
+        }
+    })
+}
+
+/// Starts reading packets from the `AF_XDP` sockets of an interface. There
+/// is no socket to attach the filter to, so it is run on the packets here.
+#[cfg(target_os = "linux")]
+fn open_xdp_stream(
+    xdp: XdpSocket,
+    caps: InterfaceCapabilities,
+    options: &CaptureOptions,
+    target: &mut impl CaptureTarget,
+) -> Result<CaptureStream, Error> {
+    tracing::debug!("interface capabilities: {caps}");
+    for advice in caps.guidance() {
+        tracing::warn!("{advice}");
+    }
+    target.on_interface_probed(&caps);
+
+    let cap = XdpCapture::new(xdp)?;
+    let Some(filter) = &options.filter else {
+        return Ok(match options.snap_len {
+            Some(snap_len) => Box::new(cap.with_snap_len(snap_len as usize)),
+            None => Box::new(cap),
+        });
+    };
+    // Cut short after filtering, so that the filter sees the headers.
+    let program = filter.compile(Linktype::ETHERNET)?;
+    let snap_len = options.snap_len.map_or(usize::MAX, |s| s as usize);
+    tracing::debug!("capturing only {filter}");
+    Ok(Box::new(cap.filter_map(move |packet| {
+        future::ready(match packet {
+            Ok((mut data, meta)) if program.matches(&data) => {
+                data.truncate(snap_len);
+                Some(Ok((data, meta)))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    })))
+}
+
+/// Sets up a BPF device and starts reading packets from it.
+#[cfg(target_os = "macos")]
+fn open_capture_stream(
+    socket: &mut CaptureSocket,
+    options: &CaptureOptions,
+    _target: &mut impl CaptureTarget,
+) -> Result<CaptureStream, Error> {
+    // BPF devices give how long packets were even if the filter cut them.
+    attach_capture_filter(&socket.fd, options.filter.as_ref(), options.snap_len)?;
+    tracing::debug!("capturing on {} through a BPF device", socket.interface);
+    Ok(Box::new(unsafe { BpfCapture::new(socket.fd)? }))
+}
+
+/// Starts reading packets from an Npcap capture.
+#[cfg(windows)]
+fn open_capture_stream(
+    socket: &mut CaptureSocket,
+    options: &CaptureOptions,
+    _target: &mut impl CaptureTarget,
+) -> Result<CaptureStream, Error> {
+    let handle = socket
+        .handle
+        .take()
+        .ok_or("capture on this interface was already started")?;
+    // Like BPF devices, Npcap says how long packets were even if the filter
+    // cut them.
+    attach_capture_filter(&handle, options.filter.as_ref(), options.snap_len)?;
+    tracing::debug!("capturing on {} through Npcap", socket.interface);
+    Ok(Box::new(NpcapCapture::new(handle)?))
 }
 
 async fn start_capture(
     mut target: (impl CaptureTarget + Unpin),
-    listener: UnixListener,
-    raw_fd: RawFd,
+    mut ctx: CaptureContext,
     terminate: CancellationToken,
-) -> Result<(), Error> {
-    let mut cap = unsafe { wire_blahaj::unprivileged::UnprivilegedCapture::new(raw_fd)? }.fuse();
+) -> Result<CaptureEnd, Error> {
+    // Only captures of a program are handed over, and they have the one
+    // socket.
+    #[cfg(unix)]
+    let handoff_fds = match (ctx.child_pidfd, &ctx.listener) {
+        (Some(child_pidfd), Some(listener)) => Some(HandoffFds {
+            capture_fd: ctx.sockets[0].fd,
+            child_pidfd,
+            embedding_listener: listener.as_raw_fd(),
+        }),
+        _ => None,
+    };
+    if let Some(snap_len) = ctx.options.snap_len {
+        target.on_snap_len(snap_len);
+    }
+    let mut counters = KernelCounters::new(&ctx.sockets);
+    let mut streams = Vec::new();
+    for socket in &mut ctx.sockets {
+        streams.push(open_capture_stream(socket, &ctx.options, &mut target)?);
+    }
+    let mut cap = MergeByTime::new(streams, |(_, meta): &(Vec<u8>, CapturedPacketMeta)| {
+        meta.time
+    })
+    .fuse();
+    let mut drop_check = tokio::time::interval_at(
+        tokio::time::Instant::now() + DROP_CHECK_INTERVAL,
+        DROP_CHECK_INTERVAL,
+    );
 
-    let key_db: Arc<RwLock<KeyDB>> = Default::default();
+    let key_db: Arc<RwLock<KeyDB>> = Arc::new(RwLock::new(ctx.key_db));
+    if let Some(flows) = &ctx.flows {
+        // Not worth failing the capture over; those connections just go
+        // undecoded.
+        if let Err(e) = target.restore(key_db.clone(), flows) {
+            tracing::warn!("could not restore connections from the previous clipper: {e}");
+        }
+    }
+
+    let CaptureOptions {
+        key_sources,
+        handoff_socket,
+        mut schedule,
+        load_keys: _,
+        save_keys,
+        rsa_keys: _,
+        psks: _,
+        attribute_processes,
+        filter: _,
+        snap_len: _,
+        start_paused,
+    } = ctx.options;
+    let handoff_listener = match &handoff_socket {
+        #[cfg(unix)]
+        Some(path) => Some(HandoffListener::bind(path, handoff_fds).await?),
+        #[cfg(windows)]
+        Some(_) => return Err("handing a capture over is not supported on Windows".into()),
+        None => None,
+    };
 
-    let listener = tokio::net::UnixListener::from_std(listener)?;
-    let listener_stream = tokio_stream::wrappers::UnixListenerStream::new(listener);
     let (send, mut recv_keys) = tokio::sync::mpsc::channel(1000);
-    let embedding_server = EmbeddingServer { send };
+    key_sources.spawn(&ctx.temp_dir, &send, &terminate);
 
-    let mut server_join = tokio::spawn(
-        tonic::transport::Server::builder()
-            .add_service(ClipperEmbeddingServer::new(embedding_server))
-            .serve_with_incoming(listener_stream),
-    );
+    let (send_owner, mut recv_owners) = tokio::sync::mpsc::unbounded_channel();
+    let _tracer = if attribute_processes {
+        Some(start_flow_owner_tracer(send_owner)?)
+    } else {
+        None
+    };
 
-    loop {
-        tokio::select! {
-            v = cap.select_next_some() => {
-                let (v, meta) = v?;
+    #[cfg(unix)]
+    let mut server_join = match ctx.listener {
+        Some(listener) => {
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            let listener_stream = tokio_stream::wrappers::UnixListenerStream::new(listener);
+            let embedding_server = EmbeddingServer { send };
+            Some(tokio::spawn(
+                tonic::transport::Server::builder()
+                    .add_service(ClipperEmbeddingServer::new(embedding_server))
+                    .serve_with_incoming(listener_stream),
+            ))
+        }
+        None => None,
+    };
+    // There is no program to inject into, so nothing to send keys to us.
+    #[cfg(windows)]
+    let mut server_join: Option<tokio::task::JoinHandle<Result<(), tonic::transport::Error>>> =
+        None;
 
-                target.on_packet(key_db.clone(), meta, v).await?;
-            }
+    schedule.start(SystemTime::now());
+    let mut active = schedule.is_active(SystemTime::now());
+    if !active {
+        tracing::info!("Waiting for a capture window to open");
+    }
+    let mut next_change = schedule.next_change(SystemTime::now());
+    let mut schedule_timer = Box::pin(sleep_until(next_change.unwrap_or_else(SystemTime::now)));
+
+    let pressure = target.backpressure();
+    let mut held_back = false;
+
+    let pause = target.pause_control().unwrap_or_default();
+    if start_paused {
+        pause.pause();
+    }
+    let mut pause_signals = PauseSignals::new()?;
+
+    let result = loop {
+        tokio::select! {
+            // Keys first, so that TLS waiting on them is decrypted before
+            // more of it queues up and it is given up on, and packets last,
+            // so that a flood of them cannot hold anything else up.
+            biased;
             Some((cr, ty, secret)) = recv_keys.recv() => {
                 key_db.write().unwrap().on_secret(cr.clone(), ty, secret.clone());
                 target.on_key(key_db.clone(), cr, ty, secret).await?;
             }
+            // Also before packets, since the connection is opened before
+            // any of its packets are sent.
+            Some(owner) = recv_owners.recv() => {
+                target.on_connection_owner(owner);
+            }
+            Some(paused) = pause_signals.recv() => {
+                if paused {
+                    pause.pause();
+                } else {
+                    pause.resume();
+                }
+            }
+            _ = drop_check.tick() => {
+                let new = counters.update();
+                if new.dropped > 0 {
+                    tracing::warn!(
+                        "The kernel dropped {} packets in the last {}s, so the capture is missing \
+                         some ({} of {} so far)",
+                        new.dropped,
+                        DROP_CHECK_INTERVAL.as_secs(),
+                        counters.total.dropped,
+                        counters.total.received,
+                    );
+                }
+            }
+            _ = &mut schedule_timer, if next_change.is_some() => {
+                let now = SystemTime::now();
+                if schedule.is_over(now) {
+                    #[cfg(unix)]
+                    if let Some(child_pidfd) = ctx.child_pidfd {
+                        tracing::info!("Capture time is up, stopping the program");
+                        terminate_child(child_pidfd);
+                    } else {
+                        tracing::info!("Capture time is up");
+                    }
+                    #[cfg(windows)]
+                    tracing::info!("Capture time is up");
+                    // Shuts down the target below.
+                    terminate.cancel();
+                    next_change = None;
+                    continue;
+                }
+
+                let now_active = schedule.is_active(now);
+                if active && !now_active {
+                    tracing::info!("Capture window closed");
+                    target.end_session(key_db.clone()).await?;
+                } else if !active && now_active {
+                    tracing::info!("Capture window opened");
+                }
+                active = now_active;
+
+                next_change = schedule.next_change(now);
+                if let Some(t) = next_change {
+                    let after = t.duration_since(now).unwrap_or_default();
+                    schedule_timer
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + after);
+                }
+            }
+            handoff = accept_handoff(handoff_listener.as_ref()) => {
+                let handoff = handoff?;
+                tracing::info!("Handing the capture over to another clipper");
+                // Any keys still in flight need to make it into what we send.
+                while let Ok((cr, ty, secret)) = recv_keys.try_recv() {
+                    key_db.write().unwrap().on_secret(cr, ty, secret);
+                }
+                // Connections still open go over as a checkpoint, for the
+                // next clipper to carry on decoding (see net_decode::checkpoint
+                // for which can't be). Every packet read so far has been
+                // decoded, and the rest wait in the capture socket for it.
+                let flows = target.checkpoint().unwrap_or_else(|e| {
+                    tracing::warn!("could not checkpoint connections: {e}");
+                    None
+                });
+                handoff.send(&ctx.temp_dir, &key_db.read().unwrap(), flows.as_deref())?;
+
+                if let Some(server_join) = &server_join {
+                    server_join.abort();
+                }
+                terminate.cancel();
+                target.shutdown(key_db.clone()).await?;
+                break Ok(CaptureEnd::HandedOff);
+            }
             _ = terminate.cancelled() => {
                 target.shutdown(key_db.clone()).await?;
 
-                break Ok(());
+                break Ok(CaptureEnd::Finished);
             }
-            e = &mut server_join => {
+            e = async {
+                match &mut server_join {
+                    Some(join) => join.await,
+                    None => future::pending().await,
+                }
+            } => {
                 match e {
-                    Ok(inner) => break inner.map_err(|e| e.into()),
+                    Ok(inner) => break inner.map(|_| CaptureEnd::Finished).map_err(|e| e.into()),
                     Err(inner) => break Err(inner.into())
                 }
             }
+            v = async {
+                if let Some(pressure) = &pressure {
+                    if !pressure.has_room() {
+                        if !held_back {
+                            tracing::debug!("output is behind, pausing capture");
+                            held_back = true;
+                        }
+                        pressure.room().await;
+                    }
+                }
+                cap.select_next_some().await
+            } => {
+                if held_back {
+                    tracing::debug!("output caught up, resuming capture");
+                    held_back = false;
+                }
+                let (v, meta) = v?;
+
+                if active && !pause.is_paused() {
+                    target.on_packet(key_db.clone(), meta, v).await?;
+                }
+            }
         };
+    };
+
+    if let Some(path) = &handoff_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    // After a handoff the counts are the next clipper's to read.
+    if let Ok(CaptureEnd::Finished) = &result {
+        counters.update();
+        let KernelStats { received, dropped } = counters.total;
+        if dropped > 0 {
+            tracing::warn!(
+                "The kernel dropped {dropped} of the {received} packets captured, so the \
+                 capture is incomplete"
+            );
+        } else {
+            tracing::info!("Captured {received} packets, none dropped by the kernel");
+        }
     }
+    // If we handed off, the next clipper has the keys and will save them.
+    if let (Ok(CaptureEnd::Finished), Some(f)) = (&result, &save_keys) {
+        f.save(&key_db.read().unwrap())?;
+        tracing::info!("Saved keys to {}", f.path.display());
+    }
+    result
+}
+
+#[cfg(feature = "ebpf")]
+fn start_flow_owner_tracer(
+    send: tokio::sync::mpsc::UnboundedSender<ConnectionOwner>,
+) -> Result<FlowOwnerTracer, Error> {
+    Ok(FlowOwnerTracer::start(move |event| {
+        // Looked up now, while the process is most likely still there.
+        let process =
+            crate::process_owner::ProcessInfo::lookup(event.pid, event.comm, event.cgroup_id);
+        let _ = send.send(ConnectionOwner {
+            client: event.client,
+            server: event.server,
+            process,
+        });
+    })?)
 }
 
+#[cfg(not(feature = "ebpf"))]
+fn start_flow_owner_tracer(
+    _send: tokio::sync::mpsc::UnboundedSender<ConnectionOwner>,
+) -> Result<(), Error> {
+    Err("attributing connections to processes needs clipper built with the `ebpf` feature".into())
+}
+
+#[cfg(feature = "ebpf")]
+fn start_go_key_tracer(
+    binary: &Path,
+    send: KeySender,
+    terminate: CancellationToken,
+) -> Result<(), Error> {
+    let tracer = GoKeyTracer::start(binary, move |key| {
+        tracing::trace!("key from Go process {}: {}", key.pid, key.label);
+        let Ok(typ) = SecretType::try_from(key.label.as_bytes()) else {
+            return;
+        };
+        let _ = send.blocking_send((ClientRandom(key.client_random), typ, Secret(key.secret)));
+    })?;
+    tokio::spawn(async move {
+        terminate.cancelled().await;
+        // Dropping it waits for its thread.
+        tokio::task::spawn_blocking(move || drop(tracer));
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "ebpf"))]
+fn start_go_key_tracer(
+    _binary: &Path,
+    _send: KeySender,
+    _terminate: CancellationToken,
+) -> Result<(), Error> {
+    Err("reading keys from Go programs needs clipper built with the `ebpf` feature".into())
+}
+
+/// Runs a capture to completion on a new runtime, stopping when the program
+/// exits or on ctrl-c.
+fn run_capture<T: CaptureTarget + Unpin + 'static>(
+    make_capture: MakeCapture<T>,
+    ctx: CaptureContext,
+) -> Result<CaptureEnd, Error> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    let unix_sock_dir = ctx.temp_dir.clone();
+
+    let result = rt.block_on(async move {
+        let cancel = CancellationToken::new();
+        let child_exit = ChildExit::new(&ctx)?;
+
+        let _join_handle = tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = cancel.cancelled() => {
+                            break;
+                        }
+                        _ = tokio::signal::ctrl_c() => {
+                            cancel.cancel();
+                        }
+                        _ = child_exit.wait() => {
+                            cancel.cancel();
+                        }
+                    };
+                }
+            }
+        });
+
+        start_capture(make_capture(cancel.clone()).await?, ctx, cancel).await
+    });
+
+    // The next clipper is still using the socket in there if we handed off.
+    if !matches!(result, Ok(CaptureEnd::HandedOff)) {
+        let _ = std::fs::remove_dir_all(&unix_sock_dir);
+    }
+    result
+}
+
+#[cfg(target_os = "linux")]
 const SOCK_NAME: &'static str = "clipper.sock";
 
 type MakeCapture<T> =
     Box<dyn FnOnce(CancellationToken) -> Pin<Box<dyn Future<Output = Result<T, Error>>>>>;
 
+#[cfg(target_os = "linux")]
 struct ClipperLaunchHooks<T: CaptureTarget> {
     make_capture: MakeCapture<T>,
     temp_dir: PathBuf,
     unix_listener: Option<UnixListener>,
+    initial_keys: KeyDB,
+    options: CaptureOptions,
 }
 
+#[cfg(target_os = "linux")]
 impl<T: CaptureTarget> ClipperLaunchHooks<T> {
     fn sock(&self) -> PathBuf {
         self.temp_dir.join(SOCK_NAME)
     }
 }
 
-fn find_clipper_inject() -> Option<PathBuf> {
+/// Finds `name`, which is shipped with clipper, next to it in development or
+/// in the `lib` directory beside its `bin` once installed.
+#[cfg(target_os = "linux")]
+fn find_bundled(name: &str) -> Option<PathBuf> {
     let this_exe = read_link("/proc/self/exe").ok()?;
 
-    let dev_path = || Some(this_exe.parent()?.join(crate::CLIPPER_INJECT_DYLIB_NAME));
-    let prod_path = || {
-        Some(
-            this_exe
-                .parent()?
-                .parent()?
-                .join("lib")
-                .join(crate::CLIPPER_INJECT_DYLIB_NAME),
-        )
-    };
+    let dev_path = || Some(this_exe.parent()?.join(name));
+    let prod_path = || Some(this_exe.parent()?.parent()?.join("lib").join(name));
 
     for path in dev_path().into_iter().chain(prod_path().into_iter()) {
         if path.exists() {
@@ -349,6 +1726,12 @@ fn find_clipper_inject() -> Option<PathBuf> {
     None
 }
 
+#[cfg(target_os = "linux")]
+fn find_clipper_inject() -> Option<PathBuf> {
+    find_bundled(crate::CLIPPER_INJECT_DYLIB_NAME)
+}
+
+#[cfg(target_os = "linux")]
 impl<T: CaptureTarget + Unpin + 'static> LaunchHooks for ClipperLaunchHooks<T> {
     fn parent_after_fork(&mut self) {
         let listener = UnixListener::bind(self.sock()).expect("bind unix sock");
@@ -356,55 +1739,37 @@ impl<T: CaptureTarget + Unpin + 'static> LaunchHooks for ClipperLaunchHooks<T> {
         self.unix_listener = Some(listener);
     }
 
-    fn parent_go(&mut self, child_pidfd: RawFd, capture_fd: RawFd) {
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-
-        let unix_sock_dir = self.temp_dir.clone();
-        let listener = self.unix_listener.take().unwrap();
-
-        match rt.block_on(async move {
-            let cancel = CancellationToken::new();
-            let child_pidfd = AsyncFd::new(unsafe { OwnedFd::from_raw_fd(child_pidfd) })?;
-
-            let _join_handle = tokio::spawn({
-                let cancel = cancel.clone();
-                async move {
-                    loop {
-                        tokio::select! {
-                            _ = cancel.cancelled() => {
-                                let _ = tokio::fs::remove_dir_all(&unix_sock_dir).await;
-                                break;
-                            }
-                            _ = tokio::signal::ctrl_c() => {
-                                cancel.cancel();
-                            }
-                            _g = child_pidfd.readable() => {
-                                cancel.cancel();
-                            }
-                        };
-                    }
-                }
-            });
-
-            let make_capture = std::mem::replace(
-                &mut self.make_capture,
-                Box::new(|_| Box::pin(future::pending())),
-            );
+    fn parent_go(&mut self, child_pidfd: RawFd, capture_fd: RawFd) -> AfterParentGo {
+        let make_capture = std::mem::replace(
+            &mut self.make_capture,
+            Box::new(|_| Box::pin(future::pending())),
+        );
+        let ctx = CaptureContext {
+            listener: self.unix_listener.take(),
+            sockets: vec![CaptureSocket {
+                fd: capture_fd,
+                interface: DEV_NAME.to_string(),
+                ring: None,
+                xdp: None,
+            }],
+            child_pidfd: Some(child_pidfd),
+            temp_dir: self.temp_dir.clone(),
+            key_db: std::mem::take(&mut self.initial_keys),
+            flows: None,
+            options: self.options.clone(),
+        };
 
-            start_capture(
-                make_capture(cancel.clone()).await?,
-                listener,
-                capture_fd,
-                cancel,
-            )
-            .await
-        }) {
-            Ok(_) => {}
+        match run_capture(make_capture, ctx) {
+            Ok(CaptureEnd::Finished) => {}
+            Ok(CaptureEnd::HandedOff) => {
+                // slirp4netns only exits along with the program, which is
+                // not ours to wait for anymore.
+                tracing::info!("Capture handed off, exiting");
+                return AfterParentGo::Detach;
+            }
             Err(e) => tracing::error!("Error capturing: {e}"),
         }
+        AfterParentGo::WaitForProgram
     }
 
     fn temp_dir(&self) -> &Path {
@@ -417,24 +1782,65 @@ impl<T: CaptureTarget + Unpin + 'static> LaunchHooks for ClipperLaunchHooks<T> {
             self.sock().to_str().unwrap().to_string(),
         )];
 
+        if let Some(key_log) = &self.options.key_sources.key_log_file {
+            vars.push((
+                "SSLKEYLOGFILE".to_string(),
+                key_log.to_str().unwrap().to_string(),
+            ));
+        }
+
         let clipper_inject_so = find_clipper_inject();
         if let Some(so) = clipper_inject_so {
             vars.push(("LD_PRELOAD".to_string(), so.to_str().unwrap().to_string()))
         }
 
+        if self.options.key_sources.java_agent {
+            match find_bundled(crate::JAVA_AGENT_JAR_NAME) {
+                Some(jar) => {
+                    // Keep any options the user gave their JVMs.
+                    let mut options = std::env::var("JAVA_TOOL_OPTIONS")
+                        .map(|o| o + " ")
+                        .unwrap_or_default();
+                    options += &format!(
+                        "-javaagent:{}={}",
+                        jar.display(),
+                        self.temp_dir.join(JAVA_KEY_LOG_NAME).display()
+                    );
+                    vars.push(("JAVA_TOOL_OPTIONS".to_string(), options));
+                }
+                None => tracing::warn!(
+                    "Could not find {}, build it with `make java-agent`",
+                    crate::JAVA_AGENT_JAR_NAME
+                ),
+            }
+        }
+
         vars
     }
 }
 
-pub fn do_capture_to_pcap(file: PathBuf, args: Vec<String>) -> Result<(), Error> {
+#[cfg(target_os = "linux")]
+pub fn do_capture_to_pcap(
+    file: PathBuf,
+    embed_keys: bool,
+    annotate: bool,
+    options: CaptureOptions,
+    args: Vec<String>,
+) -> Result<(), Error> {
+    let rotate = options.schedule.has_windows();
     do_capture(
-        Box::new(move |_| Box::pin(async move { CaptureToPcap::new(&file).await })),
+        Box::new(move |_| {
+            Box::pin(async move { CaptureToPcap::new(&file, embed_keys, rotate, annotate).await })
+        }),
+        options,
         args,
     )
 }
 
+#[cfg(target_os = "linux")]
 pub fn do_capture<T: CaptureTarget + Unpin + 'static>(
     make_capture: MakeCapture<T>,
+    options: CaptureOptions,
     args: Vec<String>,
 ) -> Result<(), Error> {
     let temp_dir = tempfile::tempdir()?;
@@ -442,15 +1848,250 @@ pub fn do_capture<T: CaptureTarget + Unpin + 'static>(
         make_capture,
         temp_dir: temp_dir.into_path(),
         unix_listener: None,
+        initial_keys: options.initial_keys()?,
+        options: options.fixup_paths()?,
     };
 
     unsafe { run_in_ns(args, &mut hooks)? };
     Ok(())
 }
 
-pub fn do_capture_to_devtools(args: Vec<String>) -> Result<(), Error> {
+#[cfg(target_os = "linux")]
+pub fn do_capture_to_devtools(
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    recording: Option<Recording>,
+    options: CaptureOptions,
+    args: Vec<String>,
+) -> Result<(), Error> {
+    let rotate = options.schedule.has_windows();
     do_capture(
-        Box::new(move |cancel| Box::pin(async move { Ok(CaptureToDevtools::new(cancel).await) })),
+        Box::new(move |cancel| {
+            Box::pin(async move {
+                let recording = start_recording(recording, rotate).await?;
+                CaptureToDevtools::new(cancel, body_policies, decode_options, false, recording)
+                    .await
+            })
+        }),
+        options,
         args,
     )
 }
+
+/// Runs a program with capture, like [`do_capture_to_devtools`], but keeps
+/// serving the devtools server once the program exits, until Ctrl-C, so
+/// that everything it did can be looked through.
+#[cfg(target_os = "linux")]
+pub fn do_run(
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    recording: Option<Recording>,
+    options: CaptureOptions,
+    args: Vec<String>,
+) -> Result<(), Error> {
+    if options.handoff_socket.is_some() {
+        return Err("a capture that serves on after the program can't be handed over".into());
+    }
+    let rotate = options.schedule.has_windows();
+    do_capture(
+        Box::new(move |cancel| {
+            Box::pin(async move {
+                let recording = start_recording(recording, rotate).await?;
+                CaptureToDevtools::new(cancel, body_policies, decode_options, true, recording).await
+            })
+        }),
+        options,
+        args,
+    )
+}
+
+/// Captures everything on one or more network interfaces, rather than one
+/// program's traffic, until interrupted or the schedule runs out. Packets
+/// from all the interfaces are put in order of when they were received.
+/// Needs root or `CAP_NET_RAW`, on macOS access to the BPF devices, and on
+/// Windows Npcap. Keys only come from the [`KeySources`] and the keys loaded
+/// at the start, since there is no program to inject into.
+pub fn do_capture_interface<T: CaptureTarget + Unpin + 'static>(
+    interfaces: Vec<InterfaceOptions>,
+    make_capture: MakeCapture<T>,
+    options: CaptureOptions,
+) -> Result<(), Error> {
+    if options.handoff_socket.is_some() {
+        return Err("only captures of a program can be handed over".into());
+    }
+    if interfaces.is_empty() {
+        return Err("no interfaces to capture on".into());
+    }
+    let mut sockets = Vec::new();
+    for interface in interfaces {
+        #[cfg(target_os = "linux")]
+        if let Some(xdp) = interface.xdp {
+            let xdp = open_xdp(&interface, xdp)?;
+            sockets.push(CaptureSocket {
+                fd: xdp.control_fd(),
+                interface: interface.interface,
+                ring: None,
+                xdp: Some(xdp),
+            });
+            continue;
+        }
+        #[cfg(unix)]
+        let socket = CaptureSocket {
+            fd: open_interface(&interface)?.into_raw_fd(),
+            interface: interface.interface,
+            #[cfg(target_os = "linux")]
+            ring: interface.ring,
+            #[cfg(target_os = "linux")]
+            xdp: None,
+        };
+        #[cfg(windows)]
+        let socket = {
+            let handle = open_interface(&interface)?;
+            CaptureSocket {
+                stats: handle.stats(),
+                handle: Some(handle),
+                interface: interface.interface,
+            }
+        };
+        sockets.push(socket);
+    }
+    let key_db = options.initial_keys()?;
+    let temp_dir = tempfile::tempdir()?;
+
+    run_capture(
+        make_capture,
+        CaptureContext {
+            #[cfg(unix)]
+            listener: None,
+            sockets,
+            #[cfg(unix)]
+            child_pidfd: None,
+            temp_dir: temp_dir.into_path(),
+            key_db,
+            flows: None,
+            options: options.fixup_paths()?,
+        },
+    )?;
+    Ok(())
+}
+
+pub fn do_capture_interface_to_pcap(
+    interfaces: Vec<InterfaceOptions>,
+    file: PathBuf,
+    embed_keys: bool,
+    annotate: bool,
+    options: CaptureOptions,
+) -> Result<(), Error> {
+    let rotate = options.schedule.has_windows();
+    do_capture_interface(
+        interfaces,
+        Box::new(move |_| {
+            Box::pin(async move { CaptureToPcap::new(&file, embed_keys, rotate, annotate).await })
+        }),
+        options,
+    )
+}
+
+pub fn do_capture_interface_to_devtools(
+    interfaces: Vec<InterfaceOptions>,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    recording: Option<Recording>,
+    options: CaptureOptions,
+) -> Result<(), Error> {
+    let rotate = options.schedule.has_windows();
+    do_capture_interface(
+        interfaces,
+        Box::new(move |cancel| {
+            Box::pin(async move {
+                let recording = start_recording(recording, rotate).await?;
+                CaptureToDevtools::new(cancel, body_policies, decode_options, false, recording)
+                    .await
+            })
+        }),
+        options,
+    )
+}
+
+/// Takes over a running capture from the clipper listening on `from`, then
+/// carries on like [`do_capture`] would. The program being captured is not
+/// restarted, and packets sent in the meantime are not lost. Connections
+/// that were open carry on being decoded if the previous clipper was
+/// decoding them too.
+#[cfg(target_os = "linux")]
+pub fn do_resume<T: CaptureTarget + Unpin + 'static>(
+    from: PathBuf,
+    make_capture: MakeCapture<T>,
+    options: CaptureOptions,
+) -> Result<CaptureEnd, Error> {
+    if options.filter.is_some() {
+        return Err("the capture filter of the previous clipper is kept on resume".into());
+    }
+    let mut handoff = receive_handoff(&from)?;
+    tracing::info!("Took over capture from {}", from.display());
+    if let Some(f) = &options.load_keys {
+        f.load_into(&mut handoff.key_db)?;
+    }
+    options.add_configured_keys(&mut handoff.key_db);
+
+    let listener = UnixListener::from(handoff.embedding_listener);
+    listener.set_nonblocking(true)?;
+
+    run_capture(
+        make_capture,
+        CaptureContext {
+            listener: Some(listener),
+            sockets: vec![CaptureSocket {
+                fd: handoff.capture_fd.into_raw_fd(),
+                interface: DEV_NAME.to_string(),
+                ring: None,
+                xdp: None,
+            }],
+            child_pidfd: Some(handoff.child_pidfd.into_raw_fd()),
+            temp_dir: handoff.temp_dir,
+            key_db: handoff.key_db,
+            flows: handoff.flows,
+            options: options.fixup_paths()?,
+        },
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub fn do_resume_to_pcap(
+    from: PathBuf,
+    file: PathBuf,
+    embed_keys: bool,
+    annotate: bool,
+    options: CaptureOptions,
+) -> Result<CaptureEnd, Error> {
+    let rotate = options.schedule.has_windows();
+    do_resume(
+        from,
+        Box::new(move |_| {
+            Box::pin(async move { CaptureToPcap::new(&file, embed_keys, rotate, annotate).await })
+        }),
+        options,
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub fn do_resume_to_devtools(
+    from: PathBuf,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    recording: Option<Recording>,
+    options: CaptureOptions,
+) -> Result<CaptureEnd, Error> {
+    let rotate = options.schedule.has_windows();
+    do_resume(
+        from,
+        Box::new(move |cancel| {
+            Box::pin(async move {
+                let recording = start_recording(recording, rotate).await?;
+                CaptureToDevtools::new(cancel, body_policies, decode_options, false, recording)
+                    .await
+            })
+        }),
+        options,
+    )
+}
@@ -0,0 +1,113 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Exports server certificate chains seen in a capture as PEM files, one per
+//! host, for offline auditing.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use net_decode::{certificate::CertificateInfo, chomp, key_db::KeyDB};
+
+use crate::{
+    events::{ClipperEvent, EventListener, EventSink, TlsEvent},
+    Error,
+};
+
+type Chains = Arc<Mutex<BTreeMap<String, Vec<CertificateInfo>>>>;
+
+/// Collects the last chain seen for each host.
+struct CertificateCollector {
+    chains: Chains,
+}
+
+impl EventSink for CertificateCollector {
+    fn on_event(&mut self, event: ClipperEvent) {
+        if let ClipperEvent::Tls(TlsEvent::ServerCertificate(cert)) = event {
+            let host = cert
+                .server_name
+                .unwrap_or_else(|| cert.target.server_addr().ip().to_string());
+            self.chains.lock().unwrap().insert(host, cert.chain);
+        }
+    }
+}
+
+/// Turns a host name into something that is definitely a single path
+/// component.
+fn file_name_for_host(host: &str) -> String {
+    let safe: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.pem", safe.trim_start_matches('.'))
+}
+
+fn write_chains(
+    chains: &BTreeMap<String, Vec<CertificateInfo>>,
+    output_dir: &Path,
+) -> Result<(), Error> {
+    fs::create_dir_all(output_dir)?;
+    for (host, chain) in chains {
+        let pem: String = chain.iter().map(|c| c.to_pem()).collect();
+        let path = output_dir.join(file_name_for_host(host));
+        tracing::info!(
+            "writing {} certificates for {host} to {path:?}",
+            chain.len()
+        );
+        fs::write(path, pem)?;
+    }
+    Ok(())
+}
+
+/// Decodes a pcapng file and writes the server certificate chain for each
+/// host into `output_dir/<host>.pem`, leaf first.
+pub fn do_export_certs(file: PathBuf, output_dir: PathBuf) -> Result<(), Error> {
+    let chains: Chains = Default::default();
+    let key_db = Arc::new(std::sync::RwLock::new(KeyDB::default()));
+    let mut chomper = net_decode::chomper(
+        EventListener::new(CertificateCollector {
+            chains: chains.clone(),
+        }),
+        key_db,
+    );
+    chomp::dump_pcap_file(file, &mut chomper)?;
+
+    let chains = chains.lock().unwrap();
+    if chains.is_empty() {
+        tracing::warn!("no server certificates found; for TLS 1.3, are keys available?");
+    }
+    write_chains(&chains, &output_dir)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export_tls12_chain() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("../net_decode/corpus");
+        let output_dir = tempfile::tempdir().unwrap();
+
+        do_export_certs(
+            corpus.join("tls12-mtls.pcapng"),
+            output_dir.path().to_owned(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join("server.test.pem")).unwrap(),
+            fs::read_to_string(corpus.join("tls12-server.pem")).unwrap()
+        );
+    }
+}
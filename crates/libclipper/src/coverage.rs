@@ -0,0 +1,359 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! How much of a capture clipper actually understood: every TCP flow is
+//! either fully decoded (we got HTTP out of it), metadata only (we saw a
+//! TLS handshake, but not what was inside) or opaque (just bytes), along
+//! with why.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use bytes::Bytes;
+use net_decode::{
+    chomp::{self, EthernetChomper, IPTarget},
+    key_db::KeyDB,
+    listener::{Listener, SideData, TimingInfo},
+};
+
+use crate::{
+    events::{ClipperEvent, EventListener, EventSink, TlsEvent},
+    Error,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+    NoKeys,
+    UnknownProtocol,
+    /// The flow ends, or the capture starts, partway through.
+    Truncated,
+    DecodeError,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::NoKeys => "no keys",
+            Reason::UnknownProtocol => "unknown protocol",
+            Reason::Truncated => "truncated",
+            Reason::DecodeError => "decode error",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Coverage {
+    Decoded,
+    MetadataOnly(Reason),
+    Opaque(Reason),
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Coverage::Decoded => write!(f, "decoded"),
+            Coverage::MetadataOnly(r) => write!(f, "metadata only ({r})"),
+            Coverage::Opaque(r) => write!(f, "opaque ({r})"),
+        }
+    }
+}
+
+/// What we learned about one flow.
+#[derive(Debug, Default)]
+struct FlowCoverage {
+    bytes: u64,
+    http: bool,
+    tls_handshake: bool,
+    no_keys: bool,
+    decode_failed: bool,
+}
+
+impl FlowCoverage {
+    fn classify(&self, target: &IPTarget) -> Coverage {
+        // FIXME: keep this in sync with the ports in net_decode::chomper
+        let port = target.server_port();
+        if self.http {
+            Coverage::Decoded
+        } else if port == 443 && self.tls_handshake {
+            Coverage::MetadataOnly(if self.no_keys {
+                Reason::NoKeys
+            } else if self.decode_failed {
+                Reason::DecodeError
+            } else {
+                Reason::Truncated
+            })
+        } else if port == 443 {
+            Coverage::Opaque(if self.decode_failed {
+                Reason::DecodeError
+            } else {
+                Reason::Truncated
+            })
+        } else if port == 80 {
+            Coverage::Opaque(Reason::DecodeError)
+        } else {
+            Coverage::Opaque(Reason::UnknownProtocol)
+        }
+    }
+}
+
+type Flows = Arc<Mutex<HashMap<IPTarget, FlowCoverage>>>;
+
+/// Sits between TCP reassembly and the decoders, counting the bytes of
+/// every flow, including ones nothing decodes.
+struct ByteCounter<L> {
+    flows: Flows,
+    next: L,
+}
+
+impl<L: Listener<Bytes>> Listener<Bytes> for ByteCounter<L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: Bytes) {
+        self.flows.lock().unwrap().entry(target).or_default().bytes += data.len() as u64;
+        self.next.on_data(timing, target, to_client, data);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.next.on_side_data(data);
+    }
+}
+
+/// Notes down what the decoders managed for each flow.
+struct CoverageCollector {
+    flows: Flows,
+}
+
+impl EventSink for CoverageCollector {
+    fn on_event(&mut self, event: ClipperEvent) {
+        let mut flows = self.flows.lock().unwrap();
+        match event {
+            ClipperEvent::Http { target, .. } => flows.entry(target).or_default().http = true,
+            ClipperEvent::Tls(TlsEvent::ClientHello(hello)) => {
+                flows.entry(hello.target).or_default().tls_handshake = true
+            }
+            ClipperEvent::Tls(TlsEvent::Opaque(opaque)) => {
+                flows.entry(opaque.target).or_default().no_keys = true
+            }
+            ClipperEvent::Tls(TlsEvent::PendingKeysDropped(dropped)) => {
+                flows.entry(dropped.target).or_default().no_keys = true
+            }
+            ClipperEvent::Tls(TlsEvent::DecodeFailed(failed)) => {
+                flows.entry(failed.target).or_default().decode_failed = true
+            }
+            ClipperEvent::Flow(_)
+            | ClipperEvent::Tls(_)
+            | ClipperEvent::Finding(_)
+            | ClipperEvent::Diagnostic(_)
+            | ClipperEvent::Comment(_) => {}
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tally {
+    pub flows: u64,
+    pub bytes: u64,
+}
+
+impl Tally {
+    fn add(&mut self, bytes: u64) {
+        self.flows += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CoverageReport {
+    pub total: Tally,
+    pub by_coverage: BTreeMap<Coverage, Tally>,
+}
+
+impl CoverageReport {
+    fn from_flows(flows: &HashMap<IPTarget, FlowCoverage>) -> Self {
+        let mut report = CoverageReport::default();
+        for (target, flow) in flows {
+            // Connections that never carried anything are not interesting
+            // here.
+            if flow.bytes == 0 {
+                continue;
+            }
+            report.total.add(flow.bytes);
+            report
+                .by_coverage
+                .entry(flow.classify(target))
+                .or_default()
+                .add(flow.bytes);
+        }
+        report
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let categories: Vec<_> = self
+            .by_coverage
+            .iter()
+            .map(|(coverage, tally)| {
+                let (level, reason) = match coverage {
+                    Coverage::Decoded => ("decoded", None),
+                    Coverage::MetadataOnly(r) => ("metadata_only", Some(r.to_string())),
+                    Coverage::Opaque(r) => ("opaque", Some(r.to_string())),
+                };
+                serde_json::json!({
+                    "coverage": level,
+                    "reason": reason,
+                    "flows": tally.flows,
+                    "bytes": tally.bytes,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "flows": self.total.flows,
+            "bytes": self.total.bytes,
+            "categories": categories,
+        })
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.
+    } else {
+        part as f64 * 100. / total as f64
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<32} {:>8} {:>7} {:>14} {:>7}",
+            "coverage", "flows", "", "bytes", ""
+        )?;
+        for (coverage, tally) in &self.by_coverage {
+            writeln!(
+                f,
+                "{:<32} {:>8} {:>6.1}% {:>14} {:>6.1}%",
+                coverage.to_string(),
+                tally.flows,
+                percent(tally.flows, self.total.flows),
+                tally.bytes,
+                percent(tally.bytes, self.total.bytes),
+            )?;
+        }
+        write!(
+            f,
+            "{:<32} {:>8} {:>7} {:>14}",
+            "total", self.total.flows, "", self.total.bytes
+        )
+    }
+}
+
+/// Decodes a pcapng file and works out how much of it was understood.
+/// `key_db` has any keys not embedded in the file.
+pub fn coverage_report(file: PathBuf, key_db: KeyDB) -> Result<CoverageReport, Error> {
+    let flows: Flows = Default::default();
+    let key_db = Arc::new(RwLock::new(key_db));
+    let EthernetChomper {
+        tcp_follower,
+        udp_follower,
+        sctp_follower,
+        fragments,
+        link_stats,
+        wireguard,
+        checksums,
+        recv,
+        key_db,
+    } = net_decode::chomper(
+        EventListener::new(CoverageCollector {
+            flows: flows.clone(),
+        }),
+        key_db,
+    );
+    let mut chomper = EthernetChomper {
+        tcp_follower,
+        udp_follower,
+        sctp_follower,
+        fragments,
+        link_stats,
+        wireguard,
+        checksums,
+        recv: ByteCounter {
+            flows: flows.clone(),
+            next: recv,
+        },
+        key_db,
+    };
+    chomp::dump_pcap_file(file, &mut chomper)?;
+
+    let flows = flows.lock().unwrap();
+    Ok(CoverageReport::from_flows(&flows))
+}
+
+/// Prints a [`CoverageReport`] for a pcapng file, as a table or as JSON.
+pub fn do_coverage_report(file: PathBuf, key_db: KeyDB, json: bool) -> Result<(), Error> {
+    let report = coverage_report(file, key_db)?;
+    let mut out = io::stdout().lock();
+    if json {
+        serde_json::to_writer_pretty(&mut out, &report.to_json())?;
+        writeln!(out)?;
+    } else {
+        writeln!(out, "{report}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    fn report_for(name: &str) -> CoverageReport {
+        let file = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../net_decode/corpus")
+            .join(name);
+        coverage_report(file, KeyDB::default()).unwrap()
+    }
+
+    fn tallies(report: &CoverageReport) -> Vec<(Coverage, u64, u64)> {
+        report
+            .by_coverage
+            .iter()
+            .map(|(coverage, tally)| (*coverage, tally.flows, tally.bytes))
+            .collect()
+    }
+
+    #[test]
+    fn test_coverage_decoded() {
+        let report = report_for("http-80.pcapng");
+        assert_eq!((report.total.flows, report.total.bytes), (1, 436));
+        assert_eq!(tallies(&report), [(Coverage::Decoded, 1, 436)]);
+
+        // Keys embedded in the capture are used.
+        let report = report_for("tls12-mtls.pcapng");
+        assert_eq!(report.total.flows, 1);
+        assert!(matches!(&tallies(&report)[..], [(Coverage::Decoded, 1, _)]));
+    }
+
+    #[test]
+    fn test_coverage_no_keys() {
+        let report = report_for("tls12-rsa.pcapng");
+        assert_eq!((report.total.flows, report.total.bytes), (1, 1664));
+        assert_eq!(
+            tallies(&report),
+            [(Coverage::MetadataOnly(Reason::NoKeys), 1, 1664)]
+        );
+        assert_eq!(
+            report.to_json()["categories"][0],
+            serde_json::json!({
+                "coverage": "metadata_only",
+                "reason": "no keys",
+                "flows": 1,
+                "bytes": 1664,
+            })
+        );
+    }
+}
@@ -6,13 +6,16 @@
 
 use std::{
     collections::{BTreeMap, VecDeque},
-    fmt, io,
+    fmt,
+    io::{self, Read},
+    iter,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::{mpsc, Arc, RwLock},
 };
 
 use base64::Engine;
+use bytes::Bytes;
 use devtools_server::{
     cdp::cdp::browser_protocol::{
         network::{self, EventRequestWillBeSent},
@@ -27,16 +30,36 @@ use http::{
     HeaderMap,
 };
 use net_decode::{
-    chomp,
+    body_policy::{BodyPolicies, BodyPolicyListener},
+    capture_filter::{CaptureFilter, FilteredChomper},
+    capture_merge::MergeInput,
+    capture_slice::{self, CaptureSlice},
+    checkpoint::{Gated, ReplayGate},
+    chomp::{self, EthernetChomper, FrameChomper, IPTarget},
+    dispatch::ListenerDispatcher,
     http::HTTPStreamEvent,
     http::RequestId as NdRequestId,
-    key_db::KeyDB,
-    listener::{Listener, Nanos, TimingInfo},
+    icmp::IcmpErrorKind,
+    key_db::{ClientRandom, KeyDB, Secret, SecretType},
+    link::Linktype,
+    listener::{Nanos, TimingInfo},
+    live::{LiveConfig, LiveFilter},
+    tcp_reassemble::side_data::{CloseKind, ConnectionFailure},
+    DecodeOptions,
 };
 use tokio::sync::broadcast;
 use tokio_util::sync::CancellationToken;
 
-use crate::Error;
+use crate::{
+    backpressure::Backpressure,
+    body_store::{BodyHash, BodyRecord, BodyStore, ContentStore},
+    events::{ClipperEvent, EventListener, EventSink, FlowEvent},
+    live_control,
+    missing_keys::{UndecryptedFlow, UndecryptedFlowTracker},
+    pause::{self, CapturePause},
+    process_owner::{ProcessInfo, ProcessOwners},
+    Error,
+};
 
 pub const DEVTOOLS_PORT_RANGE: (u16, u16) = (6830, 6840);
 
@@ -55,30 +78,77 @@ pub enum DevtoolsProtoEventInner {
         id: NdRequestId,
         body: Option<Vec<u8>>,
         parts: http::request::Parts,
+        /// What made the request, if we know.
+        process: Option<ProcessInfo>,
+    },
+    /// With the server's address.
+    NewResponse(NdRequestId, http::response::Parts, SocketAddr),
+    RespBodyChunk(NdRequestId, Bytes),
+    /// With where the body went in the [`ContentStore`], if it was kept.
+    ResponseFinished(NdRequestId, usize, Option<BodyHash>),
+    /// The connection was reset partway through the response.
+    ResponseFailed(NdRequestId, &'static str),
+    /// A connection that died before carrying any HTTP. The id is distinct
+    /// from the request ids since there is no request.
+    ConnectionFailed {
+        id: String,
+        target: IPTarget,
+        reason: ConnectionFailure,
+    },
+    /// A TLS connection that closed without us decrypting it, shown so that
+    /// it is clear why its requests are missing.
+    Undecrypted {
+        id: String,
+        flow: UndecryptedFlow,
     },
-    NewResponse(NdRequestId, http::response::Parts),
-    RespBodyChunk(NdRequestId, Vec<u8>),
-    ResponseFinished(NdRequestId, usize),
 }
 
 impl fmt::Debug for DevtoolsProtoEventInner {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::NewRequest { id, body: _, parts } => {
-                f.debug_tuple("NewRequest").field(id).field(parts).finish()
-            }
-            Self::NewResponse(id, parts) => {
-                f.debug_tuple("NewResponse").field(id).field(parts).finish()
-            }
+            Self::NewRequest {
+                id,
+                body: _,
+                parts,
+                process,
+            } => f
+                .debug_tuple("NewRequest")
+                .field(id)
+                .field(parts)
+                .field(process)
+                .finish(),
+            Self::NewResponse(id, parts, remote) => f
+                .debug_tuple("NewResponse")
+                .field(id)
+                .field(parts)
+                .field(remote)
+                .finish(),
             Self::RespBodyChunk(id, chunk) => f
                 .debug_struct("RespBodyChunk")
                 .field("id", id)
                 .field("len", &chunk.len())
                 .finish(),
-            Self::ResponseFinished(id, len) => f
+            Self::ResponseFinished(id, len, body) => f
                 .debug_struct("ResponseFinished")
                 .field("id", id)
                 .field("len", len)
+                .field("body", body)
+                .finish(),
+            Self::ResponseFailed(id, error) => f
+                .debug_struct("ResponseFailed")
+                .field("id", id)
+                .field("error", error)
+                .finish(),
+            Self::ConnectionFailed { id, target, reason } => f
+                .debug_struct("ConnectionFailed")
+                .field("id", id)
+                .field("target", target)
+                .field("reason", reason)
+                .finish(),
+            Self::Undecrypted { id, flow } => f
+                .debug_struct("Undecrypted")
+                .field("id", id)
+                .field("flow", flow)
                 .finish(),
         }
     }
@@ -86,10 +156,14 @@ impl fmt::Debug for DevtoolsProtoEventInner {
 
 /// Structure to buffer events so that new clients will get all the history on
 /// connection.
+///
+/// Clients that are receiving events hold back [`EventBuffer::pressure`] if
+/// they fall behind.
 struct EventBuffer<T: Send> {
-    new_events: broadcast::Sender<Arc<T>>,
+    new_events: broadcast::Sender<(u64, Arc<T>)>,
     backlog: Arc<RwLock<VecDeque<Arc<T>>>>,
     backlog_capacity: usize,
+    pressure: Backpressure,
 }
 
 impl<T: Send> EventBuffer<T> {
@@ -98,6 +172,9 @@ impl<T: Send> EventBuffer<T> {
             new_events: broadcast::channel(capacity).0,
             backlog_capacity,
             backlog: Default::default(),
+            // Less than the channel holds, so that clients slow the capture
+            // down before they miss anything.
+            pressure: Backpressure::new(capacity / 2),
         }
     }
 
@@ -112,7 +189,8 @@ impl<T: Send> EventBuffer<T> {
         }
 
         // We don't care if anyone gets it.
-        let _ = self.new_events.send(msg);
+        let n = self.pressure.produced();
+        let _ = self.new_events.send((n, msg));
     }
 
     pub fn receiver(&self) -> impl Stream<Item = Arc<T>> {
@@ -120,8 +198,13 @@ impl<T: Send> EventBuffer<T> {
         let pos = 0usize;
         let end_pos = backlog.read().unwrap().len();
         let mut new_items = self.new_events.subscribe();
+        let pressure = self.pressure.clone();
 
         async_stream::stream! {
+            // Only once the client starts taking events, so that one that
+            // never does cannot hold the capture up.
+            let consumer = pressure.consumer();
+
             for remain in pos..end_pos {
                 let item = {
                     let lock = backlog.read().unwrap();
@@ -134,8 +217,13 @@ impl<T: Send> EventBuffer<T> {
                 let value = new_items.recv().await;
 
                 match value {
-                    Ok(v) => yield v,
-                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Ok((n, v)) => {
+                        yield v;
+                        consumer.consumed(n);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("devtools client fell behind and missed {missed} events");
+                    }
                     Err(broadcast::error::RecvError::Closed) => return,
                 }
             }
@@ -172,33 +260,93 @@ fn to_chrome_proto_version(ver: http::Version) -> Option<&'static str> {
     })
 }
 
-#[derive(Default)]
-struct ResponseBodyTracker {
-    requests: BTreeMap<NdRequestId, Vec<u8>>,
+fn nanos_to_seconds(nanos: Nanos) -> f64 {
+    nanos as f64 / 1_000_000_000.
 }
 
-impl ResponseBodyTracker {
-    fn on_chunk(&mut self, request_id: NdRequestId, chunk: &[u8]) {
-        let entry = self.requests.entry(request_id).or_default();
-        entry.extend(chunk);
-    }
+fn nanos_to_monotonic(nanos: Nanos) -> network::MonotonicTime {
+    network::MonotonicTime::new(nanos_to_seconds(nanos))
+}
 
-    fn data(&self, request_id: NdRequestId) -> Option<&[u8]> {
-        self.requests.get(&request_id).map(|v| v.as_slice())
+fn empty_request() -> network::Request {
+    network::Request {
+        url: "".to_string(),
+        method: "".to_string(),
+        url_fragment: None,
+        headers: network::Headers::new(serde_json::Value::Object(Default::default())),
+        post_data: None,
+        has_post_data: None,
+        post_data_entries: None,
+        mixed_content_type: None,
+        initial_priority: network::ResourcePriority::Medium,
+        referrer_policy: network::RequestReferrerPolicy::Origin,
+        is_link_preload: None,
+        trust_token_params: None,
+        is_same_site: None,
     }
 }
 
-fn nanos_to_seconds(nanos: Nanos) -> f64 {
-    nanos as f64 / 1_000_000_000.
+fn request_will_be_sent(
+    id: String,
+    request: network::Request,
+    timing: &TimingInfo,
+) -> EventRequestWillBeSent {
+    EventRequestWillBeSent {
+        request_id: network::RequestId::from(id),
+        loader_id: network::LoaderId::from("".to_string()),
+        document_url: "".to_string(),
+        request,
+        timestamp: nanos_to_monotonic(timing.received_on_wire),
+        wall_time: network::TimeSinceEpoch::new(nanos_to_seconds(timing.received_on_wire)),
+        initiator: network::Initiator {
+            r#type: network::InitiatorType::Other,
+            stack: None,
+            url: None,
+            line_number: None,
+            column_number: None,
+            request_id: None,
+        },
+        redirect_has_extra_info: false,
+        redirect_response: None,
+        r#type: None,
+        frame_id: None,
+        has_user_gesture: None,
+    }
 }
 
-fn nanos_to_monotonic(nanos: Nanos) -> network::MonotonicTime {
-    network::MonotonicTime::new(nanos_to_seconds(nanos))
+/// A made-up CONNECT request that fails with why the connection was not
+/// decrypted, since it has no requests of its own to show.
+fn undecrypted_events(
+    id: &str,
+    flow: &UndecryptedFlow,
+    timing: &TimingInfo,
+) -> (EventRequestWillBeSent, network::EventLoadingFailed) {
+    let sent = request_will_be_sent(
+        id.to_string(),
+        network::Request {
+            url: format!("https://{}/", flow.host()),
+            method: "CONNECT".to_string(),
+            ..empty_request()
+        },
+        timing,
+    );
+    let failed = network::EventLoadingFailed {
+        request_id: network::RequestId::new(id.to_string()),
+        timestamp: nanos_to_monotonic(timing.received_on_wire),
+        r#type: network::ResourceType::Other,
+        error_text: format!("clipper: not decrypted: {flow}"),
+        canceled: None,
+        blocked_reason: None,
+        cors_error_status: None,
+    };
+    (sent, failed)
 }
 
 struct ClientState {
     network_enabled: bool,
-    response_bodies: Arc<RwLock<ResponseBodyTracker>>,
+    response_bodies: Arc<RwLock<BodyStore>>,
+    live: LiveConfig,
+    pause: CapturePause,
 }
 
 impl ClientState {
@@ -247,14 +395,42 @@ impl ClientState {
                 conn.reply(msg.id, serde_json::Value::Object(Default::default()))
                     .await?
             }
+            // const { network::SetUserAgentOverrideParams::IDENTIFIER }
+            //
+            // Clipper only watches traffic and never sends requests itself, so
+            // there is nothing an override could apply to. Say so, rather than
+            // have devtools think it worked.
+            "Network.setUserAgentOverride" => {
+                conn.send(cdp_types::Message::Response(cdp_types::Response {
+                    id: msg.id,
+                    result: None,
+                    error: Some(cdp_types::Error {
+                        code: -1,
+                        message: "clipper does not send requests, so the user agent \
+                                  cannot be overridden"
+                            .to_string(),
+                    }),
+                }))
+                .await?
+            }
             // const { network::GetResponseBodyParams::IDENTIFIER }
             "Network.getResponseBody" => {
                 // FIXME: error handling is bad, it should throw something back
                 // at the caller
-                let data: network::GetResponseBodyParams = serde_json::from_value(msg.params)?;
+                let params: network::GetResponseBodyParams = serde_json::from_value(msg.params)?;
+                let id = u64::from_str_radix(params.request_id.inner(), 10)?;
+                // Only opened with the lock held; big bodies are read from
+                // disk after letting go of it.
+                let reader = self.response_bodies.read().unwrap().reader(id);
+                let data = reader
+                    .map(|reader| -> io::Result<Vec<u8>> {
+                        let mut data = Vec::new();
+                        reader?.read_to_end(&mut data)?;
+                        Ok(data)
+                    })
+                    .transpose()?;
                 let body = {
-                    let lock = self.response_bodies.read().unwrap();
-                    lock.data(u64::from_str_radix(data.request_id.inner(), 10)?)
+                    data.as_deref()
                         // So, devtools will only preview things if they have
                         // appropriate mime types attached for what they are.
                         // We do not do any of this at present.
@@ -292,6 +468,36 @@ impl ClientState {
                     .await?
                 }
             }
+            live_control::CONFIGURE_METHOD => {
+                match live_control::configure(&self.live, &msg.params) {
+                    Ok(()) => {
+                        conn.reply(msg.id, serde_json::Value::Object(Default::default()))
+                            .await?
+                    }
+                    Err(e) => {
+                        conn.send(cdp_types::Message::Response(cdp_types::Response {
+                            id: msg.id,
+                            result: None,
+                            error: Some(cdp_types::Error {
+                                code: devtools_server::INVALID_PARAMS,
+                                message: e.to_string(),
+                            }),
+                        }))
+                        .await?
+                    }
+                }
+            }
+            pause::PAUSE_METHOD | pause::RESUME_METHOD => {
+                match &*msg.method {
+                    pause::PAUSE_METHOD => self.pause.pause(),
+                    _ => self.pause.resume(),
+                }
+                conn.reply(
+                    msg.id,
+                    serde_json::json!({ "paused": self.pause.is_paused() }),
+                )
+                .await?
+            }
             _ => {
                 conn.send(cdp_types::Message::Response(cdp_types::Response {
                     id: msg.id,
@@ -316,16 +522,18 @@ impl ClientState {
 
         let timestamp = nanos_to_monotonic(msg.timing.received_on_wire);
         match &msg.inner {
-            DevtoolsProtoEventInner::NewRequest { id, parts, body } => {
-                let ev = EventRequestWillBeSent {
-                    request_id: network::RequestId::from(id.to_string()),
-                    loader_id: network::LoaderId::from("".to_string()),
-                    document_url: "".to_string(),
-                    request: network::Request {
+            DevtoolsProtoEventInner::NewRequest {
+                id,
+                parts,
+                body,
+                process,
+            } => {
+                let mut ev = request_will_be_sent(
+                    id.to_string(),
+                    network::Request {
                         // TODO: this is missing the domain name, thats fucked
                         url: parts.uri.to_string(),
                         method: parts.method.to_string(),
-                        url_fragment: None,
                         headers: to_cdp_headers(&parts.headers),
                         // TODO: we take post data in as a separate event, so
                         // these need coalescing before they go in. gah.
@@ -333,32 +541,13 @@ impl ClientState {
                             .as_ref()
                             .map(|b| String::from_utf8_lossy(b).to_string()),
                         has_post_data: body.as_ref().map(|_| true),
-                        post_data_entries: None,
-                        mixed_content_type: None,
-                        initial_priority: network::ResourcePriority::Medium,
-                        referrer_policy: network::RequestReferrerPolicy::Origin,
-                        is_link_preload: None,
-                        trust_token_params: None,
-                        is_same_site: None,
+                        ..empty_request()
                     },
-                    timestamp,
-                    wall_time: network::TimeSinceEpoch::new(nanos_to_seconds(
-                        msg.timing.received_on_wire,
-                    )),
-                    initiator: network::Initiator {
-                        r#type: network::InitiatorType::Other,
-                        stack: None,
-                        url: None,
-                        line_number: None,
-                        column_number: None,
-                        request_id: None,
-                    },
-                    redirect_has_extra_info: false,
-                    redirect_response: None,
-                    r#type: None,
-                    frame_id: None,
-                    has_user_gesture: None,
-                };
+                    &msg.timing,
+                );
+                // There is nowhere better to put it, and this shows up in
+                // the Initiator column.
+                ev.initiator.url = process.as_ref().map(|p| p.to_string());
 
                 // FIXME: do we actually need to send this event?
                 // let ev2 = network::EventRequestWillBeSentExtraInfo {
@@ -372,7 +561,7 @@ impl ClientState {
                 conn.send_event(ev).await?;
                 // conn.send_event(ev2).await?;
             }
-            DevtoolsProtoEventInner::NewResponse(id, parts) => {
+            DevtoolsProtoEventInner::NewResponse(id, parts, remote) => {
                 let ev = network::EventResponseReceived {
                     request_id: network::RequestId::new(id.to_string()),
                     loader_id: network::LoaderId::new(""),
@@ -398,9 +587,8 @@ impl ClientState {
                         // FIXME: we can probably find this out
                         connection_reused: false,
                         connection_id: 0.,
-                        // FIXME: we definitely have this
-                        remote_ip_address: None,
-                        remote_port: None,
+                        remote_ip_address: Some(remote.ip().to_string()),
+                        remote_port: Some(remote.port() as i64),
                         from_disk_cache: None,
                         from_service_worker: None,
                         from_prefetch_cache: None,
@@ -429,7 +617,7 @@ impl ClientState {
 
                 conn.send_event(ev).await?;
             }
-            DevtoolsProtoEventInner::ResponseFinished(id, len) => {
+            DevtoolsProtoEventInner::ResponseFinished(id, len, _body) => {
                 let ev = network::EventLoadingFinished {
                     request_id: network::RequestId::new(id.to_string()),
                     timestamp,
@@ -440,6 +628,64 @@ impl ClientState {
 
                 conn.send_event(ev).await?;
             }
+            DevtoolsProtoEventInner::ResponseFailed(id, error) => {
+                let ev = network::EventLoadingFailed {
+                    request_id: network::RequestId::new(id.to_string()),
+                    timestamp,
+                    r#type: network::ResourceType::Other,
+                    error_text: error.to_string(),
+                    canceled: None,
+                    blocked_reason: None,
+                    cors_error_status: None,
+                };
+                conn.send_event(ev).await?;
+            }
+            DevtoolsProtoEventInner::ConnectionFailed { id, target, reason } => {
+                // There is no request, so make one up so the failure shows
+                // up in the network panel at all.
+                let scheme = if target.server_port() == 443 {
+                    "https"
+                } else {
+                    "http"
+                };
+                let ev = request_will_be_sent(
+                    id.clone(),
+                    network::Request {
+                        url: format!("{scheme}://{}/", target.server_addr()),
+                        method: "CONNECT".to_string(),
+                        ..empty_request()
+                    },
+                    &msg.timing,
+                );
+                conn.send_event(ev).await?;
+
+                let ev = network::EventLoadingFailed {
+                    request_id: network::RequestId::new(id.clone()),
+                    timestamp,
+                    r#type: network::ResourceType::Other,
+                    error_text: match reason {
+                        ConnectionFailure::Refused => "net::ERR_CONNECTION_REFUSED",
+                        ConnectionFailure::ResetBeforeData => "net::ERR_CONNECTION_RESET",
+                        ConnectionFailure::Unreachable(kind) => match kind {
+                            IcmpErrorKind::PortUnreachable => "net::ERR_CONNECTION_REFUSED",
+                            IcmpErrorKind::AdministrativelyProhibited => {
+                                "net::ERR_NETWORK_ACCESS_DENIED"
+                            }
+                            _ => "net::ERR_ADDRESS_UNREACHABLE",
+                        },
+                    }
+                    .to_string(),
+                    canceled: None,
+                    blocked_reason: None,
+                    cors_error_status: None,
+                };
+                conn.send_event(ev).await?;
+            }
+            DevtoolsProtoEventInner::Undecrypted { id, flow } => {
+                let (sent, failed) = undecrypted_events(id, flow, &msg.timing);
+                conn.send_event(sent).await?;
+                conn.send_event(failed).await?;
+            }
         }
         Ok(())
     }
@@ -447,18 +693,89 @@ impl ClientState {
 
 pub struct DevtoolsListener {
     send: Arc<EventBuffer<DevtoolsProtoEvent>>,
-    response_bodies: Arc<RwLock<ResponseBodyTracker>>,
+    response_bodies: Arc<RwLock<BodyStore>>,
     requests_inflight: BTreeMap<NdRequestId, (http::request::Parts, Option<Vec<u8>>)>,
+    /// Responses that have started and not finished, and their connection.
+    responses_inflight: BTreeMap<NdRequestId, IPTarget>,
+    /// Requests that have been sent and whose response has not finished,
+    /// for the [`BodyRecord`] of the response body.
+    awaiting_body: BTreeMap<NdRequestId, (IPTarget, http::Method, http::Uri)>,
+    failed_connections: u64,
+    undecrypted: UndecryptedFlowTracker,
+    undecrypted_connections: u64,
+    owners: ProcessOwners,
 }
 
-impl Listener<HTTPStreamEvent> for DevtoolsListener {
-    fn on_data(
-        &mut self,
-        timing: TimingInfo,
-        _target: net_decode::chomp::IPTarget,
-        _to_client: bool,
-        data: HTTPStreamEvent,
-    ) {
+impl EventSink for DevtoolsListener {
+    fn on_event(&mut self, event: ClipperEvent) {
+        match &event {
+            ClipperEvent::Flow(FlowEvent::Closed(closed)) => {
+                self.owners.remove(&closed.target);
+                // No more responses are coming on it.
+                self.awaiting_body
+                    .retain(|_, (target, _, _)| *target != closed.target);
+            }
+            ClipperEvent::Flow(FlowEvent::Failed(failed)) => self.owners.remove(&failed.target),
+            _ => {}
+        }
+        if let Some(flow) = self.undecrypted.on_event(&event) {
+            let ClipperEvent::Flow(FlowEvent::Closed(closed)) = &event else {
+                unreachable!("flows are only judged when they close");
+            };
+            self.undecrypted_connections += 1;
+            self.send.send(DevtoolsProtoEvent {
+                timing: closed.timing.clone(),
+                inner: DevtoolsProtoEventInner::Undecrypted {
+                    id: format!("undecrypted-{}", self.undecrypted_connections),
+                    flow,
+                },
+            });
+        }
+
+        match event {
+            ClipperEvent::Http {
+                timing,
+                target,
+                event,
+            } => self.on_http(timing, target, event),
+            ClipperEvent::Flow(FlowEvent::Failed(failed)) => {
+                self.failed_connections += 1;
+                self.send.send(DevtoolsProtoEvent {
+                    timing: failed.timing,
+                    inner: DevtoolsProtoEventInner::ConnectionFailed {
+                        id: format!("conn-failed-{}", self.failed_connections),
+                        target: failed.target,
+                        reason: failed.reason,
+                    },
+                });
+            }
+            ClipperEvent::Flow(FlowEvent::Closed(closed))
+                if matches!(closed.kind, CloseKind::Reset { .. }) =>
+            {
+                let failed: Vec<_> = self
+                    .responses_inflight
+                    .iter()
+                    .filter(|(_, target)| **target == closed.target)
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in failed {
+                    self.responses_inflight.remove(&id);
+                    self.send.send(DevtoolsProtoEvent {
+                        timing: closed.timing.clone(),
+                        inner: DevtoolsProtoEventInner::ResponseFailed(
+                            id,
+                            "net::ERR_CONNECTION_RESET",
+                        ),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl DevtoolsListener {
+    fn on_http(&mut self, timing: TimingInfo, target: IPTarget, data: HTTPStreamEvent) {
         tracing::trace!(?data, "stream event");
         match data {
             HTTPStreamEvent::NewRequest(id, parts) => {
@@ -481,50 +798,314 @@ impl Listener<HTTPStreamEvent> for DevtoolsListener {
                     .requests_inflight
                     .remove(&id)
                     .expect("bad requests inflight remove");
+                self.awaiting_body
+                    .insert(id, (target, parts.method.clone(), parts.uri.clone()));
                 self.send.send(DevtoolsProtoEvent {
                     timing,
-                    inner: DevtoolsProtoEventInner::NewRequest { id, body, parts },
+                    inner: DevtoolsProtoEventInner::NewRequest {
+                        id,
+                        body,
+                        parts,
+                        process: self.owners.get(&target),
+                    },
                 })
             }
             HTTPStreamEvent::NewResponse(id, parts) => {
+                self.responses_inflight.insert(id, target);
                 self.send.send(DevtoolsProtoEvent {
                     timing,
-                    inner: DevtoolsProtoEventInner::NewResponse(id, parts),
+                    inner: DevtoolsProtoEventInner::NewResponse(id, parts, target.server_addr()),
                 });
             }
             HTTPStreamEvent::RespBodyChunk(id, data) => {
-                self.response_bodies.write().unwrap().on_chunk(id, &data);
+                let mut bodies = self.response_bodies.write().unwrap();
+                if let Err(e) = bodies.append(id, &data) {
+                    tracing::warn!("could not keep the body of response {id}: {e}");
+                    bodies.remove(id);
+                }
+                drop(bodies);
                 self.send.send(DevtoolsProtoEvent {
                     timing,
                     inner: DevtoolsProtoEventInner::RespBodyChunk(id, data),
                 });
             }
             HTTPStreamEvent::ResponseFinished(id, len) => {
+                self.responses_inflight.remove(&id);
+                let request = self.awaiting_body.remove(&id);
+                let mut bodies = self.response_bodies.write().unwrap();
+                let hash = match bodies.finish(id) {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        tracing::warn!("could not keep the body of response {id}: {e}");
+                        bodies.remove(id);
+                        None
+                    }
+                };
+                if let (Some(hash), Some((_, method, uri))) = (hash, request) {
+                    let record = BodyRecord {
+                        time: timing.received_on_wire,
+                        request: id,
+                        method: method.to_string(),
+                        uri: uri.to_string(),
+                        hash,
+                        len: bodies.len(id).unwrap_or_default(),
+                    };
+                    if let Err(e) = bodies.content_mut().record(&record) {
+                        tracing::warn!("could not index the body of response {id}: {e}");
+                    }
+                }
+                drop(bodies);
                 self.send.send(DevtoolsProtoEvent {
                     timing,
-                    inner: DevtoolsProtoEventInner::ResponseFinished(id, len),
+                    inner: DevtoolsProtoEventInner::ResponseFinished(id, len, hash),
                 });
             }
+            HTTPStreamEvent::ReqBodyTruncated(id, missing)
+            | HTTPStreamEvent::RespBodyTruncated(id, missing) => {
+                tracing::debug!("{missing} bytes of the body of request {id} were not captured");
+            }
+        }
+    }
+}
+
+/// Decoding stack feeding `devtools_listener`, with bodies filtered per
+/// `body_policies` and then per whatever `live` is set to at the time.
+/// Nothing reaches the listener while `gate` is replaying.
+pub fn devtools_chomper(
+    devtools_listener: DevtoolsListener,
+    body_policies: BodyPolicies,
+    live: LiveConfig,
+    gate: ReplayGate,
+    decode_options: DecodeOptions,
+    key_db: Arc<RwLock<KeyDB>>,
+) -> EthernetChomper<ListenerDispatcher> {
+    net_decode::chomper_with_options(
+        Gated::new(
+            gate,
+            BodyPolicyListener::new(
+                body_policies,
+                Box::new(LiveFilter::new(live, EventListener::new(devtools_listener))),
+            ),
+        ),
+        key_db,
+        decode_options,
+    )
+}
+
+pub async fn do_devtools_server_inner(
+    file: PathBuf,
+    merge: Vec<MergeInput>,
+    filter: Option<CaptureFilter>,
+    slice: CaptureSlice,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), devtools_server::Error> {
+    let key_db = Arc::new(RwLock::new(key_db));
+    let (devtools_listener, bits) = make_devtools_listener(&decode_options)?;
+    let gate = ReplayGate::default();
+    // Served while the file is read, so that clients that connect early are
+    // kept up with rather than having events go past them.
+    let mut chomper = WaitForRoom {
+        pressure: bits.backpressure(),
+        pause: None,
+        handle: tokio::runtime::Handle::current(),
+        next: devtools_chomper(
+            devtools_listener,
+            body_policies,
+            bits.live.clone(),
+            gate.clone(),
+            decode_options,
+            key_db,
+        ),
+    };
+
+    let cancel = CancellationToken::new();
+    let server = tokio::spawn(run_devtools_server(
+        bits,
+        cancel.clone(),
+        DEVTOOLS_PORT_RANGE,
+    ));
+
+    let (send_done, done) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let r = if merge.is_empty() {
+            capture_slice::dump_pcap_file(file, slice, filter, gate, &mut chomper)
+        } else {
+            let inputs = iter::once(MergeInput::new(file)).chain(merge).collect();
+            capture_slice::dump_merged_pcaps(inputs, slice, filter, gate, &mut chomper)
+        };
+        let _ = send_done.send(r);
+    });
+
+    serve_while_decoding(server, cancel, done).await
+}
+
+/// Holds frames back while devtools clients are behind, so that a capture
+/// being read waits for them rather than piling up events. With a `pause`,
+/// throws frames away while paused.
+struct WaitForRoom<C> {
+    pressure: Backpressure,
+    pause: Option<CapturePause>,
+    handle: tokio::runtime::Handle,
+    next: C,
+}
+
+impl<C: FrameChomper> FrameChomper for WaitForRoom<C> {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        if self.pause.as_ref().is_some_and(|p| p.is_paused()) {
+            return Ok(());
+        }
+        if !self.pressure.has_room() {
+            self.handle.block_on(self.pressure.room());
+        }
+        self.next.chomp(timing, link_type, packet)
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        self.next.on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.next.on_wireguard_keys(key_log)
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.next.on_key(client_random, secret_type, secret)
+    }
+}
+
+/// Keys that turn up while a stream is being decoded, e.g. from a key log
+/// being followed somewhere else.
+pub type LiveKeys = mpsc::Receiver<(ClientRandom, SecretType, Secret)>;
+
+/// Gives `next` the keys that have come in on `keys` before each frame.
+struct WithLiveKeys<C> {
+    keys: Option<LiveKeys>,
+    key_db: Arc<RwLock<KeyDB>>,
+    next: C,
+}
+
+impl<C: FrameChomper> FrameChomper for WithLiveKeys<C> {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        while let Some((client_random, secret_type, secret)) =
+            self.keys.as_ref().and_then(|keys| keys.try_recv().ok())
+        {
+            self.key_db.write().unwrap().on_secret(
+                client_random.clone(),
+                secret_type,
+                secret.clone(),
+            );
+            self.next.on_key(client_random, secret_type, secret);
         }
+        self.next.chomp(timing, link_type, packet)
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        self.next.on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.next.on_wireguard_keys(key_log)
     }
 
-    fn on_side_data(&mut self, _data: Box<dyn net_decode::listener::SideData>) {}
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.next.on_key(client_random, secret_type, secret)
+    }
 }
 
-pub async fn do_devtools_server_inner(file: PathBuf) -> Result<(), devtools_server::Error> {
-    let key_db = Arc::new(RwLock::new(KeyDB::default()));
-    let (devtools_listener, bits) = make_devtools_listener();
-    let mut chomper = net_decode::chomper(devtools_listener, key_db.clone());
-    chomp::dump_pcap_file(file, &mut chomper)?;
+/// Serves a devtools server on a capture while it is still being read from
+/// `reader`, e.g. a pipe from tcpdump, rather than once all of it is
+/// decoded. Carries on serving after the capture ends, until Ctrl-C.
+///
+/// Keys sent on `live_keys` are used from the next packet on.
+pub async fn do_devtools_stream_inner(
+    reader: impl Read + Send + 'static,
+    filter: Option<CaptureFilter>,
+    live_keys: Option<LiveKeys>,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), devtools_server::Error> {
+    let key_db = Arc::new(RwLock::new(key_db));
+    let (devtools_listener, bits) = make_devtools_listener(&decode_options)?;
+    let mut chomper = WaitForRoom {
+        pressure: bits.backpressure(),
+        pause: Some(bits.pause.clone()),
+        handle: tokio::runtime::Handle::current(),
+        next: WithLiveKeys {
+            keys: live_keys,
+            key_db: key_db.clone(),
+            next: devtools_chomper(
+                devtools_listener,
+                body_policies,
+                bits.live.clone(),
+                ReplayGate::default(),
+                decode_options,
+                key_db,
+            ),
+        },
+    };
 
     let cancel = CancellationToken::new();
-    let h = run_devtools_server(bits, cancel.clone(), DEVTOOLS_PORT_RANGE);
+    let server = tokio::spawn(run_devtools_server(
+        bits,
+        cancel.clone(),
+        DEVTOOLS_PORT_RANGE,
+    ));
+
+    // Not a blocking task of the runtime, since those are waited for when
+    // it shuts down, and the reader may never end.
+    let (send_done, done) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let r = match filter {
+            Some(filter) => chomp::dump_pcap(reader, &mut FilteredChomper::new(filter, chomper)),
+            None => chomp::dump_pcap(reader, &mut chomper),
+        };
+        let _ = send_done.send(r);
+    });
+
+    serve_while_decoding(server, cancel, done).await
+}
+
+/// Serves until Ctrl-C, or until the server or the decoding fails. Carries
+/// on serving what was seen once the decoding finishes.
+async fn serve_while_decoding(
+    mut server: tokio::task::JoinHandle<Result<(), devtools_server::Error>>,
+    cancel: CancellationToken,
+    done: tokio::sync::oneshot::Receiver<Result<(), Error>>,
+) -> Result<(), devtools_server::Error> {
+    let mut done = Some(done);
 
     loop {
         tokio::select! {
-            r = h => {
+            r = &mut server => {
                 cancel.cancel();
-                return r;
+                return r?;
+            }
+            r = async { done.as_mut().unwrap().await }, if done.is_some() => {
+                done = None;
+                match r {
+                    Ok(Ok(())) => tracing::info!("End of capture, still serving what was seen"),
+                    Ok(Err(e)) => {
+                        cancel.cancel();
+                        return Err(e);
+                    }
+                    Err(_) => {
+                        cancel.cancel();
+                        return Err("decoding stopped unexpectedly".into());
+                    }
+                }
             }
             _ = tokio::signal::ctrl_c() => {
                 cancel.cancel();
@@ -536,25 +1117,61 @@ pub async fn do_devtools_server_inner(file: PathBuf) -> Result<(), devtools_serv
 
 pub struct ListenerBits {
     event_buffer: Arc<EventBuffer<DevtoolsProtoEvent>>,
-    response_bodies: Arc<RwLock<ResponseBodyTracker>>,
+    response_bodies: Arc<RwLock<BodyStore>>,
+    /// Changed by `Clipper.configure`. Give it to [`devtools_chomper`].
+    pub live: LiveConfig,
+    /// Filled in by captures that know which process each connection
+    /// belongs to.
+    pub owners: ProcessOwners,
+    /// Changed by `Clipper.pause` and `Clipper.resume`.
+    pub pause: CapturePause,
 }
 
-pub fn make_devtools_listener() -> (DevtoolsListener, ListenerBits) {
+impl ListenerBits {
+    /// Held back by devtools clients that are behind on events.
+    pub fn backpressure(&self) -> Backpressure {
+        self.event_buffer.pressure.clone()
+    }
+}
+
+/// Response bodies are kept in a [`ContentStore`] in
+/// [`DecodeOptions::body_store`], or a temporary one, and in memory until
+/// they finish unless they go over [`DecodeOptions::spill_bodies_over`].
+pub fn make_devtools_listener(
+    decode_options: &DecodeOptions,
+) -> io::Result<(DevtoolsListener, ListenerBits)> {
     let event_buffer = Arc::new(EventBuffer::new(100, 1000));
-    let response_bodies: Arc<RwLock<ResponseBodyTracker>> = Default::default();
+    let owners = ProcessOwners::default();
+    let content = match &decode_options.body_store {
+        Some(dir) => ContentStore::open(dir)?,
+        None => ContentStore::temporary()?,
+    };
+    let response_bodies = Arc::new(RwLock::new(BodyStore::new(
+        content,
+        decode_options.spill_bodies_over,
+    )));
     let devtools_listener = DevtoolsListener {
         send: event_buffer.clone(),
         response_bodies: response_bodies.clone(),
         requests_inflight: Default::default(),
+        responses_inflight: Default::default(),
+        awaiting_body: Default::default(),
+        failed_connections: 0,
+        undecrypted: Default::default(),
+        undecrypted_connections: 0,
+        owners: owners.clone(),
     };
 
-    (
+    Ok((
         devtools_listener,
         ListenerBits {
             event_buffer,
             response_bodies,
+            live: LiveConfig::default(),
+            owners,
+            pause: CapturePause::default(),
         },
-    )
+    ))
 }
 
 async fn try_make_conn_stream(
@@ -603,6 +1220,8 @@ pub async fn run_devtools_server(
                 let mut client_state = ClientState {
                     network_enabled: false,
                     response_bodies: bits.response_bodies.clone(),
+                    live: bits.live.clone(),
+                    pause: bits.pause.clone(),
                 };
                 let cancel = cancel.clone();
 
@@ -619,3 +1238,97 @@ pub async fn run_devtools_server(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_undecrypted_connection_fails() {
+        let (listener, bits) = make_devtools_listener(&DecodeOptions::default()).unwrap();
+        // Closing the connection is what gets it judged.
+        let file = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../net_decode/corpus/tls12-rsa.pcapng");
+        let key_db = Arc::new(RwLock::new(KeyDB::default()));
+        let mut chomper = net_decode::chomper(EventListener::new(listener), key_db);
+        chomp::dump_pcap_file(file, &mut chomper).unwrap();
+
+        let backlog = bits.event_buffer.backlog.read().unwrap();
+        let undecrypted: Vec<_> = backlog
+            .iter()
+            .filter_map(|ev| match &ev.inner {
+                DevtoolsProtoEventInner::Undecrypted { id, flow } => Some((id, flow, &ev.timing)),
+                _ => None,
+            })
+            .collect();
+        let [(id, flow, timing)] = &undecrypted[..] else {
+            panic!("expected one undecrypted connection: {undecrypted:?}");
+        };
+        assert_eq!(id.as_str(), "undecrypted-1");
+
+        let (sent, failed) = undecrypted_events(id, flow, timing);
+        assert_eq!(sent.request.method, "CONNECT");
+        assert_eq!(sent.request.url, "https://server.test/");
+        assert_eq!(sent.request_id, failed.request_id);
+        assert!(
+            failed
+                .error_text
+                .starts_with("clipper: not decrypted: server.test: no keys (client random "),
+            "{}",
+            failed.error_text
+        );
+    }
+
+    #[test]
+    fn test_body_store_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let (listener, bits) = make_devtools_listener(&DecodeOptions {
+            body_store: Some(dir.path().to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        let file = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../net_decode/corpus/http-80.pcapng");
+        let key_db = Arc::new(RwLock::new(KeyDB::default()));
+        let mut chomper = net_decode::chomper(EventListener::new(listener), key_db);
+        chomp::dump_pcap_file(file, &mut chomper).unwrap();
+
+        let backlog = bits.event_buffer.backlog.read().unwrap();
+        let finished: Vec<_> = backlog
+            .iter()
+            .filter_map(|ev| match &ev.inner {
+                DevtoolsProtoEventInner::ResponseFinished(id, _, body) => Some((*id, *body)),
+                _ => None,
+            })
+            .collect();
+        let [(id, Some(hash))] = finished[..] else {
+            panic!("expected one stored response body: {finished:?}");
+        };
+
+        let index =
+            std::fs::read_to_string(dir.path().join(crate::body_store::INDEX_FILE)).unwrap();
+        let records: Vec<serde_json::Value> = index
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let [record] = &records[..] else {
+            panic!("expected one record: {records:?}");
+        };
+        assert_eq!(record["request"], id);
+        assert_eq!(record["method"], "GET");
+        assert_eq!(record["uri"], "/robots.txt");
+        assert_eq!(record["hash"], hash.to_string());
+        assert_eq!(record["len"], 196);
+
+        let mut body = String::new();
+        bits.response_bodies
+            .read()
+            .unwrap()
+            .content()
+            .open_body(hash)
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert!(body.contains("<title>404 Not Found</title>"), "{body}");
+    }
+}
@@ -0,0 +1,366 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Clipper's internal model of what happened on the network.
+//!
+//! The decoders in `net_decode` talk to each other with [`Listener`]s and a
+//! pile of loosely typed side data, which is great for decoding and terrible
+//! for anything that just wants to show the results. Everything that
+//! consumes decoded traffic (the devtools server, exports, ...) should
+//! instead implement [`EventSink`] and be hooked up with [`EventListener`],
+//! so that it sees one enum and does not need to know which decoder
+//! produced what.
+//!
+//! FIXME: WebSocket and DNS events go here once we decode those.
+
+use net_decode::{
+    chomp::IPTarget,
+    diagnostic::side_data::Diagnostic,
+    http::HTTPStreamEvent,
+    icmp::side_data::IcmpError,
+    listener::{Listener, SideData, TimingInfo},
+    packet_comment::side_data::PacketComment,
+    tcp_reassemble::side_data::{
+        ConnectionClosed, ConnectionFailed, ConnectionLifecycle, FlowEvicted,
+    },
+    tcp_timing::side_data::{FlowPerformance, HandshakeRtt},
+    tcp_window::side_data::ZeroWindowStall,
+    tls::side_data::{
+        ALPNCompleted, AlertLevel, ClientCertificate, ClientFingerprint, ClientHelloSeen,
+        OpaqueFlow, PendingKeysDropped, ServerCertificate, ServerFingerprint, ServerHelloSeen,
+        TlsAlert, TlsDecodeFailed,
+    },
+};
+
+#[derive(Debug)]
+pub enum ClipperEvent {
+    Flow(FlowEvent),
+    Tls(TlsEvent),
+    Http {
+        timing: TimingInfo,
+        target: IPTarget,
+        event: HTTPStreamEvent,
+    },
+    Finding(Finding),
+    /// Something the decoders had to skip over.
+    Diagnostic(Diagnostic),
+    /// Something said about the packet being decoded, for writing on it in
+    /// exported captures.
+    Comment(PacketComment),
+}
+
+/// Events about TCP connections and UDP flows themselves.
+#[derive(Clone, Debug)]
+pub enum FlowEvent {
+    Failed(ConnectionFailed),
+    Closed(ConnectionClosed),
+    Evicted(FlowEvicted),
+    Lifecycle(ConnectionLifecycle),
+    HandshakeRtt(HandshakeRtt),
+    Performance(FlowPerformance),
+    ZeroWindow(ZeroWindowStall),
+    Icmp(IcmpError),
+}
+
+#[derive(Clone, Debug)]
+pub enum TlsEvent {
+    ClientHello(ClientHelloSeen),
+    ServerHello(ServerHelloSeen),
+    ClientFingerprint(ClientFingerprint),
+    ServerFingerprint(ServerFingerprint),
+    ALPNCompleted(ALPNCompleted),
+    ServerCertificate(ServerCertificate),
+    ClientCertificate(ClientCertificate),
+    Alert(TlsAlert),
+    Opaque(OpaqueFlow),
+    PendingKeysDropped(PendingKeysDropped),
+    DecodeFailed(TlsDecodeFailed),
+}
+
+/// Something a human should probably look at, derived from the lower level
+/// events. These are sent in addition to the events they came from.
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub target: IPTarget,
+    pub message: String,
+}
+
+/// Consumer of [`ClipperEvent`]s.
+pub trait EventSink: Send + Sync {
+    fn on_event(&mut self, event: ClipperEvent);
+}
+
+/// [`Listener`] at the end of the decoding stack that translates everything
+/// into [`ClipperEvent`]s for an [`EventSink`].
+pub struct EventListener<S: EventSink> {
+    sink: S,
+}
+
+impl<S: EventSink> EventListener<S> {
+    pub fn new(sink: S) -> Self {
+        Self { sink }
+    }
+}
+
+fn findings_for(event: &TlsEvent) -> Option<Finding> {
+    match event {
+        TlsEvent::Alert(alert) if alert.level == AlertLevel::Fatal => Some(Finding {
+            target: alert.target,
+            message: format!(
+                "{} aborted the TLS connection with {:?}",
+                if alert.to_client { "server" } else { "client" },
+                alert.description
+            ),
+        }),
+        TlsEvent::Opaque(opaque) => Some(Finding {
+            target: opaque.target,
+            message: format!(
+                "no keys for TLS connection to {}",
+                opaque
+                    .server_name
+                    .clone()
+                    .unwrap_or_else(|| opaque.target.server_addr().to_string())
+            ),
+        }),
+        TlsEvent::PendingKeysDropped(dropped) => Some(Finding {
+            target: dropped.target,
+            message: format!(
+                "keys arrived too late for TLS connection to {}; dropped {} bytes",
+                dropped.target.server_addr(),
+                dropped.dropped_bytes
+            ),
+        }),
+        TlsEvent::DecodeFailed(failed) => Some(Finding {
+            target: failed.target,
+            message: format!("could not decode TLS connection: {}", failed.error),
+        }),
+        _ => None,
+    }
+}
+
+/// Picks out the side data that is interesting outside of `net_decode`.
+/// Anything else (such as keys) is decoder plumbing and is dropped.
+fn side_data_to_event(data: &(dyn SideData + 'static)) -> Option<ClipperEvent> {
+    macro_rules! convert {
+        ($($ty:ty => $make:expr),* $(,)?) => {
+            $(
+                if let Some(d) = data.downcast_ref::<$ty>() {
+                    return Some($make(d.clone()));
+                }
+            )*
+        };
+    }
+
+    convert! {
+        ConnectionFailed => |d| ClipperEvent::Flow(FlowEvent::Failed(d)),
+        ConnectionClosed => |d| ClipperEvent::Flow(FlowEvent::Closed(d)),
+        FlowEvicted => |d| ClipperEvent::Flow(FlowEvent::Evicted(d)),
+        ConnectionLifecycle => |d| ClipperEvent::Flow(FlowEvent::Lifecycle(d)),
+        HandshakeRtt => |d| ClipperEvent::Flow(FlowEvent::HandshakeRtt(d)),
+        FlowPerformance => |d| ClipperEvent::Flow(FlowEvent::Performance(d)),
+        ZeroWindowStall => |d| ClipperEvent::Flow(FlowEvent::ZeroWindow(d)),
+        IcmpError => |d| ClipperEvent::Flow(FlowEvent::Icmp(d)),
+        ClientHelloSeen => |d| ClipperEvent::Tls(TlsEvent::ClientHello(d)),
+        ServerHelloSeen => |d| ClipperEvent::Tls(TlsEvent::ServerHello(d)),
+        ClientFingerprint => |d| ClipperEvent::Tls(TlsEvent::ClientFingerprint(d)),
+        ServerFingerprint => |d| ClipperEvent::Tls(TlsEvent::ServerFingerprint(d)),
+        ALPNCompleted => |d| ClipperEvent::Tls(TlsEvent::ALPNCompleted(d)),
+        ServerCertificate => |d| ClipperEvent::Tls(TlsEvent::ServerCertificate(d)),
+        ClientCertificate => |d| ClipperEvent::Tls(TlsEvent::ClientCertificate(d)),
+        TlsAlert => |d| ClipperEvent::Tls(TlsEvent::Alert(d)),
+        OpaqueFlow => |d| ClipperEvent::Tls(TlsEvent::Opaque(d)),
+        PendingKeysDropped => |d| ClipperEvent::Tls(TlsEvent::PendingKeysDropped(d)),
+        TlsDecodeFailed => |d| ClipperEvent::Tls(TlsEvent::DecodeFailed(d)),
+        Diagnostic => ClipperEvent::Diagnostic,
+        PacketComment => ClipperEvent::Comment,
+    }
+
+    None
+}
+
+impl<S: EventSink> Listener<HTTPStreamEvent> for EventListener<S> {
+    fn on_data(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        _to_client: bool,
+        data: HTTPStreamEvent,
+    ) {
+        self.sink.on_event(ClipperEvent::Http {
+            timing,
+            target,
+            event: data,
+        });
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        let Some(event) = side_data_to_event(&*data) else {
+            return;
+        };
+
+        let finding = match &event {
+            ClipperEvent::Tls(tls) => findings_for(tls),
+            _ => None,
+        };
+        self.sink.on_event(event);
+        if let Some(finding) = finding {
+            self.sink.on_event(ClipperEvent::Finding(finding));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use net_decode::{
+        diagnostic::{Layer, Severity},
+        key_db::{ClientRandom, Secret, SecretType},
+        tcp_reassemble::side_data::CloseKind,
+        tls::side_data::{AlertDescription, NewKeyReceived},
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Collect(Vec<ClipperEvent>);
+
+    impl EventSink for Collect {
+        fn on_event(&mut self, event: ClipperEvent) {
+            self.0.push(event);
+        }
+    }
+
+    fn target() -> IPTarget {
+        IPTarget::V4 {
+            client_port: 40000,
+            server_port: 443,
+            client_ip: [10, 0, 0, 1].into(),
+            server_ip: [10, 0, 0, 2].into(),
+            overlay: None,
+        }
+    }
+
+    fn events_for(data: Box<dyn SideData>) -> Vec<ClipperEvent> {
+        let mut listener = EventListener::new(Collect::default());
+        listener.on_side_data(data);
+        listener.sink.0
+    }
+
+    fn alert(level: AlertLevel) -> Box<dyn SideData> {
+        Box::new(TlsAlert {
+            timing: TimingInfo::default(),
+            target: target(),
+            to_client: true,
+            level,
+            description: AlertDescription::HandshakeFailure,
+        })
+    }
+
+    #[test]
+    fn test_http_event() {
+        let mut listener = EventListener::new(Collect::default());
+        listener.on_data(
+            TimingInfo::default(),
+            target(),
+            false,
+            HTTPStreamEvent::RequestFinished(1, 0),
+        );
+        assert!(matches!(
+            &listener.sink.0[..],
+            [ClipperEvent::Http {
+                event: HTTPStreamEvent::RequestFinished(1, 0),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_flow_event() {
+        let events = events_for(Box::new(ConnectionClosed {
+            timing: TimingInfo::default(),
+            target: target(),
+            kind: CloseKind::Graceful,
+            stats: Default::default(),
+        }));
+        assert!(matches!(
+            &events[..],
+            [ClipperEvent::Flow(FlowEvent::Closed(ConnectionClosed {
+                kind: CloseKind::Graceful,
+                ..
+            }))]
+        ));
+    }
+
+    #[test]
+    fn test_fatal_alert_finding() {
+        let events = events_for(alert(AlertLevel::Fatal));
+        assert!(matches!(
+            &events[..],
+            [
+                ClipperEvent::Tls(TlsEvent::Alert(_)),
+                ClipperEvent::Finding(Finding { message, .. }),
+            ] if message == "server aborted the TLS connection with HandshakeFailure"
+        ));
+
+        let events = events_for(alert(AlertLevel::Warning));
+        assert!(matches!(
+            &events[..],
+            [ClipperEvent::Tls(TlsEvent::Alert(_))]
+        ));
+    }
+
+    #[test]
+    fn test_opaque_flow_finding() {
+        let events = events_for(Box::new(OpaqueFlow {
+            target: target(),
+            client_random: ClientRandom(vec![0; 32]),
+            server_name: Some("example.com".to_owned()),
+        }));
+        assert!(matches!(
+            &events[..],
+            [
+                ClipperEvent::Tls(TlsEvent::Opaque(_)),
+                ClipperEvent::Finding(Finding { message, .. }),
+            ] if message == "no keys for TLS connection to example.com"
+        ));
+    }
+
+    #[test]
+    fn test_diagnostic_and_comment() {
+        let events = events_for(Box::new(Diagnostic {
+            timing: TimingInfo::default(),
+            target: Some(target()),
+            layer: Layer::Tcp,
+            severity: Severity::Warning,
+            message: "bad checksum".to_owned(),
+        }));
+        assert!(matches!(
+            &events[..],
+            [ClipperEvent::Diagnostic(Diagnostic {
+                layer: Layer::Tcp,
+                ..
+            })]
+        ));
+
+        let events = events_for(Box::new(PacketComment {
+            timing: TimingInfo::default(),
+            target: None,
+            since: None,
+            text: "hello".to_owned(),
+        }));
+        assert!(matches!(
+            &events[..],
+            [ClipperEvent::Comment(PacketComment { text, .. })] if text == "hello"
+        ));
+    }
+
+    #[test]
+    fn test_plumbing_dropped() {
+        let events = events_for(Box::new(NewKeyReceived {
+            typ: SecretType::ClientTrafficSecret0,
+            client_random: ClientRandom(vec![0; 32]),
+            secret: Secret(vec![0; 32]),
+        }));
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Handing a running capture over to another clipper process, for instance
+//! to upgrade clipper during a long capture without restarting the program
+//! being captured.
+//!
+//! The capture socket, the pidfd of the captured program and the socket the
+//! injected library sends keys to are all just file descriptors, so the old
+//! process sends them over a unix socket along with the keys it knows about,
+//! then stops. Packets arriving in between wait in the capture socket's
+//! buffer, so none are lost. slirp4netns is tied to the captured program
+//! rather than to us, so the networking is not disturbed either.
+//!
+//! Targets that decode traffic also send a checkpoint of the connections
+//! still open (see [`net_decode::checkpoint`]), so that the new process can
+//! carry on decoding them.
+
+use std::{
+    io::{IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+use base64::Engine;
+use net_decode::key_db::KeyDB;
+use nix::{
+    cmsg_space,
+    sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+};
+
+use crate::Error;
+
+const HANDOFF_VERSION: u64 = 1;
+
+/// Everything needed to carry on with a capture, as received from the
+/// previous process.
+pub struct Handoff {
+    pub capture_fd: OwnedFd,
+    pub child_pidfd: OwnedFd,
+    /// Listening socket for the injected library.
+    pub embedding_listener: OwnedFd,
+    /// Directory with the socket for the injected library in it, to be
+    /// cleaned up when the capture ends.
+    pub temp_dir: PathBuf,
+    pub key_db: KeyDB,
+    /// Checkpoint of the connections being decoded, if the previous process
+    /// was decoding any.
+    pub flows: Option<Vec<u8>>,
+}
+
+/// The fds are duplicated into the other process by the kernel, so the
+/// caller keeps ownership of its copies.
+#[derive(Clone, Copy, Debug)]
+pub struct HandoffFds {
+    pub capture_fd: RawFd,
+    pub child_pidfd: RawFd,
+    pub embedding_listener: RawFd,
+}
+
+/// Sends the capture state over `conn`.
+///
+/// The wire format is a little endian u64 length carrying the fds as
+/// `SCM_RIGHTS`, followed by that many bytes of JSON.
+pub fn send_handoff(
+    mut conn: &UnixStream,
+    fds: HandoffFds,
+    temp_dir: &Path,
+    key_db: &KeyDB,
+    flows: Option<&[u8]>,
+) -> Result<(), Error> {
+    let body = serde_json::to_vec(&serde_json::json!({
+        "version": HANDOFF_VERSION,
+        "temp_dir": temp_dir,
+        "key_log": String::from_utf8_lossy(&key_db.to_key_log()),
+        "flows": flows.map(|f| base64::engine::general_purpose::STANDARD.encode(f)),
+    }))?;
+    let len = (body.len() as u64).to_le_bytes();
+
+    let fds = [fds.capture_fd, fds.child_pidfd, fds.embedding_listener];
+    let sent = sendmsg::<()>(
+        conn.as_raw_fd(),
+        &[IoSlice::new(&len)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    conn.write_all(&len[sent..])?;
+    conn.write_all(&body)?;
+    Ok(())
+}
+
+/// Connects to the handoff socket of a running clipper at `path` and takes
+/// over its capture.
+pub fn receive_handoff(path: &Path) -> Result<Handoff, Error> {
+    let mut conn = UnixStream::connect(path)?;
+
+    let mut len = [0u8; 8];
+    let (received, fds) = {
+        let mut iov = [IoSliceMut::new(&mut len)];
+        let mut cmsg_buf = cmsg_space!([RawFd; 3]);
+        // Not on macOS, where only captures of an interface are run, which
+        // are never handed over.
+        #[cfg(target_os = "linux")]
+        let flags = MsgFlags::MSG_CMSG_CLOEXEC;
+        #[cfg(not(target_os = "linux"))]
+        let flags = MsgFlags::empty();
+        let msg = recvmsg::<()>(conn.as_raw_fd(), &mut iov, Some(&mut cmsg_buf), flags)?;
+        let fds = msg.cmsgs().find_map(|c| match c {
+            ControlMessageOwned::ScmRights(fds) => Some(fds),
+            _ => None,
+        });
+        (msg.bytes, fds)
+    };
+    if received == 0 {
+        return Err("handoff socket closed without sending anything".into());
+    }
+    let fds: Vec<OwnedFd> = fds
+        .unwrap_or_default()
+        .into_iter()
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+    let Ok::<[OwnedFd; 3], _>([capture_fd, child_pidfd, embedding_listener]) = fds.try_into()
+    else {
+        return Err("handoff did not include the expected file descriptors".into());
+    };
+
+    conn.read_exact(&mut len[received..])?;
+    let mut body = vec![0u8; u64::from_le_bytes(len) as usize];
+    conn.read_exact(&mut body)?;
+    let body: serde_json::Value = serde_json::from_slice(&body)?;
+
+    if body["version"].as_u64() != Some(HANDOFF_VERSION) {
+        return Err(format!(
+            "handoff version {} is not supported (expected {HANDOFF_VERSION})",
+            body["version"]
+        )
+        .into());
+    }
+    let temp_dir = body["temp_dir"]
+        .as_str()
+        .ok_or("handoff is missing temp_dir")?
+        .into();
+    let mut key_db = KeyDB::default();
+    key_db.load_key_log(
+        body["key_log"].as_str().unwrap_or_default().as_bytes(),
+        &mut |_, _, _| {},
+    );
+    // Older versions did not send this.
+    let flows = match body["flows"].as_str() {
+        Some(flows) => Some(base64::engine::general_purpose::STANDARD.decode(flows)?),
+        None => None,
+    };
+
+    Ok(Handoff {
+        capture_fd,
+        child_pidfd,
+        embedding_listener,
+        temp_dir,
+        key_db,
+        flows,
+    })
+}
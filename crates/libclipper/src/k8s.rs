@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture in a Kubernetes pod, by adding an ephemeral debug container to it
+//! with `kubectl debug` and running tcpdump there, decoding the pcap it
+//! writes to its stdout as it arrives.
+//!
+//! All the containers of a pod share its network namespace, so the debug
+//! container sees the traffic of all of them without anything being
+//! changed in the pod's own containers. It needs an image with tcpdump in
+//! it, and the `netadmin` profile to be allowed to capture, which needs
+//! kubectl 1.27 or later. Ephemeral containers can't be removed from a pod,
+//! so the stopped one stays listed until the pod goes away.
+//!
+//! Nothing stops a debug container when kubectl goes away, so tcpdump is
+//! run under a shell that stops it once we stop sending it heartbeats. That
+//! needs a shell with `read -t`, like busybox's or bash.
+//!
+//! Keys have to come from files given up front, since nothing is injected
+//! into the pod.
+
+use std::{
+    io::{self, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use net_decode::{
+    body_policy::BodyPolicies, capture_filter::CaptureFilter, key_db::KeyDB, DecodeOptions,
+};
+
+use crate::{devtools::do_devtools_stream_inner, Error};
+
+/// Image with tcpdump in it, for the debug container.
+pub const DEFAULT_IMAGE: &str = "nicolaka/netshoot";
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long tcpdump carries on for without a heartbeat, in seconds.
+const HEARTBEAT_TIMEOUT_SECS: u64 = 30;
+
+/// Which pod to capture in and how.
+#[derive(Clone, Debug)]
+pub struct K8sCapture {
+    /// Pod to capture in, as given to kubectl.
+    pub pod: String,
+    /// Namespace of the pod. kubectl's current one if this is `None`.
+    pub namespace: Option<String>,
+    /// kubeconfig context to use. kubectl's current one if this is `None`.
+    pub context: Option<String>,
+    /// Container of the pod to share the process namespace of, so that its
+    /// processes are visible from the debug container.
+    pub target: Option<String>,
+    /// Image of the debug container.
+    pub image: String,
+    /// Interface to capture on. tcpdump picks one if this is `None`.
+    pub interface: Option<String>,
+    /// Applied by tcpdump in the pod.
+    pub filter: Option<CaptureFilter>,
+}
+
+impl Default for K8sCapture {
+    fn default() -> Self {
+        K8sCapture {
+            pod: String::new(),
+            namespace: None,
+            context: None,
+            target: None,
+            image: DEFAULT_IMAGE.to_string(),
+            interface: None,
+            filter: None,
+        }
+    }
+}
+
+impl K8sCapture {
+    /// Arguments for kubectl.
+    fn kubectl_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(context) = &self.context {
+            args.extend(["--context".to_string(), context.clone()]);
+        }
+        if let Some(namespace) = &self.namespace {
+            args.extend(["--namespace".to_string(), namespace.clone()]);
+        }
+        args.extend([
+            "debug".to_string(),
+            self.pod.clone(),
+            // Attached, but with no terminal, which would mangle the
+            // capture, and nothing printed on stdout but the capture.
+            "--stdin".to_string(),
+            "--quiet".to_string(),
+            "--profile=netadmin".to_string(),
+            format!("--image={}", self.image),
+        ]);
+        if let Some(target) = &self.target {
+            args.push(format!("--target={target}"));
+        }
+
+        // tcpdump's own arguments go in as the script's, so they need no
+        // quoting. -U: write each packet as it is captured, rather than
+        // when a buffer fills up.
+        let script = format!(
+            "tcpdump -U -w - \"$@\" & \
+             while read -t {HEARTBEAT_TIMEOUT_SECS} _; do :; done; \
+             kill $!"
+        );
+        args.extend(["--".to_string(), "sh".to_string(), "-c".to_string()]);
+        args.extend([script, "sh".to_string()]);
+        if let Some(interface) = &self.interface {
+            args.extend(["-i".to_string(), interface.clone()]);
+        }
+        if let Some(filter) = &self.filter {
+            args.push(filter.to_string());
+        }
+        args
+    }
+
+    /// Starts tcpdump in a debug container of the pod, with the capture on
+    /// kubectl's stdout. Whatever kubectl and tcpdump print otherwise goes
+    /// to our stderr.
+    pub fn spawn(&self) -> Result<Child, Error> {
+        let args = self.kubectl_args();
+        tracing::debug!("running kubectl {}", args.join(" "));
+        Command::new("kubectl")
+            .args(args)
+            // For the heartbeats.
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run kubectl: {e}").into())
+    }
+}
+
+/// Sends heartbeats to the debug container until it can't any more.
+fn send_heartbeats(mut stdin: ChildStdin) {
+    thread::spawn(move || {
+        while stdin.write_all(b"\n").and_then(|_| stdin.flush()).is_ok() {
+            thread::sleep(HEARTBEAT_INTERVAL);
+        }
+    });
+}
+
+/// Serves a devtools server on what tcpdump captures in a Kubernetes pod,
+/// as it is captured.
+pub async fn do_k8s_capture_to_devtools(
+    k8s: K8sCapture,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let mut child = k8s.spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    send_heartbeats(child.stdin.take().expect("stdin is piped"));
+    tracing::info!("Capturing in pod {} with a debug container", k8s.pod);
+
+    let result = do_devtools_stream_inner(
+        io::BufReader::new(stdout),
+        None,
+        None,
+        body_policies,
+        decode_options,
+        key_db,
+    )
+    .await;
+
+    // If kubectl or tcpdump failed, that says more than what came of its
+    // empty output.
+    if result.is_err() {
+        if let Ok(Some(status)) = child.try_wait() {
+            if !status.success() {
+                return Err(format!("capture in pod {} failed: kubectl {status}", k8s.pod).into());
+            }
+        }
+    }
+    // With kubectl gone, the heartbeats stop, and then tcpdump does.
+    let _ = child.kill();
+    let _ = child.wait();
+    result
+}
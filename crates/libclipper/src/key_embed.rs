@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Re-exports a pcapng with TLS keys embedded as a decryption secrets block,
+//! so the file decrypts in Wireshark and clipper without a key log alongside
+//! it.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use net_decode::{
+    chomp,
+    key_db::{ClientRandom, KeyDB},
+};
+use wire_blahaj::pcap_writer::write_dsb;
+
+use crate::{
+    events::{ClipperEvent, EventListener, EventSink, TlsEvent},
+    Error,
+};
+
+const SHB_BLOCK_TYPE: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+const BYTE_ORDER_MAGIC_LE: [u8; 4] = [0x4d, 0x3c, 0x2b, 0x1a];
+
+/// Collects the client randoms of every TLS connection in a capture.
+struct ClientRandomCollector {
+    seen: Arc<Mutex<Vec<ClientRandom>>>,
+}
+
+impl EventSink for ClientRandomCollector {
+    fn on_event(&mut self, event: ClipperEvent) {
+        if let ClipperEvent::Tls(TlsEvent::ClientHello(hello)) = event {
+            self.seen.lock().unwrap().push(hello.client_random);
+        }
+    }
+}
+
+fn client_randoms_in(file: PathBuf) -> Result<Vec<ClientRandom>, Error> {
+    let seen: Arc<Mutex<Vec<ClientRandom>>> = Default::default();
+    // ClientHellos are in the clear, so no keys are needed for this
+    let mut chomper = net_decode::chomper(
+        EventListener::new(ClientRandomCollector { seen: seen.clone() }),
+        Default::default(),
+    );
+    chomp::dump_pcap_file(file, &mut chomper)?;
+
+    let mut seen = std::mem::take(&mut *seen.lock().unwrap());
+    let mut dedup = HashSet::new();
+    seen.retain(|cr| dedup.insert(cr.clone()));
+    Ok(seen)
+}
+
+/// Length of the section header block at the start of `data`, if it is a
+/// little endian pcapng file.
+fn section_header_len(data: &[u8]) -> Result<usize, Error> {
+    if data.get(0..4) != Some(&SHB_BLOCK_TYPE) {
+        return Err("not a pcapng file".into());
+    }
+    if data.get(8..12) != Some(&BYTE_ORDER_MAGIC_LE) {
+        // We would have to write the DSB big endian to match, and there is
+        // not really any reason to have such files.
+        return Err("big endian pcapng files are not supported".into());
+    }
+    let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    if len > data.len() {
+        return Err("truncated pcapng section header".into());
+    }
+    Ok(len)
+}
+
+/// Copies `input_file` to `output_file` with the keys from `key_logs`
+/// embedded right after the section header. Unless `all_keys` is set, only
+/// keys for connections in the capture are included.
+///
+/// Any secrets already in the file are kept. The keys only go into the
+/// first section, so for files with several sections, only the first one
+/// will be decryptable with them.
+pub fn do_embed_keys(
+    input_file: PathBuf,
+    key_logs: Vec<PathBuf>,
+    output_file: PathBuf,
+    all_keys: bool,
+) -> Result<(), Error> {
+    let mut key_db = KeyDB::default();
+    for key_log in key_logs {
+        key_db.load_key_log(&fs::read(key_log)?, &mut |_, _, _| {});
+    }
+
+    let key_log = if all_keys {
+        key_db.to_key_log()
+    } else {
+        let randoms = client_randoms_in(input_file.clone())?;
+        let key_log = key_db.to_key_log_for(&randoms);
+        tracing::info!(
+            "{} TLS connections in the capture; embedding {} lines of keys",
+            randoms.len(),
+            key_log.iter().filter(|&&b| b == b'\n').count()
+        );
+        key_log
+    };
+    if key_log.is_empty() {
+        tracing::warn!("no keys to embed for this capture");
+    }
+
+    let input = fs::read(&input_file)?;
+    let shb_len = section_header_len(&input)?;
+
+    let mut out = io::BufWriter::new(fs::File::create(&output_file)?);
+    out.write_all(&input[..shb_len])?;
+    if !key_log.is_empty() {
+        write_dsb(&mut out, &key_log)?;
+    }
+    out.write_all(&input[shb_len..])?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,212 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Saving the key database to disk and loading it back, so keys from one
+//! capture can be used with another, or on another machine.
+//!
+//! Unencrypted key files are just key logs, so they work with anything that
+//! takes SSLKEYLOGFILE. Encrypted ones are a key log sealed with
+//! ChaCha20-Poly1305 under a key derived from a passphrase:
+//!
+//! ```text
+//! magic (8) | salt (16) | nonce (12) | ciphertext + tag
+//! ```
+
+use std::{fs, io::Write, num::NonZeroU32, path::PathBuf};
+
+use net_decode::key_db::KeyDB;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::Error;
+
+const MAGIC: &[u8; 8] = b"CLPKEYS\x01";
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// Environment variable that passphrases for key files are read from, to
+/// keep them out of shell history and `ps`.
+pub const PASSPHRASE_ENV_VAR: &str = "CLIPPER_KEY_PASSPHRASE";
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<LessSafeKey, Error> {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| "bad key length")?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn seal(passphrase: &str, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).map_err(|_| "no randomness")?;
+    rng.fill(&mut nonce).map_err(|_| "no randomness")?;
+
+    derive_key(passphrase, &salt)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(MAGIC),
+            &mut data,
+        )
+        .map_err(|_| "encryption failed")?;
+
+    let mut out = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + data.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&data);
+    Ok(out)
+}
+
+fn open(passphrase: &str, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    if !sealed.starts_with(MAGIC) {
+        return Err("not an encrypted key file".into());
+    }
+    let header_len = MAGIC.len() + SALT_LEN + NONCE_LEN;
+    if sealed.len() < header_len {
+        return Err("truncated encrypted key file".into());
+    }
+    let salt = &sealed[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce: [u8; NONCE_LEN] = sealed[MAGIC.len() + SALT_LEN..header_len]
+        .try_into()
+        .unwrap();
+    let mut data = sealed[header_len..].to_vec();
+
+    let len = derive_key(passphrase, salt)?
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(MAGIC),
+            &mut data,
+        )
+        .map_err(|_| "could not decrypt key file: wrong passphrase or corrupted file")?
+        .len();
+    data.truncate(len);
+    Ok(data)
+}
+
+/// A key database on disk.
+#[derive(Clone, Debug)]
+pub struct KeyFile {
+    pub path: PathBuf,
+    /// Encrypts the file when saving. Needed to load encrypted files.
+    pub passphrase: Option<String>,
+}
+
+impl KeyFile {
+    /// Key file at `path`, taking the passphrase from
+    /// [`PASSPHRASE_ENV_VAR`] if it is set.
+    pub fn from_env(path: PathBuf) -> Self {
+        KeyFile {
+            path,
+            passphrase: std::env::var(PASSPHRASE_ENV_VAR).ok(),
+        }
+    }
+
+    /// Loads the keys in this file into `key_db`.
+    pub fn load_into(&self, key_db: &mut KeyDB) -> Result<(), Error> {
+        let data = fs::read(&self.path)?;
+        let key_log = if data.starts_with(MAGIC) {
+            let passphrase = self.passphrase.as_deref().ok_or_else(|| {
+                format!(
+                    "{} is encrypted; set {PASSPHRASE_ENV_VAR} to its passphrase",
+                    self.path.display()
+                )
+            })?;
+            open(passphrase, &data)?
+        } else {
+            data
+        };
+        key_db.load_key_log(&key_log, &mut |_, _, _| {});
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<KeyDB, Error> {
+        let mut key_db = KeyDB::default();
+        self.load_into(&mut key_db)?;
+        Ok(key_db)
+    }
+
+    pub fn save(&self, key_db: &KeyDB) -> Result<(), Error> {
+        let key_log = key_db.to_key_log();
+        let data = match &self.passphrase {
+            Some(passphrase) => seal(passphrase, key_log)?,
+            None => key_log,
+        };
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&self.path)?;
+        // The mode above only applies to new files, and is subject to the
+        // umask besides.
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(&data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY_LOG: &[u8] = b"CLIENT_RANDOM \
+        0101010101010101010101010101010101010101010101010101010101010101 \
+        020202020202020202020202020202020202020202020202020202020202020202020202020202020202020202020202\n";
+
+    #[test]
+    fn test_seal_open() {
+        let sealed = seal("hunter2", KEY_LOG.to_vec()).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert_eq!(open("hunter2", &sealed).unwrap(), KEY_LOG);
+
+        assert!(open("hunter3", &sealed).is_err());
+        assert!(open("hunter2", &sealed[..sealed.len() - 1]).is_err());
+        assert!(open("hunter2", &sealed[..MAGIC.len() + SALT_LEN]).is_err());
+
+        let mut bad_magic = sealed.clone();
+        bad_magic[0] ^= 1;
+        assert!(open("hunter2", &bad_magic).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_load() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        // Saving over an existing file should still leave it private.
+        fs::write(&path, b"").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut key_db = KeyDB::default();
+        key_db.load_key_log(KEY_LOG, &mut |_, _, _| {});
+        let file = KeyFile {
+            path: path.clone(),
+            passphrase: Some("hunter2".to_owned()),
+        };
+        file.save(&key_db).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(file.load().unwrap().to_key_log(), key_db.to_key_log());
+
+        let no_passphrase = KeyFile {
+            path,
+            passphrase: None,
+        };
+        assert!(no_passphrase.load().is_err());
+    }
+}
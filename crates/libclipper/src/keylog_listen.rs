@@ -0,0 +1,229 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Accepts key log lines over a socket, so that any number of processes (or
+//! machines) can stream secrets to a running capture, e.g. with
+//! `tail -f $SSLKEYLOGFILE | nc localhost 6900`.
+
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr};
+#[cfg(unix)]
+use std::{
+    io,
+    os::unix::{fs::FileTypeExt, net::UnixStream as StdUnixStream},
+    path::Path,
+};
+
+use net_decode::key_db::KeyLogReader;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpListener,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{keylog_tail::KeySender, Error};
+
+#[derive(Clone, Debug)]
+pub enum KeyLogAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Accepts `tcp:ADDR:PORT` or `unix:PATH`. Without a prefix, anything that
+/// parses as a socket address is TCP and anything else is a unix socket
+/// path.
+impl FromStr for KeyLogAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            Ok(KeyLogAddr::Tcp(addr.parse()?))
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            Ok(KeyLogAddr::Unix(path.into()))
+        } else if let Ok(addr) = s.parse() {
+            Ok(KeyLogAddr::Tcp(addr))
+        } else if s.is_empty() {
+            Err("empty key log address".into())
+        } else {
+            Ok(KeyLogAddr::Unix(s.into()))
+        }
+    }
+}
+
+impl fmt::Display for KeyLogAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyLogAddr::Tcp(addr) => write!(f, "tcp:{addr}"),
+            KeyLogAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Reads key log lines from one connection until it closes.
+async fn handle_conn(
+    mut conn: impl AsyncRead + Unpin,
+    peer: String,
+    send: KeySender,
+) -> Result<(), Error> {
+    tracing::debug!("key log connection from {peer}");
+    let mut reader = KeyLogReader::default();
+    let mut buf = vec![0u8; 4096];
+    let mut found = Vec::new();
+
+    loop {
+        let n = conn.read(&mut buf).await?;
+        if n == 0 {
+            tracing::debug!("key log connection from {peer} closed");
+            return Ok(());
+        }
+
+        reader.feed(&buf[..n], &mut |cr, ty, secret| {
+            found.push((cr, ty, secret))
+        });
+        for key in found.drain(..) {
+            tracing::trace!("key from {peer}: {key:?}");
+            if send.send(key).await.is_err() {
+                return Ok(());
+            }
+        }
+
+        if reader.partial_len() > KeyLogReader::MAX_LINE_LEN {
+            return Err(format!(
+                "line longer than {} bytes, dropping the connection",
+                KeyLogReader::MAX_LINE_LEN
+            )
+            .into());
+        }
+    }
+}
+
+fn spawn_conn(conn: impl AsyncRead + Unpin + Send + 'static, peer: String, send: &KeySender) {
+    let send = send.clone();
+    tokio::spawn(async move {
+        if let Err(e) = handle_conn(conn, peer.clone(), send).await {
+            tracing::warn!("error reading keys from {peer}: {e}");
+        }
+    });
+}
+
+/// Removes a socket left behind by a previous run at `path`, since binding
+/// fails otherwise. Anything that is not a socket is left alone, so a typo
+/// can't delete someone's file, and so is a socket something is still
+/// listening on, so a second clipper can't take over a running one's.
+#[cfg(unix)]
+pub(crate) fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => match StdUnixStream::connect(path) {
+            Ok(_) => Err(format!("something is already listening on {}", path.display()).into()),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                Ok(std::fs::remove_file(path)?)
+            }
+            Err(e) => {
+                Err(format!("could not check whether {} is in use: {e}", path.display()).into())
+            }
+        },
+        Ok(_) => Err(format!("{} exists and is not a socket", path.display()).into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Listens on `addr` and sends every secret received on any connection to
+/// `send` until `cancel` fires.
+pub async fn listen_key_log(
+    addr: KeyLogAddr,
+    send: KeySender,
+    cancel: CancellationToken,
+) -> Result<(), Error> {
+    match addr {
+        KeyLogAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            tracing::info!("Accepting key log lines on tcp:{}", listener.local_addr()?);
+            loop {
+                tokio::select! {
+                    conn = listener.accept() => {
+                        let (conn, peer) = conn?;
+                        spawn_conn(conn, peer.to_string(), &send);
+                    }
+                    _ = cancel.cancelled() => return Ok(()),
+                }
+            }
+        }
+        #[cfg(windows)]
+        KeyLogAddr::Unix(path) => Err(format!(
+            "cannot listen on unix:{}: unix sockets are not supported on Windows",
+            path.display()
+        )
+        .into()),
+        #[cfg(unix)]
+        KeyLogAddr::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let listener = UnixListener::bind(&path)?;
+            tracing::info!("Accepting key log lines on unix:{}", path.display());
+            let result = loop {
+                tokio::select! {
+                    conn = listener.accept() => {
+                        match conn {
+                            Ok((conn, _)) => spawn_conn(conn, path.display().to_string(), &send),
+                            Err(e) => break Err(e.into()),
+                        }
+                    }
+                    _ = cancel.cancelled() => break Ok(()),
+                }
+            };
+            let _ = std::fs::remove_file(&path);
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    const KEY_LINE: &[u8] = b"CLIENT_RANDOM \
+        0000000000000000000000000000000000000000000000000000000000000000 \
+        000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\n";
+
+    #[tokio::test]
+    async fn test_overlong_line_drops_connection() {
+        let (send, mut recv) = mpsc::channel(10);
+        let mut data = KEY_LINE.to_vec();
+        data.resize(data.len() + 5000, b'A');
+
+        let result = handle_conn(&data[..], "test".to_owned(), send).await;
+
+        assert!(result.is_err());
+        // Keys before the long line still count.
+        assert!(recv.recv().await.is_some());
+        assert!(recv.recv().await.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_socket_removal() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let missing = dir.path().join("missing.sock");
+        remove_stale_socket(&missing).unwrap();
+
+        let sock = dir.path().join("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&sock).unwrap());
+        remove_stale_socket(&sock).unwrap();
+        assert!(!sock.exists());
+
+        let live = dir.path().join("live.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&live).unwrap();
+        assert!(remove_stale_socket(&live).is_err());
+        assert!(live.exists());
+
+        let file = dir.path().join("keys.log");
+        std::fs::write(&file, b"precious").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Follows an SSLKEYLOGFILE as it is written, like `tail -F`, so keys from
+//! programs we did not inject into are usable during live capture.
+
+use std::{io::SeekFrom, path::PathBuf, time::Duration};
+
+use net_decode::key_db::{ClientRandom, KeyLogReader, Secret, SecretType};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::Error;
+
+/// Key log files are typically written a few lines per connection, so
+/// polling is plenty.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub type KeySender = mpsc::Sender<(ClientRandom, SecretType, Secret)>;
+
+/// Reads whatever was appended to `path` since `offset` into `reader`.
+/// Returns the new offset.
+async fn read_new(
+    path: &PathBuf,
+    mut offset: u64,
+    reader: &mut KeyLogReader,
+    found: &mut Vec<(ClientRandom, SecretType, Secret)>,
+) -> Result<u64, Error> {
+    let mut file = match File::open(path).await {
+        Ok(f) => f,
+        // The program may not have created it yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(offset),
+        Err(e) => return Err(e.into()),
+    };
+
+    let len = file.metadata().await?.len();
+    if len < offset {
+        tracing::debug!("key log {path:?} was truncated, starting over");
+        offset = 0;
+        reader.reset();
+    }
+    if len == offset {
+        return Ok(offset);
+    }
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut buf = Vec::new();
+    let read = file.read_to_end(&mut buf).await?;
+    reader.feed(&buf, &mut |cr, ty, secret| found.push((cr, ty, secret)));
+
+    Ok(offset + read as u64)
+}
+
+/// Tails the key log at `path`, sending every secret in it (including those
+/// already there when we start) to `send` until `cancel` fires.
+pub async fn tail_key_log(
+    path: PathBuf,
+    send: KeySender,
+    cancel: CancellationToken,
+) -> Result<(), Error> {
+    let mut reader = KeyLogReader::default();
+    let mut offset = 0;
+    let mut found = Vec::new();
+
+    loop {
+        offset = read_new(&path, offset, &mut reader, &mut found).await?;
+        for key in found.drain(..) {
+            tracing::trace!("key from key log: {key:?}");
+            if send.send(key).await.is_err() {
+                // Nobody is listening any more
+                return Ok(());
+            }
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
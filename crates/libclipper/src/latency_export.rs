@@ -0,0 +1,421 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Time-bucketed request latency per endpoint, for feeding to heat map
+//! tools.
+//!
+//! Latency is measured from the first request headers seen on the wire to
+//! the end of the response, so it includes upload and download time.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use http::header;
+use net_decode::{
+    chomp::{self, IPTarget},
+    http::{HTTPStreamEvent, RequestId},
+    key_db::KeyDB,
+    listener::Nanos,
+};
+
+use crate::{
+    events::{ClipperEvent, EventListener, EventSink},
+    Error,
+};
+
+const NANOS_PER_SEC: Nanos = 1_000_000_000;
+
+/// `(bucket start, endpoint) -> latencies`
+type Matrix = BTreeMap<(Nanos, String), Vec<Nanos>>;
+
+struct LatencyCollector {
+    inflight: HashMap<RequestId, (Nanos, String)>,
+    bucket_width: Nanos,
+    matrix: Arc<Mutex<Matrix>>,
+}
+
+/// Endpoints are identified by method, host and path, without the query
+/// string, since that would make nearly every request its own endpoint.
+fn endpoint(target: &IPTarget, parts: &http::request::Parts) -> String {
+    let host = parts
+        .uri
+        .authority()
+        .map(|a| a.to_string())
+        .or_else(|| {
+            parts
+                .headers
+                .get(header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string())
+        })
+        .unwrap_or_else(|| target.server_addr().to_string());
+    format!("{} {host}{}", parts.method, parts.uri.path())
+}
+
+impl EventSink for LatencyCollector {
+    fn on_event(&mut self, event: ClipperEvent) {
+        let ClipperEvent::Http {
+            timing,
+            target,
+            event,
+        } = event
+        else {
+            return;
+        };
+
+        match event {
+            HTTPStreamEvent::NewRequest(id, parts) => {
+                self.inflight
+                    .insert(id, (timing.received_on_wire, endpoint(&target, &parts)));
+            }
+            HTTPStreamEvent::ResponseFinished(id, _len) => {
+                if let Some((start, endpoint)) = self.inflight.remove(&id) {
+                    let latency = timing.received_on_wire.saturating_sub(start);
+                    let bucket = start - start % self.bucket_width;
+                    self.matrix
+                        .lock()
+                        .unwrap()
+                        .entry((bucket, endpoint))
+                        .or_default()
+                        .push(latency);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Cell {
+    count: usize,
+    min: Nanos,
+    p50: Nanos,
+    p95: Nanos,
+    max: Nanos,
+}
+
+/// Nearest-rank percentile of sorted data.
+fn percentile(sorted: &[Nanos], p: usize) -> Nanos {
+    let rank = (sorted.len() * p + 99) / 100;
+    sorted[rank.saturating_sub(1)]
+}
+
+fn summarize(latencies: &mut [Nanos]) -> Cell {
+    latencies.sort_unstable();
+    Cell {
+        count: latencies.len(),
+        min: latencies[0],
+        p50: percentile(latencies, 50),
+        p95: percentile(latencies, 95),
+        max: latencies[latencies.len() - 1],
+    }
+}
+
+fn nanos_to_ms(n: Nanos) -> f64 {
+    n as f64 / 1_000_000.
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn write_csv(matrix: &mut Matrix, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "bucket_start_s,endpoint,count,min_ms,p50_ms,p95_ms,max_ms"
+    )?;
+    for ((bucket, endpoint), latencies) in matrix.iter_mut() {
+        let cell = summarize(latencies);
+        writeln!(
+            out,
+            "{},{},{},{:.3},{:.3},{:.3},{:.3}",
+            bucket / NANOS_PER_SEC,
+            csv_field(endpoint),
+            cell.count,
+            nanos_to_ms(cell.min),
+            nanos_to_ms(cell.p50),
+            nanos_to_ms(cell.p95),
+            nanos_to_ms(cell.max),
+        )?;
+    }
+    Ok(())
+}
+
+fn write_json(matrix: &mut Matrix, bucket_width: Nanos, out: &mut dyn Write) -> Result<(), Error> {
+    let cells: Vec<_> = matrix
+        .iter_mut()
+        .map(|((bucket, endpoint), latencies)| {
+            let cell = summarize(latencies);
+            serde_json::json!({
+                "bucket_start_s": bucket / NANOS_PER_SEC,
+                "endpoint": endpoint,
+                "count": cell.count,
+                "min_ms": nanos_to_ms(cell.min),
+                "p50_ms": nanos_to_ms(cell.p50),
+                "p95_ms": nanos_to_ms(cell.p95),
+                "max_ms": nanos_to_ms(cell.max),
+            })
+        })
+        .collect();
+    serde_json::to_writer_pretty(
+        &mut *out,
+        &serde_json::json!({
+            "bucket_width_s": bucket_width / NANOS_PER_SEC,
+            "cells": cells,
+        }),
+    )?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Decodes a pcapng file and writes a time x endpoint latency matrix to
+/// `output_file`. The format is JSON if the file name ends in `.json`, and
+/// CSV otherwise.
+pub fn do_export_latency(
+    file: PathBuf,
+    output_file: PathBuf,
+    bucket_secs: u64,
+) -> Result<(), Error> {
+    let bucket_width = bucket_secs
+        .max(1)
+        .checked_mul(NANOS_PER_SEC)
+        .ok_or("bucket width is too large")?;
+    let matrix: Arc<Mutex<Matrix>> = Default::default();
+    let key_db = Arc::new(RwLock::new(KeyDB::default()));
+    let mut chomper = net_decode::chomper(
+        EventListener::new(LatencyCollector {
+            inflight: Default::default(),
+            bucket_width,
+            matrix: matrix.clone(),
+        }),
+        key_db,
+    );
+    chomp::dump_pcap_file(file, &mut chomper)?;
+
+    let mut matrix = matrix.lock().unwrap();
+    let mut out = io::BufWriter::new(fs::File::create(&output_file)?);
+    if output_file.extension().map_or(false, |e| e == "json") {
+        write_json(&mut matrix, bucket_width, &mut out)?;
+    } else {
+        write_csv(&mut matrix, &mut out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use net_decode::listener::TimingInfo;
+
+    use super::*;
+
+    fn target() -> IPTarget {
+        IPTarget::V4 {
+            client_port: 40000,
+            server_port: 443,
+            client_ip: [10, 0, 0, 1].into(),
+            server_ip: [10, 0, 0, 2].into(),
+            overlay: None,
+        }
+    }
+
+    fn http_event(at: Nanos, event: HTTPStreamEvent) -> ClipperEvent {
+        ClipperEvent::Http {
+            timing: TimingInfo {
+                received_on_wire: at,
+                ..Default::default()
+            },
+            target: target(),
+            event,
+        }
+    }
+
+    fn request(id: RequestId, at: Nanos, uri: &str) -> ClipperEvent {
+        let (parts, ()) = http::Request::get(uri).body(()).unwrap().into_parts();
+        http_event(at, HTTPStreamEvent::NewRequest(id, parts))
+    }
+
+    fn matrix(lines: &[(Nanos, &str, &[Nanos])]) -> Matrix {
+        lines
+            .iter()
+            .map(|&(bucket, endpoint, latencies)| {
+                ((bucket, endpoint.to_string()), latencies.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_percentile() {
+        let data: Vec<Nanos> = (1..=100).collect();
+        assert_eq!(percentile(&data, 50), 50);
+        assert_eq!(percentile(&data, 95), 95);
+        assert_eq!(percentile(&data, 100), 100);
+        assert_eq!(percentile(&data, 0), 1);
+
+        // Ranks that land exactly on an element are not rounded up past it.
+        let data = [10, 20, 30, 40];
+        assert_eq!(percentile(&data, 25), 10);
+        assert_eq!(percentile(&data, 26), 20);
+        assert_eq!(percentile(&data, 50), 20);
+        assert_eq!(percentile(&data, 75), 30);
+
+        assert_eq!(percentile(&[7], 50), 7);
+        assert_eq!(percentile(&[7], 95), 7);
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut latencies = vec![5];
+        let cell = summarize(&mut latencies);
+        assert_eq!(
+            (cell.count, cell.min, cell.p50, cell.p95, cell.max),
+            (1, 5, 5, 5, 5)
+        );
+
+        let mut latencies = vec![30, 10, 20];
+        let cell = summarize(&mut latencies);
+        assert_eq!(
+            (cell.count, cell.min, cell.p50, cell.p95, cell.max),
+            (3, 10, 20, 30, 30)
+        );
+    }
+
+    #[test]
+    fn test_bucketing() {
+        let matrix: Arc<Mutex<Matrix>> = Default::default();
+        let mut collector = LatencyCollector {
+            inflight: Default::default(),
+            bucket_width: 10 * NANOS_PER_SEC,
+            matrix: matrix.clone(),
+        };
+
+        collector.on_event(request(1, 3 * NANOS_PER_SEC, "https://a.test/x?q=1"));
+        collector.on_event(request(2, 10 * NANOS_PER_SEC, "https://a.test/x?q=2"));
+        collector.on_event(request(3, 19 * NANOS_PER_SEC, "https://b.test/"));
+        // Never answered, so it is left out.
+        collector.on_event(request(4, 20 * NANOS_PER_SEC, "https://b.test/"));
+        // Bucketed by when the request started, not when it finished.
+        collector.on_event(http_event(
+            25 * NANOS_PER_SEC,
+            HTTPStreamEvent::ResponseFinished(1, 0),
+        ));
+        collector.on_event(http_event(
+            12 * NANOS_PER_SEC,
+            HTTPStreamEvent::ResponseFinished(2, 0),
+        ));
+        collector.on_event(http_event(
+            20 * NANOS_PER_SEC,
+            HTTPStreamEvent::ResponseFinished(3, 0),
+        ));
+        // Not a request that was seen.
+        collector.on_event(http_event(
+            30 * NANOS_PER_SEC,
+            HTTPStreamEvent::ResponseFinished(5, 0),
+        ));
+
+        let matrix = matrix.lock().unwrap();
+        assert_eq!(
+            *matrix,
+            self::matrix(&[
+                (0, "GET a.test/x", &[22 * NANOS_PER_SEC]),
+                (10 * NANOS_PER_SEC, "GET a.test/x", &[2 * NANOS_PER_SEC]),
+                (10 * NANOS_PER_SEC, "GET b.test/", &[NANOS_PER_SEC]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("GET a.test/"), "GET a.test/");
+        assert_eq!(csv_field("GET a.test/a,b"), "\"GET a.test/a,b\"");
+        assert_eq!(csv_field("GET a.test/\"x\""), "\"GET a.test/\"\"x\"\"\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut out = Vec::new();
+        write_csv(&mut Matrix::new(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "bucket_start_s,endpoint,count,min_ms,p50_ms,p95_ms,max_ms\n"
+        );
+
+        let mut out = Vec::new();
+        write_csv(
+            &mut matrix(&[
+                (
+                    60 * NANOS_PER_SEC,
+                    "GET a.test/a,b",
+                    &[2_000_000, 1_500_000],
+                ),
+                (120 * NANOS_PER_SEC, "GET a.test/", &[1_234_567]),
+            ]),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "bucket_start_s,endpoint,count,min_ms,p50_ms,p95_ms,max_ms\n\
+             60,\"GET a.test/a,b\",2,1.500,1.500,2.000,2.000\n\
+             120,GET a.test/,1,1.235,1.235,1.235,1.235\n"
+        );
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut out = Vec::new();
+        write_json(&mut Matrix::new(), 60 * NANOS_PER_SEC, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "bucket_width_s": 60, "cells": [] })
+        );
+
+        let mut out = Vec::new();
+        write_json(
+            &mut matrix(&[(60 * NANOS_PER_SEC, "GET a.test/", &[3_000_000, 1_000_000])]),
+            60 * NANOS_PER_SEC,
+            &mut out,
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "bucket_width_s": 60,
+                "cells": [{
+                    "bucket_start_s": 60,
+                    "endpoint": "GET a.test/",
+                    "count": 2,
+                    "min_ms": 1.0,
+                    "p50_ms": 1.0,
+                    "p95_ms": 3.0,
+                    "max_ms": 3.0,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_bucket_width_overflow() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = do_export_latency(
+            dir.path().join("in.pcapng"),
+            dir.path().join("out.csv"),
+            u64::MAX,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "bucket width is too large");
+    }
+}
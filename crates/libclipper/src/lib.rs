@@ -4,13 +4,53 @@
 
 //! All the interesting integration-level parts of Clipper.
 
-#[cfg(target_os = "linux")]
+pub mod adb;
+pub mod annotate;
+pub mod backpressure;
+pub mod body_store;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    all(windows, feature = "npcap")
+))]
 pub mod capture;
+pub mod cert_export;
+pub mod coverage;
 pub mod devtools;
+pub mod events;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod handoff;
+pub mod k8s;
+pub mod key_embed;
+pub mod key_store;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    all(windows, feature = "npcap")
+))]
+pub mod keylog_listen;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    all(windows, feature = "npcap")
+))]
+pub mod keylog_tail;
+pub mod latency_export;
+pub mod live_control;
+pub mod merge;
+pub mod missing_keys;
+pub mod pause;
+pub mod plaintext_export;
+pub mod process_owner;
+pub mod remote;
+pub mod schedule;
+pub mod slice;
 
 pub const APP_IDENTIFICATION: &'static str = concat!("clipper ", env!("CARGO_PKG_VERSION"));
 
 #[cfg(target_os = "linux")]
 pub const CLIPPER_INJECT_DYLIB_NAME: &'static str = "libclipper_inject.so";
+#[cfg(target_os = "linux")]
+pub const JAVA_AGENT_JAR_NAME: &'static str = "clipper-agent.jar";
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
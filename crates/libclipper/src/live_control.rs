@@ -0,0 +1,81 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! `Clipper.configure`, our own devtools protocol method for changing what is
+//! shown while a capture is running.
+//!
+//! Its params are all optional; those given replace the current setting and
+//! the rest are left alone:
+//!
+//! ```json
+//! {"bodies": false, "hosts": ["*.internal"], "networks": ["10.0.0.0/8"], "ports": [443]}
+//! ```
+//!
+//! It applies to every devtools session, since they all see the same capture.
+
+use net_decode::{
+    body_policy::HostPattern,
+    filter::FlowMatch,
+    live::{LiveConfig, LiveSettings},
+};
+use serde_json::Value;
+
+use crate::Error;
+
+pub const CONFIGURE_METHOD: &str = "Clipper.configure";
+
+fn strings<'a>(params: &'a Value, key: &str) -> Result<Option<Vec<&'a str>>, Error> {
+    let Some(value) = params.get(key) else {
+        return Ok(None);
+    };
+    value
+        .as_array()
+        .and_then(|items| items.iter().map(Value::as_str).collect())
+        .map(Some)
+        .ok_or_else(|| format!("{key} should be a list of strings").into())
+}
+
+/// Works out the settings `params` asks for, starting from `current`.
+fn apply_params(mut current: LiveSettings, params: &Value) -> Result<LiveSettings, Error> {
+    if let Some(bodies) = params.get("bodies") {
+        current.bodies = bodies.as_bool().ok_or("bodies should be a boolean")?;
+    }
+    if let Some(hosts) = strings(params, "hosts")? {
+        current.hosts = hosts.into_iter().map(HostPattern::new).collect();
+    }
+
+    let networks = strings(params, "networks")?;
+    let ports = params.get("ports");
+    if networks.is_some() || ports.is_some() {
+        // FlowMatch can only be added to, so build it again from what we
+        // are given, keeping nothing of the old one.
+        let mut flows = FlowMatch::default();
+        for network in networks.unwrap_or_default() {
+            flows = flows.with_host(network.parse()?);
+        }
+        if let Some(ports) = ports {
+            let ports = ports
+                .as_array()
+                .and_then(|ports| {
+                    ports
+                        .iter()
+                        .map(|p| p.as_u64().and_then(|p| u16::try_from(p).ok()))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or("ports should be a list of port numbers")?;
+            for port in ports {
+                flows = flows.with_port(port);
+            }
+        }
+        current.flows = flows;
+    }
+    Ok(current)
+}
+
+/// Handles a `Clipper.configure` call.
+pub fn configure(live: &LiveConfig, params: &Value) -> Result<(), Error> {
+    let settings = apply_params(live.get(), params)?;
+    live.update(|s| *s = settings);
+    Ok(())
+}
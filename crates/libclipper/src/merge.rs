@@ -0,0 +1,121 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Merging capture files into one, in order of time, e.g. captures taken at
+//! both ends of a connection. See [`net_decode::capture_merge`].
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use humantime::format_duration;
+use net_decode::{
+    capture_file::Record,
+    capture_merge::{read_captures_merged, MergeInput},
+    link::PacketDirection,
+};
+use wire_blahaj::pcap_writer::{CaptureMetadata, InterfaceInfo, PacketOptions, PcapWriter};
+
+use crate::Error;
+
+/// For the files to merge: `FILE`, or `FILE@OFFSET` to add `OFFSET` to the
+/// timestamps in it, where `OFFSET` is a signed duration humantime takes,
+/// e.g. `server.pcapng@-250ms`.
+pub fn parse_merge_input(s: &str) -> Result<MergeInput, Error> {
+    let Some((path, offset)) = s
+        .rsplit_once('@')
+        .filter(|(_, offset)| offset.starts_with(['+', '-']))
+    else {
+        return Ok(MergeInput::new(s.into()));
+    };
+    let nanos = i64::try_from(humantime::parse_duration(&offset[1..])?.as_nanos())
+        .map_err(|_| format!("clock offset {offset} is too big"))?;
+    let clock_offset = if offset.starts_with('-') {
+        -nanos
+    } else {
+        nanos
+    };
+    Ok(MergeInput {
+        path: path.into(),
+        clock_offset,
+    })
+}
+
+/// Writes the packets and TLS keys in the capture files `inputs` to
+/// `output_file` as one pcapng, in order of time.
+pub fn do_merge(inputs: Vec<MergeInput>, output_file: PathBuf) -> Result<(), Error> {
+    let mut out = io::BufWriter::new(fs::File::create(&output_file)?);
+    let comments = inputs
+        .iter()
+        .map(|input| {
+            let path = input.path.display();
+            let offset = Duration::from_nanos(input.clock_offset.unsigned_abs());
+            match input.clock_offset {
+                0 => format!("merged from {path}"),
+                o if o < 0 => format!("merged from {path}@-{}", format_duration(offset)),
+                _ => format!("merged from {path}@+{}", format_duration(offset)),
+            }
+        })
+        .collect();
+    let mut pcap = PcapWriter::new(
+        crate::APP_IDENTIFICATION,
+        &CaptureMetadata {
+            comments,
+            ..Default::default()
+        },
+        &mut out,
+    )?;
+
+    // Interfaces described to `pcap` so far.
+    let mut described = 0;
+    let mut packets = 0u64;
+    let mut warned_wireguard = false;
+    read_captures_merged(inputs, &mut |info, record| {
+        match record {
+            Record::Frame(frame) => {
+                for (index, interface) in info.interfaces.iter().enumerate().skip(described) {
+                    pcap.set_interface_info(
+                        index as u32,
+                        InterfaceInfo {
+                            name: interface.name.clone(),
+                            comment: Some(interface.comments.join("; ")),
+                            link_type: Some(interface.link_type),
+                            snap_len: interface.snap_len,
+                        },
+                    );
+                }
+                described = info.interfaces.len();
+
+                let comments: Vec<String> = frame.comments.iter().map(|&c| c.to_owned()).collect();
+                pcap.on_packet_with_options(
+                    &mut out,
+                    frame.timing.received_on_wire,
+                    frame.interface as u32,
+                    frame.data,
+                    frame.original_len as usize,
+                    PacketOptions {
+                        comments: &comments,
+                        flags: frame.timing.direction.map(PacketDirection::epb_flags),
+                    },
+                )?;
+                packets += 1;
+            }
+            Record::TlsKeys(keys) => pcap.on_dsb(&mut out, keys)?,
+            Record::WireguardKeys(_) => {
+                if !warned_wireguard {
+                    tracing::warn!("WireGuard keys are not carried over into merged files");
+                    warned_wireguard = true;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    out.flush()?;
+
+    tracing::info!("wrote {packets} packets to {output_file:?}");
+    Ok(())
+}
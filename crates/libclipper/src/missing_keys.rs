@@ -0,0 +1,294 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Which TLS connections we could not decrypt, and why, so that it is
+//! possible to tell why something is missing from the network panel.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use net_decode::{
+    chomp::{self, IPTarget},
+    key_db::{ClientRandom, KeyDB},
+    tls::side_data::{ALPNCompleted, DecodeFailureKind},
+};
+
+use crate::{
+    events::{ClipperEvent, EventListener, EventSink, FlowEvent, TlsEvent},
+    Error,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UndecryptedReason {
+    /// The keys for the connection never showed up.
+    NoKeys,
+    /// The keys showed up after we gave up holding the data for them.
+    KeysTooLate,
+    /// We have the keys, but do not implement the cipher suite.
+    UnsupportedCipherSuite,
+    /// The connection ended, or the capture started or ended, partway
+    /// through the handshake.
+    TruncatedHandshake,
+    /// Something went wrong decoding it; see the detail.
+    DecodeError,
+}
+
+impl fmt::Display for UndecryptedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UndecryptedReason::NoKeys => "no keys",
+            UndecryptedReason::KeysTooLate => "keys too late",
+            UndecryptedReason::UnsupportedCipherSuite => "unsupported cipher suite",
+            UndecryptedReason::TruncatedHandshake => "truncated handshake",
+            UndecryptedReason::DecodeError => "decode error",
+        })
+    }
+}
+
+/// A TLS connection we could not decrypt.
+#[derive(Clone, Debug)]
+pub struct UndecryptedFlow {
+    pub target: IPTarget,
+    pub server_name: Option<String>,
+    pub client_random: Option<ClientRandom>,
+    pub reason: UndecryptedReason,
+    /// Decoder error message, for [`UndecryptedReason::DecodeError`].
+    pub detail: Option<String>,
+}
+
+impl UndecryptedFlow {
+    /// SNI if there was one, otherwise the server address.
+    pub fn host(&self) -> String {
+        self.server_name
+            .clone()
+            .unwrap_or_else(|| self.target.server_addr().to_string())
+    }
+}
+
+impl fmt::Display for UndecryptedFlow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.host(), self.reason)?;
+        if let Some(cr) = &self.client_random {
+            write!(f, " (client random {cr})")?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, ": {detail}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct FlowState {
+    server_name: Option<String>,
+    client_random: Option<ClientRandom>,
+    server_hello: bool,
+    opaque: bool,
+    decrypted: bool,
+    failure: Option<(UndecryptedReason, Option<String>)>,
+}
+
+impl FlowState {
+    fn into_undecrypted(self, target: IPTarget) -> Option<UndecryptedFlow> {
+        let (reason, detail) = if self.decrypted {
+            return None;
+        } else if let Some(failure) = self.failure {
+            failure
+        } else if self.opaque {
+            (UndecryptedReason::NoKeys, None)
+        } else if !self.server_hello {
+            (UndecryptedReason::TruncatedHandshake, None)
+        } else {
+            return None;
+        };
+        Some(UndecryptedFlow {
+            target,
+            server_name: self.server_name,
+            client_random: self.client_random,
+            reason,
+            detail,
+        })
+    }
+}
+
+/// Follows the TLS events for each connection to work out which ones were
+/// never decrypted.
+///
+/// Keys can arrive long after the data they are for, so a connection is
+/// only judged once it is closed, or at the end with [`Self::finish`].
+#[derive(Debug, Default)]
+pub struct UndecryptedFlowTracker {
+    flows: HashMap<IPTarget, FlowState>,
+}
+
+impl UndecryptedFlowTracker {
+    /// Returns the connection if `event` closed one we could not decrypt.
+    pub fn on_event(&mut self, event: &ClipperEvent) -> Option<UndecryptedFlow> {
+        match event {
+            ClipperEvent::Tls(TlsEvent::ClientHello(hello)) => {
+                // Also replaces any earlier connection on the same ports.
+                self.flows.insert(
+                    hello.target,
+                    FlowState {
+                        server_name: hello.server_name.clone(),
+                        client_random: Some(hello.client_random.clone()),
+                        ..Default::default()
+                    },
+                );
+            }
+            ClipperEvent::Tls(TlsEvent::ServerHello(hello)) => {
+                self.update(hello.target, |f| f.server_hello = true)
+            }
+            ClipperEvent::Tls(TlsEvent::Opaque(opaque)) => {
+                self.update(opaque.target, |f| f.opaque = true)
+            }
+            ClipperEvent::Tls(TlsEvent::PendingKeysDropped(dropped)) => self
+                .update(dropped.target, |f| {
+                    f.failure = Some((UndecryptedReason::KeysTooLate, None))
+                }),
+            ClipperEvent::Tls(TlsEvent::DecodeFailed(failed)) => {
+                let reason = match failed.kind {
+                    DecodeFailureKind::UnsupportedCipherSuite => {
+                        UndecryptedReason::UnsupportedCipherSuite
+                    }
+                    DecodeFailureKind::Other => UndecryptedReason::DecodeError,
+                };
+                self.update(failed.target, |f| {
+                    f.failure = Some((reason, Some(failed.error.clone())))
+                })
+            }
+            // Only ever seen if we have the keys. Not the server
+            // certificate: TLS 1.2 sends that in the clear.
+            ClipperEvent::Tls(TlsEvent::ALPNCompleted(ALPNCompleted { target, .. }))
+            | ClipperEvent::Http { target, .. } => self.update(*target, |f| f.decrypted = true),
+            ClipperEvent::Flow(FlowEvent::Closed(closed)) => {
+                let flow = self.flows.remove(&closed.target)?;
+                return flow.into_undecrypted(closed.target);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn update(&mut self, target: IPTarget, f: impl FnOnce(&mut FlowState)) {
+        if let Some(flow) = self.flows.get_mut(&target) {
+            f(flow);
+        }
+    }
+
+    /// Judges the connections that were still open.
+    pub fn finish(self) -> Vec<UndecryptedFlow> {
+        self.flows
+            .into_iter()
+            .filter_map(|(target, flow)| flow.into_undecrypted(target))
+            .collect()
+    }
+}
+
+struct Collector {
+    tracker: Arc<Mutex<UndecryptedFlowTracker>>,
+    found: Arc<Mutex<Vec<UndecryptedFlow>>>,
+}
+
+impl EventSink for Collector {
+    fn on_event(&mut self, event: ClipperEvent) {
+        if let Some(flow) = self.tracker.lock().unwrap().on_event(&event) {
+            self.found.lock().unwrap().push(flow);
+        }
+    }
+}
+
+/// Lists the TLS connections in a pcapng file that could not be decrypted.
+/// `key_db` has any keys not embedded in the file.
+pub fn undecrypted_flows(file: PathBuf, key_db: KeyDB) -> Result<Vec<UndecryptedFlow>, Error> {
+    let tracker: Arc<Mutex<UndecryptedFlowTracker>> = Default::default();
+    let found = Arc::new(Mutex::new(Vec::new()));
+    let key_db = Arc::new(RwLock::new(key_db));
+    let mut chomper = net_decode::chomper(
+        EventListener::new(Collector {
+            tracker: tracker.clone(),
+            found: found.clone(),
+        }),
+        key_db,
+    );
+    chomp::dump_pcap_file(file, &mut chomper)?;
+
+    let tracker = std::mem::take(&mut *tracker.lock().unwrap());
+    let mut found = std::mem::take(&mut *found.lock().unwrap());
+    found.extend(tracker.finish());
+    found.sort_by_key(|f| (f.host(), f.target.server_addr(), f.target.client_port()));
+    Ok(found)
+}
+
+fn to_json(flows: &[UndecryptedFlow]) -> serde_json::Value {
+    flows
+        .iter()
+        .map(|f| {
+            serde_json::json!({
+                "server": f.target.server_addr().to_string(),
+                "client_port": f.target.client_port(),
+                "server_name": f.server_name,
+                "client_random": f.client_random.as_ref().map(|cr| cr.to_string()),
+                "reason": f.reason.to_string(),
+                "detail": f.detail,
+            })
+        })
+        .collect()
+}
+
+/// Prints the TLS connections in a pcapng file that could not be decrypted,
+/// one per line or as JSON.
+pub fn do_missing_keys_report(file: PathBuf, key_db: KeyDB, json: bool) -> Result<(), Error> {
+    let flows = undecrypted_flows(file, key_db)?;
+    let mut out = io::stdout().lock();
+    if json {
+        serde_json::to_writer_pretty(&mut out, &to_json(&flows))?;
+        writeln!(out)?;
+    } else {
+        for flow in &flows {
+            writeln!(out, "{flow}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    fn undecrypted_in(name: &str) -> Vec<UndecryptedFlow> {
+        let file = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../net_decode/corpus")
+            .join(name);
+        undecrypted_flows(file, KeyDB::default()).unwrap()
+    }
+
+    #[test]
+    fn test_no_keys() {
+        let flows = undecrypted_in("tls12-rsa.pcapng");
+        let [flow] = &flows[..] else {
+            panic!("expected one undecrypted flow: {flows:?}");
+        };
+        assert_eq!(flow.reason, UndecryptedReason::NoKeys);
+        assert_eq!(flow.target.server_addr().port(), 443);
+        assert!(flow.client_random.is_some());
+        assert_eq!(flow.detail, None);
+
+        let json = to_json(&flows);
+        assert_eq!(json[0]["reason"], "no keys");
+        assert_eq!(json[0]["server"], "10.0.0.2:443");
+    }
+
+    #[test]
+    fn test_keys_embedded() {
+        assert!(undecrypted_in("tls12-mtls.pcapng").is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Pausing a live capture without stopping it, to leave out a noisy part of
+//! a test run.
+//!
+//! Packets keep being read while paused, so that the kernel does not have
+//! to drop them, but are thrown away rather than decoded or written. The
+//! connections being followed are kept, and carry on being decoded after
+//! resuming, less whatever was sent in between.
+//!
+//! Pausing is done through a [`CapturePause`], with the devtools methods
+//! `Clipper.pause` and `Clipper.resume`, or with `SIGUSR1` and `SIGUSR2`.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+pub const PAUSE_METHOD: &str = "Clipper.pause";
+pub const RESUME_METHOD: &str = "Clipper.resume";
+
+/// Shared handle to whether a capture is paused.
+#[derive(Clone, Debug, Default)]
+pub struct CapturePause(Arc<AtomicBool>);
+
+impl CapturePause {
+    pub fn pause(&self) {
+        if !self.0.swap(true, Ordering::Relaxed) {
+            tracing::info!("Capture paused");
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.0.swap(false, Ordering::Relaxed) {
+            tracing::info!("Capture resumed");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
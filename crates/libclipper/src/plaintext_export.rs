@@ -0,0 +1,347 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Exports the decrypted contents of the TLS connections in a capture as a
+//! pcapng of plain TCP connections, for tools that cannot decrypt TLS with a
+//! key log.
+//!
+//! Each decrypted connection is written as a TCP connection between the
+//! same addresses and client port, but to [`PLAINTEXT_SERVER_PORT`] so that
+//! it is taken for plaintext. It gets a handshake in front of its first
+//! data, with the server name and port it was really for as a comment on
+//! the SYN, and sequence numbers counting the decrypted bytes. Connections
+//! that could not be decrypted, and everything that is not TLS, are left
+//! out.
+//!
+//! HTTP/2 connections lose the ALPN that said they were HTTP/2, so tools may
+//! need telling, e.g. with Wireshark's "Decode As".
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use bytes::Bytes;
+use net_decode::{
+    capture_slice::{self, CaptureSlice},
+    checkpoint::{Gated, ReplayGate},
+    checksum::{ipv4_header_checksum, transport_checksum},
+    chomp::IPTarget,
+    key_db::KeyDB,
+    listener::{Listener, Nanos, NoOpListener, SideData, SideDataHandler, TimingInfo},
+    pipeline::Pipeline,
+    tcp_reassemble::side_data::ConnectionClosed,
+    tls::side_data::ClientHelloSeen,
+};
+use wire_blahaj::pcap_writer::{CaptureMetadata, PcapWriter};
+
+use crate::Error;
+
+/// Server port of the connections written out.
+pub const PLAINTEXT_SERVER_PORT: u16 = 80;
+
+/// Most data to put in one packet, as on Ethernet.
+const MAX_SEGMENT: usize = 1460;
+
+const IPPROTO_TCP: u8 = 6;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+// Locally administered, since the real ones are not known past the TCP
+// layer.
+const CLIENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+/// Next sequence number each way of a connection being written.
+#[derive(Debug)]
+struct Flow {
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl Flow {
+    /// Sequence number of what `to_client` sends next, and what it
+    /// acknowledges.
+    fn seq_ack(&self, to_client: bool) -> (u32, u32) {
+        if to_client {
+            (self.server_seq, self.client_seq)
+        } else {
+            (self.client_seq, self.server_seq)
+        }
+    }
+
+    fn advance(&mut self, to_client: bool, len: u32) {
+        let seq = if to_client {
+            &mut self.server_seq
+        } else {
+            &mut self.client_seq
+        };
+        *seq = seq.wrapping_add(len);
+    }
+}
+
+struct PlaintextPcap<W: Write> {
+    out: W,
+    pcap: PcapWriter,
+    flows: HashMap<IPTarget, Flow>,
+    /// SNI of connections that have not sent any decrypted data yet.
+    server_names: HashMap<IPTarget, String>,
+    connections: usize,
+    /// First error writing, after which nothing more is written.
+    error: Option<io::Error>,
+}
+
+/// Builds an Ethernet frame holding a TCP segment of `target`, going the
+/// way `to_client` says.
+fn make_frame(
+    target: IPTarget,
+    to_client: bool,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let (client, server) = (target.client_addr().ip(), target.server_addr().ip());
+    let (client_port, server_port) = (target.client_addr().port(), PLAINTEXT_SERVER_PORT);
+    let ((src, src_port, src_mac), (dst, dst_port, dst_mac)) = if to_client {
+        (
+            (server, server_port, SERVER_MAC),
+            (client, client_port, CLIENT_MAC),
+        )
+    } else {
+        (
+            (client, client_port, CLIENT_MAC),
+            (server, server_port, SERVER_MAC),
+        )
+    };
+
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend(src_port.to_be_bytes());
+    tcp.extend(dst_port.to_be_bytes());
+    tcp.extend(seq.to_be_bytes());
+    tcp.extend(ack.to_be_bytes());
+    // 5 words of header, no options
+    tcp.extend([5 << 4, flags]);
+    tcp.extend(u16::MAX.to_be_bytes());
+    // checksum, urgent pointer
+    tcp.extend([0; 4]);
+    tcp.extend(payload);
+    let checksum =
+        transport_checksum(IPPROTO_TCP, src, dst, &tcp).expect("both ends are the same family");
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut frame = Vec::with_capacity(14 + 40 + tcp.len());
+    frame.extend(dst_mac);
+    frame.extend(src_mac);
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            frame.extend(0x0800u16.to_be_bytes());
+            let mut ip = Vec::with_capacity(20);
+            ip.extend([0x45, 0]);
+            ip.extend(((20 + tcp.len()) as u16).to_be_bytes());
+            // identification, then don't fragment
+            ip.extend([0, 0, 0x40, 0]);
+            ip.extend([64, IPPROTO_TCP, 0, 0]);
+            ip.extend(src.octets());
+            ip.extend(dst.octets());
+            let checksum = ipv4_header_checksum(&ip);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            frame.extend(ip);
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            frame.extend(0x86ddu16.to_be_bytes());
+            frame.extend([0x60, 0, 0, 0]);
+            frame.extend((tcp.len() as u16).to_be_bytes());
+            frame.extend([IPPROTO_TCP, 64]);
+            frame.extend(src.octets());
+            frame.extend(dst.octets());
+        }
+        _ => unreachable!("both ends are the same family"),
+    }
+    frame.extend(tcp);
+    frame
+}
+
+impl<W: Write> PlaintextPcap<W> {
+    fn new(mut out: W) -> Result<Self, io::Error> {
+        let pcap = PcapWriter::new(
+            crate::APP_IDENTIFICATION,
+            &CaptureMetadata {
+                comments: vec!["TLS connections decrypted by clipper".to_string()],
+                ..Default::default()
+            },
+            &mut out,
+        )?;
+        Ok(Self {
+            out,
+            pcap,
+            flows: Default::default(),
+            server_names: Default::default(),
+            connections: 0,
+            error: None,
+        })
+    }
+
+    fn write_segment(
+        &mut self,
+        time: Nanos,
+        target: IPTarget,
+        to_client: bool,
+        flags: u8,
+        payload: &[u8],
+        comments: &[String],
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        let flow = self.flows.get_mut(&target).expect("flow was started");
+        let (seq, ack) = flow.seq_ack(to_client);
+        // SYN and FIN take up a sequence number each
+        let len = payload.len() as u32 + (flags & (TCP_SYN | TCP_FIN) != 0) as u32;
+        flow.advance(to_client, len);
+        // The peer's SYN has not been seen yet, so there is nothing to
+        // acknowledge.
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+
+        let frame = make_frame(target, to_client, seq, ack, flags, payload);
+        if let Err(e) =
+            self.pcap
+                .on_packet_with_comments(&mut self.out, time, 0, &frame, frame.len(), comments)
+        {
+            self.error = Some(e);
+        }
+    }
+
+    /// Writes a handshake for a connection whose first decrypted data has
+    /// just been seen.
+    fn start_flow(&mut self, time: Nanos, target: IPTarget) {
+        self.connections += 1;
+        self.flows.insert(
+            target,
+            Flow {
+                client_seq: 0,
+                server_seq: 0,
+            },
+        );
+        let comment = match self.server_names.remove(&target) {
+            Some(name) => format!("TLS to {name} on port {}", target.server_port()),
+            None => format!("TLS on port {}", target.server_port()),
+        };
+        self.write_segment(time, target, false, TCP_SYN, &[], &[comment]);
+        self.write_segment(time, target, true, TCP_SYN | TCP_ACK, &[], &[]);
+        self.write_segment(time, target, false, TCP_ACK, &[], &[]);
+    }
+
+    fn on_data(&mut self, time: Nanos, target: IPTarget, to_client: bool, data: &[u8]) {
+        if !self.flows.contains_key(&target) {
+            self.start_flow(time, target);
+        }
+        let mut chunks = data.chunks(MAX_SEGMENT).peekable();
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_none() {
+                TCP_PSH | TCP_ACK
+            } else {
+                TCP_ACK
+            };
+            self.write_segment(time, target, to_client, flags, chunk, &[]);
+        }
+    }
+
+    fn on_closed(&mut self, time: Nanos, target: IPTarget) {
+        self.server_names.remove(&target);
+        if !self.flows.contains_key(&target) {
+            return;
+        }
+        self.write_segment(time, target, false, TCP_FIN | TCP_ACK, &[], &[]);
+        self.write_segment(time, target, true, TCP_FIN | TCP_ACK, &[], &[]);
+        self.write_segment(time, target, false, TCP_ACK, &[], &[]);
+        self.flows.remove(&target);
+    }
+
+    /// Flushes the output, returning the number of connections written.
+    fn finish(&mut self) -> Result<usize, io::Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.connections)
+    }
+}
+
+/// Receives decrypted TLS from the decoding stack.
+struct PlaintextListener<W: Write> {
+    pcap: Arc<Mutex<PlaintextPcap<W>>>,
+}
+
+impl<W: Write + Send> Listener<Bytes> for PlaintextListener<W> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: Bytes) {
+        self.pcap
+            .lock()
+            .unwrap()
+            .on_data(timing.received_on_wire, target, to_client, &data);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        net_decode::dispatch_side_data!(self, &data, [ClientHelloSeen, ConnectionClosed]);
+    }
+}
+
+impl<W: Write + Send> SideDataHandler<ClientHelloSeen> for PlaintextListener<W> {
+    fn handle_side_data(&mut self, hello: &ClientHelloSeen) {
+        if let Some(name) = &hello.server_name {
+            self.pcap
+                .lock()
+                .unwrap()
+                .server_names
+                .insert(hello.target, name.clone());
+        }
+    }
+}
+
+impl<W: Write + Send> SideDataHandler<ConnectionClosed> for PlaintextListener<W> {
+    fn handle_side_data(&mut self, closed: &ConnectionClosed) {
+        self.pcap
+            .lock()
+            .unwrap()
+            .on_closed(closed.timing.received_on_wire, closed.target);
+    }
+}
+
+/// Decodes `slice` of a pcapng file and writes the decrypted TLS
+/// connections in it to `output_file` as plain TCP. `key_db` has any keys
+/// not embedded in the file.
+pub fn do_export_plaintext(
+    file: PathBuf,
+    slice: CaptureSlice,
+    output_file: PathBuf,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let out = io::BufWriter::new(fs::File::create(&output_file)?);
+    let pcap = Arc::new(Mutex::new(PlaintextPcap::new(out)?));
+    let gate = ReplayGate::default();
+    let mut chomper = Pipeline::new()
+        .tls(Arc::new(RwLock::new(key_db)))
+        .decrypted(Gated::new(
+            gate.clone(),
+            PlaintextListener { pcap: pcap.clone() },
+        ))
+        .build(NoOpListener::default());
+    capture_slice::dump_pcap_file(file, slice, None, gate, &mut chomper)?;
+    drop(chomper);
+
+    let connections = pcap.lock().unwrap().finish()?;
+    if connections == 0 {
+        tracing::warn!("no TLS connections could be decrypted; are there keys for them?");
+    } else {
+        tracing::info!("wrote {connections} decrypted connections to {output_file:?}");
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Which process each connection belongs to, for live captures run with
+//! `--attribute-processes`.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use net_decode::chomp::IPTarget;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    /// Name of the program, as the kernel has it, cut off at 15 bytes.
+    pub comm: String,
+    pub cgroup_id: u64,
+    /// Path of the process' cgroup, if it was still running to look it up.
+    pub cgroup: Option<String>,
+}
+
+impl ProcessInfo {
+    /// Fills in the rest of what is known about `pid` from `/proc`.
+    pub fn lookup(pid: u32, comm: String, cgroup_id: u64) -> Self {
+        // cgroup v2 has a single line, `0::/path`.
+        let cgroup = fs::read_to_string(format!("/proc/{pid}/cgroup"))
+            .ok()
+            .and_then(|c| {
+                c.lines()
+                    .find_map(|l| l.strip_prefix("0::").map(str::to_owned))
+            });
+        ProcessInfo {
+            pid,
+            comm,
+            cgroup_id,
+            cgroup,
+        }
+    }
+}
+
+/// `curl[1234]`, and its cgroup if known.
+impl fmt::Display for ProcessInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]", self.comm, self.pid)?;
+        if let Some(cgroup) = &self.cgroup {
+            write!(f, " in {cgroup}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A process opening a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionOwner {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub process: ProcessInfo,
+}
+
+/// Shared table of the processes of connections still open.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessOwners {
+    owners: Arc<RwLock<HashMap<(SocketAddr, SocketAddr), ProcessInfo>>>,
+}
+
+impl ProcessOwners {
+    pub fn insert(&self, owner: ConnectionOwner) {
+        let ConnectionOwner {
+            client,
+            server,
+            process,
+        } = owner;
+        tracing::debug!(%client, %server, "connection opened by {process}");
+        self.owners
+            .write()
+            .unwrap()
+            .insert((client, server), process);
+    }
+
+    pub fn get(&self, target: &IPTarget) -> Option<ProcessInfo> {
+        let key = (target.client_addr(), target.server_addr());
+        self.owners.read().unwrap().get(&key).cloned()
+    }
+
+    /// Forgets a connection once it is over, since its ports can be reused
+    /// by another process.
+    pub fn remove(&self, target: &IPTarget) {
+        let key = (target.client_addr(), target.server_addr());
+        self.owners.write().unwrap().remove(&key);
+    }
+}
@@ -0,0 +1,124 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture on another machine, by running tcpdump there over SSH and
+//! decoding the pcap it writes to its stdout as it arrives.
+//!
+//! This needs tcpdump on the remote host, and either root there or `sudo`
+//! that does not ask for a password. Keys have to come from files given
+//! up front, since nothing is injected into programs on the remote host.
+
+use std::{
+    io,
+    process::{Child, Command, Stdio},
+};
+
+use net_decode::{
+    body_policy::BodyPolicies, capture_filter::CaptureFilter, key_db::KeyDB, DecodeOptions,
+};
+
+use crate::{devtools::do_devtools_stream_inner, Error};
+
+/// Where and how to capture on a remote host.
+#[derive(Clone, Debug, Default)]
+pub struct RemoteCapture {
+    /// Host to capture on, as given to ssh, e.g. `user@server`.
+    pub host: String,
+    /// Interface to capture on. tcpdump picks one if this is `None`.
+    pub interface: Option<String>,
+    /// Applied by tcpdump on the remote host, so that packets not matching
+    /// it are not sent over the network.
+    pub filter: Option<CaptureFilter>,
+    /// Run tcpdump with `sudo -n`.
+    pub sudo: bool,
+    /// Extra arguments for ssh, e.g. `-p 2222`.
+    pub ssh_args: Vec<String>,
+}
+
+/// Quotes `s` for the POSIX shell that sshd runs the command with.
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl RemoteCapture {
+    /// Command line for the remote shell.
+    fn remote_command(&self) -> String {
+        // The session's own packets are left out, since each would be sent
+        // back over the session and captured again. sshd puts the client
+        // address and port, then the server address and port, in
+        // $SSH_CONNECTION.
+        let mut command = String::from("set -- $SSH_CONNECTION; exec ");
+        if self.sudo {
+            command += "sudo -n ";
+        }
+        // -U: write each packet as it is captured, rather than when a
+        // buffer fills up.
+        command += "tcpdump -U -w -";
+        if let Some(interface) = &self.interface {
+            command += " -i ";
+            command += &shell_quote(interface);
+        }
+        command += " \"not (host $1 and port $2 and host $3 and port $4)\"";
+        if let Some(filter) = &self.filter {
+            command += &shell_quote(&format!(" and ({filter})"));
+        }
+        command
+    }
+
+    /// Starts ssh running tcpdump on the remote host, with the capture on
+    /// its stdout. Whatever ssh and tcpdump print otherwise goes to our
+    /// stderr.
+    pub fn spawn(&self) -> Result<Child, Error> {
+        let remote_command = self.remote_command();
+        tracing::debug!("running on {}: {remote_command}", self.host);
+        Command::new("ssh")
+            .args(&self.ssh_args)
+            // No terminal, which would mangle the capture.
+            .arg("-T")
+            .arg("--")
+            .arg(&self.host)
+            .arg(remote_command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run ssh: {e}").into())
+    }
+}
+
+/// Serves a devtools server on what tcpdump captures on a remote host, as
+/// it is captured.
+pub async fn do_remote_capture_to_devtools(
+    remote: RemoteCapture,
+    body_policies: BodyPolicies,
+    decode_options: DecodeOptions,
+    key_db: KeyDB,
+) -> Result<(), Error> {
+    let mut child = remote.spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    tracing::info!("Capturing on {} over SSH", remote.host);
+
+    let result = do_devtools_stream_inner(
+        io::BufReader::new(stdout),
+        None,
+        None,
+        body_policies,
+        decode_options,
+        key_db,
+    )
+    .await;
+
+    // If ssh or tcpdump failed, that says more than what came of its empty
+    // output.
+    if result.is_err() {
+        if let Ok(Some(status)) = child.try_wait() {
+            if !status.success() {
+                return Err(format!("capture on {} failed: ssh {status}", remote.host).into());
+            }
+        }
+    }
+    // Closing the session stops tcpdump too.
+    let _ = child.kill();
+    let _ = child.wait();
+    result
+}
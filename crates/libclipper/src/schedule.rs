@@ -0,0 +1,285 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! When a capture should be running: stopping after a while or at a given
+//! time, and only recording inside daily windows such as `09:00-10:00`.
+//!
+//! All times are UTC, since we do not know about time zones.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::Error;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+fn secs_since_epoch(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Parses `HH:MM` or `HH:MM:SS` into seconds since midnight.
+fn parse_time_of_day(s: &str) -> Result<u64, Error> {
+    let bad = || format!("{s:?} is not a time of day like 09:30");
+    let mut parts = s.split(':').map(|p| p.parse::<u64>().map_err(|_| bad()));
+    let (h, m) = match (parts.next(), parts.next()) {
+        (Some(h), Some(m)) => (h?, m?),
+        _ => return Err(bad().into()),
+    };
+    let sec = parts.next().transpose()?.unwrap_or(0);
+    if parts.next().is_some() || h > 23 || m > 59 || sec > 59 {
+        return Err(bad().into());
+    }
+    Ok(h * 3600 + m * 60 + sec)
+}
+
+/// For `--duration`: anything humantime takes, e.g. `90s` or `1h 30m`.
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    Ok(humantime::parse_duration(s)?)
+}
+
+/// For `--until`: either an RFC 3339-ish timestamp (`2023-08-01 10:00:00`),
+/// or a time of day, meaning the next time it comes around.
+pub fn parse_until(s: &str) -> Result<SystemTime, Error> {
+    parse_until_at(s, SystemTime::now())
+}
+
+fn parse_until_at(s: &str, now: SystemTime) -> Result<SystemTime, Error> {
+    if let Ok(t) = humantime::parse_rfc3339_weak(s) {
+        return Ok(t);
+    }
+    let time_of_day = parse_time_of_day(s)?;
+    let now = secs_since_epoch(now);
+    let mut until = now - now % SECS_PER_DAY + time_of_day;
+    if until <= now {
+        until += SECS_PER_DAY;
+    }
+    Ok(UNIX_EPOCH + Duration::from_secs(until))
+}
+
+/// Time range that repeats every day, possibly across midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DailyWindow {
+    /// Seconds since midnight.
+    start: u64,
+    end: u64,
+}
+
+impl DailyWindow {
+    pub fn contains(&self, t: SystemTime) -> bool {
+        let s = secs_since_epoch(t) % SECS_PER_DAY;
+        if self.start < self.end {
+            self.start <= s && s < self.end
+        } else {
+            s >= self.start || s < self.end
+        }
+    }
+
+    /// Next time after `t` that the window opens or closes.
+    pub fn next_change(&self, t: SystemTime) -> SystemTime {
+        let now = secs_since_epoch(t);
+        let s = now % SECS_PER_DAY;
+        let until = |b: u64| match (b + SECS_PER_DAY - s) % SECS_PER_DAY {
+            0 => SECS_PER_DAY,
+            d => d,
+        };
+        UNIX_EPOCH + Duration::from_secs(now + until(self.start).min(until(self.end)))
+    }
+}
+
+/// Parses `HH:MM-HH:MM`.
+impl FromStr for DailyWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("capture window {s:?} should look like 09:00-10:00"))?;
+        let window = DailyWindow {
+            start: parse_time_of_day(start)?,
+            end: parse_time_of_day(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("capture window {s:?} is empty").into());
+        }
+        Ok(window)
+    }
+}
+
+impl fmt::Display for DailyWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hm = |s: u64| (s / 3600, s / 60 % 60);
+        let ((sh, sm), (eh, em)) = (hm(self.start), hm(self.end));
+        write!(f, "{sh:02}:{sm:02}-{eh:02}:{em:02}")
+    }
+}
+
+/// When to record. The default is to record everything until the program
+/// exits.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureSchedule {
+    /// Stop the capture (and the program) this long after it starts.
+    duration: Option<Duration>,
+    /// Stop the capture (and the program) at this time.
+    until: Option<SystemTime>,
+    /// Only record inside these windows. Each window is its own session,
+    /// which for pcap captures means its own file.
+    pub windows: Vec<DailyWindow>,
+    /// Whichever of `duration` and `until` comes first, once started.
+    stop_at: Option<SystemTime>,
+}
+
+impl CaptureSchedule {
+    /// Stops at whichever of `duration` from the start or `until` comes
+    /// first.
+    pub fn new(
+        duration: Option<Duration>,
+        until: Option<SystemTime>,
+        windows: Vec<DailyWindow>,
+    ) -> Self {
+        CaptureSchedule {
+            duration,
+            until,
+            windows,
+            stop_at: until,
+        }
+    }
+
+    /// Starts counting down `duration`. Called when the capture starts
+    /// rather than when the arguments are parsed, since the program can
+    /// take a while to get going.
+    pub fn start(&mut self, now: SystemTime) {
+        let after_duration = self.duration.map(|d| now + d);
+        self.stop_at = match (after_duration, self.until) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    pub fn has_windows(&self) -> bool {
+        !self.windows.is_empty()
+    }
+
+    pub fn is_over(&self, t: SystemTime) -> bool {
+        self.stop_at.map_or(false, |stop| stop <= t)
+    }
+
+    /// Whether packets at `t` should be recorded.
+    pub fn is_active(&self, t: SystemTime) -> bool {
+        !self.is_over(t) && (self.windows.is_empty() || self.windows.iter().any(|w| w.contains(t)))
+    }
+
+    /// Next time after `t` that [`Self::is_active`] or [`Self::is_over`]
+    /// might change, if ever.
+    pub fn next_change(&self, t: SystemTime) -> Option<SystemTime> {
+        self.windows
+            .iter()
+            .map(|w| w.next_change(t))
+            .chain(self.stop_at.filter(|&stop| stop > t))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 2023-08-01 00:00:00 UTC.
+    const DAY: u64 = 1_690_848_000;
+
+    fn at(h: u64, m: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(DAY + h * 3600 + m * 60)
+    }
+
+    #[test]
+    fn test_parse_window() {
+        let w: DailyWindow = "09:00-10:30".parse().unwrap();
+        assert_eq!(w.to_string(), "09:00-10:30");
+        assert!("09:00".parse::<DailyWindow>().is_err());
+        assert!("09:00-09:00".parse::<DailyWindow>().is_err());
+        assert!("24:00-01:00".parse::<DailyWindow>().is_err());
+        assert!("09:60-10:00".parse::<DailyWindow>().is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        let w: DailyWindow = "09:00-10:00".parse().unwrap();
+        assert!(!w.contains(at(8, 59)));
+        assert!(w.contains(at(9, 0)));
+        assert!(w.contains(at(9, 59)));
+        assert!(!w.contains(at(10, 0)));
+
+        assert_eq!(w.next_change(at(8, 0)), at(9, 0));
+        assert_eq!(w.next_change(at(9, 0)), at(10, 0));
+        assert_eq!(w.next_change(at(11, 0)), at(24 + 9, 0));
+    }
+
+    #[test]
+    fn test_window_across_midnight() {
+        let w: DailyWindow = "23:00-01:00".parse().unwrap();
+        assert!(w.contains(at(23, 30)));
+        assert!(w.contains(at(0, 30)));
+        assert!(!w.contains(at(1, 0)));
+        assert!(!w.contains(at(12, 0)));
+
+        assert_eq!(w.next_change(at(12, 0)), at(23, 0));
+        assert_eq!(w.next_change(at(23, 0)), at(24 + 1, 0));
+        assert_eq!(w.next_change(at(0, 30)), at(1, 0));
+    }
+
+    #[test]
+    fn test_window_ignores_dst() {
+        // Europe switched to summer time at 01:00 UTC on 2023-03-26. Windows
+        // are in UTC, so that day is 24 hours long like any other.
+        let dst_day = 1_679_788_800;
+        let w: DailyWindow = "00:30-02:30".parse().unwrap();
+        let t = UNIX_EPOCH + Duration::from_secs(dst_day + 3600);
+        assert!(w.contains(t));
+        assert_eq!(
+            w.next_change(t),
+            UNIX_EPOCH + Duration::from_secs(dst_day + 2 * 3600 + 1800)
+        );
+    }
+
+    #[test]
+    fn test_parse_until() {
+        assert_eq!(parse_until_at("10:00", at(9, 0)).unwrap(), at(10, 0));
+        // A time of day already gone today means tomorrow.
+        assert_eq!(parse_until_at("08:00", at(9, 0)).unwrap(), at(24 + 8, 0));
+        assert_eq!(parse_until_at("09:00", at(9, 0)).unwrap(), at(24 + 9, 0));
+        assert_eq!(
+            parse_until_at("2023-08-01 17:00:00", at(9, 0)).unwrap(),
+            at(17, 0)
+        );
+        assert!(parse_until_at("soon", at(9, 0)).is_err());
+    }
+
+    #[test]
+    fn test_schedule_duration_from_start() {
+        let mut schedule =
+            CaptureSchedule::new(Some(Duration::from_secs(3600)), Some(at(12, 0)), Vec::new());
+        schedule.start(at(9, 0));
+        assert!(!schedule.is_over(at(9, 59)));
+        assert!(schedule.is_over(at(10, 0)));
+        assert_eq!(schedule.next_change(at(9, 0)), Some(at(10, 0)));
+
+        // `until` wins if it comes first.
+        schedule.start(at(11, 30));
+        assert_eq!(schedule.next_change(at(11, 30)), Some(at(12, 0)));
+    }
+
+    #[test]
+    fn test_schedule_next_change() {
+        let schedule =
+            CaptureSchedule::new(None, Some(at(9, 30)), vec!["09:00-10:00".parse().unwrap()]);
+        assert!(!schedule.is_active(at(8, 0)));
+        assert_eq!(schedule.next_change(at(8, 0)), Some(at(9, 0)));
+        assert!(schedule.is_active(at(9, 0)));
+        assert_eq!(schedule.next_change(at(9, 0)), Some(at(9, 30)));
+        assert!(!schedule.is_active(at(9, 30)));
+        assert_eq!(CaptureSchedule::default().next_change(at(9, 0)), None);
+    }
+}
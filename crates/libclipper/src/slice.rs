@@ -0,0 +1,36 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading the ends of a [`CaptureSlice`](net_decode::capture_slice::CaptureSlice)
+//! given on the command line.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use net_decode::{capture_slice::SliceBound, listener::Nanos};
+
+use crate::Error;
+
+fn to_nanos(d: Duration) -> Result<Nanos, Error> {
+    Nanos::try_from(d.as_nanos()).map_err(|_| "time is too far off".into())
+}
+
+/// A packet number counting from 1, e.g. `1500`; a time since the first
+/// packet, e.g. `+90s`; or a time in RFC 3339, e.g. `2023-06-15T21:45:51Z`.
+pub fn parse_slice_bound(s: &str) -> Result<SliceBound, Error> {
+    if let Ok(number) = s.parse::<u64>() {
+        if number == 0 {
+            return Err("packets are numbered from 1".into());
+        }
+        return Ok(SliceBound::Packet(number));
+    }
+    if let Some(since_start) = s.strip_prefix('+') {
+        let since_start = humantime::parse_duration(since_start)?;
+        return Ok(SliceBound::SinceStart(to_nanos(since_start)?));
+    }
+    let time = humantime::parse_rfc3339_weak(s)
+        .map_err(|e| format!("{s:?} is not a packet number, +DURATION or RFC 3339 time: {e}"))?;
+    Ok(SliceBound::Time(to_nanos(
+        time.duration_since(UNIX_EPOCH)?,
+    )?))
+}
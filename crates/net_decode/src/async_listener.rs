@@ -0,0 +1,243 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Listeners that need to await, such as ones writing to disk or to the
+//! network, without holding up decoding while they do.
+//!
+//! An [`AsyncBridge`] is an ordinary [`Listener`] which queues what it gets
+//! for a task running the [`AsyncListener`]. If that falls behind and the
+//! queue fills up, further messages and side data are dropped and counted
+//! rather than making packet processing wait.
+//!
+//! Side data such as keys and connections closing is small but matters more
+//! than any one message, so [`AsyncBridge::spawn_prioritized`] gives it a
+//! queue of its own which is always handled first.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use tokio::{runtime::Handle, sync::mpsc, task::JoinHandle};
+
+use crate::{
+    chomp::IPTarget,
+    listener::{Listener, SideData, TimingInfo},
+};
+
+/// [`Listener`], but async.
+#[async_trait::async_trait]
+pub trait AsyncListener<MessageType: Send + 'static>: Send {
+    async fn on_data(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        data: MessageType,
+    );
+
+    async fn on_side_data(&mut self, data: Box<dyn SideData>);
+}
+
+enum Event<T> {
+    Data(TimingInfo, IPTarget, bool, T),
+    SideData(Box<dyn SideData>),
+}
+
+/// Feeds an [`AsyncListener`] through a bounded queue.
+pub struct AsyncBridge<T> {
+    tx: mpsc::Sender<Event<T>>,
+    /// Side data, if it has a queue of its own.
+    priority_tx: Option<mpsc::Sender<Event<T>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+async fn deliver<T: Send + 'static>(listener: &mut impl AsyncListener<T>, event: Event<T>) {
+    match event {
+        Event::Data(timing, target, to_client, data) => {
+            listener.on_data(timing, target, to_client, data).await
+        }
+        Event::SideData(data) => listener.on_side_data(data).await,
+    }
+}
+
+impl<T: Send + 'static> AsyncBridge<T> {
+    /// Spawns a task on `runtime` that takes up to `capacity` queued
+    /// messages and side data. The task finishes once the bridge is dropped
+    /// and everything queued has been handled.
+    pub fn spawn(
+        runtime: &Handle,
+        capacity: usize,
+        listener: impl AsyncListener<T> + 'static,
+    ) -> (Self, JoinHandle<()>) {
+        Self::spawn_inner(runtime, capacity, None, listener)
+    }
+
+    /// Like [`AsyncBridge::spawn`], but with side data queued separately, up
+    /// to `priority_capacity` of it, and handled before any messages
+    /// waiting. Side data can then overtake the messages sent before it,
+    /// such as a connection closing arriving before the end of its last
+    /// body.
+    pub fn spawn_prioritized(
+        runtime: &Handle,
+        capacity: usize,
+        priority_capacity: usize,
+        listener: impl AsyncListener<T> + 'static,
+    ) -> (Self, JoinHandle<()>) {
+        Self::spawn_inner(runtime, capacity, Some(priority_capacity), listener)
+    }
+
+    fn spawn_inner(
+        runtime: &Handle,
+        capacity: usize,
+        priority_capacity: Option<usize>,
+        mut listener: impl AsyncListener<T> + 'static,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::channel(capacity);
+        let (priority_tx, mut priority_rx) = match priority_capacity {
+            Some(capacity) => {
+                let (tx, rx) = mpsc::channel(capacity);
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
+        let task = runtime.spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    biased;
+                    Some(event) = async {
+                        match &mut priority_rx {
+                            Some(rx) => rx.recv().await,
+                            None => None,
+                        }
+                    } => event,
+                    event = rx.recv() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                };
+                deliver(&mut listener, event).await;
+            }
+            if let Some(rx) = &mut priority_rx {
+                while let Some(event) = rx.recv().await {
+                    deliver(&mut listener, event).await;
+                }
+            }
+        });
+        let bridge = AsyncBridge {
+            tx,
+            priority_tx,
+            dropped: Default::default(),
+        };
+        (bridge, task)
+    }
+
+    /// How many messages and side data were dropped because the queue was
+    /// full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&mut self, event: Event<T>) {
+        let tx = match (&event, &self.priority_tx) {
+            (Event::SideData(_), Some(priority_tx)) => priority_tx,
+            _ => &self.tx,
+        };
+        match tx.try_send(event) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    tracing::warn!("async listener is falling behind, dropping data");
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::debug!("async listener task is gone, dropping data");
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Listener<T> for AsyncBridge<T> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        self.send(Event::Data(timing, target, to_client, data));
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.send(Event::SideData(data));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::chomp::IPHeader;
+
+    struct Collect(Arc<Mutex<Vec<u32>>>);
+
+    #[async_trait::async_trait]
+    impl AsyncListener<u32> for Collect {
+        async fn on_data(&mut self, _: TimingInfo, _: IPTarget, _: bool, data: u32) {
+            tokio::task::yield_now().await;
+            self.0.lock().unwrap().push(data);
+        }
+
+        async fn on_side_data(&mut self, _data: Box<dyn SideData>) {
+            self.0.lock().unwrap().push(u32::MAX);
+        }
+    }
+
+    #[test]
+    fn test_bridge() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (mut bridge, task) = AsyncBridge::spawn(runtime.handle(), 3, Collect(received.clone()));
+
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+        let target = IPTarget::from_ports(&IPHeader::V4(header), 40000, 80);
+        // Nothing runs the task until we block on it, so the last two are
+        // dropped.
+        for i in 0..4 {
+            bridge.on_data(TimingInfo::default(), target, false, i);
+        }
+        bridge.on_side_data(Box::new(()));
+        assert_eq!(bridge.dropped(), 2);
+
+        drop(bridge);
+        runtime.block_on(task).unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_bridge_prioritized() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (mut bridge, task) =
+            AsyncBridge::spawn_prioritized(runtime.handle(), 2, 1, Collect(received.clone()));
+
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+        let target = IPTarget::from_ports(&IPHeader::V4(header), 40000, 80);
+        // The side data is not stuck behind the full message queue
+        for i in 0..3 {
+            bridge.on_data(TimingInfo::default(), target, false, i);
+        }
+        bridge.on_side_data(Box::new(()));
+        assert_eq!(bridge.dropped(), 1);
+
+        drop(bridge);
+        runtime.block_on(task).unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![u32::MAX, 0, 1]);
+    }
+}
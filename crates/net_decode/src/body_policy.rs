@@ -0,0 +1,270 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Per-host control over whether HTTP bodies are passed on past the HTTP
+//! decoder, so that traffic to third parties can be kept to headers only.
+//!
+//! Rules look like `*.internal=full` or `*.google.com=headers`. The first
+//! matching rule wins; hosts matching no rule get the default (full bodies).
+
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use http::header;
+
+use crate::{
+    chomp::IPTarget,
+    http::{HTTPStreamEvent, RequestId},
+    listener::{Listener, SideData, TimingInfo},
+    Error,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BodyPolicy {
+    #[default]
+    Full,
+    HeadersOnly,
+}
+
+impl FromStr for BodyPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(BodyPolicy::Full),
+            "headers" | "headers-only" => Ok(BodyPolicy::HeadersOnly),
+            other => Err(format!("unknown body policy {other:?}, expected full or headers").into()),
+        }
+    }
+}
+
+/// Host name pattern: either an exact host, `*.domain` for any subdomain of
+/// `domain`, or `*` for everything. Matching is case insensitive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostPattern(String);
+
+impl HostPattern {
+    pub fn new(pattern: &str) -> Self {
+        HostPattern(pattern.to_ascii_lowercase())
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self.0.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+            _ => host == self.0,
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BodyPolicyRule {
+    pub pattern: HostPattern,
+    pub policy: BodyPolicy,
+}
+
+/// Parses `PATTERN=POLICY`.
+impl FromStr for BodyPolicyRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, policy) = s
+            .split_once('=')
+            .ok_or_else(|| format!("body policy rule {s:?} should look like PATTERN=POLICY"))?;
+        Ok(BodyPolicyRule {
+            pattern: HostPattern::new(pattern),
+            policy: policy.parse()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BodyPolicies {
+    pub rules: Vec<BodyPolicyRule>,
+    /// Used for hosts matching no rule, and requests we cannot find a host
+    /// for.
+    pub default: BodyPolicy,
+}
+
+impl BodyPolicies {
+    pub fn new(rules: Vec<BodyPolicyRule>) -> Self {
+        BodyPolicies {
+            rules,
+            default: BodyPolicy::default(),
+        }
+    }
+
+    pub fn policy_for(&self, host: Option<&str>) -> BodyPolicy {
+        let Some(host) = host else {
+            return self.default;
+        };
+        self.rules
+            .iter()
+            .find(|r| r.pattern.matches(host))
+            .map_or(self.default, |r| r.policy)
+    }
+}
+
+/// Host a request is for, without the port: the authority for HTTP/2 and
+/// absolute-form requests, otherwise the Host header.
+pub(crate) fn request_host(parts: &http::request::Parts) -> Option<String> {
+    if let Some(host) = parts.uri.host() {
+        return Some(host.to_string());
+    }
+    let host = parts.headers.get(header::HOST)?.to_str().ok()?;
+    let authority: http::uri::Authority = host.parse().ok()?;
+    Some(authority.host().to_string())
+}
+
+/// Listener that goes directly after the HTTP decoder and drops the body
+/// chunks of requests whose host has a [`BodyPolicy::HeadersOnly`] policy.
+/// The finished events (with lengths) are still passed on.
+pub struct BodyPolicyListener {
+    policies: BodyPolicies,
+    /// Request ids are only unique per HTTP decoder, so these are keyed by
+    /// flow too.
+    headers_only: HashSet<(IPTarget, RequestId)>,
+    next: Box<dyn Listener<HTTPStreamEvent>>,
+}
+
+impl BodyPolicyListener {
+    pub fn new(policies: BodyPolicies, next: Box<dyn Listener<HTTPStreamEvent>>) -> Self {
+        BodyPolicyListener {
+            policies,
+            headers_only: Default::default(),
+            next,
+        }
+    }
+}
+
+impl Listener<HTTPStreamEvent> for BodyPolicyListener {
+    fn on_data(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        data: HTTPStreamEvent,
+    ) {
+        match &data {
+            HTTPStreamEvent::NewRequest(id, parts) => {
+                let host = request_host(parts);
+                if self.policies.policy_for(host.as_deref()) == BodyPolicy::HeadersOnly {
+                    tracing::debug!("request_id={id} host={host:?}: not passing on bodies");
+                    self.headers_only.insert((target, *id));
+                }
+            }
+            HTTPStreamEvent::ReqBodyChunk(id, _)
+            | HTTPStreamEvent::RespBodyChunk(id, _)
+            | HTTPStreamEvent::ReqBodyTruncated(id, _)
+            | HTTPStreamEvent::RespBodyTruncated(id, _) => {
+                if self.headers_only.contains(&(target, *id)) {
+                    return;
+                }
+            }
+            HTTPStreamEvent::ResponseFinished(id, _) => {
+                self.headers_only.remove(&(target, *id));
+            }
+            HTTPStreamEvent::RequestFinished(..) | HTTPStreamEvent::NewResponse(..) => {}
+        }
+        self.next.on_data(timing, target, to_client, data);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.next.on_side_data(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::test_support::{sample_target, Received, TestListener};
+
+    #[test]
+    fn test_host_pattern() {
+        let p = HostPattern::new("*.Google.com");
+        assert!(p.matches("www.google.com"));
+        assert!(p.matches("a.b.GOOGLE.com"));
+        assert!(!p.matches("google.com"));
+        assert!(!p.matches("notgoogle.com"));
+
+        let p = HostPattern::new("api.internal");
+        assert!(p.matches("api.internal"));
+        assert!(!p.matches("x.api.internal"));
+
+        assert!(HostPattern::new("*").matches("anything"));
+    }
+
+    #[test]
+    fn test_rules() {
+        let policies = BodyPolicies::new(vec![
+            "*.internal=full".parse().unwrap(),
+            "*.google.com=headers".parse().unwrap(),
+            "*=headers-only".parse().unwrap(),
+        ]);
+        assert_eq!(policies.policy_for(Some("api.internal")), BodyPolicy::Full);
+        assert_eq!(
+            policies.policy_for(Some("www.google.com")),
+            BodyPolicy::HeadersOnly
+        );
+        assert_eq!(
+            policies.policy_for(Some("example.com")),
+            BodyPolicy::HeadersOnly
+        );
+        assert_eq!(policies.policy_for(None), BodyPolicy::Full);
+
+        assert!("*.internal".parse::<BodyPolicyRule>().is_err());
+        assert!("*.internal=some".parse::<BodyPolicyRule>().is_err());
+    }
+
+    #[test]
+    fn test_drops_bodies() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut listener = BodyPolicyListener::new(
+            BodyPolicies::new(vec!["*.google.com=headers".parse().unwrap()]),
+            Box::new(TestListener {
+                received: received.clone(),
+            }),
+        );
+        let target = sample_target();
+
+        for (id, host) in [(0, "www.google.com:443"), (1, "api.internal")] {
+            let (mut parts, _) = http::Request::new(()).into_parts();
+            parts.headers.insert(header::HOST, host.parse().unwrap());
+            let (resp, _) = http::Response::new(()).into_parts();
+            for ev in [
+                HTTPStreamEvent::NewRequest(id, parts),
+                HTTPStreamEvent::ReqBodyChunk(id, Bytes::from_static(b"secret")),
+                HTTPStreamEvent::RequestFinished(id, 6),
+                HTTPStreamEvent::NewResponse(id, resp),
+                HTTPStreamEvent::RespBodyChunk(id, Bytes::from_static(b"hidden")),
+                HTTPStreamEvent::ResponseFinished(id, 6),
+            ] {
+                listener.on_data(TimingInfo::default(), target, false, ev);
+            }
+        }
+
+        let received = received.read().unwrap();
+        let bodies: Vec<_> = received
+            .iter()
+            .filter_map(|r| match r {
+                Received::Message(_, HTTPStreamEvent::ReqBodyChunk(id, _))
+                | Received::Message(_, HTTPStreamEvent::RespBodyChunk(id, _)) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bodies, vec![1, 1]);
+        // Everything else still gets through
+        assert_eq!(received.len(), 10);
+    }
+}
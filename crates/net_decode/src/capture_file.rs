@@ -0,0 +1,860 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading capture files, in pcapng or classic pcap, whichever they turn
+//! out to be.
+//!
+//! pcapng files may have several sections, each numbering its interfaces
+//! from 0 again, and each interface may count time in its own units. Both
+//! are dealt with here, so that every [`Frame`] comes out with a timestamp
+//! in nanoseconds and an interface that is unique in the file. Host names
+//! from name resolution blocks and the comments on sections, interfaces and
+//! packets are kept too, for whoever wants to show them.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::Arc,
+};
+
+use pcap_parser::{
+    nom, parse_block_be, parse_block_le, parse_pcap_frame, parse_pcap_frame_be, parse_pcap_header,
+    traits::PcapNGPacketBlock, Block, EnhancedPacketBlock, InterfaceDescriptionBlock, Linktype,
+    NameRecord, NameRecordType, OptionCode, PcapBlockOwned, PcapError, PcapNGOption, SecretsType,
+};
+use tracing::Level;
+
+use crate::{
+    link::{self, PacketDirection},
+    listener::{CaptureInterface, Nanos, TimingInfo},
+    mapped_file::MappedFile,
+    Error,
+};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// How much of the file to read at once, to start with. Grown if a block
+/// does not fit.
+const INITIAL_BUFFER_SIZE: usize = 65536;
+
+/// Where the packet data starts in each kind of block that holds one.
+const LEGACY_DATA_OFFSET: u64 = 16;
+const EPB_DATA_OFFSET: u64 = 28;
+const SPB_DATA_OFFSET: u64 = 12;
+
+/// Block type of a pcapng section header, which pcapng files start with.
+const SHB_MAGIC: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+
+/// Interface options that `pcap_parser` has no names for.
+const OPT_IF_NAME: OptionCode = OptionCode(2);
+const OPT_IF_DESCRIPTION: OptionCode = OptionCode(3);
+/// Enhanced packet block option with the direction of the packet.
+const OPT_EPB_FLAGS: OptionCode = OptionCode(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureFormat {
+    Pcap,
+    PcapNg,
+}
+
+/// An interface that packets in the file were captured on.
+#[derive(Clone, Debug)]
+pub struct Interface {
+    pub link_type: Linktype,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub comments: Vec<String>,
+    /// How much of each packet was kept, if that was limited.
+    pub snap_len: Option<u32>,
+    /// Timestamp units per second.
+    ticks_per_sec: u64,
+    /// Seconds to add to every timestamp.
+    offset_secs: u64,
+    /// `name`, to be shared by every frame captured on the interface.
+    shared_name: Option<Arc<str>>,
+}
+
+impl Interface {
+    fn new(link_type: Linktype, ticks_per_sec: u64, snap_len: u32) -> Self {
+        if !link::is_supported(link_type) {
+            tracing::warn!(
+                "interface has unsupported link type {link_type:?}, its packets will be ignored"
+            );
+        }
+        Interface {
+            link_type,
+            name: None,
+            description: None,
+            comments: Vec::new(),
+            snap_len: (snap_len != 0).then_some(snap_len),
+            ticks_per_sec,
+            offset_secs: 0,
+            shared_name: None,
+        }
+    }
+
+    fn from_idb(idb: &InterfaceDescriptionBlock) -> Self {
+        const DEFAULT_RESOLUTION: u64 = 1_000_000;
+        let ticks_per_sec = idb.ts_resolution().unwrap_or_else(|| {
+            tracing::warn!(
+                "bad pcap file: interface has unusable timestamp resolution {:#x}",
+                idb.if_tsresol
+            );
+            DEFAULT_RESOLUTION
+        });
+
+        let mut interface = Interface::new(idb.linktype, ticks_per_sec, idb.snaplen);
+        interface.name = option_strings(&idb.options, OPT_IF_NAME)
+            .next()
+            .map(str::to_owned);
+        interface.shared_name = interface.name.as_deref().map(Arc::from);
+        interface.description = option_strings(&idb.options, OPT_IF_DESCRIPTION)
+            .next()
+            .map(str::to_owned);
+        interface.comments = option_strings(&idb.options, OptionCode::Comment)
+            .map(str::to_owned)
+            .collect();
+        interface.offset_secs = idb.ts_offset();
+        interface
+    }
+
+    /// Where frames captured on this interface, which is at `index` in
+    /// [`CaptureInfo::interfaces`], were seen.
+    pub(crate) fn capture_interface(&self, index: usize) -> CaptureInterface {
+        CaptureInterface {
+            index: index as u32,
+            name: self.shared_name.clone(),
+        }
+    }
+
+    /// Converts a timestamp in this interface's units to nanoseconds since
+    /// the epoch.
+    fn resolve_timestamp(&self, ticks: u64) -> Nanos {
+        let nanos = ticks as u128 * NANOS_PER_SEC / self.ticks_per_sec as u128
+            + self.offset_secs as u128 * NANOS_PER_SEC;
+        nanos as Nanos
+    }
+}
+
+/// What is known about a capture file, filled in as it is read.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureInfo {
+    pub format: Option<CaptureFormat>,
+    /// Machine and operating system the capture was taken on, as the first
+    /// section of a pcapng file says.
+    pub hardware: Option<String>,
+    pub os: Option<String>,
+    /// Comments on the sections of a pcapng file.
+    pub comments: Vec<String>,
+    /// Every interface in the file, across all of its sections.
+    pub interfaces: Vec<Interface>,
+    /// Host names given to addresses by name resolution blocks.
+    pub names: BTreeMap<IpAddr, Vec<String>>,
+}
+
+/// A packet read from a capture file.
+#[derive(Debug)]
+pub struct Frame<'a> {
+    /// Index into [`CaptureInfo::interfaces`].
+    pub interface: usize,
+    pub timing: TimingInfo,
+    pub link_type: Linktype,
+    pub data: &'a [u8],
+    /// Where `data` is in the file, for seeking back to it.
+    pub offset: u64,
+    /// How long the packet was, which is more than `data` if it was cut
+    /// short when captured.
+    pub original_len: u32,
+    pub comments: Vec<&'a str>,
+}
+
+/// Something of interest in a capture file.
+#[derive(Debug)]
+pub enum Record<'a> {
+    Frame(Frame<'a>),
+    /// A TLS key log, from a decryption secrets block.
+    TlsKeys(&'a [u8]),
+    /// A WireGuard key log, see [`crate::wireguard`].
+    WireguardKeys(&'a [u8]),
+}
+
+/// The string values of the options with `code`.
+fn option_strings<'a, 'o>(
+    options: &'o [PcapNGOption<'a>],
+    code: OptionCode,
+) -> impl Iterator<Item = &'a str> + 'o {
+    options
+        .iter()
+        .filter(move |o| o.code == code)
+        .filter_map(|o| std::str::from_utf8(o.value).ok())
+        .map(|s| s.trim_end_matches('\0'))
+}
+
+/// Which way the packet in `epb` was going, from its flags.
+fn epb_direction(epb: &EnhancedPacketBlock<'_>) -> Option<PacketDirection> {
+    let flags = epb.options.iter().find(|o| o.code == OPT_EPB_FLAGS)?;
+    let flags: [u8; 4] = flags.value.get(..4)?.try_into().unwrap();
+    let flags = match epb.big_endian() {
+        true => u32::from_be_bytes(flags),
+        false => u32::from_le_bytes(flags),
+    };
+    PacketDirection::from_epb_flags(flags)
+}
+
+/// An address and the names it is given by a name resolution record.
+fn parse_name_record(record: &NameRecord) -> Option<(IpAddr, Vec<String>)> {
+    let value = record.record_value;
+    let (addr, names) = match record.record_type {
+        NameRecordType::Ipv4 if value.len() >= 4 => {
+            let octets: [u8; 4] = value[..4].try_into().unwrap();
+            (IpAddr::from(Ipv4Addr::from(octets)), &value[4..])
+        }
+        NameRecordType::Ipv6 if value.len() >= 16 => {
+            let octets: [u8; 16] = value[..16].try_into().unwrap();
+            (IpAddr::from(Ipv6Addr::from(octets)), &value[16..])
+        }
+        _ => return None,
+    };
+    let names = names
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| std::str::from_utf8(name).ok())
+        .map(str::to_owned)
+        .collect();
+    Some((addr, names))
+}
+
+/// Reads from `reader` until `buf` holds `len` bytes or there is no more.
+fn read_up_to(reader: &mut impl io::Read, buf: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    while buf.len() < len {
+        let start = buf.len();
+        buf.resize(len, 0);
+        match reader.read(&mut buf[start..]) {
+            Ok(0) => {
+                buf.truncate(start);
+                break;
+            }
+            Ok(n) => buf.truncate(start + n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => buf.truncate(start),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the file header of a capture, or the whole first block of a
+/// pcapng one. `pcap_parser` tells the format from a single read, which out
+/// of a pipe may be just the first few bytes.
+fn read_header(reader: &mut impl io::Read) -> io::Result<Vec<u8>> {
+    // Block type, block length and byte order magic of a section header.
+    let mut header = Vec::new();
+    read_up_to(reader, &mut header, 12)?;
+    if header.len() == 12 && header[..4] == SHB_MAGIC {
+        let len: [u8; 4] = header[4..8].try_into().unwrap();
+        let len = match header[8..12] {
+            [0x4d, 0x3c, 0x2b, 0x1a] => u32::from_le_bytes(len),
+            _ => u32::from_be_bytes(len),
+        };
+        read_up_to(reader, &mut header, (len as usize).min(INITIAL_BUFFER_SIZE))?;
+    } else {
+        // Classic pcap file header.
+        read_up_to(reader, &mut header, 24)?;
+    }
+    Ok(header)
+}
+
+/// What has been read of a capture so far, between blocks.
+#[derive(Default)]
+struct Reading {
+    info: CaptureInfo,
+    /// Interfaces of the current section, as indices into `info.interfaces`.
+    section: Vec<usize>,
+    /// Simple packet blocks have no timestamp, so they get the last one
+    /// seen.
+    last_timestamp: Nanos,
+    packet_count: u64,
+}
+
+impl Reading {
+    /// Reads a block that starts `block_start` bytes into the file.
+    fn on_block(
+        &mut self,
+        block: PcapBlockOwned<'_>,
+        block_start: u64,
+        on_record: &mut dyn FnMut(&CaptureInfo, Record<'_>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let span = tracing::span!(Level::DEBUG, "packet", count = self.packet_count + 1);
+        let _enter = span.enter();
+        let info = &mut self.info;
+
+        match block {
+            PcapBlockOwned::LegacyHeader(header) => {
+                tracing::debug!("pcap header: {header:?}");
+                info.format = Some(CaptureFormat::Pcap);
+                let ticks_per_sec = if header.is_nanosecond_precision() {
+                    1_000_000_000
+                } else {
+                    1_000_000
+                };
+                self.section = vec![info.interfaces.len()];
+                info.interfaces.push(Interface::new(
+                    header.network,
+                    ticks_per_sec,
+                    header.snaplen,
+                ));
+            }
+            PcapBlockOwned::Legacy(packet) => {
+                let Some(&interface) = self.section.first() else {
+                    tracing::warn!("bad pcap file: packet before the file header");
+                    return Ok(());
+                };
+                let iface = &info.interfaces[interface];
+                let ticks = packet.ts_sec as u64 * iface.ticks_per_sec + packet.ts_usec as u64;
+                let ts = iface.resolve_timestamp(ticks);
+                self.last_timestamp = ts;
+                on_record(
+                    info,
+                    Record::Frame(Frame {
+                        interface,
+                        timing: TimingInfo {
+                            received_on_wire: ts,
+                            interface: Some(iface.capture_interface(interface)),
+                            ..Default::default()
+                        },
+                        link_type: iface.link_type,
+                        data: packet.data,
+                        offset: block_start + LEGACY_DATA_OFFSET,
+                        original_len: packet.origlen,
+                        comments: Vec::new(),
+                    }),
+                )?;
+                self.packet_count += 1;
+            }
+            PcapBlockOwned::NG(Block::SectionHeader(shb)) => {
+                tracing::debug!("SHB: {:?}", shb);
+                info.format = Some(CaptureFormat::PcapNg);
+                if info.hardware.is_none() {
+                    info.hardware = option_strings(&shb.options, OptionCode::ShbHardware)
+                        .next()
+                        .map(str::to_owned);
+                }
+                if info.os.is_none() {
+                    info.os = option_strings(&shb.options, OptionCode::ShbOs)
+                        .next()
+                        .map(str::to_owned);
+                }
+                info.comments
+                    .extend(option_strings(&shb.options, OptionCode::Comment).map(str::to_owned));
+                self.section.clear();
+            }
+            PcapBlockOwned::NG(Block::InterfaceDescription(idb)) => {
+                tracing::debug!("IDB: {:?}", idb);
+                self.section.push(info.interfaces.len());
+                info.interfaces.push(Interface::from_idb(&idb));
+            }
+            PcapBlockOwned::NG(Block::NameResolution(nrb)) => {
+                for (addr, names) in nrb.nr.iter().filter_map(parse_name_record) {
+                    let known = info.names.entry(addr).or_default();
+                    for name in names {
+                        if !known.contains(&name) {
+                            known.push(name);
+                        }
+                    }
+                }
+            }
+            PcapBlockOwned::NG(Block::DecryptionSecrets(dsb)) => {
+                match dsb.data.get(..dsb.secrets_len as usize) {
+                    // Wireshark also writes e.g. ZigBee keys here.
+                    _ if dsb.secrets_type != SecretsType::TlsKeyLog
+                        && dsb.secrets_type != SecretsType::WireguardKeyLog =>
+                    {
+                        tracing::debug!("skipping DSB with secrets type {:?}", dsb.secrets_type);
+                    }
+                    Some(secrets) if dsb.secrets_type == SecretsType::WireguardKeyLog => {
+                        on_record(info, Record::WireguardKeys(secrets))?;
+                    }
+                    Some(secrets) => {
+                        tracing::debug!("DSB: {}", misc::Show(secrets));
+                        on_record(info, Record::TlsKeys(secrets))?;
+                    }
+                    None => {
+                        tracing::warn!(
+                            "bad pcap file: DSB secrets length {} is longer than the block",
+                            dsb.secrets_len
+                        );
+                    }
+                }
+            }
+            PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => {
+                let Some(&interface) = self.section.get(epb.if_id as usize) else {
+                    tracing::warn!("bad pcap file: interface {} is not defined", epb.if_id);
+                    return Ok(());
+                };
+                let iface = &info.interfaces[interface];
+                let ticks = ((epb.ts_high as u64) << 32) | (epb.ts_low as u64);
+                let ts = iface.resolve_timestamp(ticks);
+                self.last_timestamp = ts;
+                on_record(
+                    info,
+                    Record::Frame(Frame {
+                        interface,
+                        timing: TimingInfo {
+                            received_on_wire: ts,
+                            interface: Some(iface.capture_interface(interface)),
+                            direction: epb_direction(&epb),
+                            ..Default::default()
+                        },
+                        link_type: iface.link_type,
+                        data: epb.packet_data(),
+                        offset: block_start + EPB_DATA_OFFSET,
+                        original_len: epb.origlen,
+                        comments: option_strings(&epb.options, OptionCode::Comment).collect(),
+                    }),
+                )?;
+                self.packet_count += 1;
+            }
+            PcapBlockOwned::NG(Block::SimplePacket(spb)) => {
+                let Some(&interface) = self.section.first() else {
+                    tracing::warn!("bad pcap file: simple packet with no interface defined");
+                    return Ok(());
+                };
+                let iface = &info.interfaces[interface];
+                on_record(
+                    info,
+                    Record::Frame(Frame {
+                        interface,
+                        timing: TimingInfo {
+                            received_on_wire: self.last_timestamp,
+                            interface: Some(iface.capture_interface(interface)),
+                            ..Default::default()
+                        },
+                        link_type: iface.link_type,
+                        data: spb.packet_data(),
+                        offset: block_start + SPB_DATA_OFFSET,
+                        original_len: spb.origlen,
+                        comments: Vec::new(),
+                    }),
+                )?;
+                self.packet_count += 1;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Reads a capture file, giving each record in it to `on_record` along with
+/// what is known about the file so far. Returns everything that was
+/// learned about the file.
+///
+/// A file that is cut off part way through a packet, as happens when the
+/// program writing it is killed, is read up to there. `reader` may be a
+/// pipe that is still being written to, e.g. by `tcpdump -w -`, in which
+/// case records are given to `on_record` as they arrive.
+pub fn read_capture(
+    mut reader: impl io::Read,
+    on_record: &mut dyn FnMut(&CaptureInfo, Record<'_>) -> Result<(), Error>,
+) -> Result<CaptureInfo, Error> {
+    let header =
+        read_header(&mut reader).map_err(|e| format!("error reading capture file: {e}"))?;
+    if header.is_empty() {
+        return Err("capture file is empty".into());
+    }
+    let mut buffer_size = INITIAL_BUFFER_SIZE;
+    let mut pcap = pcap_parser::create_reader(buffer_size, io::Cursor::new(header).chain(reader))?;
+
+    let mut reading = Reading::default();
+    // Where the next block starts in the file.
+    let mut position = 0u64;
+
+    loop {
+        let (offset, block) = match pcap.next() {
+            Ok(next) => next,
+            Err(PcapError::Eof) => break,
+            Err(PcapError::Incomplete) => {
+                let buffered = pcap.data().len();
+                pcap.refill()
+                    .map_err(|e| format!("error reading capture file: {e:?}"))?;
+                if pcap.data().len() == buffered {
+                    if pcap.reader_exhausted() {
+                        tracing::warn!("capture file is cut off part way through a block");
+                        break;
+                    }
+                    // The block is bigger than the whole buffer.
+                    buffer_size *= 2;
+                    if !pcap.grow(buffer_size) {
+                        return Err("block in capture file is too big to read".into());
+                    }
+                }
+                continue;
+            }
+            Err(e) => return Err(format!("bad capture file: {e:?}").into()),
+        };
+        let block_start = position;
+        position += offset as u64;
+        reading.on_block(block, block_start, on_record)?;
+        pcap.consume(offset);
+    }
+
+    Ok(reading.info)
+}
+
+/// Reads a capture file that is all in memory, like [`read_capture`], but
+/// with the frames given to `on_record` pointing into `data` rather than
+/// copied out of it.
+pub fn read_capture_slice(
+    data: &[u8],
+    on_record: &mut dyn FnMut(&CaptureInfo, Record<'_>) -> Result<(), Error>,
+) -> Result<CaptureInfo, Error> {
+    if data.is_empty() {
+        return Err("capture file is empty".into());
+    }
+    let mut reading = Reading::default();
+    let mut rest = data;
+    // Classic pcap files are in one byte order throughout, pcapng ones may
+    // change it at each section.
+    let legacy_big_endian = if data.starts_with(&SHB_MAGIC) {
+        None
+    } else {
+        let (after, header) =
+            parse_pcap_header(data).map_err(|e| format!("bad capture file: {e:?}"))?;
+        let big_endian = header.is_bigendian();
+        reading.on_block(header.into(), 0, on_record)?;
+        rest = after;
+        Some(big_endian)
+    };
+    let mut big_endian = false;
+
+    while !rest.is_empty() {
+        let block_start = (data.len() - rest.len()) as u64;
+        let parsed = match legacy_big_endian {
+            Some(false) => parse_pcap_frame(rest).map(|(r, b)| (r, PcapBlockOwned::from(b))),
+            Some(true) => parse_pcap_frame_be(rest).map(|(r, b)| (r, PcapBlockOwned::from(b))),
+            None if big_endian => parse_block_be(rest).map(|(r, b)| (r, PcapBlockOwned::from(b))),
+            None => parse_block_le(rest).map(|(r, b)| (r, PcapBlockOwned::from(b))),
+        };
+        let block = match parsed {
+            Ok((after, block)) => {
+                rest = after;
+                block
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                tracing::warn!("capture file is cut off part way through a block");
+                break;
+            }
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                return Err(format!("bad capture file: {e:?}").into())
+            }
+        };
+        if let PcapBlockOwned::NG(Block::SectionHeader(shb)) = &block {
+            big_endian = shb.big_endian();
+        }
+        reading.on_block(block, block_start, on_record)?;
+    }
+
+    Ok(reading.info)
+}
+
+/// Reads the capture file at `path`, like [`read_capture`]. Regular files
+/// are mapped into memory and read with [`read_capture_slice`], anything
+/// else, like a named pipe, as it comes.
+pub fn read_capture_file(
+    path: &Path,
+    on_record: &mut dyn FnMut(&CaptureInfo, Record<'_>) -> Result<(), Error>,
+) -> Result<CaptureInfo, Error> {
+    let file = fs::File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return read_capture(io::BufReader::new(file), on_record);
+    }
+    let map = MappedFile::new(&file)?;
+    map.advise_sequential();
+    read_capture_slice(&map, on_record)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pad(mut v: Vec<u8>) -> Vec<u8> {
+        v.resize(v.len() + (4 - v.len() % 4) % 4, 0);
+        v
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = (12 + body.len()) as u32;
+        let mut b = block_type.to_le_bytes().to_vec();
+        b.extend(len.to_le_bytes());
+        b.extend(body);
+        b.extend(len.to_le_bytes());
+        b
+    }
+
+    fn options(options: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut b = Vec::new();
+        for (code, value) in options {
+            b.extend(code.to_le_bytes());
+            b.extend((value.len() as u16).to_le_bytes());
+            b.extend(pad(value.to_vec()));
+        }
+        b.extend([0; 4]);
+        b
+    }
+
+    fn shb(comment: &str) -> Vec<u8> {
+        let mut body = 0x1A2B3C4Du32.to_le_bytes().to_vec();
+        body.extend(1u16.to_le_bytes());
+        body.extend(0u16.to_le_bytes());
+        body.extend((-1i64).to_le_bytes());
+        body.extend(options(&[(1, comment.as_bytes())]));
+        block(0x0A0D0D0A, &body)
+    }
+
+    fn idb(opts: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = 1u16.to_le_bytes().to_vec();
+        body.extend(0u16.to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        body.extend(options(opts));
+        block(1, &body)
+    }
+
+    fn epb(if_id: u32, ts: u64, data: &[u8], origlen: u32, opts: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = if_id.to_le_bytes().to_vec();
+        body.extend(((ts >> 32) as u32).to_le_bytes());
+        body.extend((ts as u32).to_le_bytes());
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend(origlen.to_le_bytes());
+        body.extend(pad(data.to_vec()));
+        body.extend(options(opts));
+        block(6, &body)
+    }
+
+    /// Interface, timestamp, data, original length and comments.
+    type ReadFrame = (usize, Nanos, Vec<u8>, u32, Vec<String>);
+
+    /// Reads `file` both as a stream and as a slice, which have to agree.
+    fn read(file: &[u8]) -> (CaptureInfo, Vec<ReadFrame>) {
+        let mut frames = Vec::new();
+        let mut on_record = |_: &CaptureInfo, record: Record<'_>| {
+            if let Record::Frame(f) = record {
+                // The data really is where the frame says it is.
+                let start = f.offset as usize;
+                assert_eq!(&file[start..start + f.data.len()], f.data);
+                frames.push((
+                    f.interface,
+                    f.timing.received_on_wire,
+                    f.data.to_vec(),
+                    f.original_len,
+                    f.comments.iter().map(|c| c.to_string()).collect(),
+                ));
+            }
+            Ok(())
+        };
+        let info = read_capture(file, &mut on_record).unwrap();
+        let sliced_info = read_capture_slice(file, &mut on_record).unwrap();
+
+        let (streamed, sliced) = frames.split_at(frames.len() / 2);
+        assert_eq!(streamed, sliced);
+        assert_eq!(format!("{info:?}"), format!("{sliced_info:?}"));
+        frames.truncate(frames.len() / 2);
+        (info, frames)
+    }
+
+    #[test]
+    fn test_classic_pcap() {
+        for (magic, ts_frac, expected, big_endian) in [
+            (
+                0xa1b2c3d4u32,
+                250_000u32,
+                1_700_000_000_250_000_000u64,
+                false,
+            ),
+            (0xa1b23c4d, 250_000, 1_700_000_000_000_250_000, false),
+            (0xa1b2c3d4, 250_000, 1_700_000_000_250_000_000, true),
+        ] {
+            let u16_bytes = |v: u16| match big_endian {
+                true => v.to_be_bytes(),
+                false => v.to_le_bytes(),
+            };
+            let u32_bytes = |v: u32| match big_endian {
+                true => v.to_be_bytes(),
+                false => v.to_le_bytes(),
+            };
+            let mut file = u32_bytes(magic).to_vec();
+            file.extend(u16_bytes(2));
+            file.extend(u16_bytes(4));
+            file.extend([0; 8]);
+            file.extend(u32_bytes(96));
+            file.extend(u32_bytes(1));
+            file.extend(u32_bytes(1_700_000_000));
+            file.extend(u32_bytes(ts_frac));
+            file.extend(u32_bytes(4));
+            file.extend(u32_bytes(1500));
+            file.extend([1, 2, 3, 4]);
+
+            let (info, frames) = read(&file);
+            assert_eq!(info.format, Some(CaptureFormat::Pcap));
+            assert_eq!(info.interfaces[0].link_type, Linktype::ETHERNET);
+            assert_eq!(info.interfaces[0].snap_len, Some(96));
+            assert_eq!(
+                frames,
+                vec![(0, expected, vec![1, 2, 3, 4], 1500, Vec::new())]
+            );
+        }
+    }
+
+    /// Gives out a byte at a time, like a pipe being written to slowly.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_from_pipe() {
+        let mut file = shb("piped");
+        file.extend(idb(&[(2, b"eth0")]));
+        file.extend(epb(0, 1_000_000, b"abcd", 4, &[]));
+        file.extend(epb(0, 2_000_000, b"efgh", 4, &[]));
+
+        let mut frames = Vec::new();
+        let info = read_capture(Trickle(&file), &mut |_, record| {
+            if let Record::Frame(f) = record {
+                frames.push(f.data.to_vec());
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(info.comments, ["piped"]);
+        assert_eq!(frames, [b"abcd".to_vec(), b"efgh".to_vec()]);
+
+        assert!(read_capture(Trickle(&[]), &mut |_, _| Ok(())).is_err());
+    }
+
+    /// A capture on disk reads the same through the map as streamed.
+    #[test]
+    fn test_read_capture_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/nya-dsb.pcapng");
+        let collect = |records: &mut Vec<(Nanos, Vec<u8>)>, record: Record<'_>| {
+            match record {
+                Record::Frame(f) => records.push((f.timing.received_on_wire, f.data.to_vec())),
+                Record::TlsKeys(keys) => records.push((0, keys.to_vec())),
+                Record::WireguardKeys(_) => panic!("no WireGuard here"),
+            }
+            Ok(())
+        };
+
+        let mut mapped = Vec::new();
+        let mapped_info =
+            read_capture_file(&path, &mut |_, record| collect(&mut mapped, record)).unwrap();
+        let mut streamed = Vec::new();
+        let streamed_info = read_capture(
+            io::BufReader::new(fs::File::open(&path).unwrap()),
+            &mut |_, record| collect(&mut streamed, record),
+        )
+        .unwrap();
+
+        assert_eq!(mapped, streamed);
+        assert_eq!(format!("{mapped_info:?}"), format!("{streamed_info:?}"));
+        assert!(mapped.iter().any(|(time, _)| *time == 0), "no keys read");
+        assert!(mapped.len() > 10, "only {} records", mapped.len());
+    }
+
+    #[test]
+    fn test_pcapng_sections_and_metadata() {
+        let mut nrb_body = 1u16.to_le_bytes().to_vec();
+        let record = b"\x0a\x00\x00\x01example.com\0www.example.com\0";
+        nrb_body.extend((record.len() as u16).to_le_bytes());
+        nrb_body.extend(pad(record.to_vec()));
+        nrb_body.extend([0; 4]);
+
+        let mut file = shb("first section");
+        // Nanosecond timestamps
+        file.extend(idb(&[(2, b"eth0"), (9, &[9]), (1, b"uplink")]));
+        file.extend(block(4, &nrb_body));
+        file.extend(epb(
+            0,
+            5_000_000_123,
+            b"abcd",
+            4,
+            &[(1, b"request 42 starts here")],
+        ));
+        file.extend(shb("second section"));
+        // Microsecond timestamps, by default, 10 seconds on
+        file.extend(idb(&[(2, b"lo"), (14, &10u64.to_le_bytes())]));
+        file.extend(epb(0, 1_000_001, b"efgh", 60, &[]));
+        // Cut off part way through a block
+        file.extend(&epb(0, 0, b"ijkl", 4, &[])[..10]);
+
+        let (info, frames) = read(&file);
+        assert_eq!(info.format, Some(CaptureFormat::PcapNg));
+        assert_eq!(info.comments, ["first section", "second section"]);
+        let names: Vec<_> = info.interfaces.iter().map(|i| i.name.as_deref()).collect();
+        assert_eq!(names, [Some("eth0"), Some("lo")]);
+        assert_eq!(info.interfaces[0].comments, ["uplink"]);
+        assert_eq!(
+            info.names[&IpAddr::from([10, 0, 0, 1])],
+            ["example.com", "www.example.com"]
+        );
+        assert_eq!(
+            frames,
+            vec![
+                (
+                    0,
+                    5_000_000_123,
+                    b"abcd".to_vec(),
+                    4,
+                    vec!["request 42 starts here".to_string()]
+                ),
+                (1, 11_000_001_000, b"efgh".to_vec(), 60, Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_packet_interface_and_direction() {
+        let mut file = shb("directions");
+        file.extend(idb(&[(2, b"eth0")]));
+        file.extend(idb(&[]));
+        // Outbound
+        file.extend(epb(0, 0, b"abcd", 4, &[(2, &2u32.to_le_bytes())]));
+        // Inbound, broadcast
+        file.extend(epb(1, 0, b"efgh", 4, &[(2, &0b1101u32.to_le_bytes())]));
+        file.extend(epb(0, 0, b"ijkl", 4, &[]));
+
+        let mut seen = Vec::new();
+        read_capture_slice(&file, &mut |_, record| {
+            if let Record::Frame(f) = record {
+                seen.push((f.timing.interface, f.timing.direction));
+            }
+            Ok(())
+        })
+        .unwrap();
+        let eth0 = CaptureInterface {
+            index: 0,
+            name: Some("eth0".into()),
+        };
+        assert_eq!(
+            seen,
+            [
+                (Some(eth0.clone()), Some(PacketDirection::Outgoing)),
+                (
+                    Some(CaptureInterface {
+                        index: 1,
+                        name: None
+                    }),
+                    Some(PacketDirection::Broadcast)
+                ),
+                (Some(eth0), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1060 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture filters: tcpdump-style expressions such as
+//! `tcp port 443 and not host 10.0.0.1`, compiled to classic BPF.
+//!
+//! The same program is attached to live capture sockets, so that the kernel
+//! drops what is not wanted before it is copied to us, and run here by
+//! [`FilteredChomper`] when reading files, so that both see the same
+//! packets.
+//!
+//! What is understood, combined with `and`/`&&`, `or`/`||`, `not`/`!` and
+//! parentheses:
+//!
+//! - `ip`, `ip6`, `arp`, `tcp`, `udp`, `sctp`, `icmp`, `icmp6`
+//! - `[src|dst] host ADDR`, `[src|dst] net ADDR/LEN`
+//! - `[tcp|udp|sctp] [src|dst] port N`, and `portrange N-M` likewise
+//! - `greater N`, `less N`: frame length, as tcpdump has them
+//!
+//! Like tcpdump without `vlan`, VLAN tagged frames are not looked into, and
+//! IPv6 extension headers are not skipped over to find ports.
+
+use std::{fmt, net::IpAddr, path::PathBuf, str::FromStr};
+
+use crate::{
+    capture_merge::MergeInput,
+    chomp::{self, FrameChomper},
+    key_db::{ClientRandom, Secret, SecretType},
+    link::{Linktype, ETHERTYPE_IPV4, ETHERTYPE_IPV6, LINKTYPE_LINUX_SLL2},
+    listener::TimingInfo,
+    Error,
+};
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+const IPPROTO_SCTP: u8 = 132;
+
+/// What accepted packets are cut down to, which is all of them.
+const SNAPLEN: u32 = 0x40000;
+
+// Instruction classes, sizes, modes and operations, from linux/bpf_common.h.
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// One instruction, laid out as `struct sock_filter`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BpfInsn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// A compiled filter, for one link type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BpfProgram(Vec<BpfInsn>);
+
+impl BpfProgram {
+    pub fn instructions(&self) -> &[BpfInsn] {
+        &self.0
+    }
+
+    /// Runs the program on `packet` as the kernel would.
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        run(&self.0, packet) != 0
+    }
+
+    /// Program accepting every packet, keeping the first `snap_len` bytes.
+    pub fn snap_len_only(snap_len: u32) -> BpfProgram {
+        BpfProgram(vec![BpfInsn {
+            code: BPF_RET | BPF_K,
+            jt: 0,
+            jf: 0,
+            k: snap_len,
+        }])
+    }
+
+    /// Keeps only the first `snap_len` bytes of the packets this accepts.
+    /// The kernel still reports how long they were.
+    pub fn with_snap_len(mut self, snap_len: u32) -> BpfProgram {
+        for insn in &mut self.0 {
+            if insn.code == BPF_RET | BPF_K && insn.k != 0 {
+                insn.k = insn.k.min(snap_len);
+            }
+        }
+        self
+    }
+}
+
+/// Interprets `program`, returning how much of the packet to keep. Loads
+/// past the end of the packet and anything we don't implement drop it.
+fn run(program: &[BpfInsn], packet: &[u8]) -> u32 {
+    let load = |at: u32, size: u16| -> Option<u32> {
+        let at = at as usize;
+        Some(match size {
+            BPF_W => u32::from_be_bytes(packet.get(at..at.checked_add(4)?)?.try_into().unwrap()),
+            BPF_H => {
+                u16::from_be_bytes(packet.get(at..at.checked_add(2)?)?.try_into().unwrap()) as u32
+            }
+            BPF_B => *packet.get(at)? as u32,
+            _ => return None,
+        })
+    };
+
+    let (mut a, mut x) = (0u32, 0u32);
+    let mut pc = 0;
+    loop {
+        let Some(&BpfInsn { code, jt, jf, k }) = program.get(pc) else {
+            return 0;
+        };
+        pc += 1;
+        match code & 0x07 {
+            BPF_LD => {
+                let loaded = match code & 0xe0 {
+                    BPF_IMM => Some(k),
+                    BPF_ABS => load(k, code & 0x18),
+                    BPF_IND => load(x.wrapping_add(k), code & 0x18),
+                    BPF_LEN => Some(packet.len() as u32),
+                    _ => None,
+                };
+                let Some(loaded) = loaded else { return 0 };
+                a = loaded;
+            }
+            BPF_LDX => {
+                let loaded = match code & 0xe0 {
+                    BPF_IMM => Some(k),
+                    BPF_LEN => Some(packet.len() as u32),
+                    BPF_MSH => load(k, BPF_B).map(|b| (b & 0xf) * 4),
+                    _ => None,
+                };
+                let Some(loaded) = loaded else { return 0 };
+                x = loaded;
+            }
+            BPF_ALU => {
+                let operand = if code & BPF_X != 0 { x } else { k };
+                a = match code & 0xf0 {
+                    BPF_ADD => a.wrapping_add(operand),
+                    BPF_SUB => a.wrapping_sub(operand),
+                    BPF_OR => a | operand,
+                    BPF_AND => a & operand,
+                    BPF_LSH => a.checked_shl(operand).unwrap_or(0),
+                    BPF_RSH => a.checked_shr(operand).unwrap_or(0),
+                    _ => return 0,
+                };
+            }
+            BPF_JMP => {
+                let operand = if code & BPF_X != 0 { x } else { k };
+                let taken = match code & 0xf0 {
+                    BPF_JA => {
+                        pc += k as usize;
+                        continue;
+                    }
+                    BPF_JEQ => a == operand,
+                    BPF_JGT => a > operand,
+                    BPF_JGE => a >= operand,
+                    BPF_JSET => a & operand != 0,
+                    _ => return 0,
+                };
+                pc += (if taken { jt } else { jf }) as usize;
+            }
+            BPF_RET => {
+                return match code & 0x18 {
+                    BPF_K => k,
+                    BPF_A => a,
+                    _ => 0,
+                }
+            }
+            BPF_MISC => match code & 0xf8 {
+                BPF_TAX => x = a,
+                BPF_TXA => a = x,
+                _ => return 0,
+            },
+            _ => return 0,
+        }
+    }
+}
+
+/// A single test on a packet, which needs whatever it depends on (e.g.
+/// being IPv4) checked before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Atom {
+    EtherType(u16),
+    Ip4Proto(u8),
+    Ip6Next(u8),
+    /// Not a later fragment, which would have no ports.
+    Ip4FirstFragment,
+    Ip4Addr {
+        dst: bool,
+        net: u32,
+        mask: u32,
+    },
+    Ip6Addr {
+        dst: bool,
+        net: u128,
+        mask: u128,
+    },
+    Ip4Port {
+        dst: bool,
+        lo: u16,
+        hi: u16,
+    },
+    Ip6Port {
+        dst: bool,
+        lo: u16,
+        hi: u16,
+    },
+    LenAtLeast(u32),
+    LenAtMost(u32),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Atom(Atom),
+}
+
+fn and(a: Node, b: Node) -> Node {
+    Node::And(Box::new(a), Box::new(b))
+}
+
+fn or(a: Node, b: Node) -> Node {
+    Node::Or(Box::new(a), Box::new(b))
+}
+
+fn any(nodes: impl IntoIterator<Item = Node>) -> Node {
+    nodes.into_iter().reduce(or).expect("any of nothing")
+}
+
+fn ether(ethertype: u16) -> Node {
+    Node::Atom(Atom::EtherType(ethertype))
+}
+
+/// `src`, `dst`, or either if not given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dir {
+    Src,
+    Dst,
+}
+
+fn either_end(dir: Option<Dir>, atom: impl Fn(bool) -> Atom) -> Node {
+    match dir {
+        Some(Dir::Src) => Node::Atom(atom(false)),
+        Some(Dir::Dst) => Node::Atom(atom(true)),
+        None => or(Node::Atom(atom(false)), Node::Atom(atom(true))),
+    }
+}
+
+fn proto(proto: u8) -> Node {
+    match proto {
+        IPPROTO_ICMP => and(ether(ETHERTYPE_IPV4), Node::Atom(Atom::Ip4Proto(proto))),
+        IPPROTO_ICMPV6 => and(ether(ETHERTYPE_IPV6), Node::Atom(Atom::Ip6Next(proto))),
+        _ => or(
+            and(ether(ETHERTYPE_IPV4), Node::Atom(Atom::Ip4Proto(proto))),
+            and(ether(ETHERTYPE_IPV6), Node::Atom(Atom::Ip6Next(proto))),
+        ),
+    }
+}
+
+fn net(dir: Option<Dir>, addr: IpAddr, prefix: u8) -> Node {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            let net = u32::from(addr);
+            and(
+                ether(ETHERTYPE_IPV4),
+                either_end(dir, |dst| Atom::Ip4Addr { dst, net, mask }),
+            )
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            let net = u128::from(addr);
+            and(
+                ether(ETHERTYPE_IPV6),
+                either_end(dir, |dst| Atom::Ip6Addr { dst, net, mask }),
+            )
+        }
+    }
+}
+
+fn port(dir: Option<Dir>, protos: &[u8], lo: u16, hi: u16) -> Node {
+    let v4 = and(
+        ether(ETHERTYPE_IPV4),
+        and(
+            any(protos.iter().map(|&p| Node::Atom(Atom::Ip4Proto(p)))),
+            and(
+                Node::Atom(Atom::Ip4FirstFragment),
+                either_end(dir, |dst| Atom::Ip4Port { dst, lo, hi }),
+            ),
+        ),
+    );
+    let v6 = and(
+        ether(ETHERTYPE_IPV6),
+        and(
+            any(protos.iter().map(|&p| Node::Atom(Atom::Ip6Next(p)))),
+            either_end(dir, |dst| Atom::Ip6Port { dst, lo, hi }),
+        ),
+    );
+    or(v4, v6)
+}
+
+fn tokenize(expr: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '(' | ')' => c.to_string(),
+            '!' => "!".to_string(),
+            '&' | '|' if chars.peek() == Some(&c) => {
+                chars.next();
+                format!("{c}{c}")
+            }
+            c if c.is_whitespace() => String::new(),
+            c => {
+                word.push(c);
+                continue;
+            }
+        };
+        if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+        if !token.is_empty() {
+            tokens.push(token);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.at).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str, Error> {
+        let token = self.tokens.get(self.at).ok_or("filter ends too soon")?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, words: &[&str]) -> bool {
+        if self.peek().is_some_and(|t| words.contains(&t)) {
+            self.at += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<Node, Error> {
+        let mut node = self.and()?;
+        while self.eat(&["or", "||"]) {
+            node = or(node, self.and()?);
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, Error> {
+        let mut node = self.unary()?;
+        while self.eat(&["and", "&&"]) {
+            node = and(node, self.unary()?);
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, Error> {
+        if self.eat(&["not", "!"]) {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&["("]) {
+            let node = self.expr()?;
+            if !self.eat(&[")"]) {
+                return Err("missing `)` in filter".into());
+            }
+            return Ok(node);
+        }
+        self.primitive()
+    }
+
+    fn primitive(&mut self) -> Result<Node, Error> {
+        let transport = match self.peek() {
+            Some("tcp") => Some(IPPROTO_TCP),
+            Some("udp") => Some(IPPROTO_UDP),
+            Some("sctp") => Some(IPPROTO_SCTP),
+            _ => None,
+        };
+        if let Some(p) = transport {
+            self.at += 1;
+            // `tcp port 80` rather than just `tcp`.
+            let is_port = |t: Option<&str>| matches!(t, Some("port" | "portrange"));
+            let qualified = matches!(self.peek(), Some("src" | "dst"))
+                && is_port(self.tokens.get(self.at + 1).map(String::as_str));
+            if !(qualified || is_port(self.peek())) {
+                return Ok(proto(p));
+            }
+            return self.qualified(&[p]);
+        }
+
+        let node = match self.peek() {
+            Some("ip") => ether(ETHERTYPE_IPV4),
+            Some("ip6") => ether(ETHERTYPE_IPV6),
+            Some("arp") => ether(ETHERTYPE_ARP),
+            Some("icmp") => proto(IPPROTO_ICMP),
+            Some("icmp6") => proto(IPPROTO_ICMPV6),
+            Some("greater") => {
+                self.at += 1;
+                return Ok(Node::Atom(Atom::LenAtLeast(self.number("greater")?)));
+            }
+            Some("less") => {
+                self.at += 1;
+                return Ok(Node::Atom(Atom::LenAtMost(self.number("less")?)));
+            }
+            _ => return self.qualified(&[IPPROTO_TCP, IPPROTO_UDP, IPPROTO_SCTP]),
+        };
+        self.at += 1;
+        Ok(node)
+    }
+
+    /// `[src|dst] host/net/port/portrange VALUE`, with ports of `protos`.
+    fn qualified(&mut self, protos: &[u8]) -> Result<Node, Error> {
+        let dir = if self.eat(&["src"]) {
+            Some(Dir::Src)
+        } else if self.eat(&["dst"]) {
+            Some(Dir::Dst)
+        } else {
+            None
+        };
+        let kind = self.next()?.to_owned();
+        match kind.as_str() {
+            "host" => {
+                let value = self.next()?;
+                let addr: IpAddr = value
+                    .parse()
+                    .map_err(|_| format!("bad host address {value:?} in filter"))?;
+                let prefix = if addr.is_ipv4() { 32 } else { 128 };
+                Ok(net(dir, addr, prefix))
+            }
+            "net" => {
+                let value = self.next()?.to_owned();
+                let (addr, prefix) = parse_net(&value)?;
+                Ok(net(dir, addr, prefix))
+            }
+            "port" => {
+                let n = self.number("port")?;
+                let n = u16::try_from(n).map_err(|_| format!("bad port {n}"))?;
+                Ok(port(dir, protos, n, n))
+            }
+            "portrange" => {
+                let value = self.next()?;
+                let range = value
+                    .split_once('-')
+                    .and_then(|(lo, hi)| Some((lo.parse().ok()?, hi.parse().ok()?)))
+                    .filter(|(lo, hi): &(u16, u16)| lo <= hi);
+                let Some((lo, hi)) = range else {
+                    return Err(format!("bad port range {value:?}, expected e.g. 8000-8080").into());
+                };
+                Ok(port(dir, protos, lo, hi))
+            }
+            other => Err(format!("unknown filter primitive {other:?}").into()),
+        }
+    }
+
+    fn number(&mut self, what: &str) -> Result<u32, Error> {
+        let value = self.next()?;
+        value
+            .parse()
+            .map_err(|_| format!("bad number {value:?} after {what}").into())
+    }
+}
+
+fn parse_net(value: &str) -> Result<(IpAddr, u8), Error> {
+    let bad = || -> Error { format!("bad network {value:?} in filter").into() };
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (value, None),
+    };
+    let addr: IpAddr = addr.parse().map_err(|_| bad())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(p) => p.parse::<u8>().ok().filter(|&p| p <= max).ok_or_else(bad)?,
+        None => max,
+    };
+    let host_bits = match addr {
+        IpAddr::V4(a) => u32::from(a) & !u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0) != 0,
+        IpAddr::V6(a) => {
+            u128::from(a) & !u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0) != 0
+        }
+    };
+    if host_bits {
+        return Err(format!("network {value:?} has bits set past its prefix").into());
+    }
+    Ok((addr, prefix))
+}
+
+/// Where things are in frames of a link type.
+#[derive(Clone, Copy, Debug)]
+enum Layout {
+    /// An ethertype at `ethertype`, and the network header at `network`.
+    EtherType { ethertype: u32, network: u32 },
+    /// Bare IP, told apart by the version.
+    Raw,
+}
+
+impl Layout {
+    fn of(link_type: Linktype) -> Option<Layout> {
+        Some(match link_type {
+            Linktype::ETHERNET => Layout::EtherType {
+                ethertype: 12,
+                network: 14,
+            },
+            Linktype::LINUX_SLL => Layout::EtherType {
+                ethertype: 14,
+                network: 16,
+            },
+            LINKTYPE_LINUX_SLL2 => Layout::EtherType {
+                ethertype: 0,
+                network: 20,
+            },
+            Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => Layout::Raw,
+            _ => return None,
+        })
+    }
+
+    fn network(&self) -> u32 {
+        match *self {
+            Layout::EtherType { network, .. } => network,
+            Layout::Raw => 0,
+        }
+    }
+}
+
+type Label = usize;
+
+#[derive(Clone, Copy)]
+enum Jump {
+    None,
+    Always(Label),
+    Cond(Label, Label),
+}
+
+struct Codegen {
+    layout: Layout,
+    insns: Vec<(BpfInsn, Jump)>,
+    /// Where each label has been placed.
+    labels: Vec<Option<usize>>,
+}
+
+impl Codegen {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: Label) {
+        self.labels[label] = Some(self.insns.len());
+    }
+
+    fn stmt(&mut self, code: u16, k: u32) {
+        let insn = BpfInsn {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        self.insns.push((insn, Jump::None));
+    }
+
+    fn jump(&mut self, op: u16, k: u32, t: Label, f: Label) {
+        let insn = BpfInsn {
+            code: BPF_JMP | op | BPF_K,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        self.insns.push((insn, Jump::Cond(t, f)));
+    }
+
+    fn always(&mut self, to: Label) {
+        let insn = BpfInsn {
+            code: BPF_JMP | BPF_JA,
+            jt: 0,
+            jf: 0,
+            k: 0,
+        };
+        self.insns.push((insn, Jump::Always(to)));
+    }
+
+    /// Jumps to `t` if `node` matches, otherwise to `f`.
+    fn node(&mut self, node: &Node, t: Label, f: Label) {
+        match node {
+            Node::And(a, b) => {
+                let next = self.label();
+                self.node(a, next, f);
+                self.place(next);
+                self.node(b, t, f);
+            }
+            Node::Or(a, b) => {
+                let next = self.label();
+                self.node(a, t, next);
+                self.place(next);
+                self.node(b, t, f);
+            }
+            Node::Not(a) => self.node(a, f, t),
+            Node::Atom(atom) => self.atom(atom, t, f),
+        }
+    }
+
+    /// Compares the accumulator with `lo..=hi`.
+    fn range(&mut self, lo: u16, hi: u16, t: Label, f: Label) {
+        if lo == hi {
+            self.jump(BPF_JEQ, lo as u32, t, f);
+        } else {
+            let next = self.label();
+            self.jump(BPF_JGE, lo as u32, next, f);
+            self.place(next);
+            self.jump(BPF_JGT, hi as u32, f, t);
+        }
+    }
+
+    fn atom(&mut self, atom: &Atom, t: Label, f: Label) {
+        let nh = self.layout.network();
+        match *atom {
+            Atom::EtherType(ethertype) => match self.layout {
+                Layout::EtherType { ethertype: at, .. } => {
+                    self.stmt(BPF_LD | BPF_H | BPF_ABS, at);
+                    self.jump(BPF_JEQ, ethertype as u32, t, f);
+                }
+                Layout::Raw => {
+                    let version = match ethertype {
+                        ETHERTYPE_IPV4 => 0x40,
+                        ETHERTYPE_IPV6 => 0x60,
+                        _ => return self.always(f),
+                    };
+                    self.stmt(BPF_LD | BPF_B | BPF_ABS, 0);
+                    self.stmt(BPF_ALU | BPF_AND | BPF_K, 0xf0);
+                    self.jump(BPF_JEQ, version, t, f);
+                }
+            },
+            Atom::Ip4Proto(proto) => {
+                self.stmt(BPF_LD | BPF_B | BPF_ABS, nh + 9);
+                self.jump(BPF_JEQ, proto as u32, t, f);
+            }
+            Atom::Ip6Next(proto) => {
+                self.stmt(BPF_LD | BPF_B | BPF_ABS, nh + 6);
+                self.jump(BPF_JEQ, proto as u32, t, f);
+            }
+            Atom::Ip4FirstFragment => {
+                self.stmt(BPF_LD | BPF_H | BPF_ABS, nh + 6);
+                self.jump(BPF_JSET, 0x1fff, f, t);
+            }
+            Atom::Ip4Addr { dst, net, mask } => {
+                self.stmt(BPF_LD | BPF_W | BPF_ABS, nh + if dst { 16 } else { 12 });
+                if mask != u32::MAX {
+                    self.stmt(BPF_ALU | BPF_AND | BPF_K, mask);
+                }
+                self.jump(BPF_JEQ, net & mask, t, f);
+            }
+            Atom::Ip6Addr { dst, net, mask } => {
+                let at = nh + if dst { 24 } else { 8 };
+                let words: Vec<_> = (0..4)
+                    .map(|i| {
+                        let shift = 96 - 32 * i;
+                        (i, (net >> shift) as u32, (mask >> shift) as u32)
+                    })
+                    .filter(|&(_, _, mask)| mask != 0)
+                    .collect();
+                if words.is_empty() {
+                    return self.always(t);
+                }
+                for (n, &(i, net, mask)) in words.iter().enumerate() {
+                    self.stmt(BPF_LD | BPF_W | BPF_ABS, at + 4 * i as u32);
+                    if mask != u32::MAX {
+                        self.stmt(BPF_ALU | BPF_AND | BPF_K, mask);
+                    }
+                    if n + 1 == words.len() {
+                        self.jump(BPF_JEQ, net & mask, t, f);
+                    } else {
+                        let next = self.label();
+                        self.jump(BPF_JEQ, net & mask, next, f);
+                        self.place(next);
+                    }
+                }
+            }
+            Atom::Ip4Port { dst, lo, hi } => {
+                // X = IPv4 header length.
+                self.stmt(BPF_LDX | BPF_B | BPF_MSH, nh);
+                self.stmt(BPF_LD | BPF_H | BPF_IND, nh + if dst { 2 } else { 0 });
+                self.range(lo, hi, t, f);
+            }
+            Atom::Ip6Port { dst, lo, hi } => {
+                self.stmt(BPF_LD | BPF_H | BPF_ABS, nh + 40 + if dst { 2 } else { 0 });
+                self.range(lo, hi, t, f);
+            }
+            Atom::LenAtLeast(len) => {
+                self.stmt(BPF_LD | BPF_W | BPF_LEN, 0);
+                self.jump(BPF_JGE, len, t, f);
+            }
+            Atom::LenAtMost(len) => {
+                self.stmt(BPF_LD | BPF_W | BPF_LEN, 0);
+                self.jump(BPF_JGT, len, f, t);
+            }
+        }
+    }
+
+    fn finish(self) -> Result<BpfProgram, Error> {
+        let offset = |from: usize, to: Label| -> usize {
+            // Labels are only ever placed after what jumps to them.
+            self.labels[to].expect("unplaced label") - (from + 1)
+        };
+        let too_long = || -> Error { "filter is too long to compile".into() };
+        let mut program = Vec::with_capacity(self.insns.len());
+        for (pc, &(mut insn, jump)) in self.insns.iter().enumerate() {
+            match jump {
+                Jump::None => {}
+                Jump::Always(to) => insn.k = offset(pc, to) as u32,
+                Jump::Cond(t, f) => {
+                    insn.jt = u8::try_from(offset(pc, t)).map_err(|_| too_long())?;
+                    insn.jf = u8::try_from(offset(pc, f)).map_err(|_| too_long())?;
+                }
+            }
+            program.push(insn);
+        }
+        Ok(BpfProgram(program))
+    }
+}
+
+/// A parsed filter expression. See the [module docs](self) for what can be
+/// in it.
+#[derive(Clone, Debug)]
+pub struct CaptureFilter {
+    expr: String,
+    root: Node,
+}
+
+impl FromStr for CaptureFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            at: 0,
+        };
+        let root = parser.expr()?;
+        if let Some(extra) = parser.peek() {
+            return Err(format!("unexpected {extra:?} in filter").into());
+        }
+        Ok(CaptureFilter {
+            expr: s.to_owned(),
+            root,
+        })
+    }
+}
+
+impl fmt::Display for CaptureFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expr)
+    }
+}
+
+impl CaptureFilter {
+    /// Compiles the filter for frames of `link_type`.
+    pub fn compile(&self, link_type: Linktype) -> Result<BpfProgram, Error> {
+        let layout = Layout::of(link_type)
+            .ok_or_else(|| format!("capture filters don't support link type {link_type:?}"))?;
+        let mut codegen = Codegen {
+            layout,
+            insns: Vec::new(),
+            labels: Vec::new(),
+        };
+        let (accept, reject) = (codegen.label(), codegen.label());
+        codegen.node(&self.root, accept, reject);
+        codegen.place(accept);
+        codegen.stmt(BPF_RET | BPF_K, SNAPLEN);
+        codegen.place(reject);
+        codegen.stmt(BPF_RET | BPF_K, 0);
+        codegen.finish()
+    }
+}
+
+/// Passes on only the frames a [`CaptureFilter`] matches, for reading
+/// files. Frames of link types the filter can't be compiled for are all
+/// passed on.
+pub struct FilteredChomper<C> {
+    filter: CaptureFilter,
+    /// Compiled for each link type seen so far.
+    programs: Vec<(Linktype, Option<BpfProgram>)>,
+    next: C,
+}
+
+impl<C> FilteredChomper<C> {
+    pub fn new(filter: CaptureFilter, next: C) -> Self {
+        FilteredChomper {
+            filter,
+            programs: Vec::new(),
+            next,
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.next
+    }
+}
+
+impl<C: FrameChomper> FrameChomper for FilteredChomper<C> {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        let at = match self.programs.iter().position(|(lt, _)| *lt == link_type) {
+            Some(at) => at,
+            None => {
+                let program = self
+                    .filter
+                    .compile(link_type)
+                    .map_err(|e| tracing::warn!("not filtering: {e}"))
+                    .ok();
+                self.programs.push((link_type, program));
+                self.programs.len() - 1
+            }
+        };
+        match &self.programs[at].1 {
+            Some(program) if !program.matches(packet) => Ok(()),
+            _ => self.next.chomp(timing, link_type, packet),
+        }
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        self.next.on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.next.on_wireguard_keys(key_log)
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.next.on_key(client_random, secret_type, secret)
+    }
+}
+
+/// Reads a pcapng file into `chomper` like [`chomp::dump_pcap_file`], but
+/// only the frames `filter` matches, if there is one.
+pub fn dump_pcap_file(
+    file: PathBuf,
+    filter: Option<CaptureFilter>,
+    chomper: &mut dyn FrameChomper,
+) -> Result<(), Error> {
+    match filter {
+        Some(filter) => chomp::dump_pcap_file(file, &mut FilteredChomper::new(filter, chomper)),
+        None => chomp::dump_pcap_file(file, chomper),
+    }
+}
+
+/// Reads several capture files into `chomper` as one, like
+/// [`chomp::dump_merged_pcaps`], but only the frames `filter` matches, if
+/// there is one.
+pub fn dump_merged_pcaps(
+    inputs: Vec<MergeInput>,
+    filter: Option<CaptureFilter>,
+    chomper: &mut dyn FrameChomper,
+) -> Result<(), Error> {
+    match filter {
+        Some(filter) => {
+            chomp::dump_merged_pcaps(inputs, &mut FilteredChomper::new(filter, chomper))
+        }
+        None => chomp::dump_merged_pcaps(inputs, chomper),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend(ethertype.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    fn ipv4(proto: u8, src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        let mut ip = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, proto, 0, 0];
+        ip.extend(src);
+        ip.extend(dst);
+        ip.extend(sport.to_be_bytes());
+        ip.extend(dport.to_be_bytes());
+        ip.extend([0; 16]);
+        ethernet(ETHERTYPE_IPV4, &ip)
+    }
+
+    fn ipv6(proto: u8, src: &str, dst: &str, sport: u16, dport: u16) -> Vec<u8> {
+        let mut ip = vec![0x60, 0, 0, 0, 0, 8, proto, 64];
+        ip.extend(src.parse::<std::net::Ipv6Addr>().unwrap().octets());
+        ip.extend(dst.parse::<std::net::Ipv6Addr>().unwrap().octets());
+        ip.extend(sport.to_be_bytes());
+        ip.extend(dport.to_be_bytes());
+        ip.extend([0; 4]);
+        ethernet(ETHERTYPE_IPV6, &ip)
+    }
+
+    fn matches(expr: &str, frame: &[u8]) -> bool {
+        let filter: CaptureFilter = expr.parse().unwrap();
+        filter.compile(Linktype::ETHERNET).unwrap().matches(frame)
+    }
+
+    #[test]
+    fn test_filter_expressions() {
+        let https = ipv4(IPPROTO_TCP, [10, 0, 0, 1], [192, 168, 1, 2], 40000, 443);
+        let dns = ipv4(IPPROTO_UDP, [10, 0, 0, 1], [10, 0, 0, 53], 40000, 53);
+        let v6 = ipv6(IPPROTO_TCP, "fd00::1", "2001:db8::2", 40000, 8080);
+
+        assert!(matches("tcp", &https));
+        assert!(!matches("udp", &https));
+        assert!(matches("ip and not ip6", &https));
+        assert!(matches("tcp port 443", &https));
+        assert!(!matches("udp port 443", &https));
+        assert!(matches("dst port 443", &https));
+        assert!(!matches("src port 443", &https));
+        assert!(matches("host 192.168.1.2", &https));
+        assert!(matches(
+            "src net 10.0.0.0/8 && dst host 192.168.1.2",
+            &https
+        ));
+        assert!(!matches("dst net 10.0.0.0/8", &https));
+        assert!(matches("portrange 400-500", &https));
+        assert!(!matches("portrange 444-500", &https));
+
+        assert!(matches("udp and (port 53 or port 5353)", &dns));
+        assert!(!matches("!(udp port 53)", &dns));
+        assert!(matches("tcp port 443 or udp port 53", &dns));
+
+        assert!(matches("ip6 and tcp port 8080", &v6));
+        assert!(matches("src net fd00::/8", &v6));
+        assert!(matches("dst host 2001:db8::2", &v6));
+        assert!(!matches("host 2001:db8::3", &v6));
+        assert!(!matches("host 10.0.0.1", &v6));
+        assert!(matches("portrange 8000-8080", &v6));
+
+        assert!(matches("greater 54", &https));
+        assert!(!matches("less 53", &https));
+        assert!(matches("arp", &ethernet(ETHERTYPE_ARP, &[0; 28])));
+        // Too short to have the addresses.
+        assert!(!matches("host 10.0.0.1", &https[..20]));
+    }
+
+    #[test]
+    fn test_snap_len() {
+        let https = ipv4(IPPROTO_TCP, [10, 0, 0, 1], [192, 168, 1, 2], 40000, 443);
+        let filter: CaptureFilter = "tcp port 443".parse().unwrap();
+        let program = filter
+            .compile(Linktype::ETHERNET)
+            .unwrap()
+            .with_snap_len(34);
+        assert_eq!(run(program.instructions(), &https), 34);
+        let dns = ipv4(IPPROTO_UDP, [10, 0, 0, 1], [10, 0, 0, 53], 40000, 53);
+        assert_eq!(run(program.instructions(), &dns), 0);
+
+        let program = BpfProgram::snap_len_only(14);
+        assert_eq!(run(program.instructions(), &dns), 14);
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        for bad in [
+            "",
+            "port",
+            "port 99999",
+            "host example.com",
+            "net 10.0.0.1/8",
+            "(tcp",
+            "tcp)",
+            "portrange 90-80",
+            "frobnicate",
+        ] {
+            assert!(bad.parse::<CaptureFilter>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn test_raw_link_type() {
+        let filter: CaptureFilter = "tcp port 443".parse().unwrap();
+        let program = filter.compile(Linktype::RAW).unwrap();
+        let frame = ipv4(IPPROTO_TCP, [10, 0, 0, 1], [10, 0, 0, 2], 40000, 443);
+        assert!(program.matches(&frame[14..]));
+        let frame = ipv4(IPPROTO_TCP, [10, 0, 0, 1], [10, 0, 0, 2], 40000, 80);
+        assert!(!program.matches(&frame[14..]));
+    }
+
+    #[test]
+    fn test_filtered_chomper() {
+        #[derive(Default)]
+        struct Count(usize);
+
+        impl FrameChomper for Count {
+            fn chomp(&mut self, _: TimingInfo, _: Linktype, _: &[u8]) -> Result<(), Error> {
+                self.0 += 1;
+                Ok(())
+            }
+            fn on_keys(&mut self, _dsb: &[u8]) {}
+            fn on_wireguard_keys(&mut self, _key_log: &[u8]) {}
+            fn on_key(&mut self, _: ClientRandom, _: SecretType, _: Secret) {}
+        }
+
+        let mut chomper = FilteredChomper::new("port 53".parse().unwrap(), Count::default());
+        for dport in [53, 443, 53] {
+            let frame = ipv4(IPPROTO_UDP, [10, 0, 0, 1], [10, 0, 0, 2], 40000, dport);
+            chomper
+                .chomp(TimingInfo::default(), Linktype::ETHERNET, &frame)
+                .unwrap();
+        }
+        // Not one the filter can be compiled for, so it is let through.
+        chomper
+            .chomp(TimingInfo::default(), Linktype(147), &[0; 10])
+            .unwrap();
+        assert_eq!(chomper.into_inner().0, 3);
+    }
+}
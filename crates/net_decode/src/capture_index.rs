@@ -0,0 +1,749 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! An index kept next to a capture file, so that part of a huge capture can
+//! be read again without reading everything before it.
+//!
+//! The index is written as `FILE.clipper-index` the first time a capture of
+//! more than [`AUTO_INDEX_OVER`] bytes is sliced (see
+//! [`capture_slice`](crate::capture_slice)), or by `clipper index`, and is
+//! built again if the capture's length or modification time change. It
+//! holds where each packet is and when it was captured, the TCP and UDP
+//! flows they are part of, where each request in a TCP flow starts, and the
+//! keys embedded in the capture.
+//!
+//! Requests are told apart without decoding anything, as the client sending
+//! again after the server has, which is where each request starts in HTTP/1
+//! and most other request-response protocols, encrypted or not. HTTP/2
+//! sends requests while others are being answered, so its connections look
+//! like they carried fewer than they did.
+//!
+//! The format, with every number little-endian:
+//!
+//! ```text
+//! magic (8) | capture length (8) | capture modified, ns since the epoch (8)
+//! interface count (4) | link type (4) ...
+//! key log count (4) | kind (1) | length (4) | key log ...
+//! flow count (4) | proto (1) | IP version (1) | client IP (16) | server IP (16)
+//!     | client port (2) | server port (2) | first packet (8) | last packet (8)
+//!     | packets (8) | bytes (8) ...
+//! packet count (8) | data offset (8) | time (8) | captured length (4)
+//!     | original length (4) | interface (4) | flow (4) ...
+//! request count (8) | flow (4) | packet (8) ...
+//! ```
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use pcap_parser::Linktype;
+
+use crate::{
+    capture_file::{read_capture_file, Record},
+    capture_slice::{CaptureSlice, SliceBound},
+    checkpoint::ReplayGate,
+    chomp::{transport_segment, FrameChomper, IPTarget, Segment, IPPROTO_TCP},
+    listener::{CaptureInterface, Nanos, TimingInfo},
+    mapped_file::MappedFile,
+    Error,
+};
+
+/// Size of capture past which an index is built when the capture is first
+/// sliced.
+pub const AUTO_INDEX_OVER: u64 = 1024 * 1024 * 1024;
+
+const MAGIC: &[u8; 8] = b"CLPINDX\x01";
+const NO_FLOW: u32 = u32::MAX;
+
+const KEYS_TLS: u8 = 0;
+const KEYS_WIREGUARD: u8 = 1;
+
+/// A key log embedded in the capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IndexedKeys {
+    Tls(Vec<u8>),
+    Wireguard(Vec<u8>),
+}
+
+/// A TCP or UDP flow, with the sender of its first packet as the client.
+/// Later connections on the same addresses and ports are part of it too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedFlow {
+    pub proto: u8,
+    pub target: IPTarget,
+    /// Packet numbers, counting from 1.
+    pub first_packet: u64,
+    pub last_packet: u64,
+    pub packets: u64,
+    /// Of whole frames, as captured.
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexedPacket {
+    /// Where the packet data is in the capture.
+    pub offset: u64,
+    pub time: Nanos,
+    pub captured_len: u32,
+    pub original_len: u32,
+    /// Index into [`CaptureIndex::link_types`].
+    pub interface: u32,
+    /// Index into [`CaptureIndex::flows`].
+    pub flow: Option<u32>,
+}
+
+/// Where a request starts: the first packet the client sent it in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexedRequest {
+    pub flow: u32,
+    pub packet: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaptureIndex {
+    capture_len: u64,
+    capture_modified: Nanos,
+    /// Of each interface in the capture.
+    pub link_types: Vec<Linktype>,
+    pub keys: Vec<IndexedKeys>,
+    pub flows: Vec<IndexedFlow>,
+    /// Packet number `n` is at `n - 1`.
+    pub packets: Vec<IndexedPacket>,
+    /// In order of packet number.
+    pub requests: Vec<IndexedRequest>,
+}
+
+/// Where the index of the capture at `path` is kept.
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".clipper-index");
+    name.into()
+}
+
+/// Length and modification time of the capture at `path`, to tell whether
+/// an index of it is out of date.
+fn capture_version(path: &Path) -> Result<(u64, Nanos), Error> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |m| m.as_nanos() as Nanos);
+    Ok((metadata.len(), modified))
+}
+
+#[derive(Default)]
+struct Builder {
+    index: CaptureIndex,
+    flow_ids: HashMap<(u8, IPTarget), u32>,
+    /// Whether the client was the last to send data, for each flow.
+    client_sent_last: Vec<Option<bool>>,
+}
+
+impl Builder {
+    fn on_record(&mut self, record: Record<'_>) {
+        match record {
+            Record::Frame(frame) => {
+                let number = self.index.packets.len() as u64 + 1;
+                let flow = transport_segment(frame.link_type, frame.data)
+                    .map(|segment| self.on_segment(number, frame.data.len(), segment));
+                self.index.packets.push(IndexedPacket {
+                    offset: frame.offset,
+                    time: frame.timing.received_on_wire,
+                    captured_len: frame.data.len() as u32,
+                    original_len: frame.original_len,
+                    interface: frame.interface as u32,
+                    flow,
+                });
+            }
+            Record::TlsKeys(keys) => self.index.keys.push(IndexedKeys::Tls(keys.to_vec())),
+            Record::WireguardKeys(keys) => {
+                self.index.keys.push(IndexedKeys::Wireguard(keys.to_vec()))
+            }
+        }
+    }
+
+    /// Adds a packet to its flow, returning the flow's index.
+    fn on_segment(&mut self, number: u64, len: usize, segment: Segment) -> u32 {
+        let (id, from_client) =
+            if let Some(&id) = self.flow_ids.get(&(segment.proto, segment.target)) {
+                (id, true)
+            } else if let Some(&id) = self.flow_ids.get(&(segment.proto, segment.target.flip())) {
+                (id, false)
+            } else {
+                let id = self.index.flows.len() as u32;
+                self.flow_ids.insert((segment.proto, segment.target), id);
+                self.index.flows.push(IndexedFlow {
+                    proto: segment.proto,
+                    target: segment.target,
+                    first_packet: number,
+                    last_packet: number,
+                    packets: 0,
+                    bytes: 0,
+                });
+                self.client_sent_last.push(None);
+                (id, true)
+            };
+
+        let flow = &mut self.index.flows[id as usize];
+        flow.last_packet = number;
+        flow.packets += 1;
+        flow.bytes += len as u64;
+        if segment.proto == IPPROTO_TCP && segment.payload_len > 0 {
+            let client_sent_last = &mut self.client_sent_last[id as usize];
+            if from_client && *client_sent_last != Some(true) {
+                self.index.requests.push(IndexedRequest {
+                    flow: id,
+                    packet: number,
+                });
+            }
+            *client_sent_last = Some(from_client);
+        }
+        id
+    }
+}
+
+/// Reads numbers out of an index file.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err("capture index is cut off".into());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A count of things each at least `min_len` long, checked against what
+    /// is left so that a bad one can't allocate everything.
+    fn count(&mut self, wide: bool, min_len: usize) -> Result<usize, Error> {
+        let count = if wide {
+            self.u64()?
+        } else {
+            self.u32()? as u64
+        };
+        if count.saturating_mul(min_len as u64) > self.0.len() as u64 {
+            return Err("capture index is cut off".into());
+        }
+        Ok(count as usize)
+    }
+}
+
+fn write_flow(out: &mut impl Write, flow: &IndexedFlow) -> io::Result<()> {
+    let (version, client_ip, server_ip) = match flow.target {
+        IPTarget::V4 {
+            client_ip,
+            server_ip,
+            ..
+        } => (4u8, client_ip.to_ipv6_mapped(), server_ip.to_ipv6_mapped()),
+        IPTarget::V6 {
+            client_ip,
+            server_ip,
+            ..
+        } => (6u8, client_ip, server_ip),
+    };
+    out.write_all(&[flow.proto, version])?;
+    out.write_all(&client_ip.octets())?;
+    out.write_all(&server_ip.octets())?;
+    out.write_all(&flow.target.client_port().to_le_bytes())?;
+    out.write_all(&flow.target.server_port().to_le_bytes())?;
+    for n in [
+        flow.first_packet,
+        flow.last_packet,
+        flow.packets,
+        flow.bytes,
+    ] {
+        out.write_all(&n.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_flow(input: &mut Input<'_>) -> Result<IndexedFlow, Error> {
+    let proto = input.u8()?;
+    let version = input.u8()?;
+    let client_ip = Ipv6Addr::from(<[u8; 16]>::try_from(input.take(16)?).unwrap());
+    let server_ip = Ipv6Addr::from(<[u8; 16]>::try_from(input.take(16)?).unwrap());
+    let client_port = input.u16()?;
+    let server_port = input.u16()?;
+    let v4 = |ip: Ipv6Addr| -> Result<Ipv4Addr, Error> {
+        ip.to_ipv4_mapped()
+            .ok_or_else(|| "bad IPv4 address in capture index".into())
+    };
+    let target = match version {
+        4 => IPTarget::V4 {
+            client_port,
+            server_port,
+            client_ip: v4(client_ip)?,
+            server_ip: v4(server_ip)?,
+            overlay: None,
+        },
+        6 => IPTarget::V6 {
+            client_port,
+            server_port,
+            client_ip,
+            server_ip,
+            overlay: None,
+        },
+        _ => return Err(format!("bad IP version {version} in capture index").into()),
+    };
+    Ok(IndexedFlow {
+        proto,
+        target,
+        first_packet: input.u64()?,
+        last_packet: input.u64()?,
+        packets: input.u64()?,
+        bytes: input.u64()?,
+    })
+}
+
+impl CaptureIndex {
+    /// Reads all of the capture at `path` to index it.
+    pub fn build(path: &Path) -> Result<CaptureIndex, Error> {
+        let (capture_len, capture_modified) = capture_version(path)?;
+        let mut builder = Builder::default();
+        let info = read_capture_file(path, &mut |_, record| {
+            builder.on_record(record);
+            Ok(())
+        })?;
+        let mut index = builder.index;
+        index.capture_len = capture_len;
+        index.capture_modified = capture_modified;
+        index.link_types = info.interfaces.iter().map(|i| i.link_type).collect();
+        Ok(index)
+    }
+
+    /// The index kept for the capture at `path`, if there is one and it is
+    /// up to date.
+    pub fn load(path: &Path) -> Result<Option<CaptureIndex>, Error> {
+        let data = match fs::read(index_path(path)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let index = Self::decode(&data)?;
+        let up_to_date = capture_version(path)? == (index.capture_len, index.capture_modified);
+        Ok(up_to_date.then_some(index))
+    }
+
+    /// Writes the index next to the capture at `path`.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let index_path = index_path(path);
+        let mut temp = index_path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut out = io::BufWriter::new(fs::File::create(&temp)?);
+        self.encode(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp, &index_path)?;
+        Ok(())
+    }
+
+    /// The index kept for the capture at `path`, building and saving it if
+    /// there is none that is up to date.
+    pub fn open(path: &Path) -> Result<CaptureIndex, Error> {
+        match Self::load(path) {
+            Ok(Some(index)) => return Ok(index),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("ignoring the index of {}: {e}", path.display()),
+        }
+        tracing::info!("indexing {}", path.display());
+        let index = Self::build(path)?;
+        tracing::info!(
+            "indexed {} packets in {} flows",
+            index.packets.len(),
+            index.flows.len()
+        );
+        if let Err(e) = index.save(path) {
+            tracing::warn!("could not save the index of {}: {e}", path.display());
+        }
+        Ok(index)
+    }
+
+    /// The index to read the capture at `path` with, if it has one or is
+    /// big enough to be worth building one for. Never for stdin or pipes.
+    pub fn for_file(path: &Path) -> Result<Option<CaptureIndex>, Error> {
+        if path.as_os_str() == "-" {
+            return Ok(None);
+        }
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        if metadata.len() > AUTO_INDEX_OVER {
+            return Self::open(path).map(Some);
+        }
+        match Self::load(path) {
+            Ok(index) => Ok(index),
+            Err(e) => {
+                tracing::warn!("ignoring the index of {}: {e}", path.display());
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn encode(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.capture_len.to_le_bytes())?;
+        out.write_all(&self.capture_modified.to_le_bytes())?;
+
+        out.write_all(&(self.link_types.len() as u32).to_le_bytes())?;
+        for link_type in &self.link_types {
+            out.write_all(&link_type.0.to_le_bytes())?;
+        }
+
+        out.write_all(&(self.keys.len() as u32).to_le_bytes())?;
+        for keys in &self.keys {
+            let (kind, data) = match keys {
+                IndexedKeys::Tls(data) => (KEYS_TLS, data),
+                IndexedKeys::Wireguard(data) => (KEYS_WIREGUARD, data),
+            };
+            out.write_all(&[kind])?;
+            out.write_all(&(data.len() as u32).to_le_bytes())?;
+            out.write_all(data)?;
+        }
+
+        out.write_all(&(self.flows.len() as u32).to_le_bytes())?;
+        for flow in &self.flows {
+            write_flow(out, flow)?;
+        }
+
+        out.write_all(&(self.packets.len() as u64).to_le_bytes())?;
+        for packet in &self.packets {
+            out.write_all(&packet.offset.to_le_bytes())?;
+            out.write_all(&packet.time.to_le_bytes())?;
+            out.write_all(&packet.captured_len.to_le_bytes())?;
+            out.write_all(&packet.original_len.to_le_bytes())?;
+            out.write_all(&packet.interface.to_le_bytes())?;
+            out.write_all(&packet.flow.unwrap_or(NO_FLOW).to_le_bytes())?;
+        }
+
+        out.write_all(&(self.requests.len() as u64).to_le_bytes())?;
+        for request in &self.requests {
+            out.write_all(&request.flow.to_le_bytes())?;
+            out.write_all(&request.packet.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn decode(data: &[u8]) -> Result<CaptureIndex, Error> {
+        let mut input = Input(data);
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err("not a capture index, or from another version of clipper".into());
+        }
+        let capture_len = input.u64()?;
+        let capture_modified = input.u64()?;
+
+        let count = input.count(false, 4)?;
+        let link_types = (0..count)
+            .map(|_| Ok(Linktype(input.u32()? as i32)))
+            .collect::<Result<_, Error>>()?;
+
+        let count = input.count(false, 5)?;
+        let mut keys = Vec::with_capacity(count);
+        for _ in 0..count {
+            let kind = input.u8()?;
+            let len = input.u32()? as usize;
+            let data = input.take(len)?.to_vec();
+            keys.push(match kind {
+                KEYS_TLS => IndexedKeys::Tls(data),
+                KEYS_WIREGUARD => IndexedKeys::Wireguard(data),
+                _ => return Err(format!("bad key kind {kind} in capture index").into()),
+            });
+        }
+
+        let count = input.count(false, 70)?;
+        let flows = (0..count)
+            .map(|_| read_flow(&mut input))
+            .collect::<Result<_, Error>>()?;
+
+        let count = input.count(true, 32)?;
+        let mut packets = Vec::with_capacity(count);
+        for _ in 0..count {
+            packets.push(IndexedPacket {
+                offset: input.u64()?,
+                time: input.u64()?,
+                captured_len: input.u32()?,
+                original_len: input.u32()?,
+                interface: input.u32()?,
+                flow: Some(input.u32()?).filter(|&f| f != NO_FLOW),
+            });
+        }
+
+        let count = input.count(true, 12)?;
+        let mut requests = Vec::with_capacity(count);
+        for _ in 0..count {
+            requests.push(IndexedRequest {
+                flow: input.u32()?,
+                packet: input.u64()?,
+            });
+        }
+
+        Ok(CaptureIndex {
+            capture_len,
+            capture_modified,
+            link_types,
+            keys,
+            flows,
+            packets,
+            requests,
+        })
+    }
+
+    /// Packet numbers of the first and last packets in `slice`, taking the
+    /// packets to be in order of time, as captures usually are. The first
+    /// is after the last if there are none.
+    pub fn packet_range(&self, slice: &CaptureSlice) -> (u64, u64) {
+        let start = self.packets.first().map_or(0, |p| p.time);
+        // Number of packets from before `time`, or also at it.
+        let before = |time: Nanos, at: bool| {
+            self.packets
+                .partition_point(|p| p.time < time || (at && p.time == time)) as u64
+        };
+        let from = match slice.from {
+            None => 1,
+            Some(SliceBound::Packet(n)) => n,
+            Some(SliceBound::Time(t)) => before(t, false) + 1,
+            Some(SliceBound::SinceStart(d)) => before(start.saturating_add(d), false) + 1,
+        };
+        let to = match slice.to {
+            None => self.packets.len() as u64,
+            Some(SliceBound::Packet(n)) => n.min(self.packets.len() as u64),
+            Some(SliceBound::Time(t)) => before(t, true),
+            Some(SliceBound::SinceStart(d)) => before(start.saturating_add(d), true),
+        };
+        (from, to)
+    }
+}
+
+/// A capture file, read by going straight to the packets wanted with its
+/// index.
+pub struct IndexedCapture {
+    index: CaptureIndex,
+    map: MappedFile,
+}
+
+impl IndexedCapture {
+    pub fn new(path: &Path, index: CaptureIndex) -> Result<Self, Error> {
+        let map = MappedFile::open(path)?;
+        if (map.len() as u64) < index.capture_len {
+            return Err(format!("{} is shorter than its index says", path.display()).into());
+        }
+        map.advise_random();
+        Ok(IndexedCapture { index, map })
+    }
+
+    /// Opens the capture at `path`, indexing it first if need be.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::new(path, CaptureIndex::open(path)?)
+    }
+
+    pub fn index(&self) -> &CaptureIndex {
+        &self.index
+    }
+
+    fn send_keys(&self, chomper: &mut dyn FrameChomper) {
+        for keys in &self.index.keys {
+            match keys {
+                IndexedKeys::Tls(keys) => chomper.on_keys(keys),
+                IndexedKeys::Wireguard(keys) => chomper.on_wireguard_keys(keys),
+            }
+        }
+    }
+
+    fn send_packet(&self, number: u64, chomper: &mut dyn FrameChomper) -> Result<(), Error> {
+        let packet = self.index.packets[number as usize - 1];
+        let start = packet.offset as usize;
+        let data = &self.map[start..start + packet.captured_len as usize];
+
+        // The index keeps no names, only where the interface is in the file.
+        let timing = TimingInfo {
+            received_on_wire: packet.time,
+            interface: Some(CaptureInterface {
+                index: packet.interface,
+                name: None,
+            }),
+            ..Default::default()
+        };
+        let link_type = self.index.link_types[packet.interface as usize];
+        chomper.chomp(timing, link_type, data)
+    }
+
+    /// Feeds the packets of `flow` through `chomper`, with the keys in the
+    /// capture.
+    pub fn dump_flow(&self, flow: u32, chomper: &mut dyn FrameChomper) -> Result<(), Error> {
+        let Some(indexed) = self.index.flows.get(flow as usize) else {
+            return Err(format!("there is no flow {flow} in the capture").into());
+        };
+        self.send_keys(chomper);
+        for number in indexed.first_packet..=indexed.last_packet {
+            if self.index.packets[number as usize - 1].flow == Some(flow) {
+                self.send_packet(number, chomper)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Feeds the packets in `slice` through `chomper`, with the keys in the
+    /// capture, like a [`SlicedChomper`](crate::capture_slice::SlicedChomper)
+    /// but seeking past everything that isn't wanted: only the packets from
+    /// before the slice of the flows that carry on into it are read, for
+    /// warming up, with the output held back by `gate`.
+    pub fn dump_slice(
+        &self,
+        slice: &CaptureSlice,
+        gate: &ReplayGate,
+        chomper: &mut dyn FrameChomper,
+    ) -> Result<(), Error> {
+        self.send_keys(chomper);
+        let (from, to) = self.index.packet_range(slice);
+
+        let mut open = vec![false; self.index.flows.len()];
+        let mut warm_up_from = from;
+        for (id, flow) in self.index.flows.iter().enumerate() {
+            if flow.first_packet < from && flow.last_packet >= from {
+                open[id] = true;
+                warm_up_from = warm_up_from.min(flow.first_packet);
+            }
+        }
+        tracing::info!(
+            "warming up {} flows from before the start of the slice",
+            open.iter().filter(|&&o| o).count()
+        );
+        gate.set_replaying(true);
+        let result = (warm_up_from..from).try_for_each(|number| {
+            match self.index.packets[number as usize - 1].flow {
+                Some(flow) if open[flow as usize] => self.send_packet(number, chomper),
+                _ => Ok(()),
+            }
+        });
+        gate.set_replaying(false);
+        result?;
+
+        for number in from..=to {
+            self.send_packet(number, chomper)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::{
+        capture_slice::SlicedChomper,
+        chomp,
+        http::HTTPStreamEvent,
+        pipeline::Pipeline,
+        test_support::{KeyMessageReorderer, Received, TestListener, H2},
+    };
+
+    fn corpus(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "corpus", name]
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_index() {
+        let path = corpus("http-conn-reuse.pcapng");
+        let index = CaptureIndex::build(&path).unwrap();
+
+        let mut encoded = Vec::new();
+        index.encode(&mut encoded).unwrap();
+        assert_eq!(CaptureIndex::decode(&encoded).unwrap(), index);
+        assert!(CaptureIndex::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        // One connection, reused for more than one request
+        assert_eq!(index.flows.len(), 1);
+        assert!(index.requests.len() > 1);
+        assert!(index.requests.iter().all(|r| r.flow == 0));
+
+        // Reading through the index gets the same packets as reading the
+        // whole file.
+        let mut whole = KeyMessageReorderer::default();
+        chomp::dump_pcap_file(path.clone(), &mut whole).unwrap();
+        let mut indexed = KeyMessageReorderer::default();
+        IndexedCapture::new(&path, index)
+            .unwrap()
+            .dump_flow(0, &mut indexed)
+            .unwrap();
+        assert_eq!(indexed.packets.len(), whole.packets.len());
+        for (a, b) in indexed.packets.iter().zip(&whole.packets) {
+            assert_eq!(a.0.received_on_wire, b.0.received_on_wire);
+            assert_eq!((a.1, &a.2), (b.1, &b.2));
+        }
+    }
+
+    #[test]
+    fn test_dump_slice() {
+        let path = corpus("http2-conn-reuse.pcapng");
+        let index = CaptureIndex::build(&path).unwrap();
+        let half = index.packets.len() as u64 / 2;
+        let slice = CaptureSlice {
+            from: Some(SliceBound::Packet(half)),
+            to: Some(SliceBound::Packet(half + 5)),
+        };
+
+        let run = |send: &mut dyn FnMut(&mut dyn FrameChomper, ReplayGate)| {
+            let received = Arc::new(RwLock::new(Vec::new()));
+            let gate = ReplayGate::default();
+            let mut chomper = Pipeline::new()
+                .tls(Default::default())
+                .http()
+                .replay_gate(gate.clone())
+                .build(TestListener::<HTTPStreamEvent> {
+                    received: received.clone(),
+                });
+            send(&mut chomper, gate);
+            let messages: Vec<String> = received
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|r| matches!(r, Received::Message(..)))
+                .map(|r| r.to_string())
+                .collect();
+            messages
+        };
+
+        let mut frames = KeyMessageReorderer::default();
+        chomp::dump_pcap(io::Cursor::new(H2), &mut frames).unwrap();
+        let sliced = run(&mut |chomper, gate| {
+            let mut sliced = SlicedChomper::new(slice.clone(), gate, chomper);
+            let _ = frames.send(&mut sliced);
+        });
+        let indexed = run(&mut |chomper, gate| {
+            IndexedCapture::new(&path, index.clone())
+                .unwrap()
+                .dump_slice(&slice, &gate, chomper)
+                .unwrap();
+        });
+        assert!(!indexed.is_empty());
+        assert_eq!(indexed, sliced);
+    }
+}
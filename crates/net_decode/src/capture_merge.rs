@@ -0,0 +1,301 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading several capture files as one, in order of time, such as captures
+//! taken at both ends of a connection.
+//!
+//! Each file is read on a thread of its own, which hands its records over
+//! as they are read, and the earliest of the frames handed over goes next.
+//! Keys go as soon as they are read, since they have no time. Interfaces
+//! are numbered across all of the files, as they would be in one pcapng
+//! file with a section for each.
+
+use std::{
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+};
+
+use pcap_parser::Linktype;
+
+use crate::{
+    capture_file::{read_capture_file, CaptureInfo, Frame, Interface, Record},
+    link::PacketDirection,
+    listener::{Nanos, TimingInfo},
+    Error,
+};
+
+/// How many records each file may be read ahead of the merge.
+const READ_AHEAD: usize = 256;
+
+/// A capture file to merge with others.
+#[derive(Clone, Debug)]
+pub struct MergeInput {
+    pub path: PathBuf,
+    /// Nanoseconds to add to every timestamp in the file, to make up for the
+    /// clock of the machine it was captured on being off from the others.
+    pub clock_offset: i64,
+}
+
+impl MergeInput {
+    pub fn new(path: PathBuf) -> Self {
+        MergeInput {
+            path,
+            clock_offset: 0,
+        }
+    }
+}
+
+/// A [`Record`] sent over from the thread reading a file.
+enum OwnedRecord {
+    /// An interface was added to the file's [`CaptureInfo`].
+    Interface(Interface),
+    Frame {
+        interface: usize,
+        time: Nanos,
+        direction: Option<PacketDirection>,
+        link_type: Linktype,
+        data: Vec<u8>,
+        offset: u64,
+        original_len: u32,
+        comments: Vec<String>,
+    },
+    TlsKeys(Vec<u8>),
+    WireguardKeys(Vec<u8>),
+}
+
+impl OwnedRecord {
+    fn from_record(record: Record<'_>) -> Self {
+        match record {
+            Record::Frame(frame) => OwnedRecord::Frame {
+                interface: frame.interface,
+                time: frame.timing.received_on_wire,
+                direction: frame.timing.direction,
+                link_type: frame.link_type,
+                data: frame.data.to_vec(),
+                offset: frame.offset,
+                original_len: frame.original_len,
+                comments: frame.comments.into_iter().map(str::to_owned).collect(),
+            },
+            Record::TlsKeys(keys) => OwnedRecord::TlsKeys(keys.to_vec()),
+            Record::WireguardKeys(keys) => OwnedRecord::WireguardKeys(keys.to_vec()),
+        }
+    }
+}
+
+/// Reads `path`, sending what is in it to `send` until it is read or the
+/// merge stops.
+fn read_into(path: PathBuf, send: SyncSender<Result<OwnedRecord, Error>>) {
+    let mut interfaces_sent = 0;
+    let mut read = || -> Result<(), Error> {
+        read_capture_file(&path, &mut |info, record| {
+            for interface in &info.interfaces[interfaces_sent..] {
+                send.send(Ok(OwnedRecord::Interface(interface.clone())))
+                    .map_err(|_| "merge stopped")?;
+            }
+            interfaces_sent = info.interfaces.len();
+            send.send(Ok(OwnedRecord::from_record(record)))
+                .map_err(|_| "merge stopped")?;
+            Ok(())
+        })?;
+        Ok(())
+    };
+    if let Err(e) = read() {
+        let _ = send.send(Err(format!("{}: {e}", path.display()).into()));
+    }
+}
+
+struct Source {
+    input: MergeInput,
+    recv: Receiver<Result<OwnedRecord, Error>>,
+    /// The next frame, held until it is the earliest.
+    head: Option<OwnedRecord>,
+    done: bool,
+    /// Indices into the merged [`CaptureInfo::interfaces`] of the file's
+    /// interfaces.
+    interfaces: Vec<usize>,
+}
+
+impl Source {
+    /// Reads until there is a frame in `head` or the file is done. Keys
+    /// are given to `on_record` on the way.
+    fn fill(
+        &mut self,
+        info: &mut CaptureInfo,
+        on_record: &mut dyn FnMut(&CaptureInfo, Record<'_>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        while self.head.is_none() && !self.done {
+            let Ok(record) = self.recv.recv() else {
+                self.done = true;
+                break;
+            };
+            match record? {
+                OwnedRecord::Interface(mut interface) => {
+                    interface
+                        .comments
+                        .push(format!("merged from {}", self.input.path.display()));
+                    self.interfaces.push(info.interfaces.len());
+                    info.interfaces.push(interface);
+                }
+                OwnedRecord::TlsKeys(keys) => on_record(info, Record::TlsKeys(&keys))?,
+                OwnedRecord::WireguardKeys(keys) => on_record(info, Record::WireguardKeys(&keys))?,
+                frame @ OwnedRecord::Frame { .. } => self.head = Some(frame),
+            }
+        }
+        Ok(())
+    }
+
+    fn head_time(&self) -> Option<Nanos> {
+        match self.head {
+            Some(OwnedRecord::Frame { time, .. }) => {
+                Some(time.saturating_add_signed(self.input.clock_offset))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn merge(
+    sources: &mut [Source],
+    on_record: &mut dyn FnMut(&CaptureInfo, Record<'_>) -> Result<(), Error>,
+) -> Result<CaptureInfo, Error> {
+    let mut info = CaptureInfo::default();
+    loop {
+        for source in sources.iter_mut() {
+            source.fill(&mut info, on_record)?;
+        }
+        let Some((source, time)) = sources
+            .iter_mut()
+            .filter_map(|s| s.head_time().map(|t| (s, t)))
+            .min_by_key(|(_, t)| *t)
+        else {
+            return Ok(info);
+        };
+        let Some(OwnedRecord::Frame {
+            interface,
+            direction,
+            link_type,
+            data,
+            offset,
+            original_len,
+            comments,
+            ..
+        }) = source.head.take()
+        else {
+            unreachable!("head_time is only Some for frames");
+        };
+        let interface = source.interfaces[interface];
+        on_record(
+            &info,
+            Record::Frame(Frame {
+                interface,
+                timing: TimingInfo {
+                    received_on_wire: time,
+                    interface: Some(info.interfaces[interface].capture_interface(interface)),
+                    direction,
+                    ..Default::default()
+                },
+                link_type,
+                data: &data,
+                offset,
+                original_len,
+                comments: comments.iter().map(String::as_str).collect(),
+            }),
+        )?;
+    }
+}
+
+/// Reads the capture files `inputs` as one, like [`read_capture`], giving
+/// their frames to `on_record` in order of time, with each file's clock
+/// offset added.
+///
+/// Each file has to be in order of time itself, as captures usually are.
+pub fn read_captures_merged(
+    inputs: Vec<MergeInput>,
+    on_record: &mut dyn FnMut(&CaptureInfo, Record<'_>) -> Result<(), Error>,
+) -> Result<CaptureInfo, Error> {
+    thread::scope(|scope| {
+        let mut sources: Vec<Source> = inputs
+            .into_iter()
+            .map(|input| {
+                let (send, recv) = mpsc::sync_channel(READ_AHEAD);
+                let path = input.path.clone();
+                scope.spawn(move || read_into(path, send));
+                Source {
+                    input,
+                    recv,
+                    head: None,
+                    done: false,
+                    interfaces: Vec::new(),
+                }
+            })
+            .collect();
+        let result = merge(&mut sources, on_record);
+        // Stops the readers, if the merge stopped early.
+        drop(sources);
+        result
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn corpus(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "corpus", name]
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let inputs = vec![
+            MergeInput::new(corpus("nya-dsb.pcapng")),
+            MergeInput {
+                path: corpus("nya-dsb.pcapng"),
+                clock_offset: -1_000_000,
+            },
+        ];
+        let mut frames = Vec::new();
+        let mut keys = 0;
+        let info = read_captures_merged(inputs, &mut |_, record| {
+            match record {
+                Record::Frame(frame) => {
+                    frames.push((frame.timing.received_on_wire, frame.interface))
+                }
+                Record::TlsKeys(_) => keys += 1,
+                Record::WireguardKeys(_) => {}
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let mut alone = 0;
+        read_capture_file(&corpus("nya-dsb.pcapng"), &mut |_, record| {
+            alone += matches!(record, Record::Frame(_)) as usize;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(info.interfaces.len(), 2);
+        assert_eq!(frames.len(), 2 * alone);
+        assert_eq!(keys, 2);
+        assert!(frames.windows(2).all(|w| w[0].0 <= w[1].0));
+        // The second file is a millisecond behind, so its copy of each
+        // packet goes first.
+        assert_eq!(frames[0].1, 1);
+        let first = frames.iter().find(|(_, interface)| *interface == 0);
+        assert_eq!(first.unwrap().0, frames[0].0 + 1_000_000);
+    }
+
+    #[test]
+    fn test_merge_missing_file() {
+        let inputs = vec![
+            MergeInput::new(corpus("nya-dsb.pcapng")),
+            MergeInput::new(corpus("does-not-exist.pcapng")),
+        ];
+        let err = read_captures_merged(inputs, &mut |_, _| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("does-not-exist"), "{err}");
+    }
+}
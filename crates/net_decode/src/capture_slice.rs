@@ -0,0 +1,435 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Decoding only a window of a capture, between two times or packet
+//! numbers.
+//!
+//! The connections already open when the window starts can't be decoded
+//! from their middle, so the frames before it are not thrown away straight
+//! off: the frames of each TCP connection are kept until it is seen to
+//! end, and when the window starts, those of the connections still open
+//! are fed through with the output held back by a [`ReplayGate`], like
+//! restoring a [`checkpoint`](crate::checkpoint). Connections that ended
+//! before the window cost nothing but the scan past them.
+//!
+//! Connections that sent more than [`DEFAULT_MAX_WARM_UP_BYTES`] before the
+//! window are not kept, and neither is anything but TCP, so those carry on
+//! undecoded or from wherever the window starts.
+
+use std::{cmp::Ordering, collections::HashMap, fmt, path::PathBuf};
+
+use crate::{
+    capture_filter::{CaptureFilter, FilteredChomper},
+    capture_index::{CaptureIndex, IndexedCapture},
+    capture_merge::MergeInput,
+    checkpoint::ReplayGate,
+    chomp::{self, FrameChomper, IPTarget, IPPROTO_TCP},
+    key_db::{ClientRandom, Secret, SecretType},
+    link::Linktype,
+    listener::{Nanos, TimingInfo},
+    Error,
+};
+
+/// How much of a connection's frames from before the window to keep, in
+/// bytes.
+pub const DEFAULT_MAX_WARM_UP_BYTES: usize = 16 * 1024 * 1024;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+/// One end of a [`CaptureSlice`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SliceBound {
+    /// Packet number, counting from 1 as Wireshark does.
+    Packet(u64),
+    /// Nanoseconds since the Unix epoch.
+    Time(Nanos),
+    /// Nanoseconds since the first packet.
+    SinceStart(Nanos),
+}
+
+/// The part of a capture to decode, from `from` to `to`, both included.
+/// Either may be left out to go from the start or to the end.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureSlice {
+    pub from: Option<SliceBound>,
+    pub to: Option<SliceBound>,
+}
+
+impl CaptureSlice {
+    pub fn is_whole(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+}
+
+/// Returned from [`SlicedChomper::chomp`] once past the end of the slice,
+/// to stop reading. See [`finished`].
+#[derive(Debug)]
+pub struct SliceEnded;
+
+impl fmt::Display for SliceEnded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("past the end of the capture slice")
+    }
+}
+
+impl std::error::Error for SliceEnded {}
+
+/// Reading having stopped at the end of the slice is not an error.
+pub fn finished(result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+        Err(e) if e.is::<SliceEnded>() => Ok(()),
+        r => r,
+    }
+}
+
+struct Frame {
+    number: u64,
+    timing: TimingInfo,
+    link_type: Linktype,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct WarmUp {
+    frames: Vec<Frame>,
+    bytes: usize,
+    /// Which ends have sent a FIN: 1 for the client, 2 for the server.
+    fins: u8,
+    ended: bool,
+    too_big: bool,
+}
+
+/// Passes on the frames in a [`CaptureSlice`], after warming up the
+/// connections still open when it starts. Keys are always passed on.
+pub struct SlicedChomper<C> {
+    slice: CaptureSlice,
+    gate: ReplayGate,
+    next: C,
+    /// Number of the frame being chomped.
+    packets: u64,
+    start: Option<Nanos>,
+    in_window: bool,
+    flows: HashMap<IPTarget, WarmUp>,
+    max_warm_up_bytes: usize,
+}
+
+impl<C: FrameChomper> SlicedChomper<C> {
+    /// `gate` has to be the one the [`Gated`](crate::checkpoint::Gated)
+    /// listeners at the end of `next`'s stack were given.
+    pub fn new(slice: CaptureSlice, gate: ReplayGate, next: C) -> Self {
+        SlicedChomper {
+            in_window: slice.from.is_none(),
+            slice,
+            gate,
+            next,
+            packets: 0,
+            start: None,
+            flows: HashMap::new(),
+            max_warm_up_bytes: DEFAULT_MAX_WARM_UP_BYTES,
+        }
+    }
+
+    pub fn with_max_warm_up_bytes(mut self, max_warm_up_bytes: usize) -> Self {
+        self.max_warm_up_bytes = max_warm_up_bytes;
+        self
+    }
+
+    pub fn into_inner(self) -> C {
+        self.next
+    }
+
+    /// Where the frame being chomped is relative to `bound`.
+    fn compare(&self, bound: SliceBound, now: Nanos) -> Ordering {
+        match bound {
+            SliceBound::Packet(n) => self.packets.cmp(&n),
+            SliceBound::Time(t) => now.cmp(&t),
+            SliceBound::SinceStart(d) => now.cmp(&self.start.unwrap_or(now).saturating_add(d)),
+        }
+    }
+
+    /// Keeps a frame from before the window, if it is part of a TCP
+    /// connection that has not ended yet.
+    fn hold(&mut self, timing: &TimingInfo, link_type: Linktype, packet: &[u8]) {
+        let Some(segment) =
+            chomp::transport_segment(link_type, packet).filter(|s| s.proto == IPPROTO_TCP)
+        else {
+            return;
+        };
+        let (target, flags) = (segment.target, segment.tcp_flags);
+        let (target, from_client) = if self.flows.contains_key(&target.flip()) {
+            (target.flip(), false)
+        } else {
+            (target, true)
+        };
+        let flow = self.flows.entry(target).or_default();
+        if flow.ended {
+            if flags & TCP_SYN == 0 {
+                return;
+            }
+            // The ports are being used again
+            *flow = WarmUp::default();
+        }
+        if flags & TCP_RST != 0 {
+            *flow = WarmUp {
+                ended: true,
+                ..Default::default()
+            };
+            return;
+        }
+        if flags & TCP_FIN != 0 {
+            flow.fins |= if from_client { 1 } else { 2 };
+            if flow.fins == 3 {
+                *flow = WarmUp {
+                    ended: true,
+                    ..Default::default()
+                };
+                return;
+            }
+        }
+        if flow.too_big {
+            return;
+        }
+        flow.bytes += packet.len();
+        if flow.bytes > self.max_warm_up_bytes {
+            tracing::debug!(?target, "connection too big to warm up");
+            flow.frames = Vec::new();
+            flow.too_big = true;
+            return;
+        }
+        flow.frames.push(Frame {
+            number: self.packets,
+            timing: timing.clone(),
+            link_type,
+            data: packet.to_vec(),
+        });
+    }
+
+    /// Feeds the connections still open at the start of the window
+    /// through, without anything coming out of the gated listeners.
+    fn warm_up(&mut self) -> Result<(), Error> {
+        let flows = std::mem::take(&mut self.flows);
+        let too_big = flows.values().filter(|f| f.too_big && !f.ended).count();
+        if too_big > 0 {
+            tracing::warn!(
+                "{too_big} connections sent too much before the start of the slice to be warmed up"
+            );
+        }
+        let mut open = 0;
+        let mut frames = Vec::new();
+        for flow in flows.into_values().filter(|f| !f.ended && !f.too_big) {
+            open += 1;
+            frames.extend(flow.frames);
+        }
+        // Interleaved again, since the order across connections can matter,
+        // e.g. for MPTCP subflows.
+        frames.sort_by_key(|f| f.number);
+        tracing::info!(
+            "warming up {open} connections from {} packets before the start of the slice",
+            frames.len()
+        );
+
+        self.gate.set_replaying(true);
+        let result = frames
+            .into_iter()
+            .try_for_each(|f| self.next.chomp(f.timing, f.link_type, &f.data));
+        self.gate.set_replaying(false);
+        result
+    }
+}
+
+impl<C: FrameChomper> FrameChomper for SlicedChomper<C> {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        let now = timing.received_on_wire;
+        self.packets += 1;
+        self.start.get_or_insert(now);
+
+        if let Some(to) = self.slice.to {
+            if self.compare(to, now) == Ordering::Greater {
+                return Err(Box::new(SliceEnded));
+            }
+        }
+        if !self.in_window {
+            let from = self
+                .slice
+                .from
+                .expect("only out of the window before `from`");
+            if self.compare(from, now) == Ordering::Less {
+                self.hold(&timing, link_type, packet);
+                return Ok(());
+            }
+            self.in_window = true;
+            self.warm_up()?;
+        }
+        self.next.chomp(timing, link_type, packet)
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        self.next.on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.next.on_wireguard_keys(key_log)
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.next.on_key(client_random, secret_type, secret)
+    }
+}
+
+/// Reads a pcapng file into `chomper` like
+/// [`capture_filter::dump_pcap_file`](crate::capture_filter::dump_pcap_file),
+/// but only the frames in `slice`, stopping at its end. Packet numbers count
+/// every frame in the file, not only those `filter` matches.
+///
+/// If the file has an index, or is big enough to be worth making one for,
+/// the slice is seeked to rather than read up to; see
+/// [`capture_index`](crate::capture_index).
+pub fn dump_pcap_file(
+    file: PathBuf,
+    slice: CaptureSlice,
+    filter: Option<CaptureFilter>,
+    gate: ReplayGate,
+    chomper: &mut dyn FrameChomper,
+) -> Result<(), Error> {
+    let index = match slice.is_whole() {
+        true => None,
+        false => CaptureIndex::for_file(&file)?,
+    };
+    let mut filtered;
+    let chomper: &mut dyn FrameChomper = match filter {
+        Some(filter) => {
+            filtered = FilteredChomper::new(filter, chomper);
+            &mut filtered
+        }
+        None => chomper,
+    };
+    match index {
+        Some(index) => IndexedCapture::new(&file, index)?.dump_slice(&slice, &gate, chomper),
+        None => finished(chomp::dump_pcap_file(
+            file,
+            &mut SlicedChomper::new(slice, gate, chomper),
+        )),
+    }
+}
+
+/// Reads several capture files into `chomper` as one like
+/// [`dump_pcap_file`], with packets numbered in the order they are merged
+/// in.
+pub fn dump_merged_pcaps(
+    inputs: Vec<MergeInput>,
+    slice: CaptureSlice,
+    filter: Option<CaptureFilter>,
+    gate: ReplayGate,
+    chomper: &mut dyn FrameChomper,
+) -> Result<(), Error> {
+    finished(match filter {
+        Some(filter) => chomp::dump_merged_pcaps(
+            inputs,
+            &mut SlicedChomper::new(slice, gate, FilteredChomper::new(filter, chomper)),
+        ),
+        None => chomp::dump_merged_pcaps(inputs, &mut SlicedChomper::new(slice, gate, chomper)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        sync::{Arc, RwLock},
+    };
+
+    use super::*;
+    use crate::{
+        chomp::EthernetChomper,
+        dispatch::ListenerDispatcher,
+        http::HTTPStreamEvent,
+        pipeline::Pipeline,
+        test_support::{KeyMessageReorderer, Received, TestListener, H2},
+    };
+
+    type Log = Arc<RwLock<Vec<Received<HTTPStreamEvent>>>>;
+
+    fn stack(gate: ReplayGate) -> (EthernetChomper<ListenerDispatcher>, Log) {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let chomper = Pipeline::new()
+            .tls(Default::default())
+            .http()
+            .replay_gate(gate)
+            .build(TestListener {
+                received: received.clone(),
+            });
+        (chomper, received)
+    }
+
+    /// The messages, leaving out side data about keys, which goes out when
+    /// the keys are given rather than in the slice.
+    fn messages(received: &Log) -> Vec<String> {
+        received
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|r| matches!(r, Received::Message(..)))
+            .map(|r| r.to_string())
+            .collect()
+    }
+
+    fn frames() -> KeyMessageReorderer {
+        let mut frames = KeyMessageReorderer::default();
+        chomp::dump_pcap(io::Cursor::new(H2), &mut frames).unwrap();
+        frames
+    }
+
+    #[test]
+    fn test_slice_from() {
+        let frames = frames();
+        let (mut whole, whole_received) = stack(ReplayGate::default());
+        frames.send(&mut whole).unwrap();
+
+        // Start in the middle of the HTTP/2 connection, which has to be
+        // warmed up to be decrypted.
+        let half = frames.packets.len() / 2;
+        let gate = ReplayGate::default();
+        let (inner, sliced_received) = stack(gate.clone());
+        let slice = CaptureSlice {
+            from: Some(SliceBound::Packet(half as u64 + 1)),
+            to: None,
+        };
+        let mut sliced = SlicedChomper::new(slice, gate, inner);
+        frames.send(&mut sliced).unwrap();
+
+        let whole = messages(&whole_received);
+        let sliced = messages(&sliced_received);
+        assert!(!sliced.is_empty());
+        assert!(sliced.len() < whole.len());
+        assert!(whole.ends_with(&sliced));
+    }
+
+    #[test]
+    fn test_slice_to() {
+        let frames = frames();
+        let half = frames.packets.len() / 2;
+        let to = frames.packets[half].0.received_on_wire;
+
+        let slice = CaptureSlice {
+            from: None,
+            to: Some(SliceBound::Time(to)),
+        };
+        let mut sliced =
+            SlicedChomper::new(slice, ReplayGate::default(), KeyMessageReorderer::default());
+        let result = frames.send(&mut sliced);
+        assert!(result.unwrap_err().is::<SliceEnded>());
+        let seen = sliced.into_inner().packets;
+        assert!(seen.len() > half);
+        assert!(seen
+            .iter()
+            .all(|(timing, _, _)| timing.received_on_wire <= to));
+    }
+}
@@ -0,0 +1,105 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Summaries of X.509 certificates seen in TLS handshakes.
+
+use std::fmt;
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+/// A certificate as presented on the wire, along with the bits of it people
+/// usually want to look at.
+///
+/// Parsing is best effort: if the certificate cannot be parsed, the DER and
+/// fingerprint are still available but the other fields are `None`.
+#[derive(Clone)]
+pub struct CertificateInfo {
+    pub der: Vec<u8>,
+    /// Lowercase hex SHA-256 of the DER, as shown by browsers.
+    pub sha256_fingerprint: String,
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    pub serial: Option<String>,
+    /// Validity period as Unix timestamps.
+    pub not_before: Option<i64>,
+    pub not_after: Option<i64>,
+}
+
+impl fmt::Debug for CertificateInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateInfo")
+            .field("sha256_fingerprint", &self.sha256_fingerprint)
+            .field("subject", &self.subject)
+            .field("issuer", &self.issuer)
+            .field("serial", &self.serial)
+            .field("not_before", &self.not_before)
+            .field("not_after", &self.not_after)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CertificateInfo {
+    pub fn from_der(der: &[u8]) -> Self {
+        let mut info = CertificateInfo {
+            der: der.to_vec(),
+            sha256_fingerprint: hex::encode(Sha256::digest(der)),
+            subject: None,
+            issuer: None,
+            serial: None,
+            not_before: None,
+            not_after: None,
+        };
+
+        match x509_parser::parse_x509_certificate(der) {
+            Ok((_, cert)) => {
+                info.subject = Some(cert.subject().to_string());
+                info.issuer = Some(cert.issuer().to_string());
+                info.serial = Some(cert.raw_serial_as_string());
+                info.not_before = Some(cert.validity().not_before.timestamp());
+                info.not_after = Some(cert.validity().not_after.timestamp());
+            }
+            Err(e) => {
+                tracing::debug!("failed to parse certificate: {e}");
+            }
+        }
+
+        info
+    }
+
+    /// PEM encoding of the certificate, with the usual 64 column lines.
+    pub fn to_pem(&self) -> String {
+        let b64 = base64::engine::general_purpose::STANDARD.encode(&self.der);
+        let mut out = String::from("-----BEGIN CERTIFICATE-----\n");
+        // base64 is ASCII, so splitting on bytes is fine
+        for line in b64.as_bytes().chunks(64) {
+            out.push_str(std::str::from_utf8(line).unwrap());
+            out.push('\n');
+        }
+        out.push_str("-----END CERTIFICATE-----\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unparseable_certificate() {
+        let info = CertificateInfo::from_der(b"not a certificate");
+        assert_eq!(
+            info.sha256_fingerprint,
+            "47209c9b7af839de69e9a9cd625e9182c1ad63dae79ed88a2dd680fe34218620"
+        );
+        assert_eq!(info.subject, None);
+        assert_eq!(info.der, b"not a certificate");
+        assert_eq!(
+            info.to_pem(),
+            "-----BEGIN CERTIFICATE-----\n\
+             bm90IGEgY2VydGlmaWNhdGU=\n\
+             -----END CERTIFICATE-----\n"
+        );
+    }
+}
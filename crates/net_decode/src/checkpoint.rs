@@ -0,0 +1,371 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Carrying the connections being decoded over to a new decoding stack, such
+//! as in another process after an upgrade.
+//!
+//! TLS and HTTP decoder state is full of library internals that can't be
+//! written out, so rather than the state itself, a checkpoint holds the
+//! frames of every TCP connection still open. Restoring feeds them through
+//! the new stack with its output held back by a [`ReplayGate`], which puts
+//! every layer back where it was without passing on anything twice.
+//!
+//! A checkpoint is a pcapng file with the TLS keys in it, so it can be
+//! looked at with the usual tools too.
+//!
+//! Connections that sent more than [`DEFAULT_MAX_FLOW_BYTES`] are not kept,
+//! and neither are fragmented or tunnelled packets, so those connections
+//! carry on undecoded after a restore.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use bytes::Bytes;
+use pcap_parser::{
+    DecryptionSecretsBlock, EnhancedPacketBlock, InterfaceDescriptionBlock, Linktype, OptionCode,
+    PcapNGOption, SecretsType, SectionHeaderBlock, ToVec,
+};
+
+use crate::{
+    chomp::{self, EthernetChomper, FrameChomper, IPTarget, IPPROTO_TCP},
+    key_db::{ClientRandom, Secret, SecretType},
+    listener::{Listener, Nanos, SideData, TimingInfo},
+    Error,
+};
+
+/// How much of a connection's frames to keep, in bytes.
+pub const DEFAULT_MAX_FLOW_BYTES: usize = 1024 * 1024;
+/// How often to forget the frames of connections that have ended, in
+/// capture time.
+const PRUNE_INTERVAL: Nanos = 1_000_000_000;
+
+/// Holds back everything going through a [`Gated`] listener while a
+/// checkpoint is being restored.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayGate(Arc<AtomicBool>);
+
+impl ReplayGate {
+    pub fn is_replaying(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_replaying(&self, replaying: bool) {
+        self.0.store(replaying, Ordering::Relaxed);
+    }
+}
+
+/// Passes everything on to `L`, except while its [`ReplayGate`] is
+/// replaying.
+pub struct Gated<L> {
+    gate: ReplayGate,
+    next: L,
+}
+
+impl<L> Gated<L> {
+    pub fn new(gate: ReplayGate, next: L) -> Self {
+        Gated { gate, next }
+    }
+}
+
+impl<T, L: Listener<T>> Listener<T> for Gated<L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        if !self.gate.is_replaying() {
+            self.next.on_data(timing, target, to_client, data);
+        }
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        if !self.gate.is_replaying() {
+            self.next.on_side_data(data);
+        }
+    }
+}
+
+struct Frame {
+    received_on_wire: Nanos,
+    link_type: Linktype,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct FlowFrames {
+    frames: Vec<Frame>,
+    bytes: usize,
+}
+
+/// An [`EthernetChomper`] which keeps the frames of the open TCP
+/// connections, for [`CheckpointingChomper::checkpoint`].
+pub struct CheckpointingChomper<Recv: Listener<Bytes>> {
+    pub inner: EthernetChomper<Recv>,
+    gate: ReplayGate,
+    flows: HashMap<IPTarget, FlowFrames>,
+    /// Connections that went over `max_flow_bytes`, which are not kept.
+    too_big: HashSet<IPTarget>,
+    max_flow_bytes: usize,
+    last_prune: Nanos,
+}
+
+impl<Recv: Listener<Bytes>> CheckpointingChomper<Recv> {
+    /// `gate` has to be the one the [`Gated`] listeners at the end of
+    /// `inner`'s stack were given.
+    pub fn new(inner: EthernetChomper<Recv>, gate: ReplayGate) -> Self {
+        CheckpointingChomper {
+            inner,
+            gate,
+            flows: HashMap::new(),
+            too_big: HashSet::new(),
+            max_flow_bytes: DEFAULT_MAX_FLOW_BYTES,
+            last_prune: 0,
+        }
+    }
+
+    pub fn with_max_flow_bytes(mut self, max_flow_bytes: usize) -> Self {
+        self.max_flow_bytes = max_flow_bytes;
+        self
+    }
+
+    /// The connection the TCP follower knows `target` as, if it does.
+    fn followed(&self, target: IPTarget) -> Option<IPTarget> {
+        let flows = &self.inner.tcp_follower.flows;
+        [target, target.flip()]
+            .into_iter()
+            .find(|t| flows.contains_key(t))
+    }
+
+    fn prune(&mut self) {
+        let flows = &self.inner.tcp_follower.flows;
+        self.flows.retain(|target, _| flows.contains_key(target));
+        self.too_big.retain(|target| flows.contains_key(target));
+    }
+
+    fn record(&mut self, timing: &TimingInfo, link_type: Linktype, packet: &[u8]) {
+        let Some(target) = chomp::transport_segment(link_type, packet)
+            .filter(|s| s.proto == IPPROTO_TCP)
+            .and_then(|s| self.followed(s.target))
+        else {
+            return;
+        };
+        if self.too_big.contains(&target) {
+            return;
+        }
+        let flow = self.flows.entry(target).or_default();
+        flow.bytes += packet.len();
+        if flow.bytes > self.max_flow_bytes {
+            tracing::debug!(?target, "connection too big to checkpoint");
+            self.flows.remove(&target);
+            self.too_big.insert(target);
+            return;
+        }
+        flow.frames.push(Frame {
+            received_on_wire: timing.received_on_wire,
+            link_type,
+            data: packet.to_vec(),
+        });
+    }
+
+    /// Writes the open connections and the TLS keys out as pcapng, for
+    /// [`CheckpointingChomper::restore`].
+    pub fn checkpoint(&mut self, out: &mut impl io::Write) -> Result<(), Error> {
+        self.prune();
+        if !self.too_big.is_empty() {
+            tracing::warn!(
+                "{} connections are too big to checkpoint and will not be decoded after restoring",
+                self.too_big.len()
+            );
+        }
+
+        let mut shb = SectionHeaderBlock {
+            block_type: 0,
+            block_len1: 0,
+            bom: 0,
+            major_version: 0,
+            minor_version: 0,
+            section_len: -1i64,
+            options: vec![PcapNGOption {
+                code: OptionCode::EndOfOpt,
+                len: 0,
+                value: &[],
+            }],
+            block_len2: 0,
+        };
+        out.write_all(&shb.to_vec().unwrap())?;
+
+        let key_log = self.inner.key_db.read().unwrap().to_key_log();
+        let mut dsb = DecryptionSecretsBlock {
+            block_type: 0,
+            block_len1: 0,
+            secrets_type: SecretsType::TlsKeyLog,
+            secrets_len: key_log.len() as u32,
+            data: &key_log,
+            options: Vec::new(),
+            block_len2: 0,
+        };
+        out.write_all(&dsb.to_vec().unwrap())?;
+
+        // Interleaved again, since the order across connections can matter,
+        // e.g. for MPTCP subflows.
+        let mut frames: Vec<&Frame> = self.flows.values().flat_map(|f| &f.frames).collect();
+        frames.sort_by_key(|f| f.received_on_wire);
+        let mut interfaces: Vec<Linktype> = Vec::new();
+        for frame in frames {
+            let if_id = match interfaces.iter().position(|&l| l == frame.link_type) {
+                Some(i) => i,
+                None => {
+                    let tsresol = 9u8.to_le_bytes();
+                    let mut idb = InterfaceDescriptionBlock {
+                        block_type: 0,
+                        block_len1: 0,
+                        block_len2: 0,
+                        linktype: frame.link_type,
+                        reserved: 0,
+                        snaplen: 262144,
+                        options: vec![PcapNGOption {
+                            code: OptionCode::IfTsresol,
+                            len: 1,
+                            value: &tsresol,
+                        }],
+                        if_tsresol: 9,
+                        if_tsoffset: 0,
+                    };
+                    out.write_all(&idb.to_vec().unwrap())?;
+                    interfaces.push(frame.link_type);
+                    interfaces.len() - 1
+                }
+            };
+            let time = frame.received_on_wire;
+            let mut epb = EnhancedPacketBlock {
+                block_type: 0,
+                block_len1: 0,
+                block_len2: 0,
+                if_id: if_id as u32,
+                ts_high: (time >> 32) as u32,
+                ts_low: time as u32,
+                caplen: frame.data.len() as u32,
+                origlen: frame.data.len() as u32,
+                data: &frame.data,
+                options: Vec::new(),
+            };
+            out.write_all(&epb.to_vec().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Feeds a checkpoint through, without anything coming out of the
+    /// [`Gated`] listeners.
+    pub fn restore(&mut self, checkpoint: &[u8]) -> Result<(), Error> {
+        self.gate.set_replaying(true);
+        let result = chomp::dump_pcap(io::Cursor::new(checkpoint), self);
+        self.gate.set_replaying(false);
+        tracing::info!(
+            "restored {} connections from a checkpoint",
+            self.inner.tcp_follower.flows.len()
+        );
+        result
+    }
+}
+
+impl<Recv: Listener<Bytes>> FrameChomper for CheckpointingChomper<Recv> {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        let now = timing.received_on_wire;
+        self.inner.chomp(timing.clone(), link_type, packet)?;
+        self.record(&timing, link_type, packet);
+        if now.saturating_sub(self.last_prune) >= PRUNE_INTERVAL {
+            self.last_prune = now;
+            self.prune();
+        }
+        Ok(())
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        self.inner.on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.inner.on_wireguard_keys(key_log)
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.inner.on_key(client_random, secret_type, secret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::RwLock;
+
+    use super::*;
+    use crate::{
+        dispatch::ListenerDispatcher,
+        http::HTTPStreamEvent,
+        pipeline::Pipeline,
+        test_support::{KeyMessageReorderer, Received, TestListener, H2},
+    };
+
+    type Log = Arc<RwLock<Vec<Received<HTTPStreamEvent>>>>;
+
+    fn stack() -> (CheckpointingChomper<ListenerDispatcher>, Log) {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let gate = ReplayGate::default();
+        let chomper = Pipeline::new()
+            .tls(Default::default())
+            .http()
+            .replay_gate(gate.clone())
+            .build(TestListener {
+                received: received.clone(),
+            });
+        (CheckpointingChomper::new(chomper, gate), received)
+    }
+
+    fn printed(received: &Log) -> Vec<String> {
+        received
+            .read()
+            .unwrap()
+            .iter()
+            .map(|r| r.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let mut frames = KeyMessageReorderer::default();
+        chomp::dump_pcap(io::Cursor::new(H2), &mut frames).unwrap();
+        let (mut whole, whole_received) = stack();
+        frames.send(&mut whole).unwrap();
+
+        // Stop halfway through, in the middle of the HTTP/2 connection
+        let half = frames.packets.len() / 2;
+        let (mut before, before_received) = stack();
+        for key in &frames.keys {
+            before.on_keys(key);
+        }
+        for (timing, link_type, packet) in &frames.packets[..half] {
+            before.chomp(timing.clone(), *link_type, packet).unwrap();
+        }
+        let mut checkpoint = Vec::new();
+        before.checkpoint(&mut checkpoint).unwrap();
+        assert!(!before.flows.is_empty());
+
+        let (mut after, after_received) = stack();
+        after.restore(&checkpoint).unwrap();
+        assert!(after_received.read().unwrap().is_empty());
+        for (timing, link_type, packet) in &frames.packets[half..] {
+            after.chomp(timing.clone(), *link_type, packet).unwrap();
+        }
+
+        let mut resumed = printed(&before_received);
+        resumed.extend(printed(&after_received));
+        assert_eq!(resumed, printed(&whole_received));
+    }
+}
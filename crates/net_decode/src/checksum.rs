@@ -0,0 +1,206 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! IPv4 header, TCP and UDP checksums (RFC 1071).
+//!
+//! Checking is off by default: packets sent by the capturing machine itself
+//! are usually captured before the network card fills their checksums in,
+//! so they would all look corrupt.
+
+use std::{net::IpAddr, str::FromStr};
+
+use crate::{
+    chomp::{IPPROTO_TCP, IPPROTO_UDP},
+    Error,
+};
+
+pub mod side_data {
+    use std::net::IpAddr;
+
+    use super::ChecksumLayer;
+    use crate::listener::TimingInfo;
+
+    /// Fired by `net_decode::chomp` for a packet with a wrong checksum, when
+    /// checking is on.
+    #[derive(Clone, Debug)]
+    pub struct BadChecksum {
+        pub timing: TimingInfo,
+        pub layer: ChecksumLayer,
+        pub src: IpAddr,
+        pub dst: IpAddr,
+    }
+}
+
+/// What to do about checksums.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumMode {
+    #[default]
+    Off,
+    /// Send [`side_data::BadChecksum`] for corrupt packets, and decode them
+    /// anyway.
+    Flag,
+    /// The same, but then drop them.
+    Drop,
+}
+
+impl FromStr for ChecksumMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ChecksumMode::Off),
+            "flag" => Ok(ChecksumMode::Flag),
+            "drop" => Ok(ChecksumMode::Drop),
+            other => {
+                Err(format!("unknown checksum mode {other:?}, expected off, flag or drop").into())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumLayer {
+    Ipv4,
+    Tcp,
+    Udp,
+}
+
+impl ChecksumLayer {
+    /// The layer whose checksum covers an IP payload of protocol `proto`,
+    /// if we check it.
+    pub fn of_proto(proto: u8) -> Option<Self> {
+        match proto {
+            IPPROTO_TCP => Some(ChecksumLayer::Tcp),
+            IPPROTO_UDP => Some(ChecksumLayer::Udp),
+            _ => None,
+        }
+    }
+}
+
+/// Adds up `data` as big endian 16 bit words onto `sum`, without folding
+/// the carries back in yet.
+fn add_words(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Folds the carries of `sum` back into its low 16 bits.
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Whether the one's complement sum comes out as all ones, which is what a
+/// correct checksum makes it.
+fn sums_to_ones(sum: u32) -> bool {
+    fold(sum) == 0xffff
+}
+
+pub(crate) fn ipv4_header_valid(header: &[u8]) -> bool {
+    sums_to_ones(add_words(0, header))
+}
+
+/// The checksum for an IPv4 `header` whose checksum field is zero.
+pub fn ipv4_header_checksum(header: &[u8]) -> u16 {
+    !fold(add_words(0, header))
+}
+
+/// Sum of `segment` and the pseudo-header in front of it, or `None` if
+/// `src` and `dst` are not of the same family.
+fn transport_sum(proto: u8, src: IpAddr, dst: IpAddr, segment: &[u8]) -> Option<u32> {
+    let mut sum = 0;
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            sum = add_words(sum, &src.octets());
+            sum = add_words(sum, &dst.octets());
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            sum = add_words(sum, &src.octets());
+            sum = add_words(sum, &dst.octets());
+        }
+        _ => return None,
+    }
+    // The IPv6 pseudo-header has a 32 bit length, but the same sum.
+    sum += proto as u32 + segment.len() as u32;
+    Some(add_words(sum, segment))
+}
+
+/// Whether the TCP or UDP `segment` between `src` and `dst` has the right
+/// checksum. UDP datagrams without one are fine.
+pub(crate) fn transport_valid(proto: u8, src: IpAddr, dst: IpAddr, segment: &[u8]) -> bool {
+    if proto == IPPROTO_UDP && segment.get(6..8) == Some(&[0, 0][..]) {
+        return true;
+    }
+    transport_sum(proto, src, dst, segment).is_some_and(sums_to_ones)
+}
+
+/// The checksum for a TCP or UDP `segment` between `src` and `dst` whose
+/// checksum field is zero, or `None` if the addresses are not of the same
+/// family.
+pub fn transport_checksum(proto: u8, src: IpAddr, dst: IpAddr, segment: &[u8]) -> Option<u16> {
+    transport_sum(proto, src, dst, segment).map(|sum| !fold(sum))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ipv4_header() {
+        // The usual worked example, with checksum 0xb861
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert!(ipv4_header_valid(&header));
+        header[8] = 0x3f;
+        assert!(!ipv4_header_valid(&header));
+    }
+
+    #[test]
+    fn test_udp() {
+        let (src, dst): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        // 5353 -> 53, odd length to exercise the padding
+        let mut udp = vec![0x14, 0xe9, 0x00, 0x35, 0x00, 0x0b, 0x4d, 0x4e];
+        udp.extend(b"hi!");
+        assert!(transport_valid(IPPROTO_UDP, src, dst, &udp));
+
+        udp[8] ^= 1;
+        assert!(!transport_valid(IPPROTO_UDP, src, dst, &udp));
+        // No checksum at all
+        udp[6..8].copy_from_slice(&[0, 0]);
+        assert!(transport_valid(IPPROTO_UDP, src, dst, &udp));
+        // TCP has no such exception
+        assert!(!transport_valid(IPPROTO_TCP, src, dst, &udp));
+    }
+
+    #[test]
+    fn test_make_checksums() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(ipv4_header_checksum(&header), 0xb861);
+        header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert!(ipv4_header_valid(&header));
+
+        let (src, dst): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let mut udp = vec![0x14, 0xe9, 0x00, 0x35, 0x00, 0x0b, 0x00, 0x00];
+        udp.extend(b"hi!");
+        let checksum = transport_checksum(IPPROTO_UDP, src, dst, &udp).unwrap();
+        assert_eq!(checksum, 0x4d4e);
+        assert_eq!(
+            transport_checksum(IPPROTO_UDP, src, "::1".parse().unwrap(), &udp),
+            None
+        );
+    }
+}
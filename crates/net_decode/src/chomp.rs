@@ -3,25 +3,32 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use crate::{
+    capture_file::{self, Record},
+    capture_merge::{self, MergeInput},
+    checksum::{self, side_data::BadChecksum, ChecksumLayer, ChecksumMode},
+    icmp,
+    ip_fragment::{FragmentKey, FragmentReassembler},
     key_db::{ClientRandom, KeyDB, Secret, SecretType},
+    link::{self, LinkStats},
     listener::{Listener, Nanos, TimingInfo},
+    sctp::{self, SctpFollower},
     tcp_reassemble::TcpFollower,
-    tls, Error,
+    tls,
+    tunnel::{self, Tunnel, TunnelKind},
+    udp_flow::{self, UdpFollower},
+    wireguard::WireGuardDecryptor,
+    Error,
 };
-use pcap_parser::{
-    traits::{PcapNGPacketBlock, PcapReaderIterator},
-    InterfaceDescriptionBlock, PcapError, PcapNGReader,
-};
-use pktparse::{ethernet::EtherType, tcp::TcpHeader};
+use bytes::Bytes;
+use pcap_parser::Linktype;
+use pktparse::{ip::IPProtocol, tcp::TcpHeader};
 use std::{
-    collections::BTreeMap,
     fmt::{self, Debug},
-    fs, io,
-    net::{Ipv4Addr, Ipv6Addr},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     sync::{Arc, RwLock},
 };
-use tracing::Level;
 
 #[derive(Clone, Debug)]
 pub enum IPHeader {
@@ -29,6 +36,119 @@ pub enum IPHeader {
     V6(pktparse::ipv6::IPv6Header),
 }
 
+pub(crate) const IPPROTO_TCP: u8 = 6;
+pub(crate) const IPPROTO_UDP: u8 = 17;
+
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTH: u8 = 51;
+const IPV6_DEST_OPTS: u8 = 60;
+
+/// Where an IPv6 packet's extension headers lead.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Ipv6Payload<'a> {
+    /// The upper-layer protocol number and its data.
+    Upper(u8, &'a [u8]),
+    /// Part of a fragmented datagram, which is `next_header` once put back
+    /// together.
+    Fragment {
+        next_header: u8,
+        id: u32,
+        offset: usize,
+        more: bool,
+        data: &'a [u8],
+    },
+}
+
+/// Skips the IPv6 extension headers at the start of `data`. `None` if the
+/// packet is truncated.
+pub(crate) fn ipv6_upper_layer(mut next_header: u8, mut data: &[u8]) -> Option<Ipv6Payload<'_>> {
+    loop {
+        let len = match next_header {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTS => (*data.get(1)? as usize + 1) * 8,
+            IPV6_AUTH => (*data.get(1)? as usize + 2) * 4,
+            IPV6_FRAGMENT => {
+                // Offset (13 bits), reserved (2 bits), more fragments (1 bit).
+                // Atomic fragments (RFC 6946) are whole packets.
+                let field = u16::from_be_bytes(data.get(2..4)?.try_into().unwrap());
+                if field >> 3 != 0 || field & 1 != 0 {
+                    return Some(Ipv6Payload::Fragment {
+                        next_header: data[0],
+                        id: u32::from_be_bytes(data.get(4..8)?.try_into().unwrap()),
+                        offset: (field >> 3) as usize * 8,
+                        more: field & 1 != 0,
+                        data: &data[8..],
+                    });
+                }
+                8
+            }
+            _ => return Some(Ipv6Payload::Upper(next_header, data)),
+        };
+        next_header = *data.first()?;
+        data = data.get(len..)?;
+    }
+}
+
+/// Which flow a frame is part of, as far as can be told without following
+/// it.
+pub(crate) struct Segment {
+    /// `IPPROTO_TCP` or `IPPROTO_UDP`.
+    pub proto: u8,
+    /// With the sender as the client.
+    pub target: IPTarget,
+    /// 0 for UDP.
+    pub tcp_flags: u8,
+    /// Bytes of data past the TCP or UDP header.
+    pub payload_len: usize,
+}
+
+/// The TCP or UDP segment in a frame, if it is one. Fragments and tunnelled
+/// packets are not looked into.
+pub(crate) fn transport_segment(link_type: Linktype, packet: &[u8]) -> Option<Segment> {
+    let frame = link::parse_link(link_type, packet, &mut Vec::new())?;
+    let ip = frame.payload;
+    let (header, proto, segment) = match frame.ethertype {
+        link::ETHERTYPE_IPV4 => {
+            let (_, header) = pktparse::ipv4::parse_ipv4_header(ip).ok()?;
+            // More fragments (1 bit), then offset (13 bits).
+            let fragment = u16::from_be_bytes([ip[6], ip[7]]);
+            if fragment & 0x3fff != 0 {
+                return None;
+            }
+            // Leaving out the options, and any padding after the packet.
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let total_len = (u16::from_be_bytes([ip[2], ip[3]]) as usize).min(ip.len());
+            (IPHeader::V4(header), ip[9], ip.get(header_len..total_len)?)
+        }
+        link::ETHERTYPE_IPV6 => {
+            let (rest, header) = pktparse::ipv6::parse_ipv6_header(ip).ok()?;
+            let payload_len = (u16::from_be_bytes([ip[4], ip[5]]) as usize).min(rest.len());
+            match ipv6_upper_layer(ip[6], &rest[..payload_len])? {
+                Ipv6Payload::Upper(proto, payload) => (IPHeader::V6(header), proto, payload),
+                Ipv6Payload::Fragment { .. } => return None,
+            }
+        }
+        _ => return None,
+    };
+    let (tcp_flags, header_len) = match proto {
+        IPPROTO_TCP => (*segment.get(13)?, (*segment.get(12)? >> 4) as usize * 4),
+        IPPROTO_UDP => (0, 8),
+        _ => return None,
+    };
+    let ports = segment.get(..4)?;
+    Some(Segment {
+        proto,
+        target: IPTarget::from_ports(
+            &header,
+            u16::from_be_bytes([ports[0], ports[1]]),
+            u16::from_be_bytes([ports[2], ports[3]]),
+        ),
+        tcp_flags,
+        payload_len: segment.len().saturating_sub(header_len),
+    })
+}
+
 impl IPHeader {
     pub fn proto(&self) -> pktparse::ip::IPProtocol {
         match self {
@@ -36,6 +156,14 @@ impl IPHeader {
             IPHeader::V6(v6) => v6.next_header,
         }
     }
+
+    /// The ECN field (RFC 3168), the low two bits of the traffic class.
+    pub fn ecn(&self) -> u8 {
+        match self {
+            IPHeader::V4(v4) => v4.tos & 0b11,
+            IPHeader::V6(v6) => v6.ecn,
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
@@ -45,12 +173,15 @@ pub enum IPTarget {
         server_port: u16,
         client_ip: Ipv4Addr,
         server_ip: Ipv4Addr,
+        /// See [`tunnel::overlay_id`].
+        overlay: Option<u32>,
     },
     V6 {
         client_port: u16,
         server_port: u16,
         client_ip: Ipv6Addr,
         server_ip: Ipv6Addr,
+        overlay: Option<u32>,
     },
 }
 
@@ -62,42 +193,67 @@ impl Debug for IPTarget {
                 server_port: dest_port,
                 client_ip: source_ip,
                 server_ip: dest_ip,
-            } => {
-                write!(f, "{source_ip:?}:{source_port} -> {dest_ip:?}:{dest_port}")
-            }
+                ..
+            } => write!(f, "{source_ip:?}:{source_port} -> {dest_ip:?}:{dest_port}")?,
             Self::V6 {
                 client_port: source_port,
                 server_port: dest_port,
                 client_ip: source_ip,
                 server_ip: dest_ip,
-            } => {
-                write!(
-                    f,
-                    "[{source_ip:?}]:{source_port} -> [{dest_ip:?}]:{dest_port}"
-                )
-            }
+                ..
+            } => write!(
+                f,
+                "[{source_ip:?}]:{source_port} -> [{dest_ip:?}]:{dest_port}"
+            )?,
+        }
+        if let Some(overlay) = self.overlay() {
+            write!(f, " (overlay {overlay})")?;
         }
+        Ok(())
     }
 }
 
 impl IPTarget {
     pub fn from_headers(ip: &IPHeader, tcp: &TcpHeader) -> IPTarget {
+        Self::from_ports(ip, tcp.source_port, tcp.dest_port)
+    }
+
+    /// Taking the source of the packet as the client.
+    pub fn from_ports(ip: &IPHeader, source_port: u16, dest_port: u16) -> IPTarget {
         match ip {
             IPHeader::V4(v4) => IPTarget::V4 {
-                client_port: tcp.source_port,
-                server_port: tcp.dest_port,
+                client_port: source_port,
+                server_port: dest_port,
                 client_ip: v4.source_addr,
                 server_ip: v4.dest_addr,
+                overlay: None,
             },
             IPHeader::V6(v6) => IPTarget::V6 {
-                client_port: tcp.source_port,
-                server_port: tcp.dest_port,
+                client_port: source_port,
+                server_port: dest_port,
                 client_ip: v6.source_addr,
                 server_ip: v6.dest_addr,
+                overlay: None,
             },
         }
     }
 
+    /// The same flow on the overlay network `overlay`.
+    pub fn on_overlay(mut self, overlay: Option<u32>) -> IPTarget {
+        match &mut self {
+            IPTarget::V4 { overlay: o, .. } | IPTarget::V6 { overlay: o, .. } => *o = overlay,
+        }
+        self
+    }
+
+    /// Which overlay network the flow is on, if it came out of a tunnel.
+    /// Flows are told apart by it too, since overlays may reuse addresses.
+    pub fn overlay(&self) -> Option<u32> {
+        match self {
+            IPTarget::V4 { overlay, .. } | IPTarget::V6 { overlay, .. } => *overlay,
+        }
+    }
+
     pub fn server_port(&self) -> u16 {
         match self {
             IPTarget::V4 { server_port, .. } => *server_port,
@@ -105,6 +261,47 @@ impl IPTarget {
         }
     }
 
+    pub fn server_addr(&self) -> SocketAddr {
+        match *self {
+            IPTarget::V4 {
+                server_ip,
+                server_port,
+                ..
+            } => SocketAddr::new(server_ip.into(), server_port),
+            IPTarget::V6 {
+                server_ip,
+                server_port,
+                ..
+            } => SocketAddr::new(server_ip.into(), server_port),
+        }
+    }
+
+    pub fn client_addr(&self) -> SocketAddr {
+        match *self {
+            IPTarget::V4 {
+                client_ip,
+                client_port,
+                ..
+            } => SocketAddr::new(client_ip.into(), client_port),
+            IPTarget::V6 {
+                client_ip,
+                client_port,
+                ..
+            } => SocketAddr::new(client_ip.into(), client_port),
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, IPTarget::V6 { .. })
+    }
+
+    pub fn client_port(&self) -> u16 {
+        match self {
+            IPTarget::V4 { client_port, .. } => *client_port,
+            IPTarget::V6 { client_port, .. } => *client_port,
+        }
+    }
+
     pub fn flip(self) -> IPTarget {
         match self {
             IPTarget::V4 {
@@ -112,77 +309,440 @@ impl IPTarget {
                 server_port: dest_port,
                 client_ip: source_ip,
                 server_ip: dest_ip,
+                overlay,
             } => IPTarget::V4 {
                 client_port: dest_port,
                 server_port: source_port,
                 client_ip: dest_ip,
                 server_ip: source_ip,
+                overlay,
             },
             IPTarget::V6 {
                 client_port: source_port,
                 server_port: dest_port,
                 client_ip: source_ip,
                 server_ip: dest_ip,
+                overlay,
             } => IPTarget::V6 {
                 client_port: dest_port,
                 server_port: source_port,
                 client_ip: dest_ip,
                 server_ip: source_ip,
+                overlay,
             },
         }
     }
 }
 
-pub struct EthernetChomper<Recv: Listener<Vec<u8>>> {
+/// How many tunnels deep we will look for packets.
+const MAX_TUNNEL_DEPTH: usize = 4;
+
+pub struct EthernetChomper<Recv: Listener<Bytes>> {
     pub tcp_follower: TcpFollower,
+    pub udp_follower: UdpFollower,
+    pub sctp_follower: SctpFollower,
+    pub fragments: FragmentReassembler,
+    pub link_stats: LinkStats,
+    pub wireguard: WireGuardDecryptor,
+    pub checksums: ChecksumMode,
     pub recv: Recv,
     pub key_db: Arc<RwLock<KeyDB>>,
 }
 
 pub trait FrameChomper {
-    fn chomp(&mut self, timing: TimingInfo, packet: &[u8]) -> Result<(), Error>;
+    /// Takes one frame captured on an interface of `link_type`.
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error>;
     fn on_keys(&mut self, dsb: &[u8]);
+    /// Takes a WireGuard key log, see [`crate::wireguard`].
+    fn on_wireguard_keys(&mut self, key_log: &[u8]);
     fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret);
 }
 
-impl<Recv: Listener<Vec<u8>>> FrameChomper for EthernetChomper<Recv> {
-    fn chomp(&mut self, timing: TimingInfo, packet: &[u8]) -> Result<(), Error> {
-        if let Ok((remain, frame)) = pktparse::ethernet::parse_ethernet_frame(&packet) {
-            // tracing::debug!("frame! {:?}", &frame);
-            match frame.ethertype {
-                EtherType::IPv4 => {
-                    if let Ok((remain, pkt)) = pktparse::ipv4::parse_ipv4_header(remain) {
-                        // tracing::debug!("ipv4 pakit! {:?}", &pkt);
-                        self.tcp_follower.chomp(
-                            timing,
-                            IPHeader::V4(pkt),
-                            remain,
-                            &mut self.recv,
-                        )?;
+impl<C: FrameChomper + ?Sized> FrameChomper for &mut C {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        (**self).chomp(timing, link_type, packet)
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        (**self).on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        (**self).on_wireguard_keys(key_log)
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        (**self).on_key(client_random, secret_type, secret)
+    }
+}
+
+impl<Recv: Listener<Bytes>> EthernetChomper<Recv> {
+    /// Ends the TCP and UDP flows that have gone idle by `now`, as the next
+    /// packet would. For the end of a capture, pass [`Nanos::MAX`] to end
+    /// every flow.
+    pub fn advance_time(&mut self, now: Nanos) {
+        self.tcp_follower.advance_time(now, &mut self.recv);
+        self.udp_follower.advance_time(now, &mut self.recv);
+    }
+
+    /// Sends [`BadChecksum`] if checking is on and `valid` says the checksum
+    /// at `layer` is wrong. Returns whether to carry on with the packet.
+    fn check_checksum(
+        &mut self,
+        timing: &TimingInfo,
+        layer: ChecksumLayer,
+        (src, dst): (IpAddr, IpAddr),
+        valid: impl FnOnce() -> bool,
+    ) -> bool {
+        if self.checksums == ChecksumMode::Off || valid() {
+            return true;
+        }
+        tracing::debug!("bad {layer:?} checksum from {src} to {dst}");
+        self.recv.on_side_data(Box::new(BadChecksum {
+            timing: timing.clone(),
+            layer,
+            src,
+            dst,
+        }));
+        self.checksums != ChecksumMode::Drop
+    }
+
+    /// The same for the TCP or UDP checksum of a whole IP payload.
+    fn check_transport(
+        &mut self,
+        timing: &TimingInfo,
+        proto: u8,
+        addrs: (IpAddr, IpAddr),
+        payload: &[u8],
+    ) -> bool {
+        let Some(layer) = ChecksumLayer::of_proto(proto) else {
+            return true;
+        };
+        self.check_checksum(timing, layer, addrs, || {
+            checksum::transport_valid(proto, addrs.0, addrs.1, payload)
+        })
+    }
+
+    /// Takes whatever follows the link layer header.
+    fn chomp_ethertype(
+        &mut self,
+        timing: TimingInfo,
+        ethertype: u16,
+        remain: &[u8],
+    ) -> Result<(), Error> {
+        match ethertype {
+            link::ETHERTYPE_IPV4 => {
+                if let Ok((_, pkt)) = pktparse::ipv4::parse_ipv4_header(remain) {
+                    // tracing::debug!("ipv4 pakit! {:?}", &pkt);
+                    let header_len = (remain[0] & 0xf) as usize * 4;
+                    // Only as much as the header says is there, since
+                    // short Ethernet frames are padded. Segmentation
+                    // offload can leave the length zero on outgoing
+                    // packets, though.
+                    let len_field = u16::from_be_bytes([remain[2], remain[3]]) as usize;
+                    let total_len = match len_field {
+                        0 => remain.len(),
+                        len => len.min(remain.len()),
+                    };
+                    let Some(payload) = remain.get(header_len..total_len) else {
+                        tracing::debug!("ignored truncated ipv4 packet");
+                        return Ok(());
+                    };
+                    // What the capture cut off the end, e.g. with a snap
+                    // length.
+                    let missing = len_field.saturating_sub(remain.len());
+                    let addrs = (pkt.source_addr.into(), pkt.dest_addr.into());
+                    if !self.check_checksum(&timing, ChecksumLayer::Ipv4, addrs, || {
+                        checksum::ipv4_header_valid(&remain[..header_len])
+                    }) {
+                        return Ok(());
                     }
-                }
-                EtherType::IPv6 => {
-                    if let Ok((remain, pkt)) = pktparse::ipv6::parse_ipv6_header(remain) {
-                        tracing::debug!("ipv6 pakit! {:?}", &pkt);
-                        self.tcp_follower.chomp(
-                            timing,
-                            IPHeader::V6(pkt),
-                            remain,
-                            &mut self.recv,
-                        )?;
+                    // Whether we have all of the payload to check its
+                    // checksum with.
+                    let mut whole = len_field != 0 && len_field <= remain.len();
+
+                    // Flags (3 bits), of which the last is more
+                    // fragments, then offset (13 bits).
+                    let field = u16::from_be_bytes([remain[6], remain[7]]);
+                    let (more, offset) = (field & 0x2000 != 0, (field & 0x1fff) as usize * 8);
+                    let datagram;
+                    let payload = if more || offset != 0 {
+                        if missing > 0 {
+                            tracing::debug!("ignored truncated ipv4 fragment");
+                            return Ok(());
+                        }
+                        let key = FragmentKey {
+                            src: pkt.source_addr.into(),
+                            dst: pkt.dest_addr.into(),
+                            id: u16::from_be_bytes([remain[4], remain[5]]) as u32,
+                            proto: remain[9],
+                        };
+                        match self.fragments.on_fragment(
+                            timing.received_on_wire,
+                            key,
+                            offset,
+                            more,
+                            payload,
+                        ) {
+                            Some(reassembled) => {
+                                datagram = reassembled;
+                                whole = true;
+                                &datagram[..]
+                            }
+                            None => return Ok(()),
+                        }
+                    } else {
+                        payload
+                    };
+
+                    let proto = remain[9];
+                    if whole && !self.check_transport(&timing, proto, addrs, payload) {
+                        return Ok(());
                     }
+                    self.chomp_ip_payload(timing, IPHeader::V4(pkt), proto, payload, missing)?;
                 }
-                _ => {
-                    tracing::warn!(
-                        "ignored frame with unsupported ethertype {:?}",
-                        frame.ethertype
-                    );
+            }
+            link::ETHERTYPE_IPV6 => {
+                if let Ok((payload, mut pkt)) = pktparse::ipv6::parse_ipv6_header(remain) {
+                    tracing::trace!("ipv6 pakit! {:?}", &pkt);
+                    // Only as much as the header says is there, since
+                    // short Ethernet frames are padded.
+                    let payload_len = u16::from_be_bytes([remain[4], remain[5]]) as usize;
+                    let mut whole = payload_len != 0 && payload_len <= payload.len();
+                    let missing = payload_len.saturating_sub(payload.len());
+                    let payload = &payload[..payload.len().min(payload_len)];
+                    let datagram;
+                    let (proto, payload) = match ipv6_upper_layer(remain[6], payload) {
+                        Some(Ipv6Payload::Upper(proto, payload)) => (proto, payload),
+                        Some(Ipv6Payload::Fragment {
+                            next_header,
+                            id,
+                            offset,
+                            more,
+                            data,
+                        }) => {
+                            if missing > 0 {
+                                tracing::debug!("ignored truncated ipv6 fragment");
+                                return Ok(());
+                            }
+                            let key = FragmentKey {
+                                src: pkt.source_addr.into(),
+                                dst: pkt.dest_addr.into(),
+                                id,
+                                proto: next_header,
+                            };
+                            let Some(reassembled) = self.fragments.on_fragment(
+                                timing.received_on_wire,
+                                key,
+                                offset,
+                                more,
+                                data,
+                            ) else {
+                                return Ok(());
+                            };
+                            datagram = reassembled;
+                            whole = true;
+                            match ipv6_upper_layer(next_header, &datagram) {
+                                Some(Ipv6Payload::Upper(proto, payload)) => (proto, payload),
+                                _ => {
+                                    tracing::debug!("ignored malformed fragmented ipv6 packet");
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        None => {
+                            tracing::debug!("ignored truncated ipv6 packet");
+                            return Ok(());
+                        }
+                    };
+                    // The fixed header points at the first extension
+                    // header, if any, rather than what comes after them.
+                    match proto {
+                        IPPROTO_TCP => pkt.next_header = IPProtocol::TCP,
+                        IPPROTO_UDP => pkt.next_header = IPProtocol::UDP,
+                        _ => {}
+                    }
+                    // FIXME: with a routing header, the checksum is over the
+                    // final destination rather than this one.
+                    let addrs = (pkt.source_addr.into(), pkt.dest_addr.into());
+                    if whole && !self.check_transport(&timing, proto, addrs, payload) {
+                        return Ok(());
+                    }
+                    self.chomp_ip_payload(timing, IPHeader::V6(pkt), proto, payload, missing)?;
                 }
             }
+            _ => {
+                tracing::warn!("ignored frame with unsupported ethertype {ethertype:#06x}");
+            }
         }
         Ok(())
     }
 
+    /// If the IP payload `payload` is a WireGuard message we have keys for,
+    /// decrypts it.
+    fn decrypt_wireguard(&mut self, proto: u8, payload: &[u8]) -> Option<(u32, Vec<u8>)> {
+        if proto != IPPROTO_UDP {
+            return None;
+        }
+        let key_db = self.key_db.read().unwrap();
+        let keys = key_db.wireguard_keys();
+        if keys.is_empty() {
+            return None;
+        }
+        let (_, _, datagram) = udp_flow::parse_udp(payload)?;
+        self.wireguard.on_datagram(keys, datagram)
+    }
+
+    /// Passes ICMP errors on to the flow of the packet they quote.
+    fn chomp_icmp(&mut self, timing: TimingInfo, header: &IPHeader, proto: u8, payload: &[u8]) {
+        let Some(mut error) = icmp::parse_error(proto, payload) else {
+            return;
+        };
+        let reporter = match header {
+            IPHeader::V4(v4) => v4.source_addr.into(),
+            IPHeader::V6(v6) => v6.source_addr.into(),
+        };
+        tracing::debug!(
+            "icmp {:?} from {reporter:?} about {:?}",
+            error.kind,
+            error.target
+        );
+        // The error came back through the same tunnel as the flow.
+        error.target = error.target.on_overlay(tunnel::overlay_id(&timing.tunnels));
+        match error.proto {
+            IPPROTO_TCP => self.tcp_follower.on_icmp_error(
+                timing,
+                error.target,
+                error.kind,
+                reporter,
+                &mut self.recv,
+            ),
+            IPPROTO_UDP => self.udp_follower.on_icmp_error(
+                timing,
+                error.target,
+                error.kind,
+                reporter,
+                &mut self.recv,
+            ),
+            _ => {}
+        }
+    }
+
+    /// Takes the payload of an IP packet, of protocol `proto`, looking
+    /// inside it if it is a tunnel. The capture cut `missing` bytes off the
+    /// end of it.
+    fn chomp_ip_payload(
+        &mut self,
+        mut timing: TimingInfo,
+        header: IPHeader,
+        proto: u8,
+        payload: &[u8],
+        missing: usize,
+    ) -> Result<(), Error> {
+        if proto == icmp::IPPROTO_ICMP || proto == icmp::IPPROTO_ICMPV6 {
+            self.chomp_icmp(timing, &header, proto, payload);
+            return Ok(());
+        }
+        if proto == sctp::IPPROTO_SCTP {
+            self.sctp_follower
+                .chomp(timing, &header, payload, &mut self.recv);
+            return Ok(());
+        }
+        if proto == IPPROTO_UDP {
+            self.udp_follower
+                .chomp(timing.clone(), &header, payload, &mut self.recv);
+        }
+
+        let decrypted;
+        let found = match self.decrypt_wireguard(proto, payload) {
+            Some((receiver, packet)) => {
+                decrypted = packet;
+                let ethertype = match decrypted[0] >> 4 {
+                    4 => link::ETHERTYPE_IPV4,
+                    6 => link::ETHERTYPE_IPV6,
+                    _ => {
+                        tracing::debug!("ignored non-IP packet from wireguard tunnel");
+                        return Ok(());
+                    }
+                };
+                Some((
+                    TunnelKind::WireGuard,
+                    Some(receiver),
+                    tunnel::Inner::Network(ethertype, &decrypted[..]),
+                ))
+            }
+            None => tunnel::decapsulate(proto, payload),
+        };
+        let Some((kind, id, inner)) = found else {
+            if proto == IPPROTO_UDP {
+                return Ok(());
+            }
+            return self
+                .tcp_follower
+                .chomp(timing, header, payload, missing, &mut self.recv);
+        };
+        if timing.tunnels.len() >= MAX_TUNNEL_DEPTH {
+            tracing::debug!("ignored packet nested in too many tunnels");
+            return Ok(());
+        }
+
+        let (outer_src, outer_dst) = match &header {
+            IPHeader::V4(v4) => (v4.source_addr.into(), v4.dest_addr.into()),
+            IPHeader::V6(v6) => (v6.source_addr.into(), v6.dest_addr.into()),
+        };
+        timing.tunnels.push(Tunnel {
+            kind,
+            id,
+            outer_src,
+            outer_dst,
+        });
+        match inner {
+            tunnel::Inner::Ethernet(frame) => {
+                let mut tags = Vec::new();
+                let Some((ethertype, remain)) = link::parse_ethernet(frame, &mut tags) else {
+                    tracing::debug!("ignored truncated frame in {kind:?} tunnel");
+                    return Ok(());
+                };
+                self.link_stats.record(&tags);
+                self.chomp_ethertype(timing, ethertype, remain)
+            }
+            tunnel::Inner::Network(ethertype, packet) => {
+                self.chomp_ethertype(timing, ethertype, packet)
+            }
+        }
+    }
+}
+
+impl<Recv: Listener<Bytes>> FrameChomper for EthernetChomper<Recv> {
+    fn chomp(
+        &mut self,
+        mut timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        self.link_stats.frames += 1;
+        self.link_stats.bytes += packet.len() as u64;
+        let mut tags = Vec::new();
+        let Some(frame) = link::parse_link(link_type, packet, &mut tags) else {
+            tracing::debug!("ignored truncated or unsupported {link_type:?} frame");
+            return Ok(());
+        };
+        self.link_stats.record(&tags);
+        // A cooked header knows better than the capture around it.
+        timing.direction = frame.direction.or(timing.direction);
+        self.chomp_ethertype(timing, frame.ethertype, frame.payload)
+    }
+
     fn on_keys(&mut self, dsb: &[u8]) {
         // We definitely don't want to be holding the lock when sending
         // notifications of these downstream.
@@ -199,11 +759,17 @@ impl<Recv: Listener<Vec<u8>>> FrameChomper for EthernetChomper<Recv> {
             });
         }
 
+        tracing::debug!("loaded {} secrets from the capture", new_keys.len());
         for k in new_keys {
             self.recv.on_side_data(Box::new(k));
         }
     }
 
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        let loaded = self.key_db.write().unwrap().load_wireguard_key_log(key_log);
+        tracing::debug!("loaded {loaded} wireguard keys from the capture");
+    }
+
     fn on_key(&mut self, client_random: ClientRandom, typ: SecretType, secret: Secret) {
         self.recv
             .on_side_data(Box::new(tls::side_data::NewKeyReceived {
@@ -214,123 +780,269 @@ impl<Recv: Listener<Vec<u8>>> FrameChomper for EthernetChomper<Recv> {
     }
 }
 
-/// Timestamps in pcapng have resolution dependent on the capture interface.
-/// This is a pain in the ass, but we have to implement it.
-struct InterfaceDescriptor {
-    timestamp_to_nanos: u64,
+fn chomp_record(chomper: &mut dyn FrameChomper, record: Record<'_>) -> Result<(), Error> {
+    match record {
+        Record::Frame(frame) => chomper.chomp(frame.timing, frame.link_type, frame.data)?,
+        Record::TlsKeys(keys) => chomper.on_keys(keys),
+        Record::WireguardKeys(keys) => chomper.on_wireguard_keys(keys),
+    }
+    Ok(())
 }
 
-impl InterfaceDescriptor {
-    fn resolve_timestamp(&self, low: u32, high: u32) -> Nanos {
-        let full = ((high as u64) << 32) | (low as u64);
-
-        full * self.timestamp_to_nanos
+/// Feeds a capture file through `chomper`. A `file` of `-` is read from
+/// stdin.
+pub fn dump_pcap_file(file: PathBuf, chomper: &mut dyn FrameChomper) -> Result<(), Error> {
+    if file.as_os_str() == "-" {
+        return dump_pcap(io::stdin().lock(), chomper);
     }
+    capture_file::read_capture_file(&file, &mut |_, record| chomp_record(chomper, record))?;
+    Ok(())
 }
 
-impl From<InterfaceDescriptionBlock<'_>> for InterfaceDescriptor {
-    fn from(value: InterfaceDescriptionBlock) -> Self {
-        const NS_PER_S: u64 = 1_000_000_000;
-        const DEFAULT_RESOLUTION: u64 = 1_000_000;
-        let ticks_per_sec = value.ts_resolution().unwrap_or(DEFAULT_RESOLUTION);
-        let nsec_per_tick = NS_PER_S / ticks_per_sec;
-
-        Self {
-            timestamp_to_nanos: nsec_per_tick,
-        }
-    }
+/// Feeds a pcap or pcapng capture through `chomper`.
+pub fn dump_pcap<Reader>(reader: Reader, chomper: &mut dyn FrameChomper) -> Result<(), Error>
+where
+    Reader: io::Read,
+{
+    capture_file::read_capture(reader, &mut |_, record| chomp_record(chomper, record))?;
+    Ok(())
 }
 
-#[derive(Default)]
-struct InterfaceDB {
-    last_seen: u32,
-    interfaces: BTreeMap<u32, InterfaceDescriptor>,
+/// Feeds several capture files through `chomper` as one, in order of
+/// time. See [`capture_merge`].
+pub fn dump_merged_pcaps(
+    inputs: Vec<MergeInput>,
+    chomper: &mut dyn FrameChomper,
+) -> Result<(), Error> {
+    capture_merge::read_captures_merged(inputs, &mut |_, record| chomp_record(chomper, record))?;
+    Ok(())
 }
 
-impl InterfaceDB {
-    fn on_interface(&mut self, idb: InterfaceDescriptionBlock) {
-        let id = self.last_seen;
-        self.last_seen += 1;
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Cursor,
+        sync::{Arc, RwLock},
+    };
 
-        self.interfaces.insert(id, idb.into());
+    use super::*;
+    use crate::{
+        http::HTTPStreamEvent,
+        link::{ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+        test_support::*,
+    };
+
+    /// Turns an Ethernet frame carrying IPv4 into one carrying IPv6 from
+    /// 2001:db8::(last byte of the v4 address), with hop-by-hop and
+    /// destination options headers before the payload, and padding after.
+    fn to_ipv6_with_extension_headers(frame: &[u8]) -> Vec<u8> {
+        let (eth, ip) = frame.split_at(14);
+        let header_len = (ip[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+        let payload = &ip[header_len..total_len];
+        let address = |v4: &[u8]| {
+            let mut v6 = [0; 16];
+            v6[..4].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+            v6[15] = v4[3];
+            v6
+        };
+
+        let mut out = eth[..12].to_vec();
+        out.extend(ETHERTYPE_IPV6.to_be_bytes());
+        out.extend([0x60, 0, 0, 0]);
+        out.extend((16 + payload.len() as u16).to_be_bytes());
+        out.extend([IPV6_HOP_BY_HOP, 64]);
+        out.extend(address(&ip[12..16]));
+        out.extend(address(&ip[16..20]));
+        // Each is 8 bytes: next header, length, then a PadN option.
+        out.extend([IPV6_DEST_OPTS, 0, 1, 4, 0, 0, 0, 0]);
+        out.extend([ip[9], 0, 1, 4, 0, 0, 0, 0]);
+        out.extend(payload);
+        out.extend([0; 6]);
+        out
     }
 
-    fn get_interface(&self, id: u32) -> Option<&InterfaceDescriptor> {
-        self.interfaces.get(&id)
+    #[test]
+    fn test_wireguard_keys_replayed() {
+        let block = |typ: u32, body: &[u8]| {
+            let len = 12 + body.len() as u32;
+            let mut b = typ.to_le_bytes().to_vec();
+            b.extend(len.to_le_bytes());
+            b.extend(body);
+            b.extend(len.to_le_bytes());
+            b
+        };
+        let key_log = b"LOCAL_STATIC_PRIVATE_KEY = QChaGDXeH3eQsbFAhueUNWFdq9KfpF3yl+eITjZbXEk=\n";
+        // Decryption secrets block holding a WireGuard key log
+        let mut dsb = 0x5747_4b4cu32.to_le_bytes().to_vec();
+        dsb.extend((key_log.len() as u32).to_le_bytes());
+        dsb.extend(key_log);
+        dsb.resize((dsb.len() + 3) & !3, 0);
+
+        // Section header: byte order magic, version 1.0, unknown length
+        let mut pcapng = block(
+            0x0a0d_0d0a,
+            &[
+                0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ],
+        );
+        pcapng.extend(block(0x0a, &dsb));
+
+        let mut reorderer = KeyMessageReorderer::default();
+        dump_pcap(&mut Cursor::new(pcapng), &mut reorderer).unwrap();
+        assert_eq!(reorderer.wireguard_keys, [key_log.to_vec()]);
+
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        reorderer
+            .send(&mut tls_chomper(key_db.clone(), received))
+            .unwrap();
+        assert!(!key_db.read().unwrap().wireguard_keys().is_empty());
     }
-}
 
-pub fn dump_pcap_file(file: PathBuf, chomper: &mut dyn FrameChomper) -> Result<(), Error> {
-    let f = io::BufReader::new(fs::OpenOptions::new().read(true).open(file)?);
-    dump_pcap(f, chomper)
-}
+    /// Wraps an Ethernet frame in VXLAN with network identifier `vni`.
+    fn in_vxlan(vni: u32, frame: &[u8]) -> Vec<u8> {
+        let mut udp = 50000u16.to_be_bytes().to_vec();
+        udp.extend(tunnel::VXLAN_PORT.to_be_bytes());
+        udp.extend((16 + frame.len() as u16).to_be_bytes());
+        udp.extend([0, 0]);
+        udp.extend([0x08, 0, 0, 0]);
+        udp.extend((vni << 8).to_be_bytes());
+        udp.extend(frame);
 
-pub fn dump_pcap<Reader>(reader: Reader, chomper: &mut dyn FrameChomper) -> Result<(), Error>
-where
-    Reader: io::Seek + io::Read,
-{
-    let mut pcap = PcapNGReader::new(65536, reader)?;
+        let mut ip = vec![0x45, 0];
+        ip.extend((20 + udp.len() as u16).to_be_bytes());
+        ip.extend([0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+        ip.extend([192, 0, 2, 1, 192, 0, 2, 2]);
+        let sum = crate::checksum::ipv4_header_checksum(&ip);
+        ip[10..12].copy_from_slice(&sum.to_be_bytes());
 
-    let mut packet_count = 1u64;
-    let mut iface_db = InterfaceDB::default();
+        let mut out = vec![0x02, 0, 0, 0, 0, 2, 0x02, 0, 0, 0, 0, 1];
+        out.extend(ETHERTYPE_IPV4.to_be_bytes());
+        out.extend(ip);
+        out.extend(udp);
+        out
+    }
 
-    loop {
-        match pcap.next() {
-            Ok((offset, block)) => {
-                let span = tracing::span!(Level::DEBUG, "packet", count = packet_count);
-                let _enter = span.enter();
-                match block {
-                    pcap_parser::PcapBlockOwned::NG(block) => {
-                        match block {
-                            pcap_parser::Block::InterfaceDescription(idb) => {
-                                tracing::debug!("IDB: {:?}", idb);
-                                iface_db.on_interface(idb);
-                            }
-                            pcap_parser::Block::DecryptionSecrets(dsb) => {
-                                tracing::debug!(
-                                    "DSB: {}",
-                                    misc::Show(&dsb.data[..dsb.secrets_len as usize])
-                                );
-                                chomper.on_keys(&dsb.data[..dsb.secrets_len as usize]);
-                            }
-                            pcap_parser::Block::EnhancedPacket(epb) => {
-                                let iface = match iface_db.get_interface(epb.if_id) {
-                                    Some(v) => v,
-                                    None => {
-                                        tracing::warn!(
-                                            "bad pcap file: interface {} is not defined",
-                                            epb.if_id
-                                        );
-                                        continue;
-                                    }
-                                };
-                                let ts = iface.resolve_timestamp(epb.ts_low, epb.ts_high);
-                                chomper
-                                    .chomp(
-                                        TimingInfo {
-                                            received_on_wire: ts,
-                                            other_times: Default::default(),
-                                        },
-                                        epb.packet_data(),
-                                    )
-                                    .unwrap();
-                                packet_count += 1;
-                            }
-                            _ => {}
-                        }
-                        // println!("{:?}", block);
-                    }
-                    _ => unimplemented!(),
-                }
-                pcap.consume(offset);
+    #[test]
+    fn test_overlays_kept_apart() {
+        let mut frames = KeyMessageReorderer::default();
+        dump_pcap(&mut Cursor::new(H1_UNENCRYPTED), &mut frames).unwrap();
+
+        // The same connection on two overlay networks at once.
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = http_chomper(Default::default(), received.clone());
+        for (timing, link_type, frame) in &frames.packets {
+            for vni in [1, 2] {
+                chomper
+                    .chomp(timing.clone(), *link_type, &in_vxlan(vni, frame))
+                    .unwrap();
             }
-            Err(PcapError::Eof) => break,
-            Err(PcapError::Incomplete) => {
-                pcap.refill().unwrap();
+        }
+
+        let received = received.read().unwrap();
+        let requests: Vec<_> = received
+            .iter()
+            .filter_map(|r| match r {
+                Received::Message(meta, HTTPStreamEvent::NewRequest(..)) => Some(meta.target),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].overlay(), Some(1));
+        assert_eq!(requests[1].overlay(), Some(2));
+        assert_eq!(requests[0], requests[1].on_overlay(Some(1)));
+    }
+
+    #[test]
+    fn test_http_over_ipv6_extension_headers() {
+        let mut frames = KeyMessageReorderer::default();
+        dump_pcap(&mut Cursor::new(H1_UNENCRYPTED), &mut frames).unwrap();
+
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = http_chomper(Default::default(), received.clone());
+        for (timing, link_type, frame) in &frames.packets {
+            assert_eq!(*link_type, Linktype::ETHERNET);
+            // There is some ARP too.
+            if frame[12..14] != ETHERTYPE_IPV4.to_be_bytes() {
+                continue;
             }
-            Err(e) => panic!("error while parsing pcap {e:?}"),
+            chomper
+                .chomp(
+                    timing.clone(),
+                    *link_type,
+                    &to_ipv6_with_extension_headers(frame),
+                )
+                .unwrap();
+        }
+
+        let received = received.read().unwrap();
+        let messages: Vec<_> = received
+            .iter()
+            .filter_map(|r| match r {
+                Received::Message(meta, ev) => Some((meta, ev)),
+                Received::SideData(_) => None,
+            })
+            .collect();
+        assert!(matches!(
+            messages.first(),
+            Some((_, HTTPStreamEvent::NewRequest(_, parts))) if parts.method == "GET"
+        ));
+        assert!(messages
+            .iter()
+            .any(|(_, ev)| matches!(ev, HTTPStreamEvent::NewResponse(..))));
+        for (meta, _) in &messages {
+            assert!(meta.target.is_ipv6());
+            // What devtools shows as the remote address.
+            assert_eq!(
+                meta.target.server_addr().ip(),
+                "2001:db8::3".parse::<IpAddr>().unwrap()
+            );
         }
     }
 
-    Ok(())
+    #[test]
+    fn test_ipv6_extension_headers() {
+        let tcp = [0xaa; 20];
+
+        assert_eq!(
+            ipv6_upper_layer(IPPROTO_TCP, &tcp),
+            Some(Ipv6Payload::Upper(IPPROTO_TCP, &tcp[..]))
+        );
+
+        // Hop-by-hop options (8 bytes), then destination options (16 bytes)
+        let mut packet = vec![IPV6_DEST_OPTS, 0, 1, 4, 0, 0, 0, 0];
+        packet.extend([IPPROTO_TCP, 1]);
+        packet.extend([0; 14]);
+        packet.extend(tcp);
+        assert_eq!(
+            ipv6_upper_layer(IPV6_HOP_BY_HOP, &packet),
+            Some(Ipv6Payload::Upper(IPPROTO_TCP, &tcp[..]))
+        );
+
+        // Atomic fragment
+        let mut packet = vec![IPPROTO_TCP, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend(tcp);
+        assert_eq!(
+            ipv6_upper_layer(IPV6_FRAGMENT, &packet),
+            Some(Ipv6Payload::Upper(IPPROTO_TCP, &tcp[..]))
+        );
+
+        // First fragment of several
+        let mut packet = vec![IPPROTO_TCP, 0, 0, 1, 0, 0, 0, 1];
+        packet.extend(tcp);
+        assert_eq!(
+            ipv6_upper_layer(IPV6_FRAGMENT, &packet),
+            Some(Ipv6Payload::Fragment {
+                next_header: IPPROTO_TCP,
+                id: 1,
+                offset: 0,
+                more: true,
+                data: &tcp[..],
+            })
+        );
+
+        // Truncated
+        assert_eq!(ipv6_upper_layer(IPV6_ROUTING, &[IPPROTO_TCP, 2, 0]), None);
+    }
 }
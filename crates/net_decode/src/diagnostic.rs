@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Recoverable problems with decoding, sent along the chain as side data so
+//! that malformed traffic can be shown to whoever is looking at the capture
+//! instead of only being logged.
+//!
+//! Problems that decoding further up the chain needs to react to have their
+//! own side data, such as
+//! [`TlsDecodeFailed`](crate::tls::side_data::TlsDecodeFailed).
+
+use std::fmt;
+
+use crate::{
+    chomp::IPTarget,
+    listener::{Listener, TimingInfo},
+};
+
+pub mod side_data {
+    use super::{Layer, Severity};
+    use crate::{chomp::IPTarget, listener::TimingInfo};
+
+    /// Fired by any part of `net_decode` that had to skip over something
+    /// it could not make sense of.
+    #[derive(Clone, Debug)]
+    pub struct Diagnostic {
+        pub timing: TimingInfo,
+        /// The flow it happened on, if it is about one.
+        pub target: Option<IPTarget>,
+        pub layer: Layer,
+        pub severity: Severity,
+        pub message: String,
+    }
+}
+
+use side_data::Diagnostic;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Some data was skipped, but the flow is still being decoded.
+    Warning,
+    /// The flow is no longer being decoded at this layer.
+    Error,
+}
+
+/// Where the problem was.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Layer {
+    Link,
+    Ip,
+    Tcp,
+    Udp,
+    Sctp,
+    Tls,
+    Http,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Layer::Link => "link",
+            Layer::Ip => "ip",
+            Layer::Tcp => "tcp",
+            Layer::Udp => "udp",
+            Layer::Sctp => "sctp",
+            Layer::Tls => "tls",
+            Layer::Http => "http",
+        })
+    }
+}
+
+/// Logs a problem on `target` and sends it on to `recv` as a
+/// [`Diagnostic`].
+pub(crate) fn report<T>(
+    recv: &mut (impl Listener<T> + ?Sized),
+    timing: &TimingInfo,
+    target: Option<IPTarget>,
+    layer: Layer,
+    severity: Severity,
+    message: String,
+) {
+    tracing::warn!(?target, "{layer}: {message}");
+    recv.on_side_data(Box::new(Diagnostic {
+        timing: timing.clone(),
+        target,
+        layer,
+        severity,
+        message,
+    }));
+}
@@ -9,6 +9,8 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use bytes::Bytes;
+
 use crate::{chomp::IPTarget, listener::Listener};
 
 trait ErasedMatcher: Matcher + fmt::Debug + Send + Sync + 'static {}
@@ -42,7 +44,7 @@ where
     }
 }
 
-type ErasedBytesListener = Box<dyn Listener<Vec<u8>> + Send + Sync + 'static>;
+type ErasedBytesListener = Box<dyn Listener<Bytes> + Send + Sync + 'static>;
 
 #[derive(Default)]
 pub struct ListenerDispatcher {
@@ -53,20 +55,20 @@ impl ListenerDispatcher {
     pub fn add(
         mut self,
         m: impl Matcher + fmt::Debug + Send + Sync + 'static,
-        listener: impl Listener<Vec<u8>> + Send + Sync + 'static,
+        listener: impl Listener<Bytes> + Send + Sync + 'static,
     ) -> Self {
         self.listeners.push((Box::new(m), Box::new(listener)));
         self
     }
 }
 
-impl Listener<Vec<u8>> for ListenerDispatcher {
+impl Listener<Bytes> for ListenerDispatcher {
     fn on_data(
         &mut self,
         timing: crate::listener::TimingInfo,
         target: IPTarget,
         to_client: bool,
-        data: Vec<u8>,
+        data: Bytes,
     ) {
         for (m, l) in &mut self.listeners {
             tracing::trace!(rule = ?m.as_debug(), "try rule");
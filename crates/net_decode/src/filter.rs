@@ -0,0 +1,186 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Passing on only some flows, so that expensive work further down such as
+//! storing bodies or exporting can be limited to the traffic of interest.
+//!
+//! Side data is always passed on, since keys and the like are needed
+//! whichever flows they end up being used for.
+
+use std::marker::PhantomData;
+
+use cidr::IpCidr;
+
+use crate::{
+    chomp::IPTarget,
+    listener::{Listener, SideData, TimingInfo},
+};
+
+/// Decides whether a message should be passed on.
+pub trait FlowPredicate<T>: Send + Sync {
+    fn matches(&self, target: &IPTarget, to_client: bool, data: &T) -> bool;
+}
+
+impl<T, F> FlowPredicate<T> for F
+where
+    F: Fn(&IPTarget, bool, &T) -> bool + Send + Sync,
+{
+    fn matches(&self, target: &IPTarget, to_client: bool, data: &T) -> bool {
+        self(target, to_client, data)
+    }
+}
+
+/// Which way messages are going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// Matches flows by address, port and direction, like a capture filter
+/// would. Every condition given has to hold; within one, any of the values
+/// will do. Addresses and ports may be on either end.
+#[derive(Clone, Debug, Default)]
+pub struct FlowMatch {
+    hosts: Vec<IpCidr>,
+    ports: Vec<u16>,
+    direction: Option<Direction>,
+}
+
+impl FlowMatch {
+    /// Takes a single host as well as a network, as in `10.0.0.1` or
+    /// `10.0.0.0/8`.
+    pub fn with_host(mut self, host: IpCidr) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.ports.push(port);
+        self
+    }
+
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    pub fn matches_flow(&self, target: &IPTarget, to_client: bool) -> bool {
+        let (client, server) = (target.client_addr(), target.server_addr());
+        let host_ok = self.hosts.is_empty()
+            || self
+                .hosts
+                .iter()
+                .any(|h| h.contains(&client.ip()) || h.contains(&server.ip()));
+        let port_ok = self.ports.is_empty()
+            || self
+                .ports
+                .iter()
+                .any(|&p| p == client.port() || p == server.port());
+        let direction_ok = match self.direction {
+            None => true,
+            Some(Direction::ToServer) => !to_client,
+            Some(Direction::ToClient) => to_client,
+        };
+        host_ok && port_ok && direction_ok
+    }
+}
+
+impl<T> FlowPredicate<T> for FlowMatch {
+    fn matches(&self, target: &IPTarget, to_client: bool, _data: &T) -> bool {
+        self.matches_flow(target, to_client)
+    }
+}
+
+/// Passes on the messages `P` matches to `L`.
+pub struct Filter<T, P, L> {
+    predicate: P,
+    next: L,
+    _phantom: PhantomData<fn(T)>,
+}
+
+impl<T, P: FlowPredicate<T>, L: Listener<T>> Filter<T, P, L> {
+    pub fn new(predicate: P, next: L) -> Self {
+        Filter {
+            predicate,
+            next,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, P: FlowPredicate<T>, L: Listener<T>> Listener<T> for Filter<T, P, L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        if self.predicate.matches(&target, to_client, &data) {
+            self.next.on_data(timing, target, to_client, data);
+        }
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.next.on_side_data(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::{
+        chomp::IPHeader,
+        test_support::{Received, TestListener},
+    };
+
+    fn target(client: u8, server_port: u16) -> IPTarget {
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, client, 192, 168, 0, 1,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+        IPTarget::from_ports(&IPHeader::V4(header), 40000, server_port)
+    }
+
+    #[test]
+    fn test_flow_match() {
+        let m = FlowMatch::default()
+            .with_host("10.0.0.0/30".parse().unwrap())
+            .with_port(443)
+            .with_port(8443);
+        assert!(m.matches_flow(&target(1, 443), false));
+        assert!(m.matches_flow(&target(3, 8443), true));
+        assert!(!m.matches_flow(&target(4, 443), false));
+        assert!(!m.matches_flow(&target(1, 80), false));
+        // Either end will do
+        assert!(FlowMatch::default()
+            .with_host("192.168.0.1".parse().unwrap())
+            .matches_flow(&target(9, 80), false));
+
+        let to_client = FlowMatch::default().with_direction(Direction::ToClient);
+        assert!(to_client.matches_flow(&target(1, 80), true));
+        assert!(!to_client.matches_flow(&target(1, 80), false));
+    }
+
+    #[test]
+    fn test_filter() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut filter = Filter::new(
+            |_: &IPTarget, _: bool, data: &Vec<u8>| data.starts_with(b"GET"),
+            TestListener {
+                received: received.clone(),
+            },
+        );
+        for data in [&b"GET /"[..], b"PUT /", b"GET /other"] {
+            filter.on_data(TimingInfo::default(), target(1, 80), false, data.to_vec());
+        }
+
+        let received = received.read().unwrap();
+        let passed: Vec<_> = received
+            .iter()
+            .map(|r| match r {
+                Received::Message(_, data) => data.clone(),
+                Received::SideData(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(passed, vec![b"GET /".to_vec(), b"GET /other".to_vec()]);
+    }
+}
@@ -0,0 +1,292 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! TLS client/server fingerprints (JA3, JA3S, JA4) computed from the
+//! cleartext hello messages.
+//!
+//! References:
+//! - JA3/JA3S: <https://github.com/salesforce/ja3>
+//! - JA4: <https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md>
+
+use md5::{Digest, Md5};
+use rustls_intercept::msgs::handshake::{ClientHelloPayload, ServerHelloPayload};
+use sha2::Sha256;
+
+/// ALPN extension type; excluded from the JA4 extension hash since it is
+/// already in part a.
+const EXT_ALPN: u16 = 0x0010;
+/// SNI extension type; excluded from the JA4 extension hash since it is
+/// already in part a.
+const EXT_SERVER_NAME: u16 = 0x0000;
+
+/// GREASE values (RFC 8701) are of the form 0x?a?a with both bytes equal.
+pub fn is_grease(v: u16) -> bool {
+    (v & 0x0f0f) == 0x0a0a && (v >> 8) == (v & 0xff)
+}
+
+fn join_decimal(vals: impl IntoIterator<Item = u16>) -> String {
+    vals.into_iter()
+        .filter(|&v| !is_grease(v))
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn join_hex(vals: impl IntoIterator<Item = u16>) -> String {
+    vals.into_iter()
+        .map(|v| format!("{v:04x}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// First 12 hex characters of the SHA-256 of `s`, or all zeroes if `s` is
+/// empty, per JA4.
+fn truncated_sha256(s: &str) -> String {
+    if s.is_empty() {
+        return "000000000000".to_owned();
+    }
+    let mut hex = hex::encode(Sha256::digest(s.as_bytes()));
+    hex.truncate(12);
+    hex
+}
+
+fn md5_hex(s: &str) -> String {
+    hex::encode(Md5::digest(s.as_bytes()))
+}
+
+/// JA3 string and its MD5 hash for a ClientHello.
+pub fn ja3(chp: &ClientHelloPayload) -> (String, String) {
+    let ciphers = join_decimal(chp.cipher_suites.iter().map(|c| c.get_u16()));
+    let extensions = join_decimal(chp.extensions.iter().map(|e| e.get_type().get_u16()));
+    let curves = join_decimal(
+        chp.get_namedgroups_extension()
+            .unwrap_or_default()
+            .iter()
+            .map(|g| g.get_u16()),
+    );
+    let point_formats = join_decimal(
+        chp.get_ecpoints_extension()
+            .unwrap_or_default()
+            .iter()
+            .map(|p| p.get_u8() as u16),
+    );
+
+    let s = format!(
+        "{},{ciphers},{extensions},{curves},{point_formats}",
+        chp.client_version.get_u16()
+    );
+    let hash = md5_hex(&s);
+    (s, hash)
+}
+
+/// JA3S string and its MD5 hash for a ServerHello.
+pub fn ja3s(shp: &ServerHelloPayload) -> (String, String) {
+    let extensions = join_decimal(shp.extensions.iter().map(|e| e.get_type().get_u16()));
+    let s = format!(
+        "{},{},{extensions}",
+        shp.legacy_version.get_u16(),
+        shp.cipher_suite.get_u16()
+    );
+    let hash = md5_hex(&s);
+    (s, hash)
+}
+
+fn ja4_version(v: u16) -> &'static str {
+    match v {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0200 => "s2",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00",
+    }
+}
+
+/// JA4 fingerprint for a ClientHello seen over TCP.
+pub fn ja4(chp: &ClientHelloPayload) -> String {
+    let version = chp
+        .get_versions_extension()
+        .and_then(|vs| {
+            vs.iter()
+                .map(|v| v.get_u16())
+                .filter(|&v| !is_grease(v))
+                .max()
+        })
+        .unwrap_or(chp.client_version.get_u16());
+
+    let mut ciphers: Vec<u16> = chp
+        .cipher_suites
+        .iter()
+        .map(|c| c.get_u16())
+        .filter(|&c| !is_grease(c))
+        .collect();
+    let mut extensions: Vec<u16> = chp
+        .extensions
+        .iter()
+        .map(|e| e.get_type().get_u16())
+        .filter(|&e| !is_grease(e))
+        .collect();
+
+    let sni = if extensions.contains(&EXT_SERVER_NAME) {
+        'd'
+    } else {
+        'i'
+    };
+
+    // first and last characters of the first ALPN value; non-alphanumeric
+    // values use the first/last hex digits instead
+    let alpn = match chp.get_alpn_extension().and_then(|ps| ps.first()) {
+        Some(p) if !p.as_ref().is_empty() => {
+            let p = p.as_ref();
+            let (first, last) = (p[0], p[p.len() - 1]);
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", first as char, last as char)
+            } else {
+                let hex = hex::encode(p);
+                format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+            }
+        }
+        _ => "00".to_owned(),
+    };
+
+    let part_a = format!(
+        "t{}{sni}{:02}{:02}{alpn}",
+        ja4_version(version),
+        ciphers.len().min(99),
+        extensions.len().min(99),
+    );
+
+    ciphers.sort_unstable();
+    let part_b = truncated_sha256(&join_hex(ciphers));
+
+    extensions.retain(|&e| e != EXT_SERVER_NAME && e != EXT_ALPN);
+    extensions.sort_unstable();
+    let sigalgs: Vec<u16> = chp
+        .get_sigalgs_extension()
+        .unwrap_or_default()
+        .iter()
+        .map(|s| s.get_u16())
+        .filter(|&s| !is_grease(s))
+        .collect();
+    let mut ext_str = join_hex(extensions);
+    if !sigalgs.is_empty() {
+        ext_str.push('_');
+        ext_str.push_str(&join_hex(sigalgs));
+    }
+    let part_c = truncated_sha256(&ext_str);
+
+    format!("{part_a}_{part_b}_{part_c}")
+}
+
+#[cfg(test)]
+mod test {
+    use rustls_intercept::{
+        internal::msgs::{
+            base::Payload,
+            enums::ExtensionType,
+            handshake::{ClientExtension, ProtocolName, Random, SessionId, UnknownExtension},
+        },
+        CipherSuite, ProtocolVersion,
+    };
+
+    use super::*;
+
+    fn hello(ciphers: usize, extensions: Vec<ClientExtension>) -> ClientHelloPayload {
+        ClientHelloPayload {
+            client_version: ProtocolVersion::TLSv1_2,
+            random: Random::from([0; 32]),
+            session_id: SessionId::empty(),
+            cipher_suites: (0..ciphers as u16)
+                .map(|c| CipherSuite::Unknown(0x1000 + c))
+                .collect(),
+            compression_methods: Vec::new(),
+            extensions,
+        }
+    }
+
+    fn alpn(protocols: &[&[u8]]) -> ClientExtension {
+        ClientExtension::Protocols(
+            protocols
+                .iter()
+                .map(|p| ProtocolName::from(p.to_vec()))
+                .collect(),
+        )
+    }
+
+    fn part_a(chp: &ClientHelloPayload) -> String {
+        ja4(chp).split('_').next().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_grease() {
+        for v in [0x0a0a, 0x1a1a, 0xfafa] {
+            assert!(is_grease(v));
+        }
+        for v in [0x0a1a, 0x1301, 0x0000, 0x0b0b] {
+            assert!(!is_grease(v));
+        }
+        assert_eq!(join_decimal([0x0a0a, 4865, 0xfafa, 4866]), "4865-4866");
+    }
+
+    #[test]
+    fn test_truncated_sha256() {
+        assert_eq!(truncated_sha256(""), "000000000000");
+        assert_eq!(
+            truncated_sha256("1301"),
+            hex::encode(Sha256::digest(b"1301"))[..12]
+        );
+    }
+
+    #[test]
+    fn test_ja4_alpn() {
+        assert_eq!(
+            part_a(&hello(1, vec![alpn(&[b"h2", b"http/1.1"])])),
+            "t12i0101h2"
+        );
+        // Non-alphanumeric at either end falls back to the first and last
+        // hex digits of the whole value.
+        assert_eq!(part_a(&hello(1, vec![alpn(&[b"\x01h2"])])), "t12i010102");
+        assert_eq!(part_a(&hello(1, vec![alpn(&[b"h2\xab"])])), "t12i01016b");
+        // An empty first value counts as no ALPN, even if others follow.
+        assert_eq!(part_a(&hello(1, vec![alpn(&[b"", b"h2"])])), "t12i010100");
+        assert_eq!(part_a(&hello(1, vec![])), "t12i010000");
+    }
+
+    #[test]
+    fn test_ja4_version() {
+        // Without supported_versions, the hello's own version is used.
+        assert_eq!(part_a(&hello(1, vec![])), "t12i010000");
+        let mut old = hello(1, vec![]);
+        old.client_version = ProtocolVersion::TLSv1_0;
+        assert_eq!(part_a(&old), "t10i010000");
+
+        let versions = ClientExtension::SupportedVersions(vec![
+            ProtocolVersion::Unknown(0x0a0a),
+            ProtocolVersion::TLSv1_2,
+            ProtocolVersion::TLSv1_3,
+        ]);
+        assert_eq!(part_a(&hello(1, vec![versions])), "t13i010100");
+    }
+
+    #[test]
+    fn test_ja4_counts_capped() {
+        let extensions = (0..120)
+            .map(|e| {
+                ClientExtension::Unknown(UnknownExtension {
+                    typ: ExtensionType::Unknown(0x2000 + e),
+                    payload: Payload::empty(),
+                })
+            })
+            .collect();
+        assert_eq!(part_a(&hello(150, extensions)), "t12i999900");
+        // GREASE is not counted.
+        let mut chp = hello(2, vec![]);
+        chp.cipher_suites.push(CipherSuite::Unknown(0x1a1a));
+        assert_eq!(part_a(&chp), "t12i020000");
+    }
+}
@@ -16,14 +16,16 @@ use std::{
     task::{Context, Poll},
 };
 
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::{task::noop_waker, FutureExt};
 use h2_intercept::frame::{Frame as HTTP2Frame, StreamId};
 use http::{header::CONTENT_LENGTH, HeaderMap, HeaderName, HeaderValue};
 
 use crate::{
     chomp::IPTarget,
-    listener::{Listener, SideData, TimingInfo},
+    diagnostic::{report, Layer, Severity},
+    listener::{Listener, SideData, SideDataHandler, TimingInfo},
+    tcp_reassemble::side_data::{ConnectionClosed, Truncated},
     tls,
 };
 
@@ -31,11 +33,16 @@ pub type RequestId = u64;
 
 pub enum HTTPStreamEvent {
     NewRequest(RequestId, http::request::Parts),
-    ReqBodyChunk(RequestId, Vec<u8>),
+    ReqBodyChunk(RequestId, Bytes),
     RequestFinished(RequestId, usize),
     NewResponse(RequestId, http::response::Parts),
-    RespBodyChunk(RequestId, Vec<u8>),
+    RespBodyChunk(RequestId, Bytes),
     ResponseFinished(RequestId, usize),
+    /// This many bytes of the request body are missing from the capture,
+    /// where the next chunk would have been.
+    ReqBodyTruncated(RequestId, usize),
+    /// The same for the response body.
+    RespBodyTruncated(RequestId, usize),
 }
 
 impl fmt::Debug for HTTPStreamEvent {
@@ -67,6 +74,16 @@ impl fmt::Debug for HTTPStreamEvent {
                 .field("id", id)
                 .field("len", len)
                 .finish(),
+            Self::ReqBodyTruncated(id, missing) => f
+                .debug_struct("ReqBodyTruncated")
+                .field("id", id)
+                .field("missing", missing)
+                .finish(),
+            Self::RespBodyTruncated(id, missing) => f
+                .debug_struct("RespBodyTruncated")
+                .field("id", id)
+                .field("missing", missing)
+                .finish(),
         }
     }
 }
@@ -370,9 +387,9 @@ impl HTTP2Flow {
                     // This feels *really* dirty and
                     // insufficiently abstracted.
                     let ev = if to_client {
-                        HTTPStreamEvent::RespBodyChunk(stream.request_id, data.payload().to_vec())
+                        HTTPStreamEvent::RespBodyChunk(stream.request_id, data.payload().clone())
                     } else {
-                        HTTPStreamEvent::ReqBodyChunk(stream.request_id, data.payload().to_vec())
+                        HTTPStreamEvent::ReqBodyChunk(stream.request_id, data.payload().clone())
                     };
 
                     let onward_data = &mut *onward_data.borrow_mut();
@@ -464,9 +481,14 @@ impl HTTP2Flow {
                         // propagated
                         Self::on_h2_frame(streams, f, to_client, &mut onward_data)?;
                     }
-                    Err(err) => {
-                        tracing::warn!(%err, "h2 decode error");
-                    }
+                    Err(err) => report(
+                        &mut *onward_data.next,
+                        &onward_data.timing,
+                        Some(onward_data.target),
+                        Layer::Http,
+                        Severity::Warning,
+                        format!("h2 decode error: {err}"),
+                    ),
                 },
                 Poll::Ready(None) => {
                     // Need to wait for more data
@@ -483,8 +505,8 @@ impl HTTP2Flow {
     fn handle_request(
         &mut self,
         to_client: bool,
-        data: &mut Vec<u8>,
-        onward_data: OnwardData<'_>,
+        data: &[u8],
+        mut onward_data: OnwardData<'_>,
     ) -> Result<(), HTTPParseError> {
         if to_client {
             Self::feed_codec(
@@ -507,7 +529,14 @@ impl HTTP2Flow {
                             self.server = HTTP2Server::Serve(v.into());
                         }
                         Poll::Ready(Err(e)) => {
-                            tracing::warn!(%e, "error in h2 handshake");
+                            report(
+                                &mut *onward_data.next,
+                                &onward_data.timing,
+                                Some(onward_data.target),
+                                Layer::Http,
+                                Severity::Error,
+                                format!("error in h2 handshake: {e}"),
+                            );
                             self.server = HTTP2Server::Error;
                         }
                         Poll::Pending => unreachable!("no async here"),
@@ -538,11 +567,11 @@ pub struct HTTP1Flow {
     // FIXME: technically with malicious input this could waste unbounded
     // memory. maybe we should give up after a while?
     // FIXME: streaming misery
-    req_buf: Vec<u8>,
+    req_buf: BytesMut,
     req_remain: usize,
     req_sent: usize,
 
-    resp_buf: Vec<u8>,
+    resp_buf: BytesMut,
     resp_remain: usize,
     resp_sent: usize,
 }
@@ -634,6 +663,9 @@ impl HTTP1Flow {
         let to_client = false;
         let encoded_length = &mut self.req_sent;
 
+        // Bytes from previous calls, which are not part of what we consume
+        // from `data`
+        let buffered = buf.len();
         buf.extend_from_slice(&data);
 
         let mut headers = Vec::new();
@@ -646,7 +678,7 @@ impl HTTP1Flow {
             Ok(httparse::Status::Partial) => {
                 // we just need to get more data. it has been buffered, try
                 // again next time
-                return Ok(0);
+                return Ok(data.len());
             }
             Ok(httparse::Status::Complete(body_start)) => {
                 *encoded_length += body_start;
@@ -673,9 +705,9 @@ impl HTTP1Flow {
                 );
 
                 *state = HTTP1ParserState::Body;
-                let data = buf[body_start..].to_vec();
+                let data = buf.split_off(body_start).freeze();
                 buf.clear();
-                Ok(body_start + self.stream_body(to_client, data, next))
+                Ok(body_start - buffered + self.stream_body(to_client, data, next))
             }
             Err(err) => {
                 tracing::debug!("bad http request: {err}");
@@ -695,6 +727,9 @@ impl HTTP1Flow {
         let to_client = true;
         let encoded_length = &mut self.resp_sent;
 
+        // Bytes from previous calls, which are not part of what we consume
+        // from `data`
+        let buffered = buf.len();
         buf.extend_from_slice(&data);
 
         let mut headers = Vec::new();
@@ -707,7 +742,7 @@ impl HTTP1Flow {
             Ok(httparse::Status::Partial) => {
                 // we just need to get more data. it has been buffered, try
                 // again next time
-                return Ok(0);
+                return Ok(data.len());
             }
             Ok(httparse::Status::Complete(body_start)) => {
                 *encoded_length += body_start;
@@ -729,34 +764,42 @@ impl HTTP1Flow {
                 );
 
                 *state = HTTP1ParserState::Body;
-                let data = buf[body_start..].to_vec();
+                let data = buf.split_off(body_start).freeze();
                 buf.clear();
-                Ok(body_start + self.stream_body(to_client, data, next))
+                Ok(body_start - buffered + self.stream_body(to_client, data, next))
             }
             Err(err) => Err(err.into()),
         }
     }
 
-    fn stream_body(&mut self, to_client: bool, chunk: Vec<u8>, next: OnwardData<'_>) -> usize {
+    fn stream_body(&mut self, to_client: bool, chunk: Bytes, next: OnwardData<'_>) -> usize {
         tracing::debug!(
             "body to_client={to_client}:\n{}",
             hexdump::HexDumper::new(&chunk)
         );
 
         let len = chunk.len();
+        let msg = if to_client {
+            HTTPStreamEvent::RespBodyChunk(self.request_id, chunk)
+        } else {
+            HTTPStreamEvent::ReqBodyChunk(self.request_id, chunk)
+        };
+        self.on_body(to_client, len, msg, next)
+    }
 
-        let (remain, encoded_length, msg) = if to_client {
-            (
-                &mut self.resp_remain,
-                &mut self.req_sent,
-                HTTPStreamEvent::RespBodyChunk(self.request_id, chunk),
-            )
+    /// Counts `len` bytes of body, sent on as `msg`, returning how many of
+    /// them were part of it.
+    fn on_body(
+        &mut self,
+        to_client: bool,
+        len: usize,
+        msg: HTTPStreamEvent,
+        next: OnwardData<'_>,
+    ) -> usize {
+        let (remain, encoded_length) = if to_client {
+            (&mut self.resp_remain, &mut self.req_sent)
         } else {
-            (
-                &mut self.req_remain,
-                &mut self.req_sent,
-                HTTPStreamEvent::ReqBodyChunk(self.request_id, chunk),
-            )
+            (&mut self.req_remain, &mut self.req_sent)
         };
         let to_consume = len.min(*remain);
         *remain -= to_consume;
@@ -790,6 +833,65 @@ impl HTTP1Flow {
         to_consume
     }
 
+    /// Skips over `missing` bytes that the capture cut off. Only the body
+    /// can be skipped: if the gap runs into the headers of the next
+    /// message, this side of the flow is given up on.
+    fn on_truncated(
+        &mut self,
+        timing: &TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        missing: usize,
+        next: &mut dyn Listener<HTTPStreamEvent>,
+        new_request_id: &mut impl FnMut() -> RequestId,
+    ) {
+        let (state, remain) = if to_client {
+            (self.client_state, self.resp_remain)
+        } else {
+            (self.server_state, self.req_remain)
+        };
+        let skipped = match state {
+            HTTP1ParserState::Error => return,
+            HTTP1ParserState::Body if remain > 0 => {
+                let skipped = missing.min(remain);
+                let msg = if to_client {
+                    HTTPStreamEvent::RespBodyTruncated(self.request_id, skipped)
+                } else {
+                    HTTPStreamEvent::ReqBodyTruncated(self.request_id, skipped)
+                };
+                let onward = OnwardData {
+                    timing: timing.clone(),
+                    target,
+                    new_request_id,
+                    next: &mut *next,
+                };
+                self.on_body(to_client, skipped, msg, onward)
+            }
+            _ => 0,
+        };
+        if skipped == missing {
+            return;
+        }
+
+        report(
+            &mut *next,
+            timing,
+            Some(target),
+            Layer::Http,
+            Severity::Warning,
+            format!(
+                "request_id={} {} bytes of headers missing from the capture",
+                self.request_id,
+                missing - skipped
+            ),
+        );
+        if to_client {
+            self.client_state = HTTP1ParserState::Error;
+        } else {
+            self.server_state = HTTP1ParserState::Error;
+        }
+    }
+
     fn handle_request(
         &mut self,
         timing: &TimingInfo,
@@ -797,7 +899,7 @@ impl HTTP1Flow {
         to_client: bool,
         next: &mut dyn Listener<HTTPStreamEvent>,
         new_request_id: &mut impl FnMut() -> RequestId,
-        data: &mut Vec<u8>,
+        data: &mut Bytes,
     ) {
         while data.len() > 0 {
             let onward = OnwardData {
@@ -818,9 +920,16 @@ impl HTTP1Flow {
                     match self.do_server_recv_headers(&data, onward) {
                         Ok(s) => s,
                         Err(e) => {
-                            tracing::warn!(
-                                "request_id={} error parsing http request: {e}",
-                                self.request_id
+                            report(
+                                &mut *next,
+                                timing,
+                                Some(target),
+                                Layer::Http,
+                                Severity::Error,
+                                format!(
+                                    "request_id={} error parsing http request: {e}",
+                                    self.request_id
+                                ),
                             );
                             self.server_state = HTTP1ParserState::Error;
                             0
@@ -834,9 +943,16 @@ impl HTTP1Flow {
                     match self.do_client_recv_headers(&data, onward) {
                         Ok(s) => s,
                         Err(e) => {
-                            tracing::warn!(
-                                "request_id={} error parsing http response: {e}",
-                                self.request_id
+                            report(
+                                &mut *next,
+                                timing,
+                                Some(target),
+                                Layer::Http,
+                                Severity::Error,
+                                format!(
+                                    "request_id={} error parsing http response: {e}",
+                                    self.request_id
+                                ),
                             );
                             self.client_state = HTTP1ParserState::Error;
                             0
@@ -847,7 +963,7 @@ impl HTTP1Flow {
                 (_, HTTP1ParserState::Error) => return,
             };
 
-            *data = data[eaten..].to_vec();
+            data.advance(eaten);
         }
     }
 }
@@ -882,13 +998,13 @@ impl HTTPRequestTracker {
     }
 }
 
-impl Listener<Vec<u8>> for HTTPRequestTracker {
+impl Listener<Bytes> for HTTPRequestTracker {
     fn on_data(
         &mut self,
         timing: TimingInfo,
         target: crate::chomp::IPTarget,
         to_client: bool,
-        mut data: Vec<u8>,
+        mut data: Bytes,
     ) {
         let mut new_request_id = || {
             let i = self.request_id;
@@ -938,39 +1054,75 @@ impl Listener<Vec<u8>> for HTTPRequestTracker {
                 };
 
                 s.record("version", "h2");
-                match entry.handle_request(to_client, &mut data, onward) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        tracing::warn!("error in h2 handling: {e}");
-                    }
+                if let Err(e) = entry.handle_request(to_client, &data, onward) {
+                    report(
+                        &mut *self.next,
+                        &timing,
+                        Some(target),
+                        Layer::Http,
+                        Severity::Warning,
+                        format!("error in h2 handling: {e}"),
+                    );
                 }
             }
         }
     }
 
     fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        crate::dispatch_side_data!(
+            self,
+            &data,
+            [tls::side_data::ALPNCompleted, ConnectionClosed, Truncated]
+        );
+        self.next.on_side_data(data);
+    }
+}
+
+impl SideDataHandler<tls::side_data::ALPNCompleted> for HTTPRequestTracker {
+    fn handle_side_data(&mut self, alpn: &tls::side_data::ALPNCompleted) {
+        tracing::debug!(?alpn, "ALPN");
+
+        if alpn.protocols.iter().any(|e| e.0 == b"h2") {
+            let request_id = &mut self.request_id;
+            let _ = self.flows.entry(alpn.target).or_insert_with(|| {
+                let i = *request_id;
+                *request_id += 1;
+                HTTPFlow::HTTP2Flow(HTTP2Flow {
+                    request_id: i,
+                    ..Default::default()
+                })
+            });
+        }
+    }
+}
+
+impl SideDataHandler<Truncated> for HTTPRequestTracker {
+    fn handle_side_data(&mut self, truncated: &Truncated) {
         let mut new_request_id = || {
             let i = self.request_id;
             self.request_id += 1;
             i
         };
-
-        if let Some(alpn) = (&*data)
-            .as_any()
-            .downcast_ref::<tls::side_data::ALPNCompleted>()
-        {
-            tracing::debug!(?alpn, "ALPN");
-
-            if alpn.protocols.iter().any(|e| e.0 == b"h2") {
-                let _ = self.flows.entry(alpn.target).or_insert_with(|| {
-                    HTTPFlow::HTTP2Flow(HTTP2Flow {
-                        request_id: new_request_id(),
-                        ..Default::default()
-                    })
-                });
-            }
+        match self.flows.get_mut(&truncated.target) {
+            Some(HTTPFlow::HTTP1Flow(flow)) => flow.on_truncated(
+                &truncated.timing,
+                truncated.target,
+                truncated.to_client,
+                truncated.missing,
+                &mut *self.next,
+                &mut new_request_id,
+            ),
+            // We only know a flow is h2 from TLS, which does not pass gaps
+            // on.
+            Some(HTTPFlow::HTTP2Flow(_)) | None => {}
         }
-        self.next.on_side_data(data);
+    }
+}
+
+impl SideDataHandler<ConnectionClosed> for HTTPRequestTracker {
+    fn handle_side_data(&mut self, closed: &ConnectionClosed) {
+        // Whatever comes next on these ports is a new connection
+        self.flows.remove(&closed.target);
     }
 }
 
@@ -1037,4 +1189,113 @@ mod test {
             &http_test(H1_UNENCRYPTED),
         )
     }
+
+    #[test]
+    fn test_h1_parse_error_diagnostic() {
+        use bytes::Bytes;
+
+        use super::HTTPRequestTracker;
+        use crate::{
+            chomp::{IPHeader, IPTarget},
+            diagnostic::{side_data::Diagnostic, Layer, Severity},
+            listener::{Listener, TimingInfo},
+        };
+
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut tracker = HTTPRequestTracker::new(Box::new(SideDataListener {
+            received: received.clone(),
+        }));
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+        let target = IPTarget::from_ports(&IPHeader::V4(header), 40000, 80);
+        tracker.on_data(
+            TimingInfo::default(),
+            target,
+            false,
+            Bytes::from_static(b"\x00\x01 / HTTP/1.1\r\n\r\n"),
+        );
+        // Ignored, since the flow is no longer being decoded
+        tracker.on_data(
+            TimingInfo::default(),
+            target,
+            false,
+            Bytes::from_static(b"GET / HTTP/1.1\r\n\r\n"),
+        );
+
+        let diagnostics = SideDataListener::find::<Diagnostic>(&received);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].target, Some(target));
+        assert_eq!(diagnostics[0].layer, Layer::Http);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+    }
+
+    /// A response whose body the snap length cut short, then the next
+    /// request, whose headers it also cut short.
+    #[test]
+    fn test_h1_truncated() {
+        use bytes::Bytes;
+
+        use super::HTTPRequestTracker;
+        use crate::{
+            chomp::{IPHeader, IPTarget},
+            listener::{Listener, TimingInfo},
+            tcp_reassemble::side_data::Truncated,
+        };
+
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut tracker = HTTPRequestTracker::new(Box::new(TestListener {
+            received: received.clone(),
+        }));
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+        let target = IPTarget::from_ports(&IPHeader::V4(header), 40000, 80);
+        let mut send = |to_client: bool, data: &'static [u8], missing: usize| {
+            tracker.on_data(
+                TimingInfo::default(),
+                target,
+                to_client,
+                Bytes::from_static(data),
+            );
+            if missing > 0 {
+                tracker.on_side_data(Box::new(Truncated {
+                    timing: TimingInfo::default(),
+                    target,
+                    to_client,
+                    missing,
+                }));
+            }
+        };
+        send(false, b"GET / HTTP/1.1\r\n\r\n", 0);
+        send(true, b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc", 4);
+        send(true, b"", 3);
+        send(false, b"GET /next HT", 10);
+        // Ignored, since the request could not be decoded
+        send(false, b"GET / HTTP/1.1\r\n\r\n", 0);
+
+        let events: Vec<_> = received
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|r| match r {
+                Received::Message(_, ev) => Some(format!("{ev:?}")),
+                Received::SideData(_) => None,
+            })
+            .collect();
+        assert_eq!(events.len(), 8, "{events:?}");
+        assert!(events[0].starts_with("NewRequest(0,"), "{events:?}");
+        assert!(events[3].starts_with("NewResponse(0,"), "{events:?}");
+        assert_eq!(
+            events[4..],
+            [
+                "RespBodyChunk { id: 0, len: 3 }",
+                "RespBodyTruncated { id: 0, missing: 4 }",
+                "RespBodyTruncated { id: 0, missing: 3 }",
+                "ResponseFinished { id: 0, len: 28 }",
+            ]
+        );
+    }
 }
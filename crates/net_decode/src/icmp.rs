@@ -0,0 +1,269 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! ICMP and ICMPv6 errors, which quote the start of the packet that could
+//! not be delivered. That is enough to find the TCP or UDP flow it was part
+//! of, so that a connection that never got anywhere can say why.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::chomp::{ipv6_upper_layer, IPTarget, Ipv6Payload, IPPROTO_TCP, IPPROTO_UDP};
+
+pub mod side_data {
+    use std::net::IpAddr;
+
+    use super::IcmpErrorKind;
+    use crate::{chomp::IPTarget, listener::TimingInfo};
+
+    /// Fired by `net_decode::tcp_reassemble` and `net_decode::udp_flow` when
+    /// an ICMP error comes back about a packet of one of their flows.
+    #[derive(Clone, Debug)]
+    pub struct IcmpError {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        /// Whether the packet that could not be delivered was going to the
+        /// client.
+        pub to_client: bool,
+        pub kind: IcmpErrorKind,
+        /// Who sent the error: the destination itself, or some router on
+        /// the way.
+        pub reporter: IpAddr,
+    }
+}
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+const ICMP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// What went wrong, merging the ICMP and ICMPv6 types that mean the same
+/// thing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpErrorKind {
+    NetworkUnreachable,
+    HostUnreachable,
+    PortUnreachable,
+    /// A firewall or routing policy rejected the packet.
+    AdministrativelyProhibited,
+    /// Some other destination unreachable code.
+    Unreachable(u8),
+    /// The hop limit ran out on the way, or reassembly timed out.
+    TimeExceeded,
+    /// The packet needed fragmenting to fit the next hop, and could not be.
+    PacketTooBig {
+        mtu: u32,
+    },
+}
+
+impl IcmpErrorKind {
+    /// Whether the destination cannot be reached at all, rather than just
+    /// with packets that big.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, IcmpErrorKind::PacketTooBig { .. })
+    }
+
+    /// RFC 792 and RFC 1812 section 5.2.7.1.
+    fn from_icmp(typ: u8, code: u8, rest: [u8; 4]) -> Option<Self> {
+        Some(match (typ, code) {
+            (3, 0) => IcmpErrorKind::NetworkUnreachable,
+            (3, 1) => IcmpErrorKind::HostUnreachable,
+            (3, 3) => IcmpErrorKind::PortUnreachable,
+            (3, 4) => IcmpErrorKind::PacketTooBig {
+                mtu: u16::from_be_bytes([rest[2], rest[3]]) as u32,
+            },
+            (3, 9 | 10 | 13) => IcmpErrorKind::AdministrativelyProhibited,
+            (3, code) => IcmpErrorKind::Unreachable(code),
+            (11, _) => IcmpErrorKind::TimeExceeded,
+            _ => return None,
+        })
+    }
+
+    /// RFC 4443 section 3.
+    fn from_icmpv6(typ: u8, code: u8, rest: [u8; 4]) -> Option<Self> {
+        Some(match (typ, code) {
+            (1, 0) => IcmpErrorKind::NetworkUnreachable,
+            (1, 3) => IcmpErrorKind::HostUnreachable,
+            (1, 4) => IcmpErrorKind::PortUnreachable,
+            (1, 1 | 5 | 6) => IcmpErrorKind::AdministrativelyProhibited,
+            (1, code) => IcmpErrorKind::Unreachable(code),
+            (2, _) => IcmpErrorKind::PacketTooBig {
+                mtu: u32::from_be_bytes(rest),
+            },
+            (3, _) => IcmpErrorKind::TimeExceeded,
+            _ => return None,
+        })
+    }
+}
+
+/// An ICMP error, and the packet it is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct QuotedError {
+    pub kind: IcmpErrorKind,
+    /// Protocol of the quoted packet.
+    pub proto: u8,
+    /// The quoted packet's addresses and ports, with its sender as the
+    /// client.
+    pub target: IPTarget,
+}
+
+/// If the IP payload `data`, of protocol `proto`, is an ICMP error quoting
+/// a TCP or UDP packet, parses it. Quotes are often cut off after 8 bytes
+/// of the transport header, which is all we need.
+pub(crate) fn parse_error(proto: u8, data: &[u8]) -> Option<QuotedError> {
+    let (typ, code) = (*data.first()?, *data.get(1)?);
+    let rest: [u8; 4] = data.get(4..8)?.try_into().unwrap();
+    let quoted = &data[ICMP_HEADER_LEN..];
+    let (kind, (proto, ports, (src, dst))) = match proto {
+        IPPROTO_ICMP => (
+            IcmpErrorKind::from_icmp(typ, code, rest)?,
+            quoted_ipv4(quoted)?,
+        ),
+        IPPROTO_ICMPV6 => (
+            IcmpErrorKind::from_icmpv6(typ, code, rest)?,
+            quoted_ipv6(quoted)?,
+        ),
+        _ => return None,
+    };
+    if proto != IPPROTO_TCP && proto != IPPROTO_UDP {
+        return None;
+    }
+    let source_port = u16::from_be_bytes(ports.get(0..2)?.try_into().unwrap());
+    let dest_port = u16::from_be_bytes(ports.get(2..4)?.try_into().unwrap());
+    let target = match (src, dst) {
+        (IpAddr::V4(client_ip), IpAddr::V4(server_ip)) => IPTarget::V4 {
+            client_port: source_port,
+            server_port: dest_port,
+            client_ip,
+            server_ip,
+            overlay: None,
+        },
+        (IpAddr::V6(client_ip), IpAddr::V6(server_ip)) => IPTarget::V6 {
+            client_port: source_port,
+            server_port: dest_port,
+            client_ip,
+            server_ip,
+            overlay: None,
+        },
+        _ => unreachable!(),
+    };
+    Some(QuotedError {
+        kind,
+        proto,
+        target,
+    })
+}
+
+type Quoted<'a> = (u8, &'a [u8], (IpAddr, IpAddr));
+
+/// The protocol, payload and addresses of a quoted IPv4 packet. Anything
+/// but the first fragment has no ports to find.
+fn quoted_ipv4(packet: &[u8]) -> Option<Quoted<'_>> {
+    if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    if offset != 0 {
+        return None;
+    }
+    let src: [u8; 4] = packet[12..16].try_into().unwrap();
+    let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+    Some((
+        packet[9],
+        packet.get(header_len..)?,
+        (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into()),
+    ))
+}
+
+/// The same for a quoted IPv6 packet, past its extension headers.
+fn quoted_ipv6(packet: &[u8]) -> Option<Quoted<'_>> {
+    if packet.len() < IPV6_HEADER_LEN || packet[0] >> 4 != 6 {
+        return None;
+    }
+    let src: [u8; 16] = packet[8..24].try_into().unwrap();
+    let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+    match ipv6_upper_layer(packet[6], &packet[IPV6_HEADER_LEN..])? {
+        Ipv6Payload::Upper(proto, payload) => Some((
+            proto,
+            payload,
+            (Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into()),
+        )),
+        // FIXME: the first fragment does have the ports in it
+        Ipv6Payload::Fragment { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::sample_target;
+
+    #[test]
+    fn test_quoted_ipv4() {
+        // Host unreachable, from a router
+        let mut icmp = vec![3, 1, 0, 0, 0, 0, 0, 0];
+        let ip = [
+            0x45, 0, 0, 60, 0, 0, 0x40, 0, 1, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        icmp.extend(ip);
+        // The first 8 bytes of the SYN
+        icmp.extend([0x9c, 0x40, 0x01, 0xbb, 0, 0, 0, 100]);
+        assert_eq!(
+            parse_error(IPPROTO_ICMP, &icmp),
+            Some(QuotedError {
+                kind: IcmpErrorKind::HostUnreachable,
+                proto: IPPROTO_TCP,
+                target: sample_target(),
+            })
+        );
+
+        // Fragmentation needed, MTU 1400
+        icmp[1] = 4;
+        icmp[6..8].copy_from_slice(&1400u16.to_be_bytes());
+        assert_eq!(
+            parse_error(IPPROTO_ICMP, &icmp).map(|e| e.kind),
+            Some(IcmpErrorKind::PacketTooBig { mtu: 1400 })
+        );
+
+        // Echo reply
+        icmp[0] = 0;
+        assert_eq!(parse_error(IPPROTO_ICMP, &icmp), None);
+        // Quote cut off before the ports
+        icmp[0] = 11;
+        assert_eq!(parse_error(IPPROTO_ICMP, &icmp[..30]), None);
+    }
+
+    #[test]
+    fn test_quoted_ipv6() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        // Port unreachable
+        let mut icmp = vec![1, 4, 0, 0, 0, 0, 0, 0];
+        // Through a destination options header to UDP
+        icmp.extend([0x60, 0, 0, 0, 0, 24, 60, 64]);
+        icmp.extend(src.octets());
+        icmp.extend(dst.octets());
+        icmp.extend([IPPROTO_UDP, 0, 1, 4, 0, 0, 0, 0]);
+        icmp.extend([0x14, 0xe9, 0x00, 0x35, 0, 16, 0, 0]);
+        assert_eq!(
+            parse_error(IPPROTO_ICMPV6, &icmp),
+            Some(QuotedError {
+                kind: IcmpErrorKind::PortUnreachable,
+                proto: IPPROTO_UDP,
+                target: IPTarget::V6 {
+                    client_port: 5353,
+                    server_port: 53,
+                    client_ip: src,
+                    server_ip: dst,
+                    overlay: None,
+                },
+            })
+        );
+
+        // An ICMPv4 type means something else in ICMPv6
+        assert_eq!(parse_error(IPPROTO_ICMP, &icmp), None);
+    }
+}
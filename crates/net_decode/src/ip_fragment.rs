@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Putting fragmented IPv4 and IPv6 datagrams back together before TCP and
+//! UDP see them, so that paths with small MTUs do not lose parts of
+//! requests.
+//!
+//! Everything a fragment needs is bounded: datagrams are given up on after
+//! [`FragmentLimits::timeout`], there are at most
+//! [`FragmentLimits::max_datagrams`] in progress at once, and none may grow
+//! past [`FragmentLimits::max_datagram_len`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+use crate::listener::Nanos;
+
+/// Which datagram a fragment belongs to (RFC 791 section 3.2, RFC 8200
+/// section 4.5).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// 16 bits in IPv4, 32 in IPv6.
+    pub id: u32,
+    /// The protocol of the reassembled payload.
+    pub proto: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FragmentLimits {
+    /// How long after its first fragment a datagram is given up on. RFC 8200
+    /// says 60 seconds; Linux uses 30.
+    pub timeout: Nanos,
+    /// How many datagrams may be in progress at once. The oldest is dropped
+    /// to make room.
+    pub max_datagrams: usize,
+    /// Largest reassembled payload.
+    pub max_datagram_len: usize,
+}
+
+impl Default for FragmentLimits {
+    fn default() -> Self {
+        FragmentLimits {
+            timeout: 30_000_000_000,
+            max_datagrams: 1024,
+            max_datagram_len: u16::MAX as usize,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PartialDatagram {
+    first_seen: Nanos,
+    /// By offset. Where fragments overlap, the earlier one wins.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Known once the last fragment arrives.
+    total_len: Option<usize>,
+}
+
+impl PartialDatagram {
+    /// The whole payload, if there are no holes left.
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        let mut out = Vec::with_capacity(total_len);
+        for (&offset, data) in &self.fragments {
+            if offset > out.len() {
+                return None;
+            }
+            let end = (offset + data.len()).min(total_len);
+            if end > out.len() {
+                out.extend_from_slice(&data[out.len() - offset..end - offset]);
+            }
+        }
+        (out.len() == total_len).then_some(out)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FragmentReassembler {
+    limits: FragmentLimits,
+    partial: HashMap<FragmentKey, PartialDatagram>,
+}
+
+impl FragmentReassembler {
+    pub fn new(limits: FragmentLimits) -> Self {
+        FragmentReassembler {
+            limits,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Takes one fragment, `offset` bytes into the datagram's payload, with
+    /// `more` set if it is not the last one. Returns the whole payload once
+    /// every fragment has arrived.
+    pub fn on_fragment(
+        &mut self,
+        now: Nanos,
+        key: FragmentKey,
+        offset: usize,
+        more: bool,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        self.expire(now);
+
+        let end = offset + data.len();
+        if end > self.limits.max_datagram_len {
+            tracing::warn!("dropped oversized fragmented datagram {key:?}");
+            self.partial.remove(&key);
+            return None;
+        }
+
+        if !self.partial.contains_key(&key) && self.partial.len() >= self.limits.max_datagrams {
+            let oldest = self
+                .partial
+                .iter()
+                .min_by_key(|(_, d)| d.first_seen)
+                .map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                tracing::debug!("too many fragmented datagrams, dropped {oldest:?}");
+                self.partial.remove(&oldest);
+            }
+        }
+
+        let datagram = self.partial.entry(key).or_insert_with(|| PartialDatagram {
+            first_seen: now,
+            fragments: BTreeMap::new(),
+            total_len: None,
+        });
+        if !more {
+            match datagram.total_len {
+                Some(len) if len != end => {
+                    tracing::warn!("fragments of {key:?} disagree on its length, dropped");
+                    self.partial.remove(&key);
+                    return None;
+                }
+                _ => datagram.total_len = Some(end),
+            }
+        }
+        datagram
+            .fragments
+            .entry(offset)
+            .or_insert_with(|| data.to_vec());
+
+        let whole = datagram.assemble()?;
+        self.partial.remove(&key);
+        Some(whole)
+    }
+
+    fn expire(&mut self, now: Nanos) {
+        let timeout = self.limits.timeout;
+        self.partial.retain(|key, d| {
+            let keep = now.saturating_sub(d.first_seen) <= timeout;
+            if !keep {
+                tracing::debug!("fragmented datagram {key:?} timed out");
+            }
+            keep
+        });
+    }
+
+    /// Datagrams still waiting on fragments.
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn key(id: u32) -> FragmentKey {
+        FragmentKey {
+            src: Ipv4Addr::new(10, 0, 0, 1).into(),
+            dst: Ipv4Addr::new(10, 0, 0, 2).into(),
+            id,
+            proto: 6,
+        }
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let mut r = FragmentReassembler::default();
+        assert_eq!(r.on_fragment(0, key(1), 7, false, b"world"), None);
+        assert_eq!(r.on_fragment(1, key(1), 0, true, b"hello"), None);
+        assert_eq!(
+            r.on_fragment(2, key(2), 0, false, b"other"),
+            Some(b"other".to_vec())
+        );
+        // Overlaps what we already have, and fills the hole.
+        assert_eq!(
+            r.on_fragment(3, key(1), 3, true, b"LO, "),
+            Some(b"hello, world".to_vec())
+        );
+        assert_eq!(r.pending(), 0);
+    }
+
+    #[test]
+    fn test_fragment_limits() {
+        let mut r = FragmentReassembler::new(FragmentLimits {
+            timeout: 10,
+            max_datagrams: 2,
+            max_datagram_len: 100,
+        });
+        assert_eq!(r.on_fragment(0, key(1), 0, true, b"a"), None);
+        assert_eq!(r.on_fragment(5, key(2), 0, true, b"b"), None);
+        // Pushes out the oldest.
+        assert_eq!(r.on_fragment(6, key(3), 0, true, b"c"), None);
+        assert_eq!(r.on_fragment(7, key(1), 1, false, b"a"), None);
+        assert_eq!(r.pending(), 2);
+
+        assert_eq!(r.on_fragment(8, key(4), 96, true, b"too long"), None);
+        assert_eq!(r.pending(), 2);
+
+        // Everything but key(1), which started again at 7, has timed out.
+        assert_eq!(
+            r.on_fragment(17, key(1), 0, true, b"a"),
+            Some(b"aa".to_vec())
+        );
+        assert_eq!(r.pending(), 0);
+    }
+}
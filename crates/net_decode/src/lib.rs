@@ -2,46 +2,139 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::sync::{Arc, RwLock};
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
 
+use checksum::ChecksumMode;
 use chomp::EthernetChomper;
 use dispatch::ListenerDispatcher;
-use http::{HTTPRequestTracker, HTTPStreamEvent};
+use http::HTTPStreamEvent;
 use key_db::KeyDB;
-use listener::Listener;
-use tcp_reassemble::TcpFollower;
-use tls::TLSFlowTracker;
+use listener::{Listener, Nanos};
+use pipeline::Pipeline;
 
+pub mod async_listener;
+pub mod body_policy;
+pub mod capture_file;
+pub mod capture_filter;
+pub mod capture_index;
+pub mod capture_merge;
+pub mod capture_slice;
+pub mod certificate;
+pub mod checkpoint;
+pub mod checksum;
 pub mod chomp;
+pub mod diagnostic;
 pub mod dispatch;
+pub mod filter;
+pub mod fingerprint;
 pub mod http;
+pub mod icmp;
+pub mod ip_fragment;
 pub mod key_db;
+pub mod link;
 pub mod listener;
+pub mod live;
+pub mod mapped_file;
+pub mod metrics;
+pub mod mptcp;
+pub mod packet_comment;
+pub mod parallel;
+pub mod pipeline;
+pub mod plugin;
+mod psk;
+mod quic;
+pub mod replay;
+pub mod sampling;
+pub mod sctp;
+#[cfg(feature = "stage-timing")]
+pub mod stage_timing;
 pub mod tcp_reassemble;
+pub mod tcp_timing;
+pub mod tcp_window;
 #[cfg(test)]
 mod test_support;
 pub mod tls;
+pub mod tunnel;
+pub mod udp_flow;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm_plugin;
+pub mod wireguard;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// See [`DecodeOptions::spill_bodies_over`].
+pub const DEFAULT_SPILL_BODIES_OVER: usize = 4 * 1024 * 1024;
+
+/// Knobs for the decoding stack built by [`chomper_with_options`] or a
+/// [`Pipeline`].
+#[derive(Clone, Debug)]
+pub struct DecodeOptions {
+    /// How much TLS data to hold per flow while waiting for its keys to
+    /// arrive. See [`tls::DEFAULT_MAX_QUEUED_BYTES`].
+    pub max_queued_tls_bytes: usize,
+    /// How many closed connections to keep the TLS keys of. `None` keeps
+    /// them all. See [`KeyDB::set_closed_retention`].
+    pub closed_key_retention: Option<usize>,
+    /// How long a TCP connection may go quiet before we stop following it.
+    /// See [`tcp_reassemble::DEFAULT_IDLE_TIMEOUT`].
+    pub tcp_idle_timeout: Nanos,
+    /// How many TCP connections to follow at once. See
+    /// [`tcp_reassemble::DEFAULT_MAX_FLOWS`].
+    pub max_tcp_flows: usize,
+    /// See [`udp_flow::DEFAULT_IDLE_TIMEOUT`].
+    pub udp_idle_timeout: Nanos,
+    /// Whether to check IP, TCP and UDP checksums. See [`checksum`].
+    pub checksums: ChecksumMode,
+    /// Decode only one in this many flows, chosen by their addresses. 1
+    /// decodes everything. See [`sampling::FlowSampler`].
+    pub sample_flows: u32,
+    /// Pass on only this much of each HTTP body. See
+    /// [`sampling::BodySampler`].
+    pub max_body_bytes: Option<usize>,
+    /// Bodies bigger than this are kept on disk rather than in memory.
+    /// Not used by the decoding stack itself, but by frontends that keep
+    /// bodies around, such as devtools.
+    pub spill_bodies_over: usize,
+    /// Where frontends that keep bodies put them once they finish, for
+    /// good. `None` uses a temporary directory that goes away on exit.
+    pub body_store: Option<PathBuf>,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            max_queued_tls_bytes: tls::DEFAULT_MAX_QUEUED_BYTES,
+            closed_key_retention: None,
+            tcp_idle_timeout: tcp_reassemble::DEFAULT_IDLE_TIMEOUT,
+            max_tcp_flows: tcp_reassemble::DEFAULT_MAX_FLOWS,
+            udp_idle_timeout: udp_flow::DEFAULT_IDLE_TIMEOUT,
+            checksums: ChecksumMode::Off,
+            sample_flows: 1,
+            max_body_bytes: None,
+            spill_bodies_over: DEFAULT_SPILL_BODIES_OVER,
+            body_store: None,
+        }
+    }
+}
+
 pub fn chomper<L: Listener<HTTPStreamEvent> + 'static>(
     http_listener: L,
     key_db: Arc<RwLock<KeyDB>>,
 ) -> EthernetChomper<ListenerDispatcher> {
-    let join = dispatch::ListenerJoin::new(http_listener);
-    let dispatch = dispatch::ListenerDispatcher::default()
-        .add(80, HTTPRequestTracker::new(Box::new(join.clone())))
-        .add(
-            443,
-            TLSFlowTracker::new(
-                key_db.clone(),
-                Box::new(HTTPRequestTracker::new(Box::new(join))),
-            ),
-        );
-
-    EthernetChomper {
-        tcp_follower: TcpFollower::default(),
-        recv: dispatch,
-        key_db,
-    }
+    chomper_with_options(http_listener, key_db, DecodeOptions::default())
+}
+
+pub fn chomper_with_options<L: Listener<HTTPStreamEvent> + 'static>(
+    http_listener: L,
+    key_db: Arc<RwLock<KeyDB>>,
+    options: DecodeOptions,
+) -> EthernetChomper<ListenerDispatcher> {
+    Pipeline::new()
+        .options(options)
+        .tls(key_db)
+        .http()
+        .build(http_listener)
 }
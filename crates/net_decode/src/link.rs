@@ -0,0 +1,352 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The link layer, between the capture and IP.
+
+use std::collections::BTreeMap;
+
+pub use pcap_parser::Linktype;
+
+/// Linux cooked capture v2, which `pcap_parser` has no name for.
+pub const LINKTYPE_LINUX_SLL2: Linktype = Linktype(276);
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+/// 802.1Q tag, or the inner (customer) tag in QinQ.
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+/// 802.1ad outer (service) tag.
+pub const ETHERTYPE_QINQ: u16 = 0x88a8;
+/// What some switches used for the outer tag before 802.1ad.
+pub const ETHERTYPE_QINQ_LEGACY: u16 = 0x9100;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const SLL_HEADER_LEN: usize = 16;
+const SLL2_HEADER_LEN: usize = 20;
+const NULL_HEADER_LEN: usize = 4;
+
+/// Address families in NULL/LOOP headers. IPv6 depends on who wrote the
+/// capture.
+const AF_INET: u32 = 2;
+const AF_INET6_LINUX: u32 = 10;
+const AF_INET6_BSD: u32 = 24;
+const AF_INET6_FREEBSD: u32 = 28;
+const AF_INET6_DARWIN: u32 = 30;
+
+/// Which way a packet was going, as far as the capturing host is concerned.
+/// Only Linux cooked captures (`tcpdump -i any`), the flags of pcapng
+/// packets and live captures on Linux say.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// To this host.
+    Host,
+    Broadcast,
+    Multicast,
+    /// Between two other hosts, seen in promiscuous mode.
+    OtherHost,
+    /// From this host.
+    Outgoing,
+}
+
+impl PacketDirection {
+    /// From the `sll_pkttype` of a cooked capture or a packet socket.
+    pub fn from_sll(packet_type: u16) -> Option<Self> {
+        Some(match packet_type {
+            0 => PacketDirection::Host,
+            1 => PacketDirection::Broadcast,
+            2 => PacketDirection::Multicast,
+            3 => PacketDirection::OtherHost,
+            4 => PacketDirection::Outgoing,
+            _ => return None,
+        })
+    }
+
+    /// From the `epb_flags` option of a pcapng packet: its direction bits,
+    /// and for inbound packets, how they were received.
+    pub fn from_epb_flags(flags: u32) -> Option<Self> {
+        Some(match (flags & 0b11, (flags >> 2) & 0b111) {
+            (0b10, _) => PacketDirection::Outgoing,
+            (0b01, 2) => PacketDirection::Multicast,
+            (0b01, 3) => PacketDirection::Broadcast,
+            (0b01, 4) => PacketDirection::OtherHost,
+            (0b01, _) => PacketDirection::Host,
+            _ => return None,
+        })
+    }
+
+    /// As the `epb_flags` option of a pcapng packet.
+    pub fn epb_flags(self) -> u32 {
+        const INBOUND: u32 = 0b01;
+        match self {
+            PacketDirection::Host => INBOUND | 1 << 2,
+            PacketDirection::Multicast => INBOUND | 2 << 2,
+            PacketDirection::Broadcast => INBOUND | 3 << 2,
+            PacketDirection::OtherHost => INBOUND | 4 << 2,
+            PacketDirection::Outgoing => 0b10,
+        }
+    }
+}
+
+/// A frame with the link layer taken off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkFrame<'a> {
+    /// What the payload is, past any VLAN tags.
+    pub ethertype: u16,
+    pub direction: Option<PacketDirection>,
+    pub payload: &'a [u8],
+}
+
+/// Whether [`parse_link`] understands frames of `link_type`.
+pub fn is_supported(link_type: Linktype) -> bool {
+    [
+        Linktype::ETHERNET,
+        Linktype::LINUX_SLL,
+        LINKTYPE_LINUX_SLL2,
+        Linktype::NULL,
+        Linktype::LOOP,
+        Linktype::RAW,
+        Linktype::IPV4,
+        Linktype::IPV6,
+    ]
+    .contains(&link_type)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VlanTag {
+    /// Which kind of tag it is, e.g. [`ETHERTYPE_VLAN`].
+    pub tpid: u16,
+    pub priority: u8,
+    pub id: u16,
+}
+
+/// Skips any VLAN tags at the start of `data`, which came after
+/// `ethertype`. Returns the ethertype after the tags, and what follows it.
+/// `None` if the frame is truncated.
+pub fn strip_vlan_tags<'a>(
+    mut ethertype: u16,
+    mut data: &'a [u8],
+    tags: &mut Vec<VlanTag>,
+) -> Option<(u16, &'a [u8])> {
+    while let ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY = ethertype {
+        let tci = u16::from_be_bytes(data.get(0..2)?.try_into().unwrap());
+        tags.push(VlanTag {
+            tpid: ethertype,
+            priority: (tci >> 13) as u8,
+            id: tci & 0xfff,
+        });
+        ethertype = u16::from_be_bytes(data.get(2..4)?.try_into().unwrap());
+        data = &data[4..];
+    }
+    Some((ethertype, data))
+}
+
+/// Splits an Ethernet frame into the ethertype past any VLAN tags, and its
+/// payload.
+pub fn parse_ethernet<'a>(frame: &'a [u8], tags: &mut Vec<VlanTag>) -> Option<(u16, &'a [u8])> {
+    let ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().unwrap());
+    strip_vlan_tags(ethertype, &frame[ETHERNET_HEADER_LEN..], tags)
+}
+
+/// Takes the link layer off a frame captured on an interface of
+/// `link_type`. `None` if it is truncated, or of a type we do not support.
+pub fn parse_link<'a>(
+    link_type: Linktype,
+    frame: &'a [u8],
+    tags: &mut Vec<VlanTag>,
+) -> Option<LinkFrame<'a>> {
+    let be16 = |at: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(
+            frame.get(at..at + 2)?.try_into().unwrap(),
+        ))
+    };
+    let (ethertype, direction, payload) = match link_type {
+        Linktype::ETHERNET => {
+            let (ethertype, payload) = parse_ethernet(frame, tags)?;
+            (ethertype, None, payload)
+        }
+        // https://www.tcpdump.org/linktypes/LINKTYPE_LINUX_SLL.html
+        Linktype::LINUX_SLL => {
+            let (ethertype, payload) =
+                strip_vlan_tags(be16(14)?, frame.get(SLL_HEADER_LEN..)?, tags)?;
+            (ethertype, PacketDirection::from_sll(be16(0)?), payload)
+        }
+        // https://www.tcpdump.org/linktypes/LINKTYPE_LINUX_SLL2.html
+        LINKTYPE_LINUX_SLL2 => {
+            let (ethertype, payload) =
+                strip_vlan_tags(be16(0)?, frame.get(SLL2_HEADER_LEN..)?, tags)?;
+            let packet_type = *frame.get(10)? as u16;
+            (ethertype, PacketDirection::from_sll(packet_type), payload)
+        }
+        // The address family, in the byte order of the host that wrote the
+        // capture for NULL, and big endian for LOOP.
+        Linktype::NULL | Linktype::LOOP => {
+            let family: [u8; 4] = frame.get(..NULL_HEADER_LEN)?.try_into().unwrap();
+            let family = if link_type == Linktype::LOOP || family[..2] == [0, 0] {
+                u32::from_be_bytes(family)
+            } else {
+                u32::from_le_bytes(family)
+            };
+            let ethertype = match family {
+                AF_INET => ETHERTYPE_IPV4,
+                AF_INET6_LINUX | AF_INET6_BSD | AF_INET6_FREEBSD | AF_INET6_DARWIN => {
+                    ETHERTYPE_IPV6
+                }
+                _ => return None,
+            };
+            (ethertype, None, &frame[NULL_HEADER_LEN..])
+        }
+        // Bare IP, of either version.
+        Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => {
+            let ethertype = match frame.first()? >> 4 {
+                4 => ETHERTYPE_IPV4,
+                6 => ETHERTYPE_IPV6,
+                _ => return None,
+            };
+            (ethertype, None, frame)
+        }
+        _ => return None,
+    };
+    Some(LinkFrame {
+        ethertype,
+        direction,
+        payload,
+    })
+}
+
+/// What we have seen of the link layer across a capture.
+#[derive(Clone, Debug, Default)]
+pub struct LinkStats {
+    /// How many frames were tagged with each VLAN ID, counting every tag
+    /// of QinQ frames.
+    pub vlan_frames: BTreeMap<u16, u64>,
+    /// Frames with no tags.
+    pub untagged_frames: u64,
+    /// Every frame captured, including ones we could not decode, and their
+    /// total length.
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+impl LinkStats {
+    pub fn record(&mut self, tags: &[VlanTag]) {
+        if tags.is_empty() {
+            self.untagged_frames += 1;
+        }
+        for tag in tags {
+            let count = self.vlan_frames.entry(tag.id).or_default();
+            if *count == 0 {
+                tracing::debug!("first frame on vlan {}", tag.id);
+            }
+            *count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_qinq() {
+        let mut frame = vec![0xff; 12];
+        // Outer tag: priority 5, VLAN 100
+        frame.extend([0x88, 0xa8, 0xa0, 100]);
+        // Inner tag: VLAN 2000
+        frame.extend([0x81, 0x00, 0x07, 0xd0]);
+        frame.extend([0x86, 0xdd, 0x60, 0, 0, 0]);
+
+        let mut tags = Vec::new();
+        assert_eq!(
+            parse_ethernet(&frame, &mut tags),
+            Some((ETHERTYPE_IPV6, &[0x60, 0, 0, 0][..]))
+        );
+        assert_eq!(
+            tags,
+            vec![
+                VlanTag {
+                    tpid: ETHERTYPE_QINQ,
+                    priority: 5,
+                    id: 100
+                },
+                VlanTag {
+                    tpid: ETHERTYPE_VLAN,
+                    priority: 0,
+                    id: 2000
+                },
+            ]
+        );
+
+        // Cut off partway through the inner tag
+        tags.clear();
+        assert_eq!(parse_ethernet(&frame[..18], &mut tags), None);
+    }
+
+    #[test]
+    fn test_cooked_and_loopback() {
+        let ip = [0x45, 0, 0, 20];
+        let mut tags = Vec::new();
+
+        // SLL2: IPv4, interface 3, ARPHRD_ETHER, outgoing, 6 byte address
+        let mut frame = vec![0x08, 0x00, 0, 0, 0, 0, 0, 3, 0, 1, 4, 6];
+        frame.extend([0xaa; 8]);
+        frame.extend(ip);
+        assert_eq!(
+            parse_link(LINKTYPE_LINUX_SLL2, &frame, &mut tags),
+            Some(LinkFrame {
+                ethertype: ETHERTYPE_IPV4,
+                direction: Some(PacketDirection::Outgoing),
+                payload: &ip[..],
+            })
+        );
+
+        // SLL: to us, ARPHRD_ETHER, 6 byte address, IPv4
+        let mut frame = vec![0, 0, 0, 1, 0, 6];
+        frame.extend([0xaa; 8]);
+        frame.extend([0x08, 0x00]);
+        frame.extend(ip);
+        assert_eq!(
+            parse_link(Linktype::LINUX_SLL, &frame, &mut tags),
+            Some(LinkFrame {
+                ethertype: ETHERTYPE_IPV4,
+                direction: Some(PacketDirection::Host),
+                payload: &ip[..],
+            })
+        );
+
+        // NULL from a little endian macOS host: AF_INET6
+        let mut frame = vec![30, 0, 0, 0];
+        frame.extend(ip);
+        assert_eq!(
+            parse_link(Linktype::NULL, &frame, &mut tags).map(|f| f.ethertype),
+            Some(ETHERTYPE_IPV6)
+        );
+
+        assert_eq!(
+            parse_link(Linktype::RAW, &ip, &mut tags).map(|f| f.ethertype),
+            Some(ETHERTYPE_IPV4)
+        );
+        assert!(tags.is_empty());
+    }
+
+    #[test]
+    fn test_epb_flags() {
+        for direction in [
+            PacketDirection::Host,
+            PacketDirection::Broadcast,
+            PacketDirection::Multicast,
+            PacketDirection::OtherHost,
+            PacketDirection::Outgoing,
+        ] {
+            assert_eq!(
+                PacketDirection::from_epb_flags(direction.epb_flags()),
+                Some(direction)
+            );
+        }
+        // Inbound, with no reception type, and with the direction unknown
+        assert_eq!(
+            PacketDirection::from_epb_flags(0b01),
+            Some(PacketDirection::Host)
+        );
+        assert_eq!(PacketDirection::from_epb_flags(0b1100), None);
+    }
+}
@@ -6,11 +6,13 @@ use std::{
     any::{Any, TypeId},
     collections::BTreeMap,
     fmt,
+    sync::Arc,
 };
 
+use bytes::Bytes;
 use dyn_clone::DynClone;
 
-use crate::chomp::IPTarget;
+use crate::{chomp::IPTarget, link::PacketDirection, tunnel::Tunnel};
 
 /// Simple type indexed map
 #[derive(Default, Debug, Clone)]
@@ -29,6 +31,25 @@ impl<V> TypeMap<V> {
 /// Nanoseconds since the Unix epoch
 pub type Nanos = u64;
 
+/// The interface a packet was captured on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CaptureInterface {
+    /// For a live capture, the kernel's index of the interface. For a
+    /// capture file, where it is in
+    /// [`CaptureInfo::interfaces`](crate::capture_file::CaptureInfo::interfaces).
+    pub index: u32,
+    pub name: Option<Arc<str>>,
+}
+
+impl fmt::Display for CaptureInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => f.write_str(name),
+            None => write!(f, "interface {}", self.index),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct TimingInfo {
     pub received_on_wire: Nanos,
@@ -36,8 +57,25 @@ pub struct TimingInfo {
     // registry. I would like to not have a central registry to make this code
     // more reusable.
     pub other_times: TypeMap<Nanos>,
+    /// Which interface the packet was captured on, if the capture says.
+    pub interface: Option<CaptureInterface>,
+    /// Which way the packet was going, if the capture says.
+    pub direction: Option<PacketDirection>,
+    /// Tunnels the packet was inside of, outermost first.
+    pub tunnels: Vec<Tunnel>,
+    /// Which connection on the same addresses and ports the packet belongs
+    /// to, counting from 0. TCP starts a new one at each new SYN, so that
+    /// reused ports are not mistaken for the same connection.
+    pub connection_epoch: u32,
+    /// For protocols that carry several streams over one connection, such
+    /// as SCTP, which one the data was on.
+    pub stream: Option<u32>,
 }
 
+/// Out of band information passed along the chain next to messages.
+///
+/// Use `downcast_ref` on `dyn SideData`, or
+/// [`dispatch_side_data!`](crate::dispatch_side_data), to get at what it is.
 pub trait SideData: fmt::Debug + DynClone + Send + Sync {
     /// Note massive footgun: if you are using this on Box you need to re-deref
     /// it: `(&*some_box).as_any()`. If you do not, it will wind up using the
@@ -53,6 +91,50 @@ impl<T: fmt::Debug + Any + Sized + Send + Sync + DynClone> SideData for T {
 
 dyn_clone::clone_trait_object!(SideData);
 
+impl dyn SideData {
+    /// The side data as a `T`, if it is one.
+    ///
+    /// Unlike going through [`SideData::as_any`], this is right however
+    /// many `Box`es and references it is called through, since those do not
+    /// have it themselves.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+}
+
+/// A listener that wants side data of type `T`, see
+/// [`dispatch_side_data!`](crate::dispatch_side_data).
+pub trait SideDataHandler<T> {
+    fn handle_side_data(&mut self, data: &T);
+}
+
+/// Hands `$data`, a `&Box<dyn SideData>` or `&dyn SideData`, to the
+/// [`SideDataHandler`] of `$handler`, a `&mut`, for each of the listed
+/// types that it is. The list is what the handler subscribes to; anything
+/// else is left alone.
+///
+/// ```ignore
+/// dispatch_side_data!(self, &data, [ALPNCompleted, ConnectionClosed]);
+/// self.next.on_side_data(data);
+/// ```
+#[macro_export]
+macro_rules! dispatch_side_data {
+    ($handler:expr, $data:expr, [$($ty:ty),* $(,)?]) => {{
+        // Reborrowed rather than moved, so `self` can be used afterwards
+        let handler: &mut _ = $handler;
+        let data = $data;
+        $(
+            if let Some(d) = data.downcast_ref::<$ty>() {
+                $crate::listener::SideDataHandler::<$ty>::handle_side_data(&mut *handler, d);
+            }
+        )*
+    }};
+}
+
 pub struct MessageMeta {
     pub timing: TimingInfo,
     pub target: IPTarget,
@@ -93,11 +175,80 @@ impl<T> Listener<T> for NoOpListener {
     fn on_side_data(&mut self, _data: Box<dyn SideData>) {}
 }
 
+/// Sends everything to both `A` and `B`, so one stream of messages can feed
+/// several consumers.
+#[derive(Debug, Default)]
+pub struct Tee<A, B>(pub A, pub B);
+
+impl<T: Clone, A: Listener<T>, B: Listener<T>> Listener<T> for Tee<A, B> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        self.0
+            .on_data(timing.clone(), target, to_client, data.clone());
+        self.1.on_data(timing, target, to_client, data);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        // &* so as to clone the contents rather than the Box.
+        self.0.on_side_data(dyn_clone::clone_box(&*data));
+        self.1.on_side_data(data);
+    }
+}
+
+/// Like [`Tee`], for any number of listeners chosen at runtime.
+pub struct FanOut<T> {
+    listeners: Vec<Box<dyn Listener<T>>>,
+}
+
+impl<T> Default for FanOut<T> {
+    fn default() -> Self {
+        FanOut {
+            listeners: Vec::new(),
+        }
+    }
+}
+
+impl<T> FanOut<T> {
+    pub fn add(mut self, listener: impl Listener<T> + 'static) -> Self {
+        self.listeners.push(Box::new(listener));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.listeners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+}
+
+impl<T: Clone> Listener<T> for FanOut<T> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        let Some((last, rest)) = self.listeners.split_last_mut() else {
+            return;
+        };
+        for l in rest {
+            l.on_data(timing.clone(), target, to_client, data.clone());
+        }
+        last.on_data(timing, target, to_client, data);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        let Some((last, rest)) = self.listeners.split_last_mut() else {
+            return;
+        };
+        for l in rest {
+            l.on_side_data(dyn_clone::clone_box(&*data));
+        }
+        last.on_side_data(data);
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct HexDumpListener {}
 
-impl Listener<Vec<u8>> for HexDumpListener {
-    fn on_data(&mut self, _timing: TimingInfo, target: IPTarget, to_client: bool, data: Vec<u8>) {
+impl Listener<Bytes> for HexDumpListener {
+    fn on_data(&mut self, _timing: TimingInfo, target: IPTarget, to_client: bool, data: Bytes) {
         tracing::info!(
             "data {target:?} to_client={to_client}:\n{}",
             hexdump::HexDumper::new(&data)
@@ -119,3 +270,95 @@ impl<T: fmt::Debug> Listener<T> for DebugListener {
         tracing::info!("side data: {data:?}");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::test_support::{Received, SideDataListener, TestListener};
+
+    #[derive(Default)]
+    struct Subscriber {
+        numbers: Vec<u32>,
+        strings: Vec<String>,
+    }
+
+    impl SideDataHandler<u32> for Subscriber {
+        fn handle_side_data(&mut self, data: &u32) {
+            self.numbers.push(*data);
+        }
+    }
+
+    impl SideDataHandler<String> for Subscriber {
+        fn handle_side_data(&mut self, data: &String) {
+            self.strings.push(data.clone());
+        }
+    }
+
+    #[test]
+    fn test_dispatch_side_data() {
+        let mut subscriber = Subscriber::default();
+        let data: [Box<dyn SideData>; 3] =
+            [Box::new(1u32), Box::new("two".to_string()), Box::new(3u64)];
+        for data in &data {
+            // Through a reference to the Box, the way that goes wrong with
+            // as_any
+            assert!(!data.is::<Box<dyn SideData>>());
+            crate::dispatch_side_data!(&mut subscriber, data, [u32, String]);
+        }
+        assert_eq!(subscriber.numbers, vec![1]);
+        assert_eq!(subscriber.strings, vec!["two".to_string()]);
+        assert_eq!(data[2].downcast_ref::<u64>(), Some(&3));
+    }
+
+    #[test]
+    fn test_tee_and_fan_out() {
+        let messages = [(); 3].map(|_| Arc::new(RwLock::new(Vec::new())));
+        let side_data = Arc::new(RwLock::new(Vec::new()));
+        let mut fan_out = FanOut::default()
+            .add(TestListener {
+                received: messages[0].clone(),
+            })
+            .add(Tee(
+                TestListener {
+                    received: messages[1].clone(),
+                },
+                TestListener {
+                    received: messages[2].clone(),
+                },
+            ))
+            .add(SideDataListener {
+                received: side_data.clone(),
+            });
+        assert_eq!(fan_out.len(), 3);
+
+        let target = IPTarget::from_ports(
+            &crate::chomp::IPHeader::V4(
+                pktparse::ipv4::parse_ipv4_header(&[
+                    0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+                ])
+                .unwrap()
+                .1,
+            ),
+            40000,
+            80,
+        );
+        fan_out.on_data(
+            TimingInfo::default(),
+            target,
+            true,
+            Bytes::from_static(b"hi"),
+        );
+        fan_out.on_side_data(Box::new(42u32));
+
+        for received in &messages {
+            let received = received.read().unwrap();
+            assert_eq!(received.len(), 2);
+            assert!(matches!(&received[0], Received::Message(meta, data)
+                if meta.to_client && data[..] == b"hi"[..]));
+            assert!(matches!(&received[1], Received::SideData(sd) if sd.is::<u32>()));
+        }
+        assert_eq!(SideDataListener::find::<u32>(&side_data), vec![42]);
+    }
+}
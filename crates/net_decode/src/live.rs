@@ -0,0 +1,230 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Settings that can be changed while a capture is running, without
+//! rebuilding the decoding stack and so losing the state of the connections
+//! being followed.
+//!
+//! A [`LiveFilter`] goes after the HTTP decoder and looks at the current
+//! [`LiveSettings`] whenever a request starts. Everything about that request
+//! and its response then goes the same way, so changing settings never
+//! leaves half a request behind.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    body_policy::{request_host, HostPattern},
+    chomp::IPTarget,
+    filter::FlowMatch,
+    http::{HTTPStreamEvent, RequestId},
+    listener::{Listener, SideData, TimingInfo},
+};
+
+#[derive(Clone, Debug)]
+pub struct LiveSettings {
+    /// Flows to pass on requests of.
+    pub flows: FlowMatch,
+    /// HTTP hosts to pass on requests to. Empty passes on every host.
+    pub hosts: Vec<HostPattern>,
+    /// Whether to pass on request and response bodies.
+    pub bodies: bool,
+}
+
+impl Default for LiveSettings {
+    fn default() -> Self {
+        LiveSettings {
+            flows: FlowMatch::default(),
+            hosts: Vec::new(),
+            bodies: true,
+        }
+    }
+}
+
+impl LiveSettings {
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        if self.hosts.is_empty() {
+            return true;
+        }
+        host.is_some_and(|h| self.hosts.iter().any(|p| p.matches(h)))
+    }
+}
+
+/// Shared handle to the [`LiveSettings`] of any number of [`LiveFilter`]s.
+#[derive(Clone, Debug, Default)]
+pub struct LiveConfig(Arc<RwLock<LiveSettings>>);
+
+impl LiveConfig {
+    pub fn new(settings: LiveSettings) -> Self {
+        LiveConfig(Arc::new(RwLock::new(settings)))
+    }
+
+    pub fn get(&self) -> LiveSettings {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Applies `f` to the settings, for requests starting from now on.
+    pub fn update(&self, f: impl FnOnce(&mut LiveSettings)) {
+        let mut settings = self.0.write().unwrap();
+        f(&mut settings);
+        tracing::info!("live settings changed: {settings:?}");
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Decision {
+    Drop,
+    HeadersOnly,
+    Full,
+}
+
+/// Filters HTTP requests per the [`LiveConfig`] at the time they start.
+pub struct LiveFilter<L> {
+    config: LiveConfig,
+    /// Request ids are only unique per HTTP decoder, so these are keyed by
+    /// flow too.
+    requests: HashMap<(IPTarget, RequestId), Decision>,
+    next: L,
+}
+
+impl<L: Listener<HTTPStreamEvent>> LiveFilter<L> {
+    pub fn new(config: LiveConfig, next: L) -> Self {
+        LiveFilter {
+            config,
+            requests: HashMap::new(),
+            next,
+        }
+    }
+
+    fn decide(&self, target: &IPTarget, to_client: bool, parts: &http::request::Parts) -> Decision {
+        let settings = self.config.0.read().unwrap();
+        if !settings.flows.matches_flow(target, to_client)
+            || !settings.matches_host(request_host(parts).as_deref())
+        {
+            Decision::Drop
+        } else if !settings.bodies {
+            Decision::HeadersOnly
+        } else {
+            Decision::Full
+        }
+    }
+}
+
+impl<L: Listener<HTTPStreamEvent>> Listener<HTTPStreamEvent> for LiveFilter<L> {
+    fn on_data(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        data: HTTPStreamEvent,
+    ) {
+        let decision = match &data {
+            HTTPStreamEvent::NewRequest(id, parts) => {
+                let decision = self.decide(&target, to_client, parts);
+                self.requests.insert((target, *id), decision);
+                decision
+            }
+            HTTPStreamEvent::ResponseFinished(id, _) => self
+                .requests
+                .remove(&(target, *id))
+                .unwrap_or(Decision::Full),
+            HTTPStreamEvent::ReqBodyChunk(id, _)
+            | HTTPStreamEvent::RequestFinished(id, _)
+            | HTTPStreamEvent::NewResponse(id, _)
+            | HTTPStreamEvent::RespBodyChunk(id, _)
+            | HTTPStreamEvent::ReqBodyTruncated(id, _)
+            | HTTPStreamEvent::RespBodyTruncated(id, _) => self
+                .requests
+                .get(&(target, *id))
+                .copied()
+                .unwrap_or(Decision::Full),
+        };
+        let pass = match decision {
+            Decision::Drop => false,
+            Decision::HeadersOnly => !matches!(
+                data,
+                HTTPStreamEvent::ReqBodyChunk(..)
+                    | HTTPStreamEvent::RespBodyChunk(..)
+                    | HTTPStreamEvent::ReqBodyTruncated(..)
+                    | HTTPStreamEvent::RespBodyTruncated(..)
+            ),
+            Decision::Full => true,
+        };
+        if pass {
+            self.next.on_data(timing, target, to_client, data);
+        }
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.next.on_side_data(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::test_support::{sample_target, Received, TestListener};
+
+    fn request(id: RequestId, host: &str) -> HTTPStreamEvent {
+        let (mut parts, _) = http::Request::new(()).into_parts();
+        parts
+            .headers
+            .insert(http::header::HOST, host.parse().unwrap());
+        HTTPStreamEvent::NewRequest(id, parts)
+    }
+
+    #[test]
+    fn test_live_filter() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let config = LiveConfig::default();
+        let mut filter = LiveFilter::new(
+            config.clone(),
+            TestListener {
+                received: received.clone(),
+            },
+        );
+        let target = sample_target();
+        let mut send = |ev| filter.on_data(TimingInfo::default(), target, false, ev);
+
+        send(request(0, "a.example"));
+        config.update(|s| s.bodies = false);
+        send(request(1, "a.example"));
+        config.update(|s| s.hosts = vec![HostPattern::new("*.internal")]);
+        send(request(2, "a.example"));
+        // Requests already going are not affected by the changes
+        for id in 0..3 {
+            send(HTTPStreamEvent::ReqBodyChunk(
+                id,
+                Bytes::from_static(b"body"),
+            ));
+            send(HTTPStreamEvent::ResponseFinished(id, 0));
+        }
+
+        let received = received.read().unwrap();
+        let passed: Vec<_> = received
+            .iter()
+            .map(|r| match r {
+                Received::Message(_, HTTPStreamEvent::NewRequest(id, _)) => ("request", *id),
+                Received::Message(_, HTTPStreamEvent::ReqBodyChunk(id, _)) => ("body", *id),
+                Received::Message(_, HTTPStreamEvent::ResponseFinished(id, _)) => ("finished", *id),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            passed,
+            vec![
+                ("request", 0),
+                ("request", 1),
+                ("body", 0),
+                ("finished", 0),
+                ("finished", 1),
+            ]
+        );
+        assert!(filter.requests.is_empty());
+    }
+}
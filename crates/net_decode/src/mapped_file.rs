@@ -0,0 +1,189 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Read-only memory maps of files, for reading big captures straight out of
+//! the page cache rather than copying them through a buffer.
+//!
+//! Reading a part of a map that the file no longer has, because it was
+//! truncated after being mapped, kills the process with `SIGBUS`, so only
+//! files that are not being rewritten should be mapped. A file that is
+//! still being appended to is fine, the map just ends where the file did
+//! when it was made.
+//!
+//! Elsewhere than Unix the file is read into memory instead, which costs as
+//! much memory as the file is big but reads the same.
+
+use std::{fs, io, path::Path};
+
+#[cfg(unix)]
+pub use self::unix::MappedFile;
+
+#[cfg(not(unix))]
+pub use self::buffered::MappedFile;
+
+impl MappedFile {
+    pub fn open(path: &Path) -> io::Result<MappedFile> {
+        Self::new(&fs::File::open(path)?)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs, io,
+        ops::Deref,
+        os::fd::AsRawFd,
+        ptr::{self, NonNull},
+    };
+
+    use nix::libc::{self, c_void};
+
+    /// All of a file, mapped into memory.
+    pub struct MappedFile {
+        map: NonNull<u8>,
+        len: usize,
+    }
+
+    // Nothing can write to the map, so it can be read from anywhere.
+    unsafe impl Send for MappedFile {}
+    unsafe impl Sync for MappedFile {}
+
+    impl MappedFile {
+        /// Maps `file` as it is now.
+        pub fn new(file: &fs::File) -> io::Result<MappedFile> {
+            let len = usize::try_from(file.metadata()?.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "file is too big to map")
+            })?;
+            if len == 0 {
+                // mmap refuses empty maps.
+                return Ok(MappedFile {
+                    map: NonNull::dangling(),
+                    len,
+                });
+            }
+
+            let map = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if map == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(MappedFile {
+                map: NonNull::new(map as *mut u8).expect("mmap succeeded"),
+                len,
+            })
+        }
+
+        fn advise(&self, advice: libc::c_int) {
+            if self.len > 0 {
+                // Only a hint, so failing doesn't matter.
+                unsafe { libc::madvise(self.map.as_ptr() as *mut c_void, self.len, advice) };
+            }
+        }
+
+        /// Tells the kernel the map is going to be read from start to end, so
+        /// that it reads further ahead and drops what was read sooner.
+        pub fn advise_sequential(&self) {
+            self.advise(libc::MADV_SEQUENTIAL);
+        }
+
+        /// Tells the kernel the map is going to be read here and there, so that
+        /// it doesn't read ahead of what is wanted.
+        pub fn advise_random(&self) {
+            self.advise(libc::MADV_RANDOM);
+        }
+    }
+
+    impl Deref for MappedFile {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { std::slice::from_raw_parts(self.map.as_ptr(), self.len) }
+        }
+    }
+
+    impl Drop for MappedFile {
+        fn drop(&mut self) {
+            if self.len > 0 {
+                unsafe { libc::munmap(self.map.as_ptr() as *mut c_void, self.len) };
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod buffered {
+    use std::{
+        fs,
+        io::{self, Read},
+        ops::Deref,
+    };
+
+    /// All of a file, read into memory.
+    pub struct MappedFile {
+        data: Vec<u8>,
+    }
+
+    impl MappedFile {
+        /// Reads `file` as it is now.
+        pub fn new(file: &fs::File) -> io::Result<MappedFile> {
+            let mut data = Vec::new();
+            io::BufReader::new(file).read_to_end(&mut data)?;
+            Ok(MappedFile { data })
+        }
+
+        /// Does nothing, there being no map to advise on.
+        pub fn advise_sequential(&self) {}
+
+        /// Does nothing, there being no map to advise on.
+        pub fn advise_random(&self) {}
+    }
+
+    impl Deref for MappedFile {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.data
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn corpus(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("corpus")
+            .join(name)
+    }
+
+    #[test]
+    fn test_map() {
+        let path = corpus("http.pcapng");
+        let map = MappedFile::open(&path).unwrap();
+        map.advise_sequential();
+        assert_eq!(&map[..], &fs::read(&path).unwrap()[..]);
+    }
+
+    #[test]
+    fn test_empty() {
+        let path = std::env::temp_dir().join(format!("clipper-empty-map-{}", std::process::id()));
+        fs::File::create(&path).unwrap();
+        let map = MappedFile::open(&path);
+        fs::remove_file(&path).unwrap();
+        let map = map.unwrap();
+        map.advise_random();
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Counters for how a capture is going, for applications embedding the
+//! decoders to report on.
+//!
+//! A [`MetricsListener`] goes in front of the listener taking the
+//! reassembled bytes from [`EthernetChomper`](crate::chomp::EthernetChomper),
+//! where it sees every flow's data and all the side data. Frame counts come
+//! from the chomper's [`LinkStats`] instead.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    checksum::side_data::BadChecksum,
+    chomp::IPTarget,
+    diagnostic::{side_data::Diagnostic, Layer},
+    link::LinkStats,
+    listener::{Listener, SideData, TimingInfo},
+    tcp_reassemble::side_data::{
+        ConnectionClosed, ConnectionFailed, ConnectionLifecycle, FlowEvicted, LifecycleEvent,
+    },
+    tcp_timing::side_data::HandshakeRtt,
+    tls::side_data::{OpaqueFlow, PendingKeysDropped, TlsDecodeFailed},
+    udp_flow::side_data::UdpFlowEnded,
+};
+
+/// Counts of values by their order of magnitude: bucket `i` holds values
+/// needing `i` bits, that is, those below `2^i` and at least `2^(i-1)`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    /// The exclusive upper bound of each non-empty bucket and how many
+    /// values are in it.
+    pub fn buckets(&self) -> impl Iterator<Item = (u128, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n > 0)
+            .map(|(i, &n)| (1u128 << i, n))
+    }
+
+    /// An upper bound on the `q`th quantile, from 0 to 1.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, n) in self.buckets() {
+            seen += n;
+            if seen >= rank {
+                return Some(bound.saturating_sub(1).min(self.max as u128) as u64);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProtocolCounters {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    /// From [`MetricsSnapshot::with_link_stats`].
+    pub frames: u64,
+    pub frame_bytes: u64,
+    /// Reassembled data, by the protocol of its flow.
+    pub protocols: BTreeMap<&'static str, ProtocolCounters>,
+    /// Sizes of the reassembled messages.
+    pub message_bytes: Histogram,
+    pub tcp_opened: u64,
+    pub tcp_failed: u64,
+    pub tcp_closed: u64,
+    /// Of the closed ones, how many were forgotten about rather than seen
+    /// to close.
+    pub tcp_evicted: u64,
+    pub udp_ended: u64,
+    /// Round trip time from the capture point to the server and back to
+    /// the client, in nanoseconds.
+    pub handshake_rtt: Histogram,
+    pub bad_checksums: u64,
+    /// [`Diagnostic`]s, by layer.
+    pub decode_errors: BTreeMap<Layer, u64>,
+    /// TLS connections we had keys for but could not decrypt.
+    pub decryption_failures: u64,
+    /// TLS connections we had no keys for.
+    pub missing_keys: u64,
+    pub pending_tls_bytes_dropped: u64,
+}
+
+impl MetricsSnapshot {
+    pub fn with_link_stats(mut self, link_stats: &LinkStats) -> Self {
+        self.frames = link_stats.frames;
+        self.frame_bytes = link_stats.bytes;
+        self
+    }
+}
+
+/// Shared handle to the metrics gathered by any number of
+/// [`MetricsListener`]s.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics(Arc<Mutex<MetricsSnapshot>>);
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn on_side_data(&self, data: &(dyn SideData + 'static)) {
+        let mut m = self.0.lock().unwrap();
+        if let Some(lifecycle) = data.downcast_ref::<ConnectionLifecycle>() {
+            if lifecycle.event == LifecycleEvent::Syn {
+                m.tcp_opened += 1;
+            }
+        } else if data.is::<ConnectionFailed>() {
+            m.tcp_failed += 1;
+        } else if data.is::<ConnectionClosed>() {
+            m.tcp_closed += 1;
+        } else if data.is::<FlowEvicted>() {
+            m.tcp_evicted += 1;
+        } else if data.is::<UdpFlowEnded>() {
+            m.udp_ended += 1;
+        } else if let Some(rtt) = data.downcast_ref::<HandshakeRtt>() {
+            m.handshake_rtt.record(rtt.to_server + rtt.to_client);
+        } else if data.is::<BadChecksum>() {
+            m.bad_checksums += 1;
+        } else if let Some(diagnostic) = data.downcast_ref::<Diagnostic>() {
+            *m.decode_errors.entry(diagnostic.layer).or_default() += 1;
+        } else if data.is::<TlsDecodeFailed>() {
+            m.decryption_failures += 1;
+        } else if data.is::<OpaqueFlow>() {
+            m.missing_keys += 1;
+        } else if let Some(dropped) = data.downcast_ref::<PendingKeysDropped>() {
+            m.pending_tls_bytes_dropped += dropped.dropped_bytes as u64;
+        }
+    }
+}
+
+/// Counts what goes through it into [`Metrics`] before passing it on.
+pub struct MetricsListener<L> {
+    metrics: Metrics,
+    /// Protocol names by server port.
+    protocols: HashMap<u16, &'static str>,
+    next: L,
+}
+
+impl<L: Listener<Bytes>> MetricsListener<L> {
+    pub fn new(metrics: Metrics, next: L) -> Self {
+        MetricsListener {
+            metrics,
+            protocols: HashMap::new(),
+            next,
+        }
+    }
+
+    /// Counts data of flows to `port` under `name`. Anything else is
+    /// counted as "other".
+    pub fn with_protocol(mut self, port: u16, name: &'static str) -> Self {
+        self.protocols.insert(port, name);
+        self
+    }
+}
+
+impl<L: Listener<Bytes>> Listener<Bytes> for MetricsListener<L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: Bytes) {
+        let protocol = self
+            .protocols
+            .get(&target.server_port())
+            .copied()
+            .unwrap_or("other");
+        {
+            let mut m = self.metrics.0.lock().unwrap();
+            let counters = m.protocols.entry(protocol).or_default();
+            counters.messages += 1;
+            counters.bytes += data.len() as u64;
+            m.message_bytes.record(data.len() as u64);
+        }
+        self.next.on_data(timing, target, to_client, data);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.metrics.on_side_data(&*data);
+        self.next.on_side_data(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{chomp::IPHeader, listener::NoOpListener};
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        for v in [0, 1, 2, 3, 100, 1000] {
+            h.record(v);
+        }
+        assert_eq!(
+            h.buckets().collect::<Vec<_>>(),
+            vec![(1, 1), (2, 1), (4, 2), (128, 1), (1024, 1)]
+        );
+        assert_eq!((h.count, h.sum, h.max), (6, 1106, 1000));
+        assert_eq!(h.quantile(0.5), Some(3));
+        assert_eq!(h.quantile(1.0), Some(1000));
+        assert_eq!(Histogram::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_metrics_listener() {
+        let metrics = Metrics::default();
+        let mut listener =
+            MetricsListener::new(metrics.clone(), NoOpListener {}).with_protocol(443, "tls");
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+        let header = IPHeader::V4(header);
+        let tls = IPTarget::from_ports(&header, 40000, 443);
+        let other = IPTarget::from_ports(&header, 40001, 22);
+
+        listener.on_data(TimingInfo::default(), tls, false, Bytes::from(vec![0; 100]));
+        listener.on_data(TimingInfo::default(), tls, true, Bytes::from(vec![0; 1000]));
+        listener.on_data(
+            TimingInfo::default(),
+            other,
+            false,
+            Bytes::from(vec![0; 10]),
+        );
+        listener.on_side_data(Box::new(ConnectionLifecycle {
+            timing: TimingInfo::default(),
+            target: tls,
+            from_client: true,
+            event: LifecycleEvent::Syn,
+        }));
+        listener.on_side_data(Box::new(Diagnostic {
+            timing: TimingInfo::default(),
+            target: Some(other),
+            layer: Layer::Http,
+            severity: crate::diagnostic::Severity::Error,
+            message: "oh no".to_string(),
+        }));
+
+        let link_stats = LinkStats {
+            frames: 7,
+            bytes: 1500,
+            ..Default::default()
+        };
+        let snapshot = metrics.snapshot().with_link_stats(&link_stats);
+        assert_eq!(
+            snapshot.protocols["tls"],
+            ProtocolCounters {
+                messages: 2,
+                bytes: 1100
+            }
+        );
+        assert_eq!(snapshot.protocols["other"].bytes, 10);
+        assert_eq!(snapshot.message_bytes.count, 3);
+        assert_eq!(snapshot.tcp_opened, 1);
+        assert_eq!(snapshot.decode_errors[&Layer::Http], 1);
+        assert_eq!((snapshot.frames, snapshot.frame_bytes), (7, 1500));
+    }
+}
@@ -0,0 +1,549 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Multipath TCP (RFC 8684): one connection spread over several TCP
+//! subflows, each carrying pieces of a single data-level byte stream.
+//!
+//! Subflows are grouped by the tokens derived from the keys exchanged on the
+//! first one, and their data is put back in data sequence order and
+//! delivered as if it had all come over the first subflow. Without the keys
+//! (e.g. the handshake was not captured), additional subflows cannot be
+//! grouped and are left alone as plain TCP.
+
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+use pktparse::tcp::TcpHeader;
+
+use crate::{
+    chomp::IPTarget,
+    listener::{Listener, TimingInfo},
+};
+
+pub mod side_data {
+    use crate::{chomp::IPTarget, listener::TimingInfo};
+
+    /// Fired by `net_decode::tcp_reassemble` when a TCP flow turns out to be
+    /// another subflow of an MPTCP connection. Its data is delivered as part
+    /// of `connection`, the target of the connection's first subflow.
+    #[derive(Clone, Debug)]
+    pub struct MptcpSubflowJoined {
+        pub timing: TimingInfo,
+        pub connection: IPTarget,
+        pub subflow: IPTarget,
+    }
+}
+
+use side_data::MptcpSubflowJoined;
+
+const TCPOPT_EOL: u8 = 0;
+const TCPOPT_NOP: u8 = 1;
+pub(crate) const TCPOPT_MPTCP: u8 = 30;
+
+const MP_CAPABLE: u8 = 0;
+const MP_JOIN: u8 = 1;
+const DSS: u8 = 2;
+
+/// MP_JOIN in a SYN, which is the one with the token.
+const MP_JOIN_SYN_LEN: usize = 12;
+
+const DSS_ACK: u8 = 0x01;
+const DSS_ACK_64: u8 = 0x02;
+const DSS_MAPPING: u8 = 0x04;
+const DSS_DSN_64: u8 = 0x08;
+
+/// How many mappings to remember per subflow direction.
+const MAX_MAPPINGS: usize = 1024;
+/// How many out of order chunks to hold per connection direction.
+const MAX_HELD_CHUNKS: usize = 1024;
+
+/// The MPTCP options we need.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MptcpOption {
+    /// MP_CAPABLE, with the keys of its sender and receiver where present.
+    /// The third ACK can also carry the length of the first data.
+    Capable {
+        sender_key: Option<u64>,
+        receiver_key: Option<u64>,
+        data_len: Option<u16>,
+    },
+    /// MP_JOIN. Only the one in the SYN has the token of the connection
+    /// being joined.
+    Join { token: Option<u32> },
+    /// The mapping part of a DSS option.
+    Mapping(DssMapping),
+}
+
+/// Where a range of a subflow goes in the data-level stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DssMapping {
+    /// Only the low 32 bits unless `dsn_64`.
+    pub data_seq: u64,
+    pub dsn_64: bool,
+    /// Relative to the subflow's initial sequence number.
+    pub subflow_seq: u32,
+    /// Zero for an infinite mapping, where the subflow fell back to plain
+    /// TCP.
+    pub len: u16,
+}
+
+impl DssMapping {
+    fn covers(&self, subflow_seq: u32) -> bool {
+        let offset = subflow_seq.wrapping_sub(self.subflow_seq);
+        self.len == 0 || offset < self.len as u32
+    }
+}
+
+/// Picks the MPTCP options out of the raw options of a TCP header.
+pub(crate) fn parse_options(mut options: &[u8]) -> Vec<MptcpOption> {
+    let mut found = Vec::new();
+    while let Some(&kind) = options.first() {
+        match kind {
+            TCPOPT_EOL => break,
+            TCPOPT_NOP => {
+                options = &options[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let len = match options.get(1) {
+            Some(&len) if len >= 2 => len as usize,
+            _ => break,
+        };
+        let Some(option) = options.get(..len) else {
+            break;
+        };
+        if kind == TCPOPT_MPTCP {
+            found.extend(parse_mptcp(option));
+        }
+        options = &options[len..];
+    }
+    found
+}
+
+/// RFC 8684 section 3. Version 0 (RFC 6824) has the same layouts for what
+/// we look at.
+fn parse_mptcp(option: &[u8]) -> Option<MptcpOption> {
+    let be16 = |at: usize| {
+        Some(u16::from_be_bytes(
+            option.get(at..at + 2)?.try_into().unwrap(),
+        ))
+    };
+    let be32 = |at: usize| {
+        Some(u32::from_be_bytes(
+            option.get(at..at + 4)?.try_into().unwrap(),
+        ))
+    };
+    let be64 = |at: usize| {
+        Some(u64::from_be_bytes(
+            option.get(at..at + 8)?.try_into().unwrap(),
+        ))
+    };
+
+    match option.get(2)? >> 4 {
+        MP_CAPABLE => Some(MptcpOption::Capable {
+            sender_key: be64(4),
+            receiver_key: be64(12),
+            data_len: be16(20),
+        }),
+        MP_JOIN => Some(MptcpOption::Join {
+            token: if option.len() == MP_JOIN_SYN_LEN {
+                be32(4)
+            } else {
+                None
+            },
+        }),
+        DSS => {
+            let flags = *option.get(3)?;
+            if flags & DSS_MAPPING == 0 {
+                return None;
+            }
+            let mut at = 4;
+            if flags & DSS_ACK != 0 {
+                at += if flags & DSS_ACK_64 != 0 { 8 } else { 4 };
+            }
+            let dsn_64 = flags & DSS_DSN_64 != 0;
+            let data_seq = if dsn_64 { be64(at)? } else { be32(at)? as u64 };
+            at += if dsn_64 { 8 } else { 4 };
+            Some(MptcpOption::Mapping(DssMapping {
+                data_seq,
+                dsn_64,
+                subflow_seq: be32(at)?,
+                len: be16(at + 4)?,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// The token identifying the connection to the holder of `key`, and the
+/// initial data sequence number of what they send (RFC 8684 section 3.1).
+pub(crate) fn token_and_idsn(key: u64) -> (u32, u64) {
+    let hash = ring::digest::digest(&ring::digest::SHA256, &key.to_be_bytes());
+    let hash = hash.as_ref();
+    (
+        u32::from_be_bytes(hash[..4].try_into().unwrap()),
+        u64::from_be_bytes(hash[24..].try_into().unwrap()),
+    )
+}
+
+/// Index of the end that sent something: 0 for the client, 1 for the
+/// server.
+fn sender(from_client: bool) -> usize {
+    if from_client {
+        0
+    } else {
+        1
+    }
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    /// Of the client and the server.
+    keys: [Option<u64>; 2],
+    /// Next data sequence number to deliver from the client and from the
+    /// server.
+    next: [Option<u64>; 2],
+    /// Data that arrived ahead of `next`, by data sequence number.
+    held: [BTreeMap<u64, Bytes>; 2],
+}
+
+impl Connection {
+    /// Takes `data` sent by `end` at data sequence number `dsn`, and passes
+    /// on whatever is now in order.
+    fn ingest(
+        &mut self,
+        timing: &TimingInfo,
+        target: IPTarget,
+        end: usize,
+        dsn: u64,
+        data: Bytes,
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        let next = *self.next[end].get_or_insert(dsn);
+        let held = &mut self.held[end];
+        if dsn.wrapping_add(data.len() as u64) <= next {
+            return;
+        }
+        if held.len() >= MAX_HELD_CHUNKS {
+            tracing::warn!("too much out of order mptcp data on {target:?}, dropping");
+            return;
+        }
+        held.entry(dsn).or_insert(data);
+
+        let mut next = next;
+        while let Some(entry) = held.first_entry() {
+            let start = *entry.key();
+            if start > next {
+                break;
+            }
+            let chunk = entry.remove();
+            let end_dsn = start + chunk.len() as u64;
+            if end_dsn > next {
+                let fresh = chunk.slice((next - start) as usize..);
+                recv.on_data(timing.clone(), target, end == 1, fresh);
+                next = end_dsn;
+            }
+        }
+        self.next[end] = Some(next);
+    }
+}
+
+#[derive(Debug)]
+struct Subflow {
+    /// Target of the connection's first subflow.
+    connection: IPTarget,
+    /// Whether the subflow's client is the connection's server.
+    flipped: bool,
+    /// Initial sequence numbers of the subflow's client and server.
+    isn: [Option<u32>; 2],
+    /// What each end sent, by subflow sequence number.
+    mappings: [BTreeMap<u32, DssMapping>; 2],
+    /// Whether the server agreed to use MPTCP.
+    established: bool,
+}
+
+impl Subflow {
+    fn new(connection: IPTarget, flipped: bool, client_isn: u32) -> Self {
+        Subflow {
+            connection,
+            flipped,
+            isn: [Some(client_isn), None],
+            mappings: Default::default(),
+            established: false,
+        }
+    }
+
+    fn add_mapping(&mut self, end: usize, mapping: DssMapping) {
+        let mappings = &mut self.mappings[end];
+        mappings.insert(mapping.subflow_seq, mapping);
+        if mappings.len() > MAX_MAPPINGS {
+            mappings.pop_first();
+        }
+    }
+}
+
+/// Groups MPTCP subflows into connections and reassembles their data.
+#[derive(Debug, Default)]
+pub struct MptcpTracker {
+    /// By the target of the TCP flow.
+    subflows: HashMap<IPTarget, Subflow>,
+    /// By the target of the first subflow.
+    connections: HashMap<IPTarget, Connection>,
+    /// The connection and end each token belongs to.
+    tokens: HashMap<u32, (IPTarget, usize)>,
+}
+
+impl MptcpTracker {
+    /// Takes the MPTCP options of a segment on the TCP flow `target`.
+    pub(crate) fn on_segment(
+        &mut self,
+        timing: &TimingInfo,
+        target: IPTarget,
+        from_client: bool,
+        tcp: &TcpHeader,
+        options: &[MptcpOption],
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        let end = sender(from_client);
+        for option in options {
+            match *option {
+                MptcpOption::Capable {
+                    sender_key,
+                    receiver_key,
+                    data_len,
+                } => {
+                    if tcp.flag_syn && !tcp.flag_ack {
+                        self.subflows
+                            .insert(target, Subflow::new(target, false, tcp.sequence_no));
+                        self.connections.insert(target, Connection::default());
+                    }
+                    let Some(subflow) = self.subflows.get_mut(&target) else {
+                        continue;
+                    };
+                    if subflow.connection != target {
+                        continue;
+                    }
+                    if tcp.flag_syn && tcp.flag_ack {
+                        subflow.isn[1] = Some(tcp.sequence_no);
+                        subflow.established = true;
+                    }
+                    for (end, key) in [(end, sender_key), (1 - end, receiver_key)] {
+                        if let Some(key) = key {
+                            self.learn_key(target, end, key);
+                        }
+                    }
+                    // The first data can come with the keys instead of a
+                    // mapping.
+                    if let (Some(len), Some(key)) = (data_len, sender_key) {
+                        let (_, idsn) = token_and_idsn(key);
+                        let subflow = self.subflows.get_mut(&target).unwrap();
+                        subflow.add_mapping(
+                            end,
+                            DssMapping {
+                                data_seq: idsn.wrapping_add(1),
+                                dsn_64: true,
+                                subflow_seq: 1,
+                                len,
+                            },
+                        );
+                    }
+                }
+                MptcpOption::Join { token } if tcp.flag_syn && !tcp.flag_ack => {
+                    let Some(&(connection, joined)) = token.and_then(|t| self.tokens.get(&t))
+                    else {
+                        tracing::debug!("mptcp join of unknown connection on {target:?}");
+                        continue;
+                    };
+                    // The token is the receiver's. If that is the client of
+                    // the connection, the server opened this subflow.
+                    self.subflows.insert(
+                        target,
+                        Subflow::new(connection, joined == 0, tcp.sequence_no),
+                    );
+                }
+                MptcpOption::Join { .. } => {
+                    let Some(subflow) = self.subflows.get_mut(&target) else {
+                        continue;
+                    };
+                    if tcp.flag_syn && !subflow.established {
+                        subflow.isn[1] = Some(tcp.sequence_no);
+                        subflow.established = true;
+                        tracing::debug!("mptcp subflow {target:?} joined {:?}", subflow.connection);
+                        recv.on_side_data(Box::new(MptcpSubflowJoined {
+                            timing: timing.clone(),
+                            connection: subflow.connection,
+                            subflow: target,
+                        }));
+                    }
+                }
+                MptcpOption::Mapping(mapping) => {
+                    if let Some(subflow) = self.subflows.get_mut(&target) {
+                        subflow.add_mapping(end, mapping);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops the TCP flow `target`, along with its connection if it was the
+    /// last subflow left.
+    pub(crate) fn forget(&mut self, target: IPTarget) {
+        let Some(subflow) = self.subflows.remove(&target) else {
+            return;
+        };
+        let connection = subflow.connection;
+        if !self.subflows.values().any(|s| s.connection == connection) {
+            self.connections.remove(&connection);
+            self.tokens.retain(|_, (c, _)| *c != connection);
+        }
+    }
+
+    fn learn_key(&mut self, connection: IPTarget, end: usize, key: u64) {
+        let Some(conn) = self.connections.get_mut(&connection) else {
+            return;
+        };
+        if conn.keys[end].is_some() {
+            return;
+        }
+        conn.keys[end] = Some(key);
+        let (token, idsn) = token_and_idsn(key);
+        self.tokens.insert(token, (connection, end));
+        // The SYN takes up the first data sequence number.
+        conn.next[end].get_or_insert(idsn.wrapping_add(1));
+    }
+
+    /// Takes in-order data from the TCP flow `target`, starting at sequence
+    /// number `seq`. Returns false if it is not an MPTCP subflow, or has
+    /// fallen back to plain TCP, and the data should be delivered as is.
+    pub(crate) fn on_subflow_data(
+        &mut self,
+        timing: &TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        seq: u32,
+        data: &Bytes,
+        recv: &mut dyn Listener<Bytes>,
+    ) -> bool {
+        let Some(subflow) = self.subflows.get(&target) else {
+            return false;
+        };
+        let end = sender(!to_client);
+        let (true, Some(isn)) = (subflow.established, subflow.isn[end]) else {
+            return false;
+        };
+        let mappings = &subflow.mappings[end];
+        if mappings.is_empty() {
+            return false;
+        }
+        let Some(conn) = self.connections.get_mut(&subflow.connection) else {
+            return false;
+        };
+        let conn_end = end ^ subflow.flipped as usize;
+
+        let mut data = data.clone();
+        let mut rel = seq.wrapping_sub(isn);
+        while !data.is_empty() {
+            let mapping = mappings
+                .range(..=rel)
+                .next_back()
+                .map(|(_, m)| m)
+                .filter(|m| m.covers(rel));
+            let Some(mapping) = mapping else {
+                tracing::warn!("unmapped data on mptcp subflow {target:?}, dropping");
+                break;
+            };
+            let offset = rel.wrapping_sub(mapping.subflow_seq);
+            let n = if mapping.len == 0 {
+                data.len()
+            } else {
+                data.len().min((mapping.len as u32 - offset) as usize)
+            };
+            let dsn = expand_dsn(mapping, conn.next[conn_end]).wrapping_add(offset as u64);
+            conn.ingest(
+                timing,
+                subflow.connection,
+                conn_end,
+                dsn,
+                data.split_to(n),
+                recv,
+            );
+            rel = rel.wrapping_add(n as u32);
+        }
+        true
+    }
+}
+
+/// The full data sequence number of `mapping`, taking a 32 bit one to be
+/// the closest to where we expect to be.
+fn expand_dsn(mapping: &DssMapping, next: Option<u64>) -> u64 {
+    match next {
+        Some(next) if !mapping.dsn_64 => {
+            let candidate = (next & !0xffff_ffff) | mapping.data_seq;
+            [
+                candidate.wrapping_sub(1 << 32),
+                candidate,
+                candidate.wrapping_add(1 << 32),
+            ]
+            .into_iter()
+            .min_by_key(|c| c.wrapping_sub(next).min(next.wrapping_sub(*c)))
+            .unwrap()
+        }
+        _ => mapping.data_seq,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_options() {
+        let mut options = vec![TCPOPT_NOP, TCPOPT_NOP];
+        // Timestamps
+        options.extend([8, 10, 0, 0, 0, 1, 0, 0, 0, 2]);
+        // DSS with a 4 byte data ACK and an 8 byte DSN
+        options.extend([TCPOPT_MPTCP, 22, DSS << 4, 0x0d]);
+        options.extend(7u32.to_be_bytes());
+        options.extend(0x1_0000_0005u64.to_be_bytes());
+        options.extend([0, 0, 0, 1, 0, 100]);
+        options.extend([TCPOPT_EOL, 0, 0]);
+        assert_eq!(
+            parse_options(&options),
+            vec![MptcpOption::Mapping(DssMapping {
+                data_seq: 0x1_0000_0005,
+                dsn_64: true,
+                subflow_seq: 1,
+                len: 100,
+            })]
+        );
+
+        let mut syn = vec![TCPOPT_MPTCP, 12, MP_JOIN << 4, 0];
+        syn.extend(0xdeadbeefu32.to_be_bytes());
+        syn.extend([0; 4]);
+        assert_eq!(
+            parse_options(&syn),
+            vec![MptcpOption::Join {
+                token: Some(0xdeadbeef)
+            }]
+        );
+
+        // Truncated
+        assert_eq!(parse_options(&[TCPOPT_MPTCP, 12, MP_JOIN << 4]), vec![]);
+    }
+
+    #[test]
+    fn test_expand_dsn() {
+        let mapping = DssMapping {
+            data_seq: 0x10,
+            dsn_64: false,
+            subflow_seq: 1,
+            len: 10,
+        };
+        assert_eq!(expand_dsn(&mapping, None), 0x10);
+        assert_eq!(expand_dsn(&mapping, Some(0x5_0000_0000)), 0x5_0000_0010);
+        // Just wrapped past what we expected
+        assert_eq!(expand_dsn(&mapping, Some(0x4_ffff_fff0)), 0x5_0000_0010);
+    }
+}
@@ -0,0 +1,71 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Comments that decoders and listeners put on the packets they decode,
+//! such as "TCP handshake" or "request 42 starts here", for writing onto
+//! the packets when the capture is exported, where Wireshark shows them.
+//!
+//! A packet is decoded entirely within the call that it is given in, so a
+//! comment sent during that call is about that packet. A comment can also
+//! be about a range of packets, from an earlier one up to the one being
+//! decoded, such as all of a handshake.
+
+pub mod side_data {
+    use crate::{
+        chomp::IPTarget,
+        listener::{Nanos, TimingInfo},
+    };
+
+    /// Fired by anything with something to say about the packet being
+    /// decoded.
+    #[derive(Clone, Debug)]
+    pub struct PacketComment {
+        pub timing: TimingInfo,
+        /// The flow it is about, if it is about one.
+        pub target: Option<IPTarget>,
+        /// When the first packet the comment is about was received, if it
+        /// is about more than the one being decoded.
+        pub since: Option<Nanos>,
+        pub text: String,
+    }
+}
+
+use side_data::PacketComment;
+
+use crate::{
+    chomp::IPTarget,
+    listener::{Listener, Nanos, TimingInfo},
+};
+
+/// Comments on the packet being decoded.
+pub fn comment<T>(
+    recv: &mut (impl Listener<T> + ?Sized),
+    timing: &TimingInfo,
+    target: Option<IPTarget>,
+    text: impl Into<String>,
+) {
+    recv.on_side_data(Box::new(PacketComment {
+        timing: timing.clone(),
+        target,
+        since: None,
+        text: text.into(),
+    }));
+}
+
+/// Comments on the packets of `target` from the one received at `since` up
+/// to the one being decoded.
+pub fn comment_since<T>(
+    recv: &mut (impl Listener<T> + ?Sized),
+    timing: &TimingInfo,
+    target: IPTarget,
+    since: Nanos,
+    text: impl Into<String>,
+) {
+    recv.on_side_data(Box::new(PacketComment {
+        timing: timing.clone(),
+        target: Some(target),
+        since: Some(since),
+        text: text.into(),
+    }));
+}
@@ -0,0 +1,551 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Decoding on several threads at once, for captures too big for one core.
+//!
+//! ```ignore
+//! let mut chomper = ParallelChomper::new(
+//!     4,
+//!     move || Pipeline::new().tls(key_db.clone()).http(),
+//!     my_http_listener,
+//! );
+//! chomp::dump_pcap_file(file, &mut chomper)?;
+//! chomper.finish()?;
+//! ```
+//!
+//! Each worker has a whole decoding stack of its own, built from the
+//! [`Pipeline`], and frames are handed out by a hash of their addresses and
+//! ports, so that both directions of a flow always go to the same worker
+//! and are decoded in order. Fragmented IP packets have no ports to go by,
+//! so they go by their addresses alone: a flow which is only sometimes
+//! fragmented can end up split across workers. Keys go to every worker.
+//! Each worker's clock only moves with the frames it is given, so flows may
+//! time out a little later than they would on one thread.
+//!
+//! Unless [`ParallelChomper::ordered`] is turned off, what the workers send
+//! to the sink is put back into the order a single stack would have sent it
+//! in, that of the frames it came from, at the cost of holding on to it
+//! until the slower workers catch up.
+
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    chomp::{self, FrameChomper, IPTarget, Ipv6Payload, IPPROTO_TCP, IPPROTO_UDP},
+    http::HTTPStreamEvent,
+    key_db::{ClientRandom, Secret, SecretType},
+    link::{self, Linktype},
+    listener::{Listener, Nanos, SideData, TimingInfo},
+    pipeline::Pipeline,
+    sctp::IPPROTO_SCTP,
+    tls::side_data::NewKeyReceived,
+    Error,
+};
+
+/// How many frames may be waiting for each worker before the reader waits.
+pub const DEFAULT_QUEUE_FRAMES: usize = 1024;
+
+/// FNV-1a, so that flows go to the same workers every run.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Hash of the flow a frame is part of, the same in both directions.
+fn flow_hash(link_type: Linktype, packet: &[u8]) -> u64 {
+    let mut hasher = Fnv(0xcbf29ce484222325);
+    let Some(frame) = link::parse_link(link_type, packet, &mut Vec::new()) else {
+        return 0;
+    };
+    let (src, dst, upper) = match frame.ethertype {
+        link::ETHERTYPE_IPV4 => {
+            let Ok((_, header)) = pktparse::ipv4::parse_ipv4_header(frame.payload) else {
+                return 0;
+            };
+            let header_len = (frame.payload[0] & 0xf) as usize * 4;
+            // More fragments (1 bit), then offset (13 bits).
+            let fragment = u16::from_be_bytes([frame.payload[6], frame.payload[7]]);
+            let upper = frame
+                .payload
+                .get(header_len..)
+                .filter(|_| fragment & 0x3fff == 0)
+                .map(|rest| (frame.payload[9], rest));
+            (
+                IpAddr::V4(header.source_addr),
+                IpAddr::V4(header.dest_addr),
+                upper,
+            )
+        }
+        link::ETHERTYPE_IPV6 => {
+            let Ok((rest, header)) = pktparse::ipv6::parse_ipv6_header(frame.payload) else {
+                return 0;
+            };
+            let upper = match chomp::ipv6_upper_layer(frame.payload[6], rest) {
+                Some(Ipv6Payload::Upper(proto, payload)) => Some((proto, payload)),
+                _ => None,
+            };
+            (
+                IpAddr::V6(header.source_addr),
+                IpAddr::V6(header.dest_addr),
+                upper,
+            )
+        }
+        ethertype => {
+            ethertype.hash(&mut hasher);
+            return hasher.finish();
+        }
+    };
+    let ports = upper
+        .filter(|(proto, _)| [IPPROTO_TCP, IPPROTO_UDP, IPPROTO_SCTP].contains(proto))
+        .and_then(|(_, payload)| payload.get(..4))
+        .map(|p| {
+            (
+                u16::from_be_bytes([p[0], p[1]]),
+                u16::from_be_bytes([p[2], p[3]]),
+            )
+        })
+        .unwrap_or_default();
+    let (a, b) = ((src, ports.0), (dst, ports.1));
+    let ends = if a <= b { (a, b) } else { (b, a) };
+    ends.hash(&mut hasher);
+    hasher.finish()
+}
+
+enum Work {
+    Frame {
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: Vec<u8>,
+    },
+    Keys(Arc<[u8]>),
+    WireguardKeys(Arc<[u8]>),
+    Key(ClientRandom, SecretType, Secret),
+    AdvanceTime(Nanos),
+}
+
+struct Job {
+    /// Numbers the jobs in the order they were given to the pool, starting
+    /// from 1.
+    seq: u64,
+    work: Work,
+}
+
+enum Output {
+    Data(TimingInfo, IPTarget, bool, HTTPStreamEvent),
+    SideData(Box<dyn SideData>),
+}
+
+#[derive(Clone, Copy, Default)]
+struct Progress {
+    /// The last job given to the worker.
+    sent: u64,
+    /// The last job the worker got through.
+    finished: u64,
+}
+
+struct MergeState {
+    ordered: bool,
+    /// Held back until every worker is past the job it came from. Keyed by
+    /// job, worker, then the order the worker sent it in.
+    pending: BTreeMap<(u64, usize, u64), Output>,
+    progress: Vec<Progress>,
+    /// The last job given to any worker.
+    sent: u64,
+    /// The first error any worker ran into.
+    error: Option<Error>,
+    sink: Box<dyn Listener<HTTPStreamEvent>>,
+}
+
+impl MergeState {
+    fn deliver(&mut self, output: Output) {
+        match output {
+            Output::Data(timing, target, to_client, event) => {
+                self.sink.on_data(timing, target, to_client, event)
+            }
+            Output::SideData(data) => self.sink.on_side_data(data),
+        }
+    }
+
+    /// Sends on whatever no worker can send anything before any more.
+    fn release(&mut self) {
+        let sent = self.sent;
+        let done = self
+            .progress
+            .iter()
+            .map(|p| {
+                if p.finished == p.sent {
+                    sent
+                } else {
+                    p.finished
+                }
+            })
+            .min()
+            .unwrap_or(sent);
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 > done {
+                break;
+            }
+            let output = entry.remove();
+            self.deliver(output);
+        }
+    }
+}
+
+type Merge = Arc<Mutex<MergeState>>;
+
+/// The sink at the end of each worker's stack.
+struct WorkerSink {
+    worker: usize,
+    /// The job the worker is on.
+    job: Arc<AtomicU64>,
+    sent: u64,
+    merge: Merge,
+}
+
+impl WorkerSink {
+    fn push(&mut self, output: Output) {
+        let mut merge = self.merge.lock().unwrap();
+        if !merge.ordered {
+            merge.deliver(output);
+            return;
+        }
+        let key = (self.job.load(Ordering::Relaxed), self.worker, self.sent);
+        self.sent += 1;
+        merge.pending.insert(key, output);
+    }
+}
+
+impl Listener<HTTPStreamEvent> for WorkerSink {
+    fn on_data(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        data: HTTPStreamEvent,
+    ) {
+        self.push(Output::Data(timing, target, to_client, data));
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        // Every worker is given the keys, but the sink only wants to hear
+        // about them once.
+        if self.worker != 0 && data.is::<NewKeyReceived>() {
+            return;
+        }
+        self.push(Output::SideData(data));
+    }
+}
+
+fn run_worker(worker: usize, jobs: Receiver<Job>, pipeline: Pipeline, merge: Merge) {
+    let job = Arc::new(AtomicU64::new(0));
+    let mut chomper = pipeline.build(WorkerSink {
+        worker,
+        job: job.clone(),
+        sent: 0,
+        merge: merge.clone(),
+    });
+    for Job { seq, work } in jobs {
+        job.store(seq, Ordering::Relaxed);
+        let mut result = Ok(());
+        match work {
+            Work::Frame {
+                timing,
+                link_type,
+                packet,
+            } => result = chomper.chomp(timing, link_type, &packet),
+            Work::Keys(dsb) => chomper.on_keys(&dsb),
+            Work::WireguardKeys(key_log) => chomper.on_wireguard_keys(&key_log),
+            Work::Key(client_random, secret_type, secret) => {
+                chomper.on_key(client_random, secret_type, secret)
+            }
+            Work::AdvanceTime(now) => chomper.advance_time(now),
+        }
+
+        let mut merge = merge.lock().unwrap();
+        if let Err(e) = result {
+            tracing::warn!(worker, "decoding failed: {e}");
+            merge.error.get_or_insert(e);
+        }
+        merge.progress[worker].finished = seq;
+        merge.release();
+    }
+}
+
+/// Spreads decoding over a pool of threads. See the [module docs](self).
+pub struct ParallelChomper {
+    senders: Vec<SyncSender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+    seq: u64,
+    merge: Merge,
+}
+
+impl ParallelChomper {
+    /// Starts `workers` threads, each decoding with a stack built from
+    /// `pipeline`, all sending to `sink`.
+    pub fn new(
+        workers: usize,
+        pipeline: impl Fn() -> Pipeline + Send + Sync + 'static,
+        sink: impl Listener<HTTPStreamEvent> + 'static,
+    ) -> Self {
+        Self::with_queue_frames(workers, DEFAULT_QUEUE_FRAMES, pipeline, sink)
+    }
+
+    pub fn with_queue_frames(
+        workers: usize,
+        queue_frames: usize,
+        pipeline: impl Fn() -> Pipeline + Send + Sync + 'static,
+        sink: impl Listener<HTTPStreamEvent> + 'static,
+    ) -> Self {
+        let pipelines = (0..workers.max(1)).map(|_| pipeline()).collect();
+        Self::from_pipelines(pipelines, queue_frames, sink)
+    }
+
+    /// Starts a thread for each of `pipelines`, for when building them can
+    /// fail, such as when they load plugins.
+    ///
+    /// Panics if `pipelines` is empty.
+    pub fn from_pipelines(
+        pipelines: Vec<Pipeline>,
+        queue_frames: usize,
+        sink: impl Listener<HTTPStreamEvent> + 'static,
+    ) -> Self {
+        assert!(!pipelines.is_empty(), "no pipelines to decode with");
+        let merge = Arc::new(Mutex::new(MergeState {
+            ordered: true,
+            pending: BTreeMap::new(),
+            progress: vec![Progress::default(); pipelines.len()],
+            sent: 0,
+            error: None,
+            sink: Box::new(sink),
+        }));
+
+        let mut senders = Vec::new();
+        let mut threads = Vec::new();
+        for (worker, pipeline) in pipelines.into_iter().enumerate() {
+            let (send, recv) = mpsc::sync_channel(queue_frames);
+            let merge = merge.clone();
+            let thread = thread::Builder::new()
+                .name(format!("decode-{worker}"))
+                .spawn(move || run_worker(worker, recv, pipeline, merge))
+                .expect("could not start decoding thread");
+            senders.push(send);
+            threads.push(thread);
+        }
+        ParallelChomper {
+            senders,
+            threads,
+            seq: 0,
+            merge,
+        }
+    }
+
+    /// Whether to put what comes out back in order, which is the default.
+    /// Sinks that only look at one flow at a time can do without.
+    pub fn ordered(self, ordered: bool) -> Self {
+        self.merge.lock().unwrap().ordered = ordered;
+        self
+    }
+
+    pub fn workers(&self) -> usize {
+        self.senders.len()
+    }
+
+    /// Gives `work` to the workers in `to`.
+    fn send(&mut self, to: impl Iterator<Item = usize> + Clone, mut work: impl FnMut() -> Work) {
+        self.seq += 1;
+        let seq = self.seq;
+        {
+            let mut merge = self.merge.lock().unwrap();
+            merge.sent = seq;
+            for worker in to.clone() {
+                merge.progress[worker].sent = seq;
+            }
+        }
+        for worker in to {
+            if self.senders[worker]
+                .send(Job { seq, work: work() })
+                .is_err()
+            {
+                tracing::error!(worker, "decoding thread has gone away");
+            }
+        }
+    }
+
+    fn broadcast(&mut self, work: impl FnMut() -> Work) {
+        self.send(0..self.senders.len(), work);
+    }
+
+    /// Ends the flows that have gone idle by `now` on every worker. See
+    /// [`EthernetChomper::advance_time`](chomp::EthernetChomper::advance_time).
+    pub fn advance_time(&mut self, now: Nanos) {
+        self.broadcast(|| Work::AdvanceTime(now));
+    }
+
+    /// Waits for the workers to get through everything they were given and
+    /// sends on the rest of the output.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<(), Error> {
+        self.senders.clear();
+        let mut panicked = false;
+        for thread in self.threads.drain(..) {
+            panicked |= thread.join().is_err();
+        }
+        let mut merge = self.merge.lock().unwrap();
+        let pending = std::mem::take(&mut merge.pending);
+        for output in pending.into_values() {
+            merge.deliver(output);
+        }
+        if panicked {
+            return Err("a decoding thread panicked".into());
+        }
+        merge.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for ParallelChomper {
+    fn drop(&mut self) {
+        if let Err(e) = self.shut_down() {
+            tracing::warn!("{e}");
+        }
+    }
+}
+
+impl FrameChomper for ParallelChomper {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        if let Some(e) = self.merge.lock().unwrap().error.take() {
+            return Err(e);
+        }
+        let worker = (flow_hash(link_type, packet) % self.senders.len() as u64) as usize;
+        let packet = packet.to_vec();
+        let mut frame = Some(Work::Frame {
+            timing,
+            link_type,
+            packet,
+        });
+        // Only the one worker, so the closure runs once.
+        self.send(worker..worker + 1, || frame.take().unwrap());
+        Ok(())
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        let dsb: Arc<[u8]> = dsb.into();
+        self.broadcast(|| Work::Keys(dsb.clone()));
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        let key_log: Arc<[u8]> = key_log.into();
+        self.broadcast(|| Work::WireguardKeys(key_log.clone()));
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.broadcast(|| Work::Key(client_random.clone(), secret_type, secret.clone()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::{H1_CONN_REUSE, H2, NYA_DSB};
+
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Listener<HTTPStreamEvent> for Collect {
+        fn on_data(
+            &mut self,
+            timing: TimingInfo,
+            target: IPTarget,
+            to_client: bool,
+            data: HTTPStreamEvent,
+        ) {
+            let line = format!(
+                "{} {target:?} {to_client} {data:?}",
+                timing.received_on_wire
+            );
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn on_side_data(&mut self, _data: Box<dyn SideData>) {}
+    }
+
+    fn run(capture: &[u8], workers: Option<usize>) -> Vec<String> {
+        let collect = Collect::default();
+        let pipeline = || Pipeline::new().tls(Default::default()).http();
+        let capture = || std::io::Cursor::new(capture);
+        match workers {
+            Some(workers) => {
+                let mut chomper = ParallelChomper::new(workers, pipeline, collect.clone());
+                chomp::dump_pcap(capture(), &mut chomper).unwrap();
+                chomper.finish().unwrap();
+            }
+            None => {
+                let mut chomper = pipeline().build(collect.clone());
+                chomp::dump_pcap(capture(), &mut chomper).unwrap();
+            }
+        }
+        let lines = std::mem::take(&mut *collect.0.lock().unwrap());
+        lines
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        for capture in [NYA_DSB, H1_CONN_REUSE, H2] {
+            let serial = run(capture, None);
+            assert!(!serial.is_empty());
+            for workers in [1, 3] {
+                assert_eq!(run(capture, Some(workers)), serial, "{workers} workers");
+            }
+        }
+    }
+
+    #[test]
+    fn test_flow_hash_is_symmetric() {
+        let frame = |src: [u8; 4], sport: u16, dst: [u8; 4], dport: u16| {
+            let mut ip = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, IPPROTO_TCP, 0, 0];
+            ip.extend(src);
+            ip.extend(dst);
+            ip.extend(sport.to_be_bytes());
+            ip.extend(dport.to_be_bytes());
+            ip.extend([0; 16]);
+            ip
+        };
+        let hash = |packet: Vec<u8>| flow_hash(Linktype::RAW, &packet);
+        let (client, server) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+        assert_eq!(
+            hash(frame(client, 40000, server, 443)),
+            hash(frame(server, 443, client, 40000))
+        );
+        assert_ne!(
+            hash(frame(client, 40000, server, 443)),
+            hash(frame(client, 40001, server, 443))
+        );
+    }
+}
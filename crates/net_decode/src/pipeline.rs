@@ -0,0 +1,374 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Putting together a decoding stack without spelling out its types, for
+//! embedding clipper's decoding in other tools.
+//!
+//! ```ignore
+//! let mut chomper = Pipeline::new()
+//!     .options(options)
+//!     .tls(key_db)
+//!     .http()
+//!     .sink(my_http_listener);
+//! chomp::dump_pcap_file(file, &mut *chomper)?;
+//! ```
+//!
+//! TCP, UDP and the rest of the lower layers are always followed; the
+//! stages choose what happens to the reassembled bytes.
+
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    checkpoint::{Gated, ReplayGate},
+    chomp::{EthernetChomper, FrameChomper, IPTarget},
+    dispatch::{ListenerDispatcher, ListenerJoin, Matcher},
+    http::{HTTPRequestTracker, HTTPStreamEvent},
+    key_db::KeyDB,
+    listener::{Listener, SideData, TimingInfo},
+    plugin::{PluginRecord, PluginRegistry},
+    sampling::{BodySampler, FlowSampler},
+    tcp_reassemble::TcpFollower,
+    tls::TLSFlowTracker,
+    udp_flow::UdpFollower,
+    DecodeOptions,
+};
+
+#[cfg(feature = "stage-timing")]
+use crate::stage_timing::{Stage, StageTimes, Timed};
+
+pub const DEFAULT_HTTP_PORT: u16 = 80;
+pub const DEFAULT_TLS_PORT: u16 = 443;
+
+/// Matches whatever nothing before it did.
+#[derive(Debug)]
+struct Everything;
+
+impl Matcher for Everything {
+    fn match_traffic(&self, _target: IPTarget) -> bool {
+        true
+    }
+
+    fn as_debug(&self) -> &dyn fmt::Debug {
+        self
+    }
+}
+
+/// Passes on side data alone, for TLS without HTTP decoding, so that what
+/// is learnt from handshakes still gets to the sink.
+struct SideDataOnly<L>(L);
+
+impl<L: Listener<HTTPStreamEvent>> Listener<Bytes> for SideDataOnly<L> {
+    fn on_data(&mut self, _timing: TimingInfo, _target: IPTarget, _to_client: bool, _data: Bytes) {}
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.0.on_side_data(data);
+    }
+}
+
+/// Builder for the decoding stack. See the [module docs](self).
+#[derive(Default)]
+pub struct Pipeline {
+    options: DecodeOptions,
+    key_db: Option<Arc<RwLock<KeyDB>>>,
+    http_ports: Vec<u16>,
+    tls_ports: Vec<u16>,
+    /// For flows on none of the ports above.
+    other: Option<ListenerDispatcher>,
+    /// Takes decrypted TLS instead of HTTP decoding.
+    decrypted: Option<ListenerJoin<Bytes>>,
+    replay_gate: ReplayGate,
+    #[cfg(feature = "stage-timing")]
+    stage_times: Option<StageTimes>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn options(mut self, options: DecodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Decrypts TLS with keys from `key_db`, on [`DEFAULT_TLS_PORT`] unless
+    /// [`Pipeline::tls_port`] says otherwise. Without [`Pipeline::http`],
+    /// only side data comes out of it.
+    pub fn tls(mut self, key_db: Arc<RwLock<KeyDB>>) -> Self {
+        self.key_db = Some(key_db);
+        if self.tls_ports.is_empty() {
+            self.tls_ports.push(DEFAULT_TLS_PORT);
+        }
+        self
+    }
+
+    pub fn tls_port(mut self, port: u16) -> Self {
+        self.tls_ports.push(port);
+        self
+    }
+
+    /// Decodes HTTP inside TLS, and in the clear on [`DEFAULT_HTTP_PORT`]
+    /// unless [`Pipeline::http_port`] says otherwise.
+    pub fn http(mut self) -> Self {
+        if self.http_ports.is_empty() {
+            self.http_ports.push(DEFAULT_HTTP_PORT);
+        }
+        self
+    }
+
+    pub fn http_port(mut self, port: u16) -> Self {
+        self.http_ports.push(port);
+        self
+    }
+
+    /// Sends the decrypted bytes of TLS flows, and the side data from
+    /// decrypting them, to `listener` rather than decoding HTTP inside TLS.
+    /// The sink then gets nothing from TLS flows.
+    pub fn decrypted<L: Listener<Bytes> + 'static>(mut self, listener: L) -> Self {
+        self.decrypted = Some(ListenerJoin::new(listener));
+        self
+    }
+
+    /// Sends the reassembled bytes of flows on none of the HTTP and TLS
+    /// ports to `listener`, if `m` matches them. May be given more than
+    /// once; the first match wins.
+    pub fn other(
+        mut self,
+        m: impl Matcher + fmt::Debug + Send + Sync + 'static,
+        listener: impl Listener<Bytes> + Send + Sync + 'static,
+    ) -> Self {
+        self.other = Some(self.other.take().unwrap_or_default().add(m, listener));
+        self
+    }
+
+    /// Tries the plugins in `registry` on the flows not taken by anything
+    /// before it. See [`Pipeline::other`].
+    pub fn plugins<L: Listener<PluginRecord> + Send + Sync + 'static>(
+        self,
+        registry: PluginRegistry<L>,
+    ) -> Self {
+        self.other(Everything, registry)
+    }
+
+    /// Holds back what goes to the sink while `gate` is replaying, for
+    /// restoring checkpoints with a
+    /// [`CheckpointingChomper`](crate::checkpoint::CheckpointingChomper).
+    /// Listeners given to [`Pipeline::other`] need their own [`Gated`].
+    pub fn replay_gate(mut self, gate: ReplayGate) -> Self {
+        self.replay_gate = gate;
+        self
+    }
+
+    /// Records the time taken by the TLS, HTTP, other and sink stages into
+    /// `times`. Wrap the built chomper in a [`Timed`] to time the
+    /// [`Stage::Frames`] stage as well.
+    #[cfg(feature = "stage-timing")]
+    pub fn stage_timing(mut self, times: StageTimes) -> Self {
+        self.stage_times = Some(times);
+        self
+    }
+
+    /// Finishes the stack, sending HTTP events and side data to `sink`.
+    pub fn build<L: Listener<HTTPStreamEvent> + 'static>(
+        self,
+        sink: L,
+    ) -> EthernetChomper<ListenerDispatcher> {
+        let options = self.options;
+        let key_db = self.key_db.unwrap_or_default();
+        key_db
+            .write()
+            .unwrap()
+            .set_closed_retention(options.closed_key_retention);
+
+        #[cfg(feature = "stage-timing")]
+        let times = self.stage_times;
+        let sink = Gated::new(self.replay_gate, sink);
+        #[cfg(feature = "stage-timing")]
+        let sink = Timed::maybe(times.clone(), Stage::Sink, sink);
+        let join = match options.max_body_bytes {
+            Some(max) => ListenerJoin::new(BodySampler::new(max, sink)),
+            None => ListenerJoin::new(sink),
+        };
+        let http = !self.http_ports.is_empty();
+        let mut dispatch = ListenerDispatcher::default();
+        for port in self.http_ports {
+            let tracker = HTTPRequestTracker::new(Box::new(join.clone()));
+            #[cfg(feature = "stage-timing")]
+            let tracker = Timed::maybe(times.clone(), Stage::Http, tracker);
+            dispatch = dispatch.add(port, FlowSampler::new(options.sample_flows, tracker));
+        }
+        for port in self.tls_ports {
+            let next: Box<dyn Listener<Bytes>> = if let Some(decrypted) = &self.decrypted {
+                Box::new(decrypted.clone())
+            } else if http {
+                let tracker = HTTPRequestTracker::new(Box::new(join.clone()));
+                #[cfg(feature = "stage-timing")]
+                let tracker = Timed::maybe(times.clone(), Stage::Http, tracker);
+                Box::new(tracker)
+            } else {
+                Box::new(SideDataOnly(join.clone()))
+            };
+            let tracker = TLSFlowTracker::new(key_db.clone(), next)
+                .with_max_queued_bytes(options.max_queued_tls_bytes);
+            #[cfg(feature = "stage-timing")]
+            let tracker = Timed::maybe(times.clone(), Stage::Tls, tracker);
+            dispatch = dispatch.add(port, FlowSampler::new(options.sample_flows, tracker));
+        }
+        if let Some(other) = self.other {
+            #[cfg(feature = "stage-timing")]
+            let other = Timed::maybe(times.clone(), Stage::Other, other);
+            dispatch = dispatch.add(Everything, FlowSampler::new(options.sample_flows, other));
+        }
+
+        EthernetChomper {
+            tcp_follower: TcpFollower::default()
+                .with_idle_timeout(options.tcp_idle_timeout)
+                .with_max_flows(options.max_tcp_flows),
+            udp_follower: UdpFollower::default().with_idle_timeout(options.udp_idle_timeout),
+            sctp_follower: Default::default(),
+            fragments: Default::default(),
+            link_stats: Default::default(),
+            wireguard: Default::default(),
+            checksums: options.checksums,
+            recv: dispatch,
+            key_db,
+        }
+    }
+
+    /// [`Pipeline::build`], boxed up so that nothing needs to name its type.
+    pub fn sink<L: Listener<HTTPStreamEvent> + 'static>(self, sink: L) -> Box<dyn FrameChomper> {
+        Box::new(self.build(sink))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use pcap_parser::Linktype;
+
+    use super::*;
+    use crate::{
+        icmp::{self, side_data::IcmpError, IcmpErrorKind},
+        test_support::{ipv4_frame, udp_frame, Received, TestListener},
+        udp_flow::side_data::{QuicMigrated, UdpFlowEnded},
+    };
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn at(seconds: u64) -> TimingInfo {
+        TimingInfo {
+            received_on_wire: seconds * SECOND,
+            ..Default::default()
+        }
+    }
+
+    fn side_data<T: SideData + 'static>(received: &[Received<HTTPStreamEvent>]) -> Vec<&T> {
+        received
+            .iter()
+            .filter_map(|r| match r {
+                Received::SideData(sd) => sd.downcast_ref::<T>(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn chomper(
+        received: Arc<RwLock<Vec<Received<HTTPStreamEvent>>>>,
+    ) -> EthernetChomper<ListenerDispatcher> {
+        Pipeline::new()
+            .options(DecodeOptions {
+                udp_idle_timeout: 10 * SECOND,
+                ..Default::default()
+            })
+            .tls(Default::default())
+            .http()
+            .build(TestListener { received })
+    }
+
+    #[test]
+    fn test_udp_flow_ended() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = chomper(received.clone());
+
+        chomper
+            .chomp(
+                at(0),
+                Linktype::ETHERNET,
+                &udp_frame(false, 5353, 53, b"query"),
+            )
+            .unwrap();
+        chomper
+            .chomp(
+                at(1),
+                Linktype::ETHERNET,
+                &udp_frame(true, 53, 5353, b"answer"),
+            )
+            .unwrap();
+        chomper.advance_time(60 * SECOND);
+
+        let received = received.read().unwrap();
+        let ended = side_data::<UdpFlowEnded>(&received);
+        assert_eq!(ended.len(), 1);
+        let ended = ended[0];
+        assert_eq!(ended.target.client_port(), 5353);
+        assert_eq!(ended.target.server_port(), 53);
+        assert_eq!(ended.stats.datagrams_to_server, 1);
+        assert_eq!(ended.stats.datagrams_to_client, 1);
+        assert_eq!(ended.stats.bytes_to_client, 6);
+    }
+
+    #[test]
+    fn test_udp_port_unreachable() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = chomper(received.clone());
+
+        let query = udp_frame(false, 5353, 53, b"query");
+        chomper.chomp(at(0), Linktype::ETHERNET, &query).unwrap();
+        // Destination unreachable, port unreachable, quoting the IP header
+        // and UDP header of the query
+        let mut unreachable = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        unreachable.extend(&query[14..14 + 28]);
+        let reply = ipv4_frame(true, icmp::IPPROTO_ICMP, &unreachable);
+        chomper.chomp(at(0), Linktype::ETHERNET, &reply).unwrap();
+
+        let received = received.read().unwrap();
+        let errors = side_data::<IcmpError>(&received);
+        assert_eq!(errors.len(), 1);
+        let error = errors[0];
+        assert_eq!(error.kind, IcmpErrorKind::PortUnreachable);
+        assert_eq!(error.target.client_port(), 5353);
+        assert_eq!(error.target.server_port(), 53);
+        assert!(!error.to_client);
+        assert_eq!(error.reporter, IpAddr::from([10, 0, 0, 2]));
+    }
+
+    #[test]
+    fn test_quic_migration() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = chomper(received.clone());
+
+        let initial = b"\xc0\x00\x00\x00\x01\x08randomid\x06client";
+        let handshake = b"\xc0\x00\x00\x00\x01\x06client\x06server";
+        for (time, frame) in [
+            (0, udp_frame(false, 40000, 443, initial)),
+            (0, udp_frame(true, 443, 40000, handshake)),
+            // The client's NAT picks a new port
+            (1, udp_frame(false, 50000, 443, b"\x41serverdata")),
+        ] {
+            chomper.chomp(at(time), Linktype::ETHERNET, &frame).unwrap();
+        }
+
+        let received = received.read().unwrap();
+        let migrations = side_data::<QuicMigrated>(&received);
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(migrations[0].target.client_port(), 40000);
+        assert_eq!(migrations[0].path.client_port(), 50000);
+    }
+}
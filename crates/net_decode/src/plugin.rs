@@ -0,0 +1,364 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Decoders for protocols net_decode does not know about, provided by other
+//! crates.
+//!
+//! A [`DecoderPlugin`] looks at the first bytes of a reassembled flow to
+//! tell whether it is its protocol, and if it is, makes a [`StreamDecoder`]
+//! which gets the rest of the flow and turns it into [`Record`]s. Plugins
+//! are put in a [`PluginRegistry`], which goes in the decoding stack as a
+//! listener for reassembled bytes, for example with
+//! [`Pipeline::plugins`](crate::pipeline::Pipeline::plugins).
+//!
+//! Decoders get the bytes as they come off the wire, split up however they
+//! happened to be, so they have to keep any partial message until the rest
+//! of it arrives.
+
+use std::{collections::HashMap, fmt};
+
+use bytes::Bytes;
+
+use crate::{
+    chomp::IPTarget,
+    listener::{Listener, SideData, TimingInfo},
+    tcp_reassemble::side_data::ConnectionClosed,
+    udp_flow::side_data::UdpFlowEnded,
+    Error,
+};
+
+/// Bumped whenever [`DecoderPlugin`] or [`StreamDecoder`] change in a way
+/// that plugins built against an older version would get wrong.
+pub const PLUGIN_API_VERSION: u32 = 1;
+
+/// How many bytes of a flow are held while plugins make up their minds.
+pub const MAX_RECOGNIZE_BYTES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recognition {
+    Yes,
+    No,
+    /// Ask again once there are more bytes.
+    NeedMoreData,
+}
+
+pub trait DecoderPlugin: Send + Sync {
+    /// Short lowercase name of the protocol, such as `kafka`.
+    fn name(&self) -> &'static str;
+
+    /// The [`PLUGIN_API_VERSION`] the plugin was built against.
+    fn api_version(&self) -> u32 {
+        PLUGIN_API_VERSION
+    }
+
+    /// Looks at what has been seen of a flow so far, in each direction.
+    fn recognize(&self, target: &IPTarget, to_server: &[u8], to_client: &[u8]) -> Recognition;
+
+    fn new_decoder(&self, target: &IPTarget) -> Box<dyn StreamDecoder>;
+}
+
+pub trait StreamDecoder: Send + Sync {
+    /// Takes the next bytes going one way, adding any records they finish
+    /// to `out`.
+    fn decode(&mut self, to_client: bool, data: &[u8], out: &mut Vec<Record>);
+
+    /// Called once the flow is over, for any last records.
+    fn finish(&mut self, _out: &mut Vec<Record>) {}
+}
+
+/// One decoded message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Record {
+    /// What sort of message it is, such as `ProduceRequest`.
+    pub kind: String,
+    pub fields: Vec<(String, String)>,
+}
+
+/// A [`Record`] along with where it came from, as passed on by the
+/// [`PluginRegistry`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginRecord {
+    pub plugin: &'static str,
+    pub record: Record,
+}
+
+enum Verdict {
+    Undecided,
+    Plugin(usize),
+    Unrecognized,
+}
+
+enum FlowState {
+    Recognizing {
+        /// Everything seen so far, in order.
+        chunks: Vec<(bool, Bytes)>,
+    },
+    Decoding {
+        plugin: &'static str,
+        decoder: Box<dyn StreamDecoder>,
+    },
+    Unrecognized,
+}
+
+impl FlowState {
+    fn seen(chunks: &[(bool, Bytes)], to_client: bool) -> Vec<u8> {
+        chunks
+            .iter()
+            .filter(|(dir, _)| *dir == to_client)
+            .flat_map(|(_, data)| data.iter().copied())
+            .collect()
+    }
+}
+
+/// Passes flows to the first plugin to recognize them, and their records
+/// to `L`.
+pub struct PluginRegistry<L> {
+    plugins: Vec<Box<dyn DecoderPlugin>>,
+    flows: HashMap<IPTarget, FlowState>,
+    next: L,
+}
+
+impl<L> fmt::Debug for PluginRegistry<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PluginRegistry")
+            .field(
+                "plugins",
+                &self.plugins.iter().map(|p| p.name()).collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl<L: Listener<PluginRecord>> PluginRegistry<L> {
+    pub fn new(next: L) -> Self {
+        PluginRegistry {
+            plugins: Vec::new(),
+            flows: HashMap::new(),
+            next,
+        }
+    }
+
+    /// Adds a plugin, to be tried after those already added.
+    pub fn register(&mut self, plugin: Box<dyn DecoderPlugin>) -> Result<(), Error> {
+        if plugin.api_version() != PLUGIN_API_VERSION {
+            return Err(format!(
+                "plugin {} is for plugin API version {}, but this is version {}",
+                plugin.name(),
+                plugin.api_version(),
+                PLUGIN_API_VERSION
+            )
+            .into());
+        }
+        tracing::debug!("registered decoder plugin {}", plugin.name());
+        self.plugins.push(plugin);
+        Ok(())
+    }
+
+    pub fn with_plugin(mut self, plugin: Box<dyn DecoderPlugin>) -> Result<Self, Error> {
+        self.register(plugin)?;
+        Ok(self)
+    }
+
+    fn emit(
+        &mut self,
+        timing: &TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        plugin: &'static str,
+        records: Vec<Record>,
+    ) {
+        for record in records {
+            self.next.on_data(
+                timing.clone(),
+                target,
+                to_client,
+                PluginRecord { plugin, record },
+            );
+        }
+    }
+
+    /// Asks the plugins about a flow. If some are still unsure once there
+    /// are [`MAX_RECOGNIZE_BYTES`], it is given up on.
+    fn recognize(&self, target: &IPTarget, chunks: &[(bool, Bytes)]) -> Verdict {
+        let to_server = FlowState::seen(chunks, false);
+        let to_client = FlowState::seen(chunks, true);
+        let mut undecided = false;
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            match plugin.recognize(target, &to_server, &to_client) {
+                Recognition::Yes => return Verdict::Plugin(i),
+                Recognition::No => {}
+                Recognition::NeedMoreData => undecided = true,
+            }
+        }
+        if undecided && to_server.len() + to_client.len() < MAX_RECOGNIZE_BYTES {
+            Verdict::Undecided
+        } else {
+            Verdict::Unrecognized
+        }
+    }
+
+    fn end_flow(&mut self, timing: &TimingInfo, target: IPTarget) {
+        if let Some(FlowState::Decoding {
+            plugin,
+            mut decoder,
+        }) = self.flows.remove(&target)
+        {
+            let mut out = Vec::new();
+            decoder.finish(&mut out);
+            self.emit(timing, target, false, plugin, out);
+        }
+    }
+}
+
+impl<L: Listener<PluginRecord>> Listener<Bytes> for PluginRegistry<L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: Bytes) {
+        let state = self
+            .flows
+            .remove(&target)
+            .unwrap_or(FlowState::Recognizing { chunks: Vec::new() });
+        let state = match state {
+            FlowState::Recognizing { mut chunks } => {
+                chunks.push((to_client, data));
+                match self.recognize(&target, &chunks) {
+                    Verdict::Undecided => FlowState::Recognizing { chunks },
+                    Verdict::Plugin(i) => {
+                        let plugin = self.plugins[i].name();
+                        tracing::debug!(?target, "decoding flow with plugin {plugin}");
+                        let mut decoder = self.plugins[i].new_decoder(&target);
+                        for (to_client, data) in chunks {
+                            let mut out = Vec::new();
+                            decoder.decode(to_client, &data, &mut out);
+                            self.emit(&timing, target, to_client, plugin, out);
+                        }
+                        FlowState::Decoding { plugin, decoder }
+                    }
+                    Verdict::Unrecognized => FlowState::Unrecognized,
+                }
+            }
+            FlowState::Decoding {
+                plugin,
+                mut decoder,
+            } => {
+                let mut out = Vec::new();
+                decoder.decode(to_client, &data, &mut out);
+                self.emit(&timing, target, to_client, plugin, out);
+                FlowState::Decoding { plugin, decoder }
+            }
+            FlowState::Unrecognized => FlowState::Unrecognized,
+        };
+        self.flows.insert(target, state);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        if let Some(closed) = data.downcast_ref::<ConnectionClosed>() {
+            self.end_flow(&closed.timing, closed.target);
+        } else if let Some(ended) = data.downcast_ref::<UdpFlowEnded>() {
+            self.end_flow(&ended.timing, ended.target);
+        }
+        self.next.on_side_data(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::test_support::{Received, TestListener};
+
+    /// Newline separated `PING`s and `PONG`s.
+    struct PingPlugin;
+
+    struct PingDecoder {
+        partial: [Vec<u8>; 2],
+    }
+
+    impl DecoderPlugin for PingPlugin {
+        fn name(&self) -> &'static str {
+            "ping"
+        }
+
+        fn recognize(&self, _target: &IPTarget, to_server: &[u8], _: &[u8]) -> Recognition {
+            match to_server.len() {
+                0..=3 if b"PING".starts_with(to_server) => Recognition::NeedMoreData,
+                _ if to_server.starts_with(b"PING") => Recognition::Yes,
+                _ => Recognition::No,
+            }
+        }
+
+        fn new_decoder(&self, _target: &IPTarget) -> Box<dyn StreamDecoder> {
+            Box::new(PingDecoder {
+                partial: Default::default(),
+            })
+        }
+    }
+
+    impl StreamDecoder for PingDecoder {
+        fn decode(&mut self, to_client: bool, data: &[u8], out: &mut Vec<Record>) {
+            let partial = &mut self.partial[to_client as usize];
+            partial.extend_from_slice(data);
+            while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = partial.drain(..=end).collect();
+                out.push(Record {
+                    kind: String::from_utf8_lossy(&line[..end]).into_owned(),
+                    fields: Vec::new(),
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn test_plugin_registry() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut registry = PluginRegistry::new(TestListener {
+            received: received.clone(),
+        })
+        .with_plugin(Box::new(PingPlugin))
+        .unwrap();
+        let target = |client_port| IPTarget::V4 {
+            client_port,
+            server_port: 7,
+            client_ip: [10, 0, 0, 1].into(),
+            server_ip: [10, 0, 0, 2].into(),
+            overlay: None,
+        };
+
+        for (port, to_client, data) in [
+            (40000, false, &b"PI"[..]),
+            (40001, false, b"GET / HTTP/1.1\r\n"),
+            (40000, false, b"NG\nPI"),
+            (40000, true, b"PONG\n"),
+            (40001, false, b"PING\n"),
+            (40000, false, b"NG\n"),
+        ] {
+            registry.on_data(
+                TimingInfo::default(),
+                target(port),
+                to_client,
+                Bytes::from_static(data),
+            );
+        }
+
+        let received = received.read().unwrap();
+        let records: Vec<_> = received
+            .iter()
+            .map(|r| match r {
+                Received::Message(meta, data) => (
+                    meta.target.client_addr().port(),
+                    data.plugin,
+                    data.record.kind.as_str(),
+                ),
+                Received::SideData(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (40000, "ping", "PING"),
+                (40000, "ping", "PONG"),
+                (40000, "ping", "PING"),
+            ]
+        );
+    }
+}
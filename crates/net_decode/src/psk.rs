@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Deriving connection secrets from external pre-shared keys, for TLS-PSK
+//! connections where no key log is available.
+//!
+//! In TLS 1.3 this is only possible in `psk_ke` mode: with `psk_dhe_ke`,
+//! the handshake also mixes in an (EC)DHE shared secret we cannot know.
+
+use ring::{digest, hkdf};
+
+use crate::key_db::Secret;
+
+/// The TLS 1.2 pre-master secret for plain PSK key exchange (RFC 4279
+/// section 2): as many zeros as the key is long, then the key, each with a
+/// 16-bit length.
+pub(crate) fn tls12_premaster(psk: &[u8]) -> Vec<u8> {
+    let len = (psk.len() as u16).to_be_bytes();
+    let mut premaster = Vec::with_capacity(4 + 2 * psk.len());
+    premaster.extend_from_slice(&len);
+    premaster.resize(2 + psk.len(), 0);
+    premaster.extend_from_slice(&len);
+    premaster.extend_from_slice(psk);
+    premaster
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// The TLS 1.3 key schedule (RFC 8446 section 7.1), as far as it is needed
+/// to get traffic secrets.
+pub(crate) struct Tls13KeySchedule {
+    hash: &'static digest::Algorithm,
+    hkdf: hkdf::Algorithm,
+    current: hkdf::Prk,
+}
+
+impl Tls13KeySchedule {
+    /// Starts at the early secret. `None` if the hash is not one TLS 1.3
+    /// uses.
+    pub(crate) fn from_psk(hash: &'static digest::Algorithm, psk: &[u8]) -> Option<Self> {
+        let hkdf = if hash == &digest::SHA256 {
+            hkdf::HKDF_SHA256
+        } else if hash == &digest::SHA384 {
+            hkdf::HKDF_SHA384
+        } else {
+            return None;
+        };
+        let zeros = vec![0u8; hash.output_len];
+        Some(Tls13KeySchedule {
+            hash,
+            hkdf,
+            current: hkdf::Salt::new(hkdf, &zeros).extract(psk),
+        })
+    }
+
+    /// Moves on to the next stage, mixing in `ikm`, or zeros if there is
+    /// none (which is always the case for the master secret, and for the
+    /// handshake secret in `psk_ke` mode).
+    pub(crate) fn input_secret(&mut self, ikm: Option<&[u8]>) {
+        let zeros = vec![0u8; self.hash.output_len];
+        let empty_hash = digest::digest(self.hash, &[]);
+        let derived = self.expand_label(b"derived", empty_hash.as_ref());
+        self.current = hkdf::Salt::new(self.hkdf, &derived).extract(ikm.unwrap_or(&zeros));
+    }
+
+    pub(crate) fn transcript_hash(&self, transcript: &[u8]) -> Vec<u8> {
+        digest::digest(self.hash, transcript).as_ref().to_vec()
+    }
+
+    /// Derive-Secret, e.g. with `c hs traffic` for the client handshake
+    /// traffic secret.
+    pub(crate) fn derive(&self, label: &[u8], transcript_hash: &[u8]) -> Secret {
+        Secret(self.expand_label(label, transcript_hash))
+    }
+
+    fn expand_label(&self, label: &[u8], context: &[u8]) -> Vec<u8> {
+        let len = self.hash.output_len;
+        let out_len = (len as u16).to_be_bytes();
+        let label_len = [(b"tls13 ".len() + label.len()) as u8];
+        let context_len = [context.len() as u8];
+        let info = [
+            &out_len[..],
+            &label_len[..],
+            b"tls13 ",
+            label,
+            &context_len[..],
+            context,
+        ];
+
+        let mut out = vec![0u8; len];
+        self.current
+            .expand(&info, Len(len))
+            .and_then(|okm| okm.fill(&mut out))
+            .expect("HKDF output is one hash long");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tls12_premaster() {
+        assert_eq!(
+            tls12_premaster(&[0xaa, 0xbb]),
+            vec![0, 2, 0, 0, 0, 2, 0xaa, 0xbb]
+        );
+    }
+
+    /// RFC 8448 section 3, which has no PSK, so the early secret comes from
+    /// zeros.
+    #[test]
+    fn test_tls13_key_schedule() {
+        let hex = |s| hex::decode(s).unwrap();
+
+        let mut ks = Tls13KeySchedule::from_psk(&digest::SHA256, &[0; 32]).unwrap();
+        ks.input_secret(Some(&hex(
+            "8bd4054fb55b9d63fdfbacf9f04b9f0d35e6d63f537563efd46272900f89492d",
+        )));
+        let secret = ks.derive(
+            b"c hs traffic",
+            &hex("860c06edc07858ee8e78f0e7428c58edd6b43f2ca3e6e95f02ed063cf0e1cad8"),
+        );
+        assert_eq!(
+            secret,
+            Secret(hex(
+                "b3eddb126e067f35a780b3abf45e2d8f3b1a950738f52e9600746a0e27a55a21"
+            ))
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Just enough of QUIC (RFC 9000) headers to follow a connection by its
+//! connection IDs when it moves to a new address, as it does when a phone
+//! goes from Wi-Fi to cellular or a NAT rebinds its port.
+//!
+//! Connection IDs are chosen by the end receiving packets with them, and
+//! the ones in use are learnt from the source connection IDs of handshake
+//! packets. IDs issued later in NEW_CONNECTION_ID frames are encrypted, so
+//! a migration which switches to one of those is not followed and shows up
+//! as a new flow.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::chomp::IPTarget;
+
+const HEADER_FORM_LONG: u8 = 0x80;
+const FIXED_BIT: u8 = 0x40;
+/// RFC 9000 section 17.2.
+const MAX_CID_LEN: usize = 20;
+/// Shorter ones are too likely to match by chance, and zero length ones
+/// cannot be followed anyway.
+const MIN_CID_LEN: usize = 4;
+/// How many connection IDs to remember per connection.
+const MAX_CIDS_PER_FLOW: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum QuicHeader<'a> {
+    Long {
+        version: u32,
+        dcid: &'a [u8],
+        scid: &'a [u8],
+    },
+    /// The destination connection ID is not delimited, so this is all of
+    /// the packet after the first byte.
+    Short { rest: &'a [u8] },
+}
+
+/// The header of the first QUIC packet in a datagram, if it looks like one.
+pub(crate) fn parse_header(data: &[u8]) -> Option<QuicHeader<'_>> {
+    let first = *data.first()?;
+    if first & FIXED_BIT == 0 {
+        return None;
+    }
+    if first & HEADER_FORM_LONG == 0 {
+        return Some(QuicHeader::Short { rest: &data[1..] });
+    }
+    let version = u32::from_be_bytes(data.get(1..5)?.try_into().unwrap());
+    let dcid_len = *data.get(5)? as usize;
+    let dcid = data.get(6..6 + dcid_len)?;
+    let scid_len = *data.get(6 + dcid_len)? as usize;
+    let scid = data.get(7 + dcid_len..7 + dcid_len + scid_len)?;
+    if dcid_len > MAX_CID_LEN || scid_len > MAX_CID_LEN {
+        return None;
+    }
+    Some(QuicHeader::Long {
+        version,
+        dcid,
+        scid,
+    })
+}
+
+/// Which UDP flow each connection ID we know of belongs to.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionIds {
+    /// The flow, and whether the ID was chosen by its client.
+    by_cid: HashMap<Vec<u8>, (IPTarget, bool)>,
+    by_flow: HashMap<IPTarget, Vec<Vec<u8>>>,
+    /// Lengths of the IDs in `by_cid`, to try on short headers.
+    lens: BTreeSet<usize>,
+}
+
+impl ConnectionIds {
+    /// Takes a datagram of the flow `target`, learning the connection ID
+    /// its sender chose if it has a long header.
+    pub(crate) fn learn(&mut self, target: IPTarget, from_client: bool, payload: &[u8]) {
+        let Some(QuicHeader::Long { version, scid, .. }) = parse_header(payload) else {
+            return;
+        };
+        // Version negotiation packets echo the client's IDs back.
+        if version == 0 || scid.len() < MIN_CID_LEN || self.by_cid.contains_key(scid) {
+            return;
+        }
+        let cids = self.by_flow.entry(target).or_default();
+        if cids.len() >= MAX_CIDS_PER_FLOW {
+            return;
+        }
+        cids.push(scid.to_vec());
+        self.lens.insert(scid.len());
+        self.by_cid.insert(scid.to_vec(), (target, from_client));
+    }
+
+    /// Finds the flow of a short header datagram seen on an address we do
+    /// not know. Returns the flow and whether the datagram is going to its
+    /// client.
+    pub(crate) fn find(&self, payload: &[u8]) -> Option<(IPTarget, bool)> {
+        let Some(QuicHeader::Short { rest }) = parse_header(payload) else {
+            return None;
+        };
+        self.lens
+            .iter()
+            .find_map(|&len| self.by_cid.get(rest.get(..len)?))
+            .copied()
+    }
+
+    pub(crate) fn forget(&mut self, target: &IPTarget) {
+        for cid in self.by_flow.remove(target).unwrap_or_default() {
+            self.by_cid.remove(&cid);
+        }
+        // Dropping lengths nobody uses any more is not worth a scan.
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn long(dcid: &[u8], scid: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xc0, 0, 0, 0, 1, dcid.len() as u8];
+        packet.extend(dcid);
+        packet.push(scid.len() as u8);
+        packet.extend(scid);
+        packet.extend([0; 16]);
+        packet
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header(&long(b"abcd", b"wxyz")),
+            Some(QuicHeader::Long {
+                version: 1,
+                dcid: b"abcd",
+                scid: b"wxyz",
+            })
+        );
+        assert_eq!(
+            parse_header(&[0x41, 1, 2]),
+            Some(QuicHeader::Short { rest: &[1, 2] })
+        );
+        // No fixed bit, so probably not QUIC at all
+        assert_eq!(parse_header(&[0x80, 0, 0, 0, 1, 0, 0]), None);
+        assert_eq!(parse_header(&long(b"abcd", b"wxyz")[..8]), None);
+    }
+
+    #[test]
+    fn test_connection_ids() {
+        let target = IPTarget::from_ports(
+            &crate::chomp::IPHeader::V4(
+                pktparse::ipv4::parse_ipv4_header(&[
+                    0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+                ])
+                .unwrap()
+                .1,
+            ),
+            40000,
+            443,
+        );
+        let mut cids = ConnectionIds::default();
+        cids.learn(target, true, &long(b"random", b"client-cid"));
+        cids.learn(target, false, &long(b"client-cid", b"server"));
+
+        let mut to_server = vec![0x41];
+        to_server.extend(b"server");
+        to_server.extend([0; 20]);
+        assert_eq!(cids.find(&to_server), Some((target, false)));
+        let mut to_client = vec![0x41];
+        to_client.extend(b"client-cid");
+        assert_eq!(cids.find(&to_client), Some((target, true)));
+        assert_eq!(cids.find(b"\x41unknown-id"), None);
+
+        cids.forget(&target);
+        assert_eq!(cids.find(&to_server), None);
+    }
+}
@@ -0,0 +1,263 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Running a recorded capture through a whole decoding stack and collecting
+//! everything that comes out of it, for regression tests of decoders.
+//!
+//! ```ignore
+//! let recording = Replay::new(Pipeline::new().tls(key_db).http())
+//!     .run(include_bytes!("../corpus/http2-conn-reuse.pcapng"))?;
+//! expect_file!["./test_output/h2"].assert_eq(&recording.to_string());
+//! ```
+//!
+//! Time is virtual: the capture's timestamps are moved to start at
+//! [`Replay::starting_at`], and once the packets run out the clock is moved
+//! on until every flow has ended, so what comes out depends on nothing but
+//! the capture.
+
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    chomp::{self, EthernetChomper, FrameChomper, IPTarget},
+    dispatch::ListenerDispatcher,
+    http::HTTPStreamEvent,
+    key_db::{ClientRandom, Secret, SecretType},
+    link::Linktype,
+    listener::{Listener, MessageMeta, Nanos, SideData, TimingInfo},
+    pipeline::Pipeline,
+    Error,
+};
+
+/// Something that came out of the decoding stack.
+pub enum Recorded {
+    Message(MessageMeta, HTTPStreamEvent),
+    SideData(Box<dyn SideData>),
+}
+
+impl fmt::Display for Recorded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recorded::Message(meta, event) => {
+                let direction = if meta.to_client { "<-" } else { "->" };
+                writeln!(
+                    f,
+                    "Message at {} {:?} {direction}:",
+                    meta.timing.received_on_wire, meta.target
+                )?;
+                write!(f, "{event:?}")?;
+                if let HTTPStreamEvent::ReqBodyChunk(_, data)
+                | HTTPStreamEvent::RespBodyChunk(_, data) = event
+                {
+                    write!(f, "\n{}", hexdump::HexDumper::new(data))?;
+                }
+                Ok(())
+            }
+            Recorded::SideData(data) => write!(f, "Side data:\n{data:?}"),
+        }
+    }
+}
+
+/// Everything that came out of a [`Replay`], in order.
+pub struct Recording {
+    pub events: Vec<Recorded>,
+}
+
+impl Recording {
+    pub fn messages(&self) -> impl Iterator<Item = (&MessageMeta, &HTTPStreamEvent)> {
+        self.events.iter().filter_map(|e| match e {
+            Recorded::Message(meta, event) => Some((meta, event)),
+            Recorded::SideData(_) => None,
+        })
+    }
+
+    /// All the side data of type `T`.
+    pub fn side_data<T: Clone + 'static>(&self) -> Vec<T> {
+        self.events
+            .iter()
+            .filter_map(|e| match e {
+                Recorded::SideData(data) => data.downcast_ref::<T>().cloned(),
+                Recorded::Message(..) => None,
+            })
+            .collect()
+    }
+}
+
+/// For comparing against a file with `expect_test`.
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{event}\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<Recorded>>>);
+
+impl Listener<HTTPStreamEvent> for Recorder {
+    fn on_data(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        data: HTTPStreamEvent,
+    ) {
+        self.0.lock().unwrap().push(Recorded::Message(
+            MessageMeta {
+                timing,
+                target,
+                to_client,
+            },
+            data,
+        ));
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        let mut events = self.0.lock().unwrap();
+        // Side data reaches the sink once per listener it went through on
+        // the way, so leave out the copies.
+        if let Some(Recorded::SideData(last)) = events.last() {
+            if format!("{last:?}") == format!("{data:?}") {
+                return;
+            }
+        }
+        events.push(Recorded::SideData(data));
+    }
+}
+
+/// Moves packet times to start from `start`.
+struct VirtualClock<'a> {
+    inner: &'a mut EthernetChomper<ListenerDispatcher>,
+    start: Nanos,
+    first: Option<Nanos>,
+    now: Nanos,
+}
+
+impl FrameChomper for VirtualClock<'_> {
+    fn chomp(
+        &mut self,
+        mut timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        let first = *self.first.get_or_insert(timing.received_on_wire);
+        timing.received_on_wire = self.start + timing.received_on_wire.saturating_sub(first);
+        self.now = timing.received_on_wire;
+        self.inner.chomp(timing, link_type, packet)
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        self.inner.on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.inner.on_wireguard_keys(key_log)
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.inner.on_key(client_random, secret_type, secret)
+    }
+}
+
+/// Runs a capture through a [`Pipeline`]. See the [module docs](self).
+pub struct Replay {
+    pipeline: Pipeline,
+    start: Nanos,
+    end_flows: bool,
+}
+
+impl Replay {
+    pub fn new(pipeline: Pipeline) -> Self {
+        Replay {
+            pipeline,
+            start: 0,
+            end_flows: true,
+        }
+    }
+
+    /// Where the virtual clock starts, 0 by default.
+    pub fn starting_at(mut self, start: Nanos) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Whether to end the flows still open after the last packet, which
+    /// is the default.
+    pub fn end_flows(mut self, end_flows: bool) -> Self {
+        self.end_flows = end_flows;
+        self
+    }
+
+    /// Runs the pcap or pcapng file `capture` through.
+    pub fn run(self, capture: &[u8]) -> Result<Recording, Error> {
+        let recorder = Recorder::default();
+        let mut chomper = self.pipeline.build(recorder.clone());
+        let mut clock = VirtualClock {
+            inner: &mut chomper,
+            start: self.start,
+            first: None,
+            now: self.start,
+        };
+        chomp::dump_pcap(io::Cursor::new(capture), &mut clock)?;
+        if self.end_flows {
+            tracing::debug!("capture ended at {}, ending open flows", clock.now);
+            chomper.advance_time(Nanos::MAX);
+        }
+
+        let events = std::mem::take(&mut *recorder.0.lock().unwrap());
+        Ok(Recording { events })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        tcp_reassemble::side_data::FlowEvicted,
+        test_support::{H1_UNENCRYPTED, H2},
+    };
+
+    #[test]
+    fn test_replay_is_deterministic() {
+        let run = |start| {
+            Replay::new(Pipeline::new().tls(Default::default()).http())
+                .starting_at(start)
+                .run(H2)
+                .unwrap()
+        };
+        let recording = run(0);
+        assert!(recording.messages().count() > 0);
+        assert_eq!(recording.to_string(), run(0).to_string());
+
+        // Only the times move along with the start
+        let later = run(1_000_000_000);
+        let times = |r: &Recording| -> Vec<Nanos> {
+            r.messages()
+                .map(|(meta, _)| meta.timing.received_on_wire)
+                .collect()
+        };
+        let moved: Vec<Nanos> = times(&recording)
+            .into_iter()
+            .map(|t| t + 1_000_000_000)
+            .collect();
+        assert_eq!(times(&later), moved);
+    }
+
+    #[test]
+    fn test_replay_ends_flows() {
+        let pipeline = || Pipeline::new().http();
+        let ended = Replay::new(pipeline()).run(H1_UNENCRYPTED).unwrap();
+        let open = Replay::new(pipeline())
+            .end_flows(false)
+            .run(H1_UNENCRYPTED)
+            .unwrap();
+        // Everything goes once the clock has moved on far enough
+        assert!(ended.side_data::<FlowEvicted>().len() > open.side_data::<FlowEvicted>().len());
+        assert_eq!(ended.messages().count(), open.messages().count(),);
+    }
+}
@@ -0,0 +1,246 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Decoding only part of the traffic, for captures too busy to keep up with
+//! in full.
+//!
+//! Choices are made from the flow's addresses and ports alone, so the same
+//! flows are picked every time a capture is decoded, and a flow is either
+//! seen whole or not at all.
+
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    chomp::IPTarget,
+    http::{HTTPStreamEvent, RequestId},
+    listener::{Listener, SideData, TimingInfo},
+};
+
+/// FNV-1a, rather than the standard library's hasher, which may change
+/// between Rust releases and so pick different flows.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Passes on the messages of one in every `n` flows. Side data is always
+/// passed on.
+pub struct FlowSampler<T, L> {
+    one_in: u64,
+    seed: u64,
+    skipped: u64,
+    next: L,
+    _phantom: PhantomData<fn(T)>,
+}
+
+impl<T, L: Listener<T>> FlowSampler<T, L> {
+    /// `one_in` of 0 or 1 passes everything on.
+    pub fn new(one_in: u32, next: L) -> Self {
+        FlowSampler {
+            one_in: one_in.max(1) as u64,
+            seed: 0,
+            skipped: 0,
+            next,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Picks a different set of flows than other seeds would.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn is_sampled(&self, target: &IPTarget) -> bool {
+        if self.one_in == 1 {
+            return true;
+        }
+        let mut hasher = Fnv(0xcbf29ce484222325);
+        self.seed.hash(&mut hasher);
+        target.hash(&mut hasher);
+        hasher.finish() % self.one_in == 0
+    }
+
+    /// How many messages were not passed on.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+impl<T, L: Listener<T>> Listener<T> for FlowSampler<T, L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        if self.is_sampled(&target) {
+            self.next.on_data(timing, target, to_client, data);
+        } else {
+            self.skipped += 1;
+        }
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.next.on_side_data(data);
+    }
+}
+
+/// Goes after the HTTP decoder and passes on only the first `max_bytes` of
+/// each request and response body. The finished events still have the full
+/// lengths.
+pub struct BodySampler<L> {
+    max_bytes: usize,
+    /// Bytes passed on so far, by flow, request and whether it is the
+    /// response.
+    seen: HashMap<(IPTarget, RequestId, bool), usize>,
+    next: L,
+}
+
+impl<L: Listener<HTTPStreamEvent>> BodySampler<L> {
+    pub fn new(max_bytes: usize, next: L) -> Self {
+        BodySampler {
+            max_bytes,
+            seen: HashMap::new(),
+            next,
+        }
+    }
+
+    /// Cuts `chunk` down to what is left of the allowance, or returns
+    /// `None` if nothing is.
+    fn truncate(&mut self, key: (IPTarget, RequestId, bool), mut chunk: Bytes) -> Option<Bytes> {
+        let seen = self.seen.entry(key).or_default();
+        let left = self.max_bytes.saturating_sub(*seen);
+        if left == 0 {
+            return None;
+        }
+        chunk.truncate(left);
+        *seen += chunk.len();
+        Some(chunk)
+    }
+}
+
+impl<L: Listener<HTTPStreamEvent>> Listener<HTTPStreamEvent> for BodySampler<L> {
+    fn on_data(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        to_client: bool,
+        data: HTTPStreamEvent,
+    ) {
+        let data = match data {
+            HTTPStreamEvent::ReqBodyChunk(id, chunk) => {
+                let Some(chunk) = self.truncate((target, id, false), chunk) else {
+                    return;
+                };
+                HTTPStreamEvent::ReqBodyChunk(id, chunk)
+            }
+            HTTPStreamEvent::RespBodyChunk(id, chunk) => {
+                let Some(chunk) = self.truncate((target, id, true), chunk) else {
+                    return;
+                };
+                HTTPStreamEvent::RespBodyChunk(id, chunk)
+            }
+            HTTPStreamEvent::RequestFinished(id, len) => {
+                self.seen.remove(&(target, id, false));
+                HTTPStreamEvent::RequestFinished(id, len)
+            }
+            HTTPStreamEvent::ResponseFinished(id, len) => {
+                self.seen.remove(&(target, id, true));
+                HTTPStreamEvent::ResponseFinished(id, len)
+            }
+            other => other,
+        };
+        self.next.on_data(timing, target, to_client, data);
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.next.on_side_data(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::{
+        chomp::IPHeader,
+        listener::NoOpListener,
+        test_support::{Received, TestListener},
+    };
+
+    fn target(client_port: u16) -> IPTarget {
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+        IPTarget::from_ports(&IPHeader::V4(header), client_port, 80)
+    }
+
+    #[test]
+    fn test_flow_sampler() {
+        let mut sampler = FlowSampler::<Vec<u8>, _>::new(4, NoOpListener {});
+        let sampled = (40000..41000)
+            .filter(|&port| sampler.is_sampled(&target(port)))
+            .count();
+        assert!((150..350).contains(&sampled), "{sampled}");
+
+        // The same flows every time, in both directions
+        let first = (40000..41000)
+            .find(|&port| sampler.is_sampled(&target(port)))
+            .unwrap();
+        assert!(FlowSampler::<Vec<u8>, _>::new(4, NoOpListener {}).is_sampled(&target(first)));
+        sampler.on_data(TimingInfo::default(), target(first), true, vec![1]);
+        sampler.on_data(TimingInfo::default(), target(first), false, vec![1]);
+        assert_eq!(sampler.skipped(), 0);
+
+        let everything = FlowSampler::<Vec<u8>, _>::new(1, NoOpListener {});
+        assert!((40000..40100).all(|port| everything.is_sampled(&target(port))));
+    }
+
+    #[test]
+    fn test_body_sampler() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut sampler = BodySampler::new(
+            5,
+            TestListener {
+                received: received.clone(),
+            },
+        );
+        let id = 1;
+        for event in [
+            HTTPStreamEvent::RespBodyChunk(id, Bytes::from_static(b"abc")),
+            HTTPStreamEvent::RespBodyChunk(id, Bytes::from_static(b"defg")),
+            HTTPStreamEvent::RespBodyChunk(id, Bytes::from_static(b"hij")),
+            HTTPStreamEvent::ResponseFinished(id, 10),
+        ] {
+            sampler.on_data(TimingInfo::default(), target(40000), true, event);
+        }
+
+        let received = received.read().unwrap();
+        let events: Vec<_> = received
+            .iter()
+            .map(|r| match r {
+                Received::Message(_, event) => format!("{event:?}"),
+                Received::SideData(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert!(events[1].contains("len: 2"), "{events:?}");
+        assert!(events[2].contains("ResponseFinished"), "{events:?}");
+        assert!(sampler.seen.is_empty());
+    }
+}
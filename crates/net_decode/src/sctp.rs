@@ -0,0 +1,472 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! SCTP (RFC 9260) directly over IP, as used by telephony signalling.
+//!
+//! An association carries several streams of messages. Each message is
+//! put back together from its DATA chunks and delivered on its own, with
+//! [`TimingInfo::stream`] saying which stream it was on. Messages are
+//! delivered in the order they were sent across all streams, which keeps
+//! each stream in order too.
+//!
+//! The client of an association is whoever sent the INIT, or failing that
+//! the first packet we saw.
+//!
+//! WebRTC data channels run SCTP inside DTLS, so they never reach here.
+//!
+//! FIXME: I-DATA chunks (RFC 8260), which interleave messages, are ignored.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::Wrapping,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    chomp::{IPHeader, IPTarget},
+    listener::{Listener, TimingInfo},
+    tcp_reassemble::seq_before,
+    tunnel,
+};
+
+pub mod side_data {
+    use crate::{chomp::IPTarget, listener::TimingInfo};
+
+    /// Fired by `net_decode::sctp` when an association is shut down or
+    /// aborted. Nothing more will be delivered for it.
+    #[derive(Clone, Debug)]
+    pub struct SctpAssociationClosed {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub aborted: bool,
+    }
+}
+
+use side_data::SctpAssociationClosed;
+
+pub const IPPROTO_SCTP: u8 = 132;
+
+const COMMON_HEADER_LEN: usize = 12;
+const CHUNK_HEADER_LEN: usize = 4;
+/// Chunk header, TSN, stream identifier, stream sequence number and payload
+/// protocol identifier.
+const DATA_HEADER_LEN: usize = 16;
+
+const CHUNK_DATA: u8 = 0;
+const CHUNK_INIT: u8 = 1;
+const CHUNK_INIT_ACK: u8 = 2;
+const CHUNK_ABORT: u8 = 6;
+const CHUNK_SHUTDOWN_COMPLETE: u8 = 14;
+/// RFC 3758, for giving up on messages sent with partial reliability.
+const CHUNK_FORWARD_TSN: u8 = 192;
+
+const DATA_END: u8 = 0x01;
+const DATA_BEGIN: u8 = 0x02;
+
+/// How many out of order chunks to hold per direction.
+const MAX_HELD_CHUNKS: usize = 1024;
+/// How big a message may get before we give up on it.
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// The chunks we need.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Chunk<'a> {
+    Data {
+        tsn: u32,
+        stream: u16,
+        begin: bool,
+        end: bool,
+        data: &'a [u8],
+    },
+    /// INIT or INIT ACK.
+    Init {
+        initial_tsn: u32,
+    },
+    ForwardTsn {
+        cumulative_tsn: u32,
+    },
+    Abort,
+    ShutdownComplete,
+}
+
+/// Splits the chunks of an SCTP packet after its common header, skipping
+/// any we do not need.
+pub(crate) fn parse_chunks(mut data: &[u8]) -> Vec<Chunk<'_>> {
+    let be16 = |b: &[u8], at: usize| u16::from_be_bytes([b[at], b[at + 1]]);
+    let be32 = |b: &[u8], at: usize| u32::from_be_bytes(b[at..at + 4].try_into().unwrap());
+
+    let mut chunks = Vec::new();
+    while data.len() >= CHUNK_HEADER_LEN {
+        let (kind, flags) = (data[0], data[1]);
+        let len = be16(data, 2) as usize;
+        let Some(chunk) = data.get(..len).filter(|_| len >= CHUNK_HEADER_LEN) else {
+            tracing::debug!("truncated sctp chunk");
+            break;
+        };
+        match kind {
+            CHUNK_DATA if len >= DATA_HEADER_LEN => chunks.push(Chunk::Data {
+                tsn: be32(chunk, 4),
+                stream: be16(chunk, 8),
+                begin: flags & DATA_BEGIN != 0,
+                end: flags & DATA_END != 0,
+                data: &chunk[DATA_HEADER_LEN..],
+            }),
+            CHUNK_INIT | CHUNK_INIT_ACK if len >= 20 => chunks.push(Chunk::Init {
+                initial_tsn: be32(chunk, 16),
+            }),
+            CHUNK_FORWARD_TSN if len >= 8 => chunks.push(Chunk::ForwardTsn {
+                cumulative_tsn: be32(chunk, 4),
+            }),
+            CHUNK_ABORT => chunks.push(Chunk::Abort),
+            CHUNK_SHUTDOWN_COMPLETE => chunks.push(Chunk::ShutdownComplete),
+            _ => {}
+        }
+        // Chunks are padded to 4 bytes, except maybe the last.
+        data = data.get((len + 3) & !3..).unwrap_or_default();
+    }
+    chunks
+}
+
+#[derive(Debug)]
+struct DataChunk {
+    stream: u16,
+    begin: bool,
+    end: bool,
+    data: Vec<u8>,
+}
+
+/// What one end of an association sent.
+#[derive(Debug, Default)]
+struct Direction {
+    /// TSN of the next chunk to put in order.
+    next_tsn: Option<Wrapping<u32>>,
+    /// Chunks that arrived ahead of `next_tsn`.
+    held: BTreeMap<u32, DataChunk>,
+    /// Messages being put together, by stream.
+    partial: HashMap<u16, Vec<u8>>,
+}
+
+impl Direction {
+    /// Takes a DATA chunk, and returns the messages it completes, by stream.
+    fn on_data(&mut self, tsn: u32, chunk: DataChunk) -> Vec<(u16, Vec<u8>)> {
+        let next = *self.next_tsn.get_or_insert(Wrapping(tsn));
+        if seq_before(Wrapping(tsn), next) {
+            // Retransmitted
+            return Vec::new();
+        }
+        self.held.entry(tsn).or_insert(chunk);
+        if self.held.len() > MAX_HELD_CHUNKS {
+            // Something went missing from the capture. Carry on from the
+            // earliest chunk we do have.
+            tracing::warn!("too many out of order sctp chunks, skipping ahead");
+            let earliest = self
+                .held
+                .keys()
+                .min_by_key(|&&t| Wrapping(t) - next)
+                .copied();
+            self.next_tsn = earliest.map(Wrapping);
+            self.partial.clear();
+        }
+        self.drain()
+    }
+
+    /// Gives up on everything up to `cumulative_tsn`.
+    fn forward(&mut self, cumulative_tsn: u32) -> Vec<(u16, Vec<u8>)> {
+        let new_next = Wrapping(cumulative_tsn) + Wrapping(1);
+        match self.next_tsn {
+            Some(next) if !seq_before(next, new_next) => return Vec::new(),
+            _ => {}
+        }
+        self.held
+            .retain(|&tsn, _| !seq_before(Wrapping(tsn), new_next));
+        self.next_tsn = Some(new_next);
+        self.partial.clear();
+        self.drain()
+    }
+
+    /// Puts together whatever is now in order.
+    fn drain(&mut self) -> Vec<(u16, Vec<u8>)> {
+        let mut messages = Vec::new();
+        let Some(mut next) = self.next_tsn else {
+            return messages;
+        };
+        while let Some(chunk) = self.held.remove(&next.0) {
+            next += Wrapping(1);
+            let message = if chunk.begin {
+                self.partial.insert(chunk.stream, chunk.data);
+                self.partial.get_mut(&chunk.stream).unwrap()
+            } else if let Some(message) = self.partial.get_mut(&chunk.stream) {
+                message.extend(chunk.data);
+                message
+            } else {
+                tracing::debug!("sctp chunk from the middle of a message we missed");
+                continue;
+            };
+            if message.len() > MAX_MESSAGE_LEN {
+                tracing::warn!("sctp message too big, dropping");
+                self.partial.remove(&chunk.stream);
+            } else if chunk.end {
+                let message = self.partial.remove(&chunk.stream).unwrap();
+                messages.push((chunk.stream, message));
+            }
+        }
+        self.next_tsn = Some(next);
+        messages
+    }
+}
+
+#[derive(Debug, Default)]
+struct Association {
+    /// Sent by the client and by the server.
+    directions: [Direction; 2],
+}
+
+#[derive(Debug, Default)]
+pub struct SctpFollower {
+    associations: HashMap<IPTarget, Association>,
+}
+
+impl SctpFollower {
+    pub fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        ip_header: &IPHeader,
+        data: &[u8],
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        let (Some(header), Some(chunks)) =
+            (data.get(..COMMON_HEADER_LEN), data.get(COMMON_HEADER_LEN..))
+        else {
+            tracing::debug!("ignored truncated sctp packet");
+            return;
+        };
+        let source_port = u16::from_be_bytes([header[0], header[1]]);
+        let dest_port = u16::from_be_bytes([header[2], header[3]]);
+        let chunks = parse_chunks(chunks);
+
+        let target = IPTarget::from_ports(ip_header, source_port, dest_port)
+            .on_overlay(tunnel::overlay_id(&timing.tunnels));
+        let (target, from_client) = if self.associations.contains_key(&target) {
+            (target, true)
+        } else if self.associations.contains_key(&target.flip()) {
+            (target.flip(), false)
+        } else {
+            tracing::debug!("new sctp association {target:?}");
+            (target, true)
+        };
+        let association = self.associations.entry(target).or_default();
+        let sender = if from_client { 0 } else { 1 };
+
+        let mut closed = None;
+        for chunk in chunks {
+            let messages = match chunk {
+                Chunk::Data {
+                    tsn,
+                    stream,
+                    begin,
+                    end,
+                    data,
+                } => association.directions[sender].on_data(
+                    tsn,
+                    DataChunk {
+                        stream,
+                        begin,
+                        end,
+                        data: data.to_vec(),
+                    },
+                ),
+                Chunk::Init { initial_tsn } => {
+                    association.directions[sender] = Direction {
+                        next_tsn: Some(Wrapping(initial_tsn)),
+                        ..Default::default()
+                    };
+                    continue;
+                }
+                Chunk::ForwardTsn { cumulative_tsn } => {
+                    // This comes from the sender of the data, telling the
+                    // receiver to stop waiting for what it gave up on.
+                    association.directions[sender].forward(cumulative_tsn)
+                }
+                Chunk::Abort => {
+                    closed = Some(true);
+                    break;
+                }
+                Chunk::ShutdownComplete => {
+                    closed = Some(false);
+                    break;
+                }
+            };
+            for (stream, message) in messages {
+                let timing = TimingInfo {
+                    stream: Some(stream as u32),
+                    ..timing.clone()
+                };
+                recv.on_data(timing, target, !from_client, Bytes::from(message));
+            }
+        }
+
+        if let Some(aborted) = closed {
+            tracing::debug!("sctp association {target:?} closed");
+            self.associations.remove(&target);
+            recv.on_side_data(Box::new(SctpAssociationClosed {
+                timing,
+                target,
+                aborted,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::test_support::{Received, SideDataListener, TestListener};
+
+    /// An IPv4 header from 10.0.0.1 to 10.0.0.2 or the other way around.
+    fn ip(reply: bool) -> IPHeader {
+        let (src, dst) = if reply { (2, 1) } else { (1, 2) };
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 132, 0, 0, 10, 0, 0, src, 10, 0, 0, dst,
+        ];
+        IPHeader::V4(pktparse::ipv4::parse_ipv4_header(&ip).unwrap().1)
+    }
+
+    fn packet(reply: bool, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let (src, dst): (u16, u16) = if reply { (2905, 40000) } else { (40000, 2905) };
+        let mut packet = Vec::new();
+        packet.extend(src.to_be_bytes());
+        packet.extend(dst.to_be_bytes());
+        packet.extend([0; 8]);
+        for chunk in chunks {
+            packet.extend(chunk);
+            packet.resize((packet.len() + 3) & !3, 0);
+        }
+        packet
+    }
+
+    fn chunk(kind: u8, flags: u8, value: &[u8]) -> Vec<u8> {
+        let mut chunk = vec![kind, flags];
+        chunk.extend(((CHUNK_HEADER_LEN + value.len()) as u16).to_be_bytes());
+        chunk.extend(value);
+        chunk
+    }
+
+    fn init(kind: u8, initial_tsn: u32) -> Vec<u8> {
+        let mut value = vec![0; 12];
+        value.extend(initial_tsn.to_be_bytes());
+        chunk(kind, 0, &value)
+    }
+
+    fn data(tsn: u32, stream: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut value = Vec::new();
+        value.extend(tsn.to_be_bytes());
+        value.extend(stream.to_be_bytes());
+        value.extend([0; 6]);
+        value.extend(payload);
+        chunk(CHUNK_DATA, flags, &value)
+    }
+
+    const WHOLE: u8 = DATA_BEGIN | DATA_END;
+
+    #[test]
+    fn test_parse_chunks() {
+        let chunks = [data(7, 1, WHOLE, b"odd"), chunk(CHUNK_ABORT, 0, b"")];
+        let packet = packet(false, &chunks);
+        assert_eq!(
+            parse_chunks(&packet[COMMON_HEADER_LEN..]),
+            vec![
+                Chunk::Data {
+                    tsn: 7,
+                    stream: 1,
+                    begin: true,
+                    end: true,
+                    data: b"odd",
+                },
+                Chunk::Abort,
+            ]
+        );
+        // Cut off in the middle of a chunk
+        assert_eq!(parse_chunks(&packet[COMMON_HEADER_LEN..20]), vec![]);
+    }
+
+    #[test]
+    fn test_streams() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut listener = TestListener {
+            received: received.clone(),
+        };
+        let mut follower = SctpFollower::default();
+        let mut send = |reply: bool, chunks: &[Vec<u8>]| {
+            follower.chomp(
+                TimingInfo::default(),
+                &ip(reply),
+                &packet(reply, chunks),
+                &mut listener,
+            );
+        };
+
+        send(false, &[init(CHUNK_INIT, 100)]);
+        send(true, &[init(CHUNK_INIT_ACK, 5000)]);
+        // A message in two pieces, the second arriving first
+        send(false, &[data(101, 1, DATA_END, b" world")]);
+        send(false, &[data(100, 1, DATA_BEGIN, b"hello")]);
+        // Retransmitted
+        send(false, &[data(100, 1, DATA_BEGIN, b"hello")]);
+        send(false, &[data(102, 2, WHOLE, b"other")]);
+        send(true, &[data(5000, 1, WHOLE, b"reply")]);
+
+        let received = received.read().unwrap();
+        let messages: Vec<_> = received
+            .iter()
+            .map(|r| match r {
+                Received::Message(meta, data) => {
+                    (meta.timing.stream, meta.to_client, data.to_vec())
+                }
+                Received::SideData(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (Some(1), false, b"hello world".to_vec()),
+                (Some(2), false, b"other".to_vec()),
+                (Some(1), true, b"reply".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward_tsn() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut listener = SideDataListener {
+            received: received.clone(),
+        };
+        let mut follower = SctpFollower::default();
+        let mut send = |reply: bool, chunks: &[Vec<u8>]| {
+            follower.chomp(
+                TimingInfo::default(),
+                &ip(reply),
+                &packet(reply, chunks),
+                &mut listener,
+            );
+        };
+
+        send(false, &[init(CHUNK_INIT, 100)]);
+        // 100 is abandoned, so 101 can be delivered
+        send(false, &[data(101, 0, WHOLE, b"late")]);
+        let mut forward = vec![];
+        forward.extend(100u32.to_be_bytes());
+        send(false, &[chunk(CHUNK_FORWARD_TSN, 0, &forward)]);
+        send(true, &[chunk(CHUNK_ABORT, 0, b"")]);
+
+        let closed = SideDataListener::find::<SctpAssociationClosed>(&received);
+        assert_eq!(closed.len(), 1);
+        assert!(closed[0].aborted);
+        assert_eq!(closed[0].target.client_port(), 40000);
+        assert!(follower.associations.is_empty());
+    }
+}
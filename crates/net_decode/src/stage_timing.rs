@@ -0,0 +1,219 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! How long each stage of the decoding stack takes per message, for finding
+//! out which one is at fault when decoding can't keep up with a capture.
+//!
+//! Only built with the `stage-timing` feature, since it reads the clock
+//! twice for every message at every stage.
+//!
+//! Each [`Timed`] stage counts its own time, leaving out the time spent in
+//! the stages it passes messages on to, so that the times of all the stages
+//! add up to the time taken overall.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    chomp::{FrameChomper, IPTarget},
+    key_db::{ClientRandom, Secret, SecretType},
+    link::Linktype,
+    listener::{Listener, SideData, TimingInfo},
+    metrics::Histogram,
+    Error,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    /// Link, IP, TCP and UDP, and picking the next stage for each flow.
+    Frames,
+    Tls,
+    Http,
+    /// Flows on none of the TLS and HTTP ports.
+    Other,
+    /// Whatever the decoded messages are given to in the end.
+    Sink,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Frames => "frames",
+            Stage::Tls => "tls",
+            Stage::Http => "http",
+            Stage::Other => "other",
+            Stage::Sink => "sink",
+        })
+    }
+}
+
+/// Nanoseconds taken per message, by stage.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StageReport {
+    pub stages: BTreeMap<Stage, Histogram>,
+}
+
+impl StageReport {
+    /// Total nanoseconds across every stage.
+    pub fn total(&self) -> u64 {
+        self.stages.values().map(|h| h.sum).sum()
+    }
+}
+
+impl fmt::Display for StageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().max(1);
+        writeln!(
+            f,
+            "{:<8} {:>10} {:>12} {:>6} {:>10} {:>10} {:>10}",
+            "stage", "messages", "total ms", "%", "p50 ns", "p99 ns", "max ns"
+        )?;
+        for (stage, h) in &self.stages {
+            writeln!(
+                f,
+                "{:<8} {:>10} {:>12.3} {:>6.1} {:>10} {:>10} {:>10}",
+                stage.to_string(),
+                h.count,
+                h.sum as f64 / 1e6,
+                h.sum as f64 * 100.0 / total as f64,
+                h.quantile(0.5).unwrap_or(0),
+                h.quantile(0.99).unwrap_or(0),
+                h.max,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Shared handle to the times recorded by any number of [`Timed`] stages.
+#[derive(Clone, Debug, Default)]
+pub struct StageTimes(Arc<Mutex<StageReport>>);
+
+impl StageTimes {
+    pub fn report(&self) -> StageReport {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Runs `f` as `stage`, recording the time it took less that of any
+    /// stages it ran in turn.
+    fn time<R>(&self, stage: Stage, f: impl FnOnce() -> R) -> R {
+        NESTED.with(|n| n.borrow_mut().push(0));
+        let start = Instant::now();
+        let ret = f();
+        let elapsed = start.elapsed().as_nanos() as u64;
+        let nested = NESTED.with(|n| {
+            let mut n = n.borrow_mut();
+            let nested = n.pop().unwrap_or(0);
+            if let Some(outer) = n.last_mut() {
+                *outer += elapsed;
+            }
+            nested
+        });
+        self.0
+            .lock()
+            .unwrap()
+            .stages
+            .entry(stage)
+            .or_default()
+            .record(elapsed.saturating_sub(nested));
+        ret
+    }
+}
+
+thread_local! {
+    /// Time spent in the stages below each stage running on this thread,
+    /// innermost last.
+    static NESTED: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+}
+
+/// Times everything that goes through `next` as one [`Stage`]. Wraps
+/// listeners, and also [`FrameChomper`]s for the [`Stage::Frames`] stage.
+pub struct Timed<L> {
+    /// Passes everything straight on if `None`.
+    times: Option<StageTimes>,
+    stage: Stage,
+    next: L,
+}
+
+impl<L> Timed<L> {
+    pub fn new(times: StageTimes, stage: Stage, next: L) -> Self {
+        Self::maybe(Some(times), stage, next)
+    }
+
+    pub(crate) fn maybe(times: Option<StageTimes>, stage: Stage, next: L) -> Self {
+        Timed { times, stage, next }
+    }
+
+    fn time<R>(&mut self, f: impl FnOnce(&mut L) -> R) -> R {
+        match &self.times {
+            Some(times) => times.time(self.stage, || f(&mut self.next)),
+            None => f(&mut self.next),
+        }
+    }
+}
+
+impl<T, L: Listener<T>> Listener<T> for Timed<L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        self.time(|next| next.on_data(timing, target, to_client, data));
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.time(|next| next.on_side_data(data));
+    }
+}
+
+impl<C: FrameChomper> FrameChomper for Timed<C> {
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), Error> {
+        self.time(|next| next.chomp(timing, link_type, packet))
+    }
+
+    fn on_keys(&mut self, dsb: &[u8]) {
+        self.next.on_keys(dsb)
+    }
+
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.next.on_wireguard_keys(key_log)
+    }
+
+    fn on_key(&mut self, client_random: ClientRandom, secret_type: SecretType, secret: Secret) {
+        self.next.on_key(client_random, secret_type, secret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{chomp, pipeline::Pipeline, test_support::H2};
+
+    #[test]
+    fn test_stage_timing() {
+        let times = StageTimes::default();
+        let chomper = Pipeline::new()
+            .tls(Default::default())
+            .http()
+            .stage_timing(times.clone())
+            .build(crate::listener::NoOpListener {});
+        let mut chomper = Timed::new(times.clone(), Stage::Frames, chomper);
+        chomp::dump_pcap(std::io::Cursor::new(H2), &mut chomper).unwrap();
+
+        let report = times.report();
+        for stage in [Stage::Frames, Stage::Tls, Stage::Http, Stage::Sink] {
+            assert!(
+                report.stages.get(&stage).map_or(0, |h| h.count) > 0,
+                "nothing timed for {stage}: {report}"
+            );
+        }
+        assert!(!report.stages.contains_key(&Stage::Other));
+    }
+}
@@ -4,11 +4,13 @@
 
 //! Does a similar thing to the following:
 //! https://github.com/rusticata/pcap-analyzer/blob/master/libpcap-analyzer/src/tcp_reassembly.rs#L14
+use bytes::{Buf, Bytes};
 use pktparse::tcp::TcpHeader;
 
 use std::{
-    collections::{hash_map::Entry, BTreeMap, HashMap},
+    collections::{btree_map, hash_map::Entry, BTreeMap, HashMap},
     fmt::{self, Debug},
+    net::IpAddr,
     num::Wrapping,
     ops::Bound,
 };
@@ -16,10 +18,149 @@ use std::{
 use crate::{
     chomp::IPHeader,
     chomp::IPTarget,
-    listener::{Listener, TimingInfo},
-    Error,
+    diagnostic::{report, Layer, Severity},
+    icmp::{side_data::IcmpError, IcmpErrorKind},
+    listener::{Listener, Nanos, TimingInfo},
+    mptcp::{self, MptcpTracker},
+    packet_comment,
+    tcp_timing::{self, FlowTimer},
+    tcp_window::{self, FlowWindows},
+    tunnel, Error,
 };
 
+pub mod side_data {
+    use crate::{chomp::IPTarget, icmp::IcmpErrorKind, listener::TimingInfo};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ConnectionFailure {
+        /// The server answered our SYN with a RST.
+        Refused,
+        /// Either side reset the connection before any payload was sent.
+        ResetBeforeData,
+        /// An ICMP error came back for a packet the client sent before any
+        /// payload was.
+        Unreachable(IcmpErrorKind),
+    }
+
+    /// Fired by `net_decode::tcp_reassemble` when a connection dies before
+    /// carrying anything, so that it does not just vanish from views built
+    /// on top of the decoded data.
+    #[derive(Clone, Debug)]
+    pub struct ConnectionFailed {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub reason: ConnectionFailure,
+    }
+
+    /// Fired by `net_decode::tcp_reassemble` once both sides of a connection
+    /// have sent a FIN, or either has sent a RST. Nothing more will be
+    /// delivered for it.
+    #[derive(Clone, Debug)]
+    pub struct ConnectionClosed {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub kind: CloseKind,
+        /// Both directions together.
+        pub stats: ReassemblyStats,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum CloseKind {
+        /// Both sides sent a FIN.
+        Graceful,
+        Reset {
+            from_client: bool,
+        },
+        /// The client opened a new connection on the same addresses and
+        /// ports without this one having been seen to close.
+        Superseded,
+        /// We stopped following the connection before it closed, see
+        /// [`FlowEvicted`].
+        Evicted,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum EvictionReason {
+        /// Closed long enough ago that nothing more is expected.
+        Closed,
+        /// Nothing was sent either way for the idle timeout.
+        Idle,
+        /// Made room for a new connection once the most we follow at once
+        /// was reached. This is the one that was idle the longest.
+        TooManyFlows,
+    }
+
+    /// Fired by `net_decode::tcp_reassemble` when it forgets a connection,
+    /// after [`ConnectionClosed`] if it had not already closed. Segments
+    /// still arriving for it are dropped, like those of any connection
+    /// whose start we missed.
+    #[derive(Clone, Debug)]
+    pub struct FlowEvicted {
+        /// Of the connection's last segment.
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub reason: EvictionReason,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum LifecycleEvent {
+        /// The client asked to open the connection.
+        Syn,
+        /// The server accepted it.
+        SynAck,
+        /// The first FIN: its sender is done sending, but the other side may
+        /// carry on.
+        HalfClosed,
+        /// The FIN from the other side.
+        Fin,
+        Reset,
+    }
+
+    /// Fired by `net_decode::tcp_reassemble` the first time each
+    /// [`LifecycleEvent`] is seen on a connection, as the segment arrives
+    /// rather than once it is reassembled.
+    #[derive(Clone, Debug)]
+    pub struct ConnectionLifecycle {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub from_client: bool,
+        pub event: LifecycleEvent,
+    }
+
+    /// Fired by `net_decode::tcp_reassemble` in place of data the capture
+    /// cut off, e.g. because of its snap length, right where that data
+    /// would have been delivered. The connection carries on after it.
+    #[derive(Clone, Debug)]
+    pub struct Truncated {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub to_client: bool,
+        /// How many bytes are missing.
+        pub missing: usize,
+    }
+
+    /// Data the reassembler saw more than once and only delivered once.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct ReassemblyStats {
+        /// Segments with nothing we did not already have.
+        pub retransmitted_segments: u64,
+        /// Segments that were partly new.
+        pub overlapping_segments: u64,
+        /// Bytes dropped from either kind of segment.
+        pub duplicate_bytes: u64,
+    }
+
+    impl ReassemblyStats {
+        pub fn merge(self, other: ReassemblyStats) -> ReassemblyStats {
+            ReassemblyStats {
+                retransmitted_segments: self.retransmitted_segments + other.retransmitted_segments,
+                overlapping_segments: self.overlapping_segments + other.overlapping_segments,
+                duplicate_bytes: self.duplicate_bytes + other.duplicate_bytes,
+            }
+        }
+    }
+}
+
 /// https://datatracker.ietf.org/doc/html/rfc9293#name-state-machine-overview
 #[allow(unused)]
 #[derive(Clone, Copy, Debug)]
@@ -86,12 +227,41 @@ impl<H: HasSequenceNumber, F: FnMut(H) -> SeqNum> ReassemblerTarget<H> for F {
 
 pub trait HasSequenceNumber {
     fn sequence_number(&self) -> SeqNum;
+    /// How much sequence space the segment covers.
+    fn segment_len(&self) -> SeqNum;
+    /// Drops the first `n` bytes of the segment, which we already have.
+    fn trim_front(&mut self, n: SeqNum);
 }
 
-impl HasSequenceNumber for (TcpHeader, Vec<u8>) {
+/// A segment, with as much of its payload as was captured.
+#[derive(Clone, Debug, Default)]
+pub struct Segment {
+    pub header: TcpHeader,
+    pub data: Bytes,
+    /// How much of the payload after `data` the capture cut off.
+    pub missing: usize,
+}
+
+impl HasSequenceNumber for Segment {
     fn sequence_number(&self) -> SeqNum {
-        Wrapping(self.0.sequence_no)
+        Wrapping(self.header.sequence_no)
     }
+
+    fn segment_len(&self) -> SeqNum {
+        Wrapping((self.data.len() + self.missing) as u32)
+    }
+
+    fn trim_front(&mut self, n: SeqNum) {
+        self.header.sequence_no = self.header.sequence_no.wrapping_add(n.0);
+        let from_data = (n.0 as usize).min(self.data.len());
+        self.data.advance(from_data);
+        self.missing -= n.0 as usize - from_data;
+    }
+}
+
+/// Whether `a` comes before `b` in sequence space.
+pub(crate) fn seq_before(a: SeqNum, b: SeqNum) -> bool {
+    (a - b).0 >= u32::MAX / 2
 }
 
 /// Reorder buffer for TCP segments.
@@ -100,13 +270,16 @@ impl HasSequenceNumber for (TcpHeader, Vec<u8>) {
 /// may be leaked/order may not be preserved.
 ///
 /// Returns segments strictly in-order and containing strictly all expected
-/// data, but potentially with Bonus Data if they overlap.
+/// data. Retransmitted and overlapping data is only delivered once, taken
+/// from whichever segment covering it is delivered first, and counted in
+/// [`Self::stats`].
 #[derive(Default, Debug)]
 pub struct TcpReorderBuffer<H: HasSequenceNumber> {
     /// The lowest maybe-stored, maybe-unavailable sequence number. Used to
     /// start search for blocks when extracting data.
     lowest: Wrapping<u32>,
     reassemble: BTreeMap<Wrapping<u32>, H>,
+    stats: side_data::ReassemblyStats,
 }
 
 impl<H: HasSequenceNumber> TcpReorderBuffer<H> {
@@ -114,35 +287,114 @@ impl<H: HasSequenceNumber> TcpReorderBuffer<H> {
         TcpReorderBuffer {
             lowest: isn,
             reassemble: BTreeMap::new(),
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> side_data::ReassemblyStats {
+        self.stats
+    }
+
+    /// Cuts off the part of `data` that was already delivered, returning
+    /// `None` if that is all of it.
+    fn trim_delivered(&mut self, mut data: H) -> Option<H> {
+        let seqno = data.sequence_number();
+        if !seq_before(seqno, self.lowest) {
+            return Some(data);
+        }
+        let len = data.segment_len();
+        let delivered = self.lowest - seqno;
+        if delivered >= len {
+            // Including bare ACKs with old sequence numbers, such as
+            // keep-alives.
+            if len.0 > 0 {
+                self.stats.retransmitted_segments += 1;
+                self.stats.duplicate_bytes += len.0 as u64;
+            }
+            return None;
+        }
+        data.trim_front(delivered);
+        self.stats.overlapping_segments += 1;
+        self.stats.duplicate_bytes += delivered.0 as u64;
+        Some(data)
+    }
+
+    /// Holds on to a segment until everything before it has arrived.
+    fn hold(&mut self, mut data: H) {
+        let len = data.segment_len();
+        match self.reassemble.entry(data.sequence_number()) {
+            btree_map::Entry::Vacant(v) => {
+                v.insert(data);
+            }
+            btree_map::Entry::Occupied(mut held) => {
+                let held_len = held.get().segment_len();
+                if held_len.0 == 0 {
+                    held.insert(data);
+                } else if len.0 == 0 {
+                    // A bare ACK or window update sent after a segment that
+                    // is stuck behind a gap must not throw its data away.
+                } else if len <= held_len {
+                    self.stats.retransmitted_segments += 1;
+                    self.stats.duplicate_bytes += len.0 as u64;
+                } else {
+                    self.stats.overlapping_segments += 1;
+                    self.stats.duplicate_bytes += held_len.0 as u64;
+                    data.trim_front(held_len);
+                    self.hold(data);
+                }
+            }
+        }
+    }
+
+    /// Keys of the held segments that start before `lowest`, in sequence
+    /// order. These are the ones up to half the sequence space behind it,
+    /// found without looking at the rest of the map.
+    fn stale_keys(&self) -> Vec<SeqNum> {
+        let start = self.lowest + Wrapping(u32::MAX / 2);
+        let keys = |(k, _): (&SeqNum, &H)| *k;
+        if start < self.lowest {
+            self.reassemble
+                .range(start..self.lowest)
+                .map(keys)
+                .collect()
+        } else {
+            // The window wraps around the end of the sequence space.
+            self.reassemble
+                .range(start..)
+                .chain(self.reassemble.range(..self.lowest))
+                .map(keys)
+                .collect()
         }
     }
 
     /// Ingests a TCP segment and may call the callback some number of times to
     /// clear the queue if there is all necessary data.
     pub fn ingest<Target: ReassemblerTarget<H>>(&mut self, data: H, callback: &mut Target) {
-        let incoming_seqno = data.sequence_number();
-        // Ingest the data always
-        self.reassemble.insert(incoming_seqno, data);
-
-        // This ensures that incoming_seqno is never less than self.lowest,
-        // to the extent that we can know that
-        assert!(incoming_seqno - self.lowest < Wrapping(u32::MAX / 2));
+        let Some(data) = self.trim_delivered(data) else {
+            return;
+        };
+        self.hold(data);
 
         // Export all the data we can
-        let mut it = WrappingCursor::new(&mut self.reassemble, self.lowest);
         loop {
-            let pos = it.peek().map(|(k, _v)| *k);
-            if let Some(k) = pos {
-                if k == self.lowest {
+            // Held segments that start before the data just delivered ended
+            // overlap it.
+            for k in self.stale_keys() {
+                let data = self.reassemble.remove(&k).unwrap();
+                if let Some(data) = self.trim_delivered(data) {
+                    self.hold(data);
+                }
+            }
+
+            let mut it = WrappingCursor::new(&mut self.reassemble, self.lowest);
+            match it.peek().map(|(k, _v)| *k) {
+                Some(k) if k == self.lowest => {
                     // We have the data for the current lowest, take it out
                     let (_, v) = it.remove().unwrap();
                     self.lowest = callback.on_good_segment(v);
-                } else {
-                    // We don't have the segment we want yet.
-                    break;
                 }
-            } else {
-                break;
+                // We don't have the segment we want yet.
+                _ => break,
             }
         }
     }
@@ -165,11 +417,56 @@ pub struct TCPStateMachine {
     send_unack: u32,
 }
 
+/// Without options.
+const TCP_HEADER_LEN: usize = 20;
+/// Where the flags are in the TCP header.
+const TCP_FLAGS_OFFSET: usize = 13;
+
+/// The parts of a segment that pktparse does not give us.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RawSegment<'a> {
+    /// TCP options, unparsed.
+    pub options: &'a [u8],
+    /// The flags byte of the TCP header, which has CWR and ECE in it.
+    pub flags: u8,
+    /// The ECN field of the IP header.
+    pub ecn: u8,
+    /// How much of the payload the capture cut off.
+    pub missing: usize,
+}
+
+/// How many segments with data to hold per side while the handshake is
+/// still missing, e.g. because the SYN-ACK was reordered behind them.
+const MAX_EARLY_SEGMENTS: usize = 64;
+
+/// How long a connection may go without segments before we stop following
+/// it. Long enough for most keep-alive connections.
+pub const DEFAULT_IDLE_TIMEOUT: Nanos = 3_600_000_000_000;
+/// How many connections to follow at once.
+pub const DEFAULT_MAX_FLOWS: usize = 100_000;
+/// How long to remember closed connections, to absorb retransmitted FINs and
+/// ACKs and to number the next connection on the same ports.
+const CLOSED_LINGER: Nanos = 30_000_000_000;
+/// How often to look for connections to evict, in capture time.
+const SWEEP_INTERVAL: Nanos = 1_000_000_000;
+
 #[derive(Debug, Default)]
 pub struct TCPSide {
     state_machine: TCPStateMachine,
 
-    reorder_buffer: TcpReorderBuffer<(TcpHeader, Vec<u8>)>,
+    reorder_buffer: TcpReorderBuffer<Segment>,
+    /// Segments with data that arrived before the sequence numbers were
+    /// synchronized, to be reassembled once they are.
+    early: Vec<Segment>,
+}
+
+impl TCPSide {
+    fn is_synchronized(&self) -> bool {
+        !matches!(
+            self.state_machine.state,
+            TCPState::Listen | TCPState::SynSent | TCPState::SynReceived
+        )
+    }
 }
 
 impl TCPStateMachine {
@@ -238,6 +535,10 @@ impl TCPStateMachine {
                 }
             }
             TCPState::SynReceived => {
+                if flag_syn && tcp.sequence_no == self.irs {
+                    // Retransmitted SYN
+                    return;
+                }
                 if flag_syn {
                     // most likely the correct option here is to return to
                     // LISTEN per 3.10.7.4
@@ -286,12 +587,126 @@ pub struct TCPFlow {
     pub client: TCPSide,
     /// State machine maintained for data received by the server side
     pub server: TCPSide,
+
+    /// Whether any payload has been seen in either direction.
+    pub saw_data: bool,
+    /// Whether we already sent [`side_data::ConnectionFailed`] for this flow.
+    reported_failure: bool,
+    /// Whether we already sent [`side_data::ConnectionClosed`] for this flow.
+    reported_close: bool,
+    /// Whether the client and the server have sent a SYN.
+    sent_syn: [bool; 2],
+    /// Whether the client and the server have sent a FIN.
+    sent_fin: [bool; 2],
+    timer: FlowTimer,
+    windows: FlowWindows,
+    /// See [`TimingInfo::connection_epoch`].
+    pub epoch: u32,
+    /// Initial sequence number of the client, which tells a retransmitted
+    /// SYN apart from a new connection.
+    client_isn: u32,
+    /// When the latest segment was seen.
+    last_seen: Nanos,
 }
 
-#[derive(Debug, Default)]
+impl TCPFlow {
+    /// Retransmitted and overlapping data seen in both directions.
+    pub fn stats(&self) -> side_data::ReassemblyStats {
+        self.client
+            .reorder_buffer
+            .stats()
+            .merge(self.server.reorder_buffer.stats())
+    }
+
+    /// The transition `tcp` makes, if it is the first of its kind.
+    fn lifecycle(
+        &mut self,
+        tcp: &TcpHeader,
+        from_client: bool,
+    ) -> Option<side_data::LifecycleEvent> {
+        use side_data::LifecycleEvent;
+
+        let sender = if from_client { 0 } else { 1 };
+        if tcp.flag_rst {
+            return (!self.reported_close).then_some(LifecycleEvent::Reset);
+        }
+        if tcp.flag_syn && !self.sent_syn[sender] {
+            self.sent_syn[sender] = true;
+            return Some(if tcp.flag_ack {
+                LifecycleEvent::SynAck
+            } else {
+                LifecycleEvent::Syn
+            });
+        }
+        if tcp.flag_fin && !self.sent_fin[sender] {
+            self.sent_fin[sender] = true;
+            return Some(if self.sent_fin[1 - sender] {
+                LifecycleEvent::Fin
+            } else {
+                LifecycleEvent::HalfClosed
+            });
+        }
+        None
+    }
+
+    fn is_closed(&self) -> bool {
+        matches!(self.client.state_machine.state, TCPState::Closed)
+            && matches!(self.server.state_machine.state, TCPState::Closed)
+    }
+
+    /// Sends [`tcp_timing::side_data::FlowPerformance`] and
+    /// [`side_data::ConnectionClosed`], unless they were already sent.
+    fn report_close(
+        &mut self,
+        timing: TimingInfo,
+        target: IPTarget,
+        kind: side_data::CloseKind,
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        if self.reported_close {
+            return;
+        }
+        self.reported_close = true;
+        let [mut to_server, mut to_client] = self.timer.performance();
+        let [from_client, from_server] = self.windows.stats();
+        (to_server.window, to_server.ecn) = from_client;
+        (to_client.window, to_client.ecn) = from_server;
+        recv.on_side_data(Box::new(tcp_timing::side_data::FlowPerformance {
+            timing: timing.clone(),
+            target,
+            to_server,
+            to_client,
+        }));
+        recv.on_side_data(Box::new(side_data::ConnectionClosed {
+            timing,
+            target,
+            kind,
+            stats: self.stats(),
+        }));
+    }
+}
+
+#[derive(Debug)]
 pub struct TcpFollower {
     /// Drives a TCP state machine based on the data received on a given side.
     pub flows: HashMap<IPTarget, TCPFlow>,
+    /// Flows that are subflows of MPTCP connections.
+    mptcp: MptcpTracker,
+    idle_timeout: Nanos,
+    max_flows: usize,
+    last_sweep: Nanos,
+}
+
+impl Default for TcpFollower {
+    fn default() -> Self {
+        TcpFollower {
+            flows: HashMap::new(),
+            mptcp: MptcpTracker::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_flows: DEFAULT_MAX_FLOWS,
+            last_sweep: 0,
+        }
+    }
 }
 
 struct PrintTcpHeader<'a>(&'a TcpHeader);
@@ -316,14 +731,160 @@ impl<'a> Debug for PrintTcpHeader<'a> {
 }
 
 impl TcpFollower {
-    fn record_flow(
+    pub fn with_idle_timeout(mut self, idle_timeout: Nanos) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Past this many connections, the one idle the longest is evicted for
+    /// each new one.
+    pub fn with_max_flows(mut self, max_flows: usize) -> Self {
+        self.max_flows = max_flows.max(1);
+        self
+    }
+
+    /// Evicts the connections that have gone idle by `now`, as the next
+    /// packet would, for when no more packets are coming.
+    pub fn advance_time(&mut self, now: Nanos, recv: &mut dyn Listener<Bytes>) {
+        self.sweep(now, recv);
+    }
+
+    /// Stops following `target`, closing it first if needed, and sends
+    /// [`side_data::FlowEvicted`].
+    fn evict(
         &mut self,
-        timing: TimingInfo,
+        target: IPTarget,
+        reason: side_data::EvictionReason,
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        let Some(mut flow) = self.flows.remove(&target) else {
+            return;
+        };
+        tracing::debug!("evicting tcp flow {target:?}: {reason:?}");
+        self.mptcp.forget(target);
+        let timing = TimingInfo {
+            received_on_wire: flow.last_seen,
+            connection_epoch: flow.epoch,
+            ..Default::default()
+        };
+        flow.report_close(timing.clone(), target, side_data::CloseKind::Evicted, recv);
+        recv.on_side_data(Box::new(side_data::FlowEvicted {
+            timing,
+            target,
+            reason,
+        }));
+    }
+
+    /// Evicts connections that closed a while ago or have gone idle.
+    fn sweep(&mut self, now: Nanos, recv: &mut dyn Listener<Bytes>) {
+        use side_data::EvictionReason;
+
+        self.last_sweep = now;
+        let idle_timeout = self.idle_timeout;
+        let mut evicted: Vec<_> = self
+            .flows
+            .iter()
+            .filter_map(|(target, flow)| {
+                let idle = now.saturating_sub(flow.last_seen);
+                if flow.reported_close && idle > CLOSED_LINGER {
+                    Some((*target, EvictionReason::Closed))
+                } else if idle > idle_timeout {
+                    Some((*target, EvictionReason::Idle))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        // In a stable order, so that decoding the same capture twice gives
+        // the same output.
+        evicted.sort_by_key(|(target, _)| {
+            (
+                self.flows[target].last_seen,
+                target.client_addr(),
+                target.server_addr(),
+            )
+        });
+        for (target, reason) in evicted {
+            self.evict(target, reason, recv);
+        }
+    }
+
+    /// Evicts one connection to make room for another, preferring closed
+    /// ones.
+    fn make_room(&mut self, recv: &mut dyn Listener<Bytes>) {
+        use side_data::EvictionReason;
+
+        let oldest = self
+            .flows
+            .iter()
+            .min_by_key(|(_, flow)| (!flow.reported_close, flow.last_seen))
+            .map(|(target, flow)| (*target, flow.reported_close));
+        if let Some((target, closed)) = oldest {
+            let reason = if closed {
+                EvictionReason::Closed
+            } else {
+                EvictionReason::TooManyFlows
+            };
+            self.evict(target, reason, recv);
+        }
+    }
+
+    /// Makes way for a connection that `target`'s client opens with a SYN
+    /// with initial sequence number `isn`, ending whatever connection had
+    /// the same addresses and ports before. Returns the epoch of the new
+    /// connection.
+    fn supersede(
+        &mut self,
+        timing: &TimingInfo,
+        target: &IPTarget,
+        isn: u32,
+        recv: &mut dyn Listener<Bytes>,
+    ) -> u32 {
+        if let Some(flow) = self.flows.get(target) {
+            if flow.client_isn == isn && !flow.reported_close {
+                // Retransmitted SYN
+                return flow.epoch;
+            }
+        }
+        let old = [*target, target.flip()]
+            .into_iter()
+            .find_map(|key| Some((key, self.flows.remove(&key)?)));
+        let Some((old_key, mut old)) = old else {
+            return 0;
+        };
+        tracing::debug!("new connection on {target:?} replaces epoch {}", old.epoch);
+        self.mptcp.forget(old_key);
+        let timing = TimingInfo {
+            connection_epoch: old.epoch,
+            ..timing.clone()
+        };
+        old.report_close(timing, old_key, side_data::CloseKind::Superseded, recv);
+        old.epoch.wrapping_add(1)
+    }
+
+    pub(crate) fn record_flow(
+        &mut self,
+        mut timing: TimingInfo,
         target: &IPTarget,
         tcp: &TcpHeader,
+        raw: &RawSegment,
         data: &[u8],
-        recv: &mut dyn Listener<Vec<u8>>,
+        recv: &mut dyn Listener<Bytes>,
     ) -> Result<(), Error> {
+        let now = timing.received_on_wire;
+        let missing = raw.missing;
+        if now.saturating_sub(self.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(now, recv);
+        }
+        let epoch = if tcp.flag_syn && !tcp.flag_ack {
+            let epoch = self.supersede(&timing, target, tcp.sequence_no, recv);
+            if self.flows.len() >= self.max_flows && !self.flows.contains_key(target) {
+                self.make_room(recv);
+            }
+            epoch
+        } else {
+            0
+        };
         let received_by_client = self.flows.contains_key(&target.flip());
         let entry_key = if received_by_client {
             // the reverse of the flow exists, so it's sent by the server
@@ -357,10 +918,95 @@ impl TcpFollower {
                         },
                         ..TCPSide::default()
                     },
+                    saw_data: false,
+                    reported_failure: false,
+                    reported_close: false,
+                    sent_syn: [false; 2],
+                    sent_fin: [false; 2],
+                    timer: FlowTimer::default(),
+                    windows: FlowWindows::default(),
+                    epoch,
+                    client_isn: tcp.sequence_no,
+                    last_seen: now,
                 })
             }
             Entry::Occupied(v) => v.into_mut(),
         };
+        timing.connection_epoch = entry.epoch;
+        entry.last_seen = now;
+
+        if tcp.flag_rst && !entry.reported_failure {
+            let reason = match entry.client.state_machine.state {
+                TCPState::SynSent if received_by_client => {
+                    Some(side_data::ConnectionFailure::Refused)
+                }
+                _ if !entry.saw_data => Some(side_data::ConnectionFailure::ResetBeforeData),
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                entry.reported_failure = true;
+                recv.on_side_data(Box::new(side_data::ConnectionFailed {
+                    timing: timing.clone(),
+                    target: entry_key,
+                    reason,
+                }));
+            }
+        }
+        let payload_len = data.len() + missing;
+        if payload_len > 0 {
+            entry.saw_data = true;
+        }
+        if let Some(event) = entry.lifecycle(tcp, !received_by_client) {
+            recv.on_side_data(Box::new(side_data::ConnectionLifecycle {
+                timing: timing.clone(),
+                target: entry_key,
+                from_client: !received_by_client,
+                event,
+            }));
+        }
+        let handshake = entry.timer.on_segment(
+            timing.received_on_wire,
+            !received_by_client,
+            tcp,
+            payload_len,
+        );
+        if let Some((to_server, to_client)) = handshake {
+            recv.on_side_data(Box::new(tcp_timing::side_data::HandshakeRtt {
+                timing: timing.clone(),
+                target: entry_key,
+                to_server,
+                to_client,
+            }));
+            // The SYN was sent a round trip to each end before this ACK.
+            let syn_time = now.saturating_sub(to_server + to_client);
+            packet_comment::comment_since(recv, &timing, entry_key, syn_time, "TCP handshake");
+        }
+
+        let mptcp = &mut self.mptcp;
+        if let Some(duration) =
+            entry
+                .windows
+                .on_segment(timing.received_on_wire, !received_by_client, tcp, raw)
+        {
+            recv.on_side_data(Box::new(tcp_window::side_data::ZeroWindowStall {
+                timing: timing.clone(),
+                target: entry_key,
+                from_client: !received_by_client,
+                duration,
+            }));
+        }
+
+        let mptcp_options = mptcp::parse_options(raw.options);
+        if !mptcp_options.is_empty() {
+            mptcp.on_segment(
+                &timing,
+                entry_key,
+                !received_by_client,
+                tcp,
+                &mptcp_options,
+                recv,
+            );
+        }
 
         let rx_side = if received_by_client {
             &mut entry.client
@@ -382,62 +1028,182 @@ impl TcpFollower {
 
         // FIXME: this state handling is a tangled disaster and needs to be
         // untangled. but whatever lmao
-        match rx_side.state_machine.state {
-            TCPState::Listen | TCPState::SynSent | TCPState::SynReceived => {
-                // In these states, the TCP state machine has not yet
-                // synchronized the sequence numbers, so we cannot reorder
-                // packets yet.
-                rx_side.state_machine.drive_state(tcp, |_side| {
-                    // We expect empty SYN and SYN-ACK packets
-                    assert_eq!(0, data.len());
-                });
+        let mut segments = Vec::new();
+        if rx_side.is_synchronized() {
+            segments.push(Segment {
+                header: tcp.clone(),
+                data: Bytes::copy_from_slice(data),
+                missing,
+            });
+        } else {
+            // In these states, the TCP state machine has not yet
+            // synchronized the sequence numbers, so we cannot reorder
+            // packets yet.
+            let early;
+            if tcp.flag_syn || payload_len == 0 {
+                rx_side.state_machine.drive_state(tcp, |_side| {});
                 rx_side.reorder_buffer.lowest = Wrapping(rx_side.state_machine.rcv_next);
+                // TCP Fast Open (RFC 7413) data comes after the sequence
+                // number taken up by the SYN itself.
+                early = (payload_len > 0).then(|| {
+                    let mut header = tcp.clone();
+                    header.flag_syn = false;
+                    header.sequence_no = header.sequence_no.wrapping_add(1);
+                    header
+                });
+            } else {
+                if matches!(rx_side.state_machine.state, TCPState::SynReceived) {
+                    // The final ACK of the handshake may carry data, or be
+                    // lost, leaving the first data segment to complete it.
+                    rx_side.state_machine.drive_state(tcp, |_side| {});
+                }
+                early = Some(tcp.clone());
             }
-            _ => {
-                rx_side.reorder_buffer.ingest(
-                    (tcp.clone(), data.to_vec()),
-                    &mut |(header, bs): (TcpHeader, Vec<u8>)| {
-                        let timing = timing.clone();
-                        let new_rcv_next =
-                            Wrapping(header.sequence_no) + Wrapping(bs.len().try_into().unwrap());
-
-                        // Now have in-order segments, so we can do things with them
-                        tracing::trace!("data: {}", hexdump::HexDumper::new(&bs));
-                        // FIXME: edge cases:
-                        // * Receive a seqnum which is LESS THAN the one
-                        // expected: perhaps for some reason we got part of a
-                        // buffer sent twice
-                        // * Receive an old seqnum twice (currently I think it
-                        // throws an assert).
-                        rx_side.state_machine.drive_state(&header, |_side| {
-                            // they gave us buffer uwu
-                            recv.on_data(timing, entry_key, received_by_client, bs);
-                        });
-
-                        rx_side.state_machine.rcv_next = new_rcv_next.0;
-
-                        new_rcv_next
-                    },
-                );
+            if let Some(header) = early {
+                if rx_side.early.len() < MAX_EARLY_SEGMENTS {
+                    rx_side.early.push(Segment {
+                        header,
+                        data: Bytes::copy_from_slice(data),
+                        missing,
+                    });
+                } else {
+                    report(
+                        &mut *recv,
+                        &timing,
+                        Some(entry_key),
+                        Layer::Tcp,
+                        Severity::Warning,
+                        "too much data before the handshake, dropping".to_string(),
+                    );
+                }
+            }
+            if rx_side.is_synchronized() {
+                segments.append(&mut rx_side.early);
             }
         }
 
+        for segment in segments {
+            rx_side
+                .reorder_buffer
+                .ingest(segment, &mut |segment: Segment| {
+                    let Segment {
+                        header,
+                        data: bs,
+                        missing,
+                    } = segment;
+                    let timing = timing.clone();
+                    let new_rcv_next = Wrapping(header.sequence_no)
+                        + Wrapping((bs.len() + missing).try_into().unwrap());
+
+                    // Now have in-order segments, so we can do things with them
+                    tracing::trace!("data: {}", hexdump::HexDumper::new(&bs));
+                    rx_side.state_machine.drive_state(&header, |_side| {
+                        // they gave us buffer uwu
+                        if !bs.is_empty()
+                            && !mptcp.on_subflow_data(
+                                &timing,
+                                entry_key,
+                                received_by_client,
+                                header.sequence_no,
+                                &bs,
+                                recv,
+                            )
+                        {
+                            recv.on_data(timing.clone(), entry_key, received_by_client, bs);
+                        }
+                        if missing > 0 {
+                            recv.on_side_data(Box::new(side_data::Truncated {
+                                timing,
+                                target: entry_key,
+                                to_client: received_by_client,
+                                missing,
+                            }));
+                        }
+                    });
+
+                    rx_side.state_machine.rcv_next = new_rcv_next.0;
+
+                    new_rcv_next
+                });
+        }
+
+        if tcp.flag_rst || entry.is_closed() {
+            let kind = if tcp.flag_rst {
+                side_data::CloseKind::Reset {
+                    from_client: !received_by_client,
+                }
+            } else {
+                side_data::CloseKind::Graceful
+            };
+            entry.report_close(timing, entry_key, kind, recv);
+        }
+
         Ok(())
     }
 
+    /// Takes an ICMP error about a packet sent to `quoted`. Sends it on as
+    /// [`IcmpError`], and as [`side_data::ConnectionFailed`] if the client
+    /// cannot reach the server before the connection carried anything.
+    pub fn on_icmp_error(
+        &mut self,
+        mut timing: TimingInfo,
+        quoted: IPTarget,
+        kind: IcmpErrorKind,
+        reporter: IpAddr,
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        let (target, to_client) = if self.flows.contains_key(&quoted) {
+            (quoted, false)
+        } else if self.flows.contains_key(&quoted.flip()) {
+            (quoted.flip(), true)
+        } else {
+            tracing::debug!("icmp error for unknown flow {quoted:?}");
+            return;
+        };
+        let flow = self.flows.get_mut(&target).unwrap();
+        timing.connection_epoch = flow.epoch;
+
+        if !to_client && kind.is_fatal() && !flow.saw_data && !flow.reported_failure {
+            flow.reported_failure = true;
+            recv.on_side_data(Box::new(side_data::ConnectionFailed {
+                timing: timing.clone(),
+                target,
+                reason: side_data::ConnectionFailure::Unreachable(kind),
+            }));
+        }
+        recv.on_side_data(Box::new(IcmpError {
+            timing,
+            target,
+            to_client,
+            kind,
+            reporter,
+        }));
+    }
+
+    /// Takes an IP packet's payload, of which the capture cut off the last
+    /// `missing` bytes.
     pub fn chomp(
         &mut self,
         timing: TimingInfo,
         ip_header: IPHeader,
         data: &[u8],
-        recv: &mut dyn Listener<Vec<u8>>,
+        missing: usize,
+        recv: &mut dyn Listener<Bytes>,
     ) -> Result<(), Error> {
         let proto = ip_header.proto();
         match proto {
             pktparse::ip::IPProtocol::TCP => {
                 if let Ok((remain, tcp)) = pktparse::tcp::parse_tcp_header(data) {
-                    let ip_target = IPTarget::from_headers(&ip_header, &tcp);
-                    self.record_flow(timing, &ip_target, &tcp, remain, recv)?;
+                    let ip_target = IPTarget::from_headers(&ip_header, &tcp)
+                        .on_overlay(tunnel::overlay_id(&timing.tunnels));
+                    let header_len = data.len() - remain.len();
+                    let raw = RawSegment {
+                        options: data.get(TCP_HEADER_LEN..header_len).unwrap_or_default(),
+                        flags: data[TCP_FLAGS_OFFSET],
+                        ecn: ip_header.ecn(),
+                        missing,
+                    };
+                    self.record_flow(timing, &ip_target, &tcp, &raw, remain, recv)?;
                     tracing::trace!("\n{}", hexdump::HexDumper::new(remain));
                 }
             }
@@ -525,9 +1291,19 @@ impl<'a, K: Clone + Ord, V> WrappingCursor<'a, K, V> {
 
 #[cfg(test)]
 mod test {
+    use std::{
+        ops::Range,
+        sync::{Arc, RwLock},
+    };
+
     use proptest::{collection, prelude::*};
 
     use super::*;
+    use crate::{
+        http::{HTTPRequestTracker, HTTPStreamEvent, RequestId},
+        packet_comment::side_data::PacketComment,
+        test_support::{sample_target, Received, SideDataListener, TcpSender, TestListener},
+    };
 
     proptest! {
         fn next_expectations(ref mut map in collection::btree_map(1..10u32, 1..=1u32, (1, 4)), sel in any::<prop::sample::Selector>()) {
@@ -561,6 +1337,612 @@ mod test {
         next_expectations();
     }
 
+    const FIN: u8 = 0x01;
+    const SYN: u8 = 0x02;
+    const RST: u8 = 0x04;
+    const ACK: u8 = 0x10;
+
+    fn failures(packets: &[(bool, u32, u32, u8, &[u8])]) -> Vec<side_data::ConnectionFailed> {
+        side_data_from(packets)
+    }
+
+    /// Side data of type `T` sent while following `packets` on
+    /// [`sample_target`].
+    fn side_data_from<T: Clone + 'static>(packets: &[(bool, u32, u32, u8, &[u8])]) -> Vec<T> {
+        let received = Default::default();
+        let listener = SideDataListener {
+            received: Arc::clone(&received),
+        };
+        let mut sender = TcpSender::new(sample_target(), listener);
+        for &(to_client, seq, ack, flags, data) in packets {
+            sender.send(to_client, seq, ack, flags, data);
+        }
+        SideDataListener::find(&received)
+    }
+
+    #[test]
+    fn test_handshake_comment() {
+        let received = Default::default();
+        let listener = SideDataListener {
+            received: Arc::clone(&received),
+        };
+        let mut sender = TcpSender::new(sample_target(), listener);
+        for (time, to_client, seq, ack, flags) in [
+            (1_000, false, 100, 0, SYN),
+            (3_000, true, 500, 101, SYN | ACK),
+            (3_500, false, 101, 501, ACK),
+        ] {
+            sender.timing.received_on_wire = time;
+            sender.send(to_client, seq, ack, flags, b"");
+        }
+        let comments = SideDataListener::find::<PacketComment>(&received);
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].text, "TCP handshake");
+        assert_eq!(comments[0].since, Some(1_000));
+        assert_eq!(comments[0].timing.received_on_wire, 3_500);
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        let failed = failures(&[(false, 100, 0, SYN, b""), (true, 0, 101, RST | ACK, b"")]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].reason, side_data::ConnectionFailure::Refused);
+    }
+
+    /// What was delivered, with gaps shown as `<N missing>`.
+    #[derive(Default)]
+    struct GapLog(Vec<String>);
+
+    impl Listener<Bytes> for GapLog {
+        fn on_data(
+            &mut self,
+            _timing: TimingInfo,
+            _target: IPTarget,
+            _to_client: bool,
+            data: Bytes,
+        ) {
+            self.0.push(String::from_utf8_lossy(&data).into_owned());
+        }
+
+        fn on_side_data(&mut self, data: Box<dyn crate::listener::SideData>) {
+            if let Some(truncated) = data.downcast_ref::<side_data::Truncated>() {
+                self.0.push(format!("<{} missing>", truncated.missing));
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_segments() {
+        let mut sender = TcpSender::new(sample_target(), GapLog::default());
+        let mut send = |to_client: bool, seq: u32, ack: u32, flags: u8, data: &[u8], missing| {
+            let raw = RawSegment {
+                missing,
+                ..Default::default()
+            };
+            sender.send_raw(to_client, seq, ack, flags, &raw, data);
+        };
+        send(false, 100, 0, SYN, b"", 0);
+        send(true, 500, 101, SYN | ACK, b"", 0);
+        send(false, 101, 501, ACK, b"", 0);
+        // Out of order, and the first sent wholly cut off but for its
+        // headers.
+        send(false, 113, 501, ACK, b"de", 2);
+        send(false, 105, 501, ACK, b"", 8);
+        send(false, 101, 501, ACK, b"abc", 1);
+        send(false, 117, 501, ACK, b"fg", 0);
+
+        assert_eq!(
+            sender.recv.0,
+            [
+                "abc",
+                "<1 missing>",
+                "<8 missing>",
+                "de",
+                "<2 missing>",
+                "fg"
+            ]
+        );
+    }
+
+    /// One connection over two subflows, with the second half of what the
+    /// client sent arriving first.
+    #[test]
+    fn test_mptcp_subflows() {
+        let first = sample_target();
+        let second = IPTarget::V4 {
+            client_port: 40001,
+            server_port: 443,
+            client_ip: [10, 0, 0, 1].into(),
+            server_ip: [10, 0, 0, 2].into(),
+            overlay: None,
+        };
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let listener = TestListener {
+            received: received.clone(),
+        };
+        let mut sender = TcpSender::new(first, listener);
+        let mut send = |target: IPTarget,
+                        to_client: bool,
+                        seq: u32,
+                        ack: u32,
+                        flags: u8,
+                        options: Vec<u8>,
+                        data: &[u8]| {
+            let raw = RawSegment {
+                options: &options,
+                ..Default::default()
+            };
+            sender.target = target;
+            sender.send_raw(to_client, seq, ack, flags, &raw, data);
+        };
+
+        let (client_key, server_key) = (0x1111u64, 0x2222u64);
+        let capable = |keys: &[u64]| {
+            let mut option = vec![mptcp::TCPOPT_MPTCP, 4 + 8 * keys.len() as u8, 0x00, 0x81];
+            keys.iter().for_each(|k| option.extend(k.to_be_bytes()));
+            option
+        };
+        send(first, false, 100, 0, SYN, capable(&[client_key]), b"");
+        send(
+            first,
+            true,
+            500,
+            101,
+            SYN | ACK,
+            capable(&[server_key]),
+            b"",
+        );
+        let third_ack = capable(&[client_key, server_key]);
+        send(first, false, 101, 501, ACK, third_ack, b"");
+
+        let (server_token, _) = mptcp::token_and_idsn(server_key);
+        let mut join = vec![mptcp::TCPOPT_MPTCP, 12, 0x10, 0];
+        join.extend(server_token.to_be_bytes());
+        join.extend([0; 4]);
+        send(second, false, 7000, 0, SYN, join, b"");
+        let mut join_ack = vec![mptcp::TCPOPT_MPTCP, 16, 0x10, 0];
+        join_ack.extend([0; 12]);
+        send(second, true, 9000, 7001, SYN | ACK, join_ack, b"");
+        send(second, false, 7001, 9001, ACK, vec![], b"");
+
+        let (_, idsn) = mptcp::token_and_idsn(client_key);
+        let dss = |dsn: u64, len: u16| {
+            let mut option = vec![mptcp::TCPOPT_MPTCP, 18, 0x20, 0x0c];
+            option.extend(idsn.wrapping_add(dsn).to_be_bytes());
+            option.extend(1u32.to_be_bytes());
+            option.extend(len.to_be_bytes());
+            option
+        };
+        send(second, false, 7001, 9001, ACK, dss(7, 5), b"world");
+        send(first, false, 101, 501, ACK, dss(1, 6), b"hello ");
+
+        let received = received.read().unwrap();
+        let mut data = Vec::new();
+        for r in received.iter() {
+            match r {
+                Received::Message(meta, bs) => {
+                    assert_eq!((meta.target, meta.to_client), (first, false));
+                    data.extend_from_slice(bs);
+                }
+                Received::SideData(_) => {}
+            }
+        }
+        assert_eq!(data, b"hello world");
+    }
+
+    #[test]
+    fn test_icmp_unreachable() {
+        let target = sample_target();
+        let received = Default::default();
+        let listener = SideDataListener {
+            received: Arc::clone(&received),
+        };
+        let mut sender = TcpSender::new(target, listener);
+        sender.send(false, 100, 0, SYN, b"");
+
+        let router = IpAddr::from([192, 0, 2, 1]);
+        // Not about any flow we know
+        let other = IPTarget::V4 {
+            client_port: 40001,
+            server_port: 443,
+            client_ip: [10, 0, 0, 1].into(),
+            server_ip: [10, 0, 0, 2].into(),
+            overlay: None,
+        };
+        sender.follower.on_icmp_error(
+            TimingInfo::default(),
+            other,
+            IcmpErrorKind::HostUnreachable,
+            router,
+            &mut sender.recv,
+        );
+        assert!(SideDataListener::find::<IcmpError>(&received).is_empty());
+
+        for kind in [
+            IcmpErrorKind::PacketTooBig { mtu: 1280 },
+            IcmpErrorKind::HostUnreachable,
+            IcmpErrorKind::TimeExceeded,
+        ] {
+            sender.follower.on_icmp_error(
+                TimingInfo::default(),
+                target,
+                kind,
+                router,
+                &mut sender.recv,
+            );
+        }
+
+        let errors = SideDataListener::find::<IcmpError>(&received);
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().all(|e| e.target == target && !e.to_client));
+        let failed = SideDataListener::find::<side_data::ConnectionFailed>(&received);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            failed[0].reason,
+            side_data::ConnectionFailure::Unreachable(IcmpErrorKind::HostUnreachable)
+        );
+    }
+
+    #[test]
+    fn test_lifecycle_events() {
+        use side_data::{CloseKind, ConnectionClosed, ConnectionLifecycle, LifecycleEvent};
+
+        let packets: &[(bool, u32, u32, u8, &[u8])] = &[
+            (false, 100, 0, SYN, b""),
+            (true, 500, 101, SYN | ACK, b""),
+            // Retransmitted
+            (true, 500, 101, SYN | ACK, b""),
+            (false, 101, 501, ACK, b"hi"),
+            (false, 103, 501, FIN | ACK, b""),
+            (true, 501, 104, ACK, b"bye"),
+            (true, 504, 104, FIN | ACK, b""),
+        ];
+        let events: Vec<_> = side_data_from::<ConnectionLifecycle>(packets)
+            .iter()
+            .map(|e| (e.from_client, e.event))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (true, LifecycleEvent::Syn),
+                (false, LifecycleEvent::SynAck),
+                (true, LifecycleEvent::HalfClosed),
+                (false, LifecycleEvent::Fin),
+            ]
+        );
+        let closed = side_data_from::<ConnectionClosed>(packets);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].kind, CloseKind::Graceful);
+
+        let closed = side_data_from::<ConnectionClosed>(&[
+            (false, 100, 0, SYN, b""),
+            (true, 500, 101, SYN | ACK, b""),
+            (false, 101, 501, ACK, b"hi"),
+            (true, 501, 103, RST | ACK, b""),
+        ]);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].kind, CloseKind::Reset { from_client: false });
+    }
+
+    /// The same ports used for two connections one after the other.
+    #[test]
+    fn test_port_reuse() {
+        use side_data::{CloseKind, ConnectionClosed};
+
+        let target = sample_target();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let listener = TestListener {
+            received: received.clone(),
+        };
+        let mut sender = TcpSender::new(target, listener);
+        let packets: &[(bool, u32, u32, u8, &[u8])] = &[
+            (false, 100, 0, SYN, b""),
+            (true, 500, 101, SYN | ACK, b""),
+            (false, 101, 501, ACK, b"one"),
+            (false, 104, 501, FIN | ACK, b""),
+            (true, 501, 105, FIN | ACK, b""),
+            (false, 105, 502, ACK, b""),
+            // Both SYNs of the second connection
+            (false, 9000, 0, SYN, b""),
+            (false, 9000, 0, SYN, b""),
+            (true, 3000, 9001, SYN | ACK, b""),
+            (false, 9001, 3001, ACK, b"two"),
+        ];
+        for &(to_client, seq, ack, flags, data) in packets {
+            sender.send(to_client, seq, ack, flags, data);
+        }
+
+        let received = received.read().unwrap();
+        let epochs: Vec<_> = received
+            .iter()
+            .filter_map(|r| match r {
+                Received::Message(meta, data) => {
+                    Some((meta.timing.connection_epoch, data.to_vec()))
+                }
+                Received::SideData(_) => None,
+            })
+            .collect();
+        assert_eq!(epochs, vec![(0, b"one".to_vec()), (1, b"two".to_vec())]);
+        assert_eq!(sender.follower.flows[&target].epoch, 1);
+
+        // A connection that was never seen to close is ended by the next one
+        let closed = side_data_from::<ConnectionClosed>(&[
+            (false, 100, 0, SYN, b""),
+            (true, 500, 101, SYN | ACK, b""),
+            (false, 101, 501, ACK, b"one"),
+            (false, 9000, 0, SYN, b""),
+        ]);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].kind, CloseKind::Superseded);
+        assert_eq!(closed[0].timing.connection_epoch, 0);
+    }
+
+    #[test]
+    fn test_eviction() {
+        use side_data::{CloseKind, ConnectionClosed, EvictionReason, FlowEvicted};
+
+        const SECOND: Nanos = 1_000_000_000;
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let listener = SideDataListener {
+            received: received.clone(),
+        };
+        let follower = TcpFollower::default()
+            .with_idle_timeout(60 * SECOND)
+            .with_max_flows(2);
+        let mut sender = TcpSender::with_follower(follower, sample_target(), listener);
+        let mut open = |client_port: u16, at: Nanos| {
+            sender.target = IPTarget::V4 {
+                client_port,
+                server_port: 443,
+                client_ip: [10, 0, 0, 1].into(),
+                server_ip: [10, 0, 0, 2].into(),
+                overlay: None,
+            };
+            sender.timing.received_on_wire = at;
+            sender.send(false, 100, 0, SYN, b"");
+        };
+        open(40000, 0);
+        open(40001, 10 * SECOND);
+        // Over the limit: the oldest goes
+        open(40002, 20 * SECOND);
+        // Only the last is still within the idle timeout
+        open(40003, 75 * SECOND);
+
+        let evicted: Vec<_> = SideDataListener::find::<FlowEvicted>(&received)
+            .iter()
+            .map(|e| (e.target.client_port(), e.reason))
+            .collect();
+        assert_eq!(
+            evicted,
+            vec![
+                (40000, EvictionReason::TooManyFlows),
+                (40001, EvictionReason::Idle),
+            ]
+        );
+        let closed = SideDataListener::find::<ConnectionClosed>(&received);
+        assert_eq!(closed.len(), 2);
+        assert!(closed.iter().all(|c| c.kind == CloseKind::Evicted));
+        assert_eq!(closed[1].timing.received_on_wire, 10 * SECOND);
+        let mut ports: Vec<_> = sender
+            .follower
+            .flows
+            .keys()
+            .map(|t| t.client_port())
+            .collect();
+        ports.sort();
+        assert_eq!(ports, vec![40002, 40003]);
+    }
+
+    #[test]
+    fn test_reset_before_data() {
+        let failed = failures(&[
+            (false, 100, 0, SYN, b""),
+            (true, 500, 101, SYN | ACK, b""),
+            (false, 101, 501, ACK, b""),
+            (true, 501, 101, RST | ACK, b""),
+        ]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            failed[0].reason,
+            side_data::ConnectionFailure::ResetBeforeData
+        );
+
+        // Resets after data are just connections ending rudely
+        let failed = failures(&[
+            (false, 100, 0, SYN, b""),
+            (true, 500, 101, SYN | ACK, b""),
+            (false, 101, 501, ACK, b""),
+            (false, 101, 501, ACK, b"hi"),
+            (false, 103, 501, FIN | ACK, b""),
+            (true, 501, 104, RST | ACK, b""),
+        ]);
+        assert!(failed.is_empty());
+    }
+
+    /// Request and response pairs sent over one keep-alive connection.
+    const MESSAGES: [&[u8]; 4] = [
+        b"POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 11\r\n\r\nhello world",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nnya~\n",
+        b"GET /two HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot here\n",
+    ];
+
+    const CLIENT_ISN: u32 = 1000;
+    // Close to the end so that the server's stream wraps
+    const SERVER_ISN: u32 = u32::MAX - 50;
+
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct Transaction {
+        request: String,
+        request_body: Vec<u8>,
+        status: Option<u16>,
+        response_body: Vec<u8>,
+    }
+
+    fn transactions(received: &[Received<HTTPStreamEvent>]) -> Vec<Transaction> {
+        let mut by_id: BTreeMap<RequestId, Transaction> = BTreeMap::new();
+        for r in received {
+            let Received::Message(_, ev) = r else {
+                continue;
+            };
+            match ev {
+                HTTPStreamEvent::NewRequest(id, parts) => {
+                    by_id.entry(*id).or_default().request =
+                        format!("{} {}", parts.method, parts.uri);
+                }
+                HTTPStreamEvent::ReqBodyChunk(id, data) => {
+                    by_id.entry(*id).or_default().request_body.extend(data);
+                }
+                HTTPStreamEvent::NewResponse(id, parts) => {
+                    by_id.entry(*id).or_default().status = Some(parts.status.as_u16());
+                }
+                HTTPStreamEvent::RespBodyChunk(id, data) => {
+                    by_id.entry(*id).or_default().response_body.extend(data);
+                }
+                _ => {}
+            }
+        }
+        by_id.into_values().collect()
+    }
+
+    fn expected_transactions() -> Vec<Transaction> {
+        vec![
+            Transaction {
+                request: "POST /upload".into(),
+                request_body: b"hello world".to_vec(),
+                status: Some(200),
+                response_body: b"nya~\n".to_vec(),
+            },
+            Transaction {
+                request: "GET /two".into(),
+                request_body: vec![],
+                status: Some(404),
+                response_body: b"not here\n".to_vec(),
+            },
+        ]
+    }
+
+    /// Sends [`MESSAGES`] over a fresh connection through the reassembler
+    /// and HTTP decoder. `segments[i]` are the byte ranges of message `i` to
+    /// send as segments, in the order to send them.
+    fn run_transactions(segments: &[Vec<Range<usize>>]) -> Vec<Transaction> {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let tracker = HTTPRequestTracker::new(Box::new(TestListener {
+            received: received.clone(),
+        }));
+        let mut sender = TcpSender::new(sample_target(), tracker);
+
+        sender.send(false, CLIENT_ISN, 0, SYN, b"");
+        sender.send(true, SERVER_ISN, CLIENT_ISN + 1, SYN | ACK, b"");
+        sender.send(false, CLIENT_ISN + 1, SERVER_ISN.wrapping_add(1), ACK, b"");
+
+        // Stream offsets sent by the client and server respectively
+        let mut offsets = [0u32; 2];
+        for (i, (msg, ranges)) in MESSAGES.iter().zip(segments).enumerate() {
+            let to_client = i % 2 == 1;
+            let (isn, peer_isn) = if to_client {
+                (SERVER_ISN, CLIENT_ISN)
+            } else {
+                (CLIENT_ISN, SERVER_ISN)
+            };
+            let off = offsets[to_client as usize];
+            let ack = peer_isn
+                .wrapping_add(1)
+                .wrapping_add(offsets[!to_client as usize]);
+            for r in ranges {
+                let seq = isn.wrapping_add(1).wrapping_add(off + r.start as u32);
+                sender.send(to_client, seq, ack, ACK, &msg[r.clone()]);
+            }
+            offsets[to_client as usize] += msg.len() as u32;
+        }
+
+        let received = received.read().unwrap();
+        transactions(&received)
+    }
+
+    /// Cuts `0..len` into between 1 and 16 contiguous ranges, in order.
+    fn segmentation(len: usize) -> impl Strategy<Value = Vec<Range<usize>>> {
+        collection::btree_set(1..len, 0..len.min(16)).prop_map(move |cuts| {
+            let mut start = 0;
+            let mut out = Vec::new();
+            for cut in cuts.into_iter().chain([len]) {
+                out.push(start..cut);
+                start = cut;
+            }
+            out
+        })
+    }
+
+    /// Like [`segmentation`], but the segments arrive in any order.
+    fn reordering(len: usize) -> impl Strategy<Value = Vec<Range<usize>>> {
+        segmentation(len).prop_shuffle()
+    }
+
+    /// Like [`reordering`], with some segments sent again and some extra
+    /// segments overlapping the others.
+    fn retransmission(len: usize) -> impl Strategy<Value = Vec<Range<usize>>> {
+        (
+            segmentation(len),
+            collection::vec(any::<prop::sample::Index>(), 0..4),
+            collection::vec((0..len, 1..=len), 0..4),
+        )
+            .prop_map(move |(mut segs, dupes, overlaps)| {
+                let extra: Vec<_> = dupes.iter().map(|d| d.get(&segs).clone()).collect();
+                segs.extend(extra);
+                segs.extend(
+                    overlaps
+                        .into_iter()
+                        .map(|(start, seg_len)| start..(start + seg_len).min(len)),
+                );
+                segs
+            })
+            .prop_shuffle()
+    }
+
+    fn all_messages<S: Strategy<Value = Vec<Range<usize>>>>(
+        strategy: impl Fn(usize) -> S,
+    ) -> impl Strategy<Value = Vec<Vec<Range<usize>>>> {
+        MESSAGES
+            .iter()
+            .map(|m| strategy(m.len()))
+            .collect::<Vec<_>>()
+    }
+
+    proptest! {
+        fn segmented_transactions(segments in all_messages(segmentation)) {
+            prop_assert_eq!(run_transactions(&segments), expected_transactions());
+        }
+
+        fn reordered_transactions(segments in all_messages(reordering)) {
+            prop_assert_eq!(run_transactions(&segments), expected_transactions());
+        }
+
+        fn retransmitted_transactions(segments in all_messages(retransmission)) {
+            prop_assert_eq!(run_transactions(&segments), expected_transactions());
+        }
+    }
+
+    #[test]
+    fn test_unsegmented_transactions() {
+        let whole: Vec<_> = MESSAGES.iter().map(|m| vec![0..m.len()]).collect();
+        assert_eq!(run_transactions(&whole), expected_transactions());
+    }
+
+    #[test]
+    fn test_segmented_transactions() {
+        segmented_transactions();
+    }
+
+    #[test]
+    fn test_reordered_transactions() {
+        reordered_transactions();
+    }
+
+    #[test]
+    fn test_retransmitted_transactions() {
+        retransmitted_transactions();
+    }
+
     #[derive(Clone, Debug, PartialEq, Eq)]
     struct FakeSegment {
         seqno: SeqNum,
@@ -580,6 +1962,15 @@ mod test {
         fn sequence_number(&self) -> SeqNum {
             self.seqno
         }
+
+        fn segment_len(&self) -> SeqNum {
+            self.len
+        }
+
+        fn trim_front(&mut self, n: SeqNum) {
+            self.seqno += n;
+            self.len -= n;
+        }
     }
 
     #[derive(Clone, Debug, Default)]
@@ -613,4 +2004,156 @@ mod test {
             &tracer.seen
         );
     }
+
+    #[test]
+    fn test_held_segment_not_replaced_by_ack() {
+        let mut tracer = SegmentTracer::default();
+        let mut rb = TcpReorderBuffer::new(Wrapping(100));
+
+        rb.ingest(FakeSegment::new(110, 5), &mut tracer);
+        // A bare ACK from the same sender, after the segment
+        rb.ingest(FakeSegment::new(110, 0), &mut tracer);
+        rb.ingest(FakeSegment::new(100, 10), &mut tracer);
+        assert_eq!(
+            &vec![FakeSegment::new(100, 10), FakeSegment::new(110, 5)],
+            &tracer.seen
+        );
+    }
+
+    #[test]
+    fn test_stale_keys() {
+        for lowest in [100, 5, u32::MAX - 5, u32::MAX / 2 + 10] {
+            let mut rb = TcpReorderBuffer::<FakeSegment>::new(Wrapping(lowest));
+            for k in [
+                0,
+                1,
+                4,
+                50,
+                99,
+                100,
+                101,
+                u32::MAX / 2,
+                u32::MAX - 6,
+                u32::MAX,
+            ] {
+                rb.reassemble.insert(Wrapping(k), FakeSegment::new(k, 1));
+            }
+            let expected: Vec<_> = rb
+                .reassemble
+                .keys()
+                .filter(|&&k| seq_before(k, rb.lowest))
+                .copied()
+                .collect();
+            let mut stale = rb.stale_keys();
+            // In sequence order, which is not map order if it wraps.
+            assert!(
+                stale.windows(2).all(|w| seq_before(w[0], w[1])),
+                "{stale:?}"
+            );
+            stale.sort();
+            assert_eq!(stale, expected, "lowest {lowest}");
+        }
+    }
+
+    #[test]
+    fn test_retransmission_stats() {
+        let mut tracer = SegmentTracer::default();
+        let mut rb = TcpReorderBuffer::new(Wrapping(100));
+
+        rb.ingest(FakeSegment::new(100, 10), &mut tracer);
+        // Sent again in full
+        rb.ingest(FakeSegment::new(100, 10), &mut tracer);
+        // Half old, half new
+        rb.ingest(FakeSegment::new(105, 10), &mut tracer);
+        // Held, then extended by a longer segment at the same place
+        rb.ingest(FakeSegment::new(120, 2), &mut tracer);
+        rb.ingest(FakeSegment::new(120, 5), &mut tracer);
+        rb.ingest(FakeSegment::new(115, 5), &mut tracer);
+
+        assert_eq!(
+            &vec![
+                FakeSegment::new(100, 10),
+                FakeSegment::new(110, 5),
+                FakeSegment::new(115, 5),
+                FakeSegment::new(120, 2),
+                FakeSegment::new(122, 3),
+            ],
+            &tracer.seen
+        );
+        assert_eq!(
+            rb.stats(),
+            side_data::ReassemblyStats {
+                retransmitted_segments: 1,
+                overlapping_segments: 2,
+                duplicate_bytes: 17,
+            }
+        );
+    }
+
+    #[test]
+    fn test_data_before_handshake_completes() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let tracker = HTTPRequestTracker::new(Box::new(TestListener {
+            received: received.clone(),
+        }));
+        let mut sender = TcpSender::new(sample_target(), tracker);
+
+        let (req, resp) = (MESSAGES[2], MESSAGES[3]);
+        let (client, server) = (CLIENT_ISN + 1, SERVER_ISN.wrapping_add(1));
+        sender.send(false, CLIENT_ISN, 0, SYN, b"");
+        // The handshake's final ACK is lost, so the request completes it
+        sender.send(false, client, server, ACK, req);
+        // The response overtakes the SYN-ACK
+        sender.send(true, server, client + req.len() as u32, ACK, resp);
+        sender.send(true, SERVER_ISN, client, SYN | ACK, b"");
+
+        let received = received.read().unwrap();
+        assert_eq!(
+            transactions(&received),
+            vec![Transaction {
+                request: "GET /two".into(),
+                request_body: vec![],
+                status: Some(404),
+                response_body: b"not here\n".to_vec(),
+            }]
+        );
+    }
+
+    /// The request goes in the SYN, whether or not the server takes it
+    /// there or has the client send it again after the handshake.
+    #[test]
+    fn test_fast_open() {
+        for accepted in [true, false] {
+            let received = Arc::new(RwLock::new(Vec::new()));
+            let tracker = HTTPRequestTracker::new(Box::new(TestListener {
+                received: received.clone(),
+            }));
+            let mut sender = TcpSender::new(sample_target(), tracker);
+
+            let (req, resp) = (MESSAGES[2], MESSAGES[3]);
+            let (client, server) = (CLIENT_ISN + 1, SERVER_ISN.wrapping_add(1));
+            let after_req = client + req.len() as u32;
+            sender.send(false, CLIENT_ISN, 0, SYN, req);
+            if accepted {
+                sender.send(true, SERVER_ISN, after_req, SYN | ACK, b"");
+                sender.send(false, after_req, server, ACK, b"");
+            } else {
+                sender.send(true, SERVER_ISN, client, SYN | ACK, b"");
+                sender.send(false, client, server, ACK, req);
+            }
+            sender.send(true, server, after_req, ACK, resp);
+
+            let received = received.read().unwrap();
+            assert_eq!(
+                transactions(&received),
+                vec![Transaction {
+                    request: "GET /two".into(),
+                    request_body: vec![],
+                    status: Some(404),
+                    response_body: b"not here\n".to_vec(),
+                }],
+                "accepted: {accepted}"
+            );
+        }
+    }
 }
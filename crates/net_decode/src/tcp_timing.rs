@@ -0,0 +1,308 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Round trip times and throughput of TCP connections, as seen from wherever
+//! the capture was taken.
+//!
+//! The handshake splits the round trip in two: SYN to SYN-ACK is the time to
+//! the server and back, and SYN-ACK to ACK the time to the client and back.
+//! Taken together with when requests and responses were seen, this tells
+//! apart a slow network from a slow server.
+
+use std::num::Wrapping;
+
+use pktparse::tcp::TcpHeader;
+
+use crate::{listener::Nanos, tcp_reassemble::seq_before};
+
+pub mod side_data {
+    use crate::{
+        chomp::IPTarget,
+        listener::Nanos,
+        listener::TimingInfo,
+        tcp_window::side_data::{EcnStats, WindowStats},
+    };
+
+    /// Fired by `net_decode::tcp_reassemble` once the client ACKs the
+    /// SYN-ACK.
+    #[derive(Clone, Debug)]
+    pub struct HandshakeRtt {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        /// From the capture point to the server and back.
+        pub to_server: Nanos,
+        /// From the capture point to the client and back.
+        pub to_client: Nanos,
+    }
+
+    /// Fired by `net_decode::tcp_reassemble` just before
+    /// [`crate::tcp_reassemble::side_data::ConnectionClosed`].
+    #[derive(Clone, Debug)]
+    pub struct FlowPerformance {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub to_server: DirectionPerformance,
+        pub to_client: DirectionPerformance,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct DirectionPerformance {
+        /// Payload bytes, not counting retransmissions.
+        pub bytes: u64,
+        /// From the first byte of payload to the last.
+        pub duration: Nanos,
+        /// Bytes per second over `duration`.
+        pub average_throughput: u64,
+        /// Bytes per second over the busiest
+        /// [`super::THROUGHPUT_WINDOW`].
+        pub peak_throughput: u64,
+        /// Time from sending data to it being ACKed, measured from the
+        /// capture point. Retransmitted data is not timed.
+        pub rtt: Option<RttStats>,
+        pub window: WindowStats,
+        pub ecn: EcnStats,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct RttStats {
+        pub samples: u64,
+        pub min: Nanos,
+        pub max: Nanos,
+        /// Smoothed as in RFC 6298.
+        pub smoothed: Nanos,
+    }
+}
+
+use side_data::{DirectionPerformance, RttStats};
+
+/// What [`side_data::DirectionPerformance::peak_throughput`] is measured
+/// over.
+pub const THROUGHPUT_WINDOW: Nanos = 100_000_000;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+fn per_second(bytes: u64, over: Nanos) -> u64 {
+    if over == 0 {
+        return 0;
+    }
+    (bytes as u128 * NANOS_PER_SEC / over as u128) as u64
+}
+
+/// Data sent one way.
+#[derive(Debug, Default)]
+struct Direction {
+    bytes: u64,
+    first_data: Option<Nanos>,
+    last_data: Nanos,
+    window_start: Nanos,
+    window_bytes: u64,
+    peak_throughput: u64,
+    /// Sequence number just past the furthest data sent.
+    highest_end: Option<Wrapping<u32>>,
+    /// The end of the segment being timed, and when it was sent.
+    timed: Option<(Wrapping<u32>, Nanos)>,
+    rtt: Option<RttStats>,
+}
+
+impl Direction {
+    fn on_data(&mut self, now: Nanos, seq: u32, len: usize) {
+        let end = Wrapping(seq) + Wrapping(len as u32);
+        if let Some(highest) = self.highest_end {
+            if !seq_before(highest, end) {
+                // Karn's algorithm: the ACK may be for either copy.
+                if matches!(self.timed, Some((timed, _)) if !seq_before(timed, end)) {
+                    self.timed = None;
+                }
+                return;
+            }
+        }
+        self.highest_end = Some(end);
+        if self.timed.is_none() {
+            self.timed = Some((end, now));
+        }
+
+        self.bytes += len as u64;
+        self.first_data.get_or_insert(now);
+        self.last_data = now;
+        if now.saturating_sub(self.window_start) >= THROUGHPUT_WINDOW {
+            self.end_window();
+            self.window_start = now;
+        }
+        self.window_bytes += len as u64;
+    }
+
+    fn end_window(&mut self) {
+        self.peak_throughput = self
+            .peak_throughput
+            .max(per_second(self.window_bytes, THROUGHPUT_WINDOW));
+        self.window_bytes = 0;
+    }
+
+    fn on_ack(&mut self, now: Nanos, ack: u32) {
+        let Some((end, sent)) = self.timed else {
+            return;
+        };
+        if seq_before(Wrapping(ack), end) {
+            return;
+        }
+        self.timed = None;
+        let sample = now.saturating_sub(sent);
+        self.rtt = Some(match self.rtt {
+            None => RttStats {
+                samples: 1,
+                min: sample,
+                max: sample,
+                smoothed: sample,
+            },
+            Some(rtt) => RttStats {
+                samples: rtt.samples + 1,
+                min: rtt.min.min(sample),
+                max: rtt.max.max(sample),
+                smoothed: (rtt.smoothed * 7 + sample) / 8,
+            },
+        });
+    }
+
+    fn performance(&self) -> DirectionPerformance {
+        let duration = self
+            .first_data
+            .map_or(0, |first| self.last_data.saturating_sub(first));
+        DirectionPerformance {
+            bytes: self.bytes,
+            duration,
+            average_throughput: per_second(self.bytes, duration),
+            peak_throughput: self
+                .peak_throughput
+                .max(per_second(self.window_bytes, THROUGHPUT_WINDOW)),
+            rtt: self.rtt,
+            ..Default::default()
+        }
+    }
+}
+
+/// Times one TCP connection.
+#[derive(Debug, Default)]
+pub(crate) struct FlowTimer {
+    /// When the latest SYN and SYN-ACK were seen, since the other side
+    /// answers whichever copy it gets last.
+    syn: Option<Nanos>,
+    syn_ack: Option<Nanos>,
+    reported_handshake: bool,
+    /// Sent by the client and by the server.
+    directions: [Direction; 2],
+}
+
+impl FlowTimer {
+    /// Takes a segment carrying `data_len` bytes of payload. Returns the
+    /// round trip times to the server and to the client when it completes
+    /// the handshake.
+    pub(crate) fn on_segment(
+        &mut self,
+        now: Nanos,
+        from_client: bool,
+        tcp: &TcpHeader,
+        data_len: usize,
+    ) -> Option<(Nanos, Nanos)> {
+        let sender = if from_client { 0 } else { 1 };
+        if data_len > 0 {
+            // Fast Open data comes after the SYN's sequence number
+            let seq = tcp.sequence_no.wrapping_add(tcp.flag_syn as u32);
+            self.directions[sender].on_data(now, seq, data_len);
+        }
+        if tcp.flag_ack {
+            self.directions[1 - sender].on_ack(now, tcp.ack_no);
+        }
+
+        match (tcp.flag_syn, tcp.flag_ack, from_client) {
+            (true, false, true) => self.syn = Some(now),
+            (true, true, false) => self.syn_ack = Some(now),
+            (false, true, true) if !self.reported_handshake => {
+                if let (Some(syn), Some(syn_ack)) = (self.syn, self.syn_ack) {
+                    self.reported_handshake = true;
+                    return Some((syn_ack.saturating_sub(syn), now.saturating_sub(syn_ack)));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Sent by the client and by the server.
+    pub(crate) fn performance(&self) -> [DirectionPerformance; 2] {
+        [
+            self.directions[0].performance(),
+            self.directions[1].performance(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MS: Nanos = 1_000_000;
+
+    fn tcp(seq: u32, ack: u32, flags: u8) -> TcpHeader {
+        let mut raw = vec![0x9c, 0x40, 0x01, 0xbb];
+        raw.extend(seq.to_be_bytes());
+        raw.extend(ack.to_be_bytes());
+        raw.extend([5 << 4, flags, 0, 0, 0, 0, 0, 0]);
+        pktparse::tcp::parse_tcp_header(&raw).unwrap().1
+    }
+
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+
+    #[test]
+    fn test_handshake_rtt() {
+        let mut timer = FlowTimer::default();
+        assert_eq!(timer.on_segment(0, true, &tcp(100, 0, SYN), 0), None);
+        // Retransmitted SYN, which is what the server answers
+        assert_eq!(
+            timer.on_segment(1000 * MS, true, &tcp(100, 0, SYN), 0),
+            None
+        );
+        let syn_ack = tcp(500, 101, SYN | ACK);
+        assert_eq!(timer.on_segment(1030 * MS, false, &syn_ack, 0), None);
+        assert_eq!(
+            timer.on_segment(1035 * MS, true, &tcp(101, 501, ACK), 0),
+            Some((30 * MS, 5 * MS))
+        );
+        // Only once
+        assert_eq!(
+            timer.on_segment(1036 * MS, true, &tcp(101, 501, ACK), 0),
+            None
+        );
+    }
+
+    #[test]
+    fn test_data_rtt_and_throughput() {
+        let mut timer = FlowTimer::default();
+        // The server sends 1000 bytes every 50ms, the client ACKs 20ms later
+        for i in 0..10 {
+            let seq = 501 + i * 1000;
+            let sent = i as Nanos * 50 * MS;
+            timer.on_segment(sent, false, &tcp(seq, 101, ACK), 1000);
+            timer.on_segment(sent + 20 * MS, true, &tcp(101, seq + 1000, ACK), 0);
+        }
+        // Retransmission of the last segment, and its ACK, are not timed
+        timer.on_segment(500 * MS, false, &tcp(9501, 101, ACK), 1000);
+        timer.on_segment(900 * MS, true, &tcp(101, 10501, ACK), 0);
+
+        let [to_server, to_client] = timer.performance();
+        assert_eq!(to_server, DirectionPerformance::default());
+        assert_eq!(to_client.bytes, 10_000);
+        assert_eq!(to_client.duration, 450 * MS);
+        assert_eq!(to_client.average_throughput, 22_222);
+        assert_eq!(to_client.peak_throughput, 20_000);
+        assert_eq!(
+            to_client.rtt,
+            Some(RttStats {
+                samples: 10,
+                min: 20 * MS,
+                max: 20 * MS,
+                smoothed: 20 * MS,
+            })
+        );
+    }
+}
@@ -0,0 +1,283 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Receive windows and ECN marks of TCP connections, which say whether a
+//! slow transfer was held back by the receiver or by congestion on the way,
+//! rather than by the sender.
+
+use pktparse::tcp::TcpHeader;
+
+use crate::{listener::Nanos, tcp_reassemble::RawSegment};
+
+pub mod side_data {
+    use crate::{chomp::IPTarget, listener::Nanos, listener::TimingInfo};
+
+    /// Fired by `net_decode::tcp_reassemble` when a receiver that had
+    /// advertised a zero window opens it again.
+    #[derive(Clone, Debug)]
+    pub struct ZeroWindowStall {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        /// Whether the client was the one not accepting data.
+        pub from_client: bool,
+        /// How long the other side could not send.
+        pub duration: Nanos,
+    }
+
+    /// What the receiver of one direction of a connection advertised.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct WindowStats {
+        /// In bytes, after window scaling.
+        pub min_window: Option<u32>,
+        pub max_window: Option<u32>,
+        /// How many times the window closed.
+        pub zero_windows: u64,
+        /// How long it was closed for in total, not counting a stall still
+        /// going on when the connection ended.
+        pub zero_window_time: Nanos,
+    }
+
+    /// ECN marks (RFC 3168) on one direction of a connection, counted in
+    /// segments.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct EcnStats {
+        /// Sent as ECN capable.
+        pub ect: u64,
+        /// Marked as having met congestion on the way.
+        pub ce: u64,
+        /// Echoes of that congestion from the receiver.
+        pub ece: u64,
+        /// Times the sender said it slowed down in response.
+        pub cwr: u64,
+    }
+}
+
+use side_data::{EcnStats, WindowStats};
+
+const TCPOPT_EOL: u8 = 0;
+const TCPOPT_NOP: u8 = 1;
+const TCPOPT_WINDOW_SCALE: u8 = 3;
+/// RFC 7323 section 2.3.
+const MAX_WINDOW_SCALE: u8 = 14;
+
+const TCP_FLAG_ECE: u8 = 0x40;
+const TCP_FLAG_CWR: u8 = 0x80;
+const ECN_CE: u8 = 0b11;
+
+/// The window scale option in `options`, if any.
+fn window_scale(mut options: &[u8]) -> Option<u8> {
+    while let Some(&kind) = options.first() {
+        match kind {
+            TCPOPT_EOL => break,
+            TCPOPT_NOP => {
+                options = &options[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let len = match options.get(1) {
+            Some(&len) if len >= 2 => len as usize,
+            _ => break,
+        };
+        let option = options.get(..len)?;
+        if kind == TCPOPT_WINDOW_SCALE {
+            return Some((*option.get(2)?).min(MAX_WINDOW_SCALE));
+        }
+        options = &options[len..];
+    }
+    None
+}
+
+/// One end of a connection.
+#[derive(Debug, Default)]
+struct End {
+    /// From its SYN.
+    window_scale: Option<u8>,
+    /// Stats for data sent to it, whose window it advertises.
+    window: WindowStats,
+    /// Since when its window has been closed.
+    zero_since: Option<Nanos>,
+    /// Stats for data it sent.
+    ecn: EcnStats,
+}
+
+/// Watches the windows and ECN marks of one TCP connection.
+#[derive(Debug, Default)]
+pub(crate) struct FlowWindows {
+    /// The client and the server.
+    ends: [End; 2],
+}
+
+impl FlowWindows {
+    /// Takes a segment. Returns how long the sender's window was closed if
+    /// this opens it again.
+    pub(crate) fn on_segment(
+        &mut self,
+        now: Nanos,
+        from_client: bool,
+        tcp: &TcpHeader,
+        raw: &RawSegment,
+    ) -> Option<Nanos> {
+        let sender = if from_client { 0 } else { 1 };
+        if tcp.flag_syn {
+            // Window scaling is only on if both ends offer it, and the
+            // window of a SYN is never scaled.
+            self.ends[sender].window_scale = window_scale(raw.options);
+            return None;
+        }
+        if tcp.flag_rst {
+            return None;
+        }
+
+        let ecn = &mut self.ends[sender].ecn;
+        match raw.ecn {
+            0 => {}
+            ECN_CE => ecn.ce += 1,
+            _ => ecn.ect += 1,
+        }
+        if raw.flags & TCP_FLAG_CWR != 0 {
+            ecn.cwr += 1;
+        }
+        if raw.flags & TCP_FLAG_ECE != 0 {
+            self.ends[1 - sender].ecn.ece += 1;
+        }
+
+        let shift = match (self.ends[0].window_scale, self.ends[1].window_scale) {
+            (Some(_), Some(_)) => self.ends[sender].window_scale.unwrap(),
+            _ => 0,
+        };
+        let window = (tcp.window as u32) << shift;
+        let end = &mut self.ends[sender];
+        let stats = &mut end.window;
+        stats.min_window = Some(stats.min_window.map_or(window, |w| w.min(window)));
+        stats.max_window = Some(stats.max_window.map_or(window, |w| w.max(window)));
+        match (window, end.zero_since) {
+            (0, None) => {
+                stats.zero_windows += 1;
+                end.zero_since = Some(now);
+            }
+            (0, Some(_)) => {}
+            (_, Some(since)) => {
+                end.zero_since = None;
+                let duration = now.saturating_sub(since);
+                stats.zero_window_time += duration;
+                return Some(duration);
+            }
+            (_, None) => {}
+        }
+        None
+    }
+
+    /// For data sent by the client and by the server.
+    pub(crate) fn stats(&self) -> [(WindowStats, EcnStats); 2] {
+        [
+            (self.ends[1].window, self.ends[0].ecn),
+            (self.ends[0].window, self.ends[1].ecn),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MS: Nanos = 1_000_000;
+
+    fn tcp(flags: u8, window: u16) -> TcpHeader {
+        let mut raw = vec![0x9c, 0x40, 0x01, 0xbb, 0, 0, 0, 1, 0, 0, 0, 1];
+        raw.extend([5 << 4, flags]);
+        raw.extend(window.to_be_bytes());
+        raw.extend([0; 4]);
+        pktparse::tcp::parse_tcp_header(&raw).unwrap().1
+    }
+
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+
+    #[test]
+    fn test_zero_window_stall() {
+        let mut windows = FlowWindows::default();
+        let scale = |shift| [TCPOPT_NOP, TCPOPT_WINDOW_SCALE, 3, shift];
+        let (client_scale, server_scale) = (scale(2), scale(7));
+        let syn = RawSegment {
+            options: &client_scale,
+            ..Default::default()
+        };
+        let syn_ack = RawSegment {
+            options: &server_scale,
+            ..Default::default()
+        };
+        let plain = RawSegment::default();
+
+        windows.on_segment(0, true, &tcp(SYN, 64240), &syn);
+        windows.on_segment(MS, false, &tcp(SYN | ACK, 65535), &syn_ack);
+        assert_eq!(
+            windows.on_segment(2 * MS, true, &tcp(ACK, 1000), &plain),
+            None
+        );
+        // The client stops reading
+        assert_eq!(windows.on_segment(3 * MS, true, &tcp(ACK, 0), &plain), None);
+        assert_eq!(
+            windows.on_segment(50 * MS, true, &tcp(ACK, 0), &plain),
+            None
+        );
+        assert_eq!(
+            windows.on_segment(203 * MS, true, &tcp(ACK, 500), &plain),
+            Some(200 * MS)
+        );
+        windows.on_segment(204 * MS, false, &tcp(ACK, 10), &plain);
+
+        let [(to_server, _), (to_client, _)] = windows.stats();
+        assert_eq!(
+            to_client,
+            WindowStats {
+                min_window: Some(0),
+                max_window: Some(4000),
+                zero_windows: 1,
+                zero_window_time: 200 * MS,
+            }
+        );
+        assert_eq!(to_server.max_window, Some(1280));
+    }
+
+    #[test]
+    fn test_ecn_marks() {
+        let mut windows = FlowWindows::default();
+        let ce = RawSegment {
+            ecn: ECN_CE,
+            ..Default::default()
+        };
+        let ect = RawSegment {
+            ecn: 0b10,
+            ..Default::default()
+        };
+        let ece = RawSegment {
+            flags: ACK | TCP_FLAG_ECE,
+            ..Default::default()
+        };
+        let cwr = RawSegment {
+            ecn: 0b10,
+            flags: ACK | TCP_FLAG_CWR,
+            ..Default::default()
+        };
+        // Server data meets congestion, the client echoes it and the
+        // server slows down
+        windows.on_segment(0, false, &tcp(ACK, 100), &ect);
+        windows.on_segment(0, false, &tcp(ACK, 100), &ce);
+        windows.on_segment(0, true, &tcp(ACK, 100), &ece);
+        windows.on_segment(0, false, &tcp(ACK, 100), &cwr);
+
+        let [(_, from_client), (_, from_server)] = windows.stats();
+        assert_eq!(from_client, EcnStats::default());
+        assert_eq!(
+            from_server,
+            EcnStats {
+                ect: 2,
+                ce: 1,
+                ece: 1,
+                cwr: 1,
+            }
+        );
+    }
+}
@@ -9,15 +9,21 @@ use std::{
     sync::{Arc, RwLock},
 };
 
+use bytes::Bytes;
+use pcap_parser::Linktype;
+use pktparse::tcp::TcpHeader;
+
 use crate::{
-    chomp::{EthernetChomper, FrameChomper},
+    checksum,
+    chomp::{EthernetChomper, FrameChomper, IPTarget, IPPROTO_UDP},
     chomper,
     dispatch::{self, ListenerDispatcher},
     http::HTTPStreamEvent,
     key_db::KeyDB,
+    link::ETHERTYPE_IPV4,
     listener::{Listener, MessageMeta, SideData, TimingInfo},
-    tcp_reassemble::TcpFollower,
-    tls::TLSFlowTracker,
+    tcp_reassemble::{RawSegment, TcpFollower},
+    tls::{side_data, TLSFlowTracker},
 };
 
 pub static NYA_DSB: &'static [u8] = include_bytes!("../corpus/nya-dsb.pcapng");
@@ -27,13 +33,18 @@ pub static H2_BIG_HEADERS: &'static [u8] = include_bytes!("../corpus/http2-big-h
 pub static TLS13_SESSION_RESUMPTION: &'static [u8] =
     include_bytes!("../corpus/tls13-session-resumption.pcapng");
 pub static H1_UNENCRYPTED: &'static [u8] = include_bytes!("../corpus/http-80.pcapng");
+pub static TLS12_MTLS: &'static [u8] = include_bytes!("../corpus/tls12-mtls.pcapng");
+pub static TLS12_RSA: &'static [u8] = include_bytes!("../corpus/tls12-rsa.pcapng");
+pub static TLS12_RSA_CBC: &'static [u8] = include_bytes!("../corpus/tls12-rsa-cbc.pcapng");
+/// The server key for [`TLS12_RSA`] and [`TLS12_RSA_CBC`].
+pub static TLS12_SERVER_KEY: &'static str = include_str!("../corpus/tls12-server.key");
 
 pub enum Received<T> {
     Message(MessageMeta, T),
     SideData(Box<dyn SideData>),
 }
 
-impl fmt::Display for Received<Vec<u8>> {
+impl fmt::Display for Received<Bytes> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Received::Message(_meta, d) => write!(f, "Message:\n{}", hexdump::HexDumper::new(&d)),
@@ -113,15 +124,69 @@ impl<T: Send + Sync> Listener<T> for TestListener<T> {
     }
 }
 
+/// Passes on only the side data that affects decoding, for snapshot tests.
+/// Purely informational side data is checked by dedicated tests using
+/// [`SideDataListener`] so that adding more of it does not churn every
+/// snapshot.
+pub struct Snapshotted<L>(pub L);
+
+fn is_snapshotted(data: &(dyn SideData + 'static)) -> bool {
+    data.is::<side_data::NewKeyReceived>() || data.is::<side_data::ALPNCompleted>()
+}
+
+impl<T, L: Listener<T>> Listener<T> for Snapshotted<L> {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: T) {
+        self.0.on_data(timing, target, to_client, data)
+    }
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        if is_snapshotted(&*data) {
+            self.0.on_side_data(data)
+        }
+    }
+}
+
+/// Listener that records all the side data it sees and ignores messages.
+pub struct SideDataListener {
+    pub received: Arc<RwLock<Vec<Box<dyn SideData>>>>,
+}
+
+impl SideDataListener {
+    /// Finds all the received side data of a given type.
+    pub fn find<T: Clone + 'static>(received: &RwLock<Vec<Box<dyn SideData>>>) -> Vec<T> {
+        received
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|sd| sd.downcast_ref::<T>().cloned())
+            .collect()
+    }
+}
+
+impl<T> Listener<T> for SideDataListener {
+    fn on_data(&mut self, _timing: TimingInfo, _target: IPTarget, _to_client: bool, _data: T) {}
+
+    fn on_side_data(&mut self, data: Box<dyn SideData>) {
+        self.received.write().unwrap().push(data);
+    }
+}
+
 #[derive(Default)]
 pub struct KeyMessageReorderer {
-    packets: Vec<(TimingInfo, Vec<u8>)>,
-    keys: Vec<Vec<u8>>,
+    pub packets: Vec<(TimingInfo, Linktype, Vec<u8>)>,
+    pub keys: Vec<Vec<u8>>,
+    /// WireGuard key logs, sent along with the TLS keys.
+    pub wireguard_keys: Vec<Vec<u8>>,
 }
 
 impl FrameChomper for KeyMessageReorderer {
-    fn chomp(&mut self, timing: TimingInfo, packet: &[u8]) -> Result<(), crate::Error> {
-        self.packets.push((timing, packet.to_vec()));
+    fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        link_type: Linktype,
+        packet: &[u8],
+    ) -> Result<(), crate::Error> {
+        self.packets.push((timing, link_type, packet.to_vec()));
         Ok(())
     }
 
@@ -129,6 +194,10 @@ impl FrameChomper for KeyMessageReorderer {
         self.keys.push(dsb.to_vec());
     }
 
+    fn on_wireguard_keys(&mut self, key_log: &[u8]) {
+        self.wireguard_keys.push(key_log.to_vec());
+    }
+
     fn on_key(
         &mut self,
         _client_random: crate::key_db::ClientRandom,
@@ -145,21 +214,34 @@ impl KeyMessageReorderer {
         for key in &self.keys {
             recv.on_keys(&key)
         }
+        for key_log in &self.wireguard_keys {
+            recv.on_wireguard_keys(key_log)
+        }
+
+        for (timing, link_type, pkt) in &self.packets {
+            recv.chomp(timing.clone(), *link_type, &pkt)?;
+        }
+        Ok(())
+    }
 
-        for (timing, pkt) in &self.packets {
-            recv.chomp(timing.clone(), &pkt)?;
+    pub fn send_without_keys(&self, recv: &mut impl FrameChomper) -> Result<(), crate::Error> {
+        for (timing, link_type, pkt) in &self.packets {
+            recv.chomp(timing.clone(), *link_type, &pkt)?;
         }
         Ok(())
     }
 
     pub fn send_late_keys(&self, recv: &mut impl FrameChomper) -> Result<(), crate::Error> {
-        for (timing, pkt) in &self.packets {
-            recv.chomp(timing.clone(), &pkt)?;
+        for (timing, link_type, pkt) in &self.packets {
+            recv.chomp(timing.clone(), *link_type, &pkt)?;
         }
 
         for key in &self.keys {
             recv.on_keys(&key)
         }
+        for key_log in &self.wireguard_keys {
+            recv.on_wireguard_keys(key_log)
+        }
 
         Ok(())
     }
@@ -177,12 +259,18 @@ where
     expected.assert_eq(&actual);
 }
 
-pub fn raw_chomper<Recv: Listener<Vec<u8>>>(
+pub fn raw_chomper<Recv: Listener<Bytes>>(
     key_db: Arc<RwLock<KeyDB>>,
     recv: Recv,
 ) -> EthernetChomper<Recv> {
     EthernetChomper {
         tcp_follower: TcpFollower::default(),
+        udp_follower: Default::default(),
+        sctp_follower: Default::default(),
+        fragments: Default::default(),
+        link_stats: Default::default(),
+        wireguard: Default::default(),
+        checksums: Default::default(),
         recv,
         key_db: key_db.clone(),
     }
@@ -190,21 +278,24 @@ pub fn raw_chomper<Recv: Listener<Vec<u8>>>(
 
 pub fn tls_chomper(
     key_db: Arc<RwLock<KeyDB>>,
-    received: Arc<RwLock<Vec<Received<Vec<u8>>>>>,
+    received: Arc<RwLock<Vec<Received<Bytes>>>>,
 ) -> EthernetChomper<TLSFlowTracker> {
     raw_chomper(
         key_db.clone(),
-        TLSFlowTracker::new(key_db, Box::new(TestListener { received })),
+        TLSFlowTracker::new(key_db, Box::new(Snapshotted(TestListener { received }))),
     )
 }
 
 pub fn tls_chomper_dispatch(
     key_db: Arc<RwLock<KeyDB>>,
-    received: Arc<RwLock<Vec<Received<Vec<u8>>>>>,
+    received: Arc<RwLock<Vec<Received<Bytes>>>>,
 ) -> EthernetChomper<ListenerDispatcher> {
     let dispatch = dispatch::ListenerDispatcher::default().add(
         443,
-        TLSFlowTracker::new(key_db.clone(), Box::new(TestListener { received })),
+        TLSFlowTracker::new(
+            key_db.clone(),
+            Box::new(Snapshotted(TestListener { received })),
+        ),
     );
 
     raw_chomper(key_db, dispatch)
@@ -214,5 +305,122 @@ pub fn http_chomper(
     key_db: Arc<RwLock<KeyDB>>,
     received: Arc<RwLock<Vec<Received<HTTPStreamEvent>>>>,
 ) -> EthernetChomper<ListenerDispatcher> {
-    chomper(TestListener { received }, key_db)
+    chomper(Snapshotted(TestListener { received }), key_db)
+}
+
+/// The connection from 10.0.0.1:40000 to 10.0.0.2:443 that tests use when
+/// any one will do. [`ipv4_frame`] is between the same addresses.
+pub fn sample_target() -> IPTarget {
+    IPTarget::V4 {
+        client_port: 40000,
+        server_port: 443,
+        client_ip: [10, 0, 0, 1].into(),
+        server_ip: [10, 0, 0, 2].into(),
+        overlay: None,
+    }
+}
+
+/// An Ethernet frame carrying an IPv4 packet between 10.0.0.1 and 10.0.0.2,
+/// sent by 10.0.0.2 if `reply`.
+pub fn ipv4_frame(reply: bool, proto: u8, payload: &[u8]) -> Vec<u8> {
+    let (src, dst) = if reply { (2, 1) } else { (1, 2) };
+    let mut ip = vec![0x45, 0];
+    ip.extend((20 + payload.len() as u16).to_be_bytes());
+    ip.extend([0, 0, 0x40, 0, 64, proto, 0, 0]);
+    ip.extend([10, 0, 0, src, 10, 0, 0, dst]);
+    let sum = checksum::ipv4_header_checksum(&ip);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    let mut out = vec![0x02, 0, 0, 0, 0, dst, 0x02, 0, 0, 0, 0, src];
+    out.extend(ETHERTYPE_IPV4.to_be_bytes());
+    out.extend(ip);
+    out.extend(payload);
+    out
+}
+
+/// [`ipv4_frame`] with a UDP datagram in it, without a checksum.
+pub fn udp_frame(reply: bool, src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut udp = src_port.to_be_bytes().to_vec();
+    udp.extend(dst_port.to_be_bytes());
+    udp.extend((8 + payload.len() as u16).to_be_bytes());
+    udp.extend([0, 0]);
+    udp.extend(payload);
+    ipv4_frame(reply, IPPROTO_UDP, &udp)
+}
+
+/// Feeds made-up segments of the connection `target` to a [`TcpFollower`],
+/// to test reassembly without building whole packets.
+pub struct TcpSender<L> {
+    pub follower: TcpFollower,
+    pub target: IPTarget,
+    /// Of the segments sent from now on.
+    pub timing: TimingInfo,
+    pub recv: L,
+}
+
+impl<L: Listener<Bytes>> TcpSender<L> {
+    pub fn new(target: IPTarget, recv: L) -> Self {
+        Self::with_follower(TcpFollower::default(), target, recv)
+    }
+
+    pub fn with_follower(follower: TcpFollower, target: IPTarget, recv: L) -> Self {
+        TcpSender {
+            follower,
+            target,
+            timing: TimingInfo::default(),
+            recv,
+        }
+    }
+
+    /// Sends a segment with the TCP `flags`, from the server if
+    /// `to_client`.
+    pub fn send(&mut self, to_client: bool, seq: u32, ack: u32, flags: u8, data: &[u8]) {
+        self.send_raw(to_client, seq, ack, flags, &RawSegment::default(), data)
+    }
+
+    /// [`TcpSender::send`], with the options and such of `raw`.
+    pub fn send_raw(
+        &mut self,
+        to_client: bool,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        raw: &RawSegment,
+        data: &[u8],
+    ) {
+        let tcp = tcp_header(&self.target, to_client, seq, ack, flags);
+        let target = if to_client {
+            self.target.flip()
+        } else {
+            self.target
+        };
+        self.follower
+            .record_flow(
+                self.timing.clone(),
+                &target,
+                &tcp,
+                raw,
+                data,
+                &mut self.recv,
+            )
+            .unwrap();
+    }
+}
+
+fn tcp_header(target: &IPTarget, to_client: bool, seq: u32, ack: u32, flags: u8) -> TcpHeader {
+    let (src, dst) = if to_client {
+        (target.server_port(), target.client_port())
+    } else {
+        (target.client_port(), target.server_port())
+    };
+    let mut raw = Vec::new();
+    raw.extend(src.to_be_bytes());
+    raw.extend(dst.to_be_bytes());
+    raw.extend(seq.to_be_bytes());
+    raw.extend(ack.to_be_bytes());
+    // data offset 5 words, then flags
+    raw.extend([5 << 4, flags]);
+    // window, checksum, urgent pointer
+    raw.extend([0u8; 6]);
+    pktparse::tcp::parse_tcp_header(&raw).unwrap().1
 }
@@ -12,24 +12,34 @@ use std::{
     sync::{Arc, RwLock},
 };
 
+use bytes::Bytes;
 use rustls_intercept::{
     internal::{
         key_schedule::{KeyScheduleHandshake, KeyScheduleTraffic},
         msgs::{
             deframer::{Deframed, MessageDeframer},
-            handshake::{HandshakeMessagePayload, HandshakePayload},
+            enums::AlertLevel,
+            handshake::{
+                HandshakeMessagePayload, HandshakePayload, KeyExchangeAlgorithm, ServerHelloPayload,
+            },
             message::{Message, MessagePayload, PlainMessage},
         },
+        tls12::{ConnectionSecrets, PSK_SUITES, STATIC_RSA_SUITES},
     },
-    msgs::handshake::ServerExtension,
-    require_handshake_msg, CommonState, Error as RustlsError, HandshakeType, Side,
-    SupportedCipherSuite, Tls13CipherSuite, ALL_CIPHER_SUITES,
+    msgs::handshake::{HasServerExtensions, ServerExtension, ServerNamePayload},
+    require_handshake_msg, CipherSuite, CommonState, Error as RustlsError, HandshakeType, Side,
+    SupportedCipherSuite, Tls12CipherSuite, Tls13CipherSuite, ALL_CIPHER_SUITES,
 };
 
 use crate::{
+    certificate::CertificateInfo,
     chomp::IPTarget,
-    key_db::{ClientRandom, KeyDB, SecretType},
-    listener::{Listener, MessageMeta, SideData, TimingInfo},
+    diagnostic::{report, Layer, Severity},
+    fingerprint,
+    key_db::{ClientRandom, KeyDB, Secret, SecretType},
+    listener::{Listener, MessageMeta, SideData, SideDataHandler, TimingInfo},
+    psk::{self, Tls13KeySchedule},
+    tcp_reassemble::side_data::{ConnectionClosed, Truncated},
 };
 
 pub mod timings {
@@ -37,12 +47,16 @@ pub mod timings {
 }
 
 pub mod side_data {
+    pub use rustls_intercept::{internal::msgs::enums::AlertLevel, AlertDescription};
+
     use crate::{
+        certificate::CertificateInfo,
         chomp::IPTarget,
         key_db::{ClientRandom, Secret, SecretType},
+        listener::TimingInfo,
     };
 
-    use super::ProtocolName;
+    use super::{ProtocolName, TlsVersion};
 
     /// Expected to be fed into the stack when a new key is received by the
     /// keys service. The TLS decoding will use these messages to dequeue any
@@ -55,12 +69,137 @@ pub mod side_data {
     }
 
     /// Fired by `net_decode::tls` when an EncryptedExtensions message
-    /// with ALPN data is received by the client.
+    /// with ALPN data is received by the client, or, for TLS 1.2, once we
+    /// have keys for a flow that negotiated ALPN in the ServerHello.
     #[derive(Clone, Debug)]
     pub struct ALPNCompleted {
         pub target: IPTarget,
         pub protocols: Vec<ProtocolName>,
     }
+
+    /// Fired by `net_decode::tls` when a ClientHello is seen, regardless of
+    /// whether we have keys for the flow.
+    #[derive(Clone, Debug)]
+    pub struct ClientHelloSeen {
+        pub target: IPTarget,
+        pub client_random: ClientRandom,
+        pub server_name: Option<String>,
+        pub offered_protocols: Vec<ProtocolName>,
+        pub offered_versions: Vec<TlsVersion>,
+    }
+
+    /// Fired by `net_decode::tls` when a ServerHello is seen, regardless of
+    /// whether we have keys for the flow.
+    ///
+    /// `protocol` is only known here for TLS 1.2 and below: TLS 1.3 sends
+    /// ALPN in EncryptedExtensions, which shows up as [`ALPNCompleted`] if
+    /// the flow can be decrypted.
+    #[derive(Clone, Debug)]
+    pub struct ServerHelloSeen {
+        pub target: IPTarget,
+        pub version: TlsVersion,
+        pub cipher_suite: u16,
+        pub protocol: Option<ProtocolName>,
+    }
+
+    /// Fired by `net_decode::tls` alongside [`ClientHelloSeen`] with the
+    /// client's JA3 and JA4 fingerprints.
+    #[derive(Clone, Debug)]
+    pub struct ClientFingerprint {
+        pub target: IPTarget,
+        pub ja3: String,
+        pub ja3_hash: String,
+        pub ja4: String,
+    }
+
+    /// Fired by `net_decode::tls` alongside [`ServerHelloSeen`] with the
+    /// server's JA3S fingerprint.
+    #[derive(Clone, Debug)]
+    pub struct ServerFingerprint {
+        pub target: IPTarget,
+        pub ja3s: String,
+        pub ja3s_hash: String,
+    }
+
+    /// Fired by `net_decode::tls` with the certificate chain the server
+    /// presented, leaf first. `server_name` is the SNI the client asked for.
+    ///
+    /// For TLS 1.3, only emitted for flows we can decrypt, since it encrypts
+    /// the server's Certificate message.
+    #[derive(Clone, Debug)]
+    pub struct ServerCertificate {
+        pub target: IPTarget,
+        pub server_name: Option<String>,
+        pub chain: Vec<CertificateInfo>,
+    }
+
+    /// Fired by `net_decode::tls` when the client presents a certificate
+    /// (mTLS), either during the handshake or in response to a
+    /// post-handshake CertificateRequest. The leaf comes first.
+    ///
+    /// For TLS 1.3, only emitted for flows we can decrypt, since it encrypts
+    /// the client's Certificate message.
+    #[derive(Clone, Debug)]
+    pub struct ClientCertificate {
+        pub target: IPTarget,
+        pub chain: Vec<CertificateInfo>,
+    }
+
+    /// Fired by `net_decode::tls` for each alert record. Alerts sent before
+    /// the handshake is encrypted are always seen; later ones (including
+    /// most close_notify) only if we have keys.
+    ///
+    /// After a fatal alert, the flow is no longer followed.
+    #[derive(Clone, Debug)]
+    pub struct TlsAlert {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub to_client: bool,
+        pub level: AlertLevel,
+        pub description: AlertDescription,
+    }
+
+    /// Fired by `net_decode::tls` the first time a flow stalls for lack of
+    /// keys, so that traffic we cannot read is still attributed to a host.
+    ///
+    /// Keys may still arrive later, in which case the decrypted data follows
+    /// as usual.
+    #[derive(Clone, Debug)]
+    pub struct OpaqueFlow {
+        pub target: IPTarget,
+        pub client_random: ClientRandom,
+        pub server_name: Option<String>,
+    }
+
+    /// Fired by `net_decode::tls` when a flow could not be decoded for a
+    /// reason other than missing keys, after which it is not followed any
+    /// further.
+    #[derive(Clone, Debug)]
+    pub struct TlsDecodeFailed {
+        pub target: IPTarget,
+        pub kind: DecodeFailureKind,
+        pub error: String,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum DecodeFailureKind {
+        /// We have the keys, but not an implementation of the cipher suite
+        /// the server picked.
+        UnsupportedCipherSuite,
+        /// Anything else, such as data that fails to decrypt or parse.
+        Other,
+    }
+
+    /// Fired by `net_decode::tls` when more data piled up waiting for the
+    /// keys of a flow than we are willing to hold. The queued data is
+    /// dropped and the flow is not followed any further, even if the keys
+    /// show up later.
+    #[derive(Clone, Debug)]
+    pub struct PendingKeysDropped {
+        pub target: IPTarget,
+        pub client_random: ClientRandom,
+        pub dropped_bytes: usize,
+    }
 }
 
 #[derive(Clone)]
@@ -74,10 +213,28 @@ impl fmt::Debug for ProtocolName {
     }
 }
 
+/// TLS protocol version as it appears on the wire.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TlsVersion(pub u16);
+
+impl fmt::Debug for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0x0300 => write!(f, "SSLv3"),
+            0x0301 => write!(f, "TLSv1.0"),
+            0x0302 => write!(f, "TLSv1.1"),
+            0x0303 => write!(f, "TLSv1.2"),
+            0x0304 => write!(f, "TLSv1.3"),
+            v => write!(f, "TlsVersion({v:#06x})"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 enum TLSDecodeError {
     MissingKey(ClientRandom),
-    UnknownCipherSuite,
+    /// Also all the CBC suites, since ring has no CBC mode.
+    UnknownCipherSuite(CipherSuite),
     RustlsError(RustlsError),
 }
 
@@ -88,7 +245,7 @@ impl fmt::Display for TLSDecodeError {
                 f,
                 "Missing a key to decode this flow with client_random {cr}"
             ),
-            Self::UnknownCipherSuite => write!(f, "Cipher suite is not supported"),
+            Self::UnknownCipherSuite(suite) => write!(f, "Cipher suite {suite:?} is not supported"),
             Self::RustlsError(e) => fmt::Display::fmt(e, f),
         }
     }
@@ -143,8 +300,9 @@ impl TLSSide {
 
 struct CommonData<'a> {
     key_db: &'a KeyDB,
+    target: IPTarget,
     next: &'a mut dyn FnMut(bool, Vec<u8>),
-    on_alpn_completed: &'a mut dyn FnMut(Vec<ProtocolName>),
+    on_side_data: &'a mut dyn FnMut(Box<dyn SideData>),
 }
 
 macro_rules! try_giving_back {
@@ -171,6 +329,10 @@ trait TLSState: std::fmt::Debug + Send + Sync {
     fn blocked_on_keys(&self) -> Option<ClientRandom> {
         None
     }
+
+    fn is_failed(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
@@ -186,6 +348,10 @@ impl TLSState for Failed {
     ) -> NextStateOrError {
         Ok(self)
     }
+
+    fn is_failed(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Debug)]
@@ -194,10 +360,10 @@ struct ExpectClientHello {}
 impl TLSState for ExpectClientHello {
     fn drive(
         self: Box<Self>,
-        _flow: &mut TLSFlow,
+        flow: &mut TLSFlow,
         to_client: bool,
         msg: &Message,
-        _common_data: CommonData<'_>,
+        common_data: CommonData<'_>,
     ) -> NextStateOrError {
         if to_client {
             // should never happen?
@@ -216,8 +382,65 @@ impl TLSState for ExpectClientHello {
                 )
             );
 
+            let client_random: ClientRandom = chp.random.into();
+            let server_name = chp.get_sni_extension().and_then(|names| {
+                names.iter().find_map(|name| match name.payload {
+                    ServerNamePayload::HostName((ref raw, _)) => {
+                        Some(String::from_utf8_lossy(&raw.0).into_owned())
+                    }
+                    ServerNamePayload::Unknown(_) => None,
+                })
+            });
+            let offered_protocols = chp
+                .get_alpn_extension()
+                .map(|protos| {
+                    protos
+                        .iter()
+                        .map(|p| ProtocolName(p.as_ref().to_vec()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let offered_versions = match chp.get_versions_extension() {
+                Some(versions) => versions.iter().map(|v| TlsVersion(v.get_u16())).collect(),
+                None => vec![TlsVersion(chp.client_version.get_u16())],
+            };
+
+            flow.server_name = server_name.clone();
+            flow.client_random = Some(client_random.clone());
+            (common_data.on_side_data)(Box::new(side_data::ClientHelloSeen {
+                target: common_data.target,
+                client_random: client_random.clone(),
+                server_name,
+                offered_protocols,
+                offered_versions,
+            }));
+
+            let (ja3, ja3_hash) = fingerprint::ja3(chp);
+            (common_data.on_side_data)(Box::new(side_data::ClientFingerprint {
+                target: common_data.target,
+                ja3,
+                ja3_hash,
+                ja4: fingerprint::ja4(chp),
+            }));
+
+            let client_hello = match msg.payload {
+                MessagePayload::Handshake { ref encoded, .. } => encoded.0.clone(),
+                _ => Vec::new(),
+            };
+            let psk_identities = chp
+                .get_psk()
+                .map(|offer| {
+                    offer
+                        .identities
+                        .iter()
+                        .map(|id| id.identity.0.clone())
+                        .collect()
+                })
+                .unwrap_or_default();
             let new_state = Box::new(ExpectServerHello {
-                client_random: chp.random.into(),
+                client_random,
+                client_hello,
+                psk_identities,
             });
 
             Ok(new_state)
@@ -225,9 +448,41 @@ impl TLSState for ExpectClientHello {
     }
 }
 
-#[derive(Debug)]
 struct ExpectServerHello {
     client_random: ClientRandom,
+    /// Encoded ClientHello, for the TLS 1.2 extended master secret and
+    /// TLS 1.3 PSK key schedule.
+    client_hello: Vec<u8>,
+    /// PSK identities offered in the ClientHello.
+    psk_identities: Vec<Vec<u8>>,
+}
+
+impl ExpectServerHello {
+    /// The key schedule at the handshake secret, if the server picked one
+    /// of our external PSKs without (EC)DHE.
+    fn psk_key_schedule(
+        &self,
+        suite: &'static Tls13CipherSuite,
+        shp: &ServerHelloPayload,
+        key_db: &KeyDB,
+    ) -> Option<Tls13KeySchedule> {
+        if shp.get_key_share().is_some() {
+            return None;
+        }
+        let identity = self.psk_identities.get(shp.get_psk_index()? as usize)?;
+        let psk = key_db.lookup_psk(identity)?;
+        let mut ks = Tls13KeySchedule::from_psk(suite.hash_algorithm(), &psk.0)?;
+        ks.input_secret(None);
+        Some(ks)
+    }
+}
+
+impl fmt::Debug for ExpectServerHello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExpectServerHello")
+            .field("client_random", &self.client_random)
+            .finish_non_exhaustive()
+    }
 }
 
 impl TLSState for ExpectServerHello {
@@ -248,58 +503,113 @@ impl TLSState for ExpectServerHello {
                 )
             );
 
-            let client_handshake_traffic_secret = try_giving_back!(
-                self,
-                common_data
-                    .key_db
-                    .lookup_secret(
-                        &self.client_random,
-                        SecretType::ClientHandshakeTrafficSecret,
-                    )
-                    .ok_or(TLSDecodeError::MissingKey(self.client_random.clone()))
-            );
-            let server_handshake_traffic_secret = try_giving_back!(
-                self,
-                common_data
-                    .key_db
-                    .lookup_secret(
-                        &self.client_random,
-                        SecretType::ServerHandshakeTrafficSecret,
-                    )
-                    .ok_or(TLSDecodeError::MissingKey(self.client_random.clone()))
-            );
+            // This state may be driven again if we are missing keys, so only
+            // report the first time around.
+            if !flow.server_hello_seen {
+                flow.server_hello_seen = true;
+                (common_data.on_side_data)(Box::new(side_data::ServerHelloSeen {
+                    target: common_data.target,
+                    version: TlsVersion(
+                        shp.get_supported_versions()
+                            .unwrap_or(shp.legacy_version)
+                            .get_u16(),
+                    ),
+                    cipher_suite: shp.cipher_suite.get_u16(),
+                    protocol: shp.get_alpn_protocol().map(|p| ProtocolName(p.to_vec())),
+                }));
+
+                let (ja3s, ja3s_hash) = fingerprint::ja3s(shp);
+                (common_data.on_side_data)(Box::new(side_data::ServerFingerprint {
+                    target: common_data.target,
+                    ja3s,
+                    ja3s_hash,
+                }));
+            }
 
             let suite = try_giving_back!(
                 self,
                 ALL_CIPHER_SUITES
                     .iter()
+                    .chain(STATIC_RSA_SUITES)
+                    .chain(PSK_SUITES)
                     .find(|s| s.suite() == shp.cipher_suite)
-                    .ok_or(TLSDecodeError::UnknownCipherSuite)
+                    .ok_or(TLSDecodeError::UnknownCipherSuite(shp.cipher_suite))
             );
-
-            match suite {
-                SupportedCipherSuite::Tls13(suite) => {
-                    let ks = KeyScheduleHandshake::from_data(
-                        suite,
-                        &client_handshake_traffic_secret.0,
-                        &server_handshake_traffic_secret.0,
-                    );
-
-                    // FIXME: early data
-                    ks.install_client_handshake_secrets(false, &mut flow.client.common_state);
-                    ks.install_server_handshake_secrets(&mut flow.server.common_state);
-
-                    return Ok(Box::new(WaitForFinish {
+            let suite = match suite {
+                SupportedCipherSuite::Tls13(suite) => suite,
+                SupportedCipherSuite::Tls12(suite) => {
+                    let mut transcript = None;
+                    if shp.ems_support_acked() {
+                        let mut t = self.client_hello;
+                        if let MessagePayload::Handshake { ref encoded, .. } = msg.payload {
+                            t.extend_from_slice(&encoded.0);
+                        }
+                        transcript = Some(t);
+                    }
+                    return Ok(Box::new(Tls12Handshake {
                         suite,
-                        client_random: self.client_random,
-                        client_finished: false,
-                        server_finished: false,
+                        client_random: self.client_random.clone(),
+                        randoms: (self.client_random.0.clone(), shp.random.0.to_vec()),
+                        protocol: shp.get_alpn_protocol().map(|p| ProtocolName(p.to_vec())),
+                        transcript,
+                        ems_session_hash: None,
+                        encrypted_premaster: None,
+                        psk_identity: None,
+                        secrets: None,
                     }));
                 }
-                SupportedCipherSuite::Tls12(suite) => {
-                    unimplemented!("tls12 {suite:?}");
-                }
+            };
+
+            let mut psk = self
+                .psk_key_schedule(suite, shp, common_data.key_db)
+                .map(|ks| {
+                    let mut transcript = self.client_hello.clone();
+                    if let MessagePayload::Handshake { ref encoded, .. } = msg.payload {
+                        transcript.extend_from_slice(&encoded.0);
+                    }
+                    PskSchedule {
+                        ks,
+                        transcript,
+                        traffic: None,
+                    }
+                });
+            let client_random = self.client_random.clone();
+            let lookup = |typ, label| {
+                common_data
+                    .key_db
+                    .lookup_secret(&client_random, typ)
+                    .or_else(|| psk.as_ref().map(|psk| psk.derive(label)))
+                    .ok_or(TLSDecodeError::MissingKey(client_random.clone()))
+            };
+            let client_handshake_traffic_secret = try_giving_back!(
+                self,
+                lookup(SecretType::ClientHandshakeTrafficSecret, b"c hs traffic")
+            );
+            let server_handshake_traffic_secret = try_giving_back!(
+                self,
+                lookup(SecretType::ServerHandshakeTrafficSecret, b"s hs traffic")
+            );
+            if let Some(psk) = &mut psk {
+                psk.ks.input_secret(None);
             }
+
+            let ks = KeyScheduleHandshake::from_data(
+                suite,
+                &client_handshake_traffic_secret.0,
+                &server_handshake_traffic_secret.0,
+            );
+
+            // FIXME: early data
+            ks.install_client_handshake_secrets(false, &mut flow.client.common_state);
+            ks.install_server_handshake_secrets(&mut flow.server.common_state);
+
+            return Ok(Box::new(WaitForFinish {
+                suite,
+                client_random: self.client_random,
+                psk,
+                client_finished: false,
+                server_finished: false,
+            }));
         } else {
             dbg!(&msg);
             unreachable!();
@@ -307,13 +617,52 @@ impl TLSState for ExpectServerHello {
     }
 }
 
+/// Where we are in the key schedule of a TLS 1.3 connection using an
+/// external PSK, since there will be no key log for it.
+///
+/// FIXME: HelloRetryRequest is not handled.
+struct PskSchedule {
+    ks: Tls13KeySchedule,
+    /// Handshake messages so far.
+    transcript: Vec<u8>,
+    /// Client and server application traffic secrets and exporter secret,
+    /// once the server has finished.
+    traffic: Option<[Secret; 3]>,
+}
+
+impl PskSchedule {
+    fn derive(&self, label: &[u8]) -> Secret {
+        self.ks
+            .derive(label, &self.ks.transcript_hash(&self.transcript))
+    }
+}
+
 struct WaitForFinish {
     suite: &'static Tls13CipherSuite,
     client_random: ClientRandom,
+    psk: Option<PskSchedule>,
     client_finished: bool,
     server_finished: bool,
 }
 
+impl WaitForFinish {
+    fn traffic_secret(&self, key_db: &KeyDB, typ: SecretType) -> Result<Secret, TLSDecodeError> {
+        let derived = || {
+            let [client, server, exporter] = self.psk.as_ref()?.traffic.as_ref()?;
+            match typ {
+                SecretType::ClientTrafficSecret0 => Some(client.clone()),
+                SecretType::ServerTrafficSecret0 => Some(server.clone()),
+                SecretType::ExporterSecret => Some(exporter.clone()),
+                _ => None,
+            }
+        };
+        key_db
+            .lookup_secret(&self.client_random, typ)
+            .or_else(derived)
+            .ok_or(TLSDecodeError::MissingKey(self.client_random.clone()))
+    }
+}
+
 impl fmt::Debug for WaitForFinish {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("WaitForFinish")
@@ -325,12 +674,29 @@ impl fmt::Debug for WaitForFinish {
 
 impl TLSState for WaitForFinish {
     fn drive(
-        self: Box<Self>,
+        mut self: Box<Self>,
         flow: &mut TLSFlow,
         to_client: bool,
         msg: &Message,
         common_data: CommonData<'_>,
     ) -> NextStateOrError {
+        if let (Some(psk), MessagePayload::Handshake { parsed, encoded }) =
+            (&mut self.psk, &msg.payload)
+        {
+            // The application secrets cover the handshake up to the server's
+            // Finished, so nothing after it is needed.
+            if psk.traffic.is_none() {
+                psk.transcript.extend_from_slice(&encoded.0);
+            }
+            if to_client && parsed.typ == HandshakeType::Finished && psk.traffic.is_none() {
+                psk.traffic = Some([
+                    psk.derive(b"c ap traffic"),
+                    psk.derive(b"s ap traffic"),
+                    psk.derive(b"exp master"),
+                ]);
+            }
+        }
+
         match msg.payload {
             MessagePayload::Handshake {
                 parsed:
@@ -351,7 +717,38 @@ impl TLSState for WaitForFinish {
                 });
 
                 if let Some(protos) = protos {
-                    (common_data.on_alpn_completed)(protos)
+                    (common_data.on_side_data)(Box::new(side_data::ALPNCompleted {
+                        target: common_data.target,
+                        protocols: protos,
+                    }))
+                }
+            }
+            MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        typ: HandshakeType::Certificate,
+                        payload: HandshakePayload::CertificateTLS13(ref certs),
+                    },
+                encoded: _,
+            } => {
+                let chain: Vec<_> = certs
+                    .entries
+                    .iter()
+                    .map(|e| CertificateInfo::from_der(&e.cert.0))
+                    .collect();
+                if to_client {
+                    (common_data.on_side_data)(Box::new(side_data::ServerCertificate {
+                        target: common_data.target,
+                        server_name: flow.server_name.clone(),
+                        chain,
+                    }));
+                } else if !chain.is_empty() {
+                    // An empty certificate list means the client declined the
+                    // CertificateRequest.
+                    (common_data.on_side_data)(Box::new(side_data::ClientCertificate {
+                        target: common_data.target,
+                        chain,
+                    }));
                 }
             }
             MessagePayload::Handshake {
@@ -365,24 +762,15 @@ impl TLSState for WaitForFinish {
                 // FIXME: key switching
                 let client_traffic_secret = try_giving_back!(
                     self,
-                    common_data
-                        .key_db
-                        .lookup_secret(&self.client_random, SecretType::ClientTrafficSecret0)
-                        .ok_or(TLSDecodeError::MissingKey(self.client_random.clone()))
+                    self.traffic_secret(common_data.key_db, SecretType::ClientTrafficSecret0)
                 );
                 let server_traffic_secret = try_giving_back!(
                     self,
-                    common_data
-                        .key_db
-                        .lookup_secret(&self.client_random, SecretType::ServerTrafficSecret0)
-                        .ok_or(TLSDecodeError::MissingKey(self.client_random.clone()))
+                    self.traffic_secret(common_data.key_db, SecretType::ServerTrafficSecret0)
                 );
                 let exporter_secret = try_giving_back!(
                     self,
-                    common_data
-                        .key_db
-                        .lookup_secret(&self.client_random, SecretType::ExporterSecret)
-                        .ok_or(TLSDecodeError::MissingKey(self.client_random.clone()))
+                    self.traffic_secret(common_data.key_db, SecretType::ExporterSecret)
                 );
 
                 // install traffic keys
@@ -416,6 +804,165 @@ impl TLSState for WaitForFinish {
     }
 }
 
+/// TLS 1.2 from the ServerHello on. Each direction is decrypted from its
+/// ChangeCipherSpec, with keys derived from the master secret, which comes
+/// from the key log, from decrypting a static RSA pre-master secret with the
+/// server's private key, or from an external PSK.
+///
+/// FIXME: resumed sessions can only be decrypted with a key log, since we
+/// do not remember master secrets we derived from RSA keys.
+struct Tls12Handshake {
+    suite: &'static Tls12CipherSuite,
+    client_random: ClientRandom,
+    /// Client and server random.
+    randoms: (Vec<u8>, Vec<u8>),
+    /// ALPN result, which for TLS 1.2 is in the clear in the ServerHello.
+    protocol: Option<ProtocolName>,
+    /// Handshake messages so far, if extended master secret is in use.
+    transcript: Option<Vec<u8>>,
+    ems_session_hash: Option<Vec<u8>>,
+    encrypted_premaster: Option<Vec<u8>>,
+    psk_identity: Option<Vec<u8>>,
+    secrets: Option<ConnectionSecrets>,
+}
+
+impl fmt::Debug for Tls12Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls12Handshake")
+            .field("suite", &self.suite)
+            .field("client_random", &self.client_random)
+            .field("have_secrets", &self.secrets.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tls12Handshake {
+    fn make_secrets(&self, key_db: &KeyDB) -> Result<ConnectionSecrets, TLSDecodeError> {
+        let random = |r: &[u8]| -> [u8; 32] { r.try_into().unwrap_or([0; 32]) };
+        let (client_random, server_random) = (random(&self.randoms.0), random(&self.randoms.1));
+
+        if let Some(master_secret) =
+            key_db.lookup_secret(&self.client_random, SecretType::Tls12ClientMasterSecret)
+        {
+            return Ok(ConnectionSecrets::from_master_secret(
+                self.suite,
+                client_random,
+                server_random,
+                &master_secret.0,
+            )?);
+        }
+
+        let premaster = self
+            .encrypted_premaster
+            .as_ref()
+            .and_then(|encrypted| key_db.decrypt_premaster(encrypted))
+            .or_else(|| {
+                let psk = key_db.lookup_psk(self.psk_identity.as_ref()?)?;
+                Some(psk::tls12_premaster(&psk.0))
+            });
+        if let Some(premaster) = premaster {
+            return Ok(ConnectionSecrets::from_premaster_secret(
+                self.suite,
+                client_random,
+                server_random,
+                &premaster,
+                self.ems_session_hash.as_deref(),
+            ));
+        }
+
+        Err(TLSDecodeError::MissingKey(self.client_random.clone()))
+    }
+}
+
+impl TLSState for Tls12Handshake {
+    fn drive(
+        mut self: Box<Self>,
+        flow: &mut TLSFlow,
+        to_client: bool,
+        msg: &Message,
+        common_data: CommonData<'_>,
+    ) -> NextStateOrError {
+        match msg.payload {
+            MessagePayload::Handshake {
+                ref parsed,
+                ref encoded,
+            } => {
+                if let Some(transcript) = &mut self.transcript {
+                    transcript.extend_from_slice(&encoded.0);
+                }
+                // Unlike in TLS 1.3, certificates are sent in the clear.
+                if let HandshakePayload::Certificate(ref certs) = parsed.payload {
+                    let chain: Vec<_> = certs
+                        .iter()
+                        .map(|c| CertificateInfo::from_der(&c.0))
+                        .collect();
+                    if to_client {
+                        (common_data.on_side_data)(Box::new(side_data::ServerCertificate {
+                            target: common_data.target,
+                            server_name: flow.server_name.clone(),
+                            chain,
+                        }));
+                    } else if !chain.is_empty() {
+                        // An empty certificate list means the client declined
+                        // the CertificateRequest.
+                        (common_data.on_side_data)(Box::new(side_data::ClientCertificate {
+                            target: common_data.target,
+                            chain,
+                        }));
+                    }
+                }
+                if let HandshakePayload::ClientKeyExchange(ref cke) = parsed.payload {
+                    // The extended master secret covers the handshake up to
+                    // and including this message.
+                    self.ems_session_hash = self.transcript.take().map(|t| {
+                        ring::digest::digest(self.suite.hash_algorithm(), &t)
+                            .as_ref()
+                            .to_vec()
+                    });
+                    match self.suite.kx {
+                        // opaque EncryptedPreMasterSecret<0..2^16-1>
+                        KeyExchangeAlgorithm::RSA => {
+                            self.encrypted_premaster = cke.0.get(2..).map(|e| e.to_vec())
+                        }
+                        // opaque psk_identity<0..2^16-1>
+                        KeyExchangeAlgorithm::PSK => {
+                            self.psk_identity = cke.0.get(2..).map(|e| e.to_vec())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            MessagePayload::ChangeCipherSpec(_) => {
+                let secrets = match self.secrets.take() {
+                    Some(secrets) => secrets,
+                    None => {
+                        let secrets = self.make_secrets(common_data.key_db);
+                        let secrets = try_giving_back!(self, secrets);
+                        if let Some(protocol) = self.protocol.clone() {
+                            (common_data.on_side_data)(Box::new(side_data::ALPNCompleted {
+                                target: common_data.target,
+                                protocols: vec![protocol],
+                            }));
+                        }
+                        secrets
+                    }
+                };
+                if to_client {
+                    secrets.install_decrypter(Side::Client, &mut flow.client.common_state);
+                } else {
+                    secrets.install_decrypter(Side::Server, &mut flow.server.common_state);
+                }
+                self.secrets = Some(secrets);
+            }
+            MessagePayload::ApplicationData(ref p) => {
+                (common_data.next)(to_client, p.0.clone());
+            }
+            _ => {}
+        }
+        Ok(self)
+    }
+}
+
 #[derive(Debug)]
 struct NeedKeys {
     next: Box<dyn TLSState>,
@@ -443,6 +990,18 @@ struct TLSFlow {
     server: TLSSide,
     client: TLSSide,
     state: Box<dyn TLSState>,
+
+    /// SNI from the ClientHello, kept around to attribute the flow if we
+    /// cannot decrypt it.
+    server_name: Option<String>,
+    /// From the ClientHello, so we can tell the key DB once we are done
+    /// with the flow.
+    client_random: Option<ClientRandom>,
+    server_hello_seen: bool,
+    reported_opaque: bool,
+    /// TCP is done with the flow, but it has data waiting on keys, so it
+    /// is kept until that data is dealt with.
+    closed: bool,
 }
 
 impl TLSFlow {
@@ -451,6 +1010,11 @@ impl TLSFlow {
             server: TLSSide::new(Side::Server),
             client: TLSSide::new(Side::Client),
             state: Box::new(ExpectClientHello {}),
+            server_name: None,
+            client_random: None,
+            server_hello_seen: false,
+            reported_opaque: false,
+            closed: false,
         }
     }
 }
@@ -463,16 +1027,44 @@ impl TLSFlow {
 //    idea, but we need to unify the way we deal with the buffering.
 
 enum Queued {
-    Raw(Vec<u8>),
+    Raw(Bytes),
     Message(Message),
 }
 
+impl Queued {
+    /// Approximately how much memory this is holding on to.
+    fn len(&self) -> usize {
+        match self {
+            Queued::Raw(v) => v.len(),
+            Queued::Message(m) => match &m.payload {
+                MessagePayload::ApplicationData(p) => p.0.len(),
+                _ => 0,
+            },
+        }
+    }
+}
+
+/// How much data we hold on to per flow while waiting for its keys, by
+/// default. Keys coming through a key log or the injected library usually
+/// arrive within a few packets, but a flow can move a lot of data before a
+/// slow key log tail catches up.
+pub const DEFAULT_MAX_QUEUED_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Default)]
+struct PendingKeys {
+    messages: VecDeque<(MessageMeta, Queued)>,
+    bytes: usize,
+}
+
 /// Accepts TLS data and queues messages for which we do not have the keys.
 ///
 /// Expects the state handling to be sufficiently idempotent that failing to
 /// get keys will not severely break it.
 pub struct TLSFlowTracker {
-    queued: HashMap<ClientRandom, VecDeque<(MessageMeta, Queued)>>,
+    queued: HashMap<ClientRandom, PendingKeys>,
+    /// Beyond this many bytes queued for one client random, we give up on
+    /// the flow.
+    max_queued_bytes: usize,
     downstream: TLSFlowTrackerInner,
 }
 
@@ -483,18 +1075,110 @@ enum OkOrRetry<T, E> {
 }
 
 impl TLSFlowTracker {
-    pub fn new(key_db: Arc<RwLock<KeyDB>>, next: Box<dyn Listener<Vec<u8>>>) -> Self {
+    pub fn new(key_db: Arc<RwLock<KeyDB>>, next: Box<dyn Listener<Bytes>>) -> Self {
         TLSFlowTracker {
             queued: Default::default(),
+            max_queued_bytes: DEFAULT_MAX_QUEUED_BYTES,
             downstream: TLSFlowTrackerInner::new(key_db, next),
         }
     }
 
+    pub fn with_max_queued_bytes(mut self, max_queued_bytes: usize) -> Self {
+        self.max_queued_bytes = max_queued_bytes;
+        self
+    }
+
     fn enqueue(&mut self, meta: MessageMeta, queued: Queued, client_random: ClientRandom) {
-        self.queued
-            .entry(client_random)
-            .or_insert_with(VecDeque::new)
-            .push_back((meta, queued));
+        let pending = self.queued.entry(client_random.clone()).or_default();
+        pending.bytes += queued.len();
+
+        if pending.bytes > self.max_queued_bytes {
+            let dropped_bytes = pending.bytes;
+            self.queued.remove(&client_random);
+            tracing::warn!(
+                target = ?meta.target,
+                "gave up waiting for keys for {client_random:?} with {dropped_bytes} bytes queued"
+            );
+            // Whatever comes next on this flow is garbage without the data
+            // we just dropped.
+            if let Some(flow) = self.downstream.flows.get_mut(&meta.target) {
+                flow.state = Box::new(Failed {});
+            }
+            self.forget_closed(&client_random);
+            self.downstream
+                .next
+                .on_side_data(Box::new(side_data::PendingKeysDropped {
+                    target: meta.target,
+                    client_random,
+                    dropped_bytes,
+                }));
+            return;
+        }
+
+        pending.messages.push_back((meta, queued));
+    }
+
+    /// Forgets about a flow once TCP is done with it. If nothing is waiting
+    /// on its keys, the key DB is told it may drop them.
+    fn on_connection_closed(&mut self, target: IPTarget) {
+        let client_random = match self.downstream.flows.get(&target) {
+            Some(flow) => flow.client_random.clone(),
+            None => return,
+        };
+        if let Some(client_random) = client_random {
+            if self.queued.contains_key(&client_random) {
+                // The keys might still show up, and the queued data needs
+                // the flow state to decode.
+                if let Some(flow) = self.downstream.flows.get_mut(&target) {
+                    flow.closed = true;
+                }
+                return;
+            }
+            self.downstream
+                .key_db
+                .write()
+                .unwrap()
+                .on_connection_closed(&client_random);
+        }
+        self.downstream.flows.remove(&target);
+    }
+
+    /// Forgets about the closed flows of `client_random` once nothing is
+    /// queued for them any more.
+    fn forget_closed(&mut self, client_random: &ClientRandom) {
+        let closed: Vec<IPTarget> = self
+            .downstream
+            .flows
+            .iter()
+            .filter(|(_, flow)| flow.closed && flow.client_random.as_ref() == Some(client_random))
+            .map(|(target, _)| *target)
+            .collect();
+        for target in closed {
+            self.on_connection_closed(target);
+        }
+    }
+
+    /// Gives up on a flow once part of it is missing from the capture,
+    /// since nothing after the gap can be decrypted.
+    fn on_truncated(&mut self, truncated: &Truncated) {
+        let Some(flow) = self.downstream.flows.get_mut(&truncated.target) else {
+            return;
+        };
+        if flow.state.is_failed() {
+            return;
+        }
+        flow.state = Box::new(Failed {});
+        report(
+            &mut *self.downstream.next,
+            &truncated.timing,
+            Some(truncated.target),
+            Layer::Tls,
+            Severity::Warning,
+            format!(
+                "{} bytes missing from the capture, not decrypting the rest",
+                truncated.missing
+            ),
+        );
     }
 
     fn process_queued(
@@ -518,8 +1202,8 @@ impl TLSFlowTracker {
     }
 }
 
-impl Listener<Vec<u8>> for TLSFlowTracker {
-    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: Vec<u8>) {
+impl Listener<Bytes> for TLSFlowTracker {
+    fn on_data(&mut self, timing: TimingInfo, target: IPTarget, to_client: bool, data: Bytes) {
         let meta = MessageMeta {
             timing: timing.clone(),
             target,
@@ -535,30 +1219,49 @@ impl Listener<Vec<u8>> for TLSFlowTracker {
     }
 
     fn on_side_data(&mut self, data: Box<dyn SideData>) {
-        // If the side data appears before the packet, the key db should
-        // already contain the data, so we don't care about that case.
-        //
-        // If we are here we got the keys late.
-        if let Some(upd) = (&*data)
-            .as_any()
-            .downcast_ref::<side_data::NewKeyReceived>()
-        {
-            tracing::debug!("new keys: {upd:?}");
-            if let Some(q) = self.queued.get_mut(&upd.client_random) {
-                while let Some((meta, msg)) = q.pop_front() {
-                    let kdb = self.downstream.key_db.clone();
-
-                    match Self::process_queued(&mut self.downstream, &kdb, &meta, msg) {
-                        OkOrRetry::Ok(_) => continue,
-                        OkOrRetry::Retry(queued) => {
-                            q.push_front((meta, queued));
-                            break;
-                        }
+        if let Some(truncated) = data.downcast_ref::<Truncated>() {
+            if is_tls(&truncated.target) {
+                // The gap is in the records, not in what they decrypt to.
+                self.on_truncated(truncated);
+                return;
+            }
+        }
+        crate::dispatch_side_data!(self, &data, [side_data::NewKeyReceived, ConnectionClosed]);
+        self.downstream.next.on_side_data(data)
+    }
+}
+
+impl SideDataHandler<side_data::NewKeyReceived> for TLSFlowTracker {
+    // If the side data appears before the packet, the key db should
+    // already contain the data, so we don't care about that case.
+    //
+    // If we are here we got the keys late.
+    fn handle_side_data(&mut self, upd: &side_data::NewKeyReceived) {
+        tracing::debug!("new keys: {upd:?}");
+        if let Some(q) = self.queued.get_mut(&upd.client_random) {
+            while let Some((meta, msg)) = q.messages.pop_front() {
+                let kdb = self.downstream.key_db.clone();
+                let len = msg.len();
+
+                match Self::process_queued(&mut self.downstream, &kdb, &meta, msg) {
+                    OkOrRetry::Ok(_) => q.bytes = q.bytes.saturating_sub(len),
+                    OkOrRetry::Retry(queued) => {
+                        q.messages.push_front((meta, queued));
+                        break;
                     }
                 }
             }
+            if q.messages.is_empty() {
+                self.queued.remove(&upd.client_random);
+                self.forget_closed(&upd.client_random);
+            }
         }
-        self.downstream.next.on_side_data(data)
+    }
+}
+
+impl SideDataHandler<ConnectionClosed> for TLSFlowTracker {
+    fn handle_side_data(&mut self, closed: &ConnectionClosed) {
+        self.on_connection_closed(closed.target);
     }
 }
 
@@ -589,7 +1292,7 @@ pub struct TLSFlowTrackerInner {
     // FIXME: should this exist, or should the whole thing be handled as
     // side data and then we just own our own?
     key_db: Arc<RwLock<KeyDB>>,
-    next: Box<dyn Listener<Vec<u8>>>,
+    next: Box<dyn Listener<Bytes>>,
 }
 
 fn is_tls(target: &IPTarget) -> bool {
@@ -597,10 +1300,7 @@ fn is_tls(target: &IPTarget) -> bool {
 }
 
 impl TLSFlowTrackerInner {
-    pub fn new(
-        key_db: Arc<RwLock<KeyDB>>,
-        next: Box<dyn Listener<Vec<u8>>>,
-    ) -> TLSFlowTrackerInner {
+    pub fn new(key_db: Arc<RwLock<KeyDB>>, next: Box<dyn Listener<Bytes>>) -> TLSFlowTrackerInner {
         TLSFlowTrackerInner {
             flows: Default::default(),
             key_db,
@@ -633,12 +1333,29 @@ impl TLSFlowTrackerInner {
     fn do_message(
         entry: &mut TLSFlow,
         key_db: &RwLock<KeyDB>,
-        next: &mut Box<dyn Listener<Vec<u8>>>,
+        next: &mut Box<dyn Listener<Bytes>>,
         to_client: bool,
         msg: &Message,
         timing: TimingInfo,
         target: IPTarget,
     ) -> OkOrRetry<bool, ClientRandom> {
+        if let MessagePayload::Alert(ref alert) = msg.payload {
+            tracing::debug!(?target, ?to_client, "tls alert: {alert:?}");
+            next.on_side_data(Box::new(side_data::TlsAlert {
+                timing,
+                target,
+                to_client,
+                level: alert.level,
+                description: alert.description,
+            }));
+            if alert.level == AlertLevel::Warning {
+                return OkOrRetry::Ok(true);
+            } else {
+                entry.state = Box::new(Failed {});
+                return OkOrRetry::Ok(false);
+            }
+        }
+
         let state = std::mem::replace(&mut entry.state, Box::new(Failed {}));
         let lock = key_db.read().unwrap();
         let start = timing.received_on_wire;
@@ -650,21 +1367,17 @@ impl TLSFlowTrackerInner {
             msg,
             CommonData {
                 key_db: &*lock,
+                target,
                 next: &mut |to_client, data| {
                     let mut timing = timing.clone();
                     timing
                         .other_times
                         .insert::<timings::TlsConnectionStart>(start);
 
-                    next.borrow_mut().on_data(timing, target, to_client, data);
-                },
-                on_alpn_completed: &mut |protos| {
                     next.borrow_mut()
-                        .on_side_data(Box::new(side_data::ALPNCompleted {
-                            target,
-                            protocols: protos,
-                        }))
+                        .on_data(timing, target, to_client, Bytes::from(data));
                 },
+                on_side_data: &mut |data| next.borrow_mut().on_side_data(data),
             },
         );
 
@@ -677,6 +1390,15 @@ impl TLSFlowTrackerInner {
                 // Stop immediately and let us get called again
                 // with no new data when we get a relevant key.
                 tracing::debug!("Missing key for client_random {cr:?}, new state: {state:?}");
+                if !entry.reported_opaque {
+                    entry.reported_opaque = true;
+                    next.borrow_mut()
+                        .on_side_data(Box::new(side_data::OpaqueFlow {
+                            target,
+                            client_random: cr.clone(),
+                            server_name: entry.server_name.clone(),
+                        }));
+                }
                 entry.state = Box::new(NeedKeys {
                     client_random: cr.clone(),
                     next: state,
@@ -685,6 +1407,18 @@ impl TLSFlowTrackerInner {
             }
             Err((_s, e)) => {
                 tracing::warn!("failed while processing tls connection: {e}");
+                let kind = match e {
+                    TLSDecodeError::UnknownCipherSuite(_) => {
+                        side_data::DecodeFailureKind::UnsupportedCipherSuite
+                    }
+                    _ => side_data::DecodeFailureKind::Other,
+                };
+                next.borrow_mut()
+                    .on_side_data(Box::new(side_data::TlsDecodeFailed {
+                        target,
+                        kind,
+                        error: e.to_string(),
+                    }));
                 return OkOrRetry::Ok(false);
             }
         }
@@ -695,7 +1429,7 @@ impl TLSFlowTrackerInner {
         timing: TimingInfo,
         target: IPTarget,
         to_client: bool,
-        data: Vec<u8>,
+        data: Bytes,
     ) -> OkOrRetry<(), (ClientRandom, Queued)> {
         if !is_tls(&target) {
             return OkOrRetry::Ok(());
@@ -768,13 +1502,17 @@ impl TLSFlowTrackerInner {
 mod test {
     use std::io::Cursor;
 
+    use rustls_intercept::AlertDescription;
+
     use super::*;
     use crate::{
         chomp::{dump_pcap, EthernetChomper},
+        http::HTTPStreamEvent,
+        key_db::RsaKey,
         test_support::*,
     };
 
-    fn inorder_test(f: &[u8]) -> Vec<Received<Vec<u8>>> {
+    fn inorder_test(f: &[u8]) -> Vec<Received<Bytes>> {
         let mut reader = Cursor::new(f);
         let key_db: Arc<RwLock<KeyDB>> = Default::default();
         let received = Arc::new(RwLock::new(Vec::new()));
@@ -785,13 +1523,13 @@ mod test {
         std::mem::take(&mut *lock)
     }
 
-    fn reorder_test<T: Listener<Vec<u8>>>(
+    fn reorder_test<T: Listener<Bytes>>(
         f: &[u8],
         make_chomper: impl FnOnce(
             Arc<RwLock<KeyDB>>,
-            Arc<RwLock<Vec<Received<Vec<u8>>>>>,
+            Arc<RwLock<Vec<Received<Bytes>>>>,
         ) -> EthernetChomper<T>,
-    ) -> Vec<Received<Vec<u8>>> {
+    ) -> Vec<Received<Bytes>> {
         let mut reader = Cursor::new(f);
         let key_db: Arc<RwLock<KeyDB>> = Default::default();
         let received = Arc::new(RwLock::new(Vec::new()));
@@ -837,6 +1575,328 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_handshake_side_data_without_keys() {
+        let mut reader = Cursor::new(NYA_DSB);
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(SideDataListener {
+                    received: received.clone(),
+                }),
+            ),
+        );
+        let mut reorderer = KeyMessageReorderer::default();
+
+        dump_pcap(&mut reader, &mut reorderer).unwrap();
+        reorderer.send_without_keys(&mut chomper).unwrap();
+
+        let client_hellos = SideDataListener::find::<side_data::ClientHelloSeen>(&received);
+        assert_eq!(client_hellos.len(), 1);
+        assert_eq!(client_hellos[0].server_name.as_deref(), Some("jade.fyi"));
+
+        let server_hellos = SideDataListener::find::<side_data::ServerHelloSeen>(&received);
+        assert_eq!(server_hellos.len(), 1);
+        assert_eq!(server_hellos[0].version, TlsVersion(0x0304));
+
+        let opaque = SideDataListener::find::<side_data::OpaqueFlow>(&received);
+        assert_eq!(opaque.len(), 1);
+        assert_eq!(opaque[0].server_name.as_deref(), Some("jade.fyi"));
+        assert_eq!(opaque[0].client_random, client_hellos[0].client_random);
+    }
+
+    #[test]
+    fn test_late_keys_over_limit() {
+        let mut reorderer = KeyMessageReorderer::default();
+        dump_pcap(&mut Cursor::new(NYA_DSB), &mut reorderer).unwrap();
+
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let side_data = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(SideDataListener {
+                    received: side_data.clone(),
+                }),
+            )
+            .with_max_queued_bytes(0),
+        );
+        reorderer.send_late_keys(&mut chomper).unwrap();
+
+        let dropped = SideDataListener::find::<side_data::PendingKeysDropped>(&side_data);
+        assert_eq!(dropped.len(), 1);
+        let opaque = SideDataListener::find::<side_data::OpaqueFlow>(&side_data);
+        assert_eq!(dropped[0].client_random, opaque[0].client_random);
+
+        // And nothing gets decrypted once the keys do show up.
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(TestListener {
+                    received: received.clone(),
+                }),
+            )
+            .with_max_queued_bytes(0),
+        );
+        reorderer.send_late_keys(&mut chomper).unwrap();
+        assert!(!received
+            .read()
+            .unwrap()
+            .iter()
+            .any(|r| matches!(r, Received::Message(..))));
+    }
+
+    #[test]
+    fn test_closed_flow_keys_pruned() {
+        let mut reorderer = KeyMessageReorderer::default();
+        dump_pcap(&mut Cursor::new(NYA_DSB), &mut reorderer).unwrap();
+
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        key_db.write().unwrap().set_closed_retention(Some(0));
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = tls_chomper(key_db.clone(), received.clone());
+        reorderer.send(&mut chomper).unwrap();
+
+        // The flow was decoded before the keys went away.
+        assert!(received
+            .read()
+            .unwrap()
+            .iter()
+            .any(|r| matches!(r, Received::Message(..))));
+        assert!(key_db.read().unwrap().is_empty());
+        assert!(chomper.recv.downstream.flows.is_empty());
+    }
+
+    #[test]
+    fn test_closed_flow_pruned_after_late_keys() {
+        let mut reorderer = KeyMessageReorderer::default();
+        dump_pcap(&mut Cursor::new(NYA_DSB), &mut reorderer).unwrap();
+
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        key_db.write().unwrap().set_closed_retention(Some(0));
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = tls_chomper(key_db.clone(), received.clone());
+        // The connection closes while its data waits for the keys.
+        reorderer.send_late_keys(&mut chomper).unwrap();
+
+        assert!(received
+            .read()
+            .unwrap()
+            .iter()
+            .any(|r| matches!(r, Received::Message(..))));
+        assert!(chomper.recv.queued.is_empty());
+        assert!(key_db.read().unwrap().is_empty());
+        assert!(chomper.recv.downstream.flows.is_empty());
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let mut reader = Cursor::new(NYA_DSB);
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(SideDataListener {
+                    received: received.clone(),
+                }),
+            ),
+        );
+        let mut reorderer = KeyMessageReorderer::default();
+
+        dump_pcap(&mut reader, &mut reorderer).unwrap();
+        reorderer.send_without_keys(&mut chomper).unwrap();
+
+        let client = SideDataListener::find::<side_data::ClientFingerprint>(&received);
+        assert_eq!(client.len(), 1);
+        assert_eq!(
+            client[0].ja3,
+            "771,4866-4865-4867-49196-49195-52393-49200-49199-52392-255,\
+             43-11-10-13-23-5-0-18-51-45-35,29-23-24,0"
+        );
+        assert_eq!(client[0].ja3_hash, "a94fc11547bcef10847672ff518b3fb9");
+        assert_eq!(client[0].ja4, "t13d101100_61a7ad8aa9b6_dc02626b439c");
+
+        let server = SideDataListener::find::<side_data::ServerFingerprint>(&received);
+        assert_eq!(server.len(), 1);
+        assert_eq!(server[0].ja3s, "771,4866,43-51");
+        assert_eq!(server[0].ja3s_hash, "15af977ce25de452b96affa2addb1036");
+    }
+
+    #[test]
+    fn test_server_certificate() {
+        let mut reader = Cursor::new(NYA_DSB);
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(SideDataListener {
+                    received: received.clone(),
+                }),
+            ),
+        );
+
+        dump_pcap(&mut reader, &mut chomper).unwrap();
+
+        let certs = SideDataListener::find::<side_data::ServerCertificate>(&received);
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].server_name.as_deref(), Some("jade.fyi"));
+        assert!(!certs[0].chain.is_empty());
+        let leaf = &certs[0].chain[0];
+        assert!(leaf.subject.as_deref().unwrap().contains("jade.fyi"));
+        assert!(leaf.to_pem().starts_with("-----BEGIN CERTIFICATE-----\n"));
+
+        assert!(SideDataListener::find::<side_data::ClientCertificate>(&received).is_empty());
+    }
+
+    #[test]
+    fn test_tls12_client_certificate() {
+        let mut reader = Cursor::new(TLS12_MTLS);
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(SideDataListener {
+                    received: received.clone(),
+                }),
+            ),
+        );
+
+        dump_pcap(&mut reader, &mut chomper).unwrap();
+
+        let certs = SideDataListener::find::<side_data::ClientCertificate>(&received);
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].chain.len(), 1);
+        let leaf = &certs[0].chain[0];
+        assert!(leaf.subject.as_deref().unwrap().contains("client.test"));
+    }
+
+    #[test]
+    fn test_tls12_server_certificate() {
+        let mut reader = Cursor::new(TLS12_MTLS);
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(SideDataListener {
+                    received: received.clone(),
+                }),
+            ),
+        );
+
+        dump_pcap(&mut reader, &mut chomper).unwrap();
+
+        let certs = SideDataListener::find::<side_data::ServerCertificate>(&received);
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].server_name.as_deref(), Some("server.test"));
+        let pem: String = certs[0].chain.iter().map(|c| c.to_pem()).collect();
+        assert_eq!(pem, include_str!("../corpus/tls12-server.pem"));
+    }
+
+    #[test]
+    fn test_tls12_static_rsa() {
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        key_db
+            .write()
+            .unwrap()
+            .add_rsa_key(RsaKey::from_pem(TLS12_SERVER_KEY).unwrap());
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = http_chomper(key_db, received.clone());
+
+        dump_pcap(&mut Cursor::new(TLS12_RSA), &mut chomper).unwrap();
+
+        let received = received.read().unwrap();
+        let mut events = received.iter().filter_map(|r| match r {
+            Received::Message(_, ev) => Some(ev),
+            Received::SideData(_) => None,
+        });
+        assert!(matches!(
+            events.next(),
+            Some(HTTPStreamEvent::NewRequest(_, parts))
+                if parts.method == "GET" && parts.headers["host"] == "server.test"
+        ));
+        assert!(events.any(|ev| matches!(
+            ev,
+            HTTPStreamEvent::NewResponse(_, parts) if parts.status == 200
+        )));
+    }
+
+    #[test]
+    fn test_tls12_cbc_unsupported() {
+        let key_db: Arc<RwLock<KeyDB>> = Default::default();
+        key_db
+            .write()
+            .unwrap()
+            .add_rsa_key(RsaKey::from_pem(TLS12_SERVER_KEY).unwrap());
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut chomper = raw_chomper(
+            key_db.clone(),
+            TLSFlowTracker::new(
+                key_db,
+                Box::new(SideDataListener {
+                    received: received.clone(),
+                }),
+            ),
+        );
+
+        dump_pcap(&mut Cursor::new(TLS12_RSA_CBC), &mut chomper).unwrap();
+
+        let failed = SideDataListener::find::<side_data::TlsDecodeFailed>(&received);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            failed[0].kind,
+            side_data::DecodeFailureKind::UnsupportedCipherSuite
+        );
+        assert_eq!(
+            failed[0].error,
+            "Cipher suite TLS_RSA_WITH_AES_128_CBC_SHA is not supported"
+        );
+    }
+
+    #[test]
+    fn test_alert() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut tracker = TLSFlowTrackerInner::new(
+            Default::default(),
+            Box::new(SideDataListener {
+                received: received.clone(),
+            }),
+        );
+        let target = sample_target();
+        let meta = MessageMeta {
+            timing: Default::default(),
+            target,
+            to_client: true,
+        };
+        let key_db = tracker.key_db.clone();
+
+        let alert = Message::build_alert(AlertLevel::Fatal, AlertDescription::HandshakeFailure);
+        assert!(matches!(
+            tracker.process_message(&key_db, &meta, &alert),
+            OkOrRetry::Ok(false)
+        ));
+
+        let alerts = SideDataListener::find::<side_data::TlsAlert>(&received);
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].to_client);
+        assert_eq!(alerts[0].level, AlertLevel::Fatal);
+        assert_eq!(alerts[0].description, AlertDescription::HandshakeFailure);
+    }
+
     #[test]
     fn test_tls13_session_resumption() {
         check(
@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Overlay network tunnels (GRE, VXLAN and Geneve), whose inner packets we
+//! decode as if they had been captured directly. WireGuard is also a
+//! tunnel, but needs keys: see [`crate::wireguard`].
+
+use std::net::IpAddr;
+
+use crate::{
+    link::{ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+    udp_flow::parse_udp,
+};
+
+pub const IPPROTO_GRE: u8 = 47;
+pub const VXLAN_PORT: u16 = 4789;
+pub const GENEVE_PORT: u16 = 6081;
+
+/// GRE and Geneve protocol type for a whole Ethernet frame inside.
+const ETHERTYPE_TRANSPARENT_BRIDGING: u16 = 0x6558;
+
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_ROUTING: u16 = 0x4000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+
+const VXLAN_HEADER_LEN: usize = 8;
+const VXLAN_VALID_VNI: u8 = 0x08;
+const GENEVE_HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TunnelKind {
+    Gre,
+    Vxlan,
+    Geneve,
+    WireGuard,
+}
+
+/// A tunnel some packet came out of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tunnel {
+    pub kind: TunnelKind,
+    /// GRE key or VXLAN/Geneve network identifier, which is what tells
+    /// apart overlay networks that reuse the same addresses. For WireGuard,
+    /// the receiver's session index.
+    pub id: Option<u32>,
+    pub outer_src: IpAddr,
+    pub outer_dst: IpAddr,
+}
+
+/// The overlay network a packet that came out of `tunnels`, outermost
+/// first, is on: the ID of the innermost tunnel that has one. Not
+/// WireGuard's, which changes with every handshake.
+pub fn overlay_id(tunnels: &[Tunnel]) -> Option<u32> {
+    tunnels
+        .iter()
+        .rev()
+        .filter(|t| t.kind != TunnelKind::WireGuard)
+        .find_map(|t| t.id)
+}
+
+/// What is inside a tunnel.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Inner<'a> {
+    Ethernet(&'a [u8]),
+    /// Bare network layer packet, of the given ethertype.
+    Network(u16, &'a [u8]),
+}
+
+/// If the IP payload `data`, of protocol `proto`, is a tunnel we know,
+/// returns its kind, ID and what it carries.
+pub(crate) fn decapsulate(proto: u8, data: &[u8]) -> Option<(TunnelKind, Option<u32>, Inner<'_>)> {
+    match proto {
+        IPPROTO_GRE => parse_gre(data),
+        crate::chomp::IPPROTO_UDP => {
+            let (_, dest_port, payload) = parse_udp(data)?;
+            match dest_port {
+                VXLAN_PORT => parse_vxlan(payload),
+                GENEVE_PORT => parse_geneve(payload),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// RFC 2784 with the key and sequence number extensions of RFC 2890.
+fn parse_gre(data: &[u8]) -> Option<(TunnelKind, Option<u32>, Inner<'_>)> {
+    let flags = u16::from_be_bytes(data.get(0..2)?.try_into().unwrap());
+    // Version 1 is PPTP, which is not a tunnel in the sense we care about.
+    if flags & GRE_VERSION != 0 {
+        return None;
+    }
+    let protocol = u16::from_be_bytes(data.get(2..4)?.try_into().unwrap());
+
+    let mut offset = 4;
+    if flags & (GRE_CHECKSUM | GRE_ROUTING) != 0 {
+        offset += 4;
+    }
+    let key = if flags & GRE_KEY != 0 {
+        let key = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().unwrap());
+        offset += 4;
+        Some(key)
+    } else {
+        None
+    };
+    if flags & GRE_SEQUENCE != 0 {
+        offset += 4;
+    }
+
+    let inner = inner(protocol, data.get(offset..)?)?;
+    Some((TunnelKind::Gre, key, inner))
+}
+
+/// RFC 7348.
+fn parse_vxlan(data: &[u8]) -> Option<(TunnelKind, Option<u32>, Inner<'_>)> {
+    if data.first()? & VXLAN_VALID_VNI == 0 {
+        return None;
+    }
+    let vni = u32::from_be_bytes(data.get(4..8)?.try_into().unwrap()) >> 8;
+    let frame = data.get(VXLAN_HEADER_LEN..)?;
+    Some((TunnelKind::Vxlan, Some(vni), Inner::Ethernet(frame)))
+}
+
+/// RFC 8926.
+fn parse_geneve(data: &[u8]) -> Option<(TunnelKind, Option<u32>, Inner<'_>)> {
+    let version = data.first()? >> 6;
+    if version != 0 {
+        return None;
+    }
+    let options_len = (data[0] & 0x3f) as usize * 4;
+    let protocol = u16::from_be_bytes(data.get(2..4)?.try_into().unwrap());
+    let vni = u32::from_be_bytes(data.get(4..8)?.try_into().unwrap()) >> 8;
+
+    let inner = inner(protocol, data.get(GENEVE_HEADER_LEN + options_len..)?)?;
+    Some((TunnelKind::Geneve, Some(vni), inner))
+}
+
+fn inner(protocol: u16, data: &[u8]) -> Option<Inner<'_>> {
+    match protocol {
+        ETHERTYPE_TRANSPARENT_BRIDGING => Some(Inner::Ethernet(data)),
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(Inner::Network(protocol, data)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gre_with_key() {
+        let ip = [0x45, 0, 0, 20];
+        // Key and sequence number present
+        let mut packet = vec![0x30, 0, 0x08, 0x00, 0, 0, 0x12, 0x34, 0, 0, 0, 1];
+        packet.extend(ip);
+        assert_eq!(
+            decapsulate(IPPROTO_GRE, &packet),
+            Some((
+                TunnelKind::Gre,
+                Some(0x1234),
+                Inner::Network(ETHERTYPE_IPV4, &ip[..])
+            ))
+        );
+
+        // PPTP
+        packet[1] = 1;
+        assert_eq!(decapsulate(IPPROTO_GRE, &packet), None);
+    }
+
+    #[test]
+    fn test_overlay_id() {
+        let tunnel = |kind, id| Tunnel {
+            kind,
+            id,
+            outer_src: [192, 0, 2, 1].into(),
+            outer_dst: [192, 0, 2, 2].into(),
+        };
+        assert_eq!(overlay_id(&[]), None);
+        assert_eq!(
+            overlay_id(&[
+                tunnel(TunnelKind::Gre, Some(7)),
+                tunnel(TunnelKind::Vxlan, Some(42)),
+                tunnel(TunnelKind::WireGuard, Some(1234)),
+            ]),
+            Some(42)
+        );
+        assert_eq!(
+            overlay_id(&[
+                tunnel(TunnelKind::Gre, Some(7)),
+                tunnel(TunnelKind::Gre, None),
+            ]),
+            Some(7)
+        );
+        assert_eq!(overlay_id(&[tunnel(TunnelKind::WireGuard, Some(1))]), None);
+    }
+
+    #[test]
+    fn test_vxlan_and_geneve() {
+        let frame = [0xee; 14];
+
+        let mut vxlan = vec![0xc0, 0x00, 0x12, 0xb5, 0, 30, 0, 0];
+        vxlan.extend([0x08, 0, 0, 0, 0, 0, 42, 0]);
+        vxlan.extend(frame);
+        assert_eq!(
+            decapsulate(crate::chomp::IPPROTO_UDP, &vxlan),
+            Some((TunnelKind::Vxlan, Some(42), Inner::Ethernet(&frame[..])))
+        );
+
+        // One 4 byte option
+        let mut geneve = vec![0xc0, 0x00, 0x17, 0xc1, 0, 34, 0, 0];
+        geneve.extend([0x01, 0, 0x65, 0x58, 0, 1, 0, 0]);
+        geneve.extend([0xff; 4]);
+        geneve.extend(frame);
+        assert_eq!(
+            decapsulate(crate::chomp::IPPROTO_UDP, &geneve),
+            Some((TunnelKind::Geneve, Some(256), Inner::Ethernet(&frame[..])))
+        );
+
+        // Some other UDP traffic
+        vxlan[3] = 53;
+        assert_eq!(decapsulate(crate::chomp::IPPROTO_UDP, &vxlan), None);
+    }
+}
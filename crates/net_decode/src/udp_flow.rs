@@ -0,0 +1,450 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! UDP "flows": datagrams between the same two addresses and ports, until
+//! they go quiet for a while. This gives protocols on top of UDP the same
+//! per-flow routing, timing and stats that TCP connections get.
+//!
+//! The client of a flow is whoever sent its first datagram.
+//!
+//! QUIC connections are also followed by their connection IDs, so one that
+//! moves to a new address or port stays the same flow.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use bytes::Bytes;
+
+use crate::{
+    chomp::{IPHeader, IPTarget},
+    icmp::{side_data::IcmpError, IcmpErrorKind},
+    listener::{Listener, Nanos, TimingInfo},
+    quic::ConnectionIds,
+    tunnel,
+};
+
+pub mod side_data {
+    use crate::{chomp::IPTarget, listener::Nanos, listener::TimingInfo};
+
+    /// Fired by `net_decode::udp_flow` once a flow has been idle for the
+    /// timeout. A later datagram with the same addresses starts a new flow.
+    #[derive(Clone, Debug)]
+    pub struct UdpFlowEnded {
+        /// Of the flow's last datagram.
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        pub stats: UdpFlowStats,
+    }
+
+    /// Fired by `net_decode::udp_flow` when datagrams of a QUIC connection
+    /// start arriving on new addresses or ports. They are still delivered
+    /// as part of `target`.
+    #[derive(Clone, Debug)]
+    pub struct QuicMigrated {
+        pub timing: TimingInfo,
+        pub target: IPTarget,
+        /// The new addresses and ports, with the client first like `target`.
+        pub path: IPTarget,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct UdpFlowStats {
+        pub first_seen: Nanos,
+        pub last_seen: Nanos,
+        pub datagrams_to_server: u64,
+        pub datagrams_to_client: u64,
+        pub bytes_to_server: u64,
+        pub bytes_to_client: u64,
+    }
+}
+
+use side_data::{QuicMigrated, UdpFlowEnded, UdpFlowStats};
+
+/// How long a flow may go without datagrams before it is over. The same as
+/// Linux connection tracking uses for UDP streams.
+pub const DEFAULT_IDLE_TIMEOUT: Nanos = 120_000_000_000;
+/// How often to look for idle flows, in capture time.
+const SWEEP_INTERVAL: Nanos = 1_000_000_000;
+
+const UDP_HEADER_LEN: usize = 8;
+
+/// Splits a UDP datagram into its source port, destination port and
+/// payload.
+pub(crate) fn parse_udp(data: &[u8]) -> Option<(u16, u16, &[u8])> {
+    let source_port = u16::from_be_bytes(data.get(0..2)?.try_into().unwrap());
+    let dest_port = u16::from_be_bytes(data.get(2..4)?.try_into().unwrap());
+    let len = u16::from_be_bytes(data.get(4..6)?.try_into().unwrap()) as usize;
+    let payload = data.get(UDP_HEADER_LEN..len.clamp(UDP_HEADER_LEN, data.len()))?;
+    Some((source_port, dest_port, payload))
+}
+
+struct UdpFlow {
+    stats: UdpFlowStats,
+    last_timing: TimingInfo,
+    /// Other addresses the flow has moved to.
+    paths: Vec<IPTarget>,
+}
+
+pub struct UdpFollower {
+    flows: HashMap<IPTarget, UdpFlow>,
+    /// The flows of the `paths` of each flow.
+    paths: HashMap<IPTarget, IPTarget>,
+    quic: ConnectionIds,
+    idle_timeout: Nanos,
+    last_sweep: Nanos,
+    payloads: Option<Box<dyn Listener<Bytes>>>,
+}
+
+impl Default for UdpFollower {
+    fn default() -> Self {
+        UdpFollower {
+            flows: HashMap::new(),
+            paths: HashMap::new(),
+            quic: ConnectionIds::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            last_sweep: 0,
+            payloads: None,
+        }
+    }
+}
+
+impl UdpFollower {
+    /// Sends the payload of each datagram to `payloads`. Without it, flows
+    /// are only counted. Side data, such as [`side_data::UdpFlowEnded`],
+    /// goes to the listener given along with each datagram either way.
+    pub fn with_listener(mut self, payloads: impl Listener<Bytes> + 'static) -> Self {
+        self.payloads = Some(Box::new(payloads));
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Nanos) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Ends the flows that have gone idle by `now`, as the next datagram
+    /// would, for when no more are coming.
+    pub fn advance_time(&mut self, now: Nanos, recv: &mut dyn Listener<Bytes>) {
+        self.sweep(now, recv);
+    }
+
+    /// Flows that have not gone idle yet.
+    pub fn active_flows(&self) -> impl Iterator<Item = (&IPTarget, &UdpFlowStats)> {
+        self.flows
+            .iter()
+            .map(|(target, flow)| (target, &flow.stats))
+    }
+
+    pub fn chomp(
+        &mut self,
+        timing: TimingInfo,
+        ip_header: &IPHeader,
+        data: &[u8],
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        let Some((source_port, dest_port, payload)) = parse_udp(data) else {
+            tracing::debug!("ignored truncated udp datagram");
+            return;
+        };
+        let now = timing.received_on_wire;
+        if now.saturating_sub(self.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(now, recv);
+        }
+
+        let target = IPTarget::from_ports(ip_header, source_port, dest_port)
+            .on_overlay(tunnel::overlay_id(&timing.tunnels));
+        let mut migrated = None;
+        let (target, to_client) = match self.find(target) {
+            Some(found) => found,
+            None => match self.quic.find(payload) {
+                Some((flow, to_client)) if self.flows.contains_key(&flow) => {
+                    let path = if to_client { target.flip() } else { target };
+                    tracing::debug!("quic connection {flow:?} moved to {path:?}");
+                    self.paths.insert(path, flow);
+                    self.flows.get_mut(&flow).unwrap().paths.push(path);
+                    migrated = Some(path);
+                    (flow, to_client)
+                }
+                _ => {
+                    tracing::debug!("new udp flow {target:?}");
+                    (target, false)
+                }
+            },
+        };
+        self.quic.learn(target, !to_client, payload);
+
+        let flow = self.flows.entry(target).or_insert_with(|| UdpFlow {
+            stats: UdpFlowStats {
+                first_seen: now,
+                ..Default::default()
+            },
+            last_timing: timing.clone(),
+            paths: Vec::new(),
+        });
+        flow.stats.last_seen = now;
+        flow.last_timing = timing.clone();
+        if to_client {
+            flow.stats.datagrams_to_client += 1;
+            flow.stats.bytes_to_client += payload.len() as u64;
+        } else {
+            flow.stats.datagrams_to_server += 1;
+            flow.stats.bytes_to_server += payload.len() as u64;
+        }
+
+        if let Some(path) = migrated {
+            recv.on_side_data(Box::new(QuicMigrated {
+                timing: timing.clone(),
+                target,
+                path,
+            }));
+        }
+        if let Some(payloads) = &mut self.payloads {
+            payloads.on_data(timing, target, to_client, Bytes::copy_from_slice(payload));
+        }
+    }
+
+    /// The flow `target` belongs to, and whether it is going to the flow's
+    /// client.
+    fn find(&self, target: IPTarget) -> Option<(IPTarget, bool)> {
+        if self.flows.contains_key(&target) {
+            Some((target, false))
+        } else if self.flows.contains_key(&target.flip()) {
+            Some((target.flip(), true))
+        } else if let Some(&flow) = self.paths.get(&target) {
+            Some((flow, false))
+        } else {
+            self.paths.get(&target.flip()).map(|&flow| (flow, true))
+        }
+    }
+
+    /// Takes an ICMP error about a datagram sent to `quoted`, and sends it
+    /// on as [`IcmpError`] if it belongs to one of our flows.
+    pub fn on_icmp_error(
+        &mut self,
+        timing: TimingInfo,
+        quoted: IPTarget,
+        kind: IcmpErrorKind,
+        reporter: IpAddr,
+        recv: &mut dyn Listener<Bytes>,
+    ) {
+        let Some((target, to_client)) = self.find(quoted) else {
+            tracing::debug!("icmp error for unknown udp flow {quoted:?}");
+            return;
+        };
+        recv.on_side_data(Box::new(IcmpError {
+            timing,
+            target,
+            to_client,
+            kind,
+            reporter,
+        }));
+    }
+
+    /// Ends the flows that have been idle for longer than the timeout.
+    fn sweep(&mut self, now: Nanos, recv: &mut dyn Listener<Bytes>) {
+        self.last_sweep = now;
+        let idle_timeout = self.idle_timeout;
+        let mut ended: Vec<IPTarget> = self
+            .flows
+            .iter()
+            .filter(|(_, flow)| now.saturating_sub(flow.stats.last_seen) > idle_timeout)
+            .map(|(target, _)| *target)
+            .collect();
+        // In a stable order, so that decoding the same capture twice gives
+        // the same output.
+        ended.sort_by_key(|target| {
+            (
+                self.flows[target].stats.last_seen,
+                target.client_addr(),
+                target.server_addr(),
+            )
+        });
+        for target in ended {
+            let flow = self.flows.remove(&target).unwrap();
+            tracing::debug!("udp flow {target:?} went idle");
+            for path in &flow.paths {
+                self.paths.remove(path);
+            }
+            self.quic.forget(&target);
+            recv.on_side_data(Box::new(UdpFlowEnded {
+                timing: flow.last_timing,
+                target,
+                stats: flow.stats,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::test_support::{Received, SideDataListener, TestListener};
+
+    /// An IPv4 header from 10.0.0.1 to 10.0.0.2 or the other way around,
+    /// and a UDP datagram from `source_port` to `dest_port`.
+    fn datagram(
+        reply: bool,
+        source_port: u16,
+        dest_port: u16,
+        payload: &[u8],
+    ) -> (IPHeader, Vec<u8>) {
+        let (src, dst) = if reply { (2, 1) } else { (1, 2) };
+        let ip = [
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, src, 10, 0, 0, dst,
+        ];
+        let (_, header) = pktparse::ipv4::parse_ipv4_header(&ip).unwrap();
+
+        let mut udp = Vec::new();
+        udp.extend(source_port.to_be_bytes());
+        udp.extend(dest_port.to_be_bytes());
+        udp.extend(((UDP_HEADER_LEN + payload.len()) as u16).to_be_bytes());
+        udp.extend([0, 0]);
+        udp.extend(payload);
+        (IPHeader::V4(header), udp)
+    }
+
+    fn at(seconds: u64) -> TimingInfo {
+        TimingInfo {
+            received_on_wire: seconds * 1_000_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_udp_flow_directions() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut follower = UdpFollower::default().with_listener(TestListener {
+            received: received.clone(),
+        });
+        let mut side_data = SideDataListener {
+            received: Default::default(),
+        };
+
+        let (ip, udp) = datagram(false, 5353, 53, b"query");
+        follower.chomp(at(0), &ip, &udp, &mut side_data);
+        let (ip, udp) = datagram(true, 53, 5353, b"answer");
+        follower.chomp(at(1), &ip, &udp, &mut side_data);
+
+        let received = received.read().unwrap();
+        let directions: Vec<_> = received
+            .iter()
+            .map(|r| match r {
+                Received::Message(meta, data) => {
+                    (meta.target.server_port(), meta.to_client, data.to_vec())
+                }
+                Received::SideData(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            directions,
+            vec![
+                (53, false, b"query".to_vec()),
+                (53, true, b"answer".to_vec())
+            ]
+        );
+        let stats = follower.active_flows().next().unwrap().1;
+        assert_eq!((stats.datagrams_to_server, stats.bytes_to_client), (1, 6));
+    }
+
+    #[test]
+    fn test_udp_flow_idle_timeout() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut follower = UdpFollower::default().with_idle_timeout(10_000_000_000);
+        let mut recv = SideDataListener {
+            received: received.clone(),
+        };
+
+        let (ip, udp) = datagram(false, 40000, 51820, b"one");
+        follower.chomp(at(0), &ip, &udp, &mut recv);
+        follower.chomp(at(5), &ip, &udp, &mut recv);
+        // Some other flow comes along after the first went quiet.
+        let (ip, udp) = datagram(false, 40001, 51820, b"two");
+        follower.chomp(at(16), &ip, &udp, &mut recv);
+
+        let ended = SideDataListener::find::<UdpFlowEnded>(&received);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].target.client_port(), 40000);
+        assert_eq!(ended[0].stats.datagrams_to_server, 2);
+        assert_eq!(ended[0].stats.last_seen, 5_000_000_000);
+        assert_eq!(follower.active_flows().count(), 1);
+    }
+
+    #[test]
+    fn test_udp_port_unreachable() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut follower = UdpFollower::default();
+        let mut recv = SideDataListener {
+            received: received.clone(),
+        };
+
+        let (ip, udp) = datagram(false, 5353, 53, b"query");
+        follower.chomp(at(0), &ip, &udp, &mut recv);
+        let target = *follower.active_flows().next().unwrap().0;
+        let server = target.server_addr().ip();
+        follower.on_icmp_error(
+            at(1),
+            target,
+            IcmpErrorKind::PortUnreachable,
+            server,
+            &mut recv,
+        );
+
+        let errors = SideDataListener::find::<IcmpError>(&received);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, IcmpErrorKind::PortUnreachable);
+        assert!(!errors[0].to_client);
+    }
+
+    #[test]
+    fn test_quic_migration() {
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let mut follower = UdpFollower::default().with_listener(TestListener {
+            received: received.clone(),
+        });
+        let mut recv = TestListener {
+            received: received.clone(),
+        };
+        let initial = b"\xc0\x00\x00\x00\x01\x08randomid\x06client";
+        let handshake = b"\xc0\x00\x00\x00\x01\x06client\x06server";
+
+        let (ip, udp) = datagram(false, 40000, 443, initial);
+        follower.chomp(at(0), &ip, &udp, &mut recv);
+        let (ip, udp) = datagram(true, 443, 40000, handshake);
+        follower.chomp(at(0), &ip, &udp, &mut recv);
+        // The client's NAT picks a new port
+        let (ip, udp) = datagram(false, 50000, 443, b"\x41serverdata");
+        follower.chomp(at(1), &ip, &udp, &mut recv);
+        let (ip, udp) = datagram(true, 443, 50000, b"\x41clientdata");
+        follower.chomp(at(1), &ip, &udp, &mut recv);
+        // Not QUIC, or not a connection we know
+        let (ip, udp) = datagram(false, 50001, 443, b"\x41unknown");
+        follower.chomp(at(1), &ip, &udp, &mut recv);
+
+        let received = received.read().unwrap();
+        let messages: Vec<_> = received
+            .iter()
+            .filter_map(|r| match r {
+                Received::Message(meta, _) => Some((meta.target.client_port(), meta.to_client)),
+                Received::SideData(_) => None,
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (40000, false),
+                (40000, true),
+                (40000, false),
+                (40000, true),
+                (50001, false),
+            ]
+        );
+        let migrations = received
+            .iter()
+            .filter(|r| matches!(r, Received::SideData(sd) if sd.is::<QuicMigrated>()))
+            .count();
+        assert_eq!(migrations, 1);
+        assert_eq!(follower.active_flows().count(), 2);
+    }
+}
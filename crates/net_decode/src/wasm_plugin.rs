@@ -0,0 +1,378 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! [`DecoderPlugin`]s compiled to WebAssembly, so that dissectors can be
+//! shipped on their own and run without being trusted.
+//!
+//! Plugins can't get at anything but the bytes they are given: there is no
+//! WASI, their memory is limited to [`MAX_MEMORY`] and each call into them
+//! may only run for [`FUEL_PER_CALL`]. One that traps or runs out is logged
+//! and treated as recognizing nothing from then on.
+//!
+//! A plugin module exports `memory` and these functions:
+//!
+//! - `clipper_buffer(len: i32) -> i32`: somewhere in memory with room for
+//!   `len` bytes for the host to put data in. It only has to stay valid for
+//!   the call that follows.
+//! - `clipper_recognize(to_server: i32, to_server_len: i32, to_client: i32,
+//!   to_client_len: i32) -> i32`: 0 for no, 1 for yes, 2 to be asked again
+//!   with more data. See [`DecoderPlugin::recognize`].
+//! - `clipper_new_decoder() -> i32`: a handle for a new flow's decoder.
+//! - `clipper_decode(decoder: i32, to_client: i32, data: i32, len: i32)`
+//! - `clipper_finish(decoder: i32)`: the flow is over.
+//! - `clipper_free_decoder(decoder: i32)`: the handle won't be used again.
+//!
+//! and may import these from the `clipper` module to produce
+//! [`Record`]s while decoding:
+//!
+//! - `emit_record(kind: i32, kind_len: i32)`
+//! - `emit_field(key: i32, key_len: i32, value: i32, value_len: i32)`: adds
+//!   a field to the last record emitted.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use wasmtime::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+use crate::{
+    chomp::IPTarget,
+    plugin::{DecoderPlugin, Recognition, Record, StreamDecoder},
+    Error,
+};
+
+/// How much memory a plugin may have, in bytes.
+pub const MAX_MEMORY: usize = 64 * 1024 * 1024;
+/// Roughly how many WebAssembly instructions a plugin may run per call.
+pub const FUEL_PER_CALL: u64 = 10_000_000;
+/// Longest string a plugin may emit.
+const MAX_STRING_LEN: usize = 64 * 1024;
+/// Most records a plugin may emit per call.
+const MAX_RECORDS_PER_CALL: usize = 1024;
+
+struct HostState {
+    limits: StoreLimits,
+    records: Vec<Record>,
+}
+
+fn guest_string(
+    caller: &mut Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> wasmtime::Result<String> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmtime::Error::msg("plugin does not export memory"))?;
+    let (start, len) = (ptr as u32 as usize, len as u32 as usize);
+    if len > MAX_STRING_LEN {
+        return Err(wasmtime::Error::msg(format!(
+            "plugin emitted a string of {len} bytes"
+        )));
+    }
+    let bytes = start
+        .checked_add(len)
+        .and_then(|end| memory.data(&caller).get(start..end))
+        .ok_or_else(|| wasmtime::Error::msg("plugin emitted a string out of bounds"))?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn linker(engine: &Engine) -> wasmtime::Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        "clipper",
+        "emit_record",
+        |mut caller: Caller<'_, HostState>, kind: i32, kind_len: i32| -> wasmtime::Result<()> {
+            let kind = guest_string(&mut caller, kind, kind_len)?;
+            let records = &mut caller.data_mut().records;
+            if records.len() >= MAX_RECORDS_PER_CALL {
+                return Err(wasmtime::Error::msg("plugin emitted too many records"));
+            }
+            records.push(Record {
+                kind,
+                fields: Vec::new(),
+            });
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "clipper",
+        "emit_field",
+        |mut caller: Caller<'_, HostState>,
+         key: i32,
+         key_len: i32,
+         value: i32,
+         value_len: i32|
+         -> wasmtime::Result<()> {
+            let key = guest_string(&mut caller, key, key_len)?;
+            let value = guest_string(&mut caller, value, value_len)?;
+            caller
+                .data_mut()
+                .records
+                .last_mut()
+                .ok_or_else(|| wasmtime::Error::msg("plugin emitted a field before a record"))?
+                .fields
+                .push((key, value));
+            Ok(())
+        },
+    )?;
+    Ok(linker)
+}
+
+struct Guest {
+    name: &'static str,
+    store: Store<HostState>,
+    memory: Memory,
+    buffer: TypedFunc<i32, i32>,
+    recognize: TypedFunc<(i32, i32, i32, i32), i32>,
+    new_decoder: TypedFunc<(), i32>,
+    decode: TypedFunc<(i32, i32, i32, i32), ()>,
+    finish: TypedFunc<i32, ()>,
+    free_decoder: TypedFunc<i32, ()>,
+    /// Set once the plugin has trapped, after which it is not run again.
+    broken: bool,
+}
+
+impl Guest {
+    /// Runs `f` with a fresh allowance of fuel, giving up on the plugin if
+    /// it fails.
+    fn run<R>(&mut self, f: impl FnOnce(&mut Self) -> wasmtime::Result<R>) -> Option<R> {
+        if self.broken {
+            return None;
+        }
+        let result = self
+            .store
+            .consume_fuel(0)
+            .and_then(|left| self.store.add_fuel(FUEL_PER_CALL.saturating_sub(left)))
+            .and_then(|()| f(self));
+        match result {
+            Ok(r) => Some(r),
+            Err(e) => {
+                tracing::error!("decoder plugin {} failed, disabling it: {e:?}", self.name);
+                self.broken = true;
+                self.store.data_mut().records.clear();
+                None
+            }
+        }
+    }
+
+    /// Copies `parts` one after the other into the plugin's buffer,
+    /// returning where each went.
+    fn write<const N: usize>(&mut self, parts: [&[u8]; N]) -> wasmtime::Result<[i32; N]> {
+        let total: usize = parts.iter().map(|p| p.len()).sum();
+        let total = i32::try_from(total)?;
+        let mut ptr = self.buffer.call(&mut self.store, total)?;
+        let mut ptrs = [0; N];
+        for (part, out) in parts.iter().zip(&mut ptrs) {
+            self.memory
+                .write(&mut self.store, ptr as u32 as usize, part)?;
+            *out = ptr;
+            ptr = ptr.wrapping_add(part.len() as i32);
+        }
+        Ok(ptrs)
+    }
+
+    fn take_records(&mut self) -> Vec<Record> {
+        std::mem::take(&mut self.store.data_mut().records)
+    }
+}
+
+/// A [`DecoderPlugin`] running in a WebAssembly sandbox. All of its flows
+/// share one instance.
+pub struct WasmPlugin {
+    name: &'static str,
+    guest: Arc<Mutex<Guest>>,
+}
+
+impl WasmPlugin {
+    /// Compiles and instantiates a plugin from WebAssembly, in binary or
+    /// text form. Plugins are meant to be loaded once and kept, so `name`
+    /// is never freed.
+    pub fn load(name: impl Into<String>, wasm: &[u8]) -> Result<Self, Error> {
+        let name: &'static str = Box::leak(name.into().into_boxed_str());
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, wasm)?;
+
+        let mut store = Store::new(
+            &engine,
+            HostState {
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(MAX_MEMORY)
+                    .instances(1)
+                    .build(),
+                records: Vec::new(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.add_fuel(FUEL_PER_CALL)?;
+        let instance = linker(&engine)?.instantiate(&mut store, &module)?;
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| format!("plugin {name} does not export memory"))?;
+        let guest = Guest {
+            name,
+            memory,
+            buffer: instance.get_typed_func(&mut store, "clipper_buffer")?,
+            recognize: instance.get_typed_func(&mut store, "clipper_recognize")?,
+            new_decoder: instance.get_typed_func(&mut store, "clipper_new_decoder")?,
+            decode: instance.get_typed_func(&mut store, "clipper_decode")?,
+            finish: instance.get_typed_func(&mut store, "clipper_finish")?,
+            free_decoder: instance.get_typed_func(&mut store, "clipper_free_decoder")?,
+            store,
+            broken: false,
+        };
+        Ok(WasmPlugin {
+            name,
+            guest: Arc::new(Mutex::new(guest)),
+        })
+    }
+
+    /// Loads a plugin from a `.wasm` or `.wat` file, named after the file.
+    pub fn load_file(path: &Path) -> Result<Self, Error> {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "wasm".to_string());
+        Self::load(name, &std::fs::read(path)?)
+    }
+}
+
+impl DecoderPlugin for WasmPlugin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn recognize(&self, _target: &IPTarget, to_server: &[u8], to_client: &[u8]) -> Recognition {
+        let mut guest = self.guest.lock().unwrap();
+        let answer = guest.run(|g| {
+            let [s, c] = g.write([to_server, to_client])?;
+            let (s_len, c_len) = (to_server.len() as i32, to_client.len() as i32);
+            g.recognize.call(&mut g.store, (s, s_len, c, c_len))
+        });
+        match answer {
+            Some(1) => Recognition::Yes,
+            Some(2) => Recognition::NeedMoreData,
+            _ => Recognition::No,
+        }
+    }
+
+    fn new_decoder(&self, _target: &IPTarget) -> Box<dyn StreamDecoder> {
+        let handle = self
+            .guest
+            .lock()
+            .unwrap()
+            .run(|g| g.new_decoder.call(&mut g.store, ()));
+        Box::new(WasmDecoder {
+            guest: self.guest.clone(),
+            handle,
+        })
+    }
+}
+
+struct WasmDecoder {
+    guest: Arc<Mutex<Guest>>,
+    /// `None` if the plugin failed to make one.
+    handle: Option<i32>,
+}
+
+impl StreamDecoder for WasmDecoder {
+    fn decode(&mut self, to_client: bool, data: &[u8], out: &mut Vec<Record>) {
+        let Some(handle) = self.handle else { return };
+        let mut guest = self.guest.lock().unwrap();
+        guest.run(|g| {
+            let [ptr] = g.write([data])?;
+            g.decode.call(
+                &mut g.store,
+                (handle, to_client as i32, ptr, data.len() as i32),
+            )
+        });
+        out.extend(guest.take_records());
+    }
+
+    fn finish(&mut self, out: &mut Vec<Record>) {
+        let Some(handle) = self.handle else { return };
+        let mut guest = self.guest.lock().unwrap();
+        guest.run(|g| g.finish.call(&mut g.store, handle));
+        out.extend(guest.take_records());
+    }
+}
+
+impl Drop for WasmDecoder {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle {
+            let mut guest = self.guest.lock().unwrap();
+            guest.run(|g| g.free_decoder.call(&mut g.store, handle));
+            guest.take_records();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::sample_target;
+
+    /// Records each chunk of a flow starting with `K`, and hangs on flows
+    /// starting with `L`.
+    const ECHO: &str = r#"
+        (module
+          (import "clipper" "emit_record" (func $emit (param i32 i32)))
+          (import "clipper" "emit_field" (func $field (param i32 i32 i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "dirserverclient")
+          (func (export "clipper_buffer") (param i32) (result i32) (i32.const 1024))
+          (func (export "clipper_recognize")
+            (param $s i32) (param $s_len i32) (param $c i32) (param $c_len i32) (result i32)
+            (if (i32.eqz (local.get $s_len)) (then (return (i32.const 2))))
+            (if (i32.eq (i32.load8_u (local.get $s)) (i32.const 76))
+              (then (loop $spin (br $spin))))
+            (i32.eq (i32.load8_u (local.get $s)) (i32.const 75)))
+          (func (export "clipper_new_decoder") (result i32) (i32.const 7))
+          (func (export "clipper_decode")
+            (param $decoder i32) (param $to_client i32) (param $p i32) (param $len i32)
+            (call $emit (local.get $p) (local.get $len))
+            (if (local.get $to_client)
+              (then (call $field (i32.const 16) (i32.const 3) (i32.const 25) (i32.const 6)))
+              (else (call $field (i32.const 16) (i32.const 3) (i32.const 19) (i32.const 6)))))
+          (func (export "clipper_finish") (param i32))
+          (func (export "clipper_free_decoder") (param i32)))
+    "#;
+
+    #[test]
+    fn test_wasm_plugin() {
+        let plugin = WasmPlugin::load("echo", ECHO.as_bytes()).unwrap();
+        let target = sample_target();
+        assert_eq!(
+            plugin.recognize(&target, b"", b""),
+            Recognition::NeedMoreData
+        );
+        assert_eq!(plugin.recognize(&target, b"GET", b""), Recognition::No);
+        assert_eq!(plugin.recognize(&target, b"KFK", b""), Recognition::Yes);
+
+        let mut decoder = plugin.new_decoder(&target);
+        let mut out = Vec::new();
+        decoder.decode(true, b"hello", &mut out);
+        assert_eq!(
+            out,
+            vec![Record {
+                kind: "hello".to_string(),
+                fields: vec![("dir".to_string(), "client".to_string())],
+            }]
+        );
+
+        // Runs out of fuel and is not run again
+        assert_eq!(plugin.recognize(&target, b"LOOP", b""), Recognition::No);
+        assert_eq!(plugin.recognize(&target, b"KFK", b""), Recognition::No);
+        out.clear();
+        decoder.decode(false, b"more", &mut out);
+        assert!(out.is_empty());
+    }
+}
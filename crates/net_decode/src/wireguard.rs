@@ -0,0 +1,502 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Decrypting WireGuard tunnels, so that what goes through them can be
+//! decoded like anything else.
+//!
+//! Keys come in Wireshark's WireGuard key log format, as written by
+//! `extract-handshakes.sh` and embedded in pcapng files by
+//! `editcap --inject-secrets wg,...`. Following a handshake takes the static
+//! and ephemeral private keys of either of its ends, and the public key of
+//! the other end if it is the initiator's keys we have.
+//!
+//! See <https://www.wireguard.com/papers/wireguard.pdf> section 5.4.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use base64::Engine;
+use blake2::{Blake2s256, Digest};
+use ring::aead;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+type Key = [u8; 32];
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+
+const MESSAGE_INITIATION: u8 = 1;
+const MESSAGE_RESPONSE: u8 = 2;
+const MESSAGE_TRANSPORT: u8 = 4;
+const INITIATION_LEN: usize = 148;
+const RESPONSE_LEN: usize = 92;
+const TRANSPORT_HEADER_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+const MAX_PENDING_HANDSHAKES: usize = 1024;
+const MAX_SESSIONS: usize = 4096;
+
+/// Keys from WireGuard key logs.
+#[derive(Clone, Default)]
+pub struct WireGuardKeys {
+    static_private: Vec<Key>,
+    remote_static_public: Vec<Key>,
+    ephemeral_private: Vec<Key>,
+    preshared: Vec<Key>,
+}
+
+impl fmt::Debug for WireGuardKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireGuardKeys")
+            .field("static_private", &self.static_private.len())
+            .field("remote_static_public", &self.remote_static_public.len())
+            .field("ephemeral_private", &self.ephemeral_private.len())
+            .field("preshared", &self.preshared.len())
+            .finish()
+    }
+}
+
+impl WireGuardKeys {
+    /// Loads a key log of `NAME = BASE64` lines, skipping any lines we don't
+    /// understand. Returns how many keys were loaded.
+    pub fn load_key_log(&mut self, key_log: &[u8]) -> usize {
+        let mut loaded = 0;
+        for line in String::from_utf8_lossy(key_log).lines() {
+            let Some((name, key)) = line.split_once('=') else {
+                continue;
+            };
+            let Some(key) = base64::engine::general_purpose::STANDARD
+                .decode(key.trim())
+                .ok()
+                .and_then(|k| Key::try_from(k).ok())
+            else {
+                continue;
+            };
+            let keys = match name.trim() {
+                "LOCAL_STATIC_PRIVATE_KEY" => &mut self.static_private,
+                "REMOTE_STATIC_PUBLIC_KEY" => &mut self.remote_static_public,
+                "LOCAL_EPHEMERAL_PRIVATE_KEY" => &mut self.ephemeral_private,
+                "PRESHARED_KEY" => &mut self.preshared,
+                _ => continue,
+            };
+            if !keys.contains(&key) {
+                keys.push(key);
+                loaded += 1;
+            }
+        }
+        loaded
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.static_private.is_empty() && self.ephemeral_private.is_empty()
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> Key {
+    let mut h = Blake2s256::new();
+    for part in parts {
+        h.update(part);
+    }
+    h.finalize().into()
+}
+
+/// HMAC-BLAKE2s. Our keys are never longer than a block, so they are
+/// never hashed first.
+fn hmac(key: &Key, parts: &[&[u8]]) -> Key {
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+
+    let mut inner = Blake2s256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Blake2s256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn kdf<const N: usize>(key: &Key, input: &[u8]) -> [Key; N] {
+    let prk = hmac(key, &[input]);
+    let mut out = [[0; 32]; N];
+    let mut prev = Vec::new();
+    for (i, o) in out.iter_mut().enumerate() {
+        prev.push(i as u8 + 1);
+        *o = hmac(&prk, &[&prev]);
+        prev = o.to_vec();
+    }
+    out
+}
+
+fn aead_open(key: &Key, counter: u64, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key).ok()?);
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    let mut buf = ciphertext.to_vec();
+    let len = key
+        .open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(aad),
+            &mut buf,
+        )
+        .ok()?
+        .len();
+    buf.truncate(len);
+    Some(buf)
+}
+
+fn dh(private: &Key, public: &Key) -> Key {
+    StaticSecret::from(*private)
+        .diffie_hellman(&PublicKey::from(*public))
+        .to_bytes()
+}
+
+fn public_key(private: &Key) -> Key {
+    PublicKey::from(&StaticSecret::from(*private)).to_bytes()
+}
+
+fn le32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[..4].try_into().unwrap())
+}
+
+fn key(data: &[u8]) -> Key {
+    data[..32].try_into().unwrap()
+}
+
+#[derive(Clone, Debug)]
+struct Initiation {
+    sender: u32,
+    ephemeral: Key,
+    encrypted_static: [u8; 32 + TAG_LEN],
+    encrypted_timestamp: [u8; 12 + TAG_LEN],
+}
+
+impl Initiation {
+    fn parse(data: &[u8]) -> Self {
+        Initiation {
+            sender: le32(&data[4..]),
+            ephemeral: key(&data[8..]),
+            encrypted_static: data[40..88].try_into().unwrap(),
+            encrypted_timestamp: data[88..116].try_into().unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Response {
+    sender: u32,
+    receiver: u32,
+    ephemeral: Key,
+    encrypted_nothing: [u8; TAG_LEN],
+}
+
+impl Response {
+    fn parse(data: &[u8]) -> Self {
+        Response {
+            sender: le32(&data[4..]),
+            receiver: le32(&data[8..]),
+            ephemeral: key(&data[12..]),
+            encrypted_nothing: data[44..60].try_into().unwrap(),
+        }
+    }
+}
+
+/// The Diffie-Hellman results that need the initiator's static public key,
+/// which is only known once the initiation is decrypted: `ss`, `ee` and
+/// `se`.
+type LaterAgreements<'a> = &'a dyn Fn(&Key) -> Option<[Key; 3]>;
+
+/// Follows a handshake with the responder's static public key
+/// `responder_static`, and `es`, returning the transport keys from the
+/// initiator to the responder and back.
+fn finish_handshake(
+    keys: &WireGuardKeys,
+    init: &Initiation,
+    resp: &Response,
+    responder_static: &Key,
+    dh_es: &Key,
+    later: LaterAgreements,
+) -> Option<[Key; 2]> {
+    let c = hash(&[CONSTRUCTION]);
+    let h = hash(&[&c, IDENTIFIER]);
+    let h = hash(&[&h, responder_static]);
+    let [c] = kdf(&c, &init.ephemeral);
+    let h = hash(&[&h, &init.ephemeral]);
+    let [c, k] = kdf(&c, dh_es);
+    let initiator_static = Key::try_from(aead_open(&k, 0, &h, &init.encrypted_static)?).ok()?;
+    let h = hash(&[&h, &init.encrypted_static]);
+    let [dh_ss, dh_ee, dh_se] = later(&initiator_static)?;
+    let [c, _] = kdf(&c, &dh_ss);
+    let h = hash(&[&h, &init.encrypted_timestamp]);
+
+    let [c] = kdf(&c, &resp.ephemeral);
+    let h = hash(&[&h, &resp.ephemeral]);
+    let [c] = kdf(&c, &dh_ee);
+    let [c] = kdf(&c, &dh_se);
+    for psk in keys.preshared.iter().chain([&[0; 32]]) {
+        let [c, tau, k] = kdf(&c, psk);
+        let h = hash(&[&h, &tau]);
+        if aead_open(&k, 0, &h, &resp.encrypted_nothing).is_some() {
+            return Some(kdf(&c, &[]));
+        }
+    }
+    None
+}
+
+/// Works out the transport keys of a handshake from whichever end's keys
+/// we have.
+fn derive_transport_keys(
+    keys: &WireGuardKeys,
+    init: &Initiation,
+    resp: &Response,
+) -> Option<[Key; 2]> {
+    for e in &keys.ephemeral_private {
+        let e_public = public_key(e);
+        if e_public == init.ephemeral {
+            for responder_static in &keys.remote_static_public {
+                let later = |initiator_static: &Key| {
+                    let s = keys
+                        .static_private
+                        .iter()
+                        .find(|s| public_key(s) == *initiator_static)?;
+                    Some([
+                        dh(s, responder_static),
+                        dh(e, &resp.ephemeral),
+                        dh(s, &resp.ephemeral),
+                    ])
+                };
+                let dh_es = dh(e, responder_static);
+                if let Some(t) =
+                    finish_handshake(keys, init, resp, responder_static, &dh_es, &later)
+                {
+                    return Some(t);
+                }
+            }
+        } else if e_public == resp.ephemeral {
+            for s in &keys.static_private {
+                let later = |initiator_static: &Key| {
+                    Some([
+                        dh(s, initiator_static),
+                        dh(e, &init.ephemeral),
+                        dh(e, initiator_static),
+                    ])
+                };
+                let dh_es = dh(s, &init.ephemeral);
+                if let Some(t) = finish_handshake(keys, init, resp, &public_key(s), &dh_es, &later)
+                {
+                    return Some(t);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Follows WireGuard handshakes and decrypts the transport messages of the
+/// ones we have keys for.
+///
+/// FIXME: sessions are found by receiver index alone, without the addresses
+/// of the peers.
+#[derive(Debug, Default)]
+pub struct WireGuardDecryptor {
+    /// Handshakes waiting for a response, by the initiator's index.
+    initiations: HashMap<u32, Initiation>,
+    /// Transport keys by the index of the receiving end.
+    sessions: HashMap<u32, Key>,
+    /// Session indexes, oldest first.
+    session_order: VecDeque<u32>,
+}
+
+impl WireGuardDecryptor {
+    /// Takes the payload of a UDP datagram. If it is a WireGuard transport
+    /// message we have the keys for, returns the receiver index and the IP
+    /// packet inside it.
+    pub fn on_datagram(&mut self, keys: &WireGuardKeys, data: &[u8]) -> Option<(u32, Vec<u8>)> {
+        // Message type, then three reserved zero bytes.
+        if data.get(1..4)? != [0, 0, 0] {
+            return None;
+        }
+        match (data[0], data.len()) {
+            (MESSAGE_INITIATION, INITIATION_LEN) => {
+                if self.initiations.len() >= MAX_PENDING_HANDSHAKES {
+                    tracing::debug!("too many unanswered wireguard handshakes, forgetting them");
+                    self.initiations.clear();
+                }
+                let init = Initiation::parse(data);
+                self.initiations.insert(init.sender, init);
+                None
+            }
+            (MESSAGE_RESPONSE, RESPONSE_LEN) => {
+                let resp = Response::parse(data);
+                let init = self.initiations.remove(&resp.receiver)?;
+                match derive_transport_keys(keys, &init, &resp) {
+                    Some([to_responder, to_initiator]) => {
+                        tracing::debug!(
+                            "wireguard session {} <-> {} established",
+                            init.sender,
+                            resp.sender
+                        );
+                        self.add_session(resp.sender, to_responder);
+                        self.add_session(init.sender, to_initiator);
+                    }
+                    None => tracing::debug!(
+                        "no keys for wireguard handshake {} <-> {}",
+                        init.sender,
+                        resp.sender
+                    ),
+                }
+                None
+            }
+            (MESSAGE_TRANSPORT, len) if len >= TRANSPORT_HEADER_LEN + TAG_LEN && len % 16 == 0 => {
+                let receiver = le32(&data[4..]);
+                let counter = u64::from_le_bytes(data[8..16].try_into().unwrap());
+                let key = self.sessions.get(&receiver)?;
+                let Some(packet) = aead_open(key, counter, &[], &data[TRANSPORT_HEADER_LEN..])
+                else {
+                    tracing::debug!("failed to decrypt wireguard message to {receiver}");
+                    return None;
+                };
+                // Empty ones are keepalives.
+                (!packet.is_empty()).then_some((receiver, packet))
+            }
+            _ => None,
+        }
+    }
+
+    fn add_session(&mut self, receiver: u32, key: Key) {
+        if self.sessions.insert(receiver, key).is_none() {
+            self.session_order.push_back(receiver);
+        }
+        while self.session_order.len() > MAX_SESSIONS {
+            if let Some(oldest) = self.session_order.pop_front() {
+                self.sessions.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_key_log() {
+        let mut keys = WireGuardKeys::default();
+        let log = b"LOCAL_STATIC_PRIVATE_KEY = QChaGDXeH3eQsbFAhueUNWFdq9KfpF3yl+eITjZbXEk=\n\
+            REMOTE_STATIC_PUBLIC_KEY = ZmTWdh7pyyiyvLCw0fFLKGpOj4pmn0vi7BddOjgMUFk=\n\
+            # a comment\n\
+            LOCAL_EPHEMERAL_PRIVATE_KEY = not base64\n\
+            PRESHARED_KEY = AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n";
+        assert_eq!(keys.load_key_log(log), 3);
+        // Already have them all
+        assert_eq!(keys.load_key_log(log), 0);
+        assert!(!keys.is_empty());
+    }
+
+    /// A handshake and a transport message built the way a peer would,
+    /// decrypted from the initiator's keys and from the responder's.
+    #[test]
+    fn test_handshake_and_transport() {
+        let [s_i, e_i, s_r, e_r] = [[1u8; 32], [2; 32], [3; 32], [4; 32]];
+        let psk = [5u8; 32];
+        let seal = |key: &Key, aad: &[u8], plaintext: &[u8]| {
+            let key = aead::LessSafeKey::new(
+                aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key).unwrap(),
+            );
+            let mut buf = plaintext.to_vec();
+            key.seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key([0; 12]),
+                aead::Aad::from(aad),
+                &mut buf,
+            )
+            .unwrap();
+            buf
+        };
+
+        // Initiator side of section 5.4.2
+        let c = hash(&[CONSTRUCTION]);
+        let h = hash(&[&c, IDENTIFIER]);
+        let h = hash(&[&h, &public_key(&s_r)]);
+        let [c] = kdf(&c, &public_key(&e_i));
+        let h = hash(&[&h, &public_key(&e_i)]);
+        let [c, k] = kdf(&c, &dh(&e_i, &public_key(&s_r)));
+        let encrypted_static = seal(&k, &h, &public_key(&s_i));
+        let h = hash(&[&h, &encrypted_static]);
+        let [c, k] = kdf(&c, &dh(&s_i, &public_key(&s_r)));
+        let encrypted_timestamp = seal(&k, &h, &[0; 12]);
+        let h = hash(&[&h, &encrypted_timestamp]);
+
+        let mut initiation = vec![MESSAGE_INITIATION, 0, 0, 0, 10, 0, 0, 0];
+        initiation.extend(public_key(&e_i));
+        initiation.extend(encrypted_static);
+        initiation.extend(encrypted_timestamp);
+        initiation.extend([0; 32]);
+
+        // Responder side of section 5.4.3
+        let [c] = kdf(&c, &public_key(&e_r));
+        let h = hash(&[&h, &public_key(&e_r)]);
+        let [c] = kdf(&c, &dh(&e_r, &public_key(&e_i)));
+        let [c] = kdf(&c, &dh(&e_r, &public_key(&s_i)));
+        let [c, tau, k] = kdf(&c, &psk);
+        let h = hash(&[&h, &tau]);
+        let encrypted_nothing = seal(&k, &h, &[]);
+        let [to_responder, _] = kdf(&c, &[]);
+
+        let mut response = vec![MESSAGE_RESPONSE, 0, 0, 0, 20, 0, 0, 0, 10, 0, 0, 0];
+        response.extend(public_key(&e_r));
+        response.extend(encrypted_nothing);
+        response.extend([0; 32]);
+
+        let inner = [0x45; 16];
+        let mut transport = vec![MESSAGE_TRANSPORT, 0, 0, 0, 20, 0, 0, 0];
+        transport.extend([0; 8]);
+        transport.extend(seal(&to_responder, &[], &inner));
+
+        let b64 = |k: &Key| base64::engine::general_purpose::STANDARD.encode(k);
+        let initiator_log = format!(
+            "LOCAL_STATIC_PRIVATE_KEY={}\nLOCAL_EPHEMERAL_PRIVATE_KEY={}\n\
+             REMOTE_STATIC_PUBLIC_KEY={}\nPRESHARED_KEY={}\n",
+            b64(&s_i),
+            b64(&e_i),
+            b64(&public_key(&s_r)),
+            b64(&psk)
+        );
+        let responder_log = format!(
+            "LOCAL_STATIC_PRIVATE_KEY={}\nLOCAL_EPHEMERAL_PRIVATE_KEY={}\nPRESHARED_KEY={}\n",
+            b64(&s_r),
+            b64(&e_r),
+            b64(&psk)
+        );
+
+        for log in [initiator_log, responder_log] {
+            let mut keys = WireGuardKeys::default();
+            keys.load_key_log(log.as_bytes());
+            let mut wg = WireGuardDecryptor::default();
+            assert_eq!(wg.on_datagram(&keys, &initiation), None);
+            assert_eq!(wg.on_datagram(&keys, &response), None);
+            assert_eq!(
+                wg.on_datagram(&keys, &transport),
+                Some((20, inner.to_vec()))
+            );
+        }
+
+        // Without the pre-shared key
+        let mut keys = WireGuardKeys::default();
+        keys.load_key_log(
+            format!(
+                "LOCAL_STATIC_PRIVATE_KEY={}\nLOCAL_EPHEMERAL_PRIVATE_KEY={}\n",
+                b64(&s_r),
+                b64(&e_r)
+            )
+            .as_bytes(),
+        );
+        let mut wg = WireGuardDecryptor::default();
+        wg.on_datagram(&keys, &initiation);
+        wg.on_datagram(&keys, &response);
+        assert_eq!(wg.on_datagram(&keys, &transport), None);
+    }
+}
@@ -0,0 +1,18 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "ebpf")]
+    {
+        let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+        for name in ["flow_owner", "go_tls_keys"] {
+            let src = format!("src/bpf/{name}.bpf.c");
+            libbpf_cargo::SkeletonBuilder::new()
+                .source(&src)
+                .build_and_generate(out_dir.join(format!("{name}.skel.rs")))?;
+            println!("cargo:rerun-if-changed={src}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,228 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture straight off a network interface with an `AF_PACKET` socket.
+//!
+//! Unlike [`unprivileged`](crate::unprivileged) capture, this sees everything
+//! on the interface rather than one program's traffic, and needs root or
+//! `CAP_NET_RAW`. The socket is read the same way, with
+//! [`UnprivilegedCapture`](crate::unprivileged::UnprivilegedCapture), or
+//! with a [`RingCapture`](crate::ring::RingCapture) for busy interfaces.
+//!
+//! Any packet socket, including the one unprivileged capture makes, can
+//! have a classic BPF filter attached with [`attach_filter`].
+
+use std::{
+    fs::File,
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    ioctl_readwrite_bad, libc,
+    net::if_::if_nametoindex,
+    sched::{setns, CloneFlags},
+};
+
+use crate::{
+    error::{AddContext, Error},
+    probe::make_ifreq,
+    ring::RingOptions,
+    unprivileged::make_capture_socket,
+    xdp::XdpOptions,
+    KernelStats,
+};
+
+// From linux/if_arp.h: both of these come with Ethernet headers.
+const ARPHRD_ETHER: u32 = 1;
+const ARPHRD_LOOPBACK: u32 = 772;
+
+ioctl_readwrite_bad!(get_hw_addr, libc::SIOCGIFHWADDR, libc::ifreq);
+
+/// `struct tpacket_stats_v3`. Sockets without a `TPACKET_V3` ring only
+/// fill in the first two fields.
+#[repr(C)]
+#[derive(Default)]
+struct TpacketStats {
+    tp_packets: u32,
+    tp_drops: u32,
+    tp_freeze_q_cnt: u32,
+}
+
+/// Which interface to capture on, and how.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceOptions {
+    pub interface: String,
+    /// Also take packets addressed to other machines, for instance on a
+    /// mirror port. The interface goes back to normal when the socket is
+    /// closed.
+    pub promiscuous: bool,
+    /// Read packets out of a ring shared with the kernel rather than one
+    /// at a time, for busy interfaces.
+    pub ring: Option<RingOptions>,
+    /// Capture with `AF_XDP` sockets instead of a packet socket, for even
+    /// busier ones. See [`xdp`](crate::xdp) for what that costs.
+    pub xdp: Option<XdpOptions>,
+    /// Network namespace the interface is in, such as a container's, as a
+    /// file like those [`netns_path`] gives. Entering it needs
+    /// `CAP_SYS_ADMIN`.
+    pub netns: Option<PathBuf>,
+}
+
+/// Where the network namespace `spec` is: a process ID means the namespace
+/// of that process, a path is used as is, and anything else is the name of
+/// one made by `ip netns add`.
+pub fn netns_path(spec: &str) -> PathBuf {
+    if spec.contains('/') {
+        PathBuf::from(spec)
+    } else if spec.parse::<u32>().is_ok() {
+        PathBuf::from(format!("/proc/{spec}/ns/net"))
+    } else {
+        Path::new("/run/netns").join(spec)
+    }
+}
+
+/// Runs `f` on a thread of its own inside the network namespace `netns`,
+/// leaving the rest of the process where it is. Sockets made there stay in
+/// the namespace after the thread is gone.
+pub(crate) fn in_netns<T: Send>(
+    netns: &Path,
+    f: impl FnOnce() -> Result<T, Error> + Send,
+) -> Result<T, Error> {
+    let ns = File::open(netns).map_err(|e| {
+        Error::Other(format!("open network namespace {}: {e}", netns.display()).into())
+    })?;
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWNET).context("enter network namespace")?;
+            f()
+        })
+        .join()
+        .expect("opening an interface panicked")
+    })
+}
+
+/// Opens a capture socket on an interface. Only interfaces whose packets
+/// have Ethernet headers are supported, which excludes tunnels such as
+/// WireGuard and `tun` devices.
+pub fn open_interface(options: &InterfaceOptions) -> Result<OwnedFd, Error> {
+    match &options.netns {
+        Some(netns) => {
+            let sock = in_netns(netns, || open_interface_here(options))?;
+            tracing::debug!("{} is in {}", options.interface, netns.display());
+            Ok(sock)
+        }
+        None => open_interface_here(options),
+    }
+}
+
+fn open_interface_here(options: &InterfaceOptions) -> Result<OwnedFd, Error> {
+    let name = &options.interface;
+    let if_index = if_nametoindex(name.as_str())
+        .map_err(|_| Error::Other(format!("no such interface {name:?}").into()))?;
+
+    let sock = unsafe { OwnedFd::from_raw_fd(make_capture_socket(name)?) };
+
+    // Asked of the socket rather than read from /sys/class/net, which shows
+    // the interfaces of whichever namespace sysfs was mounted from.
+    let mut ifr = make_ifreq(name);
+    unsafe { get_hw_addr(sock.as_raw_fd(), &mut ifr) }.context("get interface type")?;
+    let arp_type = unsafe { ifr.ifr_ifru.ifru_hwaddr.sa_family } as u32;
+    if ![ARPHRD_ETHER, ARPHRD_LOOPBACK].contains(&arp_type) {
+        return Err(Error::Other(
+            format!("{name} is not an Ethernet interface (ARP type {arp_type})").into(),
+        ));
+    }
+
+    if options.promiscuous {
+        let mreq = libc::packet_mreq {
+            mr_ifindex: if_index as libc::c_int,
+            mr_type: libc::PACKET_MR_PROMISC as u16,
+            mr_alen: 0,
+            mr_address: [0; 8],
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_ADD_MEMBERSHIP,
+                &mreq as *const _ as *const libc::c_void,
+                mem::size_of_val(&mreq) as libc::socklen_t,
+            )
+        };
+        Errno::result(ret).map_err(|e| Error::Errno("set promiscuous mode", e))?;
+    }
+
+    tracing::debug!(
+        "capturing on {name}{}",
+        if options.promiscuous {
+            " in promiscuous mode"
+        } else {
+            ""
+        }
+    );
+    Ok(sock)
+}
+
+/// Attaches a classic BPF `program` to a capture socket, so that the kernel
+/// drops what it rejects before it is queued for us. Packets queued before
+/// the filter was attached are thrown away, since they were not filtered.
+pub fn attach_filter(sock: &impl AsRawFd, program: &[libc::sock_filter]) -> Result<(), Error> {
+    let prog = libc::sock_fprog {
+        len: u16::try_from(program.len())
+            .map_err(|_| Error::StringError("capture filter is too long"))?,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &prog as *const _ as *const libc::c_void,
+            mem::size_of_val(&prog) as libc::socklen_t,
+        )
+    };
+    Errno::result(ret).map_err(|e| Error::Errno("attach capture filter", e))?;
+
+    let mut buf = [0u8; 1];
+    loop {
+        let ret = unsafe {
+            libc::recv(
+                sock.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT | libc::MSG_TRUNC,
+            )
+        };
+        match Errno::result(ret) {
+            Ok(_) => {}
+            Err(Errno::EAGAIN) => return Ok(()),
+            Err(e) => return Err(Error::Errno("drain capture socket", e)),
+        }
+    }
+}
+
+/// What the kernel has counted on a packet socket since this was last
+/// called, or since it was opened. Reading the counts resets them.
+pub fn kernel_stats(sock: RawFd) -> Result<KernelStats, Error> {
+    let mut stats = TpacketStats::default();
+    let mut len = mem::size_of_val(&stats) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            sock,
+            libc::SOL_PACKET,
+            libc::PACKET_STATISTICS,
+            &mut stats as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    Errno::result(ret).context("get packet statistics")?;
+    // tp_packets already includes the drops.
+    Ok(KernelStats {
+        received: stats.tp_packets as u64,
+        dropped: stats.tp_drops as u64,
+    })
+}
@@ -0,0 +1,89 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+// Reports which process opened each outgoing TCP connection, from the
+// sock:inet_sock_set_state tracepoint. Connecting is done in the context of
+// the process, unlike everything after it, which is done from softirqs.
+
+#include <linux/bpf.h>
+#include <linux/types.h>
+#include <bpf/bpf_helpers.h>
+
+#define AF_INET 2
+#define AF_INET6 10
+#define IPPROTO_TCP 6
+#define TCP_SYN_SENT 2
+#define TCP_CLOSE 7
+
+// From /sys/kernel/tracing/events/sock/inet_sock_set_state/format
+struct inet_sock_set_state_args {
+    __u64 common;
+    const void *skaddr;
+    int oldstate;
+    int newstate;
+    __u16 sport;
+    __u16 dport;
+    __u16 family;
+    __u16 protocol;
+    __u8 saddr[4];
+    __u8 daddr[4];
+    __u8 saddr_v6[16];
+    __u8 daddr_v6[16];
+};
+
+// Read by OwnerEvent in flow_owner.rs.
+struct owner_event {
+    __u32 pid;
+    __u32 tgid;
+    __u64 cgroup_id;
+    char comm[16];
+    __u16 family;
+    // Host byte order.
+    __u16 sport;
+    __u16 dport;
+    __u16 pad;
+    __u8 saddr[16];
+    __u8 daddr[16];
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 256 * 1024);
+} owners SEC(".maps");
+
+SEC("tracepoint/sock/inet_sock_set_state")
+int on_set_state(struct inet_sock_set_state_args *args)
+{
+    if (args->protocol != IPPROTO_TCP || args->oldstate != TCP_CLOSE ||
+        args->newstate != TCP_SYN_SENT)
+        return 0;
+
+    struct owner_event *e = bpf_ringbuf_reserve(&owners, sizeof(*e), 0);
+    if (!e)
+        return 0;
+
+    __u64 pid_tgid = bpf_get_current_pid_tgid();
+    e->pid = pid_tgid;
+    e->tgid = pid_tgid >> 32;
+    e->cgroup_id = bpf_get_current_cgroup_id();
+    bpf_get_current_comm(e->comm, sizeof(e->comm));
+    e->family = args->family;
+    e->sport = args->sport;
+    e->dport = args->dport;
+    e->pad = 0;
+    __builtin_memset(e->saddr, 0, sizeof(e->saddr));
+    __builtin_memset(e->daddr, 0, sizeof(e->daddr));
+    if (args->family == AF_INET) {
+        __builtin_memcpy(e->saddr, args->saddr, 4);
+        __builtin_memcpy(e->daddr, args->daddr, 4);
+    } else {
+        __builtin_memcpy(e->saddr, args->saddr_v6, 16);
+        __builtin_memcpy(e->daddr, args->daddr_v6, 16);
+    }
+
+    bpf_ringbuf_submit(e, 0);
+    return 0;
+}
+
+char LICENSE[] SEC("license") = "Dual MPL/GPL";
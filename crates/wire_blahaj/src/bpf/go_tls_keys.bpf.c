@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+// Reports every TLS secret a Go program derives, from a uprobe on
+// crypto/tls.(*Config).writeKeyLog. crypto/tls calls it for every secret,
+// and only then checks if there is a KeyLogWriter to write it to.
+//
+// The arguments are in registers, in Go's internal ABI (Go 1.17 and newer
+// on amd64, 1.18 and newer on arm64):
+//   func (c *Config) writeKeyLog(label string, clientRandom, secret []byte) error
+// goes c, label.ptr, label.len, clientRandom.ptr, .len, .cap, secret.ptr,
+// .len, .cap.
+
+#include <linux/bpf.h>
+#include <linux/types.h>
+#include <bpf/bpf_helpers.h>
+
+#define GO_ARGS 9
+#define MAX_LABEL 32
+#define MAX_RANDOM 32
+#define MAX_SECRET 64
+
+#if defined(__TARGET_ARCH_x86)
+// From arch/x86/include/uapi/asm/ptrace.h
+struct go_regs {
+    __u64 r15, r14, r13, r12, bp, bx, r11, r10, r9, r8, ax, cx, dx, si, di;
+};
+
+static __always_inline void go_args(struct go_regs *regs, __u64 *args)
+{
+    args[0] = regs->ax;
+    args[1] = regs->bx;
+    args[2] = regs->cx;
+    args[3] = regs->di;
+    args[4] = regs->si;
+    args[5] = regs->r8;
+    args[6] = regs->r9;
+    args[7] = regs->r10;
+    args[8] = regs->r11;
+}
+#elif defined(__TARGET_ARCH_arm64)
+// From arch/arm64/include/uapi/asm/ptrace.h
+struct go_regs {
+    __u64 regs[31];
+};
+
+static __always_inline void go_args(struct go_regs *regs, __u64 *args)
+{
+    for (int i = 0; i < GO_ARGS; i++)
+        args[i] = regs->regs[i];
+}
+#else
+#error "Go's register ABI is only known for x86_64 and arm64"
+#endif
+
+// Read by RawKeyEvent in go_tls_keys.rs.
+struct key_event {
+    __u32 tgid;
+    __u32 label_len;
+    __u32 random_len;
+    __u32 secret_len;
+    char label[MAX_LABEL];
+    __u8 client_random[MAX_RANDOM];
+    __u8 secret[MAX_SECRET];
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 256 * 1024);
+} keys SEC(".maps");
+
+SEC("uprobe")
+int on_write_key_log(struct go_regs *regs)
+{
+    __u64 args[GO_ARGS];
+    go_args(regs, args);
+
+    __u64 label_len = args[2];
+    __u64 random_len = args[4];
+    __u64 secret_len = args[7];
+    if (label_len > MAX_LABEL || random_len > MAX_RANDOM || secret_len > MAX_SECRET)
+        return 0;
+
+    struct key_event *e = bpf_ringbuf_reserve(&keys, sizeof(*e), 0);
+    if (!e)
+        return 0;
+
+    e->tgid = bpf_get_current_pid_tgid() >> 32;
+    e->label_len = label_len;
+    e->random_len = random_len;
+    e->secret_len = secret_len;
+    if (bpf_probe_read_user(e->label, label_len, (void *)args[1]) ||
+        bpf_probe_read_user(e->client_random, random_len, (void *)args[3]) ||
+        bpf_probe_read_user(e->secret, secret_len, (void *)args[6])) {
+        bpf_ringbuf_discard(e, 0);
+        return 0;
+    }
+
+    bpf_ringbuf_submit(e, 0);
+    return 0;
+}
+
+char LICENSE[] SEC("license") = "Dual MPL/GPL";
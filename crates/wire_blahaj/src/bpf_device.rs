@@ -0,0 +1,299 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture straight off a network interface on macOS, through a BPF device.
+//!
+//! There is no `AF_PACKET` on macOS: one of the `/dev/bpfN` devices is
+//! opened instead and pointed at an interface with ioctls. Each read gives
+//! a whole buffer of packets, each after a `struct bpf_hdr`. Opening the
+//! devices needs root, or read access to them as set up by e.g. Wireshark's
+//! ChmodBPF.
+//!
+//! See bpf(4).
+
+use std::{
+    collections::VecDeque,
+    ffi::{c_uint, CStr},
+    fs::OpenOptions,
+    io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use nix::{
+    errno::Errno, ioctl_none, ioctl_read, ioctl_readwrite, ioctl_write_ptr, libc,
+    net::if_::if_nametoindex, sys::time::TimeSpec,
+};
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    error::{AddContext, Error},
+    ts_to_nanos, CapturedPacketMeta, KernelStats,
+};
+
+// From net/bpf.h
+const DLT_EN10MB: c_uint = 1;
+const BPF_ALIGNMENT: usize = mem::size_of::<i32>();
+const IFNAMSIZ: usize = 16;
+
+/// How big a buffer to ask for. The kernel caps it at `debug.bpf_maxbufsize`,
+/// 512KiB by default.
+const BUFFER_SIZE: c_uint = 1 << 20;
+
+/// Devices tried before giving up on finding a free one.
+const MAX_DEVICES: usize = 256;
+
+/// `struct bpf_insn`: one instruction of a classic BPF program.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BpfInsn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+#[repr(C)]
+struct BpfProgram {
+    bf_len: c_uint,
+    bf_insns: *mut BpfInsn,
+}
+
+/// `struct ifreq`, of which BPF only looks at the name.
+#[repr(C)]
+struct IfReq {
+    ifr_name: [u8; IFNAMSIZ],
+    ifr_ifru: [u8; 16],
+}
+
+/// `struct bpf_hdr`. User space gets 32 bit timestamps even on 64 bit
+/// machines.
+#[repr(C)]
+struct BpfHdr {
+    tv_sec: i32,
+    tv_usec: i32,
+    bh_caplen: u32,
+    bh_datalen: u32,
+    bh_hdrlen: u16,
+}
+
+/// `struct bpf_stat`.
+#[repr(C)]
+struct BpfStat {
+    bs_recv: c_uint,
+    bs_drop: c_uint,
+}
+
+ioctl_read!(get_buffer_len, b'B', 102, c_uint);
+ioctl_readwrite!(set_buffer_len, b'B', 102, c_uint);
+ioctl_write_ptr!(set_filter, b'B', 103, BpfProgram);
+ioctl_none!(set_promiscuous, b'B', 105);
+ioctl_read!(get_link_type, b'B', 106, c_uint);
+ioctl_read!(get_interface, b'B', 107, IfReq);
+ioctl_write_ptr!(set_interface, b'B', 108, IfReq);
+ioctl_read!(get_stats, b'B', 111, BpfStat);
+ioctl_write_ptr!(set_immediate, b'B', 112, c_uint);
+
+fn bpf_word_align(n: usize) -> usize {
+    (n + BPF_ALIGNMENT - 1) & !(BPF_ALIGNMENT - 1)
+}
+
+/// Which interface to capture on, and how.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceOptions {
+    pub interface: String,
+    /// Also take packets addressed to other machines. The interface goes
+    /// back to normal when the device is closed.
+    pub promiscuous: bool,
+}
+
+/// Opens the first BPF device nobody else has.
+fn open_device() -> Result<OwnedFd, Error> {
+    for n in 0..MAX_DEVICES {
+        match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(format!("/dev/bpf{n}"))
+        {
+            Ok(f) => return Ok(f.into()),
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => continue,
+            Err(e) => return Err(Error::IoError("open BPF device", e)),
+        }
+    }
+    Err(Error::StringError("all BPF devices are in use"))
+}
+
+/// Opens a BPF device on an interface. Only interfaces whose packets have
+/// Ethernet headers are supported, which excludes the loopback interface
+/// `lo0` and tunnels such as `utun` devices.
+pub fn open_interface(options: &InterfaceOptions) -> Result<OwnedFd, Error> {
+    let name = &options.interface;
+    if name.len() >= IFNAMSIZ {
+        return Err(Error::Other(format!("no such interface {name:?}").into()));
+    }
+    let fd = open_device()?;
+    let raw_fd = fd.as_raw_fd();
+
+    // Has to be set before the interface is.
+    let mut buffer_len = BUFFER_SIZE;
+    unsafe { set_buffer_len(raw_fd, &mut buffer_len).context("set BPF buffer size")? };
+
+    let mut ifr = IfReq {
+        ifr_name: [0; IFNAMSIZ],
+        ifr_ifru: [0; 16],
+    };
+    ifr.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+    unsafe { set_interface(raw_fd, &ifr) }
+        .map_err(|_| Error::Other(format!("no such interface {name:?}").into()))?;
+
+    let mut link_type = 0;
+    unsafe { get_link_type(raw_fd, &mut link_type).context("get link type")? };
+    if link_type != DLT_EN10MB {
+        return Err(Error::Other(
+            format!("{name} is not an Ethernet interface (link type {link_type})").into(),
+        ));
+    }
+
+    // Otherwise packets sit in the buffer until it is full.
+    unsafe { set_immediate(raw_fd, &1).context("set immediate mode")? };
+
+    if options.promiscuous {
+        unsafe { set_promiscuous(raw_fd).context("set promiscuous mode")? };
+    }
+
+    tracing::debug!(
+        "capturing on {name} with a {buffer_len} byte buffer{}",
+        if options.promiscuous {
+            " in promiscuous mode"
+        } else {
+            ""
+        }
+    );
+    Ok(fd)
+}
+
+/// Attaches a classic BPF `program` to a BPF device, so that the kernel
+/// drops what it rejects. This also throws away anything captured before.
+pub fn attach_filter(fd: &impl AsRawFd, program: &[BpfInsn]) -> Result<(), Error> {
+    let prog = BpfProgram {
+        bf_len: c_uint::try_from(program.len())
+            .map_err(|_| Error::StringError("capture filter is too long"))?,
+        bf_insns: program.as_ptr() as *mut BpfInsn,
+    };
+    unsafe { set_filter(fd.as_raw_fd(), &prog).context("attach capture filter")? };
+    Ok(())
+}
+
+/// What the kernel has counted on a BPF device since it was opened.
+pub fn kernel_stats(fd: RawFd) -> Result<KernelStats, Error> {
+    let mut stats = BpfStat {
+        bs_recv: 0,
+        bs_drop: 0,
+    };
+    unsafe { get_stats(fd, &mut stats).context("get BPF statistics")? };
+    Ok(KernelStats {
+        received: stats.bs_recv as u64,
+        dropped: stats.bs_drop as u64,
+    })
+}
+
+/// Captured packets out of a BPF device. Yields the same as
+/// [`RingCapture`](crate::ring::RingCapture) does on Linux.
+pub struct BpfCapture {
+    fd: AsyncFd<OwnedFd>,
+    /// Reads have to be exactly the size of the device's buffer.
+    buf: Vec<u8>,
+    if_index: usize,
+    /// Packets copied out of the last read, not yet taken.
+    ready: VecDeque<(Vec<u8>, CapturedPacketMeta)>,
+}
+
+impl BpfCapture {
+    /// Takes over a BPF device opened by [`open_interface`].
+    ///
+    /// Safety: `raw_fd` must be an open BPF device that nothing else owns.
+    pub unsafe fn new(raw_fd: RawFd) -> io::Result<BpfCapture> {
+        let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+
+        let mut buffer_len = 0;
+        unsafe { get_buffer_len(raw_fd, &mut buffer_len) }.map_err(io::Error::from)?;
+
+        let mut ifr: IfReq = unsafe { mem::zeroed() };
+        unsafe { get_interface(raw_fd, &mut ifr) }.map_err(io::Error::from)?;
+        let name = CStr::from_bytes_until_nul(&ifr.ifr_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "bad interface name"))?;
+        let if_index = if_nametoindex(name).map_err(io::Error::from)?;
+
+        Ok(BpfCapture {
+            fd: AsyncFd::new(fd)?,
+            buf: vec![0; buffer_len as usize],
+            if_index: if_index as usize,
+            ready: VecDeque::new(),
+        })
+    }
+
+    /// Splits what one read gave into packets.
+    fn take_packets(&mut self, len: usize) {
+        let mut offset = 0;
+        while offset + mem::size_of::<BpfHdr>() <= len {
+            let h = unsafe { (self.buf.as_ptr().add(offset) as *const BpfHdr).read_unaligned() };
+            let start = offset + h.bh_hdrlen as usize;
+            let end = start + h.bh_caplen as usize;
+            if end > len {
+                tracing::warn!("BPF device gave a packet past the end of the buffer");
+                break;
+            }
+            self.ready.push_back((
+                self.buf[start..end].to_vec(),
+                CapturedPacketMeta {
+                    len: h.bh_datalen as usize,
+                    time: ts_to_nanos(TimeSpec::new(h.tv_sec as _, (h.tv_usec as i64 * 1000) as _)),
+                    if_index: self.if_index,
+                    packet_type: None,
+                },
+            ));
+            offset = bpf_word_align(end);
+        }
+    }
+}
+
+impl futures::Stream for BpfCapture {
+    type Item = Result<(Vec<u8>, CapturedPacketMeta), std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(packet) = this.ready.pop_front() {
+                return Poll::Ready(Some(Ok(packet)));
+            }
+
+            let read = {
+                let mut guard = ready!(this.fd.poll_read_ready(cx))?;
+                let buf = &mut this.buf;
+                guard.try_io(|inner| {
+                    let ret = unsafe {
+                        libc::read(
+                            inner.as_raw_fd(),
+                            buf.as_mut_ptr() as *mut libc::c_void,
+                            buf.len(),
+                        )
+                    };
+                    Errno::result(ret)
+                        .map(|n| n as usize)
+                        .map_err(io::Error::from)
+                })
+            };
+            match read {
+                Ok(Ok(len)) => this.take_packets(len),
+                Ok(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Errors from setting up capture, on any platform.
+
+#[cfg(unix)]
+use nix::errno::Errno;
+
+pub type DynError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[cfg(unix)]
+    #[error("{0}: {1}")]
+    Errno(&'static str, Errno),
+    #[error("{0}: {1}")]
+    IoError(&'static str, std::io::Error),
+    #[error("{0}")]
+    StringError(&'static str),
+    #[error("{0}")]
+    Other(DynError),
+}
+
+pub(crate) trait AddContext<T> {
+    fn context(self, s: &'static str) -> Result<T, Error>;
+}
+
+#[cfg(unix)]
+impl<T> AddContext<T> for Result<T, Errno> {
+    fn context(self, s: &'static str) -> Result<T, Error> {
+        self.map_err(|e| Error::Errno(s, e))
+    }
+}
+
+impl<T> AddContext<T> for Result<T, std::io::Error> {
+    fn context(self, s: &'static str) -> Result<T, Error> {
+        self.map_err(|e| Error::IoError(s, e))
+    }
+}
@@ -0,0 +1,178 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Finding out which process opened each connection, with an eBPF program
+//! on the `sock:inet_sock_set_state` tracepoint.
+//!
+//! Only outgoing TCP connections are seen, since the kernel accepts
+//! connections and does everything for UDP outside of the context of the
+//! process. Loading the program needs root, or `CAP_BPF` and `CAP_PERFMON`.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::error::{DynError, Error};
+
+mod skel {
+    include!(concat!(env!("OUT_DIR"), "/flow_owner.skel.rs"));
+}
+
+use skel::FlowOwnerSkelBuilder;
+
+const AF_INET: u16 = 2;
+const AF_INET6: u16 = 10;
+
+/// `struct owner_event` in `flow_owner.bpf.c`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct RawOwnerEvent {
+    pid: u32,
+    tgid: u32,
+    cgroup_id: u64,
+    comm: [u8; 16],
+    family: u16,
+    sport: u16,
+    dport: u16,
+    pad: u16,
+    saddr: [u8; 16],
+    daddr: [u8; 16],
+}
+
+/// A process opening a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnerEvent {
+    /// Process, rather than thread, ID.
+    pub pid: u32,
+    pub comm: String,
+    pub cgroup_id: u64,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+}
+
+impl OwnerEvent {
+    fn parse(data: &[u8]) -> Option<OwnerEvent> {
+        if data.len() < std::mem::size_of::<RawOwnerEvent>() {
+            return None;
+        }
+        let raw = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const RawOwnerEvent) };
+        let ip = |addr: [u8; 16]| -> Option<IpAddr> {
+            match raw.family {
+                AF_INET => Some(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]).into()),
+                // Dual-stack sockets connecting over IPv4 have mapped
+                // addresses, which is not what is on the wire.
+                AF_INET6 => Some(match Ipv6Addr::from(addr).to_ipv4_mapped() {
+                    Some(v4) => v4.into(),
+                    None => Ipv6Addr::from(addr).into(),
+                }),
+                _ => None,
+            }
+        };
+        let comm_len = raw
+            .comm
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(raw.comm.len());
+        Some(OwnerEvent {
+            pid: raw.tgid,
+            comm: String::from_utf8_lossy(&raw.comm[..comm_len]).into_owned(),
+            cgroup_id: raw.cgroup_id,
+            client: SocketAddr::new(ip(raw.saddr)?, raw.sport),
+            server: SocketAddr::new(ip(raw.daddr)?, raw.dport),
+        })
+    }
+}
+
+/// The eBPF program, loaded and attached. Detached when dropped.
+pub struct FlowOwnerTracer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FlowOwnerTracer {
+    /// Loads the program and calls `on_owner`, on another thread, for every
+    /// connection opened from here on.
+    pub fn start(
+        mut on_owner: impl FnMut(OwnerEvent) + Send + 'static,
+    ) -> Result<FlowOwnerTracer, Error> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (loaded_send, loaded) = mpsc::sync_channel(1);
+
+        // libbpf's objects can't leave the thread they were made on.
+        let thread = thread::Builder::new()
+            .name("flow-owner".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    let load = || -> Result<_, DynError> {
+                        let mut skel = FlowOwnerSkelBuilder::default().open()?.load()?;
+                        skel.attach()?;
+                        Ok(skel)
+                    };
+                    let skel = match load() {
+                        Ok(skel) => skel,
+                        Err(e) => {
+                            let _ = loaded_send.send(Err(e));
+                            return;
+                        }
+                    };
+
+                    let mut builder = libbpf_rs::RingBufferBuilder::new();
+                    let added = builder.add(skel.maps().owners(), |data: &[u8]| {
+                        match OwnerEvent::parse(data) {
+                            Some(event) => on_owner(event),
+                            None => tracing::debug!("bad flow owner event of {}", data.len()),
+                        }
+                        0
+                    });
+                    let ring = match added.and_then(|b| b.build()) {
+                        Ok(ring) => ring,
+                        Err(e) => {
+                            let _ = loaded_send.send(Err(e.into()));
+                            return;
+                        }
+                    };
+                    let _ = loaded_send.send(Ok(()));
+
+                    while !stop.load(Ordering::Relaxed) {
+                        if let Err(e) = ring.poll(Duration::from_millis(100)) {
+                            tracing::warn!("reading flow owners failed: {e}");
+                            break;
+                        }
+                    }
+                }
+            })
+            .map_err(|e| Error::IoError("spawn flow owner thread", e))?;
+
+        match loaded.recv() {
+            Ok(Ok(())) => {
+                tracing::debug!("attributing connections to processes");
+                Ok(FlowOwnerTracer {
+                    stop,
+                    thread: Some(thread),
+                })
+            }
+            Ok(Err(e)) => Err(Error::Other(
+                format!("could not load the process attribution eBPF program: {e}").into(),
+            )),
+            Err(_) => Err(Error::StringError("flow owner thread died")),
+        }
+    }
+}
+
+impl Drop for FlowOwnerTracer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,404 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading TLS secrets out of Go programs, with an eBPF uprobe on
+//! `crypto/tls.(*Config).writeKeyLog`.
+//!
+//! Go programs link crypto/tls statically and don't go through the dynamic
+//! linker for it, so injecting a library does nothing for them. The function
+//! is found by its symbol, or failing that in `.gopclntab`, which Go keeps
+//! even in stripped binaries. Every process running the binary is probed,
+//! including ones started later. Loading the program needs root, or
+//! `CAP_BPF` and `CAP_PERFMON`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol};
+
+use crate::error::{AddContext, DynError, Error};
+
+mod skel {
+    include!(concat!(env!("OUT_DIR"), "/go_tls_keys.skel.rs"));
+}
+
+use skel::GoTlsKeysSkelBuilder;
+
+const WRITE_KEY_LOG: &str = "crypto/tls.(*Config).writeKeyLog";
+
+const MAX_LABEL: usize = 32;
+const MAX_RANDOM: usize = 32;
+const MAX_SECRET: usize = 64;
+
+/// `struct key_event` in `go_tls_keys.bpf.c`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawKeyEvent {
+    tgid: u32,
+    label_len: u32,
+    random_len: u32,
+    secret_len: u32,
+    label: [u8; MAX_LABEL],
+    client_random: [u8; MAX_RANDOM],
+    secret: [u8; MAX_SECRET],
+}
+
+/// A secret derived by a Go program, as it would go in its key log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GoKey {
+    pub pid: u32,
+    pub label: String,
+    pub client_random: Vec<u8>,
+    pub secret: Vec<u8>,
+}
+
+impl GoKey {
+    fn parse(data: &[u8]) -> Option<GoKey> {
+        if data.len() < std::mem::size_of::<RawKeyEvent>() {
+            return None;
+        }
+        let raw = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const RawKeyEvent) };
+        Some(GoKey {
+            pid: raw.tgid,
+            label: String::from_utf8(raw.label.get(..raw.label_len as usize)?.to_vec()).ok()?,
+            client_random: raw.client_random.get(..raw.random_len as usize)?.to_vec(),
+            secret: raw.secret.get(..raw.secret_len as usize)?.to_vec(),
+        })
+    }
+}
+
+/// Reads a little endian integer of `size` bytes at `off`.
+fn read_uint(data: &[u8], off: usize, size: usize) -> Option<u64> {
+    let bytes = data.get(off..off.checked_add(size)?)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &b| (acc << 8) | b as u64),
+    )
+}
+
+fn read_name(data: &[u8], off: usize) -> Option<&[u8]> {
+    let rest = data.get(off..)?;
+    Some(&rest[..rest.iter().position(|&b| b == 0)?])
+}
+
+/// Finds the address of the function `name` in a `.gopclntab`, for Go 1.16
+/// and newer. `text_start` is the address of `.text`.
+fn find_in_pclntab(pclntab: &[u8], text_start: u64, name: &str) -> Option<u64> {
+    const GO_1_16: u64 = 0xfffffffa;
+    const GO_1_18: u64 = 0xfffffff0;
+    const GO_1_20: u64 = 0xfffffff1;
+
+    let magic = read_uint(pclntab, 0, 4)?;
+    let ptr_size = *pclntab.get(7)? as usize;
+    let word = |n: usize| read_uint(pclntab, 8 + n * ptr_size, ptr_size);
+    let nfunc = word(0)? as usize;
+
+    match magic {
+        GO_1_16 => {
+            // nfiles, funcnametab, cutab, filetab, pctab, pclntab
+            let funcname_off = word(2)? as usize;
+            let functab = word(6)? as usize;
+            // Pairs of (entry, offset of _func) words.
+            (0..nfunc).find_map(|i| {
+                let entry_at = functab + i * 2 * ptr_size;
+                let func = functab + read_uint(pclntab, entry_at + ptr_size, ptr_size)? as usize;
+                let name_off = read_uint(pclntab, func + ptr_size, 4)? as usize;
+                (read_name(pclntab, funcname_off + name_off)? == name.as_bytes())
+                    .then(|| read_uint(pclntab, entry_at, ptr_size))
+                    .flatten()
+            })
+        }
+        GO_1_18 | GO_1_20 => {
+            // nfiles, textStart, funcnametab, cutab, filetab, pctab, pclntab.
+            // textStart is left to a relocation in position independent
+            // binaries, so it is not used.
+            let funcname_off = word(3)? as usize;
+            let functab = word(7)? as usize;
+            // Pairs of 32 bit (entry offset, offset of _func).
+            (0..nfunc).find_map(|i| {
+                let entry_at = functab + i * 8;
+                let func = functab + read_uint(pclntab, entry_at + 4, 4)? as usize;
+                let name_off = read_uint(pclntab, func + 4, 4)? as usize;
+                (read_name(pclntab, funcname_off + name_off)? == name.as_bytes())
+                    .then(|| Some(text_start + read_uint(pclntab, entry_at, 4)?))
+                    .flatten()
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Finds where in the file `binary` the function `name` starts, which is
+/// what uprobes are attached by.
+fn find_function(binary: &[u8], name: &str) -> Result<u64, Error> {
+    let file = object::File::parse(binary).map_err(|e| Error::Other(e.into()))?;
+
+    let address = file
+        .symbols()
+        .find(|s| s.name() == Ok(name))
+        .map(|s| s.address())
+        .or_else(|| {
+            let pclntab = file.section_by_name(".gopclntab")?;
+            let text = file.section_by_name(".text")?;
+            find_in_pclntab(pclntab.data().ok()?, text.address(), name)
+        })
+        .ok_or(Error::StringError(
+            "could not find crypto/tls in the Go binary; it may not use it, or be older than Go 1.16",
+        ))?;
+
+    file.segments()
+        .find(|s| (s.address()..s.address() + s.size()).contains(&address))
+        .map(|s| s.file_range().0 + (address - s.address()))
+        .ok_or(Error::StringError("Go function is outside of the binary"))
+}
+
+/// The eBPF program, attached to one Go binary. Detached when dropped.
+pub struct GoKeyTracer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl GoKeyTracer {
+    /// Probes `binary` and calls `on_key`, on another thread, for every
+    /// secret any process running it derives from here on.
+    pub fn start(
+        binary: &Path,
+        mut on_key: impl FnMut(GoKey) + Send + 'static,
+    ) -> Result<GoKeyTracer, Error> {
+        let offset = find_function(
+            &std::fs::read(binary).context("read Go binary")?,
+            WRITE_KEY_LOG,
+        )?;
+        let binary: PathBuf = binary.to_owned();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (loaded_send, loaded) = mpsc::sync_channel(1);
+
+        // libbpf's objects can't leave the thread they were made on.
+        let thread = thread::Builder::new()
+            .name("go-tls-keys".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    let load = || -> Result<_, DynError> {
+                        let mut skel = GoTlsKeysSkelBuilder::default().open()?.load()?;
+                        let link = skel.progs_mut().on_write_key_log().attach_uprobe(
+                            false,
+                            -1,
+                            &binary,
+                            offset as usize,
+                        )?;
+                        Ok((skel, link))
+                    };
+                    let (skel, _link) = match load() {
+                        Ok(loaded) => loaded,
+                        Err(e) => {
+                            let _ = loaded_send.send(Err(e));
+                            return;
+                        }
+                    };
+
+                    let mut builder = libbpf_rs::RingBufferBuilder::new();
+                    let added = builder.add(skel.maps().keys(), |data: &[u8]| {
+                        match GoKey::parse(data) {
+                            Some(key) => on_key(key),
+                            None => tracing::debug!("bad Go key event of {}", data.len()),
+                        }
+                        0
+                    });
+                    let ring = match added.and_then(|b| b.build()) {
+                        Ok(ring) => ring,
+                        Err(e) => {
+                            let _ = loaded_send.send(Err(e.into()));
+                            return;
+                        }
+                    };
+                    let _ = loaded_send.send(Ok(()));
+
+                    while !stop.load(Ordering::Relaxed) {
+                        if let Err(e) = ring.poll(Duration::from_millis(100)) {
+                            tracing::warn!("reading Go keys failed: {e}");
+                            break;
+                        }
+                    }
+                }
+            })
+            .map_err(|e| Error::IoError("spawn Go key thread", e))?;
+
+        match loaded.recv() {
+            Ok(Ok(())) => {
+                tracing::debug!("reading keys from Go binary at offset {offset:#x}");
+                Ok(GoKeyTracer {
+                    stop,
+                    thread: Some(thread),
+                })
+            }
+            Ok(Err(e)) => Err(Error::Other(
+                format!("could not load the Go key eBPF program: {e}").into(),
+            )),
+            Err(_) => Err(Error::StringError("Go key thread died")),
+        }
+    }
+}
+
+impl Drop for GoKeyTracer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GO_1_16: u32 = 0xfffffffa;
+    const GO_1_18: u32 = 0xfffffff0;
+    const GO_1_20: u32 = 0xfffffff1;
+
+    const FUNCS: &[(u64, &str)] = &[(0x1000, "main.main"), (0x2340, WRITE_KEY_LOG)];
+
+    fn push_uint(out: &mut Vec<u8>, value: u64, size: usize) {
+        out.extend(&value.to_le_bytes()[..size]);
+    }
+
+    /// A `.gopclntab` with just enough in it to name `funcs`, which are
+    /// pairs of the entry (an offset from `.text` from Go 1.18) and name.
+    fn pclntab(magic: u32, ptr_size: usize, funcs: &[(u64, &str)]) -> Vec<u8> {
+        let go_1_16 = magic == GO_1_16;
+        let words = if go_1_16 { 7 } else { 8 };
+        let funcnametab = 8 + words * ptr_size;
+
+        let mut names = Vec::new();
+        let mut name_offs = Vec::new();
+        for (_, name) in funcs {
+            name_offs.push(names.len() as u64);
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+        let functab = funcnametab + names.len();
+
+        // The table of entries, then the _func of each right after it,
+        // with its entry followed by its name offset.
+        let (entry_size, func_size) = if go_1_16 {
+            (2 * ptr_size, ptr_size + 4)
+        } else {
+            (8, 8)
+        };
+        let func_at = |i: usize| (funcs.len() * entry_size + i * func_size) as u64;
+        let mut table = Vec::new();
+        let mut func_structs = Vec::new();
+        for (i, &(entry, _)) in funcs.iter().enumerate() {
+            let field = if go_1_16 { ptr_size } else { 4 };
+            push_uint(&mut table, entry, field);
+            push_uint(&mut table, func_at(i), field);
+            push_uint(&mut func_structs, entry, field);
+            push_uint(&mut func_structs, name_offs[i], 4);
+        }
+
+        let mut out = Vec::new();
+        push_uint(&mut out, magic as u64, 4);
+        out.extend([0, 0, 1, ptr_size as u8]);
+        let header = if go_1_16 {
+            // nfunc, nfiles, funcnametab, cutab, filetab, pctab, pclntab
+            vec![funcs.len(), 0, funcnametab, 0, 0, 0, functab]
+        } else {
+            // nfunc, nfiles, textStart, funcnametab, cutab, filetab, pctab,
+            // pclntab
+            vec![funcs.len(), 0, 0, funcnametab, 0, 0, 0, functab]
+        };
+        for word in header {
+            push_uint(&mut out, word as u64, ptr_size);
+        }
+        out.extend(names);
+        out.extend(table);
+        out.extend(func_structs);
+        out
+    }
+
+    #[test]
+    fn test_pclntab_go_1_16() {
+        for ptr_size in [4, 8] {
+            let tab = pclntab(GO_1_16, ptr_size, FUNCS);
+            // Entries are absolute, so the start of .text is not used
+            assert_eq!(find_in_pclntab(&tab, 0x400000, WRITE_KEY_LOG), Some(0x2340));
+            assert_eq!(find_in_pclntab(&tab, 0x400000, "main.main"), Some(0x1000));
+            assert_eq!(find_in_pclntab(&tab, 0x400000, "main.other"), None);
+        }
+    }
+
+    #[test]
+    fn test_pclntab_go_1_18() {
+        for magic in [GO_1_18, GO_1_20] {
+            let tab = pclntab(magic, 8, FUNCS);
+            assert_eq!(
+                find_in_pclntab(&tab, 0x400000, WRITE_KEY_LOG),
+                Some(0x402340)
+            );
+            assert_eq!(find_in_pclntab(&tab, 0x400000, "main.main"), Some(0x401000));
+            assert_eq!(find_in_pclntab(&tab, 0x400000, "main.other"), None);
+        }
+    }
+
+    #[test]
+    fn test_pclntab_bad() {
+        // Go 1.2 to 1.15
+        let tab = pclntab(0xfffffffb, 8, FUNCS);
+        assert_eq!(find_in_pclntab(&tab, 0x400000, WRITE_KEY_LOG), None);
+        // Cut off in the middle of the table
+        let tab = pclntab(GO_1_20, 8, FUNCS);
+        let cut = &tab[..tab.len() - 12];
+        assert_eq!(find_in_pclntab(cut, 0x400000, WRITE_KEY_LOG), None);
+        assert_eq!(find_in_pclntab(&[], 0x400000, WRITE_KEY_LOG), None);
+    }
+
+    fn key_event(label: &str, random_len: u32, secret_len: u32) -> Vec<u8> {
+        let mut raw = RawKeyEvent {
+            tgid: 1234,
+            label_len: label.len() as u32,
+            random_len,
+            secret_len,
+            label: [0; MAX_LABEL],
+            client_random: [0xaa; MAX_RANDOM],
+            secret: [0xbb; MAX_SECRET],
+        };
+        raw.label[..label.len()].copy_from_slice(label.as_bytes());
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &raw as *const RawKeyEvent as *const u8,
+                std::mem::size_of::<RawKeyEvent>(),
+            )
+        };
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            GoKey::parse(&key_event("CLIENT_TRAFFIC_SECRET_0", 32, 48)),
+            Some(GoKey {
+                pid: 1234,
+                label: "CLIENT_TRAFFIC_SECRET_0".to_string(),
+                client_random: vec![0xaa; 32],
+                secret: vec![0xbb; 48],
+            })
+        );
+
+        let event = key_event("CLIENT_RANDOM", 32, 48);
+        assert_eq!(GoKey::parse(&event[..event.len() - 1]), None);
+        // Lengths past the end of their fields
+        assert_eq!(GoKey::parse(&key_event("CLIENT_RANDOM", 33, 48)), None);
+        assert_eq!(GoKey::parse(&key_event("CLIENT_RANDOM", 32, 65)), None);
+    }
+}
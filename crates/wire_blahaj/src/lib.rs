@@ -6,16 +6,83 @@
 //!
 //! Packet capture functionality for clipper.
 
+use std::ops::{AddAssign, Sub};
+
+#[cfg(unix)]
 use nix::sys::time::TimeSpec;
 
+#[cfg(target_os = "linux")]
+pub mod af_packet;
+#[cfg(target_os = "macos")]
+pub mod bpf_device;
+pub mod error;
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+pub mod flow_owner;
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+pub mod go_tls_keys;
 #[cfg(target_os = "linux")]
 pub mod unprivileged;
 
+pub mod merge;
+#[cfg(all(windows, feature = "npcap"))]
+pub mod npcap;
+#[cfg(any(all(windows, feature = "npcap"), test))]
+pub mod npcap_interface;
 pub mod pcap_writer;
+#[cfg(target_os = "linux")]
+pub mod probe;
+#[cfg(target_os = "linux")]
+pub mod ring;
+#[cfg(target_os = "linux")]
+pub mod xdp;
 
 /// Nanoseconds since the Unix epoch
 pub type Nanos = u64;
 
+#[derive(Debug)]
+pub struct CapturedPacketMeta {
+    /// How long the packet was on the wire. The data captured may be
+    /// shorter, if it was cut off at the snap length.
+    pub len: usize,
+    /// When the kernel received the packet.
+    pub time: Nanos,
+    pub if_index: usize,
+    /// Which way the packet was going, as the `sll_pkttype` of a Linux
+    /// packet socket (`PACKET_HOST`, `PACKET_OUTGOING`, ...), if the capture
+    /// says.
+    pub packet_type: Option<u8>,
+}
+
+/// What the kernel counted of a capture: the packets that matched its
+/// filter, and how many of those it had to drop because they were not read
+/// fast enough.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KernelStats {
+    /// Packets that matched the filter, including those dropped.
+    pub received: u64,
+    pub dropped: u64,
+}
+
+impl AddAssign for KernelStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.received += rhs.received;
+        self.dropped += rhs.dropped;
+    }
+}
+
+impl Sub for KernelStats {
+    type Output = KernelStats;
+
+    /// Counts since `rhs` was taken, from the same running totals.
+    fn sub(self, rhs: Self) -> Self::Output {
+        KernelStats {
+            received: self.received.saturating_sub(rhs.received),
+            dropped: self.dropped.saturating_sub(rhs.dropped),
+        }
+    }
+}
+
+#[cfg(unix)]
 pub fn ts_to_nanos(ts: TimeSpec) -> Nanos {
     (ts.tv_sec() as u64) * 10u64.pow(9) + (ts.tv_nsec() as u64)
 }
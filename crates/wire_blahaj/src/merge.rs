@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Merging the packets from several capture sockets into one stream, in the
+//! order they were received.
+//!
+//! Each socket hands packets over in order, so this only has to hold one
+//! packet back per socket: the earliest packet held back can go once every
+//! other socket either has a packet held back too or has nothing queued. A
+//! socket with nothing queued can only produce packets received after the
+//! ones already read from the others.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Stream, StreamExt};
+
+use crate::Nanos;
+
+struct Input<S, T> {
+    stream: S,
+    /// Next packet from the stream, held until it is the earliest.
+    head: Option<T>,
+    done: bool,
+}
+
+/// Stream of the packets from several streams, ordered by time.
+pub struct MergeByTime<S, T> {
+    inputs: Vec<Input<S, T>>,
+    time_of: fn(&T) -> Nanos,
+}
+
+impl<S, T> MergeByTime<S, T> {
+    /// Merges `streams`, each of which is in order by `time_of`.
+    pub fn new(streams: impl IntoIterator<Item = S>, time_of: fn(&T) -> Nanos) -> Self {
+        Self {
+            inputs: streams
+                .into_iter()
+                .map(|stream| Input {
+                    stream,
+                    head: None,
+                    done: false,
+                })
+                .collect(),
+            time_of,
+        }
+    }
+}
+
+// Nothing is pinned through a `MergeByTime`.
+impl<S, T> Unpin for MergeByTime<S, T> {}
+
+impl<S, T, E> Stream for MergeByTime<S, T>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        for input in &mut this.inputs {
+            if input.head.is_some() || input.done {
+                continue;
+            }
+            match input.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(item))) => input.head = Some(item),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => input.done = true,
+                Poll::Pending => {}
+            }
+        }
+
+        let time_of = this.time_of;
+        let earliest = this
+            .inputs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, input)| Some((time_of(input.head.as_ref()?), i)))
+            .min();
+        match earliest {
+            Some((_, i)) => Poll::Ready(this.inputs[i].head.take().map(Ok)),
+            None if this.inputs.iter().all(|input| input.done) => Poll::Ready(None),
+            // Every stream without a packet held back has been polled, so
+            // will wake us up.
+            None => Poll::Pending,
+        }
+    }
+}
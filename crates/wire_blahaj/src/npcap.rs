@@ -0,0 +1,430 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture straight off a network interface on Windows, through Npcap.
+//!
+//! Npcap's `wpcap.dll` is loaded when it is first needed rather than linked
+//! against, so that clipper still starts where Npcap is not installed. It
+//! lives in `System32\Npcap`, unless Npcap was installed in WinPcap
+//! compatible mode, in which case it is on the normal search path.
+//!
+//! Npcap only offers blocking reads, so each interface is read on a thread
+//! of its own, which hands the packets on to the [`NpcapCapture`] stream.
+//!
+//! See <https://npcap.com/guide/wpcap/pcap.html>.
+
+use std::{
+    ffi::{c_char, c_int, c_long, c_uint, c_void, CStr, CString},
+    io,
+    path::PathBuf,
+    pin::Pin,
+    ptr,
+    sync::{Arc, Mutex, OnceLock},
+    task::{Context, Poll},
+    thread,
+};
+
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt};
+use libloading::{os::windows::LOAD_WITH_ALTERED_SEARCH_PATH, Library};
+
+pub use crate::npcap_interface::NpcapInterface;
+use crate::{
+    error::Error, npcap_interface::find_interface, CapturedPacketMeta, KernelStats, Nanos,
+};
+
+const PCAP_ERRBUF_SIZE: usize = 256;
+const DLT_EN10MB: c_int = 1;
+/// Keep whole packets unless a filter says otherwise.
+const SNAP_LEN: c_int = 65535;
+/// How long a read waits for packets before checking whether anyone still
+/// wants them.
+const READ_TIMEOUT_MS: c_int = 100;
+/// Packets queued between the reading thread and the stream.
+const QUEUE_LEN: usize = 1024;
+/// Packets read between updates of the driver's counts, which are also
+/// updated whenever a read times out.
+const STATS_EVERY: usize = 1024;
+
+#[repr(C)]
+struct PcapT {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PcapIf {
+    next: *mut PcapIf,
+    name: *mut c_char,
+    description: *mut c_char,
+    addresses: *mut c_void,
+    flags: u32,
+}
+
+/// `struct timeval`, which has 32 bit fields on Windows.
+#[repr(C)]
+struct Timeval {
+    tv_sec: c_long,
+    tv_usec: c_long,
+}
+
+/// `struct pcap_stat`, with the fields only Windows has.
+#[repr(C)]
+#[derive(Default)]
+struct PcapStat {
+    ps_recv: c_uint,
+    ps_drop: c_uint,
+    ps_ifdrop: c_uint,
+    ps_capt: c_uint,
+    ps_sent: c_uint,
+    ps_netdrop: c_uint,
+}
+
+#[repr(C)]
+struct PcapPkthdr {
+    ts: Timeval,
+    caplen: u32,
+    len: u32,
+}
+
+/// `struct bpf_insn`: one instruction of a classic BPF program.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BpfInsn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+#[repr(C)]
+struct BpfProgram {
+    bf_len: c_uint,
+    bf_insns: *mut BpfInsn,
+}
+
+/// The functions we use out of `wpcap.dll`.
+struct Wpcap {
+    findalldevs: unsafe extern "C" fn(*mut *mut PcapIf, *mut c_char) -> c_int,
+    freealldevs: unsafe extern "C" fn(*mut PcapIf),
+    create: unsafe extern "C" fn(*const c_char, *mut c_char) -> *mut PcapT,
+    set_snaplen: unsafe extern "C" fn(*mut PcapT, c_int) -> c_int,
+    set_promisc: unsafe extern "C" fn(*mut PcapT, c_int) -> c_int,
+    set_timeout: unsafe extern "C" fn(*mut PcapT, c_int) -> c_int,
+    set_immediate_mode: unsafe extern "C" fn(*mut PcapT, c_int) -> c_int,
+    activate: unsafe extern "C" fn(*mut PcapT) -> c_int,
+    datalink: unsafe extern "C" fn(*mut PcapT) -> c_int,
+    setfilter: unsafe extern "C" fn(*mut PcapT, *mut BpfProgram) -> c_int,
+    next_ex: unsafe extern "C" fn(*mut PcapT, *mut *mut PcapPkthdr, *mut *const u8) -> c_int,
+    geterr: unsafe extern "C" fn(*mut PcapT) -> *mut c_char,
+    stats: unsafe extern "C" fn(*mut PcapT, *mut PcapStat) -> c_int,
+    close: unsafe extern "C" fn(*mut PcapT),
+    /// Keeps the functions above loaded.
+    _lib: Library,
+}
+
+impl Wpcap {
+    fn load() -> Result<Wpcap, libloading::Error> {
+        let npcap_dir = std::env::var_os("SystemRoot")
+            .map(|root| PathBuf::from(root).join("System32").join("Npcap"))
+            .unwrap_or_default();
+        // Its own directory has to be searched too, for `Packet.dll`.
+        let lib = match unsafe {
+            libloading::os::windows::Library::load_with_flags(
+                npcap_dir.join("wpcap.dll"),
+                LOAD_WITH_ALTERED_SEARCH_PATH,
+            )
+        } {
+            Ok(lib) => lib.into(),
+            Err(_) => unsafe { Library::new("wpcap.dll")? },
+        };
+
+        unsafe {
+            Ok(Wpcap {
+                findalldevs: symbol(&lib, b"pcap_findalldevs\0")?,
+                freealldevs: symbol(&lib, b"pcap_freealldevs\0")?,
+                create: symbol(&lib, b"pcap_create\0")?,
+                set_snaplen: symbol(&lib, b"pcap_set_snaplen\0")?,
+                set_promisc: symbol(&lib, b"pcap_set_promisc\0")?,
+                set_timeout: symbol(&lib, b"pcap_set_timeout\0")?,
+                set_immediate_mode: symbol(&lib, b"pcap_set_immediate_mode\0")?,
+                activate: symbol(&lib, b"pcap_activate\0")?,
+                datalink: symbol(&lib, b"pcap_datalink\0")?,
+                setfilter: symbol(&lib, b"pcap_setfilter\0")?,
+                next_ex: symbol(&lib, b"pcap_next_ex\0")?,
+                geterr: symbol(&lib, b"pcap_geterr\0")?,
+                stats: symbol(&lib, b"pcap_stats\0")?,
+                close: symbol(&lib, b"pcap_close\0")?,
+                _lib: lib,
+            })
+        }
+    }
+}
+
+/// Safety: `T` has to be the type of the function called `name`.
+unsafe fn symbol<T: Copy>(lib: &Library, name: &[u8]) -> Result<T, libloading::Error> {
+    Ok(*unsafe { lib.get::<T>(name)? })
+}
+
+fn wpcap() -> Result<&'static Wpcap, Error> {
+    static WPCAP: OnceLock<Result<Wpcap, String>> = OnceLock::new();
+    WPCAP
+        .get_or_init(|| Wpcap::load().map_err(|e| format!("could not load Npcap: {e}")))
+        .as_ref()
+        .map_err(|e| Error::Other(e.clone().into()))
+}
+
+fn errbuf_to_string(errbuf: &[c_char]) -> String {
+    unsafe { CStr::from_ptr(errbuf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Lists the interfaces Npcap can capture on, in the order it gives them.
+pub fn list_interfaces() -> Result<Vec<NpcapInterface>, Error> {
+    let wpcap = wpcap()?;
+    let mut errbuf = [0 as c_char; PCAP_ERRBUF_SIZE];
+    let mut all: *mut PcapIf = ptr::null_mut();
+    if unsafe { (wpcap.findalldevs)(&mut all, errbuf.as_mut_ptr()) } != 0 {
+        return Err(Error::Other(errbuf_to_string(&errbuf).into()));
+    }
+
+    let mut interfaces = Vec::new();
+    let mut dev = all;
+    while let Some(d) = unsafe { dev.as_ref() } {
+        let string = |s: *mut c_char| {
+            (!s.is_null()).then(|| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+        };
+        interfaces.push(NpcapInterface {
+            name: string(d.name).unwrap_or_default(),
+            description: string(d.description),
+        });
+        dev = d.next;
+    }
+    unsafe { (wpcap.freealldevs)(all) };
+    Ok(interfaces)
+}
+
+/// Which interface to capture on, and how.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceOptions {
+    /// Device name, GUID or description of the interface, as given by
+    /// [`list_interfaces`].
+    pub interface: String,
+    /// Also take packets addressed to other machines.
+    pub promiscuous: bool,
+}
+
+/// An open Npcap capture.
+pub struct NpcapHandle {
+    wpcap: &'static Wpcap,
+    pcap: *mut PcapT,
+    /// Npcap does not number interfaces, so they are numbered in the order
+    /// [`list_interfaces`] gives them, from 1.
+    if_index: usize,
+    stats: NpcapStats,
+}
+
+// Npcap handles may be used from any one thread at a time.
+unsafe impl Send for NpcapHandle {}
+
+impl NpcapHandle {
+    fn error(&self) -> String {
+        unsafe { CStr::from_ptr((self.wpcap.geterr)(self.pcap)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// The driver's counts for this capture, which carry on being updated
+    /// once it is read by an [`NpcapCapture`].
+    pub fn stats(&self) -> NpcapStats {
+        self.stats.clone()
+    }
+}
+
+/// What the driver has counted of a capture since it was opened, as of when
+/// the thread reading it last asked. These are running totals, like those of
+/// BPF devices on macOS.
+#[derive(Clone, Debug, Default)]
+pub struct NpcapStats(Arc<Mutex<KernelStats>>);
+
+impl NpcapStats {
+    pub fn get(&self) -> KernelStats {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl Drop for NpcapHandle {
+    fn drop(&mut self) {
+        unsafe { (self.wpcap.close)(self.pcap) };
+    }
+}
+
+/// Opens a capture on an interface. Only interfaces whose packets have
+/// Ethernet headers are supported, which includes Wi-Fi adapters but not
+/// the Npcap loopback adapter.
+pub fn open_interface(options: &InterfaceOptions) -> Result<NpcapHandle, Error> {
+    let wpcap = wpcap()?;
+    let interfaces = list_interfaces()?;
+    let (n, interface) = find_interface(&interfaces, &options.interface)?;
+
+    let name = CString::new(interface.name.as_str())
+        .map_err(|_| Error::StringError("interface name has a NUL in it"))?;
+    let mut errbuf = [0 as c_char; PCAP_ERRBUF_SIZE];
+    let pcap = unsafe { (wpcap.create)(name.as_ptr(), errbuf.as_mut_ptr()) };
+    if pcap.is_null() {
+        return Err(Error::Other(errbuf_to_string(&errbuf).into()));
+    }
+    let handle = NpcapHandle {
+        wpcap,
+        pcap,
+        if_index: n + 1,
+        stats: NpcapStats::default(),
+    };
+
+    unsafe {
+        (wpcap.set_snaplen)(pcap, SNAP_LEN);
+        (wpcap.set_promisc)(pcap, options.promiscuous as c_int);
+        (wpcap.set_timeout)(pcap, READ_TIMEOUT_MS);
+        // Otherwise packets are held until the driver's buffer fills up.
+        (wpcap.set_immediate_mode)(pcap, 1);
+    }
+    // Negative is an error, positive a warning.
+    let ret = unsafe { (wpcap.activate)(pcap) };
+    if ret < 0 {
+        return Err(Error::Other(
+            format!(
+                "could not capture on {}: {}",
+                interface.name,
+                handle.error()
+            )
+            .into(),
+        ));
+    } else if ret > 0 {
+        tracing::warn!("capturing on {}: {}", interface.name, handle.error());
+    }
+
+    let link_type = unsafe { (wpcap.datalink)(pcap) };
+    if link_type != DLT_EN10MB {
+        return Err(Error::Other(
+            format!(
+                "{} is not an Ethernet interface (link type {link_type})",
+                options.interface
+            )
+            .into(),
+        ));
+    }
+
+    tracing::debug!(
+        "capturing on {}{}",
+        interface.name,
+        if options.promiscuous {
+            " in promiscuous mode"
+        } else {
+            ""
+        }
+    );
+    Ok(handle)
+}
+
+/// Attaches a classic BPF `program` to a capture, so that the driver drops
+/// what it rejects.
+pub fn attach_filter(handle: &NpcapHandle, program: &[BpfInsn]) -> Result<(), Error> {
+    let mut prog = BpfProgram {
+        bf_len: c_uint::try_from(program.len())
+            .map_err(|_| Error::StringError("capture filter is too long"))?,
+        bf_insns: program.as_ptr() as *mut BpfInsn,
+    };
+    if unsafe { (handle.wpcap.setfilter)(handle.pcap, &mut prog) } != 0 {
+        return Err(Error::Other(
+            format!("could not attach capture filter: {}", handle.error()).into(),
+        ));
+    }
+    Ok(())
+}
+
+type Packet = io::Result<(Vec<u8>, CapturedPacketMeta)>;
+
+/// Copies the driver's counts for `handle` into its [`NpcapStats`].
+fn update_stats(handle: &NpcapHandle) {
+    let mut st = PcapStat::default();
+    if unsafe { (handle.wpcap.stats)(handle.pcap, &mut st) } == 0 {
+        *handle.stats.0.lock().unwrap() = KernelStats {
+            received: st.ps_recv as u64,
+            dropped: st.ps_drop as u64,
+        };
+    }
+}
+
+/// Reads packets until they are no longer wanted or the capture fails.
+fn read_packets(handle: NpcapHandle, mut send: mpsc::Sender<Packet>) {
+    let wpcap = handle.wpcap;
+    let mut since_stats = 0;
+    loop {
+        let mut hdr: *mut PcapPkthdr = ptr::null_mut();
+        let mut data: *const u8 = ptr::null();
+        let ret = unsafe { (wpcap.next_ex)(handle.pcap, &mut hdr, &mut data) };
+        since_stats += 1;
+        if ret == 0 || since_stats >= STATS_EVERY {
+            update_stats(&handle);
+            since_stats = 0;
+        }
+        let packet = match ret {
+            1 => {
+                let hdr = unsafe { &*hdr };
+                let data = unsafe { std::slice::from_raw_parts(data, hdr.caplen as usize) };
+                Ok((
+                    data.to_vec(),
+                    CapturedPacketMeta {
+                        len: hdr.len as usize,
+                        time: hdr.ts.tv_sec as Nanos * 1_000_000_000
+                            + hdr.ts.tv_usec as Nanos * 1000,
+                        if_index: handle.if_index,
+                        packet_type: None,
+                    },
+                ))
+            }
+            // Timed out, with nothing to read.
+            0 if send.is_closed() => return,
+            0 => continue,
+            _ => Err(io::Error::new(io::ErrorKind::Other, handle.error())),
+        };
+        let failed = packet.is_err();
+        if futures::executor::block_on(send.send(packet)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Captured packets out of an Npcap capture. Yields the same as
+/// [`RingCapture`](crate::ring::RingCapture) does on Linux.
+pub struct NpcapCapture {
+    recv: mpsc::Receiver<Packet>,
+    stats: NpcapStats,
+}
+
+impl NpcapCapture {
+    /// Starts reading packets from `handle` on a thread of its own, which
+    /// closes it once the capture is dropped.
+    pub fn new(handle: NpcapHandle) -> io::Result<NpcapCapture> {
+        let (send, recv) = mpsc::channel(QUEUE_LEN);
+        let stats = handle.stats();
+        thread::Builder::new()
+            .name("npcap".to_string())
+            .spawn(move || read_packets(handle, send))?;
+        Ok(NpcapCapture { recv, stats })
+    }
+
+    /// What the driver has counted since the capture was opened, as of when
+    /// the reading thread last asked.
+    pub fn kernel_stats(&self) -> KernelStats {
+        self.stats.get()
+    }
+}
+
+impl Stream for NpcapCapture {
+    type Item = Packet;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Picking which of the interfaces Npcap lists the user meant.
+//!
+//! This is apart from [`npcap`](crate::npcap) so that it builds, and is
+//! tested, everywhere and not just on Windows.
+
+use crate::error::Error;
+
+/// An interface Npcap can capture on.
+#[derive(Clone, Debug)]
+pub struct NpcapInterface {
+    /// Device name, like `\Device\NPF_{GUID}`.
+    pub name: String,
+    /// Description of the adapter, like `Intel(R) Ethernet Connection`.
+    pub description: Option<String>,
+}
+
+impl NpcapInterface {
+    /// Whether the user meant this interface by `name`: its device name, the
+    /// GUID in it, or its description.
+    pub fn matches(&self, name: &str) -> bool {
+        let guid = |s: &str| {
+            s.trim_start_matches(r"\Device\NPF_")
+                .trim_matches(['{', '}'])
+                .to_ascii_lowercase()
+        };
+        self.name == name
+            || guid(&self.name) == guid(name)
+            || self
+                .description
+                .as_deref()
+                .is_some_and(|d| d.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Finds the interface the user meant by `name` among `interfaces`, along
+/// with where it is in them. The error lists what there is to pick from.
+pub fn find_interface<'a>(
+    interfaces: &'a [NpcapInterface],
+    name: &str,
+) -> Result<(usize, &'a NpcapInterface), Error> {
+    interfaces
+        .iter()
+        .enumerate()
+        .find(|(_, i)| i.matches(name))
+        .ok_or_else(|| {
+            let known: Vec<_> = interfaces
+                .iter()
+                .map(|i| match &i.description {
+                    Some(d) => format!("{} ({d})", i.name),
+                    None => i.name.clone(),
+                })
+                .collect();
+            Error::Other(
+                format!(
+                    "no such interface {name:?}; Npcap has: {}",
+                    known.join(", ")
+                )
+                .into(),
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn interfaces() -> Vec<NpcapInterface> {
+        vec![
+            NpcapInterface {
+                name: r"\Device\NPF_Loopback".to_string(),
+                description: Some("Adapter for loopback traffic capture".to_string()),
+            },
+            NpcapInterface {
+                name: r"\Device\NPF_{3D7A1F2C-0B4E-4C59-9E8A-1F6D2B7C8E90}".to_string(),
+                description: Some("Intel(R) Ethernet Connection I219-V".to_string()),
+            },
+            NpcapInterface {
+                name: r"\Device\NPF_{A0B1C2D3-E4F5-4A6B-8C7D-9E0F1A2B3C4D}".to_string(),
+                description: None,
+            },
+        ]
+    }
+
+    fn find(name: &str) -> Option<usize> {
+        find_interface(&interfaces(), name).ok().map(|(n, _)| n)
+    }
+
+    #[test]
+    fn test_by_device_name() {
+        assert_eq!(find(r"\Device\NPF_Loopback"), Some(0));
+        assert_eq!(
+            find(r"\Device\NPF_{3D7A1F2C-0B4E-4C59-9E8A-1F6D2B7C8E90}"),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_by_guid() {
+        assert_eq!(find("3D7A1F2C-0B4E-4C59-9E8A-1F6D2B7C8E90"), Some(1));
+        assert_eq!(find("{3d7a1f2c-0b4e-4c59-9e8a-1f6d2b7c8e90}"), Some(1));
+        assert_eq!(find("a0b1c2d3-e4f5-4a6b-8c7d-9e0f1a2b3c4d"), Some(2));
+    }
+
+    #[test]
+    fn test_by_description() {
+        assert_eq!(find("Intel(R) Ethernet Connection I219-V"), Some(1));
+        assert_eq!(find("intel(r) ethernet connection i219-v"), Some(1));
+        assert_eq!(find("adapter for loopback traffic capture"), Some(0));
+        // Only whole descriptions.
+        assert_eq!(find("Intel(R) Ethernet"), None);
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let mut interfaces = interfaces();
+        interfaces[2].description = interfaces[1].description.clone();
+        let (n, _) = find_interface(&interfaces, "Intel(R) Ethernet Connection I219-V").unwrap();
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn test_no_such_interface() {
+        let err = find_interface(&interfaces(), "eth0")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            r#"no such interface "eth0"; Npcap has: \Device\NPF_Loopback (Adapter for loopback traffic capture), \Device\NPF_{3D7A1F2C-0B4E-4C59-9E8A-1F6D2B7C8E90} (Intel(R) Ethernet Connection I219-V), \Device\NPF_{A0B1C2D3-E4F5-4A6B-8C7D-9E0F1A2B3C4D}"#
+        );
+        assert!(find_interface(&[], "eth0").is_err());
+    }
+}
@@ -4,7 +4,7 @@
 
 //! Writing of pcap files.
 
-use std::{collections::BTreeMap, io};
+use std::{collections::BTreeMap, io, net::IpAddr};
 
 use pcap_parser::{
     DecryptionSecretsBlock, EnhancedPacketBlock, InterfaceDescriptionBlock, Linktype, OptionCode,
@@ -14,9 +14,70 @@ use tokio::io::AsyncWriteExt;
 
 use crate::Nanos;
 
+/// Extra information recorded on an interface's description block.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceInfo {
+    pub name: Option<String>,
+    pub comment: Option<String>,
+    /// Link type of the packets, if they are not Ethernet frames.
+    pub link_type: Option<Linktype>,
+    /// How much of each packet was kept, if not what the writer was given
+    /// with [`PcapWriter::set_snap_len`].
+    pub snap_len: Option<u32>,
+}
+
+/// Information about the capture as a whole, recorded on the section header
+/// block.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureMetadata {
+    /// Machine the capture was taken on, e.g. `x86_64`.
+    pub hardware: Option<String>,
+    /// Operating system the capture was taken on, e.g. `Linux 6.4.8`.
+    pub os: Option<String>,
+    /// Free-form notes, such as when the capture started.
+    pub comments: Vec<String>,
+}
+
+/// Extra information recorded on a packet's block.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketOptions<'a> {
+    /// Shown by Wireshark alongside the packet, e.g. "request 42 starts
+    /// here".
+    pub comments: &'a [String],
+    /// The `epb_flags` of the packet, which say which way it was going.
+    pub flags: Option<u32>,
+}
+
+/// Snap length written for interfaces whose packets were not cut short.
+const DEFAULT_SNAP_LEN: u32 = 262144;
+
+/// Enhanced packet block option with the direction of the packet.
+const OPT_EPB_FLAGS: OptionCode = OptionCode(2);
+
+/// Block type of a name resolution block.
+const NRB_BLOCK_TYPE: u32 = 4;
+/// Kinds of record in a name resolution block.
+const NRB_RECORD_END: u16 = 0;
+const NRB_RECORD_IPV4: u16 = 1;
+const NRB_RECORD_IPV6: u16 = 2;
+
+/// Makes an option out of a string, truncated if it is too long for one.
+fn string_option(code: OptionCode, value: &str) -> PcapNGOption<'_> {
+    let value = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+    PcapNGOption {
+        code,
+        len: value.len() as u16,
+        value,
+    }
+}
+
 pub struct PcapWriter {
     /// Map between host if_index values and pcapng values.
     if_index_map: BTreeMap<u32, u32>,
+    /// Info to put on interfaces when they are first seen.
+    if_info: BTreeMap<u32, InterfaceInfo>,
+    /// How much of each packet is kept, if that is limited.
+    snap_len: Option<u32>,
 
     pcap_if_index: u32,
 }
@@ -52,21 +113,49 @@ impl AsyncWriteHack {
 }
 
 impl PcapWriter {
-    pub fn new(app_name: &str, writer: &mut impl io::Write) -> Result<Self, io::Error> {
+    pub fn new(
+        app_name: &str,
+        metadata: &CaptureMetadata,
+        writer: &mut impl io::Write,
+    ) -> Result<Self, io::Error> {
         let mut w = PcapWriter {
             if_index_map: Default::default(),
+            if_info: Default::default(),
+            snap_len: None,
             pcap_if_index: 0,
         };
 
-        w.write_section_header(app_name, writer)?;
+        w.write_section_header(app_name, metadata, writer)?;
         Ok(w)
     }
 
     fn write_section_header(
         &mut self,
         app_name: &str,
+        metadata: &CaptureMetadata,
         writer: &mut impl io::Write,
     ) -> Result<(), io::Error> {
+        let mut options = vec![string_option(OptionCode::ShbUserAppl, app_name)];
+        let string_options = [
+            (OptionCode::ShbHardware, metadata.hardware.as_ref()),
+            (OptionCode::ShbOs, metadata.os.as_ref()),
+        ];
+        for (code, value) in string_options {
+            if let Some(value) = value {
+                options.push(string_option(code, value));
+            }
+        }
+        for comment in &metadata.comments {
+            options.push(string_option(OptionCode::Comment, comment));
+        }
+        // for "lol" reasons the lack of endofopt in this block is not
+        // fixed up.
+        options.push(PcapNGOption {
+            code: OptionCode::EndOfOpt,
+            len: 0,
+            value: &[],
+        });
+
         let mut shb = SectionHeaderBlock {
             block_type: 0,
             block_len1: 0,
@@ -74,27 +163,26 @@ impl PcapWriter {
             major_version: 0,
             minor_version: 0,
             section_len: -1i64,
-            // for "lol" reasons the lack of endofopt in this block is not
-            // fixed up.
-            options: vec![
-                PcapNGOption {
-                    code: OptionCode::ShbUserAppl,
-                    len: app_name.len() as u16,
-                    value: &app_name.as_bytes()[..app_name.len().min(u16::MAX as usize)],
-                },
-                PcapNGOption {
-                    code: OptionCode::EndOfOpt,
-                    len: 0,
-                    value: &[],
-                },
-            ],
+            options,
             block_len2: 0,
         };
 
-        writer.write(&shb.to_vec().unwrap())?;
+        writer.write_all(&shb.to_vec().unwrap())?;
         Ok(())
     }
 
+    /// Sets the name and comment for an interface. Only takes effect if no
+    /// packets have been written for that interface yet.
+    pub fn set_interface_info(&mut self, if_index: u32, info: InterfaceInfo) {
+        self.if_info.insert(if_index, info);
+    }
+
+    /// Sets how much of each packet is kept, for interfaces that have no
+    /// packets written yet.
+    pub fn set_snap_len(&mut self, snap_len: u32) {
+        self.snap_len = Some(snap_len);
+    }
+
     fn pcap_interface_id(
         &mut self,
         writer: &mut impl io::Write,
@@ -106,18 +194,33 @@ impl PcapWriter {
 
         let tsresol = 9u8;
         let tsresol_enc = (tsresol as u32).to_le_bytes();
+        let mut options = vec![PcapNGOption {
+            code: OptionCode::IfTsresol,
+            len: 1,
+            value: &tsresol_enc,
+        }];
+
+        let info = self.if_info.get(&if_index);
+        let string_options = [
+            (OptionCode::IfName, info.and_then(|i| i.name.as_ref())),
+            (OptionCode::Comment, info.and_then(|i| i.comment.as_ref())),
+        ];
+        for (code, value) in string_options {
+            if let Some(value) = value {
+                options.push(string_option(code, value));
+            }
+        }
         let mut idb = InterfaceDescriptionBlock {
             block_type: 0,
             block_len1: 0,
             block_len2: 0,
-            linktype: Linktype::ETHERNET,
+            linktype: info.and_then(|i| i.link_type).unwrap_or(Linktype::ETHERNET),
             reserved: 0,
-            snaplen: 262144,
-            options: vec![PcapNGOption {
-                code: OptionCode::IfTsresol,
-                len: 1,
-                value: &tsresol_enc,
-            }],
+            snaplen: info
+                .and_then(|i| i.snap_len)
+                .or(self.snap_len)
+                .unwrap_or(DEFAULT_SNAP_LEN),
+            options,
             // nanosecond resolution
             if_tsresol: tsresol,
             if_tsoffset: 0,
@@ -138,6 +241,39 @@ impl PcapWriter {
         time: Nanos,
         if_index: u32,
         data: &[u8],
+    ) -> Result<(), io::Error> {
+        self.on_packet_with_comments(writer, time, if_index, data, data.len(), &[])
+    }
+
+    /// Writes a packet with comments on it, which Wireshark shows alongside
+    /// the packet, e.g. "request 42 starts here". The packet was
+    /// `original_len` bytes long, of which `data` was captured.
+    pub fn on_packet_with_comments(
+        &mut self,
+        writer: &mut impl io::Write,
+        time: Nanos,
+        if_index: u32,
+        data: &[u8],
+        original_len: usize,
+        comments: &[String],
+    ) -> Result<(), io::Error> {
+        let options = PacketOptions {
+            comments,
+            ..Default::default()
+        };
+        self.on_packet_with_options(writer, time, if_index, data, original_len, options)
+    }
+
+    /// Writes a packet with `options` recorded on it. The packet was
+    /// `original_len` bytes long, of which `data` was captured.
+    pub fn on_packet_with_options(
+        &mut self,
+        writer: &mut impl io::Write,
+        time: Nanos,
+        if_index: u32,
+        data: &[u8],
+        original_len: usize,
+        options: PacketOptions<'_>,
     ) -> Result<(), io::Error> {
         let pcap_if_index = self.pcap_interface_id(writer, if_index)?;
 
@@ -151,10 +287,22 @@ impl PcapWriter {
             ts_high: ts_high as u32,
             ts_low: ts_low as u32,
             caplen: data.len() as u32,
-            origlen: data.len() as u32,
+            origlen: original_len.max(data.len()) as u32,
             data,
-            options: Vec::new(),
+            options: options
+                .comments
+                .iter()
+                .map(|c| string_option(OptionCode::Comment, c))
+                .collect(),
         };
+        let flags = options.flags.map(u32::to_le_bytes);
+        if let Some(flags) = &flags {
+            epb.options.push(PcapNGOption {
+                code: OPT_EPB_FLAGS,
+                len: 4,
+                value: flags,
+            });
+        }
 
         writer.write_all(&epb.to_vec().unwrap())?;
 
@@ -162,18 +310,70 @@ impl PcapWriter {
     }
 
     pub fn on_dsb(&mut self, writer: &mut impl io::Write, dsb: &[u8]) -> Result<(), io::Error> {
-        let mut dsb = DecryptionSecretsBlock {
-            block_type: 0,
-            block_len1: 0,
-            secrets_type: SecretsType::TlsKeyLog,
-            secrets_len: dsb.len() as u32,
-            data: dsb,
-            options: Vec::new(),
-            block_len2: 0,
-        };
+        write_dsb(writer, dsb)
+    }
 
-        writer.write_all(&dsb.to_vec().unwrap())?;
+    /// Writes a name resolution block giving host names for addresses,
+    /// which Wireshark shows in place of the addresses.
+    pub fn on_names(
+        &mut self,
+        writer: &mut impl io::Write,
+        names: &[(IpAddr, String)],
+    ) -> Result<(), io::Error> {
+        write_nrb(writer, names)
+    }
+}
 
-        Ok(())
+/// Writes a name resolution block with a record for each of `names`. Names
+/// that can't go in one, being empty or too long, are left out.
+fn write_nrb(writer: &mut impl io::Write, names: &[(IpAddr, String)]) -> Result<(), io::Error> {
+    let mut records = Vec::new();
+    for (addr, name) in names {
+        // Names are nul terminated.
+        if name.is_empty() || name.contains('\0') {
+            continue;
+        }
+        let (record_type, mut value) = match addr {
+            IpAddr::V4(addr) => (NRB_RECORD_IPV4, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (NRB_RECORD_IPV6, addr.octets().to_vec()),
+        };
+        value.extend_from_slice(name.as_bytes());
+        value.push(0);
+        let Ok(len) = u16::try_from(value.len()) else {
+            continue;
+        };
+        records.extend(record_type.to_le_bytes());
+        records.extend(len.to_le_bytes());
+        records.extend(&value);
+        records.resize(records.len() + (4 - value.len() % 4) % 4, 0);
     }
+    records.extend(NRB_RECORD_END.to_le_bytes());
+    records.extend(0u16.to_le_bytes());
+
+    let block_len = (12 + records.len()) as u32;
+    writer.write_all(&NRB_BLOCK_TYPE.to_le_bytes())?;
+    writer.write_all(&block_len.to_le_bytes())?;
+    writer.write_all(&records)?;
+    writer.write_all(&block_len.to_le_bytes())?;
+    Ok(())
+}
+
+/// Writes a decryption secrets block containing the TLS key log `key_log`.
+///
+/// This is usable on its own to add keys to an existing little endian
+/// section.
+pub fn write_dsb(writer: &mut impl io::Write, key_log: &[u8]) -> Result<(), io::Error> {
+    let mut dsb = DecryptionSecretsBlock {
+        block_type: 0,
+        block_len1: 0,
+        secrets_type: SecretsType::TlsKeyLog,
+        secrets_len: key_log.len() as u32,
+        data: key_log,
+        options: Vec::new(),
+        block_len2: 0,
+    };
+
+    writer.write_all(&dsb.to_vec().unwrap())?;
+
+    Ok(())
 }
@@ -0,0 +1,237 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Probing of interface settings that affect capture fidelity.
+//!
+//! Everything here is best effort: drivers (and especially TAP devices) are
+//! free to not implement any given ethtool command, in which case we just
+//! report that we don't know.
+
+use std::{fmt, fs, mem, os::fd::RawFd};
+
+use nix::{
+    ioctl_readwrite_bad,
+    libc::{self, c_char},
+    sys::socket::{getsockopt, sockopt},
+};
+
+ioctl_readwrite_bad!(ethtool_ioctl, libc::SIOCETHTOOL, libc::ifreq);
+ioctl_readwrite_bad!(get_if_index, libc::SIOCGIFINDEX, libc::ifreq);
+
+// From linux/ethtool.h
+const ETHTOOL_GRXCSUM: u32 = 0x14;
+const ETHTOOL_GTSO: u32 = 0x1e;
+const ETHTOOL_GGSO: u32 = 0x23;
+const ETHTOOL_GFLAGS: u32 = 0x25;
+const ETHTOOL_GGRO: u32 = 0x2b;
+const ETHTOOL_GET_TS_INFO: u32 = 0x41;
+const ETH_FLAG_LRO: u32 = 1 << 15;
+
+// From linux/net_tstamp.h
+const SOF_TIMESTAMPING_RX_HARDWARE: u32 = 1 << 2;
+const SOF_TIMESTAMPING_RX_SOFTWARE: u32 = 1 << 3;
+const SOF_TIMESTAMPING_RAW_HARDWARE: u32 = 1 << 6;
+
+/// Receive buffers below this are likely to drop packets under bursty load.
+const SMALL_RCVBUF: usize = 4 * 1024 * 1024;
+
+#[repr(C)]
+struct EthtoolValue {
+    cmd: u32,
+    data: u32,
+}
+
+#[repr(C)]
+struct EthtoolTsInfo {
+    cmd: u32,
+    so_timestamping: u32,
+    phc_index: i32,
+    tx_types: u32,
+    tx_reserved: [u32; 3],
+    rx_filters: u32,
+    rx_reserved: [u32; 3],
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Offloads {
+    pub gro: Option<bool>,
+    pub lro: Option<bool>,
+    pub gso: Option<bool>,
+    pub tso: Option<bool>,
+    pub rx_checksum: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timestamping {
+    pub rx_software: Option<bool>,
+    pub rx_hardware: Option<bool>,
+}
+
+/// What we could find out about an interface before capturing on it.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceCapabilities {
+    pub name: String,
+    pub if_index: Option<u32>,
+    pub offloads: Offloads,
+    pub timestamping: Timestamping,
+    /// `SO_RCVBUF` of the capture socket, as reported by the kernel (i.e.
+    /// doubled).
+    pub rcvbuf: Option<usize>,
+    /// `net.core.rmem_max`, the cap on what we could raise it to.
+    pub rmem_max: Option<usize>,
+}
+
+pub(crate) fn make_ifreq(dev_name: &str) -> libc::ifreq {
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    // leave room for the nul
+    let len = (ifr.ifr_name.len() - 1).min(dev_name.len());
+    for (dst, &src) in ifr.ifr_name.iter_mut().zip(&dev_name.as_bytes()[..len]) {
+        *dst = src as c_char;
+    }
+    ifr
+}
+
+/// Runs an ethtool command whose argument starts with the `cmd` field.
+pub(crate) fn ethtool<T>(sock: RawFd, dev_name: &str, data: &mut T) -> Option<()> {
+    let mut ifr = make_ifreq(dev_name);
+    ifr.ifr_ifru.ifru_data = data as *mut T as *mut c_char;
+    unsafe { ethtool_ioctl(sock, &mut ifr) }.ok().map(|_| ())
+}
+
+fn ethtool_value(sock: RawFd, dev_name: &str, cmd: u32) -> Option<u32> {
+    let mut val = EthtoolValue { cmd, data: 0 };
+    ethtool(sock, dev_name, &mut val)?;
+    Some(val.data)
+}
+
+fn read_sysctl(path: &str) -> Option<usize> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Probes `dev_name` using `sock`, which should be a socket in the network
+/// namespace of the device (such as the capture socket itself).
+pub fn probe_interface(sock: RawFd, dev_name: &str) -> InterfaceCapabilities {
+    let flag = |cmd| ethtool_value(sock, dev_name, cmd).map(|v| v != 0);
+
+    let mut ifr = make_ifreq(dev_name);
+    let if_index = unsafe { get_if_index(sock, &mut ifr) }
+        .ok()
+        .map(|_| unsafe { ifr.ifr_ifru.ifru_ifindex } as u32);
+
+    let mut ts_info = EthtoolTsInfo {
+        cmd: ETHTOOL_GET_TS_INFO,
+        so_timestamping: 0,
+        phc_index: -1,
+        tx_types: 0,
+        tx_reserved: [0; 3],
+        rx_filters: 0,
+        rx_reserved: [0; 3],
+    };
+    let timestamping = match ethtool(sock, dev_name, &mut ts_info) {
+        Some(()) => Timestamping {
+            rx_software: Some(ts_info.so_timestamping & SOF_TIMESTAMPING_RX_SOFTWARE != 0),
+            rx_hardware: Some(
+                ts_info.so_timestamping
+                    & (SOF_TIMESTAMPING_RX_HARDWARE | SOF_TIMESTAMPING_RAW_HARDWARE)
+                    != 0,
+            ),
+        },
+        None => Timestamping::default(),
+    };
+
+    InterfaceCapabilities {
+        name: dev_name.to_string(),
+        if_index,
+        offloads: Offloads {
+            gro: flag(ETHTOOL_GGRO),
+            lro: ethtool_value(sock, dev_name, ETHTOOL_GFLAGS).map(|f| f & ETH_FLAG_LRO != 0),
+            gso: flag(ETHTOOL_GGSO),
+            tso: flag(ETHTOOL_GTSO),
+            rx_checksum: flag(ETHTOOL_GRXCSUM),
+        },
+        timestamping,
+        rcvbuf: getsockopt(sock, sockopt::RcvBuf).ok(),
+        rmem_max: read_sysctl("/proc/sys/net/core/rmem_max"),
+    }
+}
+
+impl InterfaceCapabilities {
+    /// Suggestions for settings known to make captures worse.
+    pub fn guidance(&self) -> Vec<String> {
+        let name = &self.name;
+        let mut out = Vec::new();
+
+        if self.offloads.gro == Some(true) || self.offloads.lro == Some(true) {
+            out.push(format!(
+                "receive offload (GRO/LRO) is enabled on {name}, so captured packets may be \
+                 coalesced past the MTU and truncated; consider `ethtool -K {name} gro off lro off`"
+            ));
+        }
+        if self.offloads.tso == Some(true) || self.offloads.gso == Some(true) {
+            out.push(format!(
+                "segmentation offload (TSO/GSO) is enabled on {name}, so outgoing packets may be \
+                 captured larger than they appear on the wire; consider \
+                 `ethtool -K {name} tso off gso off`"
+            ));
+        }
+        if self.timestamping.rx_software == Some(false) {
+            out.push(format!(
+                "{name} does not support software receive timestamps, so packet times will be \
+                 taken late and may be jittery"
+            ));
+        }
+        if let Some(rcvbuf) = self.rcvbuf {
+            if rcvbuf < SMALL_RCVBUF {
+                let hint = match self.rmem_max {
+                    Some(max) if max < SMALL_RCVBUF => {
+                        format!("; raise it with `sysctl net.core.rmem_max={SMALL_RCVBUF}`")
+                    }
+                    _ => String::new(),
+                };
+                out.push(format!(
+                    "capture socket receive buffer is only {rcvbuf} bytes, so bursts may be \
+                     dropped{hint}"
+                ));
+            }
+        }
+
+        out
+    }
+}
+
+fn on_off(v: Option<bool>) -> &'static str {
+    match v {
+        Some(true) => "on",
+        Some(false) => "off",
+        None => "unknown",
+    }
+}
+
+fn or_unknown(v: Option<usize>) -> String {
+    v.map(|v| v.to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Single line summary, suitable for capture file metadata.
+impl fmt::Display for InterfaceCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let o = &self.offloads;
+        let t = &self.timestamping;
+        write!(
+            f,
+            "{}: gro={} lro={} gso={} tso={} rx-checksum={} rx-sw-timestamp={} \
+             rx-hw-timestamp={} rcvbuf={} rmem_max={}",
+            self.name,
+            on_off(o.gro),
+            on_off(o.lro),
+            on_off(o.gso),
+            on_off(o.tso),
+            on_off(o.rx_checksum),
+            on_off(t.rx_software),
+            on_off(t.rx_hardware),
+            or_unknown(self.rcvbuf),
+            or_unknown(self.rmem_max),
+        )
+    }
+}
@@ -0,0 +1,256 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture through a `TPACKET_V3` ring shared with the kernel, for packet
+//! rates where a `recvmsg` per packet cannot keep up.
+//!
+//! The kernel fills blocks of the ring with packets and hands each over once
+//! it is full or [`RingOptions::block_timeout_ms`] has passed, whichever is
+//! first. A block is copied out all at once and given straight back, so the
+//! kernel only has to drop packets once every block is waiting on us.
+//!
+//! See `Documentation/networking/packet_mmap.rst` in the kernel.
+
+use std::{
+    collections::VecDeque,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    ptr,
+    sync::atomic::{fence, Ordering},
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use nix::{
+    errno::Errno,
+    libc::{self, c_int, c_void},
+    sys::time::TimeSpec,
+};
+use tokio::io::unix::AsyncFd;
+
+use crate::{ts_to_nanos, CapturedPacketMeta};
+
+// From linux/if_packet.h
+const PACKET_RX_RING: c_int = 5;
+const PACKET_VERSION: c_int = 10;
+const TPACKET_V3: c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TPACKET_ALIGNMENT: usize = 16;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+/// `struct tpacket_block_desc` with its `tpacket_hdr_v1`, up to the fields
+/// we use.
+#[repr(C)]
+#[allow(dead_code)]
+struct BlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: u32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+}
+
+/// `struct tpacket3_hdr`, up to the fields we use.
+#[repr(C)]
+#[allow(dead_code)]
+struct Tpacket3Hdr {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+}
+
+/// Where the `sockaddr_ll` is after each `struct tpacket3_hdr`, which is
+/// 48 bytes.
+const SLL_OFFSET: usize = (48 + TPACKET_ALIGNMENT - 1) & !(TPACKET_ALIGNMENT - 1);
+
+#[derive(Clone, Copy, Debug)]
+pub struct RingOptions {
+    /// Size of each block, which must be a power of two multiple of the
+    /// page size.
+    pub block_size: usize,
+    pub block_count: usize,
+    /// How long the kernel may hold on to a block that is not full yet.
+    pub block_timeout_ms: u32,
+}
+
+impl Default for RingOptions {
+    fn default() -> Self {
+        RingOptions {
+            block_size: 1 << 20,
+            block_count: 64,
+            block_timeout_ms: 50,
+        }
+    }
+}
+
+impl RingOptions {
+    fn total_size(&self) -> usize {
+        self.block_size * self.block_count
+    }
+}
+
+/// Captured packets out of a `TPACKET_V3` ring. Yields the same as
+/// [`UnprivilegedCapture`](crate::unprivileged::UnprivilegedCapture).
+pub struct RingCapture {
+    fd: AsyncFd<OwnedFd>,
+    map: *mut u8,
+    options: RingOptions,
+    /// Next block to look at.
+    block: usize,
+    /// Packets copied out of the last block, not yet taken.
+    ready: VecDeque<(Vec<u8>, CapturedPacketMeta)>,
+}
+
+// The mapping is only touched through &mut self.
+unsafe impl Send for RingCapture {}
+
+fn setsockopt_raw<T>(fd: RawFd, name: c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            name,
+            value as *const T as *const c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    Errno::result(ret)
+        .map(drop)
+        .map_err(|e| io::Error::from_raw_os_error(e as i32))
+}
+
+impl RingCapture {
+    /// Sets up a ring on the `AF_PACKET` socket `raw_fd` and takes it over.
+    ///
+    /// Safety: `raw_fd` must be an open `AF_PACKET` socket that nothing else
+    /// owns.
+    pub unsafe fn new(raw_fd: RawFd, options: RingOptions) -> io::Result<RingCapture> {
+        let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+        setsockopt_raw(fd.as_raw_fd(), PACKET_VERSION, &TPACKET_V3)?;
+
+        // Frames only matter to the kernel's sanity checks in V3, packets
+        // are packed into blocks however they fit.
+        let frame_size = 2048;
+        let req = TpacketReq3 {
+            tp_block_size: options.block_size as u32,
+            tp_block_nr: options.block_count as u32,
+            tp_frame_size: frame_size as u32,
+            tp_frame_nr: (options.total_size() / frame_size) as u32,
+            tp_retire_blk_tov: options.block_timeout_ms,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt_raw(fd.as_raw_fd(), PACKET_RX_RING, &req)?;
+
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                options.total_size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        tracing::debug!(
+            "capture ring of {} blocks of {} bytes",
+            options.block_count,
+            options.block_size
+        );
+
+        Ok(RingCapture {
+            fd: AsyncFd::new(fd)?,
+            map: map as *mut u8,
+            options,
+            block: 0,
+            ready: VecDeque::new(),
+        })
+    }
+
+    fn block_desc(&self) -> *mut BlockDesc {
+        unsafe { self.map.add(self.block * self.options.block_size) as *mut BlockDesc }
+    }
+
+    /// Copies out the packets of the next block if the kernel has handed it
+    /// over, and gives it back. Returns whether there was one.
+    fn take_block(&mut self) -> bool {
+        let desc = self.block_desc();
+        let status = unsafe { ptr::read_volatile(ptr::addr_of!((*desc).block_status)) };
+        if status & TP_STATUS_USER == 0 {
+            return false;
+        }
+        // Don't read the packets before seeing the block is ours.
+        fence(Ordering::Acquire);
+
+        let (num_pkts, first) = unsafe { ((*desc).num_pkts, (*desc).offset_to_first_pkt) };
+        let mut hdr = unsafe { (desc as *const u8).add(first as usize) };
+        for _ in 0..num_pkts {
+            let h = unsafe { &*(hdr as *const Tpacket3Hdr) };
+            let data = unsafe {
+                std::slice::from_raw_parts(hdr.add(h.tp_mac as usize), h.tp_snaplen as usize)
+            };
+            let sll = unsafe { &*(hdr.add(SLL_OFFSET) as *const libc::sockaddr_ll) };
+            self.ready.push_back((
+                data.to_vec(),
+                CapturedPacketMeta {
+                    len: h.tp_len as usize,
+                    time: ts_to_nanos(TimeSpec::new(h.tp_sec as _, h.tp_nsec as _)),
+                    if_index: sll.sll_ifindex as usize,
+                    packet_type: Some(sll.sll_pkttype),
+                },
+            ));
+            hdr = unsafe { hdr.add(h.tp_next_offset as usize) };
+        }
+
+        // Done with it before the kernel can have it back.
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(ptr::addr_of_mut!((*desc).block_status), TP_STATUS_KERNEL) };
+        self.block = (self.block + 1) % self.options.block_count;
+        true
+    }
+}
+
+impl Drop for RingCapture {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut c_void, self.options.total_size()) };
+    }
+}
+
+impl futures::Stream for RingCapture {
+    type Item = Result<(Vec<u8>, CapturedPacketMeta), std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(packet) = self.ready.pop_front() {
+                return Poll::Ready(Some(Ok(packet)));
+            }
+            if self.take_block() {
+                continue;
+            }
+            // The socket is readable once a block is handed over.
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            guard.clear_ready();
+        }
+    }
+}
@@ -51,37 +51,13 @@ use nix::{
 };
 use tokio::io::unix::AsyncFd;
 
-const DEV_NAME: &'static str = "tap0";
-
-pub type DynError = Box<dyn std::error::Error + Send + Sync>;
-
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    #[error("{0}: {1}")]
-    Errno(&'static str, Errno),
-    #[error("{0}: {1}")]
-    IoError(&'static str, std::io::Error),
-    #[error("{0}")]
-    StringError(&'static str),
-    #[error("{0}")]
-    Other(DynError),
-}
-
-trait AddContext<T> {
-    fn context(self, s: &'static str) -> Result<T, Error>;
-}
-
-impl<T> AddContext<T> for Result<T, Errno> {
-    fn context(self, s: &'static str) -> Result<T, Error> {
-        self.map_err(|e| Error::Errno(s, e))
-    }
-}
+use crate::{
+    error::{AddContext, DynError, Error},
+    ts_to_nanos, CapturedPacketMeta,
+};
 
-impl<T> AddContext<T> for Result<T, std::io::Error> {
-    fn context(self, s: &'static str) -> Result<T, Error> {
-        self.map_err(|e| Error::IoError(s, e))
-    }
-}
+/// Name of the TAP device inside the namespace, which we capture on.
+pub const DEV_NAME: &'static str = "tap0";
 
 fn err(s: &'static str) -> Error {
     Error::StringError(s)
@@ -238,9 +214,14 @@ fn parent(
     }?;
 
     tracing::debug!("got capture fd: {fd}");
-    hooks.parent_go(child_pidfd, fd);
-
-    networking.wait().context("wait for slirp4netns")?;
+    match hooks.parent_go(child_pidfd, fd) {
+        AfterParentGo::WaitForProgram => {
+            networking.wait().context("wait for slirp4netns")?;
+        }
+        AfterParentGo::Detach => {
+            tracing::debug!("leaving slirp4netns running with the program");
+        }
+    }
 
     Ok(())
 }
@@ -417,6 +398,16 @@ fn child(
     Ok(())
 }
 
+/// What the parent does once [`LaunchHooks::parent_go`] returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AfterParentGo {
+    /// Wait for slirp4netns, which exits along with the program.
+    WaitForProgram,
+    /// Return without waiting, leaving the program and its networking
+    /// running, e.g. because another process is watching it now.
+    Detach,
+}
+
 pub trait LaunchHooks {
     /// Executed in the parent immediately after fork
     fn parent_after_fork(&mut self) {}
@@ -426,7 +417,9 @@ pub trait LaunchHooks {
 
     /// Executed in the parent process after networking is started and the
     /// child process is either about to exec or has already.
-    fn parent_go(&mut self, _child_pidfd: RawFd, _capture_fd: RawFd) {}
+    fn parent_go(&mut self, _child_pidfd: RawFd, _capture_fd: RawFd) -> AfterParentGo {
+        AfterParentGo::WaitForProgram
+    }
 
     fn temp_dir(&self) -> &Path;
 
@@ -514,23 +507,27 @@ pub unsafe fn run_in_ns(args: Vec<String>, hooks: &mut dyn LaunchHooks) -> Resul
     Ok(())
 }
 
+/// How much of each packet [`UnprivilegedCapture`] keeps by default.
+pub const DEFAULT_SNAP_LEN: usize = 2048;
+
 pub struct UnprivilegedCapture {
     fd: AsyncFd<OwnedFd>,
+    snap_len: usize,
 }
 
 impl UnprivilegedCapture {
     pub unsafe fn new(raw_fd: RawFd) -> Result<UnprivilegedCapture, DynError> {
         Ok(Self {
             fd: AsyncFd::new(unsafe { OwnedFd::from_raw_fd(raw_fd) })?,
+            snap_len: DEFAULT_SNAP_LEN,
         })
     }
-}
 
-#[derive(Debug)]
-pub struct CapturedPacketMeta {
-    pub len: usize,
-    pub time: TimeSpec,
-    pub if_index: usize,
+    /// Keeps only the first `snap_len` bytes of each packet.
+    pub fn with_snap_len(mut self, snap_len: usize) -> Self {
+        self.snap_len = snap_len;
+        self
+    }
 }
 
 fn recvmsg_cap(fd: RawFd, buf: &mut [u8]) -> io::Result<CapturedPacketMeta> {
@@ -539,7 +536,9 @@ fn recvmsg_cap(fd: RawFd, buf: &mut [u8]) -> io::Result<CapturedPacketMeta> {
         fd,
         &mut [IoSliceMut::new(buf)],
         Some(&mut cmsgs),
-        MsgFlags::MSG_DONTWAIT,
+        // So that we are told how long the packet was, even if it did not
+        // fit.
+        MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_TRUNC,
     )
     .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
 
@@ -559,8 +558,9 @@ fn recvmsg_cap(fd: RawFd, buf: &mut [u8]) -> io::Result<CapturedPacketMeta> {
     tracing::trace!("recvmsg {ret:?}");
     Ok(CapturedPacketMeta {
         if_index: addr.ifindex(),
+        packet_type: Some(addr.pkttype()),
         len: ret.bytes,
-        time: timespec,
+        time: ts_to_nanos(timespec),
     })
 }
 
@@ -574,11 +574,11 @@ impl futures::Stream for UnprivilegedCapture {
             // FIXME: giant frames? overall how the hell do you do reasonable
             // buffer management here?
             let mut buf = Vec::new();
-            buf.resize(2048, 0u8);
+            buf.resize(self.snap_len, 0u8);
 
             match guard.try_io(|inner| recvmsg_cap(inner.as_raw_fd(), &mut buf)) {
                 Ok(Ok(meta @ CapturedPacketMeta { len, .. })) => {
-                    buf.resize(len, 0);
+                    buf.truncate(len);
                     return Poll::Ready(Some(Ok((buf, meta))));
                 }
                 // errors probably imply we don't have more data?
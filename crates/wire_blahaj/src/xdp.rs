@@ -0,0 +1,934 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Capture through `AF_XDP` sockets, for packet rates where even a
+//! [`RingCapture`](crate::ring::RingCapture) drops packets.
+//!
+//! A small XDP program is attached to the interface, which hands every
+//! packet received on it to the `AF_XDP` socket of the queue it arrived on,
+//! before the kernel has built a socket buffer for it. Each queue's socket
+//! has a UMEM of its own, the buffer the packets are written into, which is
+//! allocated on the NUMA node of the network card so that neither it nor we
+//! have to reach across to another node for them.
+//!
+//! This takes the packets from the machine: they never get to its network
+//! stack, so it is only for interfaces that nothing else uses, such as one
+//! on a mirror port. Only received packets are seen, and they are timestamped
+//! when we read them rather than by the kernel.
+//!
+//! Needs Linux 5.9 or later and root, or `CAP_NET_ADMIN` and `CAP_BPF`.
+//! Zero-copy needs a driver that supports it.
+//!
+//! See `Documentation/networking/af_xdp.rst` in the kernel.
+
+use std::{
+    collections::VecDeque,
+    fs, io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    ptr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use nix::{
+    errno::Errno,
+    libc::{self, c_int, c_ulong, c_void},
+    net::if_::if_nametoindex,
+    sys::{
+        resource::{setrlimit, Resource},
+        socket::{socket, AddressFamily, SockFlag, SockType},
+    },
+};
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    af_packet::{in_netns, InterfaceOptions},
+    error::{AddContext, Error},
+    probe::ethtool,
+    CapturedPacketMeta, KernelStats, Nanos,
+};
+
+// From linux/socket.h and linux/if_xdp.h
+const AF_XDP: c_int = 44;
+const SOL_XDP: c_int = 283;
+const XDP_MMAP_OFFSETS: c_int = 1;
+const XDP_RX_RING: c_int = 2;
+const XDP_UMEM_REG: c_int = 4;
+const XDP_UMEM_FILL_RING: c_int = 5;
+const XDP_UMEM_COMPLETION_RING: c_int = 6;
+const XDP_STATISTICS: c_int = 7;
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+// From linux/bpf.h and linux/if_link.h
+const BPF_MAP_CREATE: c_int = 0;
+const BPF_MAP_UPDATE_ELEM: c_int = 2;
+const BPF_PROG_LOAD: c_int = 5;
+const BPF_LINK_CREATE: c_int = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+
+// From linux/ethtool.h
+const ETHTOOL_GCHANNELS: u32 = 0x3c;
+
+// From linux/mempolicy.h
+const MPOL_PREFERRED: c_int = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// `struct xdp_statistics`, which older kernels only fill in the first
+/// three fields of.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct XdpStatistics {
+    rx_dropped: u64,
+    rx_invalid_descs: u64,
+    tx_invalid_descs: u64,
+    rx_ring_full: u64,
+    rx_fill_ring_empty_descs: u64,
+    tx_ring_empty_descs: u64,
+}
+
+#[repr(C)]
+struct EthtoolChannels {
+    cmd: u32,
+    max_rx: u32,
+    max_tx: u32,
+    max_other: u32,
+    max_combined: u32,
+    rx_count: u32,
+    tx_count: u32,
+    other_count: u32,
+    combined_count: u32,
+}
+
+/// One eBPF instruction, `struct bpf_insn`.
+#[repr(C)]
+struct BpfInsn {
+    code: u8,
+    /// Destination register in the low nibble, source in the high one.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    BpfInsn {
+        code,
+        regs: (src << 4) | dst,
+        off,
+        imm,
+    }
+}
+
+/// `bpf_attr` for `BPF_MAP_CREATE`, up to the fields we use.
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+/// `bpf_attr` for `BPF_MAP_UPDATE_ELEM`.
+#[repr(C)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// `bpf_attr` for `BPF_PROG_LOAD`, up to the fields we use.
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+/// `bpf_attr` for `BPF_LINK_CREATE`, up to the fields we use.
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// How the packets get from the network card to us.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum XdpMode {
+    /// The kernel copies each packet into the UMEM. Works with any driver,
+    /// falling back to generic XDP for those without XDP support of their
+    /// own, which is slower.
+    #[default]
+    Copy,
+    /// The network card writes packets straight into the UMEM. Needs a
+    /// driver that supports it.
+    ZeroCopy,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct XdpOptions {
+    pub mode: XdpMode,
+    /// Frames in the UMEM of each queue, which is also the size of its
+    /// rings. Must be a power of two.
+    pub frame_count: u32,
+    /// Size of each frame: 2048 or 4096. Packets that don't fit are dropped.
+    pub frame_size: u32,
+    /// NUMA node to allocate the UMEM on. The one the network card is
+    /// attached to if `None`.
+    pub numa_node: Option<u32>,
+}
+
+impl Default for XdpOptions {
+    fn default() -> Self {
+        XdpOptions {
+            mode: XdpMode::default(),
+            frame_count: 4096,
+            frame_size: 4096,
+            numa_node: None,
+        }
+    }
+}
+
+fn bpf<T>(cmd: c_int, attr: &mut T, what: &'static str) -> Result<OwnedFd, Error> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T as *mut c_void,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    let fd = Errno::result(ret).context(what)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn setsockopt_raw<T>(fd: RawFd, name: c_int, value: &T, what: &'static str) -> Result<(), Error> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    Errno::result(ret).map(drop).context(what)
+}
+
+fn getsockopt_raw<T>(
+    fd: RawFd,
+    name: c_int,
+    value: &mut T,
+    what: &'static str,
+) -> Result<(), Error> {
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockopt(fd, SOL_XDP, name, value as *mut T as *mut c_void, &mut len) };
+    Errno::result(ret).map(drop).context(what)
+}
+
+/// The XDP program on the interface, with the map of the sockets it sends
+/// packets to. Detached when dropped.
+struct XdpProgram {
+    map: OwnedFd,
+    _program: OwnedFd,
+    _link: OwnedFd,
+}
+
+impl XdpProgram {
+    fn attach(if_index: u32, queues: u32, mode: XdpMode) -> Result<XdpProgram, Error> {
+        let map = bpf(
+            BPF_MAP_CREATE,
+            &mut MapCreateAttr {
+                map_type: BPF_MAP_TYPE_XSKMAP,
+                key_size: 4,
+                value_size: 4,
+                max_entries: queues,
+                map_flags: 0,
+            },
+            "create XDP socket map",
+        )?;
+
+        // return bpf_redirect_map(&map, ctx->rx_queue_index, XDP_PASS);
+        let program = [
+            // r2 = ctx->rx_queue_index
+            insn(0x61, 2, 1, 16, 0),
+            // r1 = &map, which takes two instructions
+            insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map.as_raw_fd()),
+            insn(0, 0, 0, 0, 0),
+            // r3 = XDP_PASS, for queues without a socket
+            insn(0xb7, 3, 0, 0, XDP_PASS),
+            insn(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+            insn(0x95, 0, 0, 0, 0),
+        ];
+        let license = b"Dual MPL/GPL\0";
+        let mut name = [0u8; 16];
+        name[..7].copy_from_slice(b"clipper");
+        let program = bpf(
+            BPF_PROG_LOAD,
+            &mut ProgLoadAttr {
+                prog_type: BPF_PROG_TYPE_XDP,
+                insn_cnt: program.len() as u32,
+                insns: program.as_ptr() as u64,
+                license: license.as_ptr() as u64,
+                log_level: 0,
+                log_size: 0,
+                log_buf: 0,
+                kern_version: 0,
+                prog_flags: 0,
+                prog_name: name,
+                prog_ifindex: 0,
+                expected_attach_type: BPF_XDP,
+            },
+            "load XDP program",
+        )?;
+
+        let link = |flags| {
+            bpf(
+                BPF_LINK_CREATE,
+                &mut LinkCreateAttr {
+                    prog_fd: program.as_raw_fd() as u32,
+                    target_ifindex: if_index,
+                    attach_type: BPF_XDP,
+                    flags,
+                },
+                "attach XDP program",
+            )
+        };
+        let link = match mode {
+            XdpMode::ZeroCopy => link(XDP_FLAGS_DRV_MODE)?,
+            // Drivers without XDP support can still have a program run on
+            // the socket buffers they make.
+            XdpMode::Copy => link(XDP_FLAGS_DRV_MODE).or_else(|e| {
+                tracing::debug!("no native XDP ({e}), falling back to generic XDP");
+                link(XDP_FLAGS_SKB_MODE)
+            })?,
+        };
+
+        Ok(XdpProgram {
+            map,
+            _program: program,
+            _link: link,
+        })
+    }
+
+    /// Has packets received on `queue` sent to `sock`.
+    fn insert(&self, queue: u32, sock: RawFd) -> Result<(), Error> {
+        let value = sock as u32;
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_MAP_UPDATE_ELEM,
+                &mut MapUpdateAttr {
+                    map_fd: self.map.as_raw_fd() as u32,
+                    key: &queue as *const u32 as u64,
+                    value: &value as *const u32 as u64,
+                    flags: 0,
+                } as *mut MapUpdateAttr as *mut c_void,
+                mem::size_of::<MapUpdateAttr>() as libc::c_uint,
+            )
+        };
+        Errno::result(ret)
+            .map(drop)
+            .context("add XDP socket to map")
+    }
+}
+
+/// A shared memory mapping, unmapped when dropped.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(len: usize, flags: c_int, fd: RawFd, offset: libc::off_t) -> Result<Mapping, Error> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::IoError("map XDP memory", io::Error::last_os_error()));
+        }
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// Asks for the pages of the mapping to be put on `node` when they are
+    /// first touched, which has to be before then. Only a preference, since
+    /// not being able to is no reason not to capture.
+    fn prefer_node(&self, node: u32) {
+        let mut mask = vec![0 as c_ulong; node as usize / c_ulong::BITS as usize + 1];
+        mask[node as usize / c_ulong::BITS as usize] |= 1 << (node % c_ulong::BITS);
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.ptr as *mut c_void,
+                self.len,
+                MPOL_PREFERRED,
+                mask.as_ptr(),
+                // One more than the bits in the mask, as mbind counts.
+                mask.len() * c_ulong::BITS as usize + 1,
+                0,
+            )
+        };
+        if ret != 0 {
+            tracing::debug!(
+                "could not put XDP memory on NUMA node {node}: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
+
+/// One of the rings of an `AF_XDP` socket, mapped from the kernel.
+struct Ring {
+    _map: Mapping,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    descs: *mut u8,
+    mask: u32,
+}
+
+impl Ring {
+    fn map(
+        fd: RawFd,
+        offsets: &XdpRingOffset,
+        size: u32,
+        desc_size: usize,
+        pgoff: libc::off_t,
+    ) -> Result<Ring, Error> {
+        let len = offsets.desc as usize + size as usize * desc_size;
+        let map = Mapping::new(len, libc::MAP_SHARED | libc::MAP_POPULATE, fd, pgoff)?;
+        let at = |offset: u64| unsafe { map.ptr.add(offset as usize) };
+        Ok(Ring {
+            producer: at(offsets.producer) as *const AtomicU32,
+            consumer: at(offsets.consumer) as *const AtomicU32,
+            flags: at(offsets.flags) as *const AtomicU32,
+            descs: at(offsets.desc),
+            mask: size - 1,
+            _map: map,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        unsafe { &*self.flags }.load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0
+    }
+}
+
+/// The socket of one queue of the interface, with its UMEM.
+struct XdpQueue {
+    fd: Arc<OwnedFd>,
+    umem: Mapping,
+    rx: Ring,
+    fill: Ring,
+    _completion: Ring,
+}
+
+impl XdpQueue {
+    fn open(
+        if_index: u32,
+        queue: u32,
+        options: &XdpOptions,
+        numa_node: Option<u32>,
+    ) -> Result<XdpQueue, Error> {
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        let fd = unsafe { OwnedFd::from_raw_fd(Errno::result(fd).context("make XDP socket")?) };
+        let raw = fd.as_raw_fd();
+
+        let len = options.frame_count as usize * options.frame_size as usize;
+        let umem = Mapping::new(len, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)?;
+        if let Some(node) = numa_node {
+            umem.prefer_node(node);
+        }
+        // Registering it pins its pages, which puts them on the node.
+        let reg = XdpUmemReg {
+            addr: umem.ptr as u64,
+            len: len as u64,
+            chunk_size: options.frame_size,
+            headroom: 0,
+            flags: 0,
+            tx_metadata_len: 0,
+        };
+        setsockopt_raw(raw, XDP_UMEM_REG, &reg, "register XDP UMEM")?;
+
+        let size = options.frame_count;
+        setsockopt_raw(raw, XDP_UMEM_FILL_RING, &size, "size XDP fill ring")?;
+        // Never used, since we don't send, but it has to be there.
+        setsockopt_raw(
+            raw,
+            XDP_UMEM_COMPLETION_RING,
+            &size,
+            "size XDP completion ring",
+        )?;
+        setsockopt_raw(raw, XDP_RX_RING, &size, "size XDP receive ring")?;
+
+        let mut offsets = XdpMmapOffsets::default();
+        getsockopt_raw(raw, XDP_MMAP_OFFSETS, &mut offsets, "get XDP ring offsets")?;
+        let rx = Ring::map(
+            raw,
+            &offsets.rx,
+            size,
+            mem::size_of::<XdpDesc>(),
+            XDP_PGOFF_RX_RING,
+        )?;
+        let fill = Ring::map(raw, &offsets.fr, size, 8, XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion = Ring::map(raw, &offsets.cr, size, 8, XDP_UMEM_PGOFF_COMPLETION_RING)?;
+
+        // Every frame is the kernel's to fill to begin with.
+        for i in 0..size {
+            unsafe {
+                *(fill.descs as *mut u64).add(i as usize) = i as u64 * options.frame_size as u64
+            };
+        }
+        fill.producer().store(size, Ordering::Release);
+
+        let copy_flag = match options.mode {
+            XdpMode::Copy => XDP_COPY,
+            XdpMode::ZeroCopy => XDP_ZEROCOPY,
+        };
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: copy_flag | XDP_USE_NEED_WAKEUP,
+            sxdp_ifindex: if_index,
+            sxdp_queue_id: queue,
+            sxdp_shared_umem_fd: 0,
+        };
+        let ret = unsafe {
+            libc::bind(
+                raw,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        match Errno::result(ret) {
+            Ok(_) => {}
+            Err(Errno::EOPNOTSUPP) if options.mode == XdpMode::ZeroCopy => {
+                return Err(Error::StringError(
+                    "the driver does not support zero-copy AF_XDP",
+                ))
+            }
+            Err(e) => return Err(Error::Errno("bind XDP socket", e)),
+        }
+
+        Ok(XdpQueue {
+            fd: Arc::new(fd),
+            umem,
+            rx,
+            fill,
+            _completion: completion,
+        })
+    }
+
+    /// Copies out what the kernel has received, giving the frames straight
+    /// back. Returns how many packets there were.
+    fn take(
+        &mut self,
+        if_index: u32,
+        snap_len: usize,
+        frame_size: u32,
+        out: &mut VecDeque<(Vec<u8>, CapturedPacketMeta)>,
+    ) -> usize {
+        let consumer = self.rx.consumer().load(Ordering::Relaxed);
+        // Don't read the descriptors before seeing they are ours.
+        let producer = self.rx.producer().load(Ordering::Acquire);
+        let count = producer.wrapping_sub(consumer);
+        if count == 0 {
+            return 0;
+        }
+
+        let time = now();
+        let fill_at = self.fill.producer().load(Ordering::Relaxed);
+        for i in 0..count {
+            let idx = consumer.wrapping_add(i) & self.rx.mask;
+            let desc = unsafe { &*(self.rx.descs as *const XdpDesc).add(idx as usize) };
+            let data = unsafe {
+                std::slice::from_raw_parts(self.umem.ptr.add(desc.addr as usize), desc.len as usize)
+            };
+            out.push_back((
+                data[..data.len().min(snap_len)].to_vec(),
+                CapturedPacketMeta {
+                    len: desc.len as usize,
+                    time,
+                    if_index: if_index as usize,
+                    // Only received packets get here, but the kernel doesn't
+                    // say who they were for.
+                    packet_type: None,
+                },
+            ));
+
+            // Whole frames go back, wherever in it the packet started.
+            let frame = desc.addr & !(frame_size as u64 - 1);
+            let fill_idx = fill_at.wrapping_add(i) & self.fill.mask;
+            unsafe { *(self.fill.descs as *mut u64).add(fill_idx as usize) = frame };
+        }
+
+        // Done with the packets before the kernel can have them back.
+        self.rx
+            .consumer()
+            .store(consumer.wrapping_add(count), Ordering::Release);
+        self.fill
+            .producer()
+            .store(fill_at.wrapping_add(count), Ordering::Release);
+        if self.fill.needs_wakeup() {
+            unsafe {
+                libc::recvfrom(
+                    self.fd.as_raw_fd(),
+                    ptr::null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+        }
+        count as usize
+    }
+}
+
+fn now() -> Nanos {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as Nanos)
+        .unwrap_or_default()
+}
+
+/// How many receive queues `name` has, asked of `sock` with ethtool. One if
+/// the driver doesn't say.
+fn rx_queues(sock: RawFd, name: &str) -> u32 {
+    let mut channels: EthtoolChannels = unsafe { mem::zeroed() };
+    channels.cmd = ETHTOOL_GCHANNELS;
+    match ethtool(sock, name, &mut channels) {
+        Some(()) => channels.combined_count.max(channels.rx_count).max(1),
+        None => 1,
+    }
+}
+
+/// The NUMA node the network card of `name` is attached to, if it is and
+/// the machine has more than one.
+fn numa_node(name: &str) -> Option<u32> {
+    fs::read_to_string(format!("/sys/class/net/{name}/device/numa_node"))
+        .ok()?
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .and_then(|n| u32::try_from(n).ok())
+}
+
+/// `AF_XDP` sockets on every receive queue of an interface, set up but not
+/// yet read from.
+pub struct XdpSocket {
+    // First, so that the program is detached before the sockets go.
+    _program: XdpProgram,
+    /// For asking about the interface, which the `AF_XDP` sockets can't be
+    /// used for.
+    control: OwnedFd,
+    if_index: u32,
+    options: XdpOptions,
+    queues: Vec<XdpQueue>,
+    received: Arc<AtomicU64>,
+}
+
+// The mappings are only touched through &mut self.
+unsafe impl Send for XdpSocket {}
+
+impl XdpSocket {
+    /// A socket in the interface's namespace, to give to
+    /// [`probe_interface`](crate::probe::probe_interface).
+    pub fn control_fd(&self) -> RawFd {
+        self.control.as_raw_fd()
+    }
+
+    pub fn stats(&self) -> XdpStats {
+        XdpStats {
+            sockets: self.queues.iter().map(|q| q.fd.clone()).collect(),
+            received: self.received.clone(),
+            last: KernelStats::default(),
+        }
+    }
+}
+
+/// Sets up `AF_XDP` capture on the interface of `options`, on all of its
+/// receive queues, each with a UMEM of its own.
+pub fn open_xdp(options: &InterfaceOptions, xdp: XdpOptions) -> Result<XdpSocket, Error> {
+    if !xdp.frame_count.is_power_of_two() {
+        return Err(Error::StringError("XDP frame count must be a power of two"));
+    }
+    if ![2048, 4096].contains(&xdp.frame_size) {
+        return Err(Error::StringError("XDP frame size must be 2048 or 4096"));
+    }
+    match &options.netns {
+        Some(netns) => in_netns(netns, || open_xdp_here(options, xdp)),
+        None => open_xdp_here(options, xdp),
+    }
+}
+
+fn open_xdp_here(options: &InterfaceOptions, xdp: XdpOptions) -> Result<XdpSocket, Error> {
+    let name = &options.interface;
+    let if_index = if_nametoindex(name.as_str())
+        .map_err(|_| Error::Other(format!("no such interface {name:?}").into()))?;
+    let control: OwnedFd = unsafe {
+        OwnedFd::from_raw_fd(
+            socket(
+                AddressFamily::Inet,
+                SockType::Datagram,
+                SockFlag::SOCK_CLOEXEC,
+                None,
+            )
+            .context("make control socket")?,
+        )
+    };
+
+    // Kernels before 5.11 count UMEMs and maps against the locked memory
+    // limit, which is tiny by default.
+    if let Err(e) = setrlimit(
+        Resource::RLIMIT_MEMLOCK,
+        libc::RLIM_INFINITY,
+        libc::RLIM_INFINITY,
+    ) {
+        tracing::debug!("could not raise the locked memory limit: {e}");
+    }
+
+    let queue_count = rx_queues(control.as_raw_fd(), name);
+    let numa_node = xdp.numa_node.or_else(|| numa_node(name));
+    let program = XdpProgram::attach(if_index, queue_count, xdp.mode)?;
+    let mut queues = Vec::new();
+    for queue in 0..queue_count {
+        let q = XdpQueue::open(if_index, queue, &xdp, numa_node)?;
+        program.insert(queue, q.fd.as_raw_fd())?;
+        queues.push(q);
+    }
+
+    tracing::debug!(
+        "capturing on {name} with AF_XDP ({:?}) on {queue_count} queues{}",
+        xdp.mode,
+        match numa_node {
+            Some(node) => format!(", memory on NUMA node {node}"),
+            None => String::new(),
+        }
+    );
+    Ok(XdpSocket {
+        _program: program,
+        control,
+        if_index,
+        options: xdp,
+        queues,
+        received: Default::default(),
+    })
+}
+
+/// What was dropped on the way to the `AF_XDP` sockets of an interface.
+pub struct XdpStats {
+    sockets: Vec<Arc<OwnedFd>>,
+    received: Arc<AtomicU64>,
+    /// The running totals the last time they were taken.
+    last: KernelStats,
+}
+
+impl XdpStats {
+    /// What happened since this was last called, like
+    /// [`kernel_stats`](crate::af_packet::kernel_stats) gives. Received
+    /// packets are those that got to us, or were dropped on the way.
+    pub fn take(&mut self) -> Result<KernelStats, Error> {
+        let mut dropped = 0;
+        for sock in &self.sockets {
+            let mut stats = XdpStatistics::default();
+            getsockopt_raw(
+                sock.as_raw_fd(),
+                XDP_STATISTICS,
+                &mut stats,
+                "get XDP statistics",
+            )?;
+            dropped += stats.rx_dropped + stats.rx_ring_full;
+        }
+        let total = KernelStats {
+            received: self.received.load(Ordering::Relaxed) + dropped,
+            dropped,
+        };
+        let new = total - self.last;
+        self.last = total;
+        Ok(new)
+    }
+}
+
+/// Captured packets out of the `AF_XDP` sockets of an interface. Yields the
+/// same as [`UnprivilegedCapture`](crate::unprivileged::UnprivilegedCapture).
+pub struct XdpCapture {
+    socket: XdpSocket,
+    fds: Vec<AsyncFd<Arc<OwnedFd>>>,
+    snap_len: usize,
+    /// Packets copied out of the rings, not yet taken.
+    ready: VecDeque<(Vec<u8>, CapturedPacketMeta)>,
+}
+
+impl XdpCapture {
+    pub fn new(socket: XdpSocket) -> io::Result<XdpCapture> {
+        let fds = socket
+            .queues
+            .iter()
+            .map(|q| AsyncFd::new(q.fd.clone()))
+            .collect::<io::Result<_>>()?;
+        Ok(XdpCapture {
+            socket,
+            fds,
+            snap_len: usize::MAX,
+            ready: VecDeque::new(),
+        })
+    }
+
+    pub fn with_snap_len(mut self, snap_len: usize) -> Self {
+        self.snap_len = snap_len;
+        self
+    }
+
+    /// Copies out what is waiting on every queue. Returns whether there was
+    /// anything.
+    fn take(&mut self) -> bool {
+        let XdpSocket {
+            if_index,
+            options,
+            queues,
+            received,
+            ..
+        } = &mut self.socket;
+        let mut count = 0;
+        for queue in queues {
+            count += queue.take(
+                *if_index,
+                self.snap_len,
+                options.frame_size,
+                &mut self.ready,
+            );
+        }
+        received.fetch_add(count as u64, Ordering::Relaxed);
+        count > 0
+    }
+}
+
+impl futures::Stream for XdpCapture {
+    type Item = Result<(Vec<u8>, CapturedPacketMeta), std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(packet) = self.ready.pop_front() {
+                return Poll::Ready(Some(Ok(packet)));
+            }
+            if self.take() {
+                continue;
+            }
+            // Each socket is readable once its receive ring has something
+            // in it. Waits on all of them, then looks again at the rings
+            // whose readiness was cleared.
+            let mut woken = false;
+            for fd in &self.fds {
+                match fd.poll_read_ready(cx) {
+                    Poll::Ready(Ok(mut guard)) => {
+                        guard.clear_ready();
+                        woken = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    Poll::Pending => {}
+                }
+            }
+            if !woken {
+                return Poll::Pending;
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+package clipper.agent;
+
+import java.lang.instrument.Instrumentation;
+import java.util.Map;
+import java.util.Set;
+
+/**
+ * Java agent that writes the TLS secrets of JSSE, Java's own TLS, to a key log
+ * file, for JVMs that clipper_inject can't get keys out of since they don't
+ * use a native TLS library.
+ *
+ * The key log file is the agent's argument, or else SSLKEYLOGFILE:
+ * {@code java -javaagent:clipper-agent.jar=keys.log ...}. It works on JDKs
+ * 11 and newer that still have their internal copy of ASM.
+ */
+public final class Agent {
+    private Agent() {}
+
+    public static void premain(String args, Instrumentation inst) {
+        start(args, inst);
+    }
+
+    public static void agentmain(String args, Instrumentation inst) {
+        start(args, inst);
+    }
+
+    private static void start(String args, Instrumentation inst) {
+        String path = args != null && !args.isEmpty() ? args : System.getenv("SSLKEYLOGFILE");
+        if (path == null) {
+            System.err.println("clipper agent: no key log file given, not logging keys");
+            return;
+        }
+
+        try {
+            KeyLog.open(path);
+
+            // We are on the boot class path, so the instrumented code can
+            // call us, once java.base can read us. We also need the JDK's
+            // ASM and the insides of JSSE.
+            Module base = Object.class.getModule();
+            Module agent = Agent.class.getModule();
+            inst.redefineModule(
+                    base,
+                    Set.of(agent),
+                    Map.of("jdk.internal.org.objectweb.asm", Set.of(agent)),
+                    Map.of("sun.security.ssl", Set.of(agent)),
+                    Set.of(),
+                    Map.of());
+            KeyLog.findFields();
+
+            inst.addTransformer(new Transformer(), true);
+            // Only if attached late; JSSE is loaded when it is first used.
+            for (Class<?> c : inst.getAllLoadedClasses()) {
+                if (Transformer.TARGETS.contains(c.getName().replace('.', '/'))) {
+                    inst.retransformClasses(c);
+                }
+            }
+        } catch (Exception e) {
+            System.err.println("clipper agent: could not start: " + e);
+        }
+    }
+}
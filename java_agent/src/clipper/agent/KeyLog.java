@@ -0,0 +1,110 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+package clipper.agent;
+
+import java.io.FileOutputStream;
+import java.io.IOException;
+import java.io.OutputStream;
+import java.lang.reflect.Field;
+import java.nio.charset.StandardCharsets;
+import java.util.Collections;
+import java.util.Map;
+import java.util.WeakHashMap;
+import javax.crypto.SecretKey;
+
+/**
+ * Where the instrumented JSSE code sends its secrets, which are written out as
+ * key log lines. It is called from java.base, so it is on the boot class path.
+ *
+ * Nothing in here may throw into JSSE: a key that can't be logged is skipped.
+ */
+public final class KeyLog {
+    private static OutputStream out;
+
+    /** The client random of the handshake each key derivation is for. */
+    private static final Map<Object, byte[]> RANDOMS =
+            Collections.synchronizedMap(new WeakHashMap<>());
+
+    private static Field clientHelloRandom;
+    private static Field randomBytes;
+
+    private KeyLog() {}
+
+    static synchronized void open(String path) throws IOException {
+        out = new FileOutputStream(path, true);
+    }
+
+    /** Needs sun.security.ssl to be open to us, so is done after that. */
+    static void findFields() throws ReflectiveOperationException {
+        clientHelloRandom = Class.forName("sun.security.ssl.HandshakeContext")
+                .getDeclaredField("clientHelloRandom");
+        clientHelloRandom.setAccessible(true);
+        randomBytes = Class.forName("sun.security.ssl.RandomCookie")
+                .getDeclaredField("randomBytes");
+        randomBytes.setAccessible(true);
+    }
+
+    /** Called when a key derivation is made for the handshake {@code context}. */
+    public static void onDerivation(Object derivation, Object context) {
+        try {
+            Object random = clientHelloRandom.get(context);
+            if (random != null) {
+                RANDOMS.put(derivation, (byte[]) randomBytes.get(random));
+            }
+        } catch (RuntimeException | ReflectiveOperationException e) {
+            // Not a handshake we can log.
+        }
+    }
+
+    /** Called when {@code derivation} has derived {@code key} for {@code algorithm}. */
+    public static void onKey(Object key, Object derivation, String algorithm) {
+        try {
+            String label = label(derivation, algorithm);
+            byte[] random = RANDOMS.get(derivation);
+            byte[] secret = key instanceof SecretKey ? ((SecretKey) key).getEncoded() : null;
+            if (label != null && random != null && secret != null) {
+                write(label + " " + hex(random) + " " + hex(secret) + "\n");
+            }
+        } catch (RuntimeException | IOException e) {
+            // The key is lost, but the connection goes on.
+        }
+    }
+
+    private static String label(Object derivation, String algorithm) {
+        if (derivation.getClass().getName().endsWith("$LegacyMasterKeyDerivation")) {
+            return "CLIENT_RANDOM";
+        }
+        switch (algorithm) {
+            case "TlsClientEarlyTrafficSecret":
+                return "CLIENT_EARLY_TRAFFIC_SECRET";
+            case "TlsClientHandshakeTrafficSecret":
+                return "CLIENT_HANDSHAKE_TRAFFIC_SECRET";
+            case "TlsServerHandshakeTrafficSecret":
+                return "SERVER_HANDSHAKE_TRAFFIC_SECRET";
+            case "TlsClientAppTrafficSecret":
+                return "CLIENT_TRAFFIC_SECRET_0";
+            case "TlsServerAppTrafficSecret":
+                return "SERVER_TRAFFIC_SECRET_0";
+            case "TlsExporterMasterSecret":
+                return "EXPORTER_SECRET";
+            default:
+                return null;
+        }
+    }
+
+    private static String hex(byte[] bytes) {
+        StringBuilder s = new StringBuilder(bytes.length * 2);
+        for (byte b : bytes) {
+            s.append(Character.forDigit((b >> 4) & 0xf, 16));
+            s.append(Character.forDigit(b & 0xf, 16));
+        }
+        return s.toString();
+    }
+
+    private static synchronized void write(String line) throws IOException {
+        out.write(line.getBytes(StandardCharsets.US_ASCII));
+        out.flush();
+    }
+}
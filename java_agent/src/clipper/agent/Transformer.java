@@ -0,0 +1,106 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+package clipper.agent;
+
+import java.lang.instrument.ClassFileTransformer;
+import java.security.ProtectionDomain;
+import java.util.Set;
+import jdk.internal.org.objectweb.asm.ClassReader;
+import jdk.internal.org.objectweb.asm.ClassVisitor;
+import jdk.internal.org.objectweb.asm.ClassWriter;
+import jdk.internal.org.objectweb.asm.MethodVisitor;
+import jdk.internal.org.objectweb.asm.Opcodes;
+
+/**
+ * Makes JSSE's key derivations for TLS 1.3 secrets and TLS 1.2 master secrets
+ * call {@link KeyLog}: their constructors, which get the handshake, and
+ * {@code deriveKey}, which returns the secret.
+ *
+ * This uses the copy of ASM inside the JDK, so the agent needs no
+ * dependencies.
+ */
+final class Transformer implements ClassFileTransformer {
+    static final Set<String> TARGETS = Set.of(
+            "sun/security/ssl/SSLSecretDerivation",
+            "sun/security/ssl/SSLMasterKeyDerivation$LegacyMasterKeyDerivation");
+
+    private static final String KEY_LOG = "clipper/agent/KeyLog";
+    private static final String CONSTRUCTOR_PREFIX = "(Lsun/security/ssl/HandshakeContext;";
+    private static final String DERIVE_KEY =
+            "(Ljava/lang/String;Ljava/security/spec/AlgorithmParameterSpec;)Ljavax/crypto/SecretKey;";
+
+    @Override
+    public byte[] transform(
+            Module module,
+            ClassLoader loader,
+            String className,
+            Class<?> classBeingRedefined,
+            ProtectionDomain protectionDomain,
+            byte[] classfileBuffer) {
+        if (!TARGETS.contains(className)) {
+            return null;
+        }
+        try {
+            ClassReader reader = new ClassReader(classfileBuffer);
+            ClassWriter writer = new ClassWriter(reader, ClassWriter.COMPUTE_MAXS);
+            reader.accept(new ClassVisitor(Opcodes.ASM7, writer) {
+                @Override
+                public MethodVisitor visitMethod(
+                        int access, String name, String descriptor, String signature,
+                        String[] exceptions) {
+                    MethodVisitor mv =
+                            super.visitMethod(access, name, descriptor, signature, exceptions);
+                    if (name.equals("<init>") && descriptor.startsWith(CONSTRUCTOR_PREFIX)) {
+                        return new DerivationHook(mv);
+                    } else if (name.equals("deriveKey") && descriptor.equals(DERIVE_KEY)) {
+                        return new KeyHook(mv);
+                    }
+                    return mv;
+                }
+            }, 0);
+            return writer.toByteArray();
+        } catch (RuntimeException e) {
+            System.err.println("clipper agent: could not instrument " + className + ": " + e);
+            return null;
+        }
+    }
+
+    /** Calls {@code KeyLog.onDerivation(this, context)} as the constructor returns. */
+    private static final class DerivationHook extends MethodVisitor {
+        DerivationHook(MethodVisitor mv) {
+            super(Opcodes.ASM7, mv);
+        }
+
+        @Override
+        public void visitInsn(int opcode) {
+            if (opcode == Opcodes.RETURN) {
+                super.visitVarInsn(Opcodes.ALOAD, 0);
+                super.visitVarInsn(Opcodes.ALOAD, 1);
+                super.visitMethodInsn(Opcodes.INVOKESTATIC, KEY_LOG, "onDerivation",
+                        "(Ljava/lang/Object;Ljava/lang/Object;)V", false);
+            }
+            super.visitInsn(opcode);
+        }
+    }
+
+    /** Calls {@code KeyLog.onKey(key, this, algorithm)} as deriveKey returns. */
+    private static final class KeyHook extends MethodVisitor {
+        KeyHook(MethodVisitor mv) {
+            super(Opcodes.ASM7, mv);
+        }
+
+        @Override
+        public void visitInsn(int opcode) {
+            if (opcode == Opcodes.ARETURN) {
+                super.visitInsn(Opcodes.DUP);
+                super.visitVarInsn(Opcodes.ALOAD, 0);
+                super.visitVarInsn(Opcodes.ALOAD, 1);
+                super.visitMethodInsn(Opcodes.INVOKESTATIC, KEY_LOG, "onKey",
+                        "(Ljava/lang/Object;Ljava/lang/Object;Ljava/lang/String;)V", false);
+            }
+            super.visitInsn(opcode);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// SPDX-FileCopyrightText: 2023 Jade Lovelace
+//
+// SPDX-License-Identifier: MPL-2.0
+
+import java.io.FileInputStream;
+import java.io.IOException;
+import java.io.UncheckedIOException;
+import java.security.KeyStore;
+import javax.net.ssl.KeyManagerFactory;
+import javax.net.ssl.SSLContext;
+import javax.net.ssl.SSLServerSocket;
+import javax.net.ssl.SSLSocket;
+import javax.net.ssl.TrustManagerFactory;
+
+/**
+ * Makes one TLS connection to itself, so that the agent has keys to log.
+ * Takes a PKCS#12 key store, with the password "changeit", that has the
+ * server's key in it and is trusted by the client.
+ */
+public final class Smoke {
+    private Smoke() {}
+
+    public static void main(String[] args) throws Exception {
+        char[] password = "changeit".toCharArray();
+        KeyStore keys = KeyStore.getInstance("PKCS12");
+        try (FileInputStream in = new FileInputStream(args[0])) {
+            keys.load(in, password);
+        }
+        KeyManagerFactory kmf = KeyManagerFactory.getInstance("PKIX");
+        kmf.init(keys, password);
+        TrustManagerFactory tmf = TrustManagerFactory.getInstance("PKIX");
+        tmf.init(keys);
+        SSLContext context = SSLContext.getInstance("TLS");
+        context.init(kmf.getKeyManagers(), tmf.getTrustManagers(), null);
+
+        try (SSLServerSocket server =
+                (SSLServerSocket) context.getServerSocketFactory().createServerSocket(0)) {
+            Thread echo = new Thread(() -> {
+                try (SSLSocket conn = (SSLSocket) server.accept()) {
+                    conn.getOutputStream().write(conn.getInputStream().read());
+                } catch (IOException e) {
+                    throw new UncheckedIOException(e);
+                }
+            });
+            echo.start();
+            try (SSLSocket client = (SSLSocket) context.getSocketFactory()
+                    .createSocket("localhost", server.getLocalPort())) {
+                client.getOutputStream().write('!');
+                if (client.getInputStream().read() != '!') {
+                    throw new IOException("echo went wrong");
+                }
+            }
+            echo.join();
+        }
+    }
+}
@@ -367,6 +367,14 @@ pub mod internal {
     pub mod key_schedule {
         pub use crate::tls13::key_schedule::*;
     }
+
+    pub mod tls12 {
+        pub use crate::tls12::{
+            ConnectionSecrets, PSK_SUITES, STATIC_RSA_SUITES, TLS_PSK_WITH_AES_128_GCM_SHA256,
+            TLS_PSK_WITH_AES_256_GCM_SHA384, TLS_RSA_WITH_AES_128_GCM_SHA256,
+            TLS_RSA_WITH_AES_256_GCM_SHA384,
+        };
+    }
 }
 
 // The public interface is:
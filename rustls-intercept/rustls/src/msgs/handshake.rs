@@ -1526,6 +1526,8 @@ pub enum KeyExchangeAlgorithm {
     RSA,
     ECDH,
     ECDHE,
+    /// Plain pre-shared key (RFC 4279), only used for decoding captures.
+    PSK,
 }
 
 // We don't support arbitrary curves.  It's a terrible
@@ -116,6 +116,91 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
         hmac_algorithm: ring::hmac::HMAC_SHA384,
     });
 
+/// The TLS1.2 ciphersuite TLS_RSA_WITH_AES_128_GCM_SHA256. Only used for
+/// decoding captures, see [`STATIC_RSA_SUITES`].
+pub static TLS_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_RSA_WITH_AES_128_GCM_SHA256,
+            bulk: BulkAlgorithm::Aes128Gcm,
+            aead_algorithm: &ring::aead::AES_128_GCM,
+        },
+        kx: KeyExchangeAlgorithm::RSA,
+        sign: TLS12_RSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        aead_alg: &AesGcm,
+        hmac_algorithm: ring::hmac::HMAC_SHA256,
+    });
+
+/// The TLS1.2 ciphersuite TLS_RSA_WITH_AES_256_GCM_SHA384. Only used for
+/// decoding captures, see [`STATIC_RSA_SUITES`].
+pub static TLS_RSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_RSA_WITH_AES_256_GCM_SHA384,
+            bulk: BulkAlgorithm::Aes256Gcm,
+            aead_algorithm: &ring::aead::AES_256_GCM,
+        },
+        kx: KeyExchangeAlgorithm::RSA,
+        sign: TLS12_RSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        aead_alg: &AesGcm,
+        hmac_algorithm: ring::hmac::HMAC_SHA384,
+    });
+
+/// Static RSA key exchange suites, which have no forward secrecy. These are
+/// deliberately not in `ALL_CIPHER_SUITES` so that rustls connections never
+/// use them; they exist so that old captures can be decrypted with the
+/// server's private key.
+pub static STATIC_RSA_SUITES: &[SupportedCipherSuite] = &[
+    TLS_RSA_WITH_AES_256_GCM_SHA384,
+    TLS_RSA_WITH_AES_128_GCM_SHA256,
+];
+
+/// The TLS1.2 ciphersuite TLS_PSK_WITH_AES_128_GCM_SHA256. Only used for
+/// decoding captures, see [`PSK_SUITES`].
+pub static TLS_PSK_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_PSK_WITH_AES_128_GCM_SHA256,
+            bulk: BulkAlgorithm::Aes128Gcm,
+            aead_algorithm: &ring::aead::AES_128_GCM,
+        },
+        kx: KeyExchangeAlgorithm::PSK,
+        sign: &[],
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        aead_alg: &AesGcm,
+        hmac_algorithm: ring::hmac::HMAC_SHA256,
+    });
+
+/// The TLS1.2 ciphersuite TLS_PSK_WITH_AES_256_GCM_SHA384. Only used for
+/// decoding captures, see [`PSK_SUITES`].
+pub static TLS_PSK_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_PSK_WITH_AES_256_GCM_SHA384,
+            bulk: BulkAlgorithm::Aes256Gcm,
+            aead_algorithm: &ring::aead::AES_256_GCM,
+        },
+        kx: KeyExchangeAlgorithm::PSK,
+        sign: &[],
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        aead_alg: &AesGcm,
+        hmac_algorithm: ring::hmac::HMAC_SHA384,
+    });
+
+/// Plain PSK key exchange suites, as used with external pre-shared keys by
+/// embedded devices. Like [`STATIC_RSA_SUITES`], these are only for decoding
+/// captures.
+pub static PSK_SUITES: &[SupportedCipherSuite] = &[
+    TLS_PSK_WITH_AES_256_GCM_SHA384,
+    TLS_PSK_WITH_AES_128_GCM_SHA256,
+];
+
 static TLS12_ECDSA_SCHEMES: &[SignatureScheme] = &[
     SignatureScheme::ED25519,
     SignatureScheme::ECDSA_NISTP521_SHA512,
@@ -198,7 +283,7 @@ impl fmt::Debug for Tls12CipherSuite {
 }
 
 /// TLS1.2 per-connection keying material
-pub(crate) struct ConnectionSecrets {
+pub struct ConnectionSecrets {
     pub(crate) randoms: ConnectionRandoms,
     suite: &'static Tls12CipherSuite,
     pub(crate) master_secret: [u8; 48],
@@ -255,6 +340,74 @@ impl ConnectionSecrets {
         ret
     }
 
+    /// Used for injecting keys from a dump
+    pub fn from_master_secret(
+        suite: &'static Tls12CipherSuite,
+        client_random: [u8; 32],
+        server_random: [u8; 32],
+        master_secret: &[u8],
+    ) -> Result<Self, Error> {
+        if master_secret.len() != 48 {
+            return Err(Error::General(
+                "TLS1.2 master secret must be 48 bytes".into(),
+            ));
+        }
+        Ok(Self::new_resume(
+            ConnectionRandoms {
+                client: client_random,
+                server: server_random,
+            },
+            suite,
+            master_secret,
+        ))
+    }
+
+    /// Used for decrypting captures of static RSA key exchanges, given the
+    /// pre-master secret. `ems_session_hash` is the handshake hash up to the
+    /// ClientKeyExchange if extended master secret was negotiated.
+    pub fn from_premaster_secret(
+        suite: &'static Tls12CipherSuite,
+        client_random: [u8; 32],
+        server_random: [u8; 32],
+        premaster_secret: &[u8],
+        ems_session_hash: Option<&[u8]>,
+    ) -> Self {
+        let mut ret = Self {
+            randoms: ConnectionRandoms {
+                client: client_random,
+                server: server_random,
+            },
+            suite,
+            master_secret: [0u8; 48],
+        };
+        let randoms = join_randoms(&ret.randoms.client, &ret.randoms.server);
+        let (label, seed): (&[u8], &[u8]) = match ems_session_hash {
+            Some(hash) => (b"extended master secret", hash),
+            None => (b"master secret", &randoms),
+        };
+        prf::prf(
+            &mut ret.master_secret,
+            suite.hmac_algorithm,
+            premaster_secret,
+            label,
+            seed,
+        );
+        ret
+    }
+
+    /// Used for decoding: decrypts what `side` receives in `common`, starting
+    /// with the next record.
+    pub fn install_decrypter(&self, side: Side, common: &mut CommonState) {
+        let (decrypter, _) = self.make_cipher_pair(side);
+        common
+            .record_layer
+            .set_message_decrypter(decrypter);
+    }
+
+    pub fn master_secret(&self) -> &[u8] {
+        &self.master_secret
+    }
+
     /// Make a `MessageCipherPair` based on the given supported ciphersuite `scs`,
     /// and the session's `secrets`.
     pub(crate) fn make_cipher_pair(&self, side: Side) -> MessageCipherPair {